@@ -8,10 +8,12 @@ use std::sync::Arc;
 use std::time::Instant;
 use qmetaobject::QString;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{ BTreeMap, BTreeSet, HashMap };
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
 use crate::rendering;
 use crate::rendering::render_queue::*;
-use indicatif::{ProgressBar, MultiProgress, ProgressState, ProgressStyle};
+use indicatif::{ProgressBar, MultiProgress, ProgressStyle};
 use gyroflow_core::filesystem::path_to_url;
 
 cpp! {{
@@ -87,9 +89,34 @@ struct Opts {
     #[argh(option)]
     open: Option<String>,
 
-    /// watch folder for automated processing
+    /// watch folder for automated processing, can be repeated. New videos are rendered once their size is stable,
+    /// with a sidecar gyro log of the same name if there is one
     #[argh(option)]
-    watch: Option<String>,
+    watch: Vec<String>,
+
+    /// output folder of the watch mode, mirroring the structure of the watched folders. By default next to the input file
+    #[argh(option)]
+    watch_output: Option<String>,
+
+    /// folder to move the files which failed in the watch mode to. By default they are only marked as failed
+    #[argh(option)]
+    watch_failed: Option<String>,
+
+    /// only print what the watch mode would do with the new files
+    #[argh(switch)]
+    dry_run: bool,
+
+    /// folder to look for the media of the .edl and .otio timelines in, can be repeated. The folder of the timeline is always searched
+    #[argh(option)]
+    media_dir: Vec<String>,
+
+    /// number of frames rendered before and after the used range of the timeline events
+    #[argh(option, default = "0")]
+    handles: u32,
+
+    /// presets applied to the timeline media by camera, as JSON object of camera name and preset path, eg. "{ 'Sony': 'sony.gyroflow' }"
+    #[argh(option)]
+    camera_presets: Option<String>,
 
     /// gyro file path
     #[argh(option, short = 'g')]
@@ -106,6 +133,126 @@ struct Opts {
     /// print app version
     #[argh(switch)]
     version: bool,
+
+    /// benchmark the processing backends at the given resolutions instead of rendering, eg. "1920x1080,3840x2160"
+    #[argh(option)]
+    benchmark: Option<String>,
+
+    /// pixel formats for the benchmark: RGBA8, RGBA16, RGBAf, Luma8, Luma16. The 16-bit ones use 10-bit values. Default: RGBA16
+    #[argh(option)]
+    benchmark_formats: Option<String>,
+
+    /// backends for the benchmark: CPU, OpenCL, wgpu. Default: all available
+    #[argh(option)]
+    benchmark_backends: Option<String>,
+
+    /// number of processed frames per backend in the benchmark
+    #[argh(option, default = "20")]
+    benchmark_iterations: usize,
+
+    /// write the benchmark results as JSON to this file. By default they are printed to stdout
+    #[argh(option)]
+    benchmark_output: Option<String>,
+
+    /// write a filmstrip of the first input as a single PNG to this file instead of rendering. Projects are stabilized, videos are not
+    #[argh(option)]
+    filmstrip: Option<String>,
+
+    /// seconds between the filmstrip frames
+    #[argh(option, default = "1.0")]
+    filmstrip_interval: f64,
+
+    /// maximum width and height of each filmstrip frame in pixels
+    #[argh(option, default = "160")]
+    filmstrip_size: u32,
+
+    /// log levels per module, eg. "info,gyroflow_core::synchronization=debug". Default: GYROFLOW_LOG if set, otherwise info
+    #[argh(option)]
+    log_levels: Option<String>,
+
+    /// also write the log as JSON lines to this file
+    #[argh(option)]
+    log_json: Option<String>,
+
+    /// deterministic processing: the same input always gives bit-identical sync offsets and zoom. Slightly slower zooming
+    #[argh(switch)]
+    deterministic: bool,
+
+    /// write a processing report (lens, sync points, rolling shutter, smoothing, crop and render stats) as <output>.report.json next to each rendered file
+    #[argh(switch)]
+    report: bool,
+
+    /// like --report, and also write a self-contained HTML summary with the graphs as <output>.report.html
+    #[argh(switch)]
+    report_html: bool,
+
+    /// run the JSON-RPC control server on this localhost port, 0 picks a free one
+    #[cfg(feature = "control-server")]
+    #[argh(option)]
+    server: Option<u16>,
+
+    /// token which the control server clients authenticate with, random by default
+    #[cfg(feature = "control-server")]
+    #[argh(option)]
+    server_token: Option<String>,
+}
+
+fn write_filmstrip(opts: &Opts, output: &str) -> Result<(), String> {
+    let input = opts.input.first().ok_or("No input file provided")?;
+    let input_url = path_to_url(input);
+    let strip = if input.ends_with(".gyroflow") {
+        let stab = StabilizationManager::default();
+        stab.import_gyroflow_file(&input_url, true, |_| (), Arc::new(std::sync::atomic::AtomicBool::new(false)), false).map_err(|e| format!("{e:?}"))?;
+        stab.recompute_blocking();
+        let video_url = stab.input_file.read().url.clone();
+        rendering::thumbnails::generate_filmstrip(&video_url, opts.filmstrip_interval, opts.filmstrip_size, Some(&stab))
+    } else {
+        rendering::thumbnails::generate_filmstrip(&input_url, opts.filmstrip_interval, opts.filmstrip_size, None)
+    }.map_err(|e| e.to_string())?;
+    strip.save(output).map_err(|e| e.to_string())?;
+    log::info!("Filmstrip written path={output} width={} height={}", strip.width(), strip.height());
+    Ok(())
+}
+
+fn run_benchmark(resolutions: &str, opts: &Opts) {
+    let list = |x: &Option<String>| x.as_deref().map(|x| x.split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect::<Vec<_>>());
+    let mut options = benchmark::BenchmarkOptions {
+        resolutions: resolutions.split(',').filter_map(|x| {
+            let (w, h) = x.trim().split_once('x')?;
+            Some((w.parse().ok()?, h.parse().ok()?))
+        }).collect(),
+        iterations: opts.benchmark_iterations.max(1),
+        ..Default::default()
+    };
+    if let Some(formats) = list(&opts.benchmark_formats) { options.pixel_formats = formats; }
+    if let Some(backends) = list(&opts.benchmark_backends) { options.backends = backends; }
+    if options.resolutions.is_empty() {
+        log::error!("Invalid benchmark resolutions: {resolutions}, expected eg. 3840x2160");
+        return;
+    }
+
+    gyroflow_core::gpu::initialize_contexts();
+    let mut report = benchmark::run(&options, |x| {
+        let name = format!("{} {}x{} {}", x.backend, x.width, x.height, x.pixel_format);
+        if let Some(reason) = &x.skipped {
+            log::info!("{name}: skipped ({reason})");
+        } else {
+            let stages = x.stages.unwrap_or_default();
+            log::info!("{name} [{}]: {:.2} ms/frame, {:.1} fps, {:.0} MP/s (upload {:.2} ms, kernel {:.2} ms, readback {:.2} ms), output {}{}",
+                x.device, x.avg_ms, x.fps, x.megapixels_per_s, stages.upload_ms, stages.kernel_ms, stages.readback_ms, x.hash,
+                match x.matches_reference { Some(true) => " matches the reference".to_owned(), Some(false) => format!(" DIFFERS from the reference by {:.4}", x.mean_diff.unwrap_or_default()), None => String::new() });
+        }
+    });
+    report.version = crate::util::get_version();
+
+    let json = serde_json::to_string_pretty(&report).unwrap_or_default();
+    if let Some(path) = &opts.benchmark_output {
+        if let Err(e) = std::fs::write(path, json) {
+            log::error!("Failed to write {path}: {e:?}");
+        }
+    } else {
+        println!("{json}");
+    }
 }
 
 pub fn will_run_in_console() -> bool {
@@ -130,7 +277,46 @@ pub fn run(open_file: &mut String) -> bool {
             return true;
         }
 
-        let (videos, mut lens_profiles, mut presets) = detect_types(&opts.input);
+        if let Some(path) = &opts.log_json {
+            if let Err(e) = gyroflow_core::logging::set_json_file(Some(std::path::Path::new(path))) {
+                log::error!("Failed to open the JSON log file path={path} error={e:?}");
+            }
+        }
+        // Without `--log-levels`, the levels from `GYROFLOW_LOG` are kept. Otherwise the CLI defaults to info
+        let set_log_levels = || {
+            let levels = match opts.log_levels.as_deref() {
+                Some(x) => x,
+                None if std::env::var_os("GYROFLOW_LOG").is_some() => return,
+                None => "info"
+            };
+            if let Err(e) = gyroflow_core::logging::set_levels(levels) {
+                log::error!("{e}");
+                let _ = gyroflow_core::logging::set_levels("info");
+            }
+        };
+
+        if let Some(resolutions) = &opts.benchmark {
+            set_log_levels();
+            run_benchmark(resolutions, &opts);
+            return true;
+        }
+
+        if let Some(output) = &opts.filmstrip {
+            set_log_levels();
+            if let Err(e) = write_filmstrip(&opts, output) {
+                log::error!("Failed to write the filmstrip: {e}");
+            }
+            return true;
+        }
+
+        #[cfg(feature = "control-server")]
+        if let Some(port) = opts.server {
+            set_log_levels();
+            crate::control_server::run(port, opts.server_token);
+            return true;
+        }
+
+        let (videos, mut lens_profiles, mut presets, timelines) = detect_types(&opts.input);
         if let Some(mut preset) = opts.preset {
             if !preset.is_empty() {
                 if preset.starts_with('{') { preset = preset.replace('\'', "\""); }
@@ -145,34 +331,35 @@ pub fn run(open_file: &mut String) -> bool {
             }
         }
 
-        for file in videos.iter().chain(lens_profiles.iter()) {
+        for file in videos.iter().chain(lens_profiles.iter()).chain(timelines.iter()) {
             if !std::path::Path::new(&file).exists() {
                 log::error!("File {} doesn't exist.", file);
                 return true;
             }
         }
-        let mut watching = opts.watch.as_ref().map(|x| !x.is_empty()).unwrap_or_default();
+        let mut watching = opts.watch.iter().any(|x| !x.is_empty());
 
         if !watching {
             if lens_profiles.len() > 1 {
                 log::error!("More than one lens profile!");
                 return true;
             }
-            if videos.is_empty() {
+            if videos.is_empty() && timelines.is_empty() {
                 log::error!("No videos provided!");
                 return true;
             }
 
-            log::info!("Videos: {:?}", videos);
+            if !videos.is_empty() { log::info!("Videos: {:?}", videos); }
+            if !timelines.is_empty() { log::info!("Timelines: {:?}", timelines); }
             if !lens_profiles.is_empty() { log::info!("Lens profiles: {:?}", lens_profiles); }
             if !presets.is_empty() { log::info!("Presets: {:?}", presets); }
         }
 
         let m = MultiProgress::new();
         m.set_draw_target(indicatif::ProgressDrawTarget::hidden());
-        let sty = ProgressStyle::with_template("[{bar:50.cyan/blue}] {pos:>5}/{len:5} {eta:11} {prefix:.magenta}\x1B[37;1m{msg}\x1B[0m")
+        // The prefix is the summary of the render stats, with the ETA
+        let sty = ProgressStyle::with_template("[{bar:50.cyan/blue}] {pos:>5}/{len:5} {prefix:.magenta}\x1B[37;1m{msg}\x1B[0m")
             .unwrap()
-            .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "ETA {:.1}s", state.eta().as_secs_f64()).unwrap())
             .progress_chars("#>-");
 
         // let spinner = ["⠋","⠙","⠹","⠸","⠼","⠴","⠦","⠧","⠇","⠏"];
@@ -185,7 +372,7 @@ pub fn run(open_file: &mut String) -> bool {
         let pbh0 = m.add(ProgressBar::new(1)); pbh0.set_style(ProgressStyle::with_template("{msg}").unwrap()); pbh0.set_message(" ");
         let pbh = m.add(ProgressBar::new(1)); pbh.set_style(ProgressStyle::with_template("{spinner:.green} {msg:73} Elapsed: {elapsed_precise}").unwrap().tick_strings(&spinner)); pbh.set_message("Queue"); pbh.enable_steady_tick(std::time::Duration::from_millis(70));
 
-        log::set_max_level(log::LevelFilter::Info);
+        set_log_levels();
 
         let time = Instant::now();
         let mut queue_printed = false;
@@ -199,7 +386,10 @@ pub fn run(open_file: &mut String) -> bool {
         if let Some((name, _list_name)) = gyroflow_core::gpu::initialize_contexts() {
             rendering::set_gpu_type_from_name(&name);
         }
-        let mut additional_data = setup_defaults(stab, &mut queue);
+        let mut additional_data = setup_defaults(stab.clone(), &mut queue);
+        if opts.deterministic {
+            stab.set_deterministic(true);
+        }
         if let Some(suffix) = opts.suffix {
             queue.default_suffix = QString::from(suffix);
         }
@@ -270,21 +460,32 @@ pub fn run(open_file: &mut String) -> bool {
         let queue = RefCell::new(queue);
         let queue_ptr = unsafe { qmetaobject::QObjectPinned::new(&queue).get_or_create_cpp_object() };
 
-        if let Some(watch) = opts.watch {
-            watching = watch_folder(watch, |path| {
-                if !path.contains(&suffix) {
+        let watcher = Rc::new(RefCell::new(Watcher::new(opts.watch_output, opts.watch_failed, opts.dry_run)));
+        if watching {
+            let on_file = Rc::new(RefCell::new(|path: String| {
+                if !path.contains(&suffix) && !watcher.borrow().is_output(std::path::Path::new(&path)) {
                     log::info!("New file detected: {}", path);
                     let extensions = [ "mp4", "mov", "mxf", "mkv", "webm", "insv", "gyroflow", "png", "exr", "dng", "braw" ];
                     let ext = std::path::Path::new(&path).extension().map(|x| x.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
                     if extensions.contains(&ext.as_str()) {
-                        let queue = unsafe { &mut *queue.as_ptr() };
-                        let additional_data2 = additional_data.to_string();
-                        qmetaobject::single_shot(std::time::Duration::from_millis(1), move || {
-                            queue.add_file(path_to_url(&path), String::new(), additional_data2.clone());
-                        });
+                        let plan = watcher.borrow_mut().plan(&path, &additional_data);
+                        if let Some((gyro_url, additional_data2)) = plan {
+                            let queue = unsafe { &mut *queue.as_ptr() };
+                            let watcher = watcher.clone();
+                            qmetaobject::single_shot(std::time::Duration::from_millis(1), move || {
+                                let job_id = queue.add_file(path_to_url(&path), gyro_url.clone(), additional_data2.clone());
+                                watcher.borrow_mut().queued(job_id, &path);
+                            });
+                        }
                     }
                 }
-            });
+            }));
+            watching = false;
+            for root in opts.watch.iter().filter(|x| !x.is_empty()) {
+                let pending = watcher.borrow_mut().add_root(root);
+                let on_file = on_file.clone();
+                watching |= watch_folder(watcher.borrow().roots.last().unwrap().to_string_lossy().to_string(), pending, move |path| (on_file.borrow_mut())(path));
+            }
         }
 
         unsafe {
@@ -306,9 +507,16 @@ pub fn run(open_file: &mut String) -> bool {
                     for item in qi.iter() {
                         if item.job_id == *job_id {
                             ok = item.error_string.is_empty();
+                            watcher.borrow_mut().job_finished(*job_id, &item.error_string.to_string());
                             break;
                         }
                     }
+                    if ok && (opts.report || opts.report_html) {
+                        match queue.write_job_report(*job_id, opts.report_html) {
+                            Ok(files) => for file in files { log::info!("[{:08x}] Report written to {}", job_id, gyroflow_core::filesystem::display_url(&file)); },
+                            Err(e) => log::error!("[{:08x}] Failed to write the report: {e:?}", job_id)
+                        }
+                    }
                     if ok {
                         pb.set_message(format!("\x1B[1;32m{}\x1B[0m", pb.message())); // Green
                     } else {
@@ -338,6 +546,13 @@ pub fn run(open_file: &mut String) -> bool {
                 pb.set_length(*total_frames as u64);
                 pb.set_position(*current_frame as u64);
             });
+            connect!(queue_ptr, q, render_stats, |job_id: &u32, stats: &QString| {
+                if let Some(pb) = pbs.get(job_id) {
+                    if let Ok(stats) = serde_json::from_str::<rendering::render_stats::RenderStats>(&stats.to_string()) {
+                        pb.set_prefix(if stats.finished { String::new() } else { format!("{} ", stats.progress_line()) });
+                    }
+                }
+            });
             connect!(queue_ptr, q, processing_progress, |job_id: &u32, progress: &f64| {
                 let mut any_other_in_progress = false;
                 {
@@ -374,7 +589,16 @@ pub fn run(open_file: &mut String) -> bool {
                     log::warn!("[{:08x}] File exists, overwriting: {}", job_id, text.to_string().strip_prefix("file_exists:").unwrap());
                     return;
                 }
-                log::error!("[{:08x}] Error: {}", job_id, text.to_string().replace("%1", &arg.to_string()));
+                let text = text.to_string().replace("%1", &arg.to_string());
+                log::error!("[{:08x}] Error: {}", job_id, text);
+
+                let queue = &mut *queue.as_ptr();
+                if watcher.borrow_mut().job_finished(*job_id, &text) && queue.jobs_added.remove(job_id) && queue.jobs_added.is_empty() {
+                    // Failed before processing was done, start the other ones
+                    qmetaobject::single_shot(std::time::Duration::from_millis(500), move || {
+                        queue.start();
+                    });
+                }
             });
             connect!(queue_ptr, q, added, |job_id: &u32| {
                 let queue = &mut *queue.as_ptr();
@@ -449,6 +673,15 @@ pub fn run(open_file: &mut String) -> bool {
             for file in &videos {
                 queue.add_file(path_to_url(file), path_to_url(&gyro_file), additional_data.to_string());
             }
+            let timeline_options = serde_json::json!({
+                "search_dirs": opts.media_dir,
+                "handles": opts.handles,
+                "presets": opts.camera_presets.and_then(|x| serde_json::from_str::<serde_json::Value>(&x.replace('\'', "\"")).ok()),
+            }).to_string();
+            for file in &timelines {
+                let report = queue.import_timeline(path_to_url(file), timeline_options.clone(), additional_data.to_string()).to_string();
+                log::info!("Timeline {}: {}", file, report);
+            }
         }
 
         // Run the event loop
@@ -467,12 +700,15 @@ pub fn run(open_file: &mut String) -> bool {
     false
 }
 
-fn detect_types(all_files: &[String]) -> (Vec<String>, Vec<String>, Vec<String>) { // -> Videos/projects, lens profiles, presets
+fn detect_types(all_files: &[String]) -> (Vec<String>, Vec<String>, Vec<String>, Vec<String>) { // -> Videos/projects, lens profiles, presets, timelines
     let mut videos = Vec::new();
     let mut lens_profiles = Vec::new();
     let mut presets = Vec::new();
+    let mut timelines = Vec::new();
     for file in all_files {
-        if file.ends_with(".json") { // Lens profile
+        if file.to_lowercase().ends_with(".edl") || file.to_lowercase().ends_with(".otio") {
+            timelines.push(file.clone());
+        } else if file.ends_with(".json") { // Lens profile
             lens_profiles.push(file.clone());
         } else if file.ends_with(".gyroflow") {
             let video_path = || -> Option<String> {
@@ -490,10 +726,10 @@ fn detect_types(all_files: &[String]) -> (Vec<String>, Vec<String>, Vec<String>)
             videos.push(file.clone());
         }
     }
-    (videos, lens_profiles, presets)
+    (videos, lens_profiles, presets, timelines)
 }
 
-fn setup_defaults(stab: Arc<StabilizationManager>, queue: &mut RenderQueue) -> serde_json::Value {
+pub(crate) fn setup_defaults(stab: Arc<StabilizationManager>, queue: &mut RenderQueue) -> serde_json::Value {
     use gyroflow_core::settings;
     let codecs = [
         "H.264/AVC",
@@ -503,6 +739,7 @@ fn setup_defaults(stab: Arc<StabilizationManager>, queue: &mut RenderQueue) -> s
         "CineForm",
         "EXR Sequence",
         "PNG Sequence",
+        "TIFF Sequence",
         "AV1",
     ];
 
@@ -576,17 +813,200 @@ fn setup_defaults(stab: Arc<StabilizationManager>, queue: &mut RenderQueue) -> s
     })
 }
 
+const WATCH_GYRO_EXTENSIONS: &[&str] = &["gcsv", "bbl", "bfl", "csv", "log", "txt"];
+
+// Files seen by the watch mode. It's saved in the settings folder, so a restart doesn't render the files again
+// and picks up the ones which arrived in the meantime
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+struct WatchState {
+    roots: BTreeSet<String>,
+    files: BTreeMap<String, WatchedFile>,
+}
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+struct WatchedFile {
+    size: u64,
+    status: String, // skipped, queued, done, failed
+    output_folder: String,
+    error: String,
+}
+
+struct Watcher {
+    roots: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    failed: Option<PathBuf>,
+    dry_run: bool,
+    state_file: PathBuf,
+    state: WatchState,
+    jobs: HashMap<u32, String>,
+}
+
+impl Watcher {
+    fn new(output: Option<String>, failed: Option<String>, dry_run: bool) -> Self {
+        Self::with_state_file(output, failed, dry_run, settings::data_dir().join("watch_state.json"))
+    }
+    fn with_state_file(output: Option<String>, failed: Option<String>, dry_run: bool, state_file: PathBuf) -> Self {
+        let state = std::fs::read(&state_file).ok().and_then(|x| serde_json::from_slice(&x).ok()).unwrap_or_default();
+        let absolute = |x: String| Self::absolute(Path::new(&x));
+        Self { roots: Vec::new(), output: output.filter(|x| !x.is_empty()).map(absolute), failed: failed.filter(|x| !x.is_empty()).map(absolute), dry_run, state_file, state, jobs: HashMap::new() }
+    }
+    fn absolute(path: &Path) -> PathBuf {
+        let path = if path.is_absolute() { path.to_path_buf() } else { std::env::current_dir().unwrap_or_default().join(path) };
+        path.components().collect()
+    }
+    fn key(path: &Path) -> String { path.to_string_lossy().replace('\\', "/") }
+
+    fn save(&self) {
+        if self.dry_run { return; }
+        match serde_json::to_vec_pretty(&self.state) {
+            Ok(data) => if let Err(e) = std::fs::write(&self.state_file, data) { log::error!("Failed to save the watch state: {e:?}"); },
+            Err(e) => log::error!("Failed to save the watch state: {e:?}"),
+        }
+    }
+
+    /// Starts watching `root` and returns the files which arrived since the last run. In a folder watched for the first time, the existing files are skipped
+    fn add_root(&mut self, root: &str) -> Vec<String> {
+        let root = Self::absolute(Path::new(root));
+        let first_time = self.state.roots.insert(Self::key(&root));
+        let mut pending = Vec::new();
+        for entry in walkdir::WalkDir::new(&root).into_iter().flatten().filter(|x| x.file_type().is_file()) {
+            let key = Self::key(entry.path());
+            if self.is_output(entry.path()) || self.state.files.contains_key(&key) { continue; }
+            if first_time {
+                let size = entry.metadata().map(|x| x.len()).unwrap_or_default();
+                self.state.files.insert(key, WatchedFile { size, status: "skipped".into(), ..Default::default() });
+            } else {
+                pending.push(key);
+            }
+        }
+        if !pending.is_empty() { log::info!("[watch] {} new file(s) in {} since the last run", pending.len(), root.display()); }
+        self.roots.push(root);
+        self.save();
+        pending
+    }
+
+    fn is_output(&self, path: &Path) -> bool {
+        self.output.iter().chain(self.failed.iter()).any(|x| path.starts_with(x))
+    }
+    // Folder of `path` relative to the watched folder
+    fn relative_folder(&self, path: &Path) -> PathBuf {
+        let parent = path.parent().unwrap_or(path);
+        self.roots.iter().find_map(|x| parent.strip_prefix(x).ok()).map(|x| x.to_path_buf()).unwrap_or_default()
+    }
+
+    fn log(&self, key: &str, msg: &str) {
+        let name = Path::new(key).file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        log::info!("[watch] {name}: {msg}");
+        if self.dry_run { return; }
+        // Per-file log next to the output
+        if let Some(folder) = self.state.files.get(key).map(|x| x.output_folder.clone()).filter(|x| !x.is_empty()) {
+            use std::io::Write;
+            let line = format!("{} {msg}\n", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"));
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(Path::new(&folder).join(format!("{name}.log")));
+            if let Err(e) = file.and_then(|mut x| x.write_all(line.as_bytes())) {
+                log::warn!("[watch] Failed to write the log of {name}: {e:?}");
+            }
+        }
+    }
+
+    /// Gyro url and additional data for rendering `path`. None when it was already processed, or in the dry run
+    fn plan(&mut self, path: &str, additional_data: &serde_json::Value) -> Option<(String, String)> {
+        let path = Path::new(path);
+        let key = Self::key(path);
+        let size = std::fs::metadata(path).map(|x| x.len()).unwrap_or_default();
+        if self.state.files.get(&key).is_some_and(|x| x.size == size) {
+            log::info!("[watch] {key} was already processed");
+            return None;
+        }
+        let output_folder = match &self.output {
+            Some(output) => output.join(self.relative_folder(path)),
+            None => path.parent()?.to_path_buf()
+        };
+        let gyro_file = find_sidecar_gyro(path);
+
+        let mut data = additional_data.clone();
+        data["output"]["output_folder"] = path_to_url(&format!("{}/", output_folder.to_string_lossy())).into();
+        if data["synchronization"].get("do_autosync").is_none() {
+            data["synchronization"]["do_autosync"] = true.into();
+        }
+        if !self.dry_run {
+            if let Err(e) = std::fs::create_dir_all(&output_folder) {
+                log::error!("[watch] Failed to create {}: {e:?}", output_folder.display());
+            }
+        }
+
+        self.state.files.insert(key.clone(), WatchedFile { size, status: "queued".into(), output_folder: output_folder.to_string_lossy().to_string(), error: String::new() });
+        self.log(&key, &format!("New file, gyro: {}, output folder: {}", gyro_file.as_ref().map(|x| x.display().to_string()).unwrap_or("from the video".into()), output_folder.display()));
+        if self.dry_run {
+            self.state.files.remove(&key);
+            log::info!("[watch] Dry run, not rendering");
+            return None;
+        }
+        self.save();
+        Some((gyro_file.map(|x| path_to_url(&x.to_string_lossy())).unwrap_or_default(), data.to_string()))
+    }
+
+    fn queued(&mut self, job_id: u32, path: &str) {
+        let key = Self::key(Path::new(path));
+        self.log(&key, &format!("Added to the queue as job {job_id:08x}"));
+        self.jobs.insert(job_id, key);
+    }
+
+    /// Records the result of a job, returns false if it's not from the watch mode
+    fn job_finished(&mut self, job_id: u32, error: &str) -> bool {
+        let Some(key) = self.jobs.remove(&job_id) else { return false; };
+        if error.is_empty() {
+            self.log(&key, "Done");
+            if let Some(x) = self.state.files.get_mut(&key) { x.status = "done".into(); }
+        } else {
+            self.log(&key, &format!("Failed: {error}"));
+            if let Some(x) = self.state.files.get_mut(&key) { x.status = "failed".into(); x.error = error.to_owned(); }
+            if let Some(failed) = &self.failed {
+                let path = PathBuf::from(&key);
+                let target = failed.join(self.relative_folder(&path));
+                let moved = std::fs::create_dir_all(&target).and_then(|_| {
+                    for x in std::iter::once(path.clone()).chain(find_sidecar_gyro(&path)) {
+                        std::fs::rename(&x, target.join(x.file_name().unwrap_or_default()))?;
+                    }
+                    Ok(())
+                });
+                match moved {
+                    // The file is gone, so it's new again when it's copied back
+                    Ok(()) => { self.log(&key, &format!("Moved to {}", target.display())); self.state.files.remove(&key); },
+                    Err(e) => self.log(&key, &format!("Failed to move to {}: {e:?}", target.display())),
+                }
+            }
+        }
+        self.save();
+        true
+    }
+}
+
+// Gyro log with the same name as the video, eg. `C0001.gcsv` for `C0001.MP4`
+fn find_sidecar_gyro(path: &Path) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_string_lossy().to_ascii_lowercase();
+    std::fs::read_dir(path.parent()?).ok()?.flatten().map(|x| x.path()).find(|x| {
+        x != path
+            && x.file_stem().is_some_and(|s| s.to_string_lossy().to_ascii_lowercase() == stem)
+            && x.extension().is_some_and(|e| WATCH_GYRO_EXTENSIONS.contains(&e.to_string_lossy().to_ascii_lowercase().as_str()))
+    })
+}
+
 // TODO: replace with `notify` crate
-fn watch_folder<F: FnMut(String)>(path: String, cb: F) -> bool {
+// `pending` are the files which arrived before watching, they are processed once their size is stable like the new ones
+fn watch_folder<F: FnMut(String)>(path: String, pending: Vec<String>, cb: F) -> bool {
     if path.is_empty() { return false; }
     if !std::path::Path::new(&path).exists() { log::info!("{} doesn't exist.", path); return false; }
 
     let path = QString::from(path);
+    let pending = QString::from(pending.join("\n"));
     let func: Box<dyn FnMut(String)> = Box::new(cb);
     let cb_ptr = Box::into_raw(func);
-    cpp!(unsafe [path as "QString", cb_ptr as "TraitObject2"] -> bool as "bool" {
+    cpp!(unsafe [path as "QString", pending as "QString", cb_ptr as "TraitObject2"] -> bool as "bool" {
         int argc = 0;
-        globalApp = new QCoreApplication(argc, nullptr);
+        if (!globalApp) globalApp = new QCoreApplication(argc, nullptr);
+        const QStringList pendingList = pending.split('\n', Qt::SkipEmptyParts);
 
         auto w = new QFileSystemWatcher();
         auto existing = new QStringList();
@@ -628,8 +1048,14 @@ fn watch_folder<F: FnMut(String)>(path: String, cb: F) -> bool {
             auto i = it.fileInfo();
             if (i.fileName() == "..") continue;
             if (i.isDir()) w->addPath(i.absoluteFilePath());
-            if (i.isFile()) existing->append(i.absoluteFilePath());
+            if (i.isFile()) {
+                if (pendingList.contains(i.absoluteFilePath()))
+                    (*paths)[i.absolutePath()].insert(i.absoluteFilePath(), 0);
+                else
+                    existing->append(i.absoluteFilePath());
+            }
         }
+        if (!pendingList.isEmpty()) t->start(1000);
         QObject::connect(w, &QFileSystemWatcher::directoryChanged, [=](const QString &file) {
             auto &paths2 = (*paths)[file];
 
@@ -644,3 +1070,93 @@ fn watch_folder<F: FnMut(String)>(path: String, cb: F) -> bool {
         return !w->directories().isEmpty();
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gyroflow_watch_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+    fn touch(path: &Path, data: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn sidecar_gyro() {
+        let dir = temp_dir("sidecar");
+        for x in ["C0001.MP4", "c0001.GCSV", "C0002.MP4", "C0002.xml", "C0003.mp4", "C0003.mp4.gcsv", "C0004.mov", "C0004.bbl"] {
+            touch(&dir.join(x), "");
+        }
+        // Case insensitive, with the same stem only
+        assert_eq!(find_sidecar_gyro(&dir.join("C0001.MP4")), Some(dir.join("c0001.GCSV")));
+        assert_eq!(find_sidecar_gyro(&dir.join("C0004.mov")), Some(dir.join("C0004.bbl")));
+        assert_eq!(find_sidecar_gyro(&dir.join("C0002.MP4")), None);
+        assert_eq!(find_sidecar_gyro(&dir.join("C0003.mp4")), None);
+        // The gyro log itself isn't its own sidecar
+        assert_eq!(find_sidecar_gyro(&dir.join("C0004.bbl")), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn plan() {
+        let dir = temp_dir("plan");
+        let (root, output) = (dir.join("in"), dir.join("out"));
+        let video = root.join("day1").join("C0001.MP4");
+        touch(&video, "1234");
+        touch(&root.join("day1").join("C0001.gcsv"), "");
+        let additional_data = serde_json::json!({ "output": { "codec": "H.264/AVC" }, "synchronization": { "max_sync_points": 3 } });
+
+        let mut watcher = Watcher::with_state_file(Some(output.to_string_lossy().to_string()), None, false, dir.join("state.json"));
+        watcher.add_root(&root.to_string_lossy());
+        let video_key = Watcher::key(&video);
+        // Existing when the folder is watched for the first time
+        assert_eq!(watcher.state.files[&video_key].status, "skipped");
+        assert!(watcher.plan(&video.to_string_lossy(), &additional_data).is_none());
+
+        // A new file goes to the same subfolder of the output, with its gyro log and autosync enabled
+        let video2 = root.join("day1").join("C0002.MP4");
+        touch(&video2, "1234");
+        touch(&root.join("day1").join("C0002.gcsv"), "");
+        let (gyro_url, data) = watcher.plan(&video2.to_string_lossy(), &additional_data).unwrap();
+        assert_eq!(gyro_url, path_to_url(&root.join("day1").join("C0002.gcsv").to_string_lossy()));
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["output"]["output_folder"], path_to_url(&format!("{}/", output.join("day1").to_string_lossy())));
+        assert_eq!(data["output"]["codec"], "H.264/AVC");
+        assert_eq!(data["synchronization"]["do_autosync"], true);
+        assert_eq!(data["synchronization"]["max_sync_points"], 3);
+        assert!(output.join("day1").is_dir());
+        assert_eq!(watcher.state.files[&Watcher::key(&video2)].status, "queued");
+        assert!(watcher.plan(&video2.to_string_lossy(), &additional_data).is_none());
+
+        // Persisted, and a file which changed is processed again
+        let mut restarted = Watcher::with_state_file(Some(output.to_string_lossy().to_string()), None, false, dir.join("state.json"));
+        // Only the gyro log which arrived after the first scan is new, the video was planned already
+        assert_eq!(restarted.add_root(&root.to_string_lossy()), vec![Watcher::key(&root.join("day1").join("C0002.gcsv"))]);
+        assert!(restarted.plan(&video2.to_string_lossy(), &additional_data).is_none());
+        touch(&video2, "123456");
+        assert!(restarted.plan(&video2.to_string_lossy(), &additional_data).is_some());
+
+        // Without the output folder it's rendered next to the video. An explicit autosync setting is kept
+        let mut watcher = Watcher::with_state_file(None, None, false, dir.join("state2.json"));
+        let video3 = root.join("C0003.MP4");
+        touch(&video3, "1234");
+        let (gyro_url, data) = watcher.plan(&video3.to_string_lossy(), &serde_json::json!({ "synchronization": { "do_autosync": false } })).unwrap();
+        assert_eq!(gyro_url, "");
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["output"]["output_folder"], path_to_url(&format!("{}/", root.to_string_lossy())));
+        assert_eq!(data["synchronization"]["do_autosync"], false);
+
+        // The dry run doesn't record anything
+        let mut watcher = Watcher::with_state_file(None, None, true, dir.join("state3.json"));
+        assert!(watcher.plan(&video3.to_string_lossy(), &additional_data).is_none());
+        assert!(watcher.state.files.is_empty());
+        assert!(!dir.join("state3.json").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,366 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// JSON-RPC 2.0 control server, for driving the app from other tools without the UI.
+// It listens on localhost only, with one JSON message per line. The first request of every connection must be `auth`
+// with the token from `--server-token`, or the generated one printed at startup when it's not set.
+// The methods map directly to the `StabilizationManager` of a render queue job and to the `RenderQueue`, so the behavior is the same as in the app and the CLI:
+//   project.load {url, gyro_url?, output?, synchronization?} -> {job_id}   Video or project file, with an optional gyro log
+//   project.save {job_id, url, type?}                                      type is Simple, WithGyroData or WithProcessedData
+//   telemetry.load {job_id, url, options?}
+//   sync.start {job_id, params?} -> {offsets}                              Streams `sync.progress` until done
+//   params.get {job_id, path?}                                              Project data, or the value at a JSON pointer
+//   params.set {job_id, data}                                               Applied like a preset
+//   keyframes.get {job_id, type}, keyframes.set {job_id, type, timestamp_us, value, easing?}, keyframes.remove {job_id, type, timestamp_us}
+//   render.start, render.pause, render.stop, render.status, render.cancel {job_id}, render.reset {job_id}, render.remove {job_id}
+//   shutdown
+// Notifications: render.progress, render.error, render.status, sync.progress, telemetry.progress
+
+use cpp::*;
+use gyroflow_core::{ StabilizationManager, GyroflowProjectType, keyframes::{ KeyframeType, Easing } };
+use qmetaobject::QString;
+use serde_json::{ json, Value };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{ BufRead, BufReader, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::str::FromStr;
+use std::sync::{ Arc, mpsc, atomic::AtomicBool };
+use parking_lot::Mutex;
+use subtle::ConstantTimeEq;
+use crate::rendering::{ self, render_queue::RenderQueue };
+use crate::util;
+
+cpp! {{
+    #include <QCoreApplication>
+}}
+macro_rules! connect {
+    ($obj_ptr:ident, $obj_borrowed:ident, $signal:ident, $cb:expr) => {
+        qmetaobject::connect($obj_ptr, $obj_borrowed.$signal.to_cpp_representation(&*$obj_borrowed), $cb);
+    };
+}
+
+const PARSE_ERROR: i64      = -32700;
+const INVALID_REQUEST: i64  = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64   = -32602;
+const SERVER_ERROR: i64     = -32000;
+const UNAUTHORIZED: i64     = -32001;
+
+struct RpcError(i64, String);
+impl<E: std::fmt::Debug> From<E> for RpcError {
+    fn from(e: E) -> Self { Self(SERVER_ERROR, format!("{e:?}")) }
+}
+type RpcResult = Result<Value, RpcError>;
+
+type QueueCall = Box<dyn FnOnce(&mut RenderQueue) + Send>;
+type Waiters = HashMap<u32, mpsc::Sender<Result<(), String>>>;
+
+#[derive(Clone)]
+struct Clients(Arc<Mutex<Vec<Arc<Mutex<TcpStream>>>>>);
+impl Clients {
+    fn notify(&self, method: &str, params: Value) {
+        let line = format!("{}\n", json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+        self.0.lock().retain(|x| x.lock().write_all(line.as_bytes()).is_ok());
+    }
+}
+
+struct Server {
+    token: String,
+    additional_data: Value, // Default output and sync settings
+    clients: Clients,
+    waiters: Arc<Mutex<Waiters>>,
+    on_queue: Box<dyn Fn(QueueCall) + Send + Sync>,
+}
+
+impl Server {
+    // Runs `cb` on the main thread, where the queue lives
+    fn with_queue<R: Send + 'static>(&self, cb: impl FnOnce(&mut RenderQueue) -> R + Send + 'static) -> R {
+        let (tx, rx) = mpsc::channel();
+        (self.on_queue)(Box::new(move |queue| { let _ = tx.send(cb(queue)); }));
+        rx.recv().expect("The event loop has stopped")
+    }
+
+    fn stab_for_job(&self, params: &Value) -> Result<Arc<StabilizationManager>, RpcError> {
+        let job_id = param::<u32>(params, "job_id")?;
+        self.with_queue(move |queue| queue.get_stab_for_job(job_id)).ok_or_else(|| RpcError(INVALID_PARAMS, format!("Unknown job {job_id}")))
+    }
+
+    fn call(&self, method: &str, params: &Value) -> RpcResult {
+        match method {
+            "project.load" => {
+                let url = param::<String>(params, "url")?;
+                let gyro_url = opt_param::<String>(params, "gyro_url")?.unwrap_or_default();
+                let mut additional_data = self.additional_data.clone();
+                for key in ["output", "synchronization"] {
+                    if let Some(v) = params.get(key) {
+                        gyroflow_core::util::merge_json(additional_data.get_mut(key).unwrap(), v);
+                    }
+                }
+                let (tx, rx) = mpsc::channel();
+                let waiters = self.waiters.clone();
+                let job_id = self.with_queue(move |queue| {
+                    let job_id = queue.add_file(url, gyro_url, additional_data.to_string());
+                    waiters.lock().insert(job_id, tx);
+                    job_id
+                });
+                rx.recv()?.map_err(|e| RpcError(SERVER_ERROR, e))?;
+                Ok(json!({ "job_id": job_id }))
+            },
+            "project.save" => {
+                let stab = self.stab_for_job(params)?;
+                let typ = GyroflowProjectType::from_str(&opt_param::<String>(params, "type")?.unwrap_or("Simple".into())).map_err(invalid_params)?;
+                stab.export_gyroflow_file(&param::<String>(params, "url")?, typ, "{}")?;
+                Ok(Value::Null)
+            },
+            "telemetry.load" => {
+                let stab = self.stab_for_job(params)?;
+                let options = opt_param(params, "options")?.unwrap_or_default();
+                let clients = self.clients.clone();
+                let job_id = param::<u32>(params, "job_id")?;
+                stab.load_gyro_data(&param::<String>(params, "url")?, false, &options, |progress| {
+                    clients.notify("telemetry.progress", json!({ "job_id": job_id, "progress": progress }));
+                }, Arc::new(AtomicBool::new(false)))?;
+                stab.recompute_blocking();
+                Ok(Value::Null)
+            },
+            "sync.start" => {
+                let stab = self.stab_for_job(params)?;
+                let job_id = param::<u32>(params, "job_id")?;
+                let mut sync_settings = self.additional_data["synchronization"].clone();
+                if let Some(v) = params.get("params") {
+                    gyroflow_core::util::merge_json(&mut sync_settings, v);
+                }
+                // `autosync` skips the sync silently when the params don't parse
+                serde_json::from_value::<gyroflow_core::synchronization::SyncParams>(sync_settings.clone())
+                    .map_err(|e| RpcError(INVALID_PARAMS, format!("Invalid sync params: {e}")))?;
+                let proc_height = sync_settings.get("processing_resolution").and_then(|x| x.as_i64()).unwrap_or(720) as i32;
+                let clients = self.clients.clone();
+                let errors = Arc::new(Mutex::new(Vec::new()));
+                let errors2 = errors.clone();
+                RenderQueue::autosync(stab.clone(), sync_settings, move |progress| {
+                    clients.notify("sync.progress", json!({ "job_id": job_id, "progress": progress }));
+                }, move |(msg, arg): (String, String)| {
+                    errors2.lock().push(msg.replace("%1", &arg));
+                }, proc_height);
+                if let Some(e) = errors.lock().first() {
+                    return Err(RpcError(SERVER_ERROR, e.clone()));
+                }
+                let offsets = stab.gyro.read().get_offsets().iter().map(|(k, v)| json!([k, v])).collect::<Vec<_>>();
+                Ok(json!({ "offsets": offsets }))
+            },
+            "params.get" => {
+                let stab = self.stab_for_job(params)?;
+                let data: Value = serde_json::from_str(&stab.export_gyroflow_data(GyroflowProjectType::Simple, "{}", None)?)?;
+                match opt_param::<String>(params, "path")? {
+                    Some(path) => data.pointer(&path).cloned().ok_or_else(|| RpcError(INVALID_PARAMS, format!("Unknown path {path}"))),
+                    None => Ok(data)
+                }
+            },
+            "params.set" => {
+                let stab = self.stab_for_job(params)?;
+                let data = params.get("data").filter(|x| x.is_object()).ok_or_else(|| RpcError(INVALID_PARAMS, "`data` must be an object".into()))?;
+                let mut is_preset = false;
+                stab.import_gyroflow_data(data.to_string().as_bytes(), true, None, |_| (), Arc::new(AtomicBool::new(false)), &mut is_preset, false)?;
+                stab.recompute_blocking();
+                Ok(Value::Null)
+            },
+            "keyframes.get" => {
+                let stab = self.stab_for_job(params)?;
+                let typ = param::<KeyframeType>(params, "type")?;
+                Ok(serde_json::to_value(stab.keyframes.read().get_keyframes(&typ).cloned().unwrap_or_default())?)
+            },
+            "keyframes.set" => {
+                let stab = self.stab_for_job(params)?;
+                let (typ, timestamp_us) = (param::<KeyframeType>(params, "type")?, param::<i64>(params, "timestamp_us")?);
+                stab.set_keyframe(&typ, timestamp_us, param::<f64>(params, "value")?);
+                if let Some(easing) = opt_param::<Easing>(params, "easing")? {
+                    stab.set_keyframe_easing(&typ, timestamp_us, easing);
+                }
+                Ok(Value::Null)
+            },
+            "keyframes.remove" => {
+                let stab = self.stab_for_job(params)?;
+                stab.remove_keyframe(&param::<KeyframeType>(params, "type")?, param::<i64>(params, "timestamp_us")?);
+                Ok(Value::Null)
+            },
+            "render.start" => { self.with_queue(|queue| queue.start()); Ok(Value::Null) },
+            "render.pause" => { self.with_queue(|queue| queue.pause()); Ok(Value::Null) },
+            "render.stop"  => { self.with_queue(|queue| queue.stop());  Ok(Value::Null) },
+            "render.cancel" => { let job_id = param(params, "job_id")?; self.with_queue(move |queue| queue.cancel_job(job_id)); Ok(Value::Null) },
+            "render.reset"  => { let job_id = param(params, "job_id")?; self.with_queue(move |queue| queue.reset_job(job_id));  Ok(Value::Null) },
+            "render.remove" => { let job_id = param(params, "job_id")?; self.with_queue(move |queue| queue.remove(job_id));     Ok(Value::Null) },
+            "render.status" => Ok(self.with_queue(queue_status)),
+            "log.recent" => Ok(json!(gyroflow_core::logging::recent_records(opt_param::<usize>(params, "limit")?.unwrap_or(500)))),
+            "log.set_levels" => {
+                gyroflow_core::logging::set_levels(&param::<String>(params, "levels")?).map_err(|e| RpcError(INVALID_PARAMS, e))?;
+                Ok(Value::Null)
+            },
+            "shutdown" => {
+                self.with_queue(|_| cpp!(unsafe [] { qApp->quit(); }));
+                Ok(Value::Null)
+            },
+            _ => Err(RpcError(METHOD_NOT_FOUND, format!("Unknown method {method}")))
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) {
+        let Ok(writer) = stream.try_clone() else { return; };
+        let writer = Arc::new(Mutex::new(writer));
+        let mut authenticated = false;
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else { break; };
+            if line.trim().is_empty() { continue; }
+            let (id, result) = match serde_json::from_str::<Value>(&line) {
+                Ok(req) => {
+                    let id = req.get("id").cloned();
+                    let params = req.get("params").cloned().unwrap_or(Value::Null);
+                    let result = match req.get("method").and_then(|x| x.as_str()) {
+                        Some("auth") => {
+                            // Constant time, so the token can't be guessed from the time it takes to reject it
+                            authenticated = params.get("token").and_then(|x| x.as_str()).is_some_and(|x| bool::from(x.as_bytes().ct_eq(self.token.as_bytes())));
+                            if authenticated {
+                                self.clients.0.lock().push(writer.clone());
+                                Ok(Value::Bool(true))
+                            } else {
+                                Err(RpcError(UNAUTHORIZED, "Invalid token".into()))
+                            }
+                        },
+                        Some(_) if !authenticated => Err(RpcError(UNAUTHORIZED, "Call `auth` first".into())),
+                        Some(method) => self.call(method, &params),
+                        None => Err(RpcError(INVALID_REQUEST, "Missing method".into()))
+                    };
+                    if id.is_none() { continue; } // Notification
+                    (id, result)
+                },
+                Err(e) => (Some(Value::Null), Err(RpcError(PARSE_ERROR, e.to_string())))
+            };
+            let response = match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err(RpcError(code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+            };
+            if writer.lock().write_all(format!("{response}\n").as_bytes()).is_err() { break; }
+        }
+        self.clients.0.lock().retain(|x| !Arc::ptr_eq(x, &writer));
+    }
+}
+
+fn param<T: serde::de::DeserializeOwned>(params: &Value, name: &str) -> Result<T, RpcError> {
+    opt_param(params, name)?.ok_or_else(|| RpcError(INVALID_PARAMS, format!("Missing parameter `{name}`")))
+}
+fn opt_param<T: serde::de::DeserializeOwned>(params: &Value, name: &str) -> Result<Option<T>, RpcError> {
+    match params.get(name) {
+        Some(v) if !v.is_null() => serde_json::from_value(v.clone()).map(Some).map_err(|e| RpcError(INVALID_PARAMS, format!("`{name}`: {e}"))),
+        _ => Ok(None)
+    }
+}
+fn invalid_params(e: serde_json::Error) -> RpcError { RpcError(INVALID_PARAMS, e.to_string()) }
+
+fn queue_status(queue: &mut RenderQueue) -> Value {
+    let items = queue.queue.borrow().iter().map(|x| json!({
+        "job_id":        x.job_id,
+        "input_file":    x.input_file.to_string(),
+        "output_path":   x.display_output_path.to_string(),
+        "status":        format!("{:?}", x.get_status()),
+        "current_frame": x.current_frame,
+        "total_frames":  x.total_frames,
+        "error":         x.error_string.to_string(),
+    })).collect::<Vec<_>>();
+    json!({ "status": queue.status.to_string(), "jobs": items })
+}
+
+/// Runs the server at `127.0.0.1:port` until `shutdown`, 0 picks a free port
+pub fn run(port: u16, token: Option<String>) {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(x) => x,
+        Err(e) => { log::error!("Failed to start the control server: {e:?}"); return; }
+    };
+    // A token given on the command line is never printed back
+    let token = token.filter(|x| !x.is_empty());
+    let generated_token = if token.is_none() {
+        // It's a secret, so from the random source of the OS
+        let mut bytes = [0u8; 24];
+        if let Err(e) = getrandom::fill(&mut bytes) {
+            log::error!("Failed to generate the control server token: {e:?}");
+            return;
+        }
+        Some(bytes.iter().map(|x| format!("{x:02x}")).collect::<String>())
+    } else {
+        None
+    };
+    let token = token.or_else(|| generated_token.clone()).unwrap_or_default();
+
+    let stab = Arc::new(StabilizationManager::default());
+    stab.lens_profile_db.write().load_all();
+    let mut queue = RenderQueue::new(stab.clone());
+
+    rendering::init_log();
+    if let Some((name, _list_name)) = gyroflow_core::gpu::initialize_contexts() {
+        rendering::set_gpu_type_from_name(&name);
+    }
+    let additional_data = crate::cli::setup_defaults(stab, &mut queue);
+
+    cpp!(unsafe [] {
+        int argc = 0;
+        if (!qApp) new QCoreApplication(argc, nullptr);
+    });
+
+    let queue = RefCell::new(queue);
+    let queue_ptr = unsafe { qmetaobject::QObjectPinned::new(&queue).get_or_create_cpp_object() };
+    let clients = Clients(Default::default());
+    let waiters = Arc::new(Mutex::new(Waiters::new()));
+    let queue_cell = &queue;
+
+    unsafe {
+        let q = queue.borrow();
+        let clients2 = clients.clone();
+        connect!(queue_ptr, q, render_progress, move |job_id: &u32, progress: &f64, current_frame: &usize, total_frames: &usize, finished: &bool, _start_time: &f64, _is_conversion: &bool| {
+            clients2.notify("render.progress", json!({ "job_id": job_id, "progress": progress, "current_frame": current_frame, "total_frames": total_frames, "finished": finished }));
+        });
+        let clients2 = clients.clone();
+        connect!(queue_ptr, q, status_changed, move || {
+            clients2.notify("render.status", queue_status(&mut *queue_cell.as_ptr()));
+        });
+        let (clients2, waiters2) = (clients.clone(), waiters.clone());
+        connect!(queue_ptr, q, error, move |job_id: &u32, text: &QString, arg: &QString, _callback: &QString| {
+            let text = text.to_string().replace("%1", &arg.to_string());
+            if let Some(tx) = waiters2.lock().remove(job_id) {
+                let _ = tx.send(Err(text.clone()));
+            }
+            clients2.notify("render.error", json!({ "job_id": job_id, "text": text }));
+        });
+        let waiters2 = waiters.clone();
+        connect!(queue_ptr, q, processing_done, move |job_id: &u32, _by_preset: &bool| {
+            let queue = &mut *queue_cell.as_ptr();
+            queue.jobs_added.remove(job_id);
+            if let Some(tx) = waiters2.lock().remove(job_id) {
+                let _ = tx.send(Ok(()));
+            }
+        });
+    }
+
+    let server = Arc::new(Server {
+        token: token.clone(),
+        additional_data,
+        clients,
+        waiters,
+        on_queue: Box::new(util::qt_queued_callback_mut(&*queue.borrow(), |queue, cb: QueueCall| cb(queue))),
+    });
+
+    let addr = listener.local_addr().map(|x| x.to_string()).unwrap_or_default();
+    println!("Control server listening on {addr}");
+    if let Some(generated_token) = generated_token {
+        println!("Generated control server token: {generated_token}");
+    }
+    let _ = std::io::stdout().flush();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let server = server.clone();
+            std::thread::spawn(move || server.handle_connection(stream));
+        }
+    });
+
+    cpp!(unsafe [] {
+        qApp->exec();
+    });
+}
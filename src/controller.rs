@@ -42,6 +42,8 @@ struct CalibrationItem {
     pub timestamp_us: i64,
     pub sharpness: f64,
     pub is_forced: bool,
+    pub score: f64,
+    pub score_info: QString,
 }
 
 #[derive(Default, QObject)]
@@ -57,6 +59,11 @@ pub struct Controller {
     get_preset_contents: qt_method!(fn(&mut self, url_or_id: QString) -> QString),
     export_lens_profile: qt_method!(fn(&mut self, url: QUrl, info: QJsonObject, upload: bool)),
     export_lens_profile_filename: qt_method!(fn(&mut self, info: QJsonObject) -> QString),
+    add_focal_length_to_lens_profile: qt_method!(fn(&mut self, url: QUrl, focal_length: f64)),
+    add_video_mode_to_lens_profile: qt_method!(fn(&mut self, url: QUrl, video_mode: QString)),
+    set_calibration_board: qt_method!(fn(&mut self, board: QString)),
+    set_calibration_auto_select: qt_method!(fn(&mut self, enabled: bool)),
+    get_calibration_frame_scores: qt_method!(fn(&self) -> QString),
 
     set_of_method: qt_method!(fn(&self, v: u32)),
     start_autosync: qt_method!(fn(&mut self, timestamps_fract: String, sync_params: String, mode: String)),
@@ -64,21 +71,44 @@ pub struct Controller {
     update_frequency_graph: qt_method!(fn(&self, graph: QJSValue, idx: usize, ts: f64, sr: f64, fft_size: usize)),
     update_keyframes_view: qt_method!(fn(&self, kfview: QJSValue)),
     rolling_shutter_estimated: qt_signal!(rolling_shutter: f64),
+    lens_validation_finished: qt_signal!(report: QString), // JSON of `LensValidationReport`, empty if there was not enough data
+    motion_data_consistency_checked: qt_signal!(report: QString), // JSON of `ConsistencyReport`
+    residual_wobble_estimated: qt_signal!(report: QString), // JSON with the stats of `ResidualWobbleResult`
+    clear_residual_corrections: qt_method!(fn(&self)),
     estimate_bias: qt_method!(fn(&self, timestamp_fract: QString)),
     bias_estimated: qt_signal!(bx: f64, by: f64, bz: f64),
+    accept_detected_bias: qt_method!(fn(&self)),
     orientation_guessed: qt_signal!(orientation: QString),
+    export_imu_orientation_presets: qt_method!(fn(&self, include_builtin: bool) -> QString),
+
+    load_secondary_gyro: qt_method!(fn(&mut self, url: QUrl, max_offset_ms: f64)),
+    remove_secondary_gyro: qt_method!(fn(&mut self)),
+    set_gyro_stream: qt_method!(fn(&mut self, stream: String)),
+    set_gyro_gap_repair: qt_method!(fn(&mut self, method: String)),
+    secondary_gyro_loaded: qt_signal!(aligned: bool, time_offset_ms: f64, residual_dps: f64),
     get_optimal_sync_points: qt_method!(fn(&mut self, target_sync_points: usize) -> QString),
 
     start_autocalibrate: qt_method!(fn(&self, max_points: usize, every_nth_frame: usize, iterations: usize, max_sharpness: f64, custom_timestamp_ms: f64, no_marker: bool)),
 
     telemetry_loaded: qt_signal!(is_main_video: bool, filename: QString, camera: QString, additional_data: QJsonObject),
     lens_profile_loaded: qt_signal!(lens_json: QString, filepath: QString, checksum: QString),
+    lens_profile_candidates: qt_signal!(candidates: QString),
 
     set_smoothing_method: qt_method!(fn(&self, index: usize) -> QJsonArray),
     get_smoothing_max_angles: qt_method!(fn(&self) -> QJsonArray),
+    get_zoom_overflow_ranges: qt_method!(fn(&self) -> QJsonArray),
+    get_zoom_center_limits: qt_method!(fn(&self) -> QJsonArray),
+    get_static_zoom_exceeding_frames: qt_method!(fn(&self) -> QJsonArray),
+    get_zoom_data: qt_method!(fn(&self) -> QJsonArray),
+    export_zoom_data: qt_method!(fn(&self, url: QUrl, csv: bool)),
+    export_camera_transforms: qt_method!(fn(&self, url: QUrl, csv: bool, convention: QString, with_homography: bool)),
     get_smoothing_status: qt_method!(fn(&self) -> QJsonArray),
+    get_graph_data: qt_method!(fn(&self, from_ms: f64, to_ms: f64, samples: usize) -> QJsonObject),
     set_smoothing_param: qt_method!(fn(&self, name: QString, val: f64)),
     set_horizon_lock: qt_method!(fn(&self, lock_percent: f64, roll: f64)),
+    set_horizon_gravity_reference: qt_method!(fn(&self, strength: f64)),
+    set_axis_lock: qt_method!(fn(&self, pitch: bool, yaw: bool, roll: bool, horizon_reference: bool)),
+    set_axis_lock_target: qt_method!(fn(&self, pitch: f64, yaw: f64, roll: f64)),
     set_use_gravity_vectors: qt_method!(fn(&self, v: bool)),
     set_horizon_lock_integration_method: qt_method!(fn(&self, v: i32)),
     set_preview_resolution: qt_method!(fn(&mut self, target_height: i32, player: QJSValue)),
@@ -90,6 +120,8 @@ pub struct Controller {
     remove_offset: qt_method!(fn(&self, timestamp_us: i64)),
     clear_offsets: qt_method!(fn(&self)),
     offset_at_video_timestamp: qt_method!(fn(&self, timestamp_us: i64) -> f64),
+    set_offset_interpolation: qt_method!(fn(&mut self, mode: String)),
+    get_offset_drift: qt_method!(fn(&self) -> QString), // JSON of `DriftFit`, empty with fewer than two offsets
     offsets_model: qt_property!(RefCell<SimpleListModel<OffsetItem>>; NOTIFY offsets_updated),
     offsets_updated: qt_signal!(),
 
@@ -102,6 +134,8 @@ pub struct Controller {
 
     set_sync_lpf: qt_method!(fn(&self, lpf: f64)),
     set_imu_lpf: qt_method!(fn(&self, lpf: f64)),
+    set_imu_acc_lpf: qt_method!(fn(&self, lpf: f64)),
+    set_magnetometer_correction: qt_method!(fn(&self, enabled: bool, time_constant_s: f64)),
     set_imu_median_filter: qt_method!(fn(&self, size: i32)),
     set_imu_rotation: qt_method!(fn(&self, pitch_deg: f64, roll_deg: f64, yaw_deg: f64)),
     set_acc_rotation: qt_method!(fn(&self, pitch_deg: f64, roll_deg: f64, yaw_deg: f64)),
@@ -126,8 +160,13 @@ pub struct Controller {
     show_safe_area: qt_property!(bool; WRITE set_show_safe_area),
     frame_readout_time: qt_property!(f64; WRITE set_frame_readout_time),
     frame_readout_direction: qt_property!(i32; WRITE set_frame_readout_direction),
+    rolling_shutter_strength: qt_property!(f64; WRITE set_rolling_shutter_strength),
+    residual_correction_strength: qt_property!(f64; WRITE set_residual_correction_strength),
+    set_frame_readout_override: qt_method!(fn(&self, time: f64, direction: i32)),
 
     adaptive_zoom: qt_property!(f64; WRITE set_adaptive_zoom),
+    adaptive_zoom_look_ahead: qt_property!(f64; WRITE set_adaptive_zoom_look_ahead),
+    static_zoom_percentile: qt_property!(f64; WRITE set_static_zoom_percentile),
     zooming_center_x: qt_property!(f64; WRITE set_zooming_center_x),
     zooming_center_y: qt_property!(f64; WRITE set_zooming_center_y),
     zooming_method: qt_property!(i32; WRITE set_zooming_method),
@@ -135,6 +174,9 @@ pub struct Controller {
     additional_rotation_x: qt_property!(f64; WRITE set_additional_rotation_x),
     additional_rotation_y: qt_property!(f64; WRITE set_additional_rotation_y),
     additional_rotation_z: qt_property!(f64; WRITE set_additional_rotation_z),
+    horizon_compensation_pitch: qt_property!(f64; WRITE set_horizon_compensation_pitch),
+    horizon_compensation_roll: qt_property!(f64; WRITE set_horizon_compensation_roll),
+    horizon_compensation_fov: qt_property!(f64; WRITE set_horizon_compensation_fov),
     additional_translation_x: qt_property!(f64; WRITE set_additional_translation_x),
     additional_translation_y: qt_property!(f64; WRITE set_additional_translation_y),
     additional_translation_z: qt_property!(f64; WRITE set_additional_translation_z),
@@ -143,6 +185,8 @@ pub struct Controller {
     light_refraction_coefficient: qt_property!(f64; WRITE set_light_refraction_coefficient),
     set_video_speed: qt_method!(fn(&self, v: f64, s: bool, z: bool, zl: bool)),
     set_max_zoom: qt_method!(fn(&self, v: f64, iters: usize)),
+    set_limit_to_zoom_budget: qt_method!(fn(&self, v: bool)),
+    set_deterministic: qt_method!(fn(&self, v: bool)),
 
     input_horizontal_stretch: qt_property!(f64; WRITE set_input_horizontal_stretch),
     input_vertical_stretch: qt_property!(f64; WRITE set_input_vertical_stretch),
@@ -204,7 +248,10 @@ pub struct Controller {
 
     get_urls_from_gyroflow_file: qt_method!(fn(&mut self, url: QUrl) -> QStringList),
     import_gyroflow_file: qt_method!(fn(&mut self, url: QUrl)),
+    import_gyroflow_file_reloading_telemetry: qt_method!(fn(&mut self, url: QUrl)),
+    embedded_data_corrupted: qt_signal!(project_url: QUrl, details: QString, source_url: QString),
     import_gyroflow_data: qt_method!(fn(&mut self, data: QString)),
+    load_project_summary: qt_method!(fn(&self, url: QUrl) -> QJsonObject),
     gyroflow_file_loaded: qt_signal!(obj: QJsonObject),
     export_gyroflow_file: qt_method!(fn(&self, url: QUrl, typ: QString, additional_data: QJsonObject)),
     export_gyroflow_data: qt_method!(fn(&self, typ: QString, additional_data: QJsonObject) -> QString),
@@ -221,7 +268,19 @@ pub struct Controller {
     request_profile_ratings: qt_method!(fn(&self)),
 
     set_preview_pipeline: qt_method!(fn(&self, index: i32)),
+    set_preview_quality: qt_method!(fn(&mut self, mode: i32, player: QJSValue)),
+    set_preview_playing: qt_method!(fn(&mut self, playing: bool, player: QJSValue)),
+    preview_quality_level: qt_property!(QString; NOTIFY preview_quality_changed),
+    preview_quality_changed: qt_signal!(),
     set_gpu_decoding: qt_method!(fn(&self, enabled: bool)),
+    set_decoder_preference: qt_method!(fn(&self, preference: QString)),
+    available_decoders: qt_method!(fn(&self) -> QString),
+    set_frame_cache_budget: qt_method!(fn(&self, mb: u64)),
+    get_frame_cache_usage: qt_method!(fn(&self) -> QJsonObject),
+
+    set_log_levels: qt_method!(fn(&self, levels: QString) -> QString),
+    set_json_log: qt_method!(fn(&self, enabled: bool)),
+    get_recent_logs: qt_method!(fn(&self, limit: usize) -> QString),
 
     list_gpu_devices: qt_method!(fn(&self)),
     set_device: qt_method!(fn(&self, i: i32)),
@@ -230,6 +289,7 @@ pub struct Controller {
 
     set_digital_lens_name: qt_method!(fn(&self, name: String)),
     set_digital_lens_param: qt_method!(fn(&self, index: usize, value: f64)),
+    set_lens_flip: qt_method!(fn(&self, horizontal: bool, vertical: bool)),
 
     get_username: qt_method!(fn(&self) -> QString),
     copy_to_clipboard: qt_method!(fn(&self, text: QString)),
@@ -239,6 +299,7 @@ pub struct Controller {
     export_full_metadata: qt_method!(fn(&self, url: QUrl, gyro_url: QUrl)),
     export_parsed_metadata: qt_method!(fn(&self, url: QUrl)),
     export_gyro_data: qt_method!(fn(&self, url: QUrl, data: QJsonObject)),
+    export_gcsv: qt_method!(fn(&self, url: QUrl, options: QJsonObject)),
 
     message: qt_signal!(text: QString, arg: QString, callback: QString, id: QString),
     error: qt_signal!(text: QString, arg: QString, callback: QString),
@@ -248,10 +309,21 @@ pub struct Controller {
     set_keyframe: qt_method!(fn(&self, typ: String, timestamp_us: i64, value: f64)),
     set_keyframe_easing: qt_method!(fn(&self, typ: String, timestamp_us: i64, easing: String)),
     keyframe_easing: qt_method!(fn(&self, typ: String, timestamp_us: i64) -> String),
+    set_keyframe_bezier: qt_method!(fn(&self, typ: String, timestamp_us: i64, x1: f64, y1: f64, x2: f64, y2: f64)),
+    keyframe_bezier: qt_method!(fn(&self, typ: String, timestamp_us: i64) -> QJsonArray),
     set_keyframe_timestamp: qt_method!(fn(&self, typ: String, id: u32, timestamp_us: i64)),
     keyframe_id: qt_method!(fn(&self, typ: String, timestamp_us: i64) -> u32),
     remove_keyframe: qt_method!(fn(&self, typ: String, timestamp_us: i64)),
     clear_keyframes_type: qt_method!(fn(&self, typ: String)),
+    shift_keyframes: qt_method!(fn(&self, types: String, delta_us: i64) -> QJsonObject),
+    scale_keyframes: qt_method!(fn(&self, types: String, anchor_us: i64, factor: f64) -> QJsonObject),
+    copy_keyframes: qt_method!(fn(&self, from: String, to: String, value_scale: f64, value_offset: f64) -> QJsonObject),
+    remove_keyframes_in_range: qt_method!(fn(&self, types: String, from_us: i64, to_us: i64) -> QJsonObject),
+    replace_keyframes: qt_method!(fn(&self, sets: QJsonObject)),
+    export_keyframes: qt_method!(fn(&self, url: QUrl, types: String, normalized: bool)),
+    set_keyframe_quantization: qt_method!(fn(&self, enabled: bool)),
+    quantize_all_keyframes: qt_method!(fn(&self) -> QJsonObject),
+    import_keyframes: qt_method!(fn(&self, url: QUrl, policy: String) -> QJsonObject),
     keyframe_value_at_video_timestamp: qt_method!(fn(&self, typ: String, timestamp_ms: f64) -> QJSValue),
     is_keyframed: qt_method!(fn(&self, typ: String) -> bool),
     set_prevent_recompute: qt_method!(fn(&self, v: bool)),
@@ -306,6 +378,7 @@ impl Controller {
         Self {
             preview_resolution: -1,
             processing_resolution: 720,
+            preview_quality_level: QString::from("Full"),
             ..Default::default()
         }
     }
@@ -335,6 +408,7 @@ impl Controller {
             image_sequence_fps: self.image_sequence_fps,
             preset_name: None,
             preset_output_size: None,
+            preserved_project_data: None,
         };
         self.input_file_url_changed();
         self.project_file_url_changed();
@@ -394,6 +468,10 @@ impl Controller {
         sync_params.time_per_syncpoint *= 1000.0; // s to ms                    // 500ms
         sync_params.search_size        *= 1000.0; // s to ms                    // 500ms
         sync_params.every_nth_frame     = sync_params.every_nth_frame.max(1);   // 1
+        if mode == "residual_wobble" {
+            // The optical flow is needed between all consecutive frames
+            sync_params.every_nth_frame = 1;
+        }
 
         // false, default mode is "synchronizeurce"
         let for_rs = mode == "estimate_rolling_shutter";
@@ -418,29 +496,8 @@ impl Controller {
                     this.rolling_shutter_estimated(offs.1);
                 }
             } else {
-                let mut gyro = this.stabilizer.gyro.write();
-                gyro.prevent_recompute = true;
-
                 //let my_offsets = [(3403.4, 49.07149195073893, 2469.454571794784), (10210.2, 49.02560488334704, 1687.1520000469884), (17000.3165, 49.38418205303837, 1856.5180621988166), (23790.4335, 50.2977951166321, 1681.702319836711), (30597.2335, 50.46544919417218, 3068.1429766153424)];
-                for x in offsets {
-                    ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4})", x.0, x.1, x.2);
-                    let new_ts = ((x.0 - x.1) * 1000.0) as i64;
-                    { // Check the offset
-                        let sync_data = this.stabilizer.sync_data.read();
-                        if !sync_data.rank.is_empty() {
-                            let index = ((x.0 - x.1) as f64 / (sync_data.ratio * 1000.0)).round() as usize;
-                            if index < sync_data.rank.len() && sync_data.rank[index] < 20.0 {
-                                continue;
-                            }
-                        }
-                    }
-                    // Remove existing offsets within 100ms range
-                    gyro.remove_offsets_near(new_ts, 100.0);
-                    gyro.set_offset(new_ts, x.1);
-                }
-                gyro.prevent_recompute = false;
-                gyro.adjust_offsets();
-                this.stabilizer.keyframes.write().update_gyro(&gyro);
+                this.stabilizer.apply_sync_offsets(&offsets);
                 this.stabilizer.invalidate_zooming();
             }
             this.update_offset_model();
@@ -448,8 +505,29 @@ impl Controller {
         });
         let set_orientation = util::qt_queued_callback_mut(self, move |this, orientation: String| {
             ::log::info!("Setting orientation {}", &orientation);
+            if gyroflow_core::settings::get_bool("saveGuessedOrientation", true) {
+                this.stabilizer.save_imu_orientation_preset(&orientation);
+            }
             this.orientation_guessed(QString::from(orientation));
         });
+        let lens_validated = util::qt_queued_callback_mut(self, move |this, report: String| {
+            this.lens_validation_finished(QString::from(report));
+        });
+        let consistency_checked = util::qt_queued_callback_mut(self, move |this, report: String| {
+            this.motion_data_consistency_checked(QString::from(report));
+        });
+        let residual_wobble_estimated = util::qt_queued_callback_mut(self, move |this, result: synchronization::ResidualWobbleResult| {
+            this.stabilizer.set_residual_corrections(&result);
+            this.stabilizer.invalidate_zooming();
+            this.residual_wobble_estimated(QString::from(serde_json::json!({
+                "frames":        result.corrections.len(),
+                "pairs":         result.pairs,
+                "skipped_pairs": result.skipped_pairs,
+                "rms_deg":       result.rms_deg,
+                "max_deg":       result.max_deg,
+            }).to_string()));
+            this.request_recompute();
+        });
         let err = util::qt_queued_callback_mut(self, |this, (msg, mut arg): (String, String)| {
             arg.push_str("\n\n");
             arg.push_str(&rendering::get_log());
@@ -469,12 +547,30 @@ impl Controller {
             sync.on_progress(move |percent, ready, total| {
                 progress((percent, ready, total));
             });
-            sync.on_finished(move |arg: Either<Vec<(f64, f64, f64)>, Option<(String, f64)>>| {
-                match arg {
-                    Either::Left(offsets) => set_offsets(offsets),
-                    Either::Right(Some(orientation)) => set_orientation(orientation.0),
-                    _=> ()
+            let sync_err = err.clone();
+            sync.on_finished(move |arg: Either<Result<Vec<(f64, f64, f64)>, synchronization::PartialSync>, Result<(String, f64), synchronization::SyncError>>| {
+                let error = match arg {
+                    Either::Left(Ok(offsets)) => { set_offsets(offsets); None },
+                    Either::Left(Err(e)) => {
+                        // Keep the sync points found before it failed
+                        if !e.offsets.is_empty() { set_offsets(e.offsets); }
+                        Some(e.error)
+                    },
+                    Either::Right(Ok(orientation)) => { set_orientation(orientation.0); None },
+                    Either::Right(Err(e)) => Some(e),
                 };
+                if let Some(e) = error.filter(|e| *e != synchronization::SyncError::Cancelled) {
+                    sync_err(("An error occured: %1".to_string(), format!("Synchronization failed: {e}")));
+                }
+            });
+            sync.on_lens_validation(move |report| {
+                lens_validated(report.and_then(|x| serde_json::to_string(&x).ok()).unwrap_or_default());
+            });
+            sync.on_consistency_check(move |report| {
+                consistency_checked(serde_json::to_string(&report).unwrap_or_default());
+            });
+            sync.on_residual_wobble(move |result| {
+                residual_wobble_estimated(result);
             });
 
             // [(3150.1, 3650.1), (9950.3, 10450.3), (16750.5, 17250.5), (23550.7, 24050.7), (30350.9, 30850.9)]
@@ -567,6 +663,16 @@ impl Controller {
         }
     }
 
+    fn export_imu_orientation_presets(&self, include_builtin: bool) -> QString {
+        QString::from(gyroflow_core::gyro_source::orientation_presets::export(include_builtin))
+    }
+
+    fn accept_detected_bias(&mut self) {
+        if let Some(detected) = self.stabilizer.get_detected_gyro_bias() {
+            self.bias_estimated(detected.bias[0], detected.bias[1], detected.bias[2]);
+        }
+    }
+
     fn get_optimal_sync_points(&mut self, target_sync_points: usize) -> QString {
         QString::from(self.stabilizer.get_optimal_sync_points(target_sync_points).into_iter().map(|x| x.to_string()).join(";"))
     }
@@ -618,7 +724,7 @@ impl Controller {
 
             if !raw_imu.is_empty() {
                 let dt_ms = 1000.0 / sr;
-                let center_ts = ts - gyro.offset_at_video_timestamp(ts);
+                let center_ts = gyro.video_to_gyro_timestamp(ts);
                 let last_ts  = center_ts + dt_ms * (fft_size as f64)/2.0;
                 let mut sample_ts = last_ts.min(raw_imu.last().unwrap().timestamp_ms) - (fft_size as f64) * dt_ms;
                 sample_ts = sample_ts.max(0.0);
@@ -751,6 +857,9 @@ impl Controller {
             let load_lens = util::qt_queued_callback_mut(self, move |this, path: String| {
                 this.load_lens_profile(path.into());
             });
+            let lens_candidates = util::qt_queued_callback_mut(self, move |this, candidates: String| {
+                this.lens_profile_candidates(QString::from(candidates));
+            });
             let reload_lens = util::qt_queued_callback_mut(self, move |this, _| {
                 let lens = this.stabilizer.lens.read();
                 if this.lens_loaded || !lens.path_to_file.is_empty() {
@@ -783,8 +892,9 @@ impl Controller {
                             options.sample_index = Some(sample_index as usize);
                         }
 
-                        if let Err(e) = stab.load_gyro_data(&url, is_main_video, &options, progress, cancel_flag) {
-                            err(("An error occured: %1".to_string(), e.to_string()));
+                        match stab.load_gyro_data(&url, is_main_video, &options, progress, cancel_flag) {
+                            Ok(()) | Err(gyroflow_core::GyroflowCoreError::Cancelled) => { },
+                            Err(e) => err(("An error occured: %1".to_string(), e.to_string()))
                         }
                     }
                     // stab.recompute_smoothness();
@@ -800,6 +910,10 @@ impl Controller {
                     additional_obj.insert("contains_quats".to_owned(),    serde_json::Value::Bool(has_quats));
                     additional_obj.insert("contains_motion".to_owned(),   serde_json::Value::Bool(has_motion));
                     additional_obj.insert("has_accurate_timestamps".to_owned(), serde_json::Value::Bool(file_metadata.has_accurate_timestamps));
+                    additional_obj.insert("contains_magnetometer".to_owned(), serde_json::Value::Bool(file_metadata.raw_imu.iter().any(|x| x.magn.is_some())));
+                    additional_obj.insert("detected_bias".to_owned(),     serde_json::to_value(&gyro.detected_bias).unwrap_or_default());
+                    additional_obj.insert("gyro_gaps".to_owned(),         serde_json::to_value(gyro.gaps()).unwrap_or_default());
+                    additional_obj.insert("has_gps".to_owned(),           serde_json::Value::Bool(file_metadata.has_gps()));
                     additional_obj.insert("sample_rate".to_owned(),       serde_json::to_value(gyroflow_core::gyro_source::GyroSource::get_sample_rate(&*file_metadata)).unwrap());
                     let has_builtin_profile = file_metadata.lens_profile.as_ref().map(|y| y.is_object()).unwrap_or_default();   // false
                     let md_data = file_metadata.additional_data.clone();
@@ -814,12 +928,19 @@ impl Controller {
 
                     let camera_id = stab.camera_id.read();
 
-                    let id_str = camera_id.as_ref().map(|v| v.get_identifier_for_autoload()).unwrap_or_default();
-                    if is_main_video && !id_str.is_empty() && !has_builtin_profile {
+                    if let (Some(cam_id), true, false) = (camera_id.clone(), is_main_video, has_builtin_profile) {
+                        let id_str = cam_id.get_identifier_for_autoload();
                         let mut db = stab.lens_profile_db.write();
                         db.on_loaded(move |db| {
-                            if db.contains_id(&id_str) {
+                            if !id_str.is_empty() && db.contains_id(&id_str) {
                                 load_lens(id_str);
+                                return;
+                            }
+                            let candidates = db.find_candidates(&cam_id);
+                            match candidates.first() {
+                                Some(c) if c.score >= gyroflow_core::lens_profile::AUTO_APPLY_SCORE => load_lens(c.id.clone()),
+                                Some(_) => lens_candidates(serde_json::to_string(&candidates[..candidates.len().min(3)]).unwrap_or_default()),
+                                None => { }
                             }
                         });
                     }
@@ -878,22 +999,27 @@ impl Controller {
         self.preview_resolution = target_height;
         if let Some(vid) = player.to_qobject::<MDKVideoItem>() {
             let vid = unsafe { &mut *vid.as_ptr() }; // vid.borrow_mut()
+            self.update_surface_size(vid);
+        }
+    }
+    fn update_surface_size(&mut self, vid: &mut MDKVideoItem) {
+        // fn aligned_to_8(mut x: u32) -> u32 { if x % 8 != 0 { x += 8 - x % 8; } x }
 
-            // fn aligned_to_8(mut x: u32) -> u32 { if x % 8 != 0 { x += 8 - x % 8; } x }
-
-            if !self.stabilizer.input_file.read().url.is_empty() {
-                let h = if target_height > 0 { target_height as u32 } else { vid.videoHeight };
-                let ratio = vid.videoHeight as f64 / h as f64;
-                let new_w = (vid.videoWidth as f64 / ratio).floor() as u32;
-                let new_h = (vid.videoHeight as f64 / (vid.videoWidth as f64 / new_w as f64)).floor() as u32;
-                ::log::info!("surface size: {}x{}", new_w, new_h);
+        if !self.stabilizer.input_file.read().url.is_empty() {
+            let target_height = self.preview_resolution;
+            let h = if target_height > 0 { target_height as u32 } else { vid.videoHeight };
+            // Lowered when the preview can't keep up with the playback
+            let h = ((h as f64 * self.stabilizer.preview_quality.read().active_level().scale()).round() as u32).max(16);
+            let ratio = vid.videoHeight as f64 / h as f64;
+            let new_w = (vid.videoWidth as f64 / ratio).floor() as u32;
+            let new_h = (vid.videoHeight as f64 / (vid.videoWidth as f64 / new_w as f64)).floor() as u32;
+            ::log::info!("surface size: {}x{}", new_w, new_h);
 
-                self.chart_data_changed();
+            self.chart_data_changed();
 
-                vid.setSurfaceSize(new_w, new_h);
-                vid.setRotation(vid.getRotation());
-                // vid.setCurrentFrame(vid.currentFrame);
-            }
+            vid.setSurfaceSize(new_w, new_h);
+            vid.setRotation(vid.getRotation());
+            // vid.setCurrentFrame(vid.currentFrame);
         }
     }
 
@@ -911,27 +1037,101 @@ impl Controller {
 
         let stab = self.stabilizer.clone();
 
-        if stab.gyro.read().integration_method == index {
+        if stab.gyro.read().integration_method.index() == index {
             return;
         }
 
         core::run_threaded(move || {
-            {
-                stab.invalidate_ongoing_computations();
+            stab.set_integration_method(core::imu_integration::IntegrationMethod::from_index(index));
+            finished(());
+        });
+    }
 
-                let mut gyro = stab.gyro.write();
-                gyro.integration_method = index;
-                gyro.integrate();
+    fn load_secondary_gyro(&mut self, url: QUrl, max_offset_ms: f64) {
+        let url = util::qurl_to_encoded(url);
+        let finished = util::qt_queued_callback_mut(self, |this, result: Result<Option<f64>, core::GyroflowCoreError>| {
+            match result {
+                Ok(residual) => {
+                    let offset = this.stabilizer.gyro.read().secondary_gyro.as_ref().map(|x| x.time_offset_ms).unwrap_or_default();
+                    this.secondary_gyro_loaded(residual.is_some(), offset, residual.unwrap_or_default());
+                },
+                Err(e) => this.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default())
             }
-            stab.invalidate_smoothing();
-            finished(());
+            this.chart_data_changed();
+            this.request_recompute();
         });
+        let stab = self.stabilizer.clone();
+        let cancel_flag = self.cancel_flag.clone();
+        core::run_threaded(move || {
+            finished(stab.load_secondary_gyro_data(&url, &Default::default(), max_offset_ms, |_| (), cancel_flag));
+        });
+    }
+    fn remove_secondary_gyro(&mut self) {
+        self.stabilizer.remove_secondary_gyro_data();
+        self.chart_data_changed();
+        self.request_recompute();
+    }
+    fn set_gyro_stream(&mut self, stream: String) {
+        if let Ok(stream) = serde_json::from_value(serde_json::Value::String(stream)) {
+            self.stabilizer.set_gyro_stream(stream);
+            self.chart_data_changed();
+            self.request_recompute();
+        }
+    }
+
+    fn set_gyro_gap_repair(&mut self, method: String) {
+        self.stabilizer.set_gyro_gap_repair(core::gyro_source::GapRepair::from(method.as_str()));
+        self.chart_data_changed();
+        self.request_recompute();
+    }
+
+    fn set_offset_interpolation(&mut self, mode: String) {
+        self.stabilizer.set_offset_interpolation(core::gyro_source::OffsetInterpolation::from(mode.as_str()));
+        self.update_offset_model();
+        self.request_recompute();
+    }
+    fn get_offset_drift(&self) -> QString {
+        QString::from(self.stabilizer.gyro.read().get_drift_fit().and_then(|x| serde_json::to_string(x).ok()).unwrap_or_default())
+    }
+
+    // Negative to use the values from the lens profile or the metadata
+    fn set_frame_readout_override(&self, time: f64, direction: i32) {
+        self.stabilizer.set_frame_readout_time_override(Some(time).filter(|x| *x >= 0.0));
+        self.stabilizer.set_frame_readout_direction_override(Some(direction).filter(|x| *x >= 0).map(Into::into));
+        self.request_recompute();
+    }
+
+    fn clear_residual_corrections(&self) {
+        self.stabilizer.clear_residual_corrections(None);
+        self.stabilizer.invalidate_zooming();
+        self.request_recompute();
     }
 
     fn set_preview_pipeline(&self, index: i32) {
         self.preview_pipeline.store(index as usize, SeqCst);
     }
 
+    // 0: Auto, 1: Full, 2: Half, 3: Quarter
+    fn set_preview_quality(&mut self, mode: i32, player: QJSValue) {
+        let level = self.stabilizer.set_preview_quality_mode(mode.into());
+        self.preview_quality_updated(level, player);
+    }
+    // The paused frame is always at full quality
+    fn set_preview_playing(&mut self, playing: bool, player: QJSValue) {
+        let level = self.stabilizer.set_preview_playing(playing);
+        self.preview_quality_updated(level, player);
+    }
+    fn preview_quality_updated(&mut self, level: Option<core::preview_quality::PreviewQualityLevel>, player: QJSValue) {
+        self.preview_quality_level = QString::from(self.stabilizer.preview_quality.read().active_level().name());
+        self.preview_quality_changed();
+        if level.is_some() {
+            if let Some(vid) = player.to_qobject::<MDKVideoItem>() {
+                let vid = unsafe { &mut *vid.as_ptr() }; // vid.borrow_mut()
+                self.update_surface_size(vid);
+            }
+        }
+    }
+
     fn set_prevent_recompute(&self, v: bool) {
         self.stabilizer.prevent_recompute.store(v, SeqCst);
     }
@@ -939,6 +1139,39 @@ impl Controller {
     fn set_gpu_decoding(&self, enabled: bool) {
         self.stabilizer.set_gpu_decoding(enabled);
     }
+    fn set_decoder_preference(&self, preference: QString) {
+        let preference = preference.to_string().split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect::<Vec<_>>();
+        rendering::ffmpeg_hw::set_decoder_preference(&preference);
+    }
+    fn available_decoders(&self) -> QString {
+        QString::from(rendering::ffmpeg_hw::available_decoders("").join(", "))
+    }
+    fn set_frame_cache_budget(&self, mb: u64) {
+        gyroflow_core::frame_cache::set_budget_mb(mb);
+    }
+    fn get_frame_cache_usage(&self) -> QJsonObject {
+        util::serde_json_to_qt_object(&serde_json::to_value(gyroflow_core::frame_cache::usage()).unwrap_or_default())
+    }
+    // Returns the error message if the levels are invalid
+    fn set_log_levels(&self, levels: QString) -> QString {
+        let levels = levels.to_string();
+        let levels = if levels.trim().is_empty() { gyroflow_core::logging::DEFAULT_LEVELS } else { levels.as_str() };
+        match gyroflow_core::logging::set_levels(levels) {
+            Ok(()) => QString::default(),
+            Err(e) => QString::from(e)
+        }
+    }
+    fn set_json_log(&self, enabled: bool) {
+        let path = gyroflow_core::settings::data_dir().join("gyroflow.log.jsonl");
+        if let Err(e) = gyroflow_core::logging::set_json_file(enabled.then_some(path.as_path())) {
+            ::log::error!("Failed to open the JSON log file path={path:?} error={e:?}");
+        }
+    }
+    // Most recent log records as JSON lines, for bug reports
+    fn get_recent_logs(&self, limit: usize) -> QString {
+        let lines = gyroflow_core::logging::recent_records(limit).iter().filter_map(|x| serde_json::to_string(x).ok()).collect::<Vec<_>>();
+        QString::from(lines.join("\n"))
+    }
 
     fn reset_player(&self, player: QJSValue) {
         if let Some(vid) = player.to_qobject::<MDKVideoItem>() {
@@ -958,6 +1191,7 @@ impl Controller {
 
         if let Some(vid) = player.to_qobject::<MDKVideoItem>() {
             let vid1 = unsafe { &mut *vid.as_ptr() }; // vid.borrow_mut()
+            let vid2 = unsafe { &mut *vid.as_ptr() }; // vid.borrow_mut()
             let vid = unsafe { &mut *vid.as_ptr() }; // vid.borrow_mut()
 
             let bg_color = vid.getBackgroundColor().get_rgba_f();
@@ -988,6 +1222,8 @@ impl Controller {
             let stab = self.stabilizer.clone();
             let preview_pipeline = self.preview_pipeline.clone();
             let out_pixels = RefCell::new(Vec::new());
+            // Displayed frame, always pinned. Registered again with its size when the buffer is allocated
+            let out_pixels_entry = RefCell::new(None);
             let update_info = util::qt_queued_callback_mut(self, move |this, (fov, minimal_fov, focal_length, info): (f64, f64, Option<f64>, QString)| {
                 this.current_fov = fov;
                 this.current_minimal_fov = minimal_fov;
@@ -996,6 +1232,13 @@ impl Controller {
                 this.processing_info_changed();
             });
             let update_info2 = update_info.clone();
+            // Processing too slow (or fast enough again) for the playback
+            let quality_changed = util::qt_queued_callback_mut(self, move |this, level: core::preview_quality::PreviewQualityLevel| {
+                ::log::info!("Preview quality: {level:?}");
+                this.preview_quality_level = QString::from(level.name());
+                this.preview_quality_changed();
+                this.update_surface_size(vid2);
+            });
 
             #[allow(unused_variables)]
             vid.onProcessTexture(Box::new(move |frame, timestamp_ms, width, height, backend_id, ptr1, ptr2, ptr3, ptr4, ptr5| -> bool {
@@ -1005,13 +1248,15 @@ impl Controller {
 
                 let _time = std::time::Instant::now();
 
-                if preview_pipeline.load(SeqCst) == 0 {
+                if preview_pipeline.load(SeqCst) == 0 && qrhi_undistort::has_shader(&stab) {
                     let mut buffers = Buffers{
                         input:  BufferDescription { size: (width as usize, height as usize, width as usize * 4), ..Default::default() },
                         output: BufferDescription { size: (width as usize, height as usize, width as usize * 4), ..Default::default() },
                     };
                     if let Some(ret) = qrhi_undistort::render(vid1.get_mdkplayer(), timestamp_ms, frame as usize, width, height, stab.clone(), &mut buffers) {
-                        update_info2((ret.fov, ret.minimal_fov, ret.focal_length, QString::from(format!("Processing {}x{} using {} took {:.2}ms", width, height, ret.backend, _time.elapsed().as_micros() as f64 / 1000.0))));
+                        let elapsed_ms = _time.elapsed().as_micros() as f64 / 1000.0;
+                        update_info2((ret.fov, ret.minimal_fov, ret.focal_length, QString::from(format!("Processing {}x{} using {} took {:.2}ms", width, height, ret.backend, elapsed_ms))));
+                        if let Some(level) = stab.preview_frame_processed(elapsed_ms) { quality_changed(level); }
                     } else {
                         update_info2((1.0, 1.0, None, QString::from("---")));
                     }
@@ -1106,7 +1351,9 @@ impl Controller {
                 if let Some((ref mut buffers, backend)) = buffers {
                     match stab.process_pixels::<RGBA8>((timestamp_ms * 1000.0).round() as i64, Some(frame as usize), buffers) {
                         Ok(ret) =>  {
-                            update_info2((ret.fov, ret.minimal_fov, ret.focal_length, QString::from(format!("Processing {}x{} using {backend}->{} took {:.2}ms", width, height, ret.backend, _time.elapsed().as_micros() as f64 / 1000.0))));
+                            let elapsed_ms = _time.elapsed().as_micros() as f64 / 1000.0;
+                            update_info2((ret.fov, ret.minimal_fov, ret.focal_length, QString::from(format!("Processing {}x{} using {backend}->{} took {:.2}ms", width, height, ret.backend, elapsed_ms))));
+                            if let Some(level) = stab.preview_frame_processed(elapsed_ms) { quality_changed(level); }
                             return true;
                         },
                         Err(e) => {
@@ -1132,7 +1379,11 @@ impl Controller {
                 drop(params);
 
                 let mut out_pixels = out_pixels.borrow_mut();
-                out_pixels.resize_with(os*oh, u8::default);
+                if out_pixels.len() != os*oh {
+                    *out_pixels_entry.borrow_mut() = None;
+                    *out_pixels = vec![0u8; os*oh];
+                    *out_pixels_entry.borrow_mut() = Some(gyroflow_core::frame_cache::track_pinned(gyroflow_core::frame_cache::CacheKind::Preview, os*oh));
+                }
 
                 let ret = stab.process_pixels::<RGBA8>((timestamp_ms * 1000.0).round() as i64, Some(frame as usize), &mut Buffers {
                     input: BufferDescription {
@@ -1185,6 +1436,9 @@ impl Controller {
         self.request_recompute();
     }
     wrap_simple_method!(set_horizon_lock, lock_percent: f64, roll: f64; recompute; chart_data_changed);
+    wrap_simple_method!(set_horizon_gravity_reference, strength: f64; recompute; chart_data_changed);
+    wrap_simple_method!(set_axis_lock, pitch: bool, yaw: bool, roll: bool, horizon_reference: bool; recompute; chart_data_changed);
+    wrap_simple_method!(set_axis_lock_target, pitch: f64, yaw: f64, roll: f64; recompute; chart_data_changed);
     wrap_simple_method!(set_use_gravity_vectors, v: bool; recompute; chart_data_changed);
     wrap_simple_method!(set_horizon_lock_integration_method, v: i32; recompute; chart_data_changed);
     pub fn get_smoothing_algs(&self) -> QVariantList {
@@ -1193,6 +1447,39 @@ impl Controller {
     fn get_smoothing_status(&self) -> QJsonArray {
         util::serde_json_to_qt_array(&self.stabilizer.get_smoothing_status())
     }
+    fn get_graph_data(&self, from_ms: f64, to_ms: f64, samples: usize) -> QJsonObject {
+        let data = self.stabilizer.get_graph_data(from_ms, to_ms, samples);
+        util::serde_json_to_qt_object(&serde_json::to_value(&*data).unwrap_or_default())
+    }
+    fn get_static_zoom_exceeding_frames(&self) -> QJsonArray {
+        let frames = self.stabilizer.get_static_zoom_exceeding_frames().into_iter().map(|(frame, timestamp_ms, excess)| {
+            serde_json::json!({ "frame": frame, "timestamp_ms": timestamp_ms, "excess": excess })
+        }).collect::<Vec<_>>();
+        util::serde_json_to_qt_array(&serde_json::Value::Array(frames))
+    }
+    fn get_zoom_data(&self) -> QJsonArray {
+        util::serde_json_to_qt_array(&serde_json::to_value(self.stabilizer.get_zoom_data()).unwrap_or_default())
+    }
+    fn export_zoom_data(&self, url: QUrl, csv: bool) {
+        if let Err(e) = self.stabilizer.export_zoom_data(&util::qurl_to_encoded(url), csv) {
+            self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
+        }
+    }
+    fn export_camera_transforms(&self, url: QUrl, csv: bool, convention: QString, with_homography: bool) {
+        let convention = gyroflow_core::camera_export::AxisConvention::from_name(&convention.to_string());
+        if let Err(e) = self.stabilizer.export_camera_transforms(&util::qurl_to_encoded(url), csv, convention, with_homography) {
+            self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
+        }
+    }
+    fn get_zoom_center_limits(&self) -> QJsonArray {
+        let frames = self.stabilizer.get_zoom_center_limits().into_iter().map(|(frame, timestamp_ms, scale)| {
+            serde_json::json!({ "frame": frame, "timestamp_ms": timestamp_ms, "scale": scale })
+        }).collect::<Vec<_>>();
+        util::serde_json_to_qt_array(&serde_json::Value::Array(frames))
+    }
+    fn get_zoom_overflow_ranges(&self) -> QJsonArray {
+        util::serde_json_to_qt_array(&serde_json::json!(self.stabilizer.get_zoom_overflow_ranges()))
+    }
     fn get_smoothing_max_angles(&self) -> QJsonArray {
         let max_angles = self.stabilizer.get_smoothing_max_angles();
         util::serde_json_to_qt_array(&serde_json::json!([max_angles.0, max_angles.1, max_angles.2]))
@@ -1309,7 +1596,13 @@ impl Controller {
     }
 
     fn import_gyroflow_file(&mut self, url: QUrl) {
-        let url = util::qurl_to_encoded(url);
+        self.import_gyroflow_file_internal(url, false);
+    }
+    fn import_gyroflow_file_reloading_telemetry(&mut self, url: QUrl) {
+        self.import_gyroflow_file_internal(url, true);
+    }
+    fn import_gyroflow_file_internal(&mut self, project_url: QUrl, reload_telemetry: bool) {
+        let url = util::qurl_to_encoded(project_url.clone());
         let progress = util::qt_queued_callback_mut(self, move |this, progress: f64| {
             this.loading_gyro_in_progress = progress < 1.0;
             this.loading_gyro_progress(progress);
@@ -1320,6 +1613,11 @@ impl Controller {
             this.loading_gyro_progress(1.0);
             this.loading_gyro_in_progress_changed();
 
+            if let Err(gyroflow_core::GyroflowCoreError::EmbeddedDataCorrupted { error, source_url }) = &obj {
+                ::log::warn!("Embedded gyro data corrupted error={error} source_url={source_url:?}");
+                this.embedded_data_corrupted(project_url.clone(), QString::from(error.to_string()), QString::from(source_url.clone().unwrap_or_default()));
+                return;
+            }
             let obj = this.import_gyroflow_internal(obj);
             this.gyroflow_file_loaded(obj);
             this.project_file_url_changed();
@@ -1334,7 +1632,11 @@ impl Controller {
                 std::thread::sleep(std::time::Duration::from_millis(200));
             }
             cancel_flag.store(false, SeqCst);
-            finished(stab.import_gyroflow_file(&url, false, progress, cancel_flag, false));
+            if reload_telemetry {
+                finished(stab.import_gyroflow_file_reloading_telemetry(&url, progress, cancel_flag, false));
+            } else {
+                finished(stab.import_gyroflow_file(&url, false, progress, cancel_flag, false));
+            }
         });
     }
     fn import_gyroflow_data(&mut self, data: QString) {
@@ -1365,6 +1667,15 @@ impl Controller {
             finished(stab.import_gyroflow_data(data.to_string().as_bytes(), false, None, progress, cancel_flag, &mut is_preset, false));
         });
     }
+    fn load_project_summary(&self, url: QUrl) -> QJsonObject {
+        match gyroflow_core::project_summary::load_metadata_only(&util::qurl_to_encoded(url)) {
+            Ok(summary) => util::serde_json_to_qt_object(&serde_json::to_value(summary).unwrap_or_default()),
+            Err(e) => {
+                ::log::warn!("Failed to read the project summary: {e:?}");
+                QJsonObject::default()
+            }
+        }
+    }
     fn import_gyroflow_internal(&mut self, result: Result<serde_json::Value, gyroflow_core::GyroflowCoreError>) -> QJsonObject {
         match result {
             Ok(thin_obj) => {
@@ -1406,13 +1717,22 @@ impl Controller {
     wrap_simple_method!(set_fov,                v: f64; recompute; chart_data_changed);
     wrap_simple_method!(set_frame_readout_time, v: f64; recompute);
     wrap_simple_method!(set_frame_readout_direction, v: i32; recompute);
+    wrap_simple_method!(set_rolling_shutter_strength, v: f64; recompute);
+    wrap_simple_method!(set_residual_correction_strength, v: f64; recompute);
     wrap_simple_method!(set_adaptive_zoom,      v: f64; recompute; zooming_data_changed);
+    wrap_simple_method!(set_adaptive_zoom_look_ahead, v: f64; recompute; zooming_data_changed);
+    wrap_simple_method!(set_static_zoom_percentile, v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_max_zoom,           v: f64, i: usize; recompute; zooming_data_changed);
+    wrap_simple_method!(set_limit_to_zoom_budget, v: bool; recompute; zooming_data_changed);
+    wrap_simple_method!(set_deterministic,      v: bool; recompute; zooming_data_changed);
     wrap_simple_method!(set_zooming_center_x,   v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_zooming_center_y,   v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_additional_rotation_x,v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_additional_rotation_y,v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_additional_rotation_z,v: f64; recompute; zooming_data_changed);
+    wrap_simple_method!(set_horizon_compensation_pitch,v: f64; recompute; zooming_data_changed);
+    wrap_simple_method!(set_horizon_compensation_roll, v: f64; recompute; zooming_data_changed);
+    wrap_simple_method!(set_horizon_compensation_fov,  v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_additional_translation_x,v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_additional_translation_y,v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_additional_translation_z,v: f64; recompute; zooming_data_changed);
@@ -1423,6 +1743,7 @@ impl Controller {
     wrap_simple_method!(set_light_refraction_coefficient, v: f64; recompute; zooming_data_changed);
     wrap_simple_method!(set_input_horizontal_stretch,  v: f64; recompute);
     wrap_simple_method!(set_lens_is_asymmetrical,      v: bool; recompute);
+    wrap_simple_method!(set_lens_flip,                 horizontal: bool, vertical: bool; recompute; zooming_data_changed);
     wrap_simple_method!(set_input_vertical_stretch,    v: f64; recompute);
     wrap_simple_method!(set_background_mode,           v: i32; recompute);
     wrap_simple_method!(set_background_margin,         v: f64; recompute);
@@ -1434,6 +1755,8 @@ impl Controller {
     wrap_simple_method!(remove_offset, timestamp_us: i64; recompute; update_offset_model);
 
     wrap_simple_method!(set_imu_lpf, v: f64; recompute; chart_data_changed);
+    wrap_simple_method!(set_imu_acc_lpf, v: f64; recompute; chart_data_changed);
+    wrap_simple_method!(set_magnetometer_correction, enabled: bool, time_constant_s: f64; recompute; chart_data_changed);
     wrap_simple_method!(set_imu_median_filter, size: i32; recompute; chart_data_changed);
     wrap_simple_method!(set_imu_rotation, pitch_deg: f64, roll_deg: f64, yaw_deg: f64; recompute; chart_data_changed);
     wrap_simple_method!(set_acc_rotation, pitch_deg: f64, roll_deg: f64, yaw_deg: f64; recompute; chart_data_changed);
@@ -1528,6 +1851,7 @@ impl Controller {
             self.stabilizer.set_smoothing_param("time_constant", 2.0);
             self.stabilizer.set_max_zoom(0.0, 0);
             self.stabilizer.set_adaptive_zoom(0.0);
+            self.stabilizer.set_limit_to_zoom_budget(false);
         }
     }
 
@@ -1651,6 +1975,7 @@ impl Controller {
                                         let cal = lock.as_mut().unwrap();
                                         if is_forced {
                                             cal.forced_frames.insert(frame);
+                                            cal.excluded_frames.remove(&frame);
                                         }
                                         cal.no_marker = no_marker;
 
@@ -1684,7 +2009,15 @@ impl Controller {
 
                 let mut lock = cal.write();
                 let cal = lock.as_mut().unwrap();
-                if let Err(e) = cal.calibrate(is_forced) {
+                let auto_select = cal.auto_select && !is_forced;
+                if auto_select {
+                    let good = cal.image_points.read().len();
+                    let progress = progress.clone();
+                    if !cal.select_frames(&cancel_flag, move |selected, count| progress((selected, count.max(1), good, 0.0, 0.0))) {
+                        ::log::warn!("No calibration frames selected");
+                    }
+                }
+                if let Err(e) = cal.calibrate(is_forced || auto_select) {
                     err(("An error occured: %1".to_string(), format!("{:?}", e)));
                 } else {
                     if cal.rms < 100.0 {
@@ -1706,12 +2039,17 @@ impl Controller {
         {
             let cal = self.stabilizer.lens_calibrator.clone();
 
-            let used_points = cal.read().as_ref().map(|x| x.used_points.clone()).unwrap_or_default();
+            let (used_points, scores) = cal.read().as_ref().map(|x| (x.used_points.clone(), x.frame_scores.clone())).unwrap_or_default();
 
-            self.calib_model = RefCell::new(used_points.values().map(|v| CalibrationItem {
-                timestamp_us: v.timestamp_us,
-                sharpness: v.avg_sharpness,
-                is_forced: v.is_forced
+            self.calib_model = RefCell::new(used_points.values().map(|v| {
+                let score = scores.iter().find(|x| x.frame == v.frame);
+                CalibrationItem {
+                    timestamp_us: v.timestamp_us,
+                    sharpness: v.avg_sharpness,
+                    is_forced: v.is_forced,
+                    score: score.map(|x| x.total).unwrap_or_default(),
+                    score_info: score.map(|x| QString::from(format!("Detected: {:.0}%, sharpness: {:.0}%, new area: {:.0}%, new pose: {:.0}%", x.detection * 100.0, x.sharpness * 100.0, x.coverage * 100.0, x.pose * 100.0))).unwrap_or_default(),
+                }
             }).collect());
 
             util::qt_queued_callback(self, |this, _| {
@@ -1741,6 +2079,9 @@ impl Controller {
                 if let Some(f) = frame_to_remove {
                     cal.forced_frames.remove(&f);
                     cal.used_points.remove(&f);
+                    // Don't pick it again in the next automatic selection
+                    cal.excluded_frames.insert(f);
+                    cal.frame_scores.retain(|x| x.frame != f);
                 }
                 if cal.calibrate(true).is_ok() {
                     rms = cal.rms;
@@ -1800,6 +2141,63 @@ impl Controller {
         }
     }
 
+    fn add_focal_length_to_lens_profile(&mut self, url: QUrl, focal_length: f64) {
+        let url = util::qurl_to_encoded(url);
+        let mut profile = core::lens_profile::LensProfile::default();
+        let result = profile.load_from_file(&url).and_then(|_| {
+            #[cfg(feature = "opencv")]
+            if let Some(ref cal) = *self.stabilizer.lens_calibrator.read() {
+                return profile.add_focal_length_from_calibrator(cal, focal_length);
+            }
+            Err(core::GyroflowCoreError::InvalidLensProfile("No calibration data".into()))
+        }).and_then(|_| profile.save_to_file(&url));
+        if let Err(e) = result {
+            self.error(QString::from("An error occured: %1"), QString::from(format!("{:?}", e)), QString::default());
+        } else {
+            ::log::info!("Added calibration at {focal_length} mm to {url}, focal lengths: {:?}", profile.focal_length_calibrations.iter().map(|x| x.focal_length).collect::<Vec<_>>());
+        }
+    }
+
+    fn set_calibration_board(&mut self, board: QString) {
+        #[cfg(feature = "opencv")]
+        match serde_json::from_str::<core::calibration::charuco::CalibrationBoard>(&board.to_string()) {
+            Ok(board) => self.stabilizer.set_calibration_board(board),
+            Err(e) => self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default())
+        }
+    }
+
+    fn set_calibration_auto_select(&mut self, enabled: bool) {
+        #[cfg(feature = "opencv")]
+        if let Some(ref mut cal) = *self.stabilizer.lens_calibrator.write() {
+            cal.auto_select = enabled;
+        }
+    }
+    fn get_calibration_frame_scores(&self) -> QString {
+        #[cfg(feature = "opencv")]
+        if let Some(ref cal) = *self.stabilizer.lens_calibrator.read() {
+            return QString::from(serde_json::to_string(&cal.frame_scores).unwrap_or_default());
+        }
+        QString::from("[]")
+    }
+
+    fn add_video_mode_to_lens_profile(&mut self, url: QUrl, video_mode: QString) {
+        let url = util::qurl_to_encoded(url);
+        let video_mode = video_mode.to_string();
+        let mut profile = core::lens_profile::LensProfile::default();
+        let result = profile.load_from_file(&url).and_then(|_| {
+            #[cfg(feature = "opencv")]
+            if let Some(ref cal) = *self.stabilizer.lens_calibrator.read() {
+                return profile.add_video_mode_from_calibrator(cal, &video_mode);
+            }
+            Err(core::GyroflowCoreError::InvalidLensProfile("No calibration data".into()))
+        }).and_then(|_| profile.save_to_file(&url));
+        if let Err(e) = result {
+            self.error(QString::from("An error occured: %1"), QString::from(format!("{:?}", e)), QString::default());
+        } else {
+            ::log::info!("Added video mode \"{video_mode}\" to {url}, modes: {:?}", profile.video_modes.iter().map(|x| x.video_mode.as_str()).collect::<Vec<_>>());
+        }
+    }
+
     fn load_profiles(&self, reload_from_disk: bool) {
         let loaded = util::qt_queued_callback_mut(self, |this, _: ()| {
             this.all_profiles_loaded();
@@ -1951,7 +2349,7 @@ impl Controller {
             }
         }
         let contents = content.to_json_pretty();
-        if let Err(e) = filesystem::write(&url, contents.to_slice()) {
+        if let Err(e) = filesystem::write_atomic(&url, contents.to_slice()) {
             self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
         }
         QString::from(filesystem::display_url(&url))
@@ -1983,6 +2381,16 @@ impl Controller {
         }
     }
 
+    fn export_gcsv(&self, url: QUrl, options: QJsonObject) {
+        let result = || -> Result<(), core::GyroflowCoreError> {
+            let options: gyroflow_core::gyro_source::GcsvExportOptions = serde_json::from_str(options.to_json().to_str().unwrap())?;
+            self.stabilizer.export_gcsv(&util::qurl_to_encoded(url), &options)
+        };
+        if let Err(e) = result() {
+            self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
+        }
+    }
+
     fn set_keyframe(&self, typ: String, timestamp_us: i64, value: f64) {
         if let Ok(kf) = KeyframeType::from_str(&typ) {
             self.stabilizer.set_keyframe(&kf, timestamp_us, value);
@@ -2001,6 +2409,18 @@ impl Controller {
             }
         }
     }
+    fn set_keyframe_bezier(&self, typ: String, timestamp_us: i64, x1: f64, y1: f64, x2: f64, y2: f64) {
+        if let Ok(kf) = KeyframeType::from_str(&typ) {
+            self.stabilizer.set_keyframe_bezier(&kf, timestamp_us, [x1, y1, x2, y2]);
+            self.keyframes_changed();
+            self.request_recompute();
+            self.chart_data_changed();
+        }
+    }
+    fn keyframe_bezier(&self, typ: String, timestamp_us: i64) -> QJsonArray {
+        let handles = KeyframeType::from_str(&typ).ok().and_then(|kf| self.stabilizer.keyframe_bezier(&kf, timestamp_us));
+        util::serde_json_to_qt_array(&serde_json::json!(handles.map(|x| x.to_vec()).unwrap_or_default()))
+    }
     fn keyframe_easing(&self, typ: String, timestamp_us: i64) -> String {
         if let Ok(kf) = KeyframeType::from_str(&typ) {
             if let Some(e) = self.stabilizer.keyframe_easing(&kf, timestamp_us) {
@@ -2041,6 +2461,71 @@ impl Controller {
             self.chart_data_changed();
         }
     }
+    // Bulk operations take the types separated with commas and return the changed keyframes, which can be passed to `replace_keyframes`
+    fn keyframe_sets_changed(&self, sets: Option<KeyframeSets>) -> QJsonObject {
+        if sets.is_some() {
+            self.keyframes_changed();
+            self.request_recompute();
+            self.chart_data_changed();
+        }
+        util::serde_json_to_qt_object(&serde_json::to_value(sets.unwrap_or_default()).unwrap_or_default())
+    }
+    fn shift_keyframes(&self, types: String, delta_us: i64) -> QJsonObject {
+        let types = types.split(',').filter_map(|x| KeyframeType::from_str(x.trim()).ok()).collect::<Vec<_>>();
+        self.keyframe_sets_changed(Some(self.stabilizer.shift_keyframes(&types, delta_us)))
+    }
+    fn scale_keyframes(&self, types: String, anchor_us: i64, factor: f64) -> QJsonObject {
+        let types = types.split(',').filter_map(|x| KeyframeType::from_str(x.trim()).ok()).collect::<Vec<_>>();
+        self.keyframe_sets_changed(Some(self.stabilizer.scale_keyframes(&types, anchor_us, factor)))
+    }
+    fn copy_keyframes(&self, from: String, to: String, value_scale: f64, value_offset: f64) -> QJsonObject {
+        let sets = match (KeyframeType::from_str(&from), KeyframeType::from_str(&to)) {
+            (Ok(from), Ok(to)) => self.stabilizer.copy_keyframes(&from, &to, |v| v * value_scale + value_offset),
+            _ => None
+        };
+        self.keyframe_sets_changed(sets)
+    }
+    fn remove_keyframes_in_range(&self, types: String, from_us: i64, to_us: i64) -> QJsonObject {
+        let types = types.split(',').filter_map(|x| KeyframeType::from_str(x.trim()).ok()).collect::<Vec<_>>();
+        self.keyframe_sets_changed(Some(self.stabilizer.remove_keyframes_in_range(&types, from_us, to_us)))
+    }
+    fn replace_keyframes(&self, sets: QJsonObject) {
+        match serde_json::from_str::<KeyframeSets>(&sets.to_json().to_string()) {
+            Ok(sets) => {
+                self.stabilizer.replace_keyframes(&sets);
+                self.keyframe_sets_changed(Some(sets));
+            }
+            Err(e) => self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default())
+        }
+    }
+    fn set_keyframe_quantization(&self, enabled: bool) {
+        self.stabilizer.set_keyframe_quantization(enabled);
+    }
+    fn quantize_all_keyframes(&self) -> QJsonObject {
+        self.keyframe_sets_changed(Some(self.stabilizer.quantize_all_keyframes()))
+    }
+    fn export_keyframes(&self, url: QUrl, types: String, normalized: bool) {
+        let types = types.split(',').filter_map(|x| KeyframeType::from_str(x.trim()).ok()).collect::<Vec<_>>();
+        if let Err(e) = self.stabilizer.export_keyframes(&util::qurl_to_encoded(url), &types, normalized) {
+            self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
+        }
+    }
+    // Returns the imported and the unknown parameters
+    fn import_keyframes(&self, url: QUrl, policy: String) -> QJsonObject {
+        let policy = KeyframeImportPolicy::from_str(&policy).unwrap_or_default();
+        match self.stabilizer.import_keyframes(&util::qurl_to_encoded(url), policy) {
+            Ok(result) => {
+                self.keyframes_changed();
+                self.request_recompute();
+                self.chart_data_changed();
+                util::serde_json_to_qt_object(&serde_json::to_value(result).unwrap_or_default())
+            }
+            Err(e) => {
+                self.error(QString::from("An error occured: %1"), QString::from(e.to_string()), QString::default());
+                QJsonObject::default()
+            }
+        }
+    }
     fn keyframe_value_at_video_timestamp(&self, typ: String, timestamp_ms: f64) -> QJSValue {
         if let Ok(typ) = KeyframeType::from_str(&typ) {
             if let Some(v) = self.stabilizer.keyframe_value_at_video_timestamp(&typ, timestamp_ms) {
@@ -2288,7 +2773,7 @@ impl Controller {
     fn has_per_frame_lens_data(&self) -> bool {
         let gyro = self.stabilizer.gyro.read();
         let md = gyro.file_metadata.read();
-        md.camera_stab_data.len() > 1 || md.lens_params.len() > 1 || md.lens_positions.len() > 1 || md.mesh_correction.len() > 1
+        md.camera_stab_data.len() > 1 || md.lens_params.len() > 1 || md.lens_positions.len() > 1 || md.focal_lengths.len() > 1 || md.mesh_correction.len() > 1
     }
     fn export_stmap(&self, folder_url: QUrl, per_frame: bool) {
         let folder_url = util::qurl_to_encoded(folder_url);
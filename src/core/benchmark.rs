@@ -0,0 +1,338 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Benchmark of the stabilization kernel on each available backend, with generated frames and motion, so the results
+// depend only on the hardware and the kernels. The parameters are the ones of a typical render: OpenCV fisheye lens model,
+// rolling shutter correction, bicubic interpolation and 10-bit values for the 16-bit formats.
+// Note that the GPU device selection is global, so running the benchmark changes the current processing device.
+
+use std::time::Instant;
+use crate::StabilizationManager;
+use crate::gpu::{ self, Buffers, BufferDescription, BufferSource, StageTimings };
+use crate::stabilization::{ self, ComputeParams, Interpolation, PixelType, Stabilization };
+use crate::stabilization::{ RGBA8, RGBA16, RGBAf, Luma8, Luma16 };
+use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+use crate::gyro_source::Quat64;
+
+/// Pixel formats which can be benchmarked. The 16-bit ones contain 10-bit values
+pub const PIXEL_FORMATS: &[&str] = &["RGBA8", "RGBA16", "RGBAf", "Luma8", "Luma16"];
+pub const BACKENDS: &[&str] = &["CPU", "OpenCL", "wgpu"];
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct BenchmarkOptions {
+    pub resolutions: Vec<(usize, usize)>,
+    pub pixel_formats: Vec<String>,
+    pub iterations: usize,
+    pub backends: Vec<String>, // Empty for all
+}
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        Self {
+            resolutions: vec![(3840, 2160)],
+            pixel_formats: vec!["RGBA16".into()],
+            iterations: 20,
+            backends: Vec::new(),
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct BackendResult {
+    pub backend: String,
+    pub device: String,
+    pub width: usize,
+    pub height: usize,
+    pub pixel_format: String,
+    pub iterations: usize,
+    pub init_ms: f64,
+    pub avg_ms: f64,
+    pub min_ms: f64,
+    pub fps: f64,
+    pub megapixels_per_s: f64,
+    pub stages: Option<StageTimings>, // Averages, measured separately since it has to wait for the device after each stage
+    pub hash: String,                 // crc32 of the output frame
+    pub max_diff: Option<f64>,        // Compared to the reference backend's output, normalized to 0-1
+    pub mean_diff: Option<f64>,
+    pub matches_reference: Option<bool>,
+    pub reference: Option<String>,
+    pub skipped: Option<String>,
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct BenchmarkReport {
+    pub version: String,
+    pub options: BenchmarkOptions,
+    pub results: Vec<BackendResult>,
+}
+
+// The outputs are never bit-exact between the backends, so they match if they only differ by rounding
+const MAX_MEAN_DIFF: f64 = 0.002;
+
+fn devices() -> Vec<(isize, &'static str, String)> {
+    let mut list = stabilization::GPU_LIST.read().clone();
+    if list.is_empty() {
+        list = Stabilization::default().list_devices();
+        *stabilization::GPU_LIST.write() = list.clone();
+    }
+    let mut ret = vec![(-1, "CPU", "CPU".to_string())];
+    for (i, name) in list.iter().enumerate() {
+        if let Some(name) = name.strip_prefix("[OpenCL] ") {
+            ret.push((i as isize, "OpenCL", name.to_string()));
+        } else if let Some(name) = name.strip_prefix("[wgpu] ") {
+            ret.push((i as isize, "wgpu", name.to_string()));
+        }
+    }
+    ret
+}
+
+fn manager(width: usize, height: usize) -> StabilizationManager {
+    let mgr = StabilizationManager::default();
+    let (w, h) = (width as f64, height as f64);
+    *mgr.lens.write() = LensProfile {
+        calib_dimension: Dimensions { w: width, h: height },
+        fisheye_params: CameraParams {
+            camera_matrix: vec![[w * 0.55, 0.0, w / 2.0], [0.0, w * 0.55, h / 2.0], [0.0, 0.0, 1.0]],
+            distortion_coeffs: vec![0.03, -0.01, 0.004, -0.001],
+            ..Default::default()
+        },
+        distortion_model: Some("opencv_fisheye".into()),
+        ..Default::default()
+    };
+    {
+        let mut params = mgr.params.write();
+        params.fps = 30.0;
+        params.frame_count = 60;
+        params.duration_ms = 2000.0;
+        params.frame_readout_time = 15.0;
+    }
+    {
+        let mut gyro = mgr.gyro.write();
+        gyro.duration_ms = 2000.0;
+        gyro.quaternions = (0..=200).map(|i| {
+            let t = i as f64 / 100.0;
+            (i * 10_000, Quat64::from_euler_angles((t * 17.0).sin() * 0.02, (t * 11.0).cos() * 0.02, (t * 7.0).sin() * 0.01))
+        }).collect();
+        let corrections = gyro.quaternions.iter().map(|(ts, q)| (*ts, Quat64::from_euler_angles(0.0, 0.0, 0.01) * *q)).collect();
+        gyro.set_smoothed_quaternions(corrections);
+    }
+    mgr.set_size(width, height);
+    mgr.set_output_size(width, height);
+    mgr.recompute_undistortion();
+    mgr
+}
+
+// Checkerboard with gradients, so the interpolation has edges to work on
+fn synthetic_frame<T: PixelType>(width: usize, height: usize, max_value: f32) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(width * height * std::mem::size_of::<T>());
+    for y in 0..height {
+        for x in 0..width {
+            let checker = if ((x / 32) + (y / 32)) % 2 == 0 { 0.8 } else { 0.2 };
+            let v = nalgebra::Vector4::new(
+                checker * (x as f32 / width as f32),
+                checker * (y as f32 / height as f32),
+                checker,
+                1.0
+            ) * max_value;
+            ret.extend_from_slice(bytemuck::bytes_of(&T::from_float(v)));
+        }
+    }
+    ret
+}
+
+fn diff<T: PixelType>(a: &[u8], b: &[u8], max_value: f32) -> (f64, f64) {
+    let size = std::mem::size_of::<T>();
+    let (mut max, mut sum, mut count) = (0.0f64, 0.0f64, 0usize);
+    for (a, b) in a.chunks_exact(size).zip(b.chunks_exact(size)) {
+        let d = T::to_float(bytemuck::pod_read_unaligned(a)) - T::to_float(bytemuck::pod_read_unaligned(b));
+        for i in 0..T::COUNT {
+            let d = (d[i] / max_value).abs() as f64;
+            max = max.max(d);
+            sum += d;
+            count += 1;
+        }
+    }
+    (max, sum / count.max(1) as f64)
+}
+
+fn run_backend<T: PixelType>(mgr: &StabilizationManager, device: isize, backend: &str, width: usize, height: usize, max_value: f32, iterations: usize, result: &mut BackendResult) -> Option<Vec<u8>> {
+    let stride = width * std::mem::size_of::<T>();
+    let mut input = synthetic_frame::<T>(width, height, max_value);
+    let mut output = vec![0u8; stride * height];
+    let timestamp_us = 500_000;
+
+    let mut plane = Stabilization::default();
+    plane.interpolation = Interpolation::Bicubic;
+    plane.init_size((width, height), (width, height));
+    plane.set_compute_params(ComputeParams::from_manager(mgr));
+    plane.set_device(device);
+
+    let mut buffers = Buffers {
+        input:  BufferDescription { size: (width, height, stride), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+        output: BufferDescription { size: (width, height, stride), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+    };
+
+    let time = Instant::now();
+    plane.ensure_ready_for_processing::<T>(timestamp_us, None, &mut buffers);
+    plane.stab_data.clear();
+    let mut transform = plane.get_frame_transform_at::<T>(timestamp_us, None, &buffers);
+    transform.kernel_params.pixel_value_limit = max_value;
+    transform.kernel_params.max_pixel_value = max_value;
+    if plane.initialized_backend.is_wgpu() && T::wgpu_format().map(|x| x.2).unwrap_or_default() {
+        transform.kernel_params.pixel_value_limit = 1.0;
+        transform.kernel_params.max_pixel_value = 1.0;
+    }
+    match plane.process_pixels::<T>(timestamp_us, None, &mut buffers, Some(&transform)) {
+        Ok(info) if info.backend == backend => { },
+        Ok(info) => { result.skipped = Some(format!("Not available, processed using {}", info.backend)); return None; },
+        Err(e) => { result.skipped = Some(format!("{e:?}")); return None; }
+    }
+    result.init_ms = time.elapsed().as_secs_f64() * 1000.0;
+
+    let mut times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let time = Instant::now();
+        if let Err(e) = plane.process_pixels::<T>(timestamp_us, None, &mut buffers, Some(&transform)) {
+            result.skipped = Some(format!("{e:?}"));
+            return None;
+        }
+        times.push(time.elapsed().as_secs_f64() * 1000.0);
+    }
+    let iterations = times.len().max(1);
+    result.iterations = times.len();
+    result.avg_ms = times.iter().sum::<f64>() / iterations as f64;
+    result.min_ms = times.iter().copied().fold(f64::MAX, f64::min);
+    result.fps = 1000.0 / result.avg_ms;
+    result.megapixels_per_s = (width * height) as f64 / 1_000_000.0 * result.fps;
+
+    // The stages are timed separately, because waiting for the device after each of them affects the throughput
+    gpu::set_stage_profiling(true);
+    let mut stages = StageTimings::default();
+    let profiled = iterations.min(5);
+    for _ in 0..profiled {
+        let time = Instant::now();
+        let _ = plane.process_pixels::<T>(timestamp_us, None, &mut buffers, Some(&transform));
+        let t = gpu::take_stage_timings().unwrap_or(StageTimings { kernel_ms: time.elapsed().as_secs_f64() * 1000.0, ..Default::default() }); // CPU
+        stages.upload_ms   += t.upload_ms   / profiled as f64;
+        stages.kernel_ms   += t.kernel_ms   / profiled as f64;
+        stages.readback_ms += t.readback_ms / profiled as f64;
+    }
+    gpu::set_stage_profiling(false);
+    result.stages = Some(stages);
+
+    drop(buffers);
+    result.hash = format!("{:08x}", crc32fast::hash(&output));
+    Some(output)
+}
+
+fn run_format(format: &str, mgr: &StabilizationManager, device: isize, backend: &str, width: usize, height: usize, iterations: usize, result: &mut BackendResult, reference: Option<&[u8]>) -> Option<Vec<u8>> {
+    macro_rules! run {
+        ($($t:ident => $max:expr),*) => {
+            match format {
+                $(stringify!($t) => {
+                    let output = run_backend::<$t>(mgr, device, backend, width, height, $max, iterations, result)?;
+                    if let Some(reference) = reference {
+                        let (max, mean) = diff::<$t>(&output, reference, $max);
+                        result.max_diff = Some(max);
+                        result.mean_diff = Some(mean);
+                        result.matches_reference = Some(mean <= MAX_MEAN_DIFF);
+                    }
+                    Some(output)
+                },)*
+                _ => {
+                    result.skipped = Some(format!("Unknown pixel format {format}"));
+                    None
+                }
+            }
+        };
+    }
+    run!(RGBA8 => 255.0, RGBA16 => 1023.0, RGBAf => 1.0, Luma8 => 255.0, Luma16 => 1023.0)
+}
+
+pub fn run<F: Fn(&BackendResult)>(options: &BenchmarkOptions, progress: F) -> BenchmarkReport {
+    let mut report = BenchmarkReport { version: env!("CARGO_PKG_VERSION").to_string(), options: options.clone(), results: Vec::new() };
+    let wanted = |backend: &str| options.backends.is_empty() || options.backends.iter().any(|x| x.eq_ignore_ascii_case(backend));
+    let devices = devices().into_iter().filter(|x| wanted(x.1)).collect::<Vec<_>>();
+
+    for &(width, height) in &options.resolutions {
+        let mgr = manager(width, height);
+        for format in &options.pixel_formats {
+            let mut missing = options.backends.iter().filter(|x| !devices.iter().any(|d| d.1.eq_ignore_ascii_case(x))).collect::<Vec<_>>();
+            missing.dedup();
+            for backend in missing {
+                let result = BackendResult { backend: backend.clone(), width, height, pixel_format: format.clone(), skipped: Some("No devices".into()), ..Default::default() };
+                progress(&result);
+                report.results.push(result);
+            }
+
+            // The first backend which works is the reference for the others, CPU if it's included
+            let mut reference: Option<(String, Vec<u8>)> = None;
+            for (index, backend, device) in &devices {
+                let mut result = BackendResult { backend: backend.to_string(), device: device.clone(), width, height, pixel_format: format.clone(), ..Default::default() };
+                let output = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    run_format(format, &mgr, *index, backend, width, height, options.iterations, &mut result, reference.as_ref().map(|x| &x.1[..]))
+                }));
+                match output {
+                    Ok(Some(output)) => {
+                        if let Some((name, _)) = &reference {
+                            result.reference = Some(name.clone());
+                        } else {
+                            reference = Some((format!("{backend} {device}"), output));
+                        }
+                    },
+                    Ok(None) => { },
+                    Err(_) => {
+                        gpu::set_stage_profiling(false);
+                        result.skipped = Some("Panicked".into());
+                    }
+                }
+                progress(&result);
+                report.results.push(result);
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_backend() {
+        let options = BenchmarkOptions {
+            resolutions: vec![(320, 180)],
+            pixel_formats: vec!["RGBA16".into(), "Luma8".into(), "YUV".into()],
+            iterations: 2,
+            backends: vec!["cpu".into(), "metal".into()],
+        };
+        let report = run(&options, |_| ());
+        let ok = report.results.iter().filter(|x| x.skipped.is_none()).collect::<Vec<_>>();
+        assert_eq!(ok.len(), 2);
+        for x in &ok {
+            assert_eq!((x.backend.as_str(), x.iterations), ("CPU", 2));
+            assert!(x.avg_ms > 0.0 && x.fps > 0.0);
+            assert!(x.stages.unwrap().kernel_ms > 0.0);
+            assert_eq!(x.hash.len(), 8);
+        }
+        assert_ne!(ok[0].hash, ok[1].hash);
+        // Unknown backend and pixel format
+        assert_eq!(report.results.iter().filter(|x| x.skipped.is_some()).count(), 4);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), report.results.len());
+    }
+
+    #[test]
+    fn same_frame_same_hash() {
+        let mgr = manager(160, 90);
+        let mut a = BackendResult::default();
+        let mut b = BackendResult::default();
+        let output = run_format("RGBA8", &mgr, -1, "CPU", 160, 90, 1, &mut a, None).unwrap();
+        run_format("RGBA8", &mgr, -1, "CPU", 160, 90, 1, &mut b, Some(&output)).unwrap();
+        assert_eq!(a.hash, b.hash);
+        assert_eq!(b.max_diff, Some(0.0));
+        assert_eq!(b.matches_reference, Some(true));
+        assert!(output.iter().any(|x| *x > 0));
+    }
+}
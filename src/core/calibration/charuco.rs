@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// ChArUco board: a chessboard with ArUco markers in the white squares.
+// Every corner is identified by the markers around it, so the board can be detected when it's only partially visible,
+// which is needed to get detections in the corners of a fisheye lens.
+
+use serde::{ Serialize, Deserialize };
+
+#[cfg(feature = "use-opencv")]
+use opencv::{
+    core::{ Mat, Size, Point2f, Vector },
+    prelude::{ MatTraitConst, CharucoDetectorTraitConst },
+    objdetect::{ CharucoBoard, CharucoDetector, PredefinedDictionaryType }
+};
+
+// Frames with fewer corners don't constrain the calibration enough
+pub const MIN_CHARUCO_CORNERS: usize = 12;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CharucoBoardParams {
+    pub squares_x: usize,
+    pub squares_y: usize,
+    pub square_length: f64, // Only the ratio of the sizes matters, so any unit can be used
+    pub marker_length: f64,
+    pub dictionary: String, // eg. "DICT_5X5_100"
+}
+impl Default for CharucoBoardParams {
+    fn default() -> Self {
+        Self { squares_x: 12, squares_y: 9, square_length: 30.0, marker_length: 22.0, dictionary: "DICT_5X5_100".into() }
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CalibrationBoard {
+    #[default]
+    Chessboard,
+    Charuco(CharucoBoardParams),
+}
+
+impl CalibrationBoard {
+    /// Grid of the inner corners, (columns, rows). `None` for the chessboard, which uses the calibrator's own size
+    pub fn corner_grid(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::Chessboard => None,
+            Self::Charuco(p) => Some((p.squares_x.saturating_sub(1), p.squares_y.saturating_sub(1))),
+        }
+    }
+
+    /// Stored in the lens profile
+    pub fn description(&self) -> String {
+        match self {
+            Self::Chessboard => "chessboard".into(),
+            Self::Charuco(p) => format!("charuco {}x{} {} {:.2}/{:.2}", p.squares_x, p.squares_y, p.dictionary, p.square_length, p.marker_length),
+        }
+    }
+}
+
+#[cfg(feature = "use-opencv")]
+fn dictionary_type(name: &str) -> Result<PredefinedDictionaryType, opencv::Error> {
+    Ok(match name.to_ascii_uppercase().trim_start_matches("DICT_") {
+        "4X4_50"   => PredefinedDictionaryType::DICT_4X4_50,
+        "4X4_100"  => PredefinedDictionaryType::DICT_4X4_100,
+        "4X4_250"  => PredefinedDictionaryType::DICT_4X4_250,
+        "4X4_1000" => PredefinedDictionaryType::DICT_4X4_1000,
+        "5X5_50"   => PredefinedDictionaryType::DICT_5X5_50,
+        "5X5_100"  => PredefinedDictionaryType::DICT_5X5_100,
+        "5X5_250"  => PredefinedDictionaryType::DICT_5X5_250,
+        "5X5_1000" => PredefinedDictionaryType::DICT_5X5_1000,
+        "6X6_50"   => PredefinedDictionaryType::DICT_6X6_50,
+        "6X6_100"  => PredefinedDictionaryType::DICT_6X6_100,
+        "6X6_250"  => PredefinedDictionaryType::DICT_6X6_250,
+        "6X6_1000" => PredefinedDictionaryType::DICT_6X6_1000,
+        "7X7_50"   => PredefinedDictionaryType::DICT_7X7_50,
+        "7X7_100"  => PredefinedDictionaryType::DICT_7X7_100,
+        "7X7_250"  => PredefinedDictionaryType::DICT_7X7_250,
+        "7X7_1000" => PredefinedDictionaryType::DICT_7X7_1000,
+        _ => return Err(opencv::Error::new(0, format!("Unknown ArUco dictionary: {name}")))
+    })
+}
+
+#[cfg(feature = "use-opencv")]
+pub fn create_board(params: &CharucoBoardParams) -> Result<CharucoBoard, opencv::Error> {
+    if params.squares_x < 3 || params.squares_y < 3 || params.marker_length <= 0.0 || params.marker_length >= params.square_length {
+        return Err(opencv::Error::new(0, format!("Invalid ChArUco board: {params:?}")));
+    }
+    let dictionary = opencv::objdetect::get_predefined_dictionary(dictionary_type(&params.dictionary)?)?;
+    CharucoBoard::new_def(Size::new(params.squares_x as i32, params.squares_y as i32), params.square_length as f32, params.marker_length as f32, &dictionary)
+}
+
+/// Detects the board in a grayscale image. Returns the corners and their ids, row by row from the top left inner corner of the board.
+/// Returns `None` if fewer than `MIN_CHARUCO_CORNERS` corners were found
+#[cfg(feature = "use-opencv")]
+pub fn detect(board: &CharucoBoard, image: &Mat) -> Result<Option<(Vec<Point2f>, Vec<i32>)>, opencv::Error> {
+    let detector = CharucoDetector::new_def(board)?;
+    let mut corners = Vector::<Point2f>::new();
+    let mut ids = Vector::<i32>::new();
+    detector.detect_board_def(image, &mut corners, &mut ids)?;
+    if corners.len() < MIN_CHARUCO_CORNERS || corners.len() != ids.len() {
+        return Ok(None);
+    }
+    Ok(Some((corners.to_vec(), ids.to_vec())))
+}
+
+#[cfg(all(test, feature = "use-opencv"))]
+mod tests {
+    use super::*;
+    use opencv::core::{ Rect, Scalar, BORDER_CONSTANT, CV_8UC1 };
+
+    // Frames rendered from the board definition itself: the whole board and a crop with about half of it out of frame
+    fn fixture_frames(board: &CharucoBoard) -> (Mat, Mat) {
+        let mut img = Mat::default();
+        opencv::objdetect::CharucoBoardTraitConst::generate_image(board, Size::new(1200, 900), &mut img, 40, 1).unwrap();
+        let mut full = Mat::default();
+        opencv::core::copy_make_border(&img, &mut full, 60, 60, 60, 60, BORDER_CONSTANT, Scalar::all(255.0)).unwrap();
+        let partial = (*Mat::roi(&full, Rect::new(0, 0, 800, 650)).unwrap()).try_clone().unwrap();
+        (full, partial)
+    }
+
+    #[test]
+    fn partial_board() {
+        let params = CharucoBoardParams::default();
+        let board = create_board(&params).unwrap();
+        let (full, partial) = fixture_frames(&board);
+        assert_eq!(full.typ(), CV_8UC1);
+
+        let (corners, ids) = detect(&board, &full).unwrap().unwrap();
+        let (columns, rows) = CalibrationBoard::Charuco(params.clone()).corner_grid().unwrap();
+        assert_eq!(corners.len(), columns * rows);
+        assert_eq!(ids.len(), corners.len());
+
+        let (corners, ids) = detect(&board, &partial).unwrap().unwrap();
+        assert!(corners.len() >= MIN_CHARUCO_CORNERS && corners.len() < columns * rows, "{}", corners.len());
+        assert!(corners.iter().all(|p| p.x < 800.0 && p.y < 650.0));
+        assert!(ids.iter().all(|id| (*id as usize) < columns * rows));
+
+        // Blank frame
+        let blank = Mat::new_rows_cols_with_default(900, 1200, CV_8UC1, Scalar::all(255.0)).unwrap();
+        assert!(detect(&board, &blank).unwrap().is_none());
+
+        assert!(create_board(&CharucoBoardParams { marker_length: 40.0, ..params }).is_err());
+    }
+}
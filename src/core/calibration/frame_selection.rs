@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Picks the calibration frames from all detections instead of random sets.
+// Every frame is scored by how much of the board was detected, how sharp the board is and how different
+// its position and tilt is from the frames picked so far, then the best one is added until there's enough.
+
+use std::collections::{ BTreeMap, HashSet };
+use std::sync::atomic::{ AtomicBool, Ordering::Relaxed };
+use nalgebra::{ SMatrix, SVector };
+use super::Detected;
+
+// Image area is divided into this many cells to measure the coverage
+const COVERAGE_GRID: (usize, usize) = (8, 6);
+
+// Sharpness scales the total, so a blurry frame doesn't get picked just for a new position
+const DETECTION_WEIGHT: f64 = 0.3;
+const NOVELTY_WEIGHT: f64 = 0.7;
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct FrameScore {
+    pub frame: i32,
+    pub timestamp_us: i64,
+    pub detection: f64, // Fraction of the board corners detected
+    pub sharpness: f64, // Variance of Laplacian on the board, relative to the sharpest frame
+    pub coverage: f64,  // Fraction of the board area in image cells not covered by the previously picked frames
+    pub pose: f64,      // Difference of the position, size and tilt from the closest picked frame, 0 - 1
+    pub total: f64,
+    pub selected: bool,
+    pub pinned: bool,
+}
+
+// Position, size and tilt of the board in the frame
+#[derive(Clone, Copy, Debug)]
+struct BoardPose { center: (f64, f64), size: f64, tilt: (f64, f64) }
+
+impl BoardPose {
+    fn distance(&self, other: &BoardPose) -> f64 {
+        ((self.center.0 - other.center.0).powi(2) +
+         (self.center.1 - other.center.1).powi(2) +
+         (self.size - other.size).powi(2) +
+         (self.tilt.0 - other.tilt.0).powi(2) +
+         (self.tilt.1 - other.tilt.1).powi(2)).sqrt()
+    }
+}
+
+/// Least squares homography from the board to the image, both normalized to 0 - 1
+fn board_homography(board: &[(f64, f64)], image: &[(f64, f64)]) -> Option<SMatrix<f64, 3, 3>> {
+    if board.len() < 4 || board.len() != image.len() { return None; }
+    let mut ata = SMatrix::<f64, 8, 8>::zeros();
+    let mut atb = SVector::<f64, 8>::zeros();
+    for ((x, y), (u, v)) in board.iter().zip(image.iter()) {
+        for (row, b) in [([*x, *y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y], *u), ([0.0, 0.0, 0.0, *x, *y, 1.0, -v * x, -v * y], *v)] {
+            let row = SVector::<f64, 8>::from_column_slice(&row);
+            ata += row * row.transpose();
+            atb += row * b;
+        }
+    }
+    let h = ata.lu().solve(&atb)?;
+    Some(SMatrix::<f64, 3, 3>::new(h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0))
+}
+
+fn board_pose(detected: &Detected, grid: (usize, usize), size: (usize, usize)) -> Option<BoardPose> {
+    let (columns, rows) = ((grid.0.max(2) - 1) as f64, (grid.1.max(2) - 1) as f64);
+    let board = (0..detected.points.len()).map(|i| {
+        let id = detected.ids.get(i).map(|x| *x as usize).unwrap_or(i);
+        ((id % grid.0) as f64 / columns, (id / grid.0) as f64 / rows)
+    }).collect::<Vec<_>>();
+    let image = detected.points.iter().map(|(x, y)| (*x as f64 / size.0 as f64, *y as f64 / size.1 as f64)).collect::<Vec<_>>();
+    let h = board_homography(&board, &image)?;
+
+    let project = |x: f64, y: f64| -> Option<(f64, f64)> {
+        let p = h * nalgebra::Vector3::new(x, y, 1.0);
+        if p.z.abs() < 1e-9 { return None; }
+        Some((p.x / p.z, p.y / p.z))
+    };
+    let (tl, tr, bl, br) = (project(0.0, 0.0)?, project(1.0, 0.0)?, project(0.0, 1.0)?, project(1.0, 1.0)?);
+    let len = |a: (f64, f64), b: (f64, f64)| ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt().max(1e-9);
+    let area = 0.5 * ((tl.0 * tr.1 - tr.0 * tl.1) + (tr.0 * br.1 - br.0 * tr.1) + (br.0 * bl.1 - bl.0 * br.1) + (bl.0 * tl.1 - tl.0 * bl.1)).abs();
+
+    Some(BoardPose {
+        center: project(0.5, 0.5)?,
+        size: area.sqrt(),
+        // Perspective makes the closer edge longer
+        tilt: ((len(tl, bl) / len(tr, br)).ln(), (len(tl, tr) / len(bl, br)).ln()),
+    })
+}
+
+fn coverage_cells(detected: &Detected, size: (usize, usize)) -> HashSet<usize> {
+    detected.points.iter().filter_map(|(x, y)| {
+        let cx = (*x as f64 / size.0 as f64 * COVERAGE_GRID.0 as f64).floor();
+        let cy = (*y as f64 / size.1 as f64 * COVERAGE_GRID.1 as f64).floor();
+        if cx < 0.0 || cy < 0.0 || cx >= COVERAGE_GRID.0 as f64 || cy >= COVERAGE_GRID.1 as f64 { return None; }
+        Some(cy as usize * COVERAGE_GRID.0 + cx as usize)
+    }).collect()
+}
+
+/// Scores all `candidates` and picks `count` of them, starting with the `pinned` ones. `excluded` frames are never picked.
+/// `grid` is the board corner grid (columns, rows), `size` is the image size of the detected points.
+/// Returns the scores of all candidates, the picked ones have `selected` set
+pub fn select_frames<F: Fn(usize, usize)>(candidates: &BTreeMap<i32, Detected>, grid: (usize, usize), size: (usize, usize), count: usize, pinned: &HashSet<i32>, excluded: &HashSet<i32>, cancel_flag: &AtomicBool, progress: F) -> Vec<FrameScore> {
+    if size.0 == 0 || size.1 == 0 || grid.0 == 0 || grid.1 == 0 { return Vec::new(); }
+
+    let max_laplacian = candidates.values().map(|x| x.laplacian_variance).fold(0.0, f64::max);
+    let mut frames = candidates.values()
+        .filter(|x| !excluded.contains(&x.frame))
+        .filter_map(|x| Some((x.frame, board_pose(x, grid, size)?, coverage_cells(x, size))))
+        .map(|(frame, pose, cells)| {
+            let d = &candidates[&frame];
+            (FrameScore {
+                frame,
+                timestamp_us: d.timestamp_us,
+                detection: (d.points.len() as f64 / (grid.0 * grid.1) as f64).min(1.0),
+                sharpness: if max_laplacian > 0.0 { d.laplacian_variance / max_laplacian } else { 1.0 },
+                pinned: pinned.contains(&frame),
+                ..Default::default()
+            }, pose, cells)
+        })
+        .collect::<Vec<_>>();
+
+    let mut covered = HashSet::new();
+    let mut selected_poses = Vec::<BoardPose>::new();
+    for (score, pose, cells) in frames.iter_mut().filter(|x| x.0.pinned) {
+        score.selected = true;
+        covered.extend(cells.iter().copied());
+        selected_poses.push(*pose);
+    }
+    let mut num_selected = selected_poses.len();
+    progress(num_selected.min(count), count);
+
+    while num_selected < count {
+        if cancel_flag.load(Relaxed) { break; }
+
+        let mut best: Option<(usize, f64)> = None;
+        for (i, (score, pose, cells)) in frames.iter_mut().enumerate() {
+            if score.selected { continue; }
+            score.coverage = cells.iter().filter(|x| !covered.contains(x)).count() as f64 / cells.len().max(1) as f64;
+            // Distance of 0.3 is about a third of the frame or a clearly visible tilt
+            score.pose = selected_poses.iter().map(|x| x.distance(pose)).reduce(f64::min).map(|d| 1.0 - (-d / 0.3).exp()).unwrap_or(1.0);
+            score.total = (DETECTION_WEIGHT * score.detection + NOVELTY_WEIGHT * 0.5 * (score.coverage + score.pose)) * score.sharpness.sqrt();
+            if !best.is_some_and(|(_, b)| score.total <= b) {
+                best = Some((i, score.total));
+            }
+        }
+        let Some((i, _)) = best else { break; };
+        let pose = frames[i].1;
+        frames[i].0.selected = true;
+        covered.extend(frames[i].2.iter().copied());
+        selected_poses.push(pose);
+        num_selected += 1;
+        progress(num_selected, count);
+    }
+
+    frames.into_iter().map(|x| x.0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Board of 14x8 corners projected with a simple pinhole camera, rotated by `tilt` (radians) around the vertical axis
+    fn detection(frame: i32, center: (f64, f64), scale: f64, tilt: f64, laplacian_variance: f64) -> Detected {
+        let mut points = Vec::new();
+        for y in 0..8 {
+            for x in 0..14 {
+                let (bx, by) = ((x as f64 - 6.5) / 13.0, (y as f64 - 3.5) / 13.0);
+                let (px, pz) = (bx * tilt.cos(), 3.0 + bx * tilt.sin());
+                let (u, v) = (px / pz * 3.0 * scale, by / pz * 3.0 * scale);
+                points.push(((center.0 + u) as f32 * 1920.0, (center.1 + v) as f32 * 1080.0));
+            }
+        }
+        Detected { points, frame, timestamp_us: frame as i64 * 33333, laplacian_variance, ..Default::default() }
+    }
+
+    #[test]
+    fn picks_diverse_frames() {
+        let mut candidates = BTreeMap::new();
+        // Ten nearly identical shots in the center, the sharpest ones
+        for i in 0..10 {
+            candidates.insert(i, detection(i, (0.5 + i as f64 * 0.002, 0.5), 0.5, 0.0, 100.0 + i as f64));
+        }
+        candidates.insert(20, detection(20, (0.2, 0.25), 0.3, 0.0, 80.0));
+        candidates.insert(21, detection(21, (0.8, 0.75), 0.3, 0.0, 80.0));
+        candidates.insert(22, detection(22, (0.5, 0.5), 0.5, 0.7, 80.0));
+        candidates.insert(23, detection(23, (0.8, 0.25), 0.3, 0.0, 10.0)); // Blurry
+
+        let cancel = AtomicBool::new(false);
+        let none = HashSet::new();
+        let picked = |scores: &[FrameScore]| scores.iter().filter(|x| x.selected).map(|x| x.frame).collect::<Vec<_>>();
+
+        let scores = select_frames(&candidates, (14, 8), (1920, 1080), 4, &none, &none, &cancel, |_, _| { });
+        assert_eq!(scores.len(), candidates.len());
+        let frames = picked(&scores);
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames.iter().filter(|x| **x < 10).count(), 1, "{frames:?}");
+        assert!(frames.contains(&20) && frames.contains(&21) && frames.contains(&22), "{frames:?}");
+
+        // Pinned and excluded frames
+        let scores = select_frames(&candidates, (14, 8), (1920, 1080), 4, &HashSet::from([23]), &HashSet::from([22]), &cancel, |_, _| { });
+        let frames = picked(&scores);
+        assert!(frames.contains(&23) && !frames.contains(&22), "{frames:?}");
+        assert!(scores.iter().all(|x| x.frame != 22));
+
+        // Partial board keeps its position on the board
+        let mut partial = detection(30, (0.5, 0.5), 0.5, 0.0, 100.0);
+        partial.ids = (0..partial.points.len() as i32).filter(|x| x % 14 < 7).collect();
+        partial.points = partial.ids.iter().map(|x| partial.points[*x as usize]).collect();
+        let (full, partial) = (board_pose(&candidates[&0], (14, 8), (1920, 1080)).unwrap(), board_pose(&partial, (14, 8), (1920, 1080)).unwrap());
+        assert!(full.distance(&partial) < 0.01, "{full:?} {partial:?}");
+
+        cancel.store(true, Relaxed);
+        let scores = select_frames(&candidates, (14, 8), (1920, 1080), 4, &none, &none, &cancel, |_, _| { });
+        assert!(picked(&scores).is_empty());
+    }
+}
@@ -23,6 +23,10 @@ use rayon::iter::{ ParallelIterator, IntoParallelIterator };
 use crate::stabilization::distortion_models::DistortionModel;
 
 pub mod drawing;
+pub mod charuco;
+pub mod frame_selection;
+
+use charuco::CalibrationBoard;
 
 #[derive(Clone, Default, Debug)]
 pub struct Detected {
@@ -30,7 +34,9 @@ pub struct Detected {
     pub frame: i32,
     pub timestamp_us: i64,
     pub avg_sharpness: f64,
-    pub is_forced: bool
+    pub is_forced: bool,
+    pub ids: Vec<i32>, // Board corner of every point for a partially visible ChArUco board, empty when all corners were detected
+    pub laplacian_variance: f64 // Sharpness of the board region, higher is sharper
 }
 #[derive(Default)]
 pub struct LensCalibrator {
@@ -53,12 +59,19 @@ pub struct LensCalibrator {
     pub sum_sharpness: Arc<RwLock<f64>>,
 
     pub forced_frames: HashSet<i32>,
+    pub excluded_frames: HashSet<i32>,
+
+    // Pick the frames by sharpness and coverage instead of the best of random sets, see `frame_selection`
+    pub auto_select: bool,
+    pub frame_scores: Vec<frame_selection::FrameScore>,
 
     pub no_marker: bool,
+    pub board: CalibrationBoard,
 
     pub digital_lens: Option<String>,
     pub digital_lens_params: Option<Vec<f64>>,
     pub asymmetrical: bool,
+    pub input_flipped: (bool, bool), // Horizontal, vertical, copied to the profile
 
     pub all_matches: Arc<RwLock<BTreeMap<i32, Detected>>>, // frame, Detected
     pub image_points: Arc<RwLock<BTreeMap<i32, Detected>>>, // frame, Detected
@@ -95,6 +108,26 @@ impl LensCalibrator {
         ret
     }
 
+    /// Changes the calibration target. Detections of the previous board are discarded
+    pub fn set_board(&mut self, board: CalibrationBoard) {
+        if board == self.board { return; }
+        let (columns, rows) = board.corner_grid().unwrap_or((14, 8));
+        self.columns = columns;
+        self.rows = rows;
+        self.objp.clear();
+        for y in 0..rows {
+            for x in 0..columns {
+                self.objp.push((x as f64, y as f64));
+            }
+        }
+        self.board = board;
+        self.forced_frames.clear();
+        self.excluded_frames.clear();
+        self.frame_scores.clear();
+        *self.sum_sharpness.write() = 0.0;
+        self.clear();
+    }
+
     pub fn clear(&mut self) {
         self.all_matches.write().clear();
         self.image_points.write().clear();
@@ -118,6 +151,8 @@ impl LensCalibrator {
         let digital_lens = self.digital_lens.as_ref().map(|x| DistortionModel::from_name(&x));
         let digital_lens_params_opt = self.digital_lens_params.clone();
         let no_marker = self.no_marker;
+        let board = self.board.clone();
+        let num_corners = self.objp.len();
 
         if let Some(detected) = all_matches.read().get(&frame) {
             if detected.avg_sharpness < max_sharpness {
@@ -146,55 +181,68 @@ impl LensCalibrator {
 
                 let _ = opencv::imgproc::equalize_hist(&inp1, &mut inp);
 
-                let mut corners = Mat::default();
+                let detection = match &board {
+                    CalibrationBoard::Chessboard => {
+                        let mut corners = Mat::default();
 
-                let mut flags = CALIB_CB_MARKER;
-                if no_marker {
-                    flags = 0;
-                }
+                        let mut flags = CALIB_CB_MARKER;
+                        if no_marker {
+                            flags = 0;
+                        }
+
+                        if opencv::calib3d::find_chessboard_corners_sb(&inp, grid_size, &mut corners, flags)? && corners.rows() > 0 {
+                            let sharpness = opencv::calib3d::estimate_chessboard_sharpness(&inp, grid_size, &corners, 0.8, false, &mut Mat::default()).unwrap_or_default();
+                            let avg_sharpness = *sharpness.get(0).unwrap_or(&100.0);
+                            Some((corners.iter::<Point2f>()?.map(|(_, pt)| pt).collect::<Vec<_>>(), Vec::new(), avg_sharpness))
+                        } else {
+                            None
+                        }
+                    },
+                    CalibrationBoard::Charuco(params) => {
+                        let board = charuco::create_board(params)?;
+                        // Sharpness can only be estimated on a complete grid, so partial boards are always accepted
+                        charuco::detect(&board, &inp)?.map(|(corners, ids)| {
+                            let (corners, ids): (Vec<_>, Vec<_>) = corners.into_iter().zip(ids).filter(|(_, id)| *id >= 0 && (*id as usize) < num_corners).unzip();
+                            let ids = if ids.len() == num_corners && ids.iter().enumerate().all(|(i, id)| *id as usize == i) { Vec::new() } else { ids };
+                            (corners, ids, 0.0)
+                        })
+                    }
+                };
 
-                if opencv::calib3d::find_chessboard_corners_sb(&inp, grid_size, &mut corners, flags)? {
-                    if corners.rows() > 0 {
-                        let sharpness = opencv::calib3d::estimate_chessboard_sharpness(&inp, grid_size, &corners, 0.8, false, &mut Mat::default()).unwrap_or_default();
-                        let avg_sharpness = *sharpness.get(0).unwrap_or(&100.0);
-                        let mut points = Vec::with_capacity(corners.rows() as usize);
+                if let Some((corners, ids, avg_sharpness)) = detection {
+                    let laplacian_variance = board_laplacian_variance(&inp, &corners).unwrap_or_default();
+                    let mut points = Vec::with_capacity(corners.len());
 
-                        let mut digital_lens_params = [0f32; 4];
-                        if let Some(p) = digital_lens_params_opt {
-                            for (i, v) in p.iter().enumerate() {
-                                digital_lens_params[i] = *v as f32;
-                            }
+                    let mut digital_lens_params = [0f32; 4];
+                    if let Some(p) = digital_lens_params_opt {
+                        for (i, v) in p.iter().enumerate() {
+                            digital_lens_params[i] = *v as f32;
                         }
-                        // TODO more params
-                        let kernel_params = crate::stabilization::KernelParams {
-                            width : size.0 as i32,
-                            height: size.1 as i32,
-                            output_width: size.0 as i32,
-                            output_height: size.1 as i32,
-                            digital_lens_params,
-                            ..Default::default()
-                        };
-
-                        for (_pos, mut pt) in corners.iter::<Point2f>()? {
-                            if let Some(digital) = &digital_lens {
-                                if let Some(mut pt2) = digital.undistort_point((pt.x,  pt.y), &kernel_params) {
-                                    // TODO
-                                    // Move from center to the left, because we trim the right part making it 4:3
-                                    //pt2.0 -= 0.125; // (16-4) / (9-3) / 16
-
-                                    pt = Point2f::new(pt2.0, pt2.1);
-                                }
+                    }
+                    let kernel_params = crate::stabilization::KernelParams {
+                        width : size.0 as i32,
+                        height: size.1 as i32,
+                        output_width: size.0 as i32,
+                        output_height: size.1 as i32,
+                        digital_lens_params,
+                        ..Default::default()
+                    };
+
+                    for mut pt in corners {
+                        if let Some(digital) = &digital_lens {
+                            if let Some(pt2) = digital.undistort_point((pt.x,  pt.y), &kernel_params) {
+                                pt = Point2f::new(pt2.0, pt2.1);
                             }
-                            points.push((pt.x * pt_scale, pt.y * pt_scale));
-                        }
-                        log::debug!("avg sharpness: {:.5}, max: {:.5}", avg_sharpness, max_sharpness);
-                        if avg_sharpness < max_sharpness || is_forced {
-                            img_points.write().insert(frame, Detected { points: points.clone(), timestamp_us, frame, avg_sharpness, is_forced });
-                            *sum_sharpness.write() += avg_sharpness;
                         }
-                        all_matches.write().insert(frame, Detected { points, timestamp_us, avg_sharpness, frame, is_forced });
-                        return Ok(avg_sharpness);
+                        points.push((pt.x * pt_scale, pt.y * pt_scale));
+                    }
+                    log::debug!("avg sharpness: {:.5}, max: {:.5}, points: {}", avg_sharpness, max_sharpness, points.len());
+                    if avg_sharpness < max_sharpness || is_forced {
+                        img_points.write().insert(frame, Detected { points: points.clone(), timestamp_us, frame, avg_sharpness, is_forced, ids: ids.clone(), laplacian_variance });
+                        *sum_sharpness.write() += avg_sharpness;
                     }
+                    all_matches.write().insert(frame, Detected { points, timestamp_us, avg_sharpness, frame, is_forced, ids, laplacian_variance });
+                    return Ok(avg_sharpness);
                 }
                 Err(opencv::Error::new(0, "Chessboard not found".to_string()))
             })();
@@ -202,6 +250,16 @@ impl LensCalibrator {
         });
     }
 
+    /// Picks `max_images` frames from the detected ones with `frame_selection` and stores them in `used_points`, to calibrate with `calibrate(true)`.
+    /// `progress` gets the number of picked frames and `max_images`
+    pub fn select_frames<F: Fn(usize, usize)>(&mut self, cancel_flag: &AtomicBool, progress: F) -> bool {
+        let image_points = self.image_points.read().clone();
+        self.frame_scores = frame_selection::select_frames(&image_points, (self.columns, self.rows), (self.width, self.height), self.max_images, &self.forced_frames, &self.excluded_frames, cancel_flag, progress);
+        self.used_points = self.frame_scores.iter().filter(|x| x.selected).filter_map(|x| Some((x.frame, image_points.get(&x.frame)?.clone()))).collect();
+        log::info!("Selected calibration frames: {:?}", self.used_points.keys());
+        !self.used_points.is_empty() && !cancel_flag.load(SeqCst)
+    }
+
     pub fn calibrate(&mut self, only_used: bool) -> Result<(), opencv::Error> {
         let calib_criteria = TermCriteria::new(TermCriteria_Type::EPS as i32 | TermCriteria_Type::COUNT as i32, 30, 1e-6)?;
 
@@ -214,12 +272,7 @@ impl LensCalibrator {
         let find_min = |a: (f64, Matrix3::<f64>, Vector4::<f64>, Vec<i32>), b: (f64, Matrix3::<f64>, Vector4::<f64>, Vec<i32>)| -> (f64, Matrix3::<f64>, Vector4::<f64>, Vec<i32>) { if a.0 < b.0 { a } else { b } };
 
         let image_points = self.image_points.read().clone();
-        let mut width = self.width as i32;
-        if let Some(digital) = self.digital_lens.as_ref().map(|x| DistortionModel::from_name(&x)) {
-            // TODO
-            //width = (width as f32 / 1.33333333).round() as i32;
-        }
-        let size = Size::new(width, self.height as i32);
+        let size = Size::new(self.width as i32, self.height as i32);
         let objp = self.objp.clone();
         let max_images = self.max_images;
         let forced_frames = self.forced_frames.clone();
@@ -263,15 +316,19 @@ impl LensCalibrator {
                 return (999.0000, Matrix3::<f64>::default(), Vector4::<f64>::default(), final_frames);
             }
 
+            let detected = final_frames.iter().filter_map(|k| image_points.get(k)).collect::<Vec<_>>();
             let imgpoints = Vector::<Vector<Point2f>>::from_iter(
-                final_frames.iter().filter_map(|k| Some(Vector::from_iter(
-                    image_points.get(k)?.points.iter().map(|(x, y)| Point2f::new(*x as f32, *y as f32))
+                detected.iter().map(|x| Vector::from_iter(
+                    x.points.iter().map(|(x, y)| Point2f::new(*x as f32, *y as f32))
                 ))
-            ));
+            );
+            // Partial boards have a different subset of the corners in every frame
             let objpoints = Vector::<Vector<Point3d>>::from_iter(
-                (0..imgpoints.len()).into_iter().map(|_| Vector::<Point3d>::from_iter(
-                    objp.iter().map(|(x, y)| Point3d::new(*x, *y, 0.0))
-                ))
+                detected.iter().map(|x| if x.ids.is_empty() {
+                    Vector::<Point3d>::from_iter(objp.iter().map(|(x, y)| Point3d::new(*x, *y, 0.0)))
+                } else {
+                    Vector::<Point3d>::from_iter(x.ids.iter().filter_map(|id| objp.get(*id as usize)).map(|(x, y)| Point3d::new(*x, *y, 0.0)))
+                })
             );
 
             let mut k  = Mat::default(); let mut d  = Mat::default();
@@ -307,6 +364,23 @@ impl LensCalibrator {
     }
 }
 
+// Variance of Laplacian inside the bounding box of the detected corners
+#[cfg(feature = "use-opencv")]
+fn board_laplacian_variance(image: &Mat, corners: &[Point2f]) -> Result<f64, opencv::Error> {
+    let (min, max) = corners.iter().fold(((f32::MAX, f32::MAX), (f32::MIN, f32::MIN)), |(min, max), p| ((min.0.min(p.x), min.1.min(p.y)), (max.0.max(p.x), max.1.max(p.y))));
+    let x = (min.0.floor() as i32).clamp(0, image.cols() - 1);
+    let y = (min.1.floor() as i32).clamp(0, image.rows() - 1);
+    let w = (max.0.ceil() as i32 - x).clamp(1, image.cols() - x);
+    let h = (max.1.ceil() as i32 - y).clamp(1, image.rows() - y);
+    let roi = Mat::roi(image, opencv::core::Rect::new(x, y, w, h))?;
+    let mut laplacian = Mat::default();
+    opencv::imgproc::laplacian(&*roi, &mut laplacian, opencv::core::CV_64F, 3, 1.0, 0.0, opencv::core::BORDER_DEFAULT)?;
+    let (mut mean, mut stddev) = (Mat::default(), Mat::default());
+    opencv::core::mean_std_dev(&laplacian, &mut mean, &mut stddev, &opencv::core::no_array())?;
+    let stddev = *stddev.at::<f64>(0)?;
+    Ok(stddev * stddev)
+}
+
 #[cfg(feature = "use-opencv")]
 fn cv_to_mat3(r1: Mat) -> Result<Matrix3<f64>, opencv::Error> {
     if r1.typ() != opencv::core::CV_64FC1 {
@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Per-frame camera motion and stabilization, for matching the stabilized camera in 3D and compositing software.
+// The quaternions are [w, x, y, z]. The original and smoothed ones are the camera orientations and the correction
+// is the rotation applied to the middle row of the frame, the same one as in the rendering kernels, including the
+// video rotation, horizon compensation and mirroring. The FOV and the camera matrix are the ones sent to the kernels.
+
+use nalgebra::{ Matrix3, Vector3 };
+use crate::gyro_source::Quat64;
+use crate::keyframes::KeyframeType;
+use crate::stabilization::{ ComputeParams, FrameTransform };
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum AxisConvention {
+    /// x right, y down, looking along +z. The camera space of the kernels
+    #[default]
+    OpenCV,
+    /// x right, y up, looking along -z
+    OpenGL,
+    /// OpenGL camera axes in a z-up world, so the orientations can be used as the camera's `rotation_quaternion`
+    Blender,
+}
+
+impl AxisConvention {
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "opengl" => Self::OpenGL,
+            "blender" => Self::Blender,
+            _ => Self::OpenCV,
+        }
+    }
+
+    // Camera axes in the convention -> OpenCV camera axes
+    fn camera_axes(&self) -> Matrix3<f64> {
+        match self {
+            Self::OpenCV => Matrix3::identity(),
+            Self::OpenGL | Self::Blender => Matrix3::from_diagonal(&Vector3::new(1.0, -1.0, -1.0)),
+        }
+    }
+    // OpenCV world axes -> world axes in the convention
+    fn world_axes(&self) -> Matrix3<f64> {
+        match self {
+            Self::OpenCV | Self::OpenGL => self.camera_axes(),
+            Self::Blender => Matrix3::new(1.0, 0.0, 0.0,  0.0, 0.0, 1.0,  0.0, -1.0, 0.0),
+        }
+    }
+
+    fn orientation(&self, r: &Matrix3<f64>) -> [f64; 4] { quat_to_array(&self.world_axes(), r, &self.camera_axes()) }
+    fn correction(&self, r: &Matrix3<f64>) -> [f64; 4] { quat_to_array(&self.camera_axes().transpose(), r, &self.camera_axes()) }
+}
+
+fn quat_to_array(a: &Matrix3<f64>, r: &Matrix3<f64>, b: &Matrix3<f64>) -> [f64; 4] {
+    let q = Quat64::from_rotation_matrix(&nalgebra::Rotation3::from_matrix_unchecked(a * r * b));
+    [q.w, q.i, q.j, q.k]
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CameraFrame {
+    pub frame: usize,
+    pub timestamp_ms: f64,
+    pub original_quat: [f64; 4],
+    pub smoothed_quat: [f64; 4],
+    pub correction_quat: [f64; 4],
+    pub fov: f64,                       // As `KernelParams::fov`, 1 is the whole input frame, lower is zoomed in
+    pub horizontal_fov_deg: f64,        // Of the output camera
+    pub focal_length_px: [f64; 2],      // Input camera matrix, as `KernelParams::f`
+    pub principal_point_px: [f64; 2],   // As `KernelParams::c`
+    pub homography: Option<[f64; 9]>,   // Row-major, input to output pixels without the lens distortion and rolling shutter
+}
+
+/// Camera data of every frame of the video
+pub fn per_frame(params: &ComputeParams, convention: AxisConvention, with_homography: bool) -> Vec<CameraFrame> {
+    (0..params.frame_count).map(|frame| {
+        let timestamp_ms = crate::timestamp_at_frame(frame as i32, params.scaled_fps);
+        at_frame(params, frame, timestamp_ms, convention, with_homography)
+    }).collect()
+}
+
+fn at_frame(params: &ComputeParams, frame: usize, timestamp_ms: f64, convention: AxisConvention, with_homography: bool) -> CameraFrame {
+    let video_rotation = params.keyframes.value_at_video_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(params.video_rotation);
+    let center_scale = params.zoom_center_scale.get(frame).copied().unwrap_or(1.0);
+    let zoom_center_x = params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterX, timestamp_ms).unwrap_or(params.adaptive_zoom_center_offset.0) * center_scale;
+    let zoom_center_y = params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterY, timestamp_ms).unwrap_or(params.adaptive_zoom_center_offset.1) * center_scale;
+
+    let (camera_matrix, _, _, _, _, _) = FrameTransform::get_lens_data_at_timestamp(params, timestamp_ms, false);
+    let fov = FrameTransform::output_fov(params, frame, timestamp_ms);
+    let new_k = FrameTransform::get_new_k(params, &camera_matrix, fov);
+    let horizon_compensation = FrameTransform::get_horizon_compensation(params, frame, true, timestamp_ms, params.framebuffer_inverted);
+    let image_rotation = Matrix3::new_rotation(video_rotation.to_radians());
+
+    let (org, correction) = {
+        let gyro = params.gyro.read();
+        let ts = timestamp_ms + gyro.file_metadata.read().per_frame_time_offsets.get(frame).unwrap_or(&0.0);
+        (gyro.org_quat_at_timestamp(ts), gyro.smoothed_quat_at_timestamp(ts))
+    };
+    let smoothed = org * correction.inverse();
+
+    let orientation = |q: &Quat64| FrameTransform::kernel_rotation(params, q, &Matrix3::identity(), None);
+    let r = if params.suppress_rotation {
+        Matrix3::identity()
+    } else {
+        FrameTransform::kernel_rotation(params, &correction, &image_rotation, horizon_compensation.as_ref())
+    };
+
+    let homography = with_homography.then(|| {
+        let mut translation = Matrix3::identity();
+        translation[(0, 2)] = -zoom_center_x * params.width as f64 / fov;
+        translation[(1, 2)] = -zoom_center_y * params.height as f64 / fov * if params.framebuffer_inverted { -1.0 } else { 1.0 };
+        let h = translation * new_k * r * camera_matrix.try_inverse().unwrap_or_default();
+        let h = h / if h[(2, 2)].abs() > 1e-12 { h[(2, 2)] } else { 1.0 };
+        [h[(0, 0)], h[(0, 1)], h[(0, 2)], h[(1, 0)], h[(1, 1)], h[(1, 2)], h[(2, 0)], h[(2, 1)], h[(2, 2)]]
+    });
+
+    CameraFrame {
+        frame,
+        timestamp_ms,
+        original_quat: convention.orientation(&orientation(&org)),
+        smoothed_quat: convention.orientation(&orientation(&smoothed)),
+        correction_quat: convention.correction(&r),
+        fov,
+        horizontal_fov_deg: (2.0 * (params.output_width as f64 / 2.0 / new_k[(0, 0)]).atan()).to_degrees(),
+        focal_length_px: [camera_matrix[(0, 0)], camera_matrix[(1, 1)]],
+        principal_point_px: [camera_matrix[(0, 2)], camera_matrix[(1, 2)]],
+        homography,
+    }
+}
+
+pub fn to_csv(frames: &[CameraFrame]) -> String {
+    let mut ret = String::from("frame,timestamp_ms");
+    for name in ["original", "smoothed", "correction"] {
+        for axis in ["w", "x", "y", "z"] {
+            ret.push_str(&format!(",{name}_{axis}"));
+        }
+    }
+    ret.push_str(",fov,horizontal_fov_deg,fx,fy,cx,cy");
+    if frames.iter().any(|x| x.homography.is_some()) {
+        (0..9).for_each(|i| ret.push_str(&format!(",h{i}")));
+    }
+    ret.push('\n');
+    for x in frames {
+        ret.push_str(&format!("{},{:.3}", x.frame, x.timestamp_ms));
+        for v in x.original_quat.iter().chain(&x.smoothed_quat).chain(&x.correction_quat) {
+            ret.push_str(&format!(",{v:.9}"));
+        }
+        ret.push_str(&format!(",{:.9},{:.6},{:.6},{:.6},{:.6},{:.6}", x.fov, x.horizontal_fov_deg, x.focal_length_px[0], x.focal_length_px[1], x.principal_point_px[0], x.principal_point_px[1]));
+        if let Some(h) = &x.homography {
+            h.iter().for_each(|v| ret.push_str(&format!(",{v:.9}")));
+        }
+        ret.push('\n');
+    }
+    ret
+}
+
+pub fn to_json(frames: &[CameraFrame]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(frames)
+}
+
+pub fn from_json(json: &str) -> serde_json::Result<Vec<CameraFrame>> {
+    serde_json::from_str(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+    use crate::stabilization::distortion_models::DistortionModel;
+
+    fn params() -> ComputeParams {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.height, params.output_width, params.output_height) = (1920, 1080, 1920, 1080);
+        params.scaled_fps = 30.0;
+        params.frame_count = 60;
+        params.frame_readout_time = 0.0;
+        params.lens = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.0; 5],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        params.distortion_model = DistortionModel::from_name("opencv_standard");
+        params.fovs = vec![0.8; 60];
+        params.video_rotation = 5.0;
+        params.horizon_compensation = (0.0, 2.0);
+        params.keyframes.set(&KeyframeType::ZoomingCenterX, 0, 0.05);
+        {
+            let mut gyro = params.gyro.write();
+            gyro.duration_ms = 2000.0;
+            gyro.quaternions = (0..=200).map(|i| (i * 10_000, Quat64::from_euler_angles(0.002 * i as f64, 0.003 * i as f64, -0.001 * i as f64))).collect();
+            let corrections = gyro.quaternions.iter().map(|(ts, q)| (*ts, Quat64::from_euler_angles(0.0, 0.0, 0.2) * *q)).collect();
+            gyro.set_smoothed_quaternions(corrections);
+        }
+        params
+    }
+
+    #[test]
+    fn kernel_params_from_export() {
+        let params = params();
+        let frames = from_json(&to_json(&per_frame(&params, AxisConvention::OpenCV, true)).unwrap()).unwrap();
+        assert_eq!(frames.len(), 60);
+        for x in frames.iter().step_by(7) {
+            let transform = FrameTransform::at_timestamp(&params, x.timestamp_ms, x.frame);
+            let kernel_params = &transform.kernel_params;
+            assert_eq!(x.fov as f32, kernel_params.fov);
+            assert_eq!([x.focal_length_px[0] as f32, x.focal_length_px[1] as f32], kernel_params.f);
+            assert_eq!([x.principal_point_px[0] as f32, x.principal_point_px[1] as f32], kernel_params.c);
+
+            // The kernel's matrix from the exported camera
+            let q = x.correction_quat;
+            let r = *Quat64::from_quaternion(nalgebra::Quaternion::new(q[0], q[1], q[2], q[3])).to_rotation_matrix().matrix();
+            let mut new_k = Matrix3::identity();
+            new_k[(0, 0)] = params.output_width as f64 / 2.0 / (x.horizontal_fov_deg.to_radians() / 2.0).tan();
+            new_k[(1, 1)] = new_k[(0, 0)] * x.focal_length_px[1] / x.focal_length_px[0];
+            new_k[(0, 2)] = params.output_width as f64 / 2.0;
+            new_k[(1, 2)] = params.output_height as f64 / 2.0;
+            let matrix = (new_k * r).try_inverse().unwrap();
+            for i in 0..9 {
+                assert!((matrix[(i / 3, i % 3)] as f32 - transform.matrices[0][i]).abs() < 1e-5, "{i}: {matrix} {:?}", transform.matrices[0]);
+            }
+
+            // The homography maps the input pixel of an output pixel back onto it
+            let h = Matrix3::from_row_slice(&x.homography.unwrap());
+            let out = nalgebra::Vector3::new(300.0, 200.0, 1.0);
+            let m = &transform.matrices[0];
+            let ray = Matrix3::new(m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7], m[8]).cast::<f64>()
+                * nalgebra::Vector3::new(out.x + kernel_params.translation2d[0] as f64, out.y + kernel_params.translation2d[1] as f64, 1.0);
+            let input = nalgebra::Vector3::new(ray.x / ray.z * x.focal_length_px[0] + x.principal_point_px[0], ray.y / ray.z * x.focal_length_px[1] + x.principal_point_px[1], 1.0);
+            let back = h * input;
+            assert!((back.x / back.z - out.x).abs() < 1e-2 && (back.y / back.z - out.y).abs() < 1e-2, "{back}");
+        }
+        assert_eq!(to_csv(&frames).lines().count(), 61);
+    }
+
+    #[test]
+    fn conventions() {
+        let params = params();
+        let cv = per_frame(&params, AxisConvention::OpenCV, false);
+        let gl = per_frame(&params, AxisConvention::OpenGL, false);
+        let blender = per_frame(&params, AxisConvention::Blender, false);
+        let to_quat = |q: [f64; 4]| Quat64::from_quaternion(nalgebra::Quaternion::new(q[0], q[1], q[2], q[3]));
+        for i in [0, 30, 59] {
+            // The same rotation angle in every convention
+            for (a, b) in [(&cv[i], &gl[i]), (&cv[i], &blender[i])] {
+                assert!((to_quat(a.smoothed_quat).angle_to(&to_quat(a.original_quat)) - to_quat(b.smoothed_quat).angle_to(&to_quat(b.original_quat))).abs() < 1e-9);
+                assert!((to_quat(a.correction_quat).angle() - to_quat(b.correction_quat).angle()).abs() < 1e-9);
+            }
+        }
+        // Without motion the Blender camera looks along +y with z up
+        let mut params = params;
+        params.gyro.write().quaternions.clear();
+        params.gyro.write().set_smoothed_quaternions(Default::default());
+        let q = to_quat(per_frame(&params, AxisConvention::Blender, false)[0].original_quat);
+        assert!((q * -Vector3::z() - Vector3::y()).norm() < 1e-9);
+        assert!((q * Vector3::y() - Vector3::z()).norm() < 1e-9);
+    }
+}
@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Prints the per-frame transform of a project, the way a plugin host would query it.
+// Usage: cargo run --example plugin_frame_transform -- project.gyroflow
+
+use gyroflow_core::{ StabilizationManager, filesystem, plugin_api::PluginFrameTransform };
+use std::sync::{ Arc, atomic::AtomicBool };
+
+fn main() {
+    let path = std::env::args().nth(1).expect("Usage: plugin_frame_transform <project.gyroflow>");
+    let stab = StabilizationManager::default();
+    stab.import_gyroflow_file(&filesystem::path_to_url(&path), true, |_| (), Arc::new(AtomicBool::new(false)), true).expect("Failed to load the project");
+
+    let (fps, num_frames) = { let params = stab.params.read(); (params.fps, params.frame_count) };
+    let mut out = PluginFrameTransform::default();
+    let mut matrices = vec![[0.0f32; 14]; stab.params.read().size.1.max(1)];
+    for frame in 0..num_frames {
+        let timestamp_us = (frame as f64 * 1_000_000.0 / fps).round() as i64;
+        match stab.fill_frame_transform(timestamp_us, &mut out, &mut matrices, None) {
+            Ok(()) => println!("{frame}: fov {:.4}, focal length {:?}, correction {:?}, {} matrices", out.fov, out.focal_length, out.correction_quat, out.matrix_count),
+            Err(e) => eprintln!("{frame}: {e:?}"),
+        }
+    }
+}
@@ -434,6 +434,36 @@ pub fn write(url: &str, data: &[u8]) -> Result<()> {
     stop_accessing_url(url, false);
     Ok(())
 }
+/// Writes to a temporary file next to `url` and renames it over the target, so the file is never left half-written.
+/// Falls back to `write` where that's not possible, like the Android content urls or a read-only folder
+pub fn write_atomic(url: &str, data: &[u8]) -> Result<()> {
+    dbg_call!(url);
+    if cfg!(target_os = "android") || url.starts_with("content://") {
+        return write(url, data);
+    }
+    let path = url_to_pathbuf(url)?;
+    let Some(file_name) = path.file_name() else { return write(url, data); };
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    start_accessing_url(url, false);
+    let result = (|| -> std::io::Result<()> {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        drop(f);
+        rename(&tmp_path, &path)
+    })();
+    stop_accessing_url(url, false);
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            log::warn!("Atomic write failed, writing in place url={url} error={e:?}");
+            let _ = std::fs::remove_file(&tmp_path);
+            write(url, data)
+        }
+    }
+}
 pub fn read_to_string(url: &str) -> Result<String> {
     dbg_call!(url);
     let data = read(url)?;
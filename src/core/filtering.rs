@@ -71,6 +71,80 @@ impl Lowpass {
         }
         Ok(())
     }
+    /// Zero-phase low-pass filter of the gyro and accelerometer streams with separate cutoffs (0 = disabled).
+    /// If the sample spacing isn't uniform, the data is resampled to the median interval, filtered and interpolated back
+    pub fn filter_imu_forward_backward(gyro_freq: f64, accl_freq: f64, data: &mut [TimeIMU]) -> Result<(), biquad::Errors> {
+        if data.len() < 3 { return Ok(()); }
+        for (freq, is_accl) in [(gyro_freq, false), (accl_freq, true)] {
+            if freq <= 0.0 { continue; }
+
+            let (timestamps, mut values): (Vec<f64>, Vec<[f64; 3]>) = data.iter().filter_map(|x| {
+                let v = if is_accl { x.accl } else { x.gyro };
+                Some((x.timestamp_ms, v?))
+            }).unzip();
+            if values.len() < 3 { continue; }
+
+            let mut intervals = timestamps.windows(2).map(|w| w[1] - w[0]).filter(|x| *x > 0.0).collect::<Vec<f64>>();
+            if intervals.is_empty() { continue; }
+            intervals.sort_by(|a, b| a.total_cmp(b));
+            let interval_ms = intervals[intervals.len() / 2];
+            let sample_rate = 1000.0 / interval_ms;
+            let is_uniform = intervals[0] > interval_ms * 0.9 && intervals[intervals.len() - 1] < interval_ms * 1.1;
+
+            if is_uniform {
+                Self::filter_vec3_forward_backward(freq, sample_rate, &mut values)?;
+            } else {
+                let first_ts = timestamps[0];
+                let count = ((timestamps[timestamps.len() - 1] - first_ts) / interval_ms).floor() as usize + 1;
+                let mut resampled = Vec::with_capacity(count);
+                let mut j = 0;
+                for i in 0..count {
+                    let ts = first_ts + i as f64 * interval_ms;
+                    while j + 2 < timestamps.len() && timestamps[j + 1] < ts { j += 1; }
+                    resampled.push(Self::lerp_vec3(timestamps[j], &values[j], timestamps[j + 1], &values[j + 1], ts));
+                }
+                Self::filter_vec3_forward_backward(freq, sample_rate, &mut resampled)?;
+                for (ts, v) in timestamps.iter().zip(values.iter_mut()) {
+                    let pos = ((ts - first_ts) / interval_ms).max(0.0);
+                    let i = (pos.floor() as usize).min(count.saturating_sub(2));
+                    let t1 = first_ts + i as f64 * interval_ms;
+                    *v = Self::lerp_vec3(t1, &resampled[i], t1 + interval_ms, &resampled[(i + 1).min(count - 1)], *ts);
+                }
+            }
+
+            let mut values = values.into_iter();
+            for x in data.iter_mut() {
+                let slot = if is_accl { &mut x.accl } else { &mut x.gyro };
+                if slot.is_some() {
+                    *slot = values.next();
+                }
+            }
+        }
+        Ok(())
+    }
+    fn filter_vec3_forward_backward(freq: f64, sample_rate: f64, data: &mut [[f64; 3]]) -> Result<(), biquad::Errors> {
+        let mut forward = Self::new(freq, sample_rate)?;
+        let mut backward = Self::new(freq, sample_rate)?;
+        for v in data.iter_mut() {
+            v[0] = forward.run(0, v[0]);
+            v[1] = forward.run(1, v[1]);
+            v[2] = forward.run(2, v[2]);
+        }
+        for v in data.iter_mut().rev() {
+            v[0] = backward.run(0, v[0]);
+            v[1] = backward.run(1, v[1]);
+            v[2] = backward.run(2, v[2]);
+        }
+        Ok(())
+    }
+    fn lerp_vec3(t1: f64, v1: &[f64; 3], t2: f64, v2: &[f64; 3], t: f64) -> [f64; 3] {
+        let fract = if t2 > t1 { ((t - t1) / (t2 - t1)).clamp(0.0, 1.0) } else { 0.0 };
+        [
+            v1[0] + (v2[0] - v1[0]) * fract,
+            v1[1] + (v2[1] - v1[1]) * fract,
+            v1[2] + (v2[2] - v1[2]) * fract,
+        ]
+    }
     pub fn filter_quats_forward_backward(freq: f64, sample_rate: f64, data: &mut TimeQuat) -> Result<(), biquad::Errors> {
         let mut forward = Self::new(freq, sample_rate)?;
         let mut backward = Self::new(freq, sample_rate)?;
@@ -159,3 +233,56 @@ impl Median {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 Hz motion with 80 Hz vibration on top, sampled at about 1 kHz
+    fn samples(jitter: bool) -> Vec<TimeIMU> {
+        (0..3000).map(|i| {
+            let ts = i as f64 + if jitter { 0.3 * (i as f64 * 1.7).sin() } else { 0.0 };
+            let motion = (ts / 1000.0 * std::f64::consts::TAU).sin();
+            let vibration = 0.5 * (ts / 1000.0 * 80.0 * std::f64::consts::TAU).sin();
+            TimeIMU { timestamp_ms: ts, gyro: Some([motion + vibration, -motion, vibration]), accl: Some([vibration, 0.0, 1.0 + vibration]), magn: None }
+        }).collect()
+    }
+    fn max_error(data: &[TimeIMU], accl: bool) -> f64 {
+        // Without the edges, where the filter settles
+        data[300..data.len() - 300].iter().map(|x| {
+            let motion = (x.timestamp_ms / 1000.0 * std::f64::consts::TAU).sin();
+            let v = if accl { x.accl.unwrap() } else { x.gyro.unwrap() };
+            let expected = if accl { [0.0, 0.0, 1.0] } else { [motion, -motion, 0.0] };
+            (0..3).map(|i| (v[i] - expected[i]).abs()).fold(0.0, f64::max)
+        }).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn forward_backward() {
+        for jitter in [false, true] {
+            let org = samples(jitter);
+            let mut data = org.clone();
+            Lowpass::filter_imu_forward_backward(10.0, 0.0, &mut data).unwrap();
+            // Zero phase, so the motion isn't delayed
+            assert!(max_error(&data, false) < if jitter { 0.03 } else { 0.005 }, "jitter: {jitter}, error: {}", max_error(&data, false));
+            // Timestamps and the disabled accelerometer are untouched
+            assert!(data.iter().zip(&org).all(|(a, b)| a.timestamp_ms == b.timestamp_ms && a.accl == b.accl));
+
+            let mut data = org.clone();
+            Lowpass::filter_imu_forward_backward(0.0, 5.0, &mut data).unwrap();
+            assert!(max_error(&data, true) < if jitter { 0.03 } else { 0.005 });
+            assert!(data.iter().zip(&org).all(|(a, b)| a.gyro == b.gyro));
+        }
+
+        // Samples without the stream are left out
+        let mut data = samples(false);
+        for x in data.iter_mut().step_by(2) { x.accl = None; }
+        Lowpass::filter_imu_forward_backward(10.0, 10.0, &mut data).unwrap();
+        assert!(data.iter().step_by(2).all(|x| x.accl.is_none()));
+        assert!(data.iter().skip(1).step_by(2).all(|x| x.accl.is_some()));
+
+        // Nothing to filter
+        let mut short = samples(false)[..2].to_vec();
+        assert!(Lowpass::filter_imu_forward_backward(10.0, 10.0, &mut short).is_ok());
+    }
+}
@@ -0,0 +1,397 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Memory budget shared by everything that keeps frames around: the GPU wrappers with their textures and staging buffers,
+// the preview output, the sync analysis frames and the thumbnails. Each cache registers its entries with their size in bytes
+// and the least recently used ones are evicted when the total goes over the budget.
+// Pinned entries (frame on screen, being analyzed or encoded) are never evicted, so they can temporarily go past the budget.
+//
+// There are two kinds of entries:
+// - `insert`/`get`: the value is owned by the frame cache and dropped on eviction
+// - `track`: the memory is owned by the caller (eg. a thread-local LRU of GPU wrappers), the returned `Entry` only does the accounting.
+//   On eviction the entry is marked and the owner is expected to drop it the next time it looks at its cache.
+//   It's still counted until then, because the memory is still there, but the eviction doesn't wait for it to free more.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst };
+use parking_lot::Mutex;
+
+pub const DEFAULT_BUDGET_MB: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub enum CacheKind {
+    Preview,
+    Analysis,
+    Thumbnail,
+    GpuBuffers,
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct CacheUsage {
+    pub budget: usize,
+    pub used: usize,
+    pub pinned: usize,
+    pub releasing: usize, // Evicted, but not dropped by the owner yet
+    pub entries: usize,
+    pub by_kind: BTreeMap<CacheKind, usize>,
+}
+
+struct Slot {
+    kind: CacheKind,
+    key: Option<u64>,
+    size: usize,
+    last_used: u64,
+    pins: usize,
+    evicted: Arc<AtomicBool>,
+    releasing: bool,
+    value: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+#[derive(Default)]
+struct State {
+    slots: BTreeMap<u64, Slot>,
+    lru: BTreeMap<u64, u64>, // last_used -> slot id
+    keys: BTreeMap<(CacheKind, u64), u64>,
+    used: usize,
+    releasing: usize,
+    tick: u64,
+}
+impl State {
+    fn touch(&mut self, id: u64) {
+        self.tick += 1;
+        if let Some(slot) = self.slots.get_mut(&id).filter(|x| !x.releasing) {
+            self.lru.remove(&slot.last_used);
+            slot.last_used = self.tick;
+            self.lru.insert(self.tick, id);
+        }
+    }
+    fn remove(&mut self, id: u64) -> Option<Slot> {
+        let slot = self.slots.remove(&id)?;
+        self.lru.remove(&slot.last_used);
+        if let Some(key) = slot.key {
+            if self.keys.get(&(slot.kind, key)) == Some(&id) {
+                self.keys.remove(&(slot.kind, key));
+            }
+        }
+        self.used -= slot.size;
+        if slot.releasing {
+            self.releasing -= slot.size;
+        }
+        Some(slot)
+    }
+    // A value owned by the cache is removed right away. The memory of the caller stays counted until it drops the `Entry`
+    fn evict_one(&mut self, id: u64) -> Option<Arc<dyn Any + Send + Sync>> {
+        let slot = self.slots.get_mut(&id)?;
+        slot.evicted.store(true, SeqCst);
+        if slot.value.is_some() {
+            return self.remove(id).and_then(|x| x.value);
+        }
+        if !slot.releasing {
+            slot.releasing = true;
+            self.releasing += slot.size;
+            let last_used = slot.last_used;
+            self.lru.remove(&last_used);
+        }
+        None
+    }
+    // Evicts the oldest unpinned entries until the usage fits in `budget`. `keep` is the entry which caused the eviction.
+    // Returns the evicted values, so they can be dropped after the lock is released
+    fn evict(&mut self, budget: usize, keep: Option<u64>) -> Vec<Arc<dyn Any + Send + Sync>> {
+        let mut dropped = Vec::new();
+        while self.used - self.releasing > budget {
+            let id = self.lru.values().copied().find(|id| Some(*id) != keep && self.slots.get(id).is_some_and(|x| x.pins == 0));
+            let Some(id) = id else { break; };
+            dropped.extend(self.evict_one(id));
+        }
+        dropped
+    }
+}
+
+pub struct FrameCache {
+    budget: AtomicUsize,
+    next_id: AtomicU64,
+    state: Mutex<State>,
+}
+
+static CACHE: FrameCache = FrameCache::new();
+
+impl FrameCache {
+    pub const fn new() -> Self {
+        Self {
+            budget: AtomicUsize::new(0),
+            next_id: AtomicU64::new(1),
+            state: Mutex::new(State { slots: BTreeMap::new(), lru: BTreeMap::new(), keys: BTreeMap::new(), used: 0, releasing: 0, tick: 0 }),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        let budget = self.budget.load(SeqCst);
+        if budget > 0 { return budget; }
+        let budget = (crate::settings::get_u64("frameCacheBudgetMB", DEFAULT_BUDGET_MB).max(1) * 1024 * 1024) as usize;
+        self.budget.store(budget, SeqCst);
+        budget
+    }
+    pub fn set_budget(&self, bytes: usize) {
+        let bytes = bytes.max(1);
+        self.budget.store(bytes, SeqCst);
+        let dropped = self.state.lock().evict(bytes, None);
+        drop(dropped);
+    }
+
+    fn add(&'static self, kind: CacheKind, key: Option<u64>, size: usize, pins: usize, value: Option<Arc<dyn Any + Send + Sync>>) -> (u64, Arc<AtomicBool>) {
+        let id = self.next_id.fetch_add(1, SeqCst);
+        let evicted = Arc::new(AtomicBool::new(false));
+        let budget = self.budget();
+        let mut state = self.state.lock();
+        let mut dropped: Vec<Arc<dyn Any + Send + Sync>> = Vec::new();
+        if let Some(key) = key {
+            if let Some(prev) = state.keys.insert((kind, key), id) {
+                dropped.extend(state.remove(prev).and_then(|x| x.value));
+            }
+        }
+        state.slots.insert(id, Slot { kind, key, size, last_used: 0, pins, evicted: evicted.clone(), releasing: false, value });
+        state.used += size;
+        state.touch(id);
+        dropped.extend(state.evict(budget, Some(id)));
+        drop(state);
+        drop(dropped);
+        (id, evicted)
+    }
+
+    pub fn insert<T: Any + Send + Sync>(&'static self, kind: CacheKind, key: u64, value: T, size: usize) {
+        self.add(kind, Some(key), size, 0, Some(Arc::new(value)));
+    }
+    pub fn get<T: Any + Send + Sync>(&self, kind: CacheKind, key: u64) -> Option<Arc<T>> {
+        let mut state = self.state.lock();
+        let id = *state.keys.get(&(kind, key))?;
+        state.touch(id);
+        state.slots.get(&id)?.value.clone()?.downcast::<T>().ok()
+    }
+    pub fn remove(&self, kind: CacheKind, key: u64) {
+        let mut state = self.state.lock();
+        let slot = state.keys.get(&(kind, key)).copied().and_then(|id| state.remove(id));
+        drop(state);
+        drop(slot);
+    }
+    pub fn pin(&'static self, kind: CacheKind, key: u64) -> Option<PinGuard> {
+        let id = *self.state.lock().keys.get(&(kind, key))?;
+        self.pin_id(id)
+    }
+    fn pin_id(&'static self, id: u64) -> Option<PinGuard> {
+        let mut state = self.state.lock();
+        state.slots.get_mut(&id)?.pins += 1;
+        state.touch(id);
+        Some(PinGuard { cache: self, id })
+    }
+
+    pub fn track(&'static self, kind: CacheKind, size: usize) -> Entry {
+        let (id, evicted) = self.add(kind, None, size, 0, None);
+        Entry { cache: self, id, evicted }
+    }
+    pub fn track_pinned(&'static self, kind: CacheKind, size: usize) -> Entry {
+        let (id, evicted) = self.add(kind, None, size, 1, None);
+        Entry { cache: self, id, evicted }
+    }
+
+    pub fn clear(&self, kind: CacheKind) {
+        let mut state = self.state.lock();
+        let ids = state.slots.iter().filter(|(_, x)| x.kind == kind && x.pins == 0).map(|(id, _)| *id).collect::<Vec<_>>();
+        let dropped = ids.into_iter().filter_map(|id| state.evict_one(id)).collect::<Vec<_>>();
+        drop(state);
+        drop(dropped);
+    }
+
+    pub fn usage(&self) -> CacheUsage {
+        let budget = self.budget();
+        let state = self.state.lock();
+        let mut ret = CacheUsage { budget, used: state.used, releasing: state.releasing, entries: state.slots.len(), ..Default::default() };
+        for slot in state.slots.values() {
+            *ret.by_kind.entry(slot.kind).or_default() += slot.size;
+            if slot.pins > 0 { ret.pinned += slot.size; }
+        }
+        ret
+    }
+}
+
+/// Accounting for memory owned by the caller. Removed from the cache on drop, also after it was evicted
+pub struct Entry {
+    cache: &'static FrameCache,
+    id: u64,
+    evicted: Arc<AtomicBool>,
+}
+impl Entry {
+    /// The budget was exceeded and this entry should be dropped by its owner
+    pub fn is_evicted(&self) -> bool { self.evicted.load(SeqCst) }
+    pub fn touch(&self) { self.cache.state.lock().touch(self.id); }
+    pub fn pin(&self) -> Option<PinGuard> { self.cache.pin_id(self.id) }
+    pub fn unpin(&self) {
+        if let Some(slot) = self.cache.state.lock().slots.get_mut(&self.id) {
+            slot.pins = slot.pins.saturating_sub(1);
+        }
+    }
+    pub fn resize(&self, size: usize) {
+        let budget = self.cache.budget();
+        let mut state = self.cache.state.lock();
+        if let Some(slot) = state.slots.get_mut(&self.id) {
+            let prev = std::mem::replace(&mut slot.size, size);
+            if slot.releasing {
+                state.releasing = state.releasing - prev + size;
+            }
+            state.used = state.used - prev + size;
+            let dropped = state.evict(budget, Some(self.id));
+            drop(state);
+            drop(dropped);
+        }
+    }
+}
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let slot = self.cache.state.lock().remove(self.id);
+        drop(slot);
+    }
+}
+
+pub struct PinGuard {
+    cache: &'static FrameCache,
+    id: u64,
+}
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        if let Some(slot) = self.cache.state.lock().slots.get_mut(&self.id) {
+            slot.pins = slot.pins.saturating_sub(1);
+        }
+    }
+}
+
+/// Value owned by the caller together with its accounting
+pub struct Cached<T> {
+    pub value: T,
+    pub entry: Entry,
+}
+impl<T> Cached<T> {
+    pub fn new(kind: CacheKind, size: usize, value: T) -> Self {
+        Self { value, entry: track(kind, size) }
+    }
+    /// For values which can't be recreated, eg. the frames being analyzed. Pin a `new` one while it's used instead if it can be
+    pub fn pinned(kind: CacheKind, size: usize, value: T) -> Self {
+        Self { value, entry: track_pinned(kind, size) }
+    }
+}
+impl<T> std::ops::Deref for Cached<T> {
+    type Target = T;
+    fn deref(&self) -> &T { &self.value }
+}
+
+pub fn budget() -> usize { CACHE.budget() }
+pub fn set_budget(bytes: usize) { CACHE.set_budget(bytes) }
+pub fn set_budget_mb(mb: u64) {
+    crate::settings::set("frameCacheBudgetMB", mb.into());
+    CACHE.set_budget((mb.max(1) * 1024 * 1024) as usize);
+}
+pub fn usage() -> CacheUsage { CACHE.usage() }
+pub fn insert<T: Any + Send + Sync>(kind: CacheKind, key: u64, value: T, size: usize) { CACHE.insert(kind, key, value, size) }
+pub fn get<T: Any + Send + Sync>(kind: CacheKind, key: u64) -> Option<Arc<T>> { CACHE.get(kind, key) }
+pub fn remove(kind: CacheKind, key: u64) { CACHE.remove(kind, key) }
+pub fn pin(kind: CacheKind, key: u64) -> Option<PinGuard> { CACHE.pin(kind, key) }
+pub fn track(kind: CacheKind, size: usize) -> Entry { CACHE.track(kind, size) }
+pub fn track_pinned(kind: CacheKind, size: usize) -> Entry { CACHE.track_pinned(kind, size) }
+pub fn clear(kind: CacheKind) { CACHE.clear(kind) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_cache(budget: usize) -> &'static FrameCache {
+        let cache = Box::leak(Box::new(FrameCache::new()));
+        cache.set_budget(budget);
+        cache
+    }
+
+    // Bytes really allocated by the values of a test, freed on drop
+    static HELD: AtomicUsize = AtomicUsize::new(0);
+    struct Held(#[allow(dead_code)] Vec<u8>);
+    impl Held {
+        fn new(size: usize) -> Self {
+            HELD.fetch_add(size, SeqCst);
+            Self(vec![0u8; size])
+        }
+    }
+    impl Drop for Held {
+        fn drop(&mut self) { HELD.fetch_sub(self.0.len(), SeqCst); }
+    }
+
+    #[test]
+    fn random_scrubbing() {
+        const FRAME: usize = 1920 * 1080 * 4;
+        let budget = FRAME * 10;
+        let cache = new_cache(budget);
+        let rng = fastrand::Rng::with_seed(1234);
+
+        // Thread-local GPU wrappers, dropped by the owner when evicted
+        let mut gpu: Vec<Cached<Held>> = Vec::new();
+        let mut gpu_evictions = 0;
+        let mut displayed: Option<PinGuard> = None;
+        let mut pos = 0u64;
+        for i in 0..5000 {
+            // The owner looks at its cache before the next frame, like `drop_evicted_instances`
+            let before = gpu.len();
+            gpu.retain(|x| !x.entry.is_evicted());
+            gpu_evictions += before - gpu.len();
+            let usage = cache.usage();
+            assert_eq!(usage.used, HELD.load(SeqCst), "{usage:?}");
+            assert_eq!(usage.releasing, 0);
+            assert!(usage.used <= budget + FRAME * 3, "{usage:?}");
+
+            pos = if rng.u8(..) < 200 { (pos + 1) % 500 } else { rng.u64(..500) };
+            if cache.get::<Held>(CacheKind::Preview, pos).is_none() {
+                cache.insert(CacheKind::Preview, pos, Held::new(FRAME), FRAME);
+            }
+            displayed = cache.pin(CacheKind::Preview, pos); // Releases the previous one
+            if i % 50 == 0 {
+                gpu.push(Cached::new(CacheKind::GpuBuffers, FRAME * 3, Held::new(FRAME * 3)));
+            }
+            if i % 7 == 0 {
+                cache.insert(CacheKind::Thumbnail, i as u64, Held::new(50 * 90 * 4), 50 * 90 * 4);
+            }
+            // The evicted GPU buffers are still allocated until the owner drops them, so they're still counted
+            let usage = cache.usage();
+            assert_eq!(usage.used, HELD.load(SeqCst), "{usage:?}");
+            assert!(usage.used - usage.releasing <= budget + FRAME * 3, "{usage:?}");
+            assert!(cache.get::<Held>(CacheKind::Preview, pos).is_some());
+        }
+        assert!(gpu_evictions > 0);
+        assert!(displayed.is_some());
+        drop(displayed);
+        drop(gpu);
+        cache.clear(CacheKind::Preview);
+        cache.clear(CacheKind::Thumbnail);
+        assert_eq!(cache.usage().used, 0);
+        assert_eq!(HELD.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn pinned_entries() {
+        let cache = new_cache(100);
+        let analysis = (0..5).map(|_| cache.track_pinned(CacheKind::Analysis, 30)).collect::<Vec<_>>();
+        let tracked = cache.track(CacheKind::GpuBuffers, 50);
+        cache.insert(CacheKind::Preview, 1, 1u8, 10);
+        assert!(tracked.is_evicted());
+        assert!(analysis.iter().all(|x| !x.is_evicted()));
+        assert_eq!(cache.usage().pinned, 150);
+
+        drop(analysis);
+        // The evicted one is counted until it's dropped
+        let usage = cache.usage();
+        assert_eq!((usage.used, usage.releasing, usage.entries), (60, 50, 2));
+        drop(tracked);
+        let usage = cache.usage();
+        assert_eq!((usage.used, usage.releasing, usage.entries), (10, 0, 1));
+
+        let _pin = cache.pin(CacheKind::Preview, 1).unwrap();
+        cache.insert(CacheKind::Preview, 2, 2u8, 95);
+        assert_eq!(*cache.get::<u8>(CacheKind::Preview, 1).unwrap(), 1);
+        assert!(cache.get::<u16>(CacheKind::Preview, 1).is_none());
+    }
+}
@@ -149,6 +149,44 @@ impl<'a> Buffers<'a> {
     }
 }
 
+/// Time spent in each stage of processing a frame. Only collected after `set_stage_profiling(true)` (for the benchmark),
+/// because the device has to be waited on after each stage
+#[derive(Default, Clone, Copy, Debug, serde::Serialize)]
+pub struct StageTimings {
+    pub upload_ms: f64,
+    pub kernel_ms: f64,
+    pub readback_ms: f64,
+}
+thread_local! {
+    static STAGE_PROFILING: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    static STAGE_TIMINGS: std::cell::Cell<Option<StageTimings>> = std::cell::Cell::new(None);
+}
+pub fn set_stage_profiling(enabled: bool) { STAGE_PROFILING.with(|x| x.set(enabled)); }
+/// Timings of the last frame processed on this thread
+pub fn take_stage_timings() -> Option<StageTimings> { STAGE_TIMINGS.with(|x| x.take()) }
+
+pub(crate) struct StageTimer {
+    last: std::time::Instant,
+    timings: StageTimings,
+}
+impl StageTimer {
+    pub fn new() -> Option<Self> {
+        STAGE_PROFILING.with(|x| x.get()).then(|| Self { last: std::time::Instant::now(), timings: StageTimings::default() })
+    }
+    fn lap(&mut self) -> f64 {
+        let now = std::time::Instant::now();
+        let ms = (now - self.last).as_secs_f64() * 1000.0;
+        self.last = now;
+        ms
+    }
+    pub fn upload_done(&mut self) { self.timings.upload_ms = self.lap(); }
+    pub fn kernel_done(&mut self) { self.timings.kernel_ms = self.lap(); }
+    pub fn finish(mut self) {
+        self.timings.readback_ms = self.lap();
+        STAGE_TIMINGS.with(|x| x.set(Some(self.timings)));
+    }
+}
+
 pub fn initialize_contexts() -> Option<(String, String)> {
     #[cfg(feature = "use-opencl")]
     if std::env::var("NO_OPENCL").unwrap_or_default().is_empty() {
@@ -181,10 +181,10 @@ impl OclWrapper {
         let mut kernel = include_str!("opencl_undistort.cl").to_string();
         // let mut kernel = std::fs::read_to_string("D:/programowanie/projekty/Rust/gyroflow/src/core/gpu/opencl_undistort.cl").unwrap();
 
-        let mut lens_model_functions = distortion_model.opencl_functions().to_string();
+        let mut lens_model_functions = distortion_model.opencl_functions()?.to_string();
         let default_digital_lens = "float2 digital_undistort_point(float2 uv, __global KernelParams *p) { return uv; }
                                         float2 digital_distort_point(float2 uv, __global KernelParams *p) { return uv; }";
-        lens_model_functions.push_str(digital_lens.as_ref().map(|x| x.opencl_functions()).unwrap_or(default_digital_lens));
+        lens_model_functions.push_str(digital_lens.as_ref().map(|x| x.opencl_functions()).transpose()?.unwrap_or(default_digital_lens));
 
         let mut extensions = String::new();
         if ocl_names.1 == "convert_half4" {
@@ -327,11 +327,17 @@ impl OclWrapper {
         }
     }
 
+    /// Device memory held by this instance: the source and destination buffers and the kernel parameters
+    pub fn memory_size(&self) -> usize {
+        self.src.len() + self.dst.len() + self.buf_params.len() + self.buf_drawing.len() + (self.buf_mesh_data.len() + self.buf_matrices.len()) * std::mem::size_of::<f32>()
+    }
+
     pub fn undistort_image(&self, buffers: &mut Buffers, itm: &crate::stabilization::FrameTransform, drawing_buffer: &[u8]) -> ocl::Result<()> {
         let matrices = unsafe { std::slice::from_raw_parts(itm.matrices.as_ptr() as *const f32, itm.matrices.len() * 14 ) };
 
         let mut _temp1 = None;
         let mut _temp2 = None;
+        let mut timer = super::StageTimer::new();
 
         if self.buf_matrices.len() < matrices.len() { log::error!("Buffer size mismatch matrices! {} vs {}", self.buf_matrices.len(), matrices.len()); return Ok(()); }
 
@@ -404,8 +410,16 @@ impl OclWrapper {
 
         self.buf_params.write(bytemuck::bytes_of(&itm.kernel_params)).enq()?;
         self.buf_matrices.write(matrices).enq()?;
+        if let Some(timer) = timer.as_mut() {
+            self.queue.finish()?;
+            timer.upload_done();
+        }
 
         unsafe { self.kernel.enq()?; }
+        if let Some(timer) = timer.as_mut() {
+            self.queue.finish()?;
+            timer.kernel_done();
+        }
 
         match &mut buffers.output.data {
             BufferSource::None => { },
@@ -443,6 +457,10 @@ impl OclWrapper {
         }
 
         // self.queue.finish();
+        if let Some(timer) = timer {
+            self.queue.finish()?;
+            timer.finish();
+        }
 
         Ok(())
     }
@@ -47,7 +47,7 @@ typedef struct {
     float pixel_value_limit;         // 16
     float light_refraction_coefficient; // 4
     int plane_index;                 // 8
-    float reserved1;                 // 12
+    int alpha_channel;               // 12
     float reserved2;                 // 16
     float4 ewa_coeffs_p;             // 16
     float4 ewa_coeffs_q;             // 16
@@ -299,6 +299,10 @@ DATA_TYPEF sample_input_at(float2 uv, float4 jac, __global const uchar *srcptr,
     bool fix_range = (params->flags & 1);
 
     DATA_TYPEF sum = 0;
+    // With a transparent background, the part of the footprint inside of the source gives the color and the alpha is its coverage
+    DATA_TYPEF covered = 0;
+    float coverage = 0.0f;
+    float total_weight = 1.0f;
 
 #   if INTERPOLATION > 8
         // find how many pixels we need around that pixel in each direction
@@ -329,6 +333,8 @@ DATA_TYPEF sample_input_at(float2 uv, float4 jac, __global const uchar *srcptr,
                     DATA_TYPE src_px = *(__global const DATA_TYPE *)&srcptr[src_index + in_x * PIXEL_BYTES];
                     draw_pixel(&src_px, in_x, in_y, true, max(params->width, params->output_width), params, drawing);
                     srcpx = DATA_CONVERTF(src_px);
+                    covered += k * srcpx;
+                    coverage += k;
                 } else {
                     srcpx = bg;
                 }
@@ -338,6 +344,7 @@ DATA_TYPEF sample_input_at(float2 uv, float4 jac, __global const uchar *srcptr,
             src_index += params->stride;
         }
         sum /= sum_div;
+        total_weight = sum_div;
 #   else
         uv -= S_OFFSET;
         // uv -= (INTERPOLATION >> 1) - 1;
@@ -359,6 +366,8 @@ DATA_TYPEF sample_input_at(float2 uv, float4 jac, __global const uchar *srcptr,
         for (int yp = 0; yp < INTERPOLATION; ++yp) {
             if (sy + yp >= params->source_rect.y && sy + yp < params->source_rect.y + params->source_rect.w) {
                 DATA_TYPEF xsum = 0.0f;
+                DATA_TYPEF xcovered = 0.0f;
+                float xcoverage = 0.0f;
                 #pragma unroll
                 for (int xp = 0; xp < INTERPOLATION; ++xp) {
                     if (sx + xp >= params->source_rect.x && sx + xp < params->source_rect.x + params->source_rect.z) {
@@ -366,11 +375,15 @@ DATA_TYPEF sample_input_at(float2 uv, float4 jac, __global const uchar *srcptr,
                         draw_pixel(&src_px, sx + xp, sy + yp, true, max(params->width, params->output_width), params, drawing);
                         DATA_TYPEF srcpx = DATA_CONVERTF(src_px);
                         xsum += srcpx * coeffs_x[xp];
+                        xcovered += srcpx * coeffs_x[xp];
+                        xcoverage += coeffs_x[xp];
                     } else {
                         xsum += bg * coeffs_x[xp];
                     }
                 }
                 sum += xsum * coeffs_y[yp];
+                covered += xcovered * coeffs_y[yp];
+                coverage += xcoverage * coeffs_y[yp];
             } else {
                 sum += bg * coeffs_y[yp];
             }
@@ -378,6 +391,14 @@ DATA_TYPEF sample_input_at(float2 uv, float4 jac, __global const uchar *srcptr,
         }
 #   endif
 
+    if (params->flags & 4096) { // Transparent background
+        DATA_TYPEF straight = coverage > 0.001f * total_weight ? covered / coverage : bg;
+        if (params->alpha_channel >= 0) {
+            ((float *)&straight)[params->alpha_channel] = fmax(((float *)&sum)[params->alpha_channel], 0.0f);
+        }
+        sum = straight;
+    }
+
     if (fix_range) {
         sum = remap_colorrange(sum, params->plane_index == 0, params);
     }
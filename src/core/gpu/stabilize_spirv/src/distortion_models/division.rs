@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Division model (Fitzgibbon): Ru = Rd / (1 + k1 * Rd^2 + k2 * Rd^4)
+
+use crate::types::*;
+use crate::glam::{ Vec2, vec2, Vec3 };
+
+pub struct Division { }
+
+const NEWTON_EPS: f32 = 0.000001;
+
+impl Division {
+    pub fn undistort_point(point: Vec2, params: &KernelParams) -> Vec2 {
+        let r2 = point.x * point.x + point.y * point.y;
+        let d = 1.0 + params.k1.x * r2 + params.k1.y * r2 * r2;
+        if d <= 0.0 { return vec2(-99999.0, -99999.0); }
+        point / d
+    }
+
+    pub fn distort_point(point: Vec3, params: &KernelParams) -> Vec2 {
+        let pos = vec2(point.x / point.z, point.y / point.z);
+        let ru = pos.length();
+        if ru == 0.0 { return pos; }
+
+        // Newton's method for: ru * (1 + k1 * rd^2 + k2 * rd^4) - rd = 0
+        let mut rd = ru;
+        let mut i = 0; while i < 10 {
+            let rd2 = rd * rd;
+            let f = ru * (1.0 + params.k1.x * rd2 + params.k1.y * rd2 * rd2) - rd;
+            if f > -NEWTON_EPS && f < NEWTON_EPS {
+                if rd < 0.0 { break; }
+                return pos * (rd / ru);
+            }
+            let df = ru * (2.0 * params.k1.x * rd + 4.0 * params.k1.y * rd2 * rd) - 1.0;
+            if df == 0.0 { break; }
+            rd -= f / df;
+            i += 1;
+        }
+        vec2(-99999.0, -99999.0)
+    }
+
+    #[cfg(not(target_arch = "spirv"))]
+    pub fn adjust_lens_profile(_calib_w: &mut usize, _calib_h: &mut usize/*, lens_model: &mut String*/) { }
+}
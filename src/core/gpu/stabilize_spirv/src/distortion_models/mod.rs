@@ -8,6 +8,7 @@ pub mod poly5;
 pub mod ptlens;
 pub mod insta360;
 pub mod sony;
+pub mod division;
 
 pub mod gopro_superview;
 pub mod gopro_hyperview;
@@ -71,6 +72,9 @@ impl_models! {
     gopro_superview::GoProSuperview,
     gopro_hyperview::GoProHyperview,
     digital_stretch::DigitalStretch,
+
+    // Added after the digital lenses to keep the ids of the existing models
+    division::Division,
 }
 
 mod none {
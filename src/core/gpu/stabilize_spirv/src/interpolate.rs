@@ -52,22 +52,30 @@ pub fn sample_input_at(uv: Vec2, _coeffs: &[f32], input: &ImageType, params: &Ke
         let coeffs_y = ind + ((sy0 as usize & (INTER_TAB_SIZE - 1)) << shift);
 
         let mut sum = Vec4::splat(0.0);
+        // With a transparent background, the part of the footprint inside of the source gives the color and the alpha is its coverage
+        let mut covered = Vec4::splat(0.0);
+        let mut coverage = 0.0;
         let mut _src_index = sy as isize * params.stride as isize + sx as isize * params.bytes_per_pixel as isize;
 
         let mut yp = 0; while yp < interpolation {
         //for yp in 0..params.interpolation {
             if sy + yp >= params.source_rect.y as i32 && sy + yp < (params.source_rect.y + params.source_rect.w) as i32 {
                 let mut xsum = Vec4::splat(0.0);
+                let mut xcovered = Vec4::splat(0.0);
+                let mut xcoverage = 0.0;
                 let mut xp = 0; while xp < interpolation {
                 // for xp in 0..params.interpolation {
                     let pixel = if sx + xp >= params.source_rect.x as i32 && sx + xp < (params.source_rect.x + params.source_rect.z) as i32 {
                         #[cfg(target_arch = "spirv")]
-                        {
+                        let src_px = {
                             use spirv_std::image::{ ImageWithMethods, sample_with };
                             to_float(input.fetch_with(glam::IVec2::new((sx + xp) as i32, (sy + yp) as i32), sample_with::lod(0)))
-                        }
+                        };
                         #[cfg(not(target_arch = "spirv"))]
-                        { input.1(&input.0[_src_index as usize + (params.bytes_per_pixel * xp) as usize.._src_index as usize + (params.bytes_per_pixel * (xp + 1)) as usize]) }
+                        let src_px = input.1(&input.0[_src_index as usize + (params.bytes_per_pixel * xp) as usize.._src_index as usize + (params.bytes_per_pixel * (xp + 1)) as usize]);
+                        xcovered += src_px * _coeffs[coeffs_x + xp as usize];
+                        xcoverage += _coeffs[coeffs_x + xp as usize];
+                        src_px
                     } else {
                         bg
                     };
@@ -77,6 +85,8 @@ pub fn sample_input_at(uv: Vec2, _coeffs: &[f32], input: &ImageType, params: &Ke
                 }
 
                 sum += xsum * _coeffs[coeffs_y + yp as usize];
+                covered += xcovered * _coeffs[coeffs_y + yp as usize];
+                coverage += xcoverage * _coeffs[coeffs_y + yp as usize];
             } else {
                 sum += bg * Vec4::splat(_coeffs[coeffs_y + yp as usize]);
             }
@@ -84,6 +94,17 @@ pub fn sample_input_at(uv: Vec2, _coeffs: &[f32], input: &ImageType, params: &Ke
             yp += 1;
             if yp >= interpolation { break; } // Bug in Dx12 backend, doesn't work without it for some strange reason
         }
+        if (_flags & 4096) == 4096 { // Transparent background
+            let mut straight = if coverage > 0.001 { covered / coverage } else { bg };
+            match params.alpha_channel {
+                0 => straight.x = sum.x.max(0.0),
+                1 => straight.y = sum.y.max(0.0),
+                2 => straight.z = sum.z.max(0.0),
+                3 => straight.w = sum.w.max(0.0),
+                _ => { }
+            }
+            sum = straight;
+        }
         glam::vec4(
             sum.x.min(params.pixel_value_limit),
             sum.y.min(params.pixel_value_limit),
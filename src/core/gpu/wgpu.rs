@@ -17,6 +17,7 @@ pub enum WgpuError {
     RequestDevice(wgpu::RequestDeviceError),
     ParamCheck,
     NoAvailableAdapter,
+    LensModel(String),
 }
 
 enum PipelineType {
@@ -248,10 +249,10 @@ impl WgpuWrapper {
             let mut kernel = include_str!("wgpu_undistort.wgsl").to_string();
             //let mut kernel = std::fs::read_to_string("D:/programowanie/projekty/Rust/gyroflow/src/core/gpu/wgpu_undistort.wgsl").unwrap();
 
-            let mut lens_model_functions = distortion_model.wgsl_functions().to_string();
+            let mut lens_model_functions = distortion_model.wgsl_functions().map_err(WgpuError::LensModel)?.to_string();
             let default_digital_lens = "fn digital_undistort_point(uv: vec2<f32>) -> vec2<f32> { return uv; }
                                         fn digital_distort_point  (uv: vec2<f32>) -> vec2<f32> { return uv; }";
-            lens_model_functions.push_str(digital_lens.as_ref().map(|x| x.wgsl_functions()).unwrap_or(default_digital_lens));
+            lens_model_functions.push_str(digital_lens.as_ref().map(|x| x.wgsl_functions()).transpose().map_err(WgpuError::LensModel)?.unwrap_or(default_digital_lens));
             kernel = kernel.replace("LENS_MODEL_FUNCTIONS;", &lens_model_functions);
             kernel = kernel.replace("SCALAR", wgpu_format.1);
 
@@ -437,6 +438,12 @@ impl WgpuWrapper {
         }
     }
 
+    /// GPU memory held by this instance: the input and output textures and all the buffers, including the staging buffer
+    pub fn memory_size(&self) -> usize {
+        let buffers = [&self.staging_buffer, &self.buf_matrices, &self.buf_params, &self.buf_mesh_data, &self.buf_drawing];
+        (self.in_size + self.out_size + buffers.iter().filter_map(|x| x.as_ref()).map(|x| x.size()).sum::<u64>()) as usize
+    }
+
     pub fn undistort_image(&self, buffers: &mut Buffers, itm: &crate::stabilization::FrameTransform, drawing_buffer: &[u8]) -> bool {
         let matrices = bytemuck::cast_slice(&itm.matrices);
 
@@ -445,6 +452,7 @@ impl WgpuWrapper {
         if self.in_size  != in_size  { log::error!("Buffer size mismatch! {} vs {}", self.in_size,  in_size);  return false; }
         if self.out_size != out_size { log::error!("Buffer size mismatch! {} vs {}", self.out_size, out_size); return false; }
 
+        let mut timer = super::StageTimer::new();
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
         let _temp_texture = handle_input_texture(&self.device, &buffers.input, &self.queue, &mut encoder, &self.in_texture, self.pixel_format, self.padded_out_stride);
@@ -461,6 +469,12 @@ impl WgpuWrapper {
             if self.buf_mesh_data.is_none() || (self.buf_mesh_data.as_ref().unwrap().size() as usize * 4) < itm.mesh_data.len() { log::error!("Buffer size mismatch buf_mesh_data! {} vs {}", self.buf_mesh_data.as_ref().unwrap().size() * 4, itm.mesh_data.len()); return false; }
             self.queue.write_buffer(self.buf_mesh_data.as_ref().unwrap(), 0, bytemuck::cast_slice(&itm.mesh_data));
         }
+        if let Some(timer) = timer.as_mut() {
+            let upload = std::mem::replace(&mut encoder, self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None }));
+            self.queue.submit(Some(upload.finish()));
+            self.device.poll(wgpu::Maintain::Wait);
+            timer.upload_done();
+        }
 
         match &self.pipeline {
             PipelineType::None => { },
@@ -491,6 +505,12 @@ impl WgpuWrapper {
                 rpass.draw(0..6, 0..1);
             }
         }
+        if let Some(timer) = timer.as_mut() {
+            let kernel = std::mem::replace(&mut encoder, self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None }));
+            self.queue.submit(Some(kernel.finish()));
+            self.device.poll(wgpu::Maintain::Wait);
+            timer.kernel_done();
+        }
 
         let _temp_texture2 = handle_output_texture(&self.device, &buffers.output, &self.queue, &mut encoder, &self.out_texture, self.pixel_format, self.staging_buffer.as_ref().unwrap(), self.padded_out_stride);
 
@@ -537,6 +557,10 @@ impl WgpuWrapper {
             }
             _ => { handle_output_texture_post(&self.device, &buffers.output, &self.out_texture, self.pixel_format, sub_index); }
         }
+        if let Some(timer) = timer {
+            self.device.poll(wgpu::Maintain::Wait);
+            timer.finish();
+        }
 
         true
     }
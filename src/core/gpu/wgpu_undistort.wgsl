@@ -45,7 +45,7 @@ struct KernelParams {
     pixel_value_limit:        f32, // 16
     light_refraction_coefficient: f32, // 4
     plane_index:              i32, // 8
-    reserved1:                f32, // 12
+    alpha_channel:            i32, // 12
     reserved2:                f32, // 16
     ewa_coeffs_p:             vec4<f32>, // 16
     ewa_coeffs_q:             vec4<f32>, // 16
@@ -214,6 +214,10 @@ fn sample_input_at(uv_param: vec2<f32>, jac: vec4<f32>) -> vec4<f32> {
 
     let bg = params.background * params.max_pixel_value;
     var sum = vec4<f32>(0.0);
+    // With a transparent background, the part of the footprint inside of the source gives the color and the alpha is its coverage
+    var covered = vec4<f32>(0.0);
+    var coverage = 0.0;
+    var total_weight = 1.0;
 
     if (interpolation > 8u) {
         // find how many pixels we need around that pixel in each direction
@@ -241,6 +245,8 @@ fn sample_input_at(uv_param: vec2<f32>, jac: vec4<f32>) -> vec4<f32> {
                 if (in_y >= params.source_rect.y && in_y < params.source_rect.y + params.source_rect.w && in_x >= params.source_rect.x && in_x < params.source_rect.x + params.source_rect.z) {
                     pixel = read_input_at(vec2<i32>(in_x, in_y));
                     pixel = draw_pixel(pixel, u32(in_x), u32(in_y), true);
+                    covered += k * pixel;
+                    coverage += k;
                 } else {
                     pixel = bg;
                 }
@@ -249,6 +255,7 @@ fn sample_input_at(uv_param: vec2<f32>, jac: vec4<f32>) -> vec4<f32> {
             }
         }
         sum /= sum_div;
+        total_weight = sum_div;
     } else {
         let shift = (interpolation >> 2u) + 1u;
         var indices: array<i32, 6> = array<i32, 6>(0, 64, 192, 0, 0, 0);
@@ -270,23 +277,40 @@ fn sample_input_at(uv_param: vec2<f32>, jac: vec4<f32>) -> vec4<f32> {
         for (var yp: i32 = 0; yp < i32(interpolation); yp = yp + 1) {
             if (sy + yp >= params.source_rect.y && sy + yp < params.source_rect.y + params.source_rect.w) {
                 var xsum = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+                var xcovered = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+                var xcoverage = 0.0;
                 for (var xp: i32 = 0; xp < i32(interpolation); xp = xp + 1) {
                     var pixel: vec4<f32>;
                     if (sx + xp >= params.source_rect.x && sx + xp < params.source_rect.x + params.source_rect.z) {
                         pixel = read_input_at(vec2<i32>(sx + xp, sy + yp));
                         pixel = draw_pixel(pixel, u32(sx + xp), u32(sy + yp), true);
+                        xcovered = xcovered + (pixel * coeffs[coeffs_x + xp]);
+                        xcoverage = xcoverage + coeffs[coeffs_x + xp];
                     } else {
                         pixel = bg;
                     }
                     xsum = xsum + (pixel * coeffs[coeffs_x + xp]);
                 }
                 sum = sum + xsum * coeffs[coeffs_y + yp];
+                covered = covered + xcovered * coeffs[coeffs_y + yp];
+                coverage = coverage + xcoverage * coeffs[coeffs_y + yp];
             } else {
                 sum = sum + bg * coeffs[coeffs_y + yp];
             }
         }
     }
 
+    if (bool(flags & 4096)) { // Transparent background
+        var straight = bg;
+        if (coverage > 0.001 * total_weight) {
+            straight = covered / coverage;
+        }
+        if (params.alpha_channel >= 0) {
+            straight[params.alpha_channel] = max(sum[params.alpha_channel], 0.0);
+        }
+        sum = straight;
+    }
+
     if (fix_range) {
         sum = remap_colorrange(sum, params.plane_index == 0);
     }
@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Data for plotting what the smoothing did: the original and smoothed camera orientation,
+// the size of the correction and the crop needed to hide it. Sampled from the already computed quaternions and FOVs
+
+use crate::gyro_source::{ GyroSource, Quat64 };
+
+const RAD2DEG: f64 = 180.0 / std::f64::consts::PI;
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct GraphData {
+    pub timestamps_ms: Vec<f64>,
+    pub original: Vec<[f64; 3]>, // pitch, yaw, roll in degrees
+    pub smoothed: Vec<[f64; 3]>,
+    pub correction: Vec<f64>,    // degrees
+    pub crop_factor: Vec<f64>,
+}
+
+fn euler_degrees(q: &Quat64) -> [f64; 3] {
+    let e = q.euler_angles();
+    [e.0 * RAD2DEG, e.1 * RAD2DEG, e.2 * RAD2DEG]
+}
+
+impl GraphData {
+    /// `samples` points evenly spread over the video time range. `minimal_fovs` are per frame,
+    /// and the crop factor is 1 where they're not computed yet
+    pub fn compute(gyro: &GyroSource, minimal_fovs: &[f64], fps: f64, from_ms: f64, to_ms: f64, samples: usize) -> Self {
+        let mut ret = Self::default();
+        if samples == 0 || to_ms < from_ms { return ret; }
+
+        let step = if samples > 1 { (to_ms - from_ms) / (samples - 1) as f64 } else { 0.0 };
+        for i in 0..samples {
+            let ts = from_ms + step * i as f64;
+            let org = gyro.org_quat_at_timestamp(ts);
+            // Stored is the correction: smoothed -> original
+            let correction = gyro.smoothed_quat_at_timestamp(ts);
+            let smoothed = org * correction.inverse();
+
+            let frame = crate::frame_at_timestamp(ts, fps).max(0) as usize;
+            let min_fov = minimal_fovs.get(frame.min(minimal_fovs.len().saturating_sub(1))).copied().unwrap_or(1.0);
+
+            ret.timestamps_ms.push(ts);
+            ret.original.push(euler_degrees(&org));
+            ret.smoothed.push(euler_degrees(&smoothed));
+            ret.correction.push(correction.angle() * RAD2DEG);
+            ret.crop_factor.push(1.0 / min_fov.max(0.001));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    #[test]
+    fn sampled_from_quaternions() {
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = 2000.0;
+        gyro.quaternions = (0..=200).map(|i| (i * 10_000, Quat64::from_axis_angle(&Vector3::z_axis(), (i as f64 * 0.1).to_radians()))).collect();
+        // Smoothed to no motion at all
+        let corrections = gyro.quaternions.clone();
+        gyro.set_smoothed_quaternions(corrections);
+
+        let data = GraphData::compute(&gyro, &[0.8; 60], 30.0, 0.0, 2000.0, 11);
+        assert_eq!(data.timestamps_ms.len(), 11);
+        assert_eq!(data.timestamps_ms[10], 2000.0);
+        for i in 0..11 {
+            let expected = data.timestamps_ms[i] / 10.0 * 0.1;
+            assert!((data.original[i][2] - expected).abs() < 1e-6, "{:?}", data.original[i]);
+            assert!(data.smoothed[i].iter().all(|x| x.abs() < 1e-6));
+            assert!((data.correction[i] - expected).abs() < 1e-6);
+            assert!((data.crop_factor[i] - 1.25).abs() < 1e-9);
+        }
+        assert!(GraphData::compute(&gyro, &[], 30.0, 0.0, 2000.0, 0).timestamps_ms.is_empty());
+        assert_eq!(GraphData::compute(&gyro, &[], 30.0, 0.0, 2000.0, 3).crop_factor, vec![1.0; 3]);
+    }
+}
@@ -104,8 +104,7 @@ pub fn export_gyro_data(filename: &str, fields_json: &str, stab: &Arc<crate::Sta
     let timestamps: Vec<(Option<usize>, usize, TimestampType, f64)> = if all_samples {
         let mut frame = 0;
         gyro.quaternions.keys().enumerate().map(|(i, ts)| {
-            let mut timestamp_ms = *ts as f64 / 1000.0;            
-            timestamp_ms += gyro.offset_at_gyro_timestamp(timestamp_ms);
+            let timestamp_ms = gyro.gyro_to_video_timestamp(*ts as f64 / 1000.0);
 
             let final_timestamp = timestamp_ms - file_metadata.per_frame_time_offsets.get(frame).unwrap_or(&0.0);
             if final_timestamp >= (frame + 1) as f64 * frame_duration {
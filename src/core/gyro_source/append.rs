@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+use std::sync::{ Arc, atomic::AtomicBool };
+use super::*;
+
+// Gaps longer than this many sample intervals are reported
+const MAX_JOIN_GAP_SAMPLES: f64 = 5.0;
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GyroSegment {
+    pub file_url: String,
+    pub time_offset_ms: f64,
+    pub start_ms: f64, // First sample in the joined timeline
+    pub end_ms: f64,   // Last sample in the joined timeline
+    pub overlap_ms: f64, // Length of the data clipped from the beginning of this segment
+    pub gap_ms: f64,     // Length of missing data before this segment
+}
+
+impl GyroSource {
+    /// Joins the motion data of `other` at the end of this source. `time_offset_ms` is the start of `other` in the joined timeline.
+    /// Samples overlapping the existing data are clipped, gaps are reported and filled with zero rotation if `hold_orientation_in_gaps` is set.
+    pub fn append(&mut self, other: GyroSource, time_offset_ms: f64) {
+        let other_md = other.file_metadata.read();
+        let has_motion = self.has_motion();
+
+        let mut md = if has_motion {
+            self.file_metadata.read().clone()
+        } else {
+            self.integration_method = other.integration_method;
+            self.imu_transforms.imu_orientation = other.imu_transforms.imu_orientation.clone();
+            if self.file_url.is_empty() { self.file_url = other.file_url.clone(); }
+            other_md.thin()
+        };
+        if has_motion && self.segments.is_empty() {
+            let first = md.raw_imu.first().map(|x| x.timestamp_ms).unwrap_or_default();
+            let last = md.raw_imu.last().map(|x| x.timestamp_ms).unwrap_or_default();
+            self.segments.push(GyroSegment { file_url: self.file_url.clone(), start_ms: first, end_ms: last, ..Default::default() });
+        }
+
+        let mut segment = GyroSegment { file_url: other.file_url.clone(), time_offset_ms, ..Default::default() };
+
+        let mut incoming = other_md.raw_imu.iter().map(|x| TimeIMU { timestamp_ms: x.timestamp_ms + time_offset_ms, ..x.clone() }).collect::<Vec<_>>();
+        if let Some(last_ts) = md.raw_imu.last().map(|x| x.timestamp_ms) {
+            let sample_ms = gaps::median_sample_interval(&md.raw_imu).or_else(|| gaps::median_sample_interval(&incoming)).unwrap_or(1.0);
+            if let Some(first_ts) = incoming.first().map(|x| x.timestamp_ms) {
+                if first_ts <= last_ts {
+                    segment.overlap_ms = last_ts - first_ts;
+                    log::warn!("Motion data of {} overlaps the previous segment by {:.1} ms, clipping", other.file_url, segment.overlap_ms);
+                    incoming.retain(|x| x.timestamp_ms > last_ts);
+                }
+            }
+            if let Some(first_ts) = incoming.first().map(|x| x.timestamp_ms) {
+                let gap = first_ts - last_ts - sample_ms;
+                if gap > sample_ms * MAX_JOIN_GAP_SAMPLES {
+                    segment.gap_ms = gap;
+                    log::warn!("Missing {gap:.1} ms of motion data before {}", other.file_url);
+                    if self.hold_orientation_in_gaps {
+                        let accl = md.raw_imu.last().and_then(|x| x.accl);
+                        let mut ts = last_ts + sample_ms;
+                        while ts < first_ts - sample_ms * 0.5 {
+                            md.raw_imu.push(TimeIMU { timestamp_ms: ts, gyro: Some([0.0, 0.0, 0.0]), accl, magn: None });
+                            ts += sample_ms;
+                        }
+                    }
+                }
+            }
+        }
+        segment.start_ms = incoming.first().map(|x| x.timestamp_ms).unwrap_or(time_offset_ms);
+        segment.end_ms = incoming.last().map(|x| x.timestamp_ms).unwrap_or(time_offset_ms);
+        md.raw_imu.extend(incoming);
+
+        let offset_us = (time_offset_ms * 1000.0).round() as i64;
+        let shifted = |map: &TimeQuat| map.iter().map(|(ts, q)| (ts + offset_us, *q)).collect::<Vec<_>>();
+
+        // Quaternions from different files don't share the reference frame, so continue from the last existing orientation
+        let mut quats = shifted(&other_md.quaternions);
+        if let Some((&last_ts, &last_q)) = md.quaternions.iter().next_back() {
+            quats.retain(|(ts, _)| *ts > last_ts);
+            if let Some(&(_, first_q)) = quats.first() {
+                let correction = last_q * first_q.inverse();
+                for (_, q) in quats.iter_mut() {
+                    *q = correction * *q;
+                }
+            }
+        }
+        md.quaternions.extend(quats);
+
+        if let Some(other_io) = &other_md.image_orientations {
+            let io = md.image_orientations.get_or_insert_with(Default::default);
+            let last_ts = io.keys().next_back().copied().unwrap_or(i64::MIN);
+            io.extend(shifted(other_io).into_iter().filter(|(ts, _)| *ts > last_ts));
+        }
+        if let Some(other_gv) = &other_md.gravity_vectors {
+            let gv = md.gravity_vectors.get_or_insert_with(Default::default);
+            let last_ts = gv.keys().next_back().copied().unwrap_or(i64::MIN);
+            gv.extend(other_gv.iter().map(|(ts, v)| (ts + offset_us, *v)).filter(|(ts, _)| *ts > last_ts));
+        }
+        drop(other_md);
+
+        self.segments.push(segment);
+        self.duration_ms = self.duration_ms.max(time_offset_ms + other.duration_ms);
+        self.file_metadata = md.into();
+        self.apply_transforms();
+    }
+
+    /// Creates a standalone source from parsed telemetry, with the duration taken from the motion data itself
+    pub fn from_telemetry(url: &str, md: FileMetadata) -> Self {
+        let mut gyro = GyroSource::new();
+        gyro.file_url = url.to_string();
+        gyro.duration_ms = telemetry_duration_ms(&md);
+        gyro.load_from_telemetry(md);
+        gyro
+    }
+
+    /// Loads and joins multiple telemetry files. Each entry is the file url and its start time in the joined timeline (in ms)
+    pub fn load_from_files<F: Fn(f64)>(files: &[(String, f64)], options: &FileLoadOptions, size: (usize, usize), fps: f64, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Result<Self, crate::GyroflowCoreError> {
+        let mut joined = GyroSource::new();
+        joined.file_load_options = options.clone();
+        for (i, (url, time_offset_ms)) in files.iter().enumerate() {
+            let md = Self::parse_telemetry_file(url, options, size, fps, |p| progress_cb((i as f64 + p) / files.len() as f64), cancel_flag.clone())?;
+            if cancel_flag.load(std::sync::atomic::Ordering::Relaxed) { break; }
+
+            joined.append(Self::from_telemetry(url, md), *time_offset_ms);
+        }
+        if !joined.has_motion() {
+            return Err(crate::GyroflowCoreError::InvalidData);
+        }
+        Ok(joined)
+    }
+}
+
+fn telemetry_duration_ms(md: &FileMetadata) -> f64 {
+    let (len, first, last) = if !md.raw_imu.is_empty() {
+        (md.raw_imu.len() as f64, md.raw_imu[0].timestamp_ms, md.raw_imu[md.raw_imu.len() - 1].timestamp_ms)
+    } else {
+        let first = md.quaternions.keys().next().copied().unwrap_or_default() as f64 / 1000.0;
+        let last = md.quaternions.keys().next_back().copied().unwrap_or_default() as f64 / 1000.0;
+        (md.quaternions.len() as f64, first, last)
+    };
+    (last - first) * ((len + 1.0) / len.max(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 deg/s around Z at 100 Hz
+    fn rotating_source(url: &str, duration_s: f64) -> GyroSource {
+        let raw_imu = (0..(duration_s * 100.0) as usize).map(|i| TimeIMU {
+            timestamp_ms: i as f64 * 10.0,
+            gyro: Some([0.0, 0.0, 10.0]),
+            accl: Some([0.0, 0.0, 1.0]),
+            magn: None
+        }).collect::<Vec<_>>();
+        let mut gyro = GyroSource::new();
+        gyro.integration_method = IntegrationMethod::GyroOnly;
+        gyro.file_url = url.into();
+        gyro.duration_ms = duration_s * 1000.0;
+        gyro.load_from_telemetry(FileMetadata { raw_imu, ..Default::default() });
+        gyro
+    }
+
+    fn timestamps(gyro: &GyroSource) -> Vec<f64> {
+        gyro.file_metadata.read().raw_imu.iter().map(|x| x.timestamp_ms).collect()
+    }
+
+    fn quat_at(gyro: &GyroSource, ts_ms: f64) -> Quat64 {
+        *gyro.quaternions.range(..=(ts_ms * 1000.0) as i64).next_back().unwrap().1
+    }
+
+    #[test]
+    fn append_clips_overlap() {
+        let mut gyro = rotating_source("a", 1.0);
+        gyro.append(rotating_source("b", 1.0), 900.0);
+
+        let ts = timestamps(&gyro);
+        assert_eq!(ts.len(), 100 + 90);
+        assert!(ts.windows(2).all(|w| w[1] > w[0]));
+        assert_eq!(gyro.segments.len(), 2);
+        assert!((gyro.segments[1].overlap_ms - 90.0).abs() < 1e-6);
+        assert_eq!(gyro.segments[1].gap_ms, 0.0);
+        assert!((gyro.segments[1].start_ms - 1000.0).abs() < 1e-6);
+        assert_eq!(gyro.quaternions.len(), ts.len());
+        assert!((gyro.duration_ms - 1900.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn append_with_gap() {
+        let mut gyro = rotating_source("a", 1.0);
+        gyro.append(rotating_source("b", 1.0), 1500.0);
+
+        assert_eq!(timestamps(&gyro).len(), 200);
+        assert!((gyro.segments[1].gap_ms - 500.0).abs() < 1e-6);
+
+        // Without filling, the first sample after the gap integrates over the whole gap
+        let jump = quat_at(&gyro, 990.0).angle_to(&quat_at(&gyro, 1500.0)) / DEG2RAD;
+        assert!((jump - 5.1).abs() < 0.01, "jump: {jump}");
+    }
+
+    #[test]
+    fn append_with_gap_holds_orientation() {
+        let mut gyro = rotating_source("a", 1.0);
+        gyro.hold_orientation_in_gaps = true;
+        gyro.append(rotating_source("b", 1.0), 1500.0);
+
+        let ts = timestamps(&gyro);
+        assert_eq!(ts.len(), 200 + 50);
+        assert!(ts.windows(2).all(|w| w[1] > w[0]));
+        assert!((gyro.segments[1].gap_ms - 500.0).abs() < 1e-6);
+
+        let held = quat_at(&gyro, 1000.0).angle_to(&quat_at(&gyro, 1490.0)) / DEG2RAD;
+        assert!(held < 1e-6, "held: {held}");
+        let step = quat_at(&gyro, 1490.0).angle_to(&quat_at(&gyro, 1500.0)) / DEG2RAD;
+        assert!((step - 0.1).abs() < 1e-6, "step: {step}");
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+use super::TimeIMU;
+
+// Length of the blocks the data is split into when looking for static parts
+const BLOCK_MS: f64 = 250.0;
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct StaticBiasEstimate {
+    pub segments: Vec<(f64, f64)>, // (start, end) gyro timestamps in ms
+    pub bias: [f64; 3], // in deg/s, in the same convention as `IMUTransforms::gyro_bias` (added to the raw readings)
+    pub num_samples: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StaticBiasParams {
+    pub max_std_dev: f64,   // deg/s, per axis
+    pub max_mean_rate: f64, // deg/s, slow constant rotation is not bias
+    pub min_duration_ms: f64,
+}
+impl Default for StaticBiasParams {
+    fn default() -> Self {
+        Self {
+            max_std_dev: 0.5,
+            max_mean_rate: 5.0,
+            min_duration_ms: 2000.0,
+        }
+    }
+}
+
+impl StaticBiasEstimate {
+    /// Finds parts of the gyro data where the camera is not moving, and estimates the gyro bias from them.
+    /// Returns `None` if there's no static segment of at least `min_duration_ms`
+    pub fn detect(raw_imu: &[TimeIMU], params: &StaticBiasParams) -> Option<Self> {
+        let samples = raw_imu.iter().filter_map(|x| Some((x.timestamp_ms, x.gyro?))).collect::<Vec<_>>();
+        if samples.len() < 2 { return None; }
+
+        // Split into blocks and mark the ones with low variance
+        let mut blocks = Vec::new(); // (start_ms, end_ms, sum, count, is_static)
+        let mut i = 0;
+        while i < samples.len() {
+            let start_ts = samples[i].0;
+            let mut sum = [0.0; 3];
+            let mut sum_sq = [0.0; 3];
+            let mut n = 0usize;
+            let mut end_ts = start_ts;
+            while i < samples.len() && samples[i].0 - start_ts < BLOCK_MS {
+                for a in 0..3 {
+                    sum[a] += samples[i].1[a];
+                    sum_sq[a] += samples[i].1[a] * samples[i].1[a];
+                }
+                end_ts = samples[i].0;
+                n += 1;
+                i += 1;
+            }
+            let is_static = n > 1 && (0..3).all(|a| {
+                let mean = sum[a] / n as f64;
+                let variance = (sum_sq[a] / n as f64 - mean * mean).max(0.0);
+                variance.sqrt() < params.max_std_dev && mean.abs() < params.max_mean_rate
+            });
+            blocks.push((start_ts, end_ts, sum, n, is_static));
+        }
+
+        // Merge consecutive static blocks into segments
+        let mut ret = Self::default();
+        let mut total = [0.0; 3];
+        let mut current: Option<(f64, f64, [f64; 3], usize)> = None;
+        let mut flush = |current: &mut Option<(f64, f64, [f64; 3], usize)>, ret: &mut Self| {
+            if let Some((start, end, sum, n)) = current.take() {
+                if end - start >= params.min_duration_ms {
+                    ret.segments.push((start, end));
+                    for a in 0..3 { total[a] += sum[a]; }
+                    ret.num_samples += n;
+                }
+            }
+        };
+        for (start, end, sum, n, is_static) in blocks {
+            if is_static {
+                match current.as_mut() {
+                    Some(c) => {
+                        c.1 = end;
+                        for a in 0..3 { c.2[a] += sum[a]; }
+                        c.3 += n;
+                    }
+                    None => { current = Some((start, end, sum, n)); }
+                }
+            } else {
+                flush(&mut current, &mut ret);
+            }
+        }
+        flush(&mut current, &mut ret);
+        drop(flush);
+
+        if ret.num_samples == 0 { return None; }
+
+        for a in 0..3 {
+            ret.bias[a] = -total[a] / ret.num_samples as f64;
+        }
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 kHz, `gyro` returns the reading at the timestamp in ms
+    fn samples(duration_ms: usize, gyro: impl Fn(f64) -> [f64; 3]) -> Vec<TimeIMU> {
+        (0..duration_ms).map(|i| TimeIMU { timestamp_ms: i as f64, gyro: Some(gyro(i as f64)), accl: Some([0.0, 0.0, 1.0]), magn: None }).collect()
+    }
+    fn noise(ts: f64) -> f64 { 0.1 * (ts * 1.3).sin() }
+
+    #[test]
+    fn detect() {
+        // Static for 3s, moving for 2s, static for 3s
+        let offset = [0.4, -0.3, 0.2];
+        let data = samples(8000, |ts| {
+            if (3000.0..5000.0).contains(&ts) {
+                [50.0 * (ts / 100.0).sin(), 20.0, -30.0]
+            } else {
+                [offset[0] + noise(ts), offset[1] - noise(ts), offset[2] + noise(ts + 1.0)]
+            }
+        });
+        let est = StaticBiasEstimate::detect(&data, &StaticBiasParams::default()).unwrap();
+        assert_eq!(est.segments.len(), 2);
+        assert!(est.segments[0].0 == 0.0 && (est.segments[0].1 - 3000.0).abs() <= 250.0, "{:?}", est.segments);
+        assert!((est.segments[1].0 - 5000.0).abs() <= 250.0 && est.segments[1].1 == 7999.0, "{:?}", est.segments);
+        // Opposite sign, it's added to the readings
+        for a in 0..3 {
+            assert!((est.bias[a] + offset[a]).abs() < 0.02, "{:?}", est.bias);
+        }
+        assert!(est.num_samples > 5000 && est.num_samples <= 6000);
+
+        // Too short
+        let params = StaticBiasParams { min_duration_ms: 4000.0, ..Default::default() };
+        assert!(StaticBiasEstimate::detect(&data, &params).is_none());
+
+        // Slow constant rotation is not bias
+        let data = samples(5000, |_| [8.0, 0.0, 0.0]);
+        assert!(StaticBiasEstimate::detect(&data, &StaticBiasParams::default()).is_none());
+
+        // Always moving
+        let data = samples(5000, |ts| [30.0 * (ts / 50.0).sin(), 0.0, 0.0]);
+        assert!(StaticBiasEstimate::detect(&data, &StaticBiasParams::default()).is_none());
+
+        // No gyro
+        let mut data = samples(5000, |_| [0.0; 3]);
+        data.iter_mut().for_each(|x| x.gyro = None);
+        assert!(StaticBiasEstimate::detect(&data, &StaticBiasParams::default()).is_none());
+    }
+}
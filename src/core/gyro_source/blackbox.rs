@@ -0,0 +1,666 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Decoder for Betaflight, INAV and Cleanflight blackbox logs (.bbl, .bfl).
+// The file is read as a stream and only the decoded gyro and accelerometer samples are kept in memory.
+// Frame format and predictors follow blackbox-tools (https://github.com/betaflight/blackbox-tools)
+
+use std::collections::BTreeMap;
+use std::io::{ BufRead, Error, ErrorKind, Result };
+use super::{ FileMetadata, TimeIMU, GRAVITY };
+
+const DEBUG_GYRO_SCALED: i64 = 6; // Betaflight `debug_mode`
+
+// Encodings
+const SIGNED_VB: u8 = 0;
+const UNSIGNED_VB: u8 = 1;
+const NEG_14BIT: u8 = 3;
+const TAG8_8SVB: u8 = 6;
+const TAG2_3S32: u8 = 7;
+const TAG8_4S16: u8 = 8;
+const NULL: u8 = 9;
+const TAG2_3SVARIABLE: u8 = 10;
+
+// Predictors
+const PREDICT_0: u8 = 0;
+const PREDICT_PREVIOUS: u8 = 1;
+const PREDICT_STRAIGHT_LINE: u8 = 2;
+const PREDICT_AVERAGE_2: u8 = 3;
+const PREDICT_MINTHROTTLE: u8 = 4;
+const PREDICT_MOTOR_0: u8 = 5;
+const PREDICT_INC: u8 = 6;
+const PREDICT_1500: u8 = 8;
+const PREDICT_VBATREF: u8 = 9;
+const PREDICT_MINMOTOR: u8 = 11;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum BlackboxGyro {
+    #[default]
+    Filtered,   // gyroADC
+    Unfiltered, // gyroUnfilt if logged, otherwise the debug fields when `debug_mode` is GYRO_SCALED
+}
+
+#[derive(Default, Clone, Debug)]
+struct FieldDefs {
+    names: Vec<String>,
+    signed: Vec<bool>,
+    predictor: Vec<u8>,
+    encoding: Vec<u8>,
+}
+
+#[derive(Default, Clone, Debug)]
+struct Header {
+    values: BTreeMap<String, String>,
+    i: FieldDefs,
+    p: FieldDefs, // Only predictors and encodings, the names are the same as in I frames
+    s: FieldDefs,
+    g: FieldDefs,
+    h: FieldDefs,
+    i_interval: i64,
+    p_num: i64,
+    p_denom: i64,
+    minthrottle: i64,
+    minmotor: i64,
+    vbatref: i64,
+    gyro_scale: f64, // deg/s per unit
+    acc_1g: f64,
+    debug_mode: i64,
+}
+
+impl Header {
+    fn parse_line(&mut self, line: &str) {
+        let Some((key, value)) = line.split_once(':') else { return; };
+        let list = |v: &str| v.split(',').map(|x| x.trim().parse::<i64>().unwrap_or_default()).collect::<Vec<_>>();
+        let int = |v: &str| v.trim().parse::<i64>().unwrap_or_default();
+        if let Some(rest) = key.strip_prefix("Field ") {
+            let mut parts = rest.splitn(2, ' ');
+            let frame = parts.next().unwrap_or_default();
+            let property = parts.next().unwrap_or_default();
+            let defs = match frame {
+                "I" => &mut self.i,
+                "P" => &mut self.p,
+                "S" => &mut self.s,
+                "G" => &mut self.g,
+                "H" => &mut self.h,
+                _ => return
+            };
+            match property {
+                "name"      => defs.names = value.split(',').map(|x| x.trim().to_owned()).collect(),
+                "signed"    => defs.signed = list(value).into_iter().map(|x| x != 0).collect(),
+                "predictor" => defs.predictor = list(value).into_iter().map(|x| x as u8).collect(),
+                "encoding"  => defs.encoding = list(value).into_iter().map(|x| x as u8).collect(),
+                _ => { }
+            }
+            return;
+        }
+        match key {
+            "I interval" => self.i_interval = int(value).max(1),
+            "P interval" => match value.split_once('/') {
+                Some((num, denom)) => { self.p_num = int(num).max(1); self.p_denom = int(denom).max(1); },
+                None => { self.p_num = 1; self.p_denom = int(value).max(1); }
+            },
+            "minthrottle" => self.minthrottle = int(value),
+            "vbatref"     => self.vbatref = int(value),
+            "motorOutput" => self.minmotor = list(value).first().copied().unwrap_or_default(),
+            "acc_1G"      => self.acc_1g = int(value) as f64,
+            "debug_mode"  => self.debug_mode = int(value),
+            "gyro_scale"  => {
+                let bits = u32::from_str_radix(value.trim().trim_start_matches("0x"), 16).unwrap_or_default();
+                self.gyro_scale = f32::from_bits(bits) as f64;
+            },
+            _ => { }
+        }
+        self.values.insert(key.to_owned(), value.to_owned());
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.i.names.is_empty() || self.i.encoding.len() != self.i.names.len() || self.i.predictor.len() != self.i.names.len() ||
+           self.p.encoding.len() != self.i.names.len() || self.p.predictor.len() != self.i.names.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid blackbox field definitions"));
+        }
+        self.i.signed.resize(self.i.names.len(), false);
+        self.p.names = self.i.names.clone();
+        self.p.signed = self.i.signed.clone();
+        if self.i_interval <= 0 { self.i_interval = 1; }
+        if self.p_num <= 0 || self.p_denom <= 0 { self.p_num = 1; self.p_denom = 1; }
+        if self.acc_1g <= 0.0 { self.acc_1g = 4096.0; }
+        // Cleanflight based firmwares log deg/s, Baseflight logs rad/us
+        if self.gyro_scale <= 0.0 {
+            self.gyro_scale = 1.0;
+        } else if self.values.get("Firmware type").is_some_and(|x| x.trim() == "Baseflight") {
+            self.gyro_scale *= 1_000_000.0 * 180.0 / std::f64::consts::PI;
+        }
+        Ok(())
+    }
+
+    fn firmware(&self) -> String {
+        self.values.get("Firmware revision").or_else(|| self.values.get("Firmware type")).cloned().unwrap_or_else(|| "Blackbox".into())
+    }
+
+    fn field(&self, name: &str) -> Option<usize> {
+        self.i.names.iter().position(|x| x == name)
+    }
+    fn fields3(&self, name: &str) -> Option<[usize; 3]> {
+        Some([self.field(&format!("{name}[0]"))?, self.field(&format!("{name}[1]"))?, self.field(&format!("{name}[2]"))?])
+    }
+
+    // Same as `shouldHaveFrame` in blackbox-tools
+    fn should_have_frame(&self, index: i64) -> bool {
+        (index % self.i_interval + self.p_num - 1) % self.p_denom < self.p_num
+    }
+}
+
+struct Stream<R: BufRead> {
+    reader: R,
+}
+impl<R: BufRead> Stream<R> {
+    fn peek(&mut self) -> Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+    fn byte(&mut self) -> Result<u8> {
+        let b = self.peek()?.ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+        self.reader.consume(1);
+        Ok(b)
+    }
+    fn line(&mut self) -> Result<String> {
+        let mut buf = Vec::new();
+        self.reader.read_until(b'\n', &mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).trim_end().to_owned())
+    }
+    fn unsigned_vb(&mut self) -> Result<u32> {
+        let mut result = 0u32;
+        for i in 0..5 {
+            let b = self.byte()?;
+            result |= ((b & 0x7F) as u32) << (i * 7);
+            if b < 0x80 { return Ok(result); }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "Invalid variable byte"))
+    }
+    fn signed_vb(&mut self) -> Result<i64> {
+        let v = self.unsigned_vb()?;
+        Ok(((v >> 1) as i32 ^ -((v & 1) as i32)) as i64)
+    }
+    fn le(&mut self, bytes: usize) -> Result<i64> {
+        let mut v = 0u32;
+        for i in 0..bytes { v |= (self.byte()? as u32) << (i * 8); }
+        Ok(sign_extend(v as i64, bytes as u32 * 8))
+    }
+
+    fn tag2_3s32(&mut self, out: &mut [i64; 3]) -> Result<()> {
+        let lead = self.byte()?;
+        match lead >> 6 {
+            0 => {
+                out[0] = sign_extend(((lead >> 4) & 0x03) as i64, 2);
+                out[1] = sign_extend(((lead >> 2) & 0x03) as i64, 2);
+                out[2] = sign_extend((lead & 0x03) as i64, 2);
+            },
+            1 => {
+                out[0] = sign_extend((lead & 0x0F) as i64, 4);
+                let b = self.byte()?;
+                out[1] = sign_extend((b >> 4) as i64, 4);
+                out[2] = sign_extend((b & 0x0F) as i64, 4);
+            },
+            2 => {
+                out[0] = sign_extend((lead & 0x3F) as i64, 6);
+                out[1] = sign_extend((self.byte()? & 0x3F) as i64, 6);
+                out[2] = sign_extend((self.byte()? & 0x3F) as i64, 6);
+            },
+            _ => self.variable_3(lead, out)?
+        }
+        Ok(())
+    }
+    fn tag2_3svariable(&mut self, out: &mut [i64; 3]) -> Result<()> {
+        let lead = self.byte()?;
+        match lead >> 6 {
+            0 => {
+                out[0] = sign_extend(((lead >> 4) & 0x03) as i64, 2);
+                out[1] = sign_extend(((lead >> 2) & 0x03) as i64, 2);
+                out[2] = sign_extend((lead & 0x03) as i64, 2);
+            },
+            1 => { // 5, 5, 4 bits
+                let b = self.byte()?;
+                out[0] = sign_extend(((lead & 0x3E) >> 1) as i64, 5);
+                out[1] = sign_extend((((lead & 0x01) << 4) | (b >> 4)) as i64, 5);
+                out[2] = sign_extend((b & 0x0F) as i64, 4);
+            },
+            2 => { // 8, 7, 7 bits
+                let b1 = self.byte()?;
+                let b2 = self.byte()?;
+                out[0] = sign_extend((((lead & 0x3F) << 2) | (b1 >> 6)) as i64, 8);
+                out[1] = sign_extend((((b1 & 0x3F) << 1) | (b2 >> 7)) as i64, 7);
+                out[2] = sign_extend((b2 & 0x7F) as i64, 7);
+            },
+            _ => self.variable_3(lead, out)?
+        }
+        Ok(())
+    }
+    fn variable_3(&mut self, mut lead: u8, out: &mut [i64; 3]) -> Result<()> {
+        for v in out.iter_mut() {
+            *v = self.le(match lead & 0x03 { 0 => 1, 1 => 2, 2 => 3, _ => 4 })?;
+            lead >>= 2;
+        }
+        Ok(())
+    }
+    fn tag8_4s16(&mut self, out: &mut [i64; 4]) -> Result<()> {
+        let mut selector = self.byte()?;
+        let mut nibble = false;
+        let mut buf = 0u8;
+        for v in out.iter_mut() {
+            *v = match selector & 0x03 {
+                0 => 0,
+                1 => if !nibble {
+                    buf = self.byte()?;
+                    nibble = true;
+                    sign_extend((buf >> 4) as i64, 4)
+                } else {
+                    nibble = false;
+                    sign_extend((buf & 0x0F) as i64, 4)
+                },
+                2 => if !nibble {
+                    sign_extend(self.byte()? as i64, 8)
+                } else {
+                    let high = buf << 4;
+                    buf = self.byte()?;
+                    sign_extend((high | (buf >> 4)) as i64, 8)
+                },
+                _ => if !nibble {
+                    let (b1, b2) = (self.byte()? as i64, self.byte()? as i64);
+                    sign_extend((b1 << 8) | b2, 16)
+                } else {
+                    let (b1, b2) = (self.byte()?, self.byte()?);
+                    let v = (((buf & 0x0F) as i64) << 12) | ((b1 as i64) << 4) | ((b2 >> 4) as i64);
+                    buf = b2;
+                    sign_extend(v, 16)
+                }
+            };
+            selector >>= 2;
+        }
+        Ok(())
+    }
+
+    /// Reads the raw (not predicted) values of one frame
+    fn values(&mut self, defs: &FieldDefs, out: &mut Vec<i64>) -> Result<()> {
+        let n = defs.encoding.len();
+        out.clear();
+        out.resize(n, 0);
+        let mut i = 0;
+        while i < n {
+            let signed = defs.signed.get(i).copied().unwrap_or_default();
+            match defs.encoding[i] {
+                SIGNED_VB => { out[i] = self.signed_vb()?; i += 1; },
+                UNSIGNED_VB => {
+                    let v = self.unsigned_vb()?;
+                    out[i] = if signed { v as i32 as i64 } else { v as i64 };
+                    i += 1;
+                },
+                NEG_14BIT => { out[i] = -sign_extend((self.unsigned_vb()? & 0x3FFF) as i64, 14); i += 1; },
+                TAG8_4S16 => {
+                    let mut v = [0; 4];
+                    self.tag8_4s16(&mut v)?;
+                    for (j, v) in v.iter().enumerate() { if i + j < n { out[i + j] = *v; } }
+                    i += 4;
+                },
+                enc @ (TAG2_3S32 | TAG2_3SVARIABLE) => {
+                    let mut v = [0; 3];
+                    if enc == TAG2_3S32 { self.tag2_3s32(&mut v)?; } else { self.tag2_3svariable(&mut v)?; }
+                    for (j, v) in v.iter().enumerate() { if i + j < n { out[i + j] = *v; } }
+                    i += 3;
+                },
+                TAG8_8SVB => {
+                    let count = (i..n.min(i + 8)).take_while(|j| defs.encoding[*j] == TAG8_8SVB).count();
+                    if count == 1 {
+                        out[i] = self.signed_vb()?;
+                    } else {
+                        let header = self.byte()?;
+                        for j in 0..count {
+                            out[i + j] = if header & (1 << j) != 0 { self.signed_vb()? } else { 0 };
+                        }
+                    }
+                    i += count;
+                },
+                NULL => { out[i] = 0; i += 1; },
+                enc => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown blackbox field encoding {enc}")))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sign_extend(v: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (v << shift) >> shift
+}
+
+struct MainState {
+    prev: Vec<i64>,
+    prev2: Vec<i64>,
+    valid: bool,
+    last_iteration: Option<i64>,
+    last_time: Option<i64>,
+}
+
+enum LogEnd { Eof, NextLog }
+
+/// Decodes all frames of one log, calls `cb` with every valid main frame
+fn decode_frames<R: BufRead>(stream: &mut Stream<R>, h: &Header, mut cb: impl FnMut(&[i64])) -> Result<LogEnd> {
+    let iteration_field = h.field("loopIteration");
+    let time_field = h.field("time");
+    let motor0_field = h.field("motor[0]");
+    let mut state = MainState { prev: Vec::new(), prev2: Vec::new(), valid: false, last_iteration: None, last_time: None };
+    let mut raw = Vec::new();
+    let mut current = Vec::new();
+
+    loop {
+        let Some(marker) = stream.peek()? else { return Ok(LogEnd::Eof); };
+        stream.byte()?;
+        let result = match marker {
+            b'I' | b'P' => {
+                let intra = marker == b'I';
+                let defs = if intra { &h.i } else { &h.p };
+                stream.values(defs, &mut raw).map(|_| {
+                    if intra || state.valid {
+                        let skipped = match (intra, state.last_iteration) {
+                            (false, Some(last)) => {
+                                let mut count = 0;
+                                while !h.should_have_frame(last + 1 + count) && count < h.i_interval { count += 1; }
+                                count
+                            },
+                            _ => 0
+                        };
+                        current.clear();
+                        for (i, raw) in raw.iter().enumerate() {
+                            let prev = state.prev.get(i).copied();
+                            let prev2 = state.prev2.get(i).copied().or(prev);
+                            let signed = h.i.signed.get(i).copied().unwrap_or_default();
+                            let prediction = match defs.predictor[i] {
+                                PREDICT_0 => 0,
+                                PREDICT_PREVIOUS => prev.unwrap_or_default(),
+                                PREDICT_STRAIGHT_LINE => prev.map(|p| 2 * p - prev2.unwrap_or(p)).unwrap_or_default(),
+                                PREDICT_AVERAGE_2 => match (prev, prev2) {
+                                    (Some(a), Some(b)) => if signed { (a + b) / 2 } else { ((a as u32 as u64 + b as u32 as u64) / 2) as i64 },
+                                    _ => 0
+                                },
+                                PREDICT_MINTHROTTLE => h.minthrottle,
+                                PREDICT_MOTOR_0 => motor0_field.filter(|x| *x < i).map(|x| current[x]).unwrap_or_default(),
+                                PREDICT_INC => prev.unwrap_or_default() + 1 + skipped,
+                                PREDICT_1500 => 1500,
+                                PREDICT_VBATREF => h.vbatref,
+                                PREDICT_MINMOTOR => h.minmotor,
+                                _ => 0
+                            };
+                            let v = raw + prediction;
+                            current.push(if signed { v as i32 as i64 } else { v as u32 as i64 });
+                        }
+                        true
+                    } else {
+                        false
+                    }
+                })
+            },
+            b'S' => stream.values(&h.s, &mut raw).map(|_| false),
+            b'G' => stream.values(&h.g, &mut raw).map(|_| false),
+            b'H' => {
+                if stream.peek()? == Some(b' ') {
+                    stream.byte()?;
+                    let line = stream.line()?;
+                    if line.starts_with("Product:") { return Ok(LogEnd::NextLog); }
+                    state.valid = false;
+                    continue;
+                }
+                stream.values(&h.h, &mut raw).map(|_| false)
+            },
+            b'E' => {
+                let event = stream.byte()?;
+                let mut read_event = || -> Result<()> { match event {
+                    0 | 15 => stream.unsigned_vb().map(|_| ()), // Sync beep, disarm
+                    10 => (0..5).try_for_each(|_| stream.byte().map(|_| ())), // Autotune cycle start
+                    11 => (0..4).try_for_each(|_| stream.byte().map(|_| ())), // Autotune cycle result
+                    13 => { // In-flight adjustment
+                        let func = stream.byte()?;
+                        if func & 0x80 != 0 { (0..4).try_for_each(|_| stream.byte().map(|_| ())) } else { stream.signed_vb().map(|_| ()) }
+                    },
+                    14 => { // Logging resumed after a pause, the next frame is an I frame
+                        let iteration = stream.unsigned_vb()?;
+                        let time = stream.unsigned_vb()?;
+                        state.last_iteration = Some(iteration as i64);
+                        state.last_time = Some(time as i64);
+                        state.valid = false;
+                        Ok(())
+                    },
+                    20 => stream.byte().and_then(|_| stream.signed_vb()).and_then(|_| stream.le(2)).map(|_| ()), // GTune cycle result
+                    30 => stream.unsigned_vb().and_then(|_| stream.unsigned_vb()).map(|_| ()), // Flight mode
+                    255 => { // End of log
+                        while stream.peek()?.is_some_and(|x| x != 0) { stream.byte()?; }
+                        if stream.peek()?.is_some() { stream.byte()?; }
+                        state.valid = false;
+                        Ok(())
+                    },
+                    _ => Err(Error::new(ErrorKind::InvalidData, "Unknown event"))
+                } };
+                if let Err(e) = read_event() {
+                    if e.kind() == ErrorKind::UnexpectedEof { return Ok(LogEnd::Eof); }
+                    state.valid = false;
+                }
+                continue;
+            },
+            _ => { state.valid = false; continue; } // Corrupted data, skip until the next frame marker
+        };
+
+        match result {
+            Ok(true) => {
+                // The frame is valid only if it's followed by another frame
+                let next_ok = stream.peek()?.map(|x| matches!(x, b'I' | b'P' | b'E' | b'S' | b'G' | b'H')).unwrap_or(true);
+                let time = time_field.map(|x| current[x]);
+                let time_ok = match (time, state.last_time) {
+                    (Some(t), Some(last)) if state.valid => t >= last && t - last < 10_000_000,
+                    _ => true
+                };
+                if next_ok && time_ok {
+                    state.prev2 = if marker == b'I' { current.clone() } else { std::mem::take(&mut state.prev) };
+                    state.prev = current.clone();
+                    state.valid = true;
+                    state.last_iteration = iteration_field.map(|x| current[x]);
+                    state.last_time = time;
+                    cb(&current);
+                } else {
+                    state.valid = false;
+                }
+            },
+            Ok(false) => { },
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(LogEnd::Eof),
+            Err(_) => state.valid = false
+        }
+    }
+}
+
+/// Skips to the first log header and parses it. Returns `None` if there are no more logs
+fn read_header<R: BufRead>(stream: &mut Stream<R>, at_log_start: bool) -> Result<Option<Header>> {
+    if !at_log_start {
+        // Look for "H Product:" at the beginning of a line
+        loop {
+            let line = stream.line()?;
+            if line.is_empty() && stream.peek()?.is_none() { return Ok(None); }
+            if line.starts_with("H Product:") { break; }
+        }
+    }
+    let mut h = Header::default();
+    while stream.peek()? == Some(b'H') {
+        stream.byte()?;
+        if stream.peek()? != Some(b' ') {
+            return Err(Error::new(ErrorKind::InvalidData, "Unexpected frame in blackbox header"));
+        }
+        stream.byte()?;
+        let line = stream.line()?;
+        h.parse_line(&line);
+    }
+    h.finish()?;
+    Ok(Some(h))
+}
+
+/// Calls `cb` for every log in the file with its header and the decoded main frames, and with `None` at the end of each log
+fn for_each_log<R: BufRead>(reader: R, mut cb: impl FnMut(usize, &Header, Option<&[i64]>)) -> Result<usize> {
+    let mut stream = Stream { reader };
+    let mut index = 0;
+    let mut at_log_start = false;
+    while let Some(h) = read_header(&mut stream, at_log_start)? {
+        let end = match decode_frames(&mut stream, &h, |frame| cb(index, &h, Some(frame))) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => LogEnd::Eof, // Truncated log
+            end => end?
+        };
+        cb(index, &h, None);
+        index += 1;
+        match end {
+            LogEnd::Eof => break,
+            LogEnd::NextLog => at_log_start = true,
+        }
+    }
+    Ok(index)
+}
+
+pub fn is_blackbox(filename: &str) -> bool {
+    let filename = filename.to_ascii_lowercase();
+    filename.ends_with(".bbl") || filename.ends_with(".bfl")
+}
+
+/// Decodes the gyro and accelerometer data of the log at `log_index`, or of all logs joined if `None`.
+/// Joined logs are placed one after another, also when the flight controller was restarted in between and the clock starts from 0 again
+pub fn parse<R: BufRead>(reader: R, log_index: Option<usize>, gyro: BlackboxGyro) -> Result<FileMetadata> {
+    struct Fields { time: usize, gyro: [usize; 3], accl: Option<[usize; 3]>, gyro_scale: f64, acc_1g: f64 }
+
+    let mut raw_imu = Vec::<TimeIMU>::new();
+    let mut logs = Vec::<(usize, f64, f64)>::new(); // index, start, end on the joined timeline
+    let mut firmware = String::new();
+    let mut current: Option<(usize, Option<Fields>, f64)> = None; // index, fields, offset from the log clock to the joined timeline
+    let mut last: Option<(f64, f64)> = None; // (log clock, joined timeline) of the last frame
+
+    let count = for_each_log(reader, |index, h, frame| {
+        let Some(frame) = frame else {
+            if firmware.is_empty() || log_index == Some(index) { firmware = h.firmware(); }
+            if !current.as_ref().is_some_and(|x| x.0 == index && x.1.is_some()) { log::warn!("Blackbox log #{} doesn't contain gyro data", index + 1); }
+            return;
+        };
+        if current.as_ref().map(|x| x.0) != Some(index) {
+            let filtered = h.fields3("gyroADC");
+            let unfiltered_debug = h.values.get("Firmware revision").is_some_and(|x| x.contains("Betaflight")) && h.debug_mode == DEBUG_GYRO_SCALED;
+            let unfiltered = h.fields3("gyroUnfilt").map(|x| (x, h.gyro_scale))
+                .or_else(|| if unfiltered_debug { h.fields3("debug").map(|x| (x, 1.0)) } else { None }); // Debug values are in deg/s
+            let gyro_fields = match gyro {
+                BlackboxGyro::Filtered => filtered.map(|x| (x, h.gyro_scale)).or(unfiltered),
+                BlackboxGyro::Unfiltered => {
+                    if unfiltered.is_none() { log::warn!("Unfiltered gyro is not logged in blackbox log #{}, using gyroADC", index + 1); }
+                    unfiltered.or(filtered.map(|x| (x, h.gyro_scale)))
+                }
+            };
+            let fields = h.field("time").zip(gyro_fields).map(|(time, (gyro, gyro_scale))| Fields { time, gyro, accl: h.fields3("accSmooth"), gyro_scale, acc_1g: h.acc_1g });
+            let t = fields.as_ref().map(|f| frame[f.time] as f64 / 1000.0).unwrap_or_default();
+            let offset = match last {
+                None => -t,
+                Some((last_t, last_ts)) if t > last_t => last_ts - last_t, // Same clock, keep the pause between the logs
+                Some((_, last_ts)) => last_ts + 1.0 - t
+            };
+            current = Some((index, fields, offset));
+        }
+        let Some((_, Some(f), offset)) = &current else { return; };
+
+        let t = frame[f.time] as f64 / 1000.0;
+        let timestamp_ms = t + offset;
+        last = Some((t, timestamp_ms));
+        match logs.last_mut() {
+            Some(l) if l.0 == index => l.2 = timestamp_ms,
+            _ => logs.push((index, timestamp_ms, timestamp_ms))
+        }
+        if log_index.is_some_and(|x| x != index) { return; }
+
+        let g = f.gyro;
+        raw_imu.push(TimeIMU {
+            timestamp_ms,
+            gyro: Some([frame[g[0]] as f64 * f.gyro_scale, frame[g[1]] as f64 * f.gyro_scale, frame[g[2]] as f64 * f.gyro_scale]),
+            accl: f.accl.map(|a| [frame[a[0]] as f64 / f.acc_1g * GRAVITY, frame[a[1]] as f64 / f.acc_1g * GRAVITY, frame[a[2]] as f64 / f.acc_1g * GRAVITY]),
+            magn: None
+        });
+    })?;
+
+    // A single log starts at 0
+    if let Some(start) = log_index.and_then(|i| logs.iter().find(|x| x.0 == i)).map(|x| x.1) {
+        for x in raw_imu.iter_mut() { x.timestamp_ms -= start; }
+    }
+
+    log::info!("Decoded {} samples from {count} blackbox logs", raw_imu.len());
+    if raw_imu.is_empty() { return Err(Error::new(ErrorKind::InvalidData, "No gyro data in the blackbox log")); }
+
+    let mut additional_data = serde_json::Map::new();
+    additional_data.insert("firmware".into(), firmware.clone().into());
+    // Same format as the logs listed by telemetry-parser
+    additional_data.insert("usable_logs".into(), logs.iter().map(|(index, start, end)| serde_json::Value::String(format!("{index};{start};{}", end - start))).collect::<Vec<_>>().into());
+
+    Ok(FileMetadata {
+        raw_imu,
+        detected_source: Some(firmware),
+        has_accurate_timestamps: true,
+        additional_data: serde_json::Value::Object(additional_data),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two logs written with the Betaflight encodings: 1000 frames at 2 kHz with `debug_mode` GYRO_SCALED,
+    // then 500 frames after a restart of the flight controller, without the debug gyro
+    static FIXTURE: &[u8] = include_bytes!("test_data/blackbox.bbl");
+
+    fn expected_gyro(idx: usize) -> [f64; 3] {
+        let s = idx as f64 * 250.0 / 1_000_000.0;
+        let rnd = |x: f64| (x + 0.5).floor();
+        [rnd(300.0 * (2.0 * std::f64::consts::PI * 2.0 * s).sin()), rnd(1500.0 * (2.0 * std::f64::consts::PI * 15.0 * s).sin()), rnd(20000.0 * (2.0 * std::f64::consts::PI * 5.0 * s).sin())]
+    }
+    fn assert_close(a: [f64; 3], b: [f64; 3], tolerance: f64) {
+        assert!((0..3).all(|i| (a[i] - b[i]).abs() <= tolerance), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn single_log() {
+        let md = parse(std::io::Cursor::new(FIXTURE), Some(0), BlackboxGyro::Filtered).unwrap();
+        assert_eq!(md.raw_imu.len(), 1000);
+        assert_eq!(md.detected_source.as_deref(), Some("Betaflight 4.4.2 (0d0cc2bd7) STM32F7X2"));
+        for (n, x) in md.raw_imu.iter().enumerate() {
+            let idx = n * 2;
+            let jitter = ((idx * 31) % 5) as f64 - 2.0;
+            let first_jitter = -2.0;
+            assert!((x.timestamp_ms - (idx as f64 * 0.25 + (jitter - first_jitter) / 1000.0)).abs() < 1e-9, "{n}: {}", x.timestamp_ms);
+            assert_close(x.gyro.unwrap(), expected_gyro(idx), 1.0);
+            let a = x.accl.unwrap();
+            assert!((a[2] - (2048 + idx % 5) as f64 / 2048.0 * GRAVITY).abs() < 1e-9);
+        }
+        let rate = (md.raw_imu.len() - 1) as f64 / (md.raw_imu.last().unwrap().timestamp_ms / 1000.0);
+        assert!((rate - 2000.0).abs() < 1.0, "{rate}");
+    }
+
+    #[test]
+    fn unfiltered_gyro() {
+        let md = parse(std::io::Cursor::new(FIXTURE), Some(0), BlackboxGyro::Unfiltered).unwrap();
+        let filtered = parse(std::io::Cursor::new(FIXTURE), Some(0), BlackboxGyro::Filtered).unwrap();
+        assert_eq!(md.raw_imu.len(), 1000);
+        assert!(md.raw_imu.iter().zip(&filtered.raw_imu).any(|(a, b)| a.gyro != b.gyro));
+        for x in &md.raw_imu {
+            assert_close(x.gyro.unwrap(), expected_gyro((x.timestamp_ms * 4.0).round() as usize), 6.0);
+        }
+
+        // Second log doesn't have the debug gyro
+        let md = parse(std::io::Cursor::new(FIXTURE), Some(1), BlackboxGyro::Unfiltered).unwrap();
+        assert_eq!(md.raw_imu.len(), 500);
+        assert_close(md.raw_imu[100].gyro.unwrap(), expected_gyro(200), 1.0);
+    }
+
+    #[test]
+    fn joined_and_truncated_logs() {
+        let md = parse(std::io::Cursor::new(FIXTURE), None, BlackboxGyro::Filtered).unwrap();
+        assert_eq!(md.raw_imu.len(), 1500);
+        assert!(md.raw_imu.windows(2).all(|w| w[1].timestamp_ms > w[0].timestamp_ms));
+        assert_eq!(md.additional_data["usable_logs"].as_array().map(|x| x.len()), Some(2));
+
+        let md = parse(std::io::Cursor::new(&FIXTURE[..FIXTURE.len() - 1000]), Some(1), BlackboxGyro::Filtered).unwrap();
+        assert!(md.raw_imu.len() > 400 && md.raw_imu.len() < 500, "{}", md.raw_imu.len());
+    }
+}
@@ -4,10 +4,11 @@
 use std::collections::BTreeMap;
 use parking_lot::RwLock;
 use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering::SeqCst };
 
 use crate::camera_identifier::CameraIdentifier;
 use crate::stabilization_params::ReadoutDirection;
-use super::{ TimeIMU, TimeQuat, TimeVec, splines };
+use super::{ TimeIMU, TimeQuat, TimeVec, gps::GpsSample, splines };
 
 #[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
@@ -48,12 +49,14 @@ pub struct FileMetadata {
     pub lens_profile:        Option<serde_json::Value>,
     pub lens_positions:      BTreeMap<i64, f64>,
     pub lens_params:         BTreeMap<i64, LensParams>,
+    pub focal_lengths:       BTreeMap<i64, f64>, // Focal length in mm by timestamp in us, only where it changes
     pub digital_zoom:        Option<f64>,
     pub has_accurate_timestamps: bool,
     pub additional_data:     serde_json::Value,
     pub per_frame_time_offsets: Vec<f64>,
     pub camera_stab_data:    Vec<CameraStabData>,
     pub mesh_correction:     Vec<(Vec<f64>, Vec<f32>)>,
+    pub gps:                 Vec<GpsSample>,
 }
 impl FileMetadata {
     pub fn thin(&self) -> Self {
@@ -71,31 +74,49 @@ impl FileMetadata {
             lens_profile:            self.lens_profile.clone(),
             lens_positions:          Default::default(),
             lens_params:             Default::default(),
+            focal_lengths:           Default::default(),
             digital_zoom:            self.digital_zoom.clone(),
             has_accurate_timestamps: self.has_accurate_timestamps.clone(),
             additional_data:         self.additional_data.clone(),
             per_frame_time_offsets:  Default::default(),
             camera_stab_data:        Default::default(),
             mesh_correction:         Default::default(),
+            gps:                     Default::default(),
         }
     }
     pub fn has_motion(&self) -> bool {
         !self.raw_imu.is_empty() || !self.quaternions.is_empty()
     }
+    pub fn has_gps(&self) -> bool {
+        self.gps.iter().any(|x| x.has_fix())
+    }
+    /// Sensor crop of the frame at `timestamp_us`: sensor height / captured area height, so 1.0 is the full sensor
+    /// and it grows with the digital zoom. From the closest lens sample within 100 ms
+    pub fn crop_at(&self, timestamp_us: i64) -> Option<f64> {
+        use crate::util::MapClosest;
+        let lens = self.lens_params.get_closest(&timestamp_us, 100000)?;
+        let (sensor, captured) = (lens.sensor_size_px?.1 as f64, lens.capture_area_size?.1 as f64);
+        (captured > 0.0).then(|| sensor / captured)
+    }
 }
 
 // ------------- ReadOnlyFileMetadata -------------
 // Make a thread-safe read-only wrapper for FileMetadata, because once it's read, it's never changed
+// The generation is unique for every new metadata and changes with every modification, so the caches of the data can be keyed on it instead of the contents
 #[derive(Clone)]
-pub struct ReadOnlyFileMetadata(pub Arc<RwLock<FileMetadata>>);
+pub struct ReadOnlyFileMetadata(Arc<RwLock<FileMetadata>>, Arc<AtomicU64>);
+
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+fn next_generation() -> u64 { GENERATION.fetch_add(1, SeqCst) }
+
 impl Default for ReadOnlyFileMetadata {
     fn default() -> Self {
-        Self(Arc::new(RwLock::new(Default::default())))
+        FileMetadata::default().into()
     }
 }
 impl From<FileMetadata> for ReadOnlyFileMetadata {
     fn from(v: FileMetadata) -> Self {
-        Self(Arc::new(RwLock::new(v)))
+        Self(Arc::new(RwLock::new(v)), Arc::new(AtomicU64::new(next_generation())))
     }
 }
 impl ReadOnlyFileMetadata {
@@ -104,6 +125,10 @@ impl ReadOnlyFileMetadata {
     }
     pub fn set_raw_imu(&mut self, v: Vec<TimeIMU>) {
         self.0.write().raw_imu = v;
+        self.1.store(next_generation(), SeqCst);
+    }
+    pub fn generation(&self) -> u64 {
+        self.1.load(SeqCst)
     }
 }
 impl serde::Serialize for ReadOnlyFileMetadata {
@@ -113,7 +138,7 @@ impl serde::Serialize for ReadOnlyFileMetadata {
 }
 impl<'de> serde::Deserialize<'de> for ReadOnlyFileMetadata {
 	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
-        Ok(Self(Arc::new(RwLock::new(FileMetadata::deserialize(deserializer)?))))
+        Ok(FileMetadata::deserialize(deserializer)?.into())
     }
 }
 // ------------- ReadOnlyFileMetadata -------------
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Nominal sample rate of the motion data and the places where samples are missing, eg. dropped by the logger or the camera.
+
+use super::TimeIMU;
+
+// Intervals longer than this many nominal intervals are considered a gap
+pub const GAP_FACTOR: f64 = 3.0;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum GapRepair {
+    #[default]
+    None,   // Keep the data as is, the integration interpolates linearly across the gap
+    Hold,   // Repeat the last sample before the gap
+    Linear,
+    Spline, // Cubic Hermite through the samples around the gap
+}
+impl From<&str> for GapRepair {
+    fn from(v: &str) -> Self {
+        match v.to_ascii_lowercase().as_str() {
+            "hold"   => Self::Hold,
+            "linear" => Self::Linear,
+            "spline" => Self::Spline,
+            _ => Self::None
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SampleRateAnalysis {
+    pub nominal_rate_hz: f64,
+    pub nominal_interval_ms: f64,
+    pub gaps: Vec<(f64, f64)>, // (last sample before, first sample after) gyro timestamps in ms
+}
+
+impl SampleRateAnalysis {
+    /// Estimates the nominal rate from the median interval and finds the intervals longer than `factor` times that
+    pub fn analyze(imu: &[TimeIMU], factor: f64) -> Option<Self> {
+        let interval = median_sample_interval(imu)?;
+        let gaps = imu.windows(2)
+            .filter(|w| w[1].timestamp_ms - w[0].timestamp_ms > interval * factor)
+            .map(|w| (w[0].timestamp_ms, w[1].timestamp_ms))
+            .collect::<Vec<_>>();
+        if !gaps.is_empty() {
+            log::warn!("Found {} gaps in the motion data, nominal rate: {:.2} Hz, longest: {:.1} ms", gaps.len(), 1000.0 / interval, gaps.iter().map(|x| x.1 - x.0).fold(0.0, f64::max));
+        }
+        Some(Self {
+            nominal_rate_hz: 1000.0 / interval,
+            nominal_interval_ms: interval,
+            gaps
+        })
+    }
+
+    pub fn overlaps_gap(&self, start_ms: f64, end_ms: f64) -> bool {
+        self.gaps.iter().any(|g| g.0 < end_ms && g.1 > start_ms)
+    }
+
+    pub fn missing_ms(&self) -> f64 {
+        self.gaps.iter().map(|g| g.1 - g.0 - self.nominal_interval_ms).sum()
+    }
+}
+
+pub(super) fn median_sample_interval(imu: &[TimeIMU]) -> Option<f64> {
+    let mut intervals = imu.windows(2).map(|w| w[1].timestamp_ms - w[0].timestamp_ms).filter(|x| *x > 0.0).collect::<Vec<_>>();
+    if intervals.is_empty() { return None; }
+    intervals.sort_by(|a, b| a.total_cmp(b));
+    Some(intervals[intervals.len() / 2])
+}
+
+/// Inserts samples at the nominal interval into every gap of `analysis`
+pub fn repair(imu: &[TimeIMU], analysis: &SampleRateAnalysis, method: GapRepair) -> Vec<TimeIMU> {
+    if method == GapRepair::None || analysis.gaps.is_empty() || !(analysis.nominal_interval_ms > 0.0) { return imu.to_vec(); }
+
+    let step = analysis.nominal_interval_ms;
+    let mut ret = Vec::with_capacity(imu.len() + (analysis.missing_ms() / step).ceil().max(0.0) as usize);
+    for i in 0..imu.len() {
+        ret.push(imu[i].clone());
+        if i + 1 >= imu.len() || !analysis.gaps.iter().any(|g| g.0 == imu[i].timestamp_ms && g.1 == imu[i + 1].timestamp_ms) { continue; }
+
+        let (p1, p2) = (&imu[i], &imu[i + 1]);
+        let p0 = if i > 0 { &imu[i - 1] } else { p1 };
+        let p3 = imu.get(i + 2).unwrap_or(p2);
+        let count = ((p2.timestamp_ms - p1.timestamp_ms) / step - 0.5).floor().max(0.0) as usize;
+        for k in 1..=count {
+            let ts = p1.timestamp_ms + k as f64 * step;
+            let value = |f: fn(&TimeIMU) -> Option<[f64; 3]>| -> Option<[f64; 3]> {
+                let (v1, v2) = (f(p1)?, f(p2)?);
+                if method == GapRepair::Hold { return Some(v1); }
+                let t = (ts - p1.timestamp_ms) / (p2.timestamp_ms - p1.timestamp_ms);
+                let mut out = [0.0; 3];
+                for a in 0..3 {
+                    out[a] = match method {
+                        GapRepair::Spline => {
+                            let dt = p2.timestamp_ms - p1.timestamp_ms;
+                            let tangent = |a_ts: f64, a_v: f64, b_ts: f64, b_v: f64| if b_ts > a_ts { (b_v - a_v) / (b_ts - a_ts) * dt } else { 0.0 };
+                            let m1 = f(p0).map(|v0| tangent(p0.timestamp_ms, v0[a], p2.timestamp_ms, v2[a])).unwrap_or(v2[a] - v1[a]);
+                            let m2 = f(p3).map(|v3| tangent(p1.timestamp_ms, v1[a], p3.timestamp_ms, v3[a])).unwrap_or(v2[a] - v1[a]);
+                            let (t2, t3) = (t * t, t * t * t);
+                            (2.0 * t3 - 3.0 * t2 + 1.0) * v1[a] + (t3 - 2.0 * t2 + t) * m1 + (-2.0 * t3 + 3.0 * t2) * v2[a] + (t3 - t2) * m2
+                        },
+                        _ => v1[a] + (v2[a] - v1[a]) * t
+                    };
+                }
+                Some(out)
+            };
+            ret.push(TimeIMU {
+                timestamp_ms: ts,
+                gyro: value(|x| x.gyro),
+                accl: value(|x| x.accl),
+                magn: value(|x| x.magn),
+            });
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200 Hz, 2 seconds of slow rotation with 40 ms missing at 0.5 s and 100 ms missing at 1.2 s
+    fn fixture() -> Vec<TimeIMU> {
+        super::super::gcsv::parse_gcsv(include_str!("test_data/gaps.gcsv")).unwrap().raw_imu
+    }
+
+    #[test]
+    fn detects_injected_gaps() {
+        let analysis = SampleRateAnalysis::analyze(&fixture(), GAP_FACTOR).unwrap();
+        assert!((analysis.nominal_rate_hz - 200.0).abs() < 0.01);
+        assert_eq!(analysis.gaps.len(), 2);
+        assert!((analysis.gaps[0].0 - 495.0).abs() < 1e-6 && (analysis.gaps[0].1 - 540.0).abs() < 1e-6);
+        assert!((analysis.gaps[1].0 - 1195.0).abs() < 1e-6 && (analysis.gaps[1].1 - 1300.0).abs() < 1e-6);
+        assert!(analysis.overlaps_gap(1000.0, 1200.0));
+        assert!(!analysis.overlaps_gap(600.0, 1100.0));
+    }
+
+    #[test]
+    fn repair_fills_gaps() {
+        let imu = fixture();
+        let analysis = SampleRateAnalysis::analyze(&imu, GAP_FACTOR).unwrap();
+        for method in [GapRepair::Hold, GapRepair::Linear, GapRepair::Spline] {
+            let repaired = repair(&imu, &analysis, method);
+            assert_eq!(repaired.len(), 400, "{method:?}");
+            let after = SampleRateAnalysis::analyze(&repaired, GAP_FACTOR).unwrap();
+            assert!(after.gaps.is_empty(), "{method:?}");
+        }
+
+        // The fixture is a sine, so the spline should be closer to it than the straight line
+        let truth = |ts: f64| (ts / 1000.0 * std::f64::consts::PI).sin() * 50.0;
+        let error = |method| repair(&imu, &analysis, method).iter()
+            .filter(|x| analysis.overlaps_gap(x.timestamp_ms - 1.0, x.timestamp_ms + 1.0))
+            .map(|x| (x.gyro.unwrap()[0] - truth(x.timestamp_ms)).abs())
+            .fold(0.0, f64::max);
+        assert!(error(GapRepair::Spline) < error(GapRepair::Linear));
+        assert!(error(GapRepair::Linear) < error(GapRepair::Hold));
+    }
+}
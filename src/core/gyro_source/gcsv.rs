@@ -0,0 +1,264 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Export of the processed motion data to gcsv (https://docs.gyroflow.xyz/app/technical-details/gcsv-format)
+// The data is written after all transforms, so the orientation is always XYZ and the bias and filters are already applied.
+// Quaternion files use `qw,qx,qy,qz` columns instead of the gyroscope ones and are read by `parse_gcsv`.
+
+use std::fmt::Write;
+use super::*;
+
+const TSCALE: f64 = 0.000001; // Timestamps are written in microseconds
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum GcsvData {
+    #[default]
+    AngularVelocity,
+    Quaternions,
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GcsvExportOptions {
+    pub data: GcsvData,
+    pub sample_rate: f64, // Hz, 0 = original samples
+    pub bake_sync_offset: bool, // Write timestamps in video time
+    pub include_accelerometer: bool,
+}
+
+impl GyroSource {
+    pub fn export_gcsv(&self, url: &str, options: &GcsvExportOptions) -> Result<(), crate::GyroflowCoreError> {
+        Ok(crate::filesystem::write(url, self.to_gcsv(options).as_bytes())?)
+    }
+
+    pub fn to_gcsv(&self, options: &GcsvExportOptions) -> String {
+        let file_metadata = self.file_metadata.read();
+        let imu = self.raw_imu(&file_metadata);
+        let has_accl = options.include_accelerometer && imu.iter().any(|x| x.accl.is_some());
+
+        let mut out = String::new();
+        let _ = writeln!(out, "GYROFLOW IMU LOG");
+        let _ = writeln!(out, "version,1.3");
+        let _ = writeln!(out, "id,gyroflow");
+        let _ = writeln!(out, "orientation,XYZ");
+        let _ = writeln!(out, "note,processed motion data");
+        if let Some(source) = &file_metadata.detected_source {
+            let _ = writeln!(out, "vendor,{}", source.replace(',', " "));
+        }
+        if !self.file_url.is_empty() {
+            let _ = writeln!(out, "videofilename,{}", crate::filesystem::get_filename(&self.file_url).replace(',', " "));
+        }
+        let _ = writeln!(out, "tscale,{TSCALE}");
+
+        let (start_ms, end_ms, native_ts): (f64, f64, Vec<f64>) = match options.data {
+            GcsvData::Quaternions     => (self.quaternions.keys().next().map(|x| *x as f64 / 1000.0).unwrap_or_default(), self.quaternions.keys().next_back().map(|x| *x as f64 / 1000.0).unwrap_or_default(), self.quaternions.keys().map(|x| *x as f64 / 1000.0).collect()),
+            GcsvData::AngularVelocity => (imu.first().map(|x| x.timestamp_ms).unwrap_or_default(), imu.last().map(|x| x.timestamp_ms).unwrap_or_default(), imu.iter().map(|x| x.timestamp_ms).collect()),
+        };
+
+        // (output timestamp, gyro timestamp) pairs in ms
+        let timestamps = if options.sample_rate > 0.0 {
+            let step = 1000.0 / options.sample_rate;
+            let (from, to) = if options.bake_sync_offset {
+                (self.gyro_to_video_timestamp(start_ms), self.gyro_to_video_timestamp(end_ms))
+            } else {
+                (start_ms, end_ms)
+            };
+            let count = ((to - from) / step).floor().max(0.0) as usize + 1;
+            (0..count).map(|i| from + i as f64 * step).map(|t| (t, if options.bake_sync_offset { self.video_to_gyro_timestamp(t) } else { t })).collect::<Vec<_>>()
+        } else {
+            native_ts.into_iter().map(|t| (if options.bake_sync_offset { self.gyro_to_video_timestamp(t) } else { t }, t)).collect()
+        };
+
+        match options.data {
+            GcsvData::Quaternions => {
+                if has_accl { let _ = writeln!(out, "ascale,1.0"); }
+                let _ = writeln!(out, "{}", if has_accl { "t,qw,qx,qy,qz,ax,ay,az" } else { "t,qw,qx,qy,qz" });
+                for (t, gyro_ts) in timestamps {
+                    let q = quat_at_gyro_timestamp(&self.quaternions, gyro_ts).into_inner();
+                    let _ = write!(out, "{},{:.10},{:.10},{:.10},{:.10}", (t * 1000.0).round() as i64, q.w, q.i, q.j, q.k);
+                    if has_accl {
+                        let a = sample_at(imu, gyro_ts).1.unwrap_or_default();
+                        let _ = write!(out, ",{:.6},{:.6},{:.6}", a[0] / GRAVITY, a[1] / GRAVITY, a[2] / GRAVITY);
+                    }
+                    out.push('\n');
+                }
+            },
+            GcsvData::AngularVelocity => {
+                let _ = writeln!(out, "gscale,{}", std::f64::consts::PI / 180.0);
+                if has_accl { let _ = writeln!(out, "ascale,1.0"); }
+                let _ = writeln!(out, "{}", if has_accl { "t,gx,gy,gz,ax,ay,az" } else { "t,gx,gy,gz" });
+                for (t, gyro_ts) in timestamps {
+                    let (g, a) = sample_at(imu, gyro_ts);
+                    let Some(mut g) = g else { continue; };
+                    if options.bake_sync_offset {
+                        // Rates are per video second
+                        let rate = self.time_mapping.rate_at_gyro(gyro_ts);
+                        for v in g.iter_mut() { *v *= rate; }
+                    }
+                    let _ = write!(out, "{},{:.8},{:.8},{:.8}", (t * 1000.0).round() as i64, g[0], g[1], g[2]);
+                    if has_accl {
+                        let a = a.unwrap_or_default();
+                        let _ = write!(out, ",{:.6},{:.6},{:.6}", a[0] / GRAVITY, a[1] / GRAVITY, a[2] / GRAVITY);
+                    }
+                    out.push('\n');
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Checks whether the file is a gcsv with quaternions (as written by `to_gcsv`), which the telemetry parser doesn't read
+pub fn is_quaternion_gcsv(contents: &str) -> bool {
+    contents.starts_with("GYROFLOW IMU LOG") && contents.lines().take(50).any(|l| l.starts_with("t,") && l.split(',').any(|c| c.trim() == "qw"))
+}
+
+/// Parses a gcsv with gyroscope or quaternion columns
+pub fn parse_gcsv(contents: &str) -> Result<FileMetadata, crate::GyroflowCoreError> {
+    let mut md = FileMetadata { detected_source: Some("Gyroflow gcsv".into()), has_accurate_timestamps: true, ..Default::default() };
+    let (mut tscale, mut gscale, mut ascale) = (0.001, 1.0, 1.0);
+
+    let mut lines = contents.lines();
+    if lines.next().map(|x| x.trim()) != Some("GYROFLOW IMU LOG") { return Err(crate::GyroflowCoreError::InvalidData); }
+
+    let mut columns = Vec::new();
+    for line in lines.by_ref() {
+        let line = line.trim();
+        if line.starts_with("t,") {
+            columns = line.split(',').map(|x| x.trim().to_owned()).collect();
+            break;
+        }
+        if let Some((key, value)) = line.split_once(',') {
+            match key {
+                "tscale" => tscale = value.parse().map_err(|_| crate::GyroflowCoreError::InvalidData)?,
+                "gscale" => gscale = value.parse().map_err(|_| crate::GyroflowCoreError::InvalidData)?,
+                "ascale" => ascale = value.parse().map_err(|_| crate::GyroflowCoreError::InvalidData)?,
+                "orientation" => md.imu_orientation = Some(value.to_owned()),
+                _ => { }
+            }
+        }
+    }
+    let col = |name: &str| columns.iter().position(|x| x == name);
+    let gyro_cols = [col("gx"), col("gy"), col("gz")];
+    let accl_cols = [col("ax"), col("ay"), col("az")];
+    let quat_cols = [col("qw"), col("qx"), col("qy"), col("qz")];
+    if columns.is_empty() || (gyro_cols.iter().any(Option::is_none) && quat_cols.iter().any(Option::is_none)) {
+        return Err(crate::GyroflowCoreError::InvalidData);
+    }
+
+    for line in lines {
+        let values = line.split(',').map(|x| x.trim().parse::<f64>().ok()).collect::<Vec<_>>();
+        let get = |i: Option<usize>| i.and_then(|i| values.get(i).copied().flatten());
+        let Some(t) = get(Some(0)) else { continue; };
+        let timestamp_ms = t * tscale * 1000.0;
+
+        if let [Some(w), Some(x), Some(y), Some(z)] = quat_cols.map(get) {
+            md.quaternions.insert((timestamp_ms * 1000.0).round() as i64, Quat64::from_quaternion(Quaternion::new(w, x, y, z)));
+        }
+        let gyro = match gyro_cols.map(get) { [Some(x), Some(y), Some(z)] => Some([x * gscale / DEG2RAD, y * gscale / DEG2RAD, z * gscale / DEG2RAD]), _ => None };
+        let accl = match accl_cols.map(get) { [Some(x), Some(y), Some(z)] => Some([x * ascale * GRAVITY, y * ascale * GRAVITY, z * ascale * GRAVITY]), _ => None };
+        if gyro.is_some() || accl.is_some() {
+            md.raw_imu.push(TimeIMU { timestamp_ms, gyro, accl, magn: None });
+        }
+    }
+    if !md.has_motion() { return Err(crate::GyroflowCoreError::InvalidData); }
+    Ok(md)
+}
+
+fn quat_at_gyro_timestamp(quats: &TimeQuat, timestamp_ms: f64) -> Quat64 {
+    let ts = timestamp_ms * 1000.0;
+    let Some(a) = quats.range(..=(ts.floor() as i64)).next_back() else { return quats.values().next().copied().unwrap_or_else(Quat64::identity) };
+    let Some(b) = quats.range((ts.floor() as i64 + 1)..).next() else { return *a.1 };
+    a.1.slerp(b.1, ((ts - *a.0 as f64) / (*b.0 - *a.0) as f64).clamp(0.0, 1.0))
+}
+
+// Linearly interpolated gyro and accelerometer readings
+pub(super) fn sample_at(imu: &[TimeIMU], timestamp_ms: f64) -> (Option<[f64; 3]>, Option<[f64; 3]>) {
+    if imu.is_empty() { return (None, None); }
+    let i = imu.partition_point(|x| x.timestamp_ms <= timestamp_ms);
+    if i == 0 { return (imu[0].gyro, imu[0].accl); }
+    if i == imu.len() { return (imu[i - 1].gyro, imu[i - 1].accl); }
+    let (a, b) = (&imu[i - 1], &imu[i]);
+    let fract = (timestamp_ms - a.timestamp_ms) / (b.timestamp_ms - a.timestamp_ms);
+    let lerp = |a: Option<[f64; 3]>, b: Option<[f64; 3]>| -> Option<[f64; 3]> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some([a[0] + (b[0] - a[0]) * fract, a[1] + (b[1] - a[1]) * fract, a[2] + (b[2] - a[2]) * fract]),
+            (a, b) => a.or(b)
+        }
+    };
+    (lerp(a.gyro, b.gyro), lerp(a.accl, b.accl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_source() -> GyroSource {
+        let raw_imu = (0..500).map(|i| {
+            let t = i as f64 / 100.0;
+            TimeIMU {
+                timestamp_ms: i as f64 * 10.0,
+                gyro: Some([20.0 * (t * 1.7).sin(), 10.0 * (t * 0.9).cos(), 30.0 * (t * 2.3).sin()]),
+                accl: Some([0.0, 0.0, GRAVITY]),
+                magn: None
+            }
+        }).collect::<Vec<_>>();
+        let mut gyro = GyroSource::new();
+        gyro.integration_method = IntegrationMethod::GyroOnly;
+        gyro.duration_ms = 5000.0;
+        gyro.load_from_telemetry(FileMetadata { raw_imu, ..Default::default() });
+        gyro
+    }
+
+    // Through the same loader as any other motion data file
+    fn load(gyro: &GyroSource, options: &GcsvExportOptions, name: &str) -> FileMetadata {
+        let path = std::env::temp_dir().join(format!("gyroflow_{}_{name}.gcsv", std::process::id()));
+        let url = crate::filesystem::path_to_url(&path.to_string_lossy());
+        gyro.export_gcsv(&url, options).unwrap();
+        let md = GyroSource::parse_telemetry_file(&url, &Default::default(), (1920, 1080), 30.0, |_| (), Arc::new(AtomicBool::new(false)));
+        let _ = std::fs::remove_file(&path);
+        md.unwrap()
+    }
+
+    fn max_angle_error(a: &TimeQuat, b: &TimeQuat) -> f64 {
+        assert_eq!(a.len(), b.len());
+        a.iter().zip(b.iter()).map(|((ta, qa), (tb, qb))| {
+            assert_eq!(ta, tb);
+            qa.angle_to(qb)
+        }).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn quaternions_round_trip() {
+        let gyro = test_source();
+        let options = GcsvExportOptions { data: GcsvData::Quaternions, include_accelerometer: true, ..Default::default() };
+        assert!(is_quaternion_gcsv(&gyro.to_gcsv(&options)));
+
+        let md = load(&gyro, &options, "quaternions");
+        assert_eq!(md.raw_imu.len(), 500);
+        assert!((md.raw_imu[0].accl.unwrap()[2] - GRAVITY).abs() < 1e-6);
+
+        let err = max_angle_error(&gyro.quaternions, &md.quaternions);
+        assert!(err < 1e-8, "error: {err}");
+    }
+
+    #[test]
+    fn angular_velocity_round_trip() {
+        let gyro = test_source();
+        assert!(!is_quaternion_gcsv(&gyro.to_gcsv(&GcsvExportOptions::default())));
+
+        let mut imported = GyroSource::new();
+        imported.integration_method = IntegrationMethod::GyroOnly;
+        imported.duration_ms = 5000.0;
+        imported.load_from_telemetry(load(&gyro, &GcsvExportOptions::default(), "angular_velocity"));
+        assert!(imported.file_metadata.read().raw_imu.iter().all(|x| x.accl.is_none()));
+
+        let err = max_angle_error(&gyro.quaternions, &imported.quaternions);
+        assert!(err < 1e-6, "error: {err}");
+
+        // The accelerometer is written in g and read back with the same gravity
+        let md = load(&gyro, &GcsvExportOptions { include_accelerometer: true, ..Default::default() }, "angular_velocity_accl");
+        assert_eq!(md.raw_imu.len(), 500);
+        assert!(md.raw_imu.iter().all(|x| (x.accl.unwrap()[2] - GRAVITY).abs() < 1e-3));
+    }
+}
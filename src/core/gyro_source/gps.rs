@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+use telemetry_parser::tags_impl::{ GroupedTagMap, GetWithType, GroupId, TagId };
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GpsSample {
+    pub timestamp_ms: f64, // gyro time
+    pub lat: f64,          // degrees
+    pub lon: f64,          // degrees
+    pub altitude: f64,     // meters
+    pub speed: f64,        // m/s
+    pub fix_quality: u8,   // 0 = no fix, the position is not valid
+}
+impl GpsSample {
+    pub fn has_fix(&self) -> bool { self.fix_quality > 0 }
+}
+
+/// GPS samples from one telemetry sample, spread evenly over its duration
+pub(super) fn collect(tag_map: &GroupedTagMap, info: &telemetry_parser::util::SampleInfo, out: &mut Vec<GpsSample>) {
+    let Some(map) = tag_map.get(&GroupId::GPS) else { return; };
+    let Some(arr) = map.get_t(TagId::Data) as Option<&Vec<telemetry_parser::tags_impl::GpsData>> else { return; };
+    let step = if arr.is_empty() { 0.0 } else { info.duration_ms / arr.len() as f64 };
+    for (i, v) in arr.iter().enumerate() {
+        out.push(GpsSample {
+            timestamp_ms: info.timestamp_ms + i as f64 * step,
+            lat: v.lat,
+            lon: v.lon,
+            altitude: v.altitude,
+            speed: v.speed,
+            fix_quality: if v.is_acquired { 1 } else { 0 },
+        });
+    }
+}
+
+/// Position at `timestamp_ms` (gyro time), interpolated between the closest samples with a fix.
+/// Returns `None` before the first and after the last fix
+pub fn interpolate(samples: &[GpsSample], timestamp_ms: f64) -> Option<GpsSample> {
+    let i = samples.partition_point(|x| x.timestamp_ms <= timestamp_ms);
+    let prev = samples[..i].iter().rev().find(|x| x.has_fix())?;
+    if prev.timestamp_ms == timestamp_ms { return Some(*prev); }
+    let next = samples[i..].iter().find(|x| x.has_fix())?;
+
+    let t = (timestamp_ms - prev.timestamp_ms) / (next.timestamp_ms - prev.timestamp_ms);
+    let lerp = |a: f64, b: f64| a + (b - a) * t;
+    let mut dlon = next.lon - prev.lon;
+    if dlon > 180.0 { dlon -= 360.0; } else if dlon < -180.0 { dlon += 360.0; }
+    let mut lon = prev.lon + dlon * t;
+    if lon > 180.0 { lon -= 360.0; } else if lon < -180.0 { lon += 360.0; }
+    Some(GpsSample {
+        timestamp_ms,
+        lat: lerp(prev.lat, next.lat),
+        lon,
+        altitude: lerp(prev.altitude, next.altitude),
+        speed: lerp(prev.speed, next.speed),
+        fix_quality: prev.fix_quality.min(next.fix_quality),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fix_acquired_halfway() {
+        // 10 Hz, no fix for the first second
+        let samples = (0..30).map(|i| GpsSample {
+            timestamp_ms: i as f64 * 100.0,
+            lat: if i < 10 { 0.0 } else { 50.0 + i as f64 * 0.0001 },
+            lon: if i < 10 { 0.0 } else { let lon = 179.99855 + i as f64 * 0.0001; if lon > 180.0 { lon - 360.0 } else { lon } },
+            altitude: 100.0,
+            speed: i as f64,
+            fix_quality: if i < 10 { 0 } else { 1 },
+        }).collect::<Vec<_>>();
+
+        assert!(interpolate(&samples, 500.0).is_none());
+        assert!(interpolate(&samples, 999.0).is_none());
+        assert!(interpolate(&samples, 3500.0).is_none());
+
+        let v = interpolate(&samples, 1050.0).unwrap();
+        assert!((v.speed - 10.5).abs() < 1e-9);
+        assert!((v.lat - 50.00105).abs() < 1e-9);
+
+        // Crossing the antimeridian
+        let v = interpolate(&samples, 1475.0).unwrap();
+        assert!((v.lon - -179.999975).abs() < 1e-6, "{}", v.lon);
+    }
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Absolute gravity direction from the accelerometer, in the parts where the camera is not accelerating.
+// Used by the horizon lock to correct the roll and pitch drift of the integrated orientation.
+
+use super::{ Quat64, TimeIMU, TimeQuat, GRAVITY };
+use nalgebra::Vector3;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GravityReferenceParams {
+    pub window_ms: f64,
+    pub max_accel_deviation: f64, // Fraction of g, for both the mean magnitude and the spread within the window
+    pub max_rotation_dps: f64,
+}
+impl Default for GravityReferenceParams {
+    fn default() -> Self {
+        Self {
+            window_ms: 500.0,
+            max_accel_deviation: 0.05,
+            max_rotation_dps: 30.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GravitySample {
+    pub timestamp_us: i64, // Center of the window
+    pub gravity: [f64; 3], // Unit vector in the body frame, in the direction of the accelerometer reading at rest (ie. up)
+    pub confidence: f64,   // 0 - 1
+}
+
+/// Finds low-dynamics windows and returns the gravity direction in each. Empty if the camera was accelerating the whole time
+pub fn estimate(imu: &[TimeIMU], params: &GravityReferenceParams) -> Vec<GravitySample> {
+    let mut ret = Vec::new();
+    if params.window_ms <= 0.0 || params.max_accel_deviation <= 0.0 { return ret; }
+
+    let mut i = 0;
+    while i < imu.len() {
+        let start_ts = imu[i].timestamp_ms;
+        let begin = i;
+        while i < imu.len() && imu[i].timestamp_ms - start_ts < params.window_ms { i += 1; }
+        let window = &imu[begin..i];
+
+        let accl = window.iter().filter_map(|x| x.accl.map(Vector3::from)).collect::<Vec<_>>();
+        if accl.len() < 3 { continue; }
+        let n = accl.len() as f64;
+        let mean = accl.iter().sum::<Vector3<f64>>() / n;
+        let magnitude = accl.iter().map(|x| x.norm()).sum::<f64>() / n;
+        let spread = (accl.iter().map(|x| (x - mean).norm_squared()).sum::<f64>() / n).sqrt();
+        let gyro = window.iter().filter_map(|x| x.gyro.map(|g| Vector3::from(g).norm())).collect::<Vec<_>>();
+        let rotation = if gyro.is_empty() { 0.0 } else { gyro.iter().sum::<f64>() / gyro.len() as f64 };
+
+        let magnitude_score = 1.0 - ((magnitude - GRAVITY).abs() / GRAVITY) / params.max_accel_deviation;
+        let spread_score    = 1.0 - (spread / GRAVITY) / params.max_accel_deviation;
+        let rotation_score  = if params.max_rotation_dps > 0.0 { 1.0 - rotation / params.max_rotation_dps } else { 1.0 };
+        if magnitude_score <= 0.0 || spread_score <= 0.0 || rotation_score <= 0.0 || mean.norm() < 1e-6 { continue; }
+
+        let g = mean.normalize();
+        let end_ts = window[window.len() - 1].timestamp_ms;
+        ret.push(GravitySample {
+            timestamp_us: ((start_ts + end_ts) * 500.0).round() as i64,
+            gravity: [g.x, g.y, g.z],
+            confidence: (magnitude_score * spread_score * rotation_score).clamp(0.0, 1.0),
+        });
+    }
+    ret
+}
+
+/// World frame rotations which move the up direction of `quats` at the reference samples toward the measured one.
+/// Each rotation is scaled by the confidence and `strength` (0 - 1)
+pub fn drift_corrections(quats: &TimeQuat, reference: &[GravitySample], strength: f64) -> TimeQuat {
+    let mut ret = TimeQuat::new();
+    if strength <= 0.0 || quats.is_empty() { return ret; }
+    for r in reference {
+        let Some((_, q)) = quats.range(..=r.timestamp_us).next_back().or_else(|| quats.iter().next()) else { continue; };
+        // Same body frame as the integrators use
+        let up = q * Vector3::new(-r.gravity[1], r.gravity[0], r.gravity[2]);
+        if let Some(correction) = Quat64::rotation_between(&up, &Vector3::z()) {
+            ret.insert(r.timestamp_us, Quat64::identity().slerp(&correction, (strength * r.confidence).clamp(0.0, 1.0)));
+        }
+    }
+    ret
+}
+
+/// Correction interpolated between the reference samples, held before the first and after the last one
+pub fn correction_at(corrections: &TimeQuat, timestamp_us: i64) -> Quat64 {
+    let prev = corrections.range(..=timestamp_us).next_back();
+    let next = corrections.range(timestamp_us..).next();
+    match (prev, next) {
+        (Some((t1, q1)), Some((t2, q2))) if t2 > t1 => q1.slerp(q2, (timestamp_us - t1) as f64 / (t2 - t1) as f64),
+        (Some((_, q)), _) | (None, Some((_, q))) => *q,
+        (None, None) => Quat64::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imu(accl: impl Fn(f64) -> [f64; 3]) -> Vec<TimeIMU> {
+        (0..1000).map(|i| {
+            let t = i as f64 * 5.0;
+            TimeIMU { timestamp_ms: t, gyro: Some([0.0, 0.0, 0.0]), accl: Some(accl(t)), magn: None }
+        }).collect()
+    }
+
+    #[test]
+    fn static_camera_has_reference() {
+        let reference = estimate(&imu(|_| [0.0, 0.0, GRAVITY]), &Default::default());
+        assert_eq!(reference.len(), 10);
+        assert!(reference.iter().all(|x| x.confidence > 0.99 && (x.gravity[2] - 1.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn high_g_has_no_reference() {
+        let reference = estimate(&imu(|t| [0.0, 3.0 * GRAVITY * (t / 50.0).sin(), 2.0 * GRAVITY]), &Default::default());
+        assert!(reference.is_empty());
+    }
+}
@@ -12,16 +12,24 @@ pub struct IMUTransforms {
     pub imu_rotation: Option<Rotation3<f64>>,
     pub acc_rotation: Option<Rotation3<f64>>,
     pub imu_lpf: f64,
+    pub acc_lpf: f64,
     pub imu_mf: i32,
     pub gyro_bias: Option<[f64; 3]>,
 }
 impl IMUTransforms {
     pub fn transform(&self, v: &mut [f64; 3], is_acc: bool) {
+        self.transform_bias(v);
+        self.transform_axes(v, is_acc);
+    }
+    pub fn transform_bias(&self, v: &mut [f64; 3]) {
         if let Some(bias) = self.gyro_bias {    // None
             v[0] += bias[0];
             v[1] += bias[1];
             v[2] += bias[2];
         }
+    }
+    /// Orientation and rotation only
+    pub fn transform_axes(&self, v: &mut [f64; 3], is_acc: bool) {
         if let Some(ref orientation) = self.imu_orientation {
             if orientation != "XYZ" {
                 *v = Self::orient(v, orientation.as_bytes());
@@ -34,12 +42,36 @@ impl IMUTransforms {
         }
     }
 
+    // The median filter doesn't commute with rotations, so the rotations have to be applied before filtering
+    pub fn rotate_before_filtering(&self) -> bool {
+        self.imu_mf > 0 && (self.imu_rotation.is_some() || self.acc_rotation.is_some())
+    }
+
+    pub fn filters_key(&self) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        if let Some(v) = &self.gyro_bias { hasher.write_u64(v[0].to_bits()); hasher.write_u64(v[1].to_bits()); hasher.write_u64(v[2].to_bits()); }
+        hasher.write_u64(self.imu_lpf.to_bits());
+        hasher.write_u64(self.acc_lpf.to_bits());
+        hasher.write_i32(self.imu_mf);
+        hasher.finish()
+    }
+    pub fn axes_key(&self) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        if let Some(v) = &self.imu_orientation { hasher.write(v.as_bytes()); }
+        if let Some(v) = &self.imu_rotation_angles { hasher.write_u64(v[0].to_bits()); hasher.write_u64(v[1].to_bits()); hasher.write_u64(v[2].to_bits()); }
+        if let Some(v) = &self.acc_rotation_angles { hasher.write_u64(v[0].to_bits()); hasher.write_u64(v[1].to_bits()); hasher.write_u64(v[2].to_bits()); }
+        hasher.finish()
+    }
+
     pub fn has_any(&self) -> bool {
         self.imu_orientation.as_deref().is_some_and(|x| x != "XYZ")
             || self.imu_rotation.is_some()
             || self.acc_rotation.is_some()
             || self.gyro_bias.is_some_and(|x| x[0].abs() > 0.0 || x[1].abs() > 0.0 || x[2].abs() > 0.0)
             || self.imu_lpf > 0.0
+            || self.acc_lpf > 0.0
             || self.imu_mf > 0
     }
 
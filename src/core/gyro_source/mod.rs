@@ -1,19 +1,40 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
+mod append;
+mod bias;
+pub mod blackbox;
 mod file_metadata;
+mod gaps;
+pub mod gps;
+pub mod gravity_reference;
+pub mod gcsv;
 mod imu_transforms;
+mod offset_model;
+mod progress_reader;
+pub mod orientation_presets;
+mod secondary;
 mod sony;
 pub mod splines;
+mod time_mapping;
+mod uniform_quats;
+pub use append::GyroSegment;
+pub use bias::*;
 pub use file_metadata::*;
+pub use gaps::{ GapRepair, SampleRateAnalysis };
+pub use gcsv::{ GcsvData, GcsvExportOptions };
 pub use imu_transforms::*;
+pub use offset_model::{ OffsetInterpolation, DriftFit, interpolate as interpolate_offset };
+pub use secondary::{ GyroStream, SecondaryGyro };
 pub use sony::interpolate_mesh;
+pub use time_mapping::TimeMapping;
+pub use uniform_quats::UniformQuats;
 
 use nalgebra::*;
 use std::iter::zip;
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
-use std::sync::{ Arc, atomic::AtomicBool };
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::SeqCst } };
 use parking_lot::RwLock;
 use telemetry_parser::{ Input, util };
 use telemetry_parser::tags_impl::{ GetWithType, GroupId, TagId, TimeQuaternion, TimeVector3 };
@@ -27,6 +48,10 @@ use super::smoothing::SmoothingAlgorithm;
 use crate::StabilizationParams;
 
 const DEG2RAD: f64 = std::f64::consts::PI / 180.0;
+const GRAVITY: f64 = 9.81; // m/s², same as the telemetry parser uses for the accelerometer readings in g
+
+// Sync points are usually computed from about a second of data around their timestamp
+const SYNC_POINT_HALF_WINDOW_MS: f64 = 500.0;
 
 pub type Quat64 = UnitQuaternion<f64>;
 pub type TimeIMU = telemetry_parser::util::IMUData;
@@ -35,7 +60,19 @@ pub type TimeVec = BTreeMap<i64, Vector3<f64>>; // key is timestamp_us
 
 #[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct FileLoadOptions {
-    pub sample_index: Option<usize>
+    pub sample_index: Option<usize>, // Also the log index in blackbox files
+    #[serde(default)]
+    pub blackbox_gyro: blackbox::BlackboxGyro,
+}
+
+// Intermediate results of `apply_transforms`, so only the stages that actually changed are recomputed
+#[derive(Default, Clone)]
+struct TransformsCache {
+    analysis_key: Option<u64>,
+    filters_key: Option<u64>,
+    filtered_imu: Arc<Vec<TimeIMU>>, // Bias and filters applied, in sensor axes unless the rotation had to be applied before filtering
+    axes_key: Option<u64>,
+    integration_key: Option<u64>,
 }
 
 #[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
@@ -48,14 +85,27 @@ pub struct GyroSource {
 
     pub imu_transforms: IMUTransforms,
 
-    pub integration_method: usize,
+    pub detected_bias: Option<StaticBiasEstimate>, // Found automatically on load, applied only when the user accepts it
+
+    pub sample_rate_analysis: Option<SampleRateAnalysis>, // Updated every time the motion data changes
+    pub gap_repair: GapRepair,
+
+    pub integration_method: IntegrationMethod,
+
+    pub magnetometer: magnetometer::MagnetometerParams,
+    pub magnetometer_calibration: Option<magnetometer::MagnetometerCalibration>, // Fitted on every integration when the correction is enabled
 
     pub quaternions: TimeQuat,
     pub smoothed_quaternions: TimeQuat,
 
+    pub quaternion_resample_rate: f64, // Hz, 0 = disabled
+
     pub use_gravity_vectors: bool,
     pub horizon_lock_integration_method: i32,
 
+    pub gravity_reference_params: gravity_reference::GravityReferenceParams,
+    pub gravity_reference: Vec<gravity_reference::GravitySample>, // From the accelerometer, updated on every integration
+
     pub max_angles: (f64, f64, f64), // (pitch, yaw, roll) in deg
 
     pub smoothing_status: serde_json::Value,
@@ -64,19 +114,39 @@ pub struct GyroSource {
 
     pub file_metadata: ReadOnlyFileMetadata, // Once this is set, it's never modified
 
+    pub segments: Vec<GyroSegment>, // Set when motion data from multiple files was joined with `append`
+    pub hold_orientation_in_gaps: bool,
+
+    pub time_mapping: TimeMapping, // video time -> gyro time, applied before the sync offsets
+
+    pub secondary_gyro: Option<SecondaryGyro>,
+    pub gyro_stream: GyroStream, // Which stream drives the stabilization, `file_metadata` contains the raw data of this stream
+
     offsets: BTreeMap<i64, f64>, // <microseconds timestamp, offset in milliseconds>
     offsets_linear: BTreeMap<i64, f64>, // <microseconds timestamp, offset in milliseconds> - linear fit
     offsets_adjusted: BTreeMap<i64, f64>, // <timestamp + offset, offset>
-
-    pub file_url: String
+    pub offset_interpolation: OffsetInterpolation,
+    drift_fit: Option<DriftFit>, // Of the offsets used for fitting, updated with the offsets
+
+    pub file_url: String,
+
+    #[serde(skip)]
+    transforms_cache: TransformsCache,
+    #[serde(skip)]
+    resampled_quaternions: Option<UniformQuats>,
+    #[serde(skip)]
+    resampled_smoothed_quaternions: Option<UniformQuats>,
+    #[serde(skip)]
+    primary_metadata: Option<ReadOnlyFileMetadata>, // Set when a stream other than the primary is selected
 }
 
 impl GyroSource {
     pub fn new() -> Self {
         Self {
-            integration_method: 2, // VQF
+            integration_method: IntegrationMethod::VQF,
             use_gravity_vectors: false,
             horizon_lock_integration_method: 1, // VQF
+            quaternion_resample_rate: 1000.0,
             ..Default::default()
         }
     }
@@ -101,6 +171,17 @@ impl GyroSource {
         self.horizon_lock_integration_method = v;
     }
 
+    /// Sets the low-pass cutoffs (in Hz, 0 = disabled) for gyro and accelerometer and re-integrates if they changed
+    pub fn set_imu_lpf(&mut self, gyro_hz: f64, accl_hz: f64) -> bool {
+        if self.imu_transforms.imu_lpf != gyro_hz || self.imu_transforms.acc_lpf != accl_hz {
+            self.imu_transforms.imu_lpf = gyro_hz;
+            self.imu_transforms.acc_lpf = accl_hz;
+            self.apply_transforms();
+            return true;
+        }
+        false
+    }
+
     pub fn init_from_params(&mut self, stabilization_params: &StabilizationParams) {
         self.duration_ms = stabilization_params.get_scaled_duration_ms();
     }
@@ -114,11 +195,34 @@ impl GyroSource {
                 return Ok(md.clone());
             }
         }
+        if blackbox::is_blackbox(&filesystem::get_filename(url)) {
+            let base = filesystem::get_engine_base();
+            let mut file = filesystem::open_file(&base, url, false, false)?;
+            let size = file.size;
+            let reader = progress_reader::ProgressReader::new(file.get_file(), size, &progress_cb, cancel_flag.clone());
+            match blackbox::parse(std::io::BufReader::new(reader), options.sample_index, options.blackbox_gyro) {
+                Ok(md) => return Ok(md),
+                Err(_) if cancel_flag.load(SeqCst) => return Err(crate::GyroflowCoreError::Cancelled),
+                Err(e) => log::warn!("Failed to decode the blackbox log, trying telemetry-parser: {e:?}")
+            }
+        }
+        if filesystem::get_filename(url).to_ascii_lowercase().ends_with(".gcsv") {
+            let base = filesystem::get_engine_base();
+            let mut file = filesystem::open_file(&base, url, false, false)?;
+            let size = file.size;
+            let mut contents = String::new();
+            let read = std::io::Read::read_to_string(&mut progress_reader::ProgressReader::new(file.get_file(), size, &progress_cb, cancel_flag.clone()), &mut contents);
+            if cancel_flag.load(SeqCst) { return Err(crate::GyroflowCoreError::Cancelled); }
+            if read.is_ok() && gcsv::is_quaternion_gcsv(&contents) {
+                return gcsv::parse_gcsv(&contents);
+            }
+        }
         // "file:///home/chg/Downloads/GoPro%20Hero%206.MP4"
         let base = filesystem::get_engine_base();
         let mut file = filesystem::open_file(&base, url, false, false)?;
         let filesize = file.size;
-        let mut input = Input::from_stream(file.get_file(), filesize, &url, progress_cb, cancel_flag)?;
+        let mut input = Input::from_stream(file.get_file(), filesize, &url, &progress_cb, cancel_flag.clone())
+            .map_err(|e| if cancel_flag.load(SeqCst) { crate::GyroflowCoreError::Cancelled } else { e.into() })?;
 
         let camera_identifier = CameraIdentifier::from_telemetry_parser(&input, size.0, size.1, fps).ok();
 
@@ -133,7 +237,9 @@ impl GyroSource {
         let mut frame_rate = None;
         let mut digital_zoom = None;
         let mut lens_positions = BTreeMap::new();
+        let mut focal_lengths = BTreeMap::new();
         let mut lens_params = BTreeMap::new();
+        let mut gps = Vec::new();
         let mut additional_data = serde_json::Value::Object(serde_json::Map::new());
 
         // "GoPro HERO6 Black"
@@ -165,6 +271,7 @@ impl GyroSource {
             for info in samples {
                 let timestamp_us = (info.timestamp_ms * 1000.0).round() as i64;
                 if let Some(ref tag_map) = info.tag_map {
+                    gps::collect(tag_map, info, &mut gps);
                     if let Some(map) = tag_map.get(&GroupId::Quaternion) {
                         if let Some(arr) = map.get_t(TagId::Data) as Option<&Vec<TimeQuaternion<f64>>> {
                             for v in arr {
@@ -194,6 +301,9 @@ impl GyroSource {
                         if let Some(v) = map.get_t(TagId::FocalLength) as Option<&f32> {
                             lens_positions.insert(timestamp_us, *v as f64);
                             lens_info.focal_length = Some(*v);
+                            if focal_lengths.values().next_back() != Some(&(*v as f64)) {
+                                focal_lengths.insert(timestamp_us, *v as f64);
+                            }
                         }
                     }
                     if lens_info.focal_length.is_none() {
@@ -352,6 +462,7 @@ impl GyroSource {
             gravity_vectors,
             lens_positions,
             lens_params,
+            focal_lengths,
             raw_imu,
             frame_readout_time: if fr != 0.0 { Some(if fr.abs() > 10000.0 { fr.abs() - 10000.0 } else { fr.abs() }) } else { None },
             frame_readout_direction: if fr < 0.0 {
@@ -368,6 +479,7 @@ impl GyroSource {
             digital_zoom,
             camera_stab_data: Vec::new(),
             mesh_correction:  Vec::new(),
+            gps,
         };
 
         let sample_rate = Self::get_sample_rate(&md);
@@ -442,6 +554,9 @@ impl GyroSource {
             }
         }
 
+        if cancel_flag.load(SeqCst) { // Partial data
+            return Err(crate::GyroflowCoreError::Cancelled);
+        }
         #[cfg(feature = "cache-gyro-metadata")]
         {
             let mut cache = CACHE.write();
@@ -458,20 +573,44 @@ impl GyroSource {
         self.imu_transforms.imu_rotation = None;
         self.imu_transforms.acc_rotation = None;
         self.imu_transforms.imu_lpf = 0.0;
+        self.imu_transforms.acc_lpf = 0.0;
         self.imu_transforms.imu_mf = 0;
+        self.detected_bias = None;
+        self.sample_rate_analysis = None;
+        self.gravity_reference.clear();
+        self.segments.clear();
+        self.secondary_gyro = None;
+        self.gyro_stream = GyroStream::Primary;
+        self.primary_metadata = None;
+        self.transforms_cache = Default::default();
+        self.resampled_quaternions = None;
+        self.resampled_smoothed_quaternions = None;
         self.file_metadata = Default::default();
         self.clear_offsets();
     }
 
     pub fn load_from_telemetry(&mut self, telemetry: FileMetadata) {
+        self.load_from_telemetry_with_progress(telemetry, |_| (), &AtomicBool::new(false));
+    }
+    /// Returns `false` if it was cancelled, the source is then partially loaded and should be discarded.
+    /// The cancellation is checked between the processing steps, the integration itself isn't interrupted
+    pub fn load_from_telemetry_with_progress<F: Fn(f64)>(&mut self, telemetry: FileMetadata, progress_cb: F, cancel_flag: &AtomicBool) -> bool {
         if self.duration_ms <= 0.0 {
             ::log::error!("Invalid duration_ms {}", self.duration_ms);
-            return;
+            return true;
         }
 
         self.clear();
 
         self.imu_transforms.imu_orientation = telemetry.imu_orientation.clone();    // "ZyX"
+        if self.imu_transforms.imu_orientation.is_none() {
+            if let Some(id) = &telemetry.camera_identifier {
+                self.imu_transforms.imu_orientation = orientation_presets::lookup_for_camera(id, &orientation_presets::firmware_from_metadata(&telemetry));
+                if let Some(v) = &self.imu_transforms.imu_orientation {
+                    log::info!("Using IMU orientation preset {v} for {} {}", id.brand, id.model);
+                }
+            }
+        }
 
         let has_quats = !telemetry.quaternions.is_empty();  // false
         let has_raw_imu = !telemetry.raw_imu.is_empty();    // true
@@ -481,7 +620,8 @@ impl GyroSource {
         if has_quats {
             let file_metadata = self.file_metadata.read();
             self.quaternions = file_metadata.quaternions.clone();
-            self.integration_method = 0;
+            self.resampled_quaternions = UniformQuats::build(&self.quaternions, self.quaternion_resample_rate);
+            self.integration_method = IntegrationMethod::FileQuaternions;
             let len = file_metadata.quaternions.len() as f64;
             let first_ts = file_metadata.quaternions.iter().next()      .map(|x| *x.0 as f64 / 1000.0).unwrap_or_default();
             let last_ts  = file_metadata.quaternions.iter().next_back() .map(|x| *x.0 as f64 / 1000.0).unwrap_or_default();
@@ -508,16 +648,26 @@ impl GyroSource {
                     }
                 }
             }
+            progress_cb(0.1);
+            if cancel_flag.load(SeqCst) { return false; }
+            self.detected_bias = self.detect_static_bias(&StaticBiasParams::default());
+            if let Some(ref b) = self.detected_bias {
+                log::info!("Detected {} static segments, gyro bias: {:?}", b.segments.len(), b.bias);
+            }
+            progress_cb(0.3);
+            if cancel_flag.load(SeqCst) { return false; }
             // mainly handles the imu orientation, and integrate.
             self.apply_transforms();
         } else if self.quaternions.is_empty() {
             self.integrate();
         }
+        progress_cb(1.0);
+        !cancel_flag.load(SeqCst)
     }
     pub fn integrate(&mut self) {
         let file_metadata = self.file_metadata.read();
         match self.integration_method {
-            0 => {
+            IntegrationMethod::FileQuaternions => {
                 self.quaternions = if file_metadata.detected_source.as_deref().unwrap_or("").starts_with("GoPro") && !file_metadata.quaternions.is_empty() && (file_metadata.gravity_vectors.is_none() || !self.use_gravity_vectors) {
                     log::info!("No gravity vectors - using accelerometer");
                     QuaternionConverter::convert(self.horizon_lock_integration_method, &file_metadata.quaternions, file_metadata.image_orientations.as_ref().unwrap_or(&TimeQuat::default()), self.raw_imu(&file_metadata), self.duration_ms)
@@ -536,17 +686,122 @@ impl GyroSource {
                     }
                 }
             },
-            1 => self.quaternions = ComplementaryIntegrator  ::integrate(self.raw_imu(&file_metadata), self.duration_ms),
-            2 => self.quaternions = VQFIntegrator            ::integrate(self.raw_imu(&file_metadata), self.duration_ms),   // default integration method
-            3 => self.quaternions = SimpleGyroIntegrator     ::integrate(self.raw_imu(&file_metadata), self.duration_ms),
-            4 => self.quaternions = SimpleGyroAccelIntegrator::integrate(self.raw_imu(&file_metadata), self.duration_ms),
-            5 => self.quaternions = MahonyIntegrator         ::integrate(self.raw_imu(&file_metadata), self.duration_ms),
-            6 => self.quaternions = MadgwickIntegrator       ::integrate(self.raw_imu(&file_metadata), self.duration_ms),
-            _ => log::error!("Unknown integrator")
+            method => {
+                let imu_data = if !self.raw_imu.is_empty() { &self.raw_imu } else { &file_metadata.raw_imu };
+                self.quaternions = method.integrate(imu_data, self.duration_ms);
+
+                self.magnetometer_calibration = None;
+                if self.magnetometer.enabled && method.uses_accelerometer() {
+                    self.magnetometer_calibration = magnetometer::MagnetometerCalibration::fit(imu_data);
+                    match &self.magnetometer_calibration {
+                        Some(calib) => magnetometer::correct_yaw(&mut self.quaternions, imu_data, calib, &self.magnetometer),
+                        None => log::warn!("Not enough magnetometer data for calibration, heading correction disabled")
+                    }
+                }
+            }
         }
+        self.transforms_cache.integration_key = Some(self.integration_key());
+        self.resampled_quaternions = UniformQuats::build(&self.quaternions, self.quaternion_resample_rate);
+        self.gravity_reference = gravity_reference::estimate(self.raw_imu(&file_metadata), &self.gravity_reference_params);
     }
 
-    pub fn recompute_smoothness(&self, alg: &dyn SmoothingAlgorithm, horizon_lock: super::smoothing::horizon::HorizonLock, compute_params: &crate::ComputeParams) -> (TimeQuat, (f64, f64, f64)) {
+    pub fn set_smoothed_quaternions(&mut self, quats: TimeQuat) {
+        self.smoothed_quaternions = quats;
+        self.resampled_smoothed_quaternions = UniformQuats::build(&self.smoothed_quaternions, self.quaternion_resample_rate);
+    }
+
+    /// Rebuilds the uniformly resampled copies of both quaternion maps, eg. after the maps or `quaternion_resample_rate` were modified directly
+    pub fn resample_quaternions(&mut self) {
+        self.resampled_quaternions = UniformQuats::build(&self.quaternions, self.quaternion_resample_rate);
+        self.resampled_smoothed_quaternions = UniformQuats::build(&self.smoothed_quaternions, self.quaternion_resample_rate);
+    }
+    /// Resampled raw orientations, if they are up to date
+    pub fn resampled_quaternions(&self) -> Option<&UniformQuats> {
+        self.resampled_quaternions.as_ref().filter(|x| x.matches(&self.quaternions))
+    }
+
+    // The metadata can be replaced or modified in place without changing the allocation, its generation changes either way
+    fn data_key(&self) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(self.file_metadata.generation());
+        hasher.write_u64(self.duration_ms.to_bits());
+        hasher.finish()
+    }
+    fn filters_key(data_key: u64, t: &IMUTransforms, gap_repair: Option<GapRepair>) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(data_key);
+        hasher.write_u64(t.filters_key());
+        if let Some(v) = gap_repair { hasher.write_u8(v as u8); }
+        if t.rotate_before_filtering() { hasher.write_u64(t.axes_key()); }
+        hasher.finish()
+    }
+    // Gap repair, bias and the filters. The orientation and rotation too if they have to be applied before filtering
+    fn filter_imu(raw_imu: &[TimeIMU], gaps: Option<(&SampleRateAnalysis, GapRepair)>, t: &IMUTransforms, duration_ms: f64) -> Vec<TimeIMU> {
+        let rotate_first = t.rotate_before_filtering();
+        let mut filtered = match gaps {
+            Some((analysis, method)) => gaps::repair(raw_imu, analysis, method),
+            None => raw_imu.to_vec()
+        };
+        for x in filtered.iter_mut() {
+            if let Some(g) = x.gyro.as_mut() { if rotate_first { t.transform(g, false) } else { t.transform_bias(g) } }
+            if let Some(a) = x.accl.as_mut() { if rotate_first { t.transform(a, true)  } else { t.transform_bias(a) } }
+            if let Some(m) = x.magn.as_mut() { if rotate_first { t.transform(m, false) } else { t.transform_bias(m) } }
+        }
+        if (t.imu_lpf > 0.0 || t.acc_lpf > 0.0) && !filtered.is_empty() {
+            if let Err(e) = super::filtering::Lowpass::filter_imu_forward_backward(t.imu_lpf, t.acc_lpf, &mut filtered) {
+                log::error!("Filter error {:?}", e);
+            }
+        }
+        if t.imu_mf > 0 && !filtered.is_empty() && duration_ms > 0.0 {
+            let sample_rate = filtered.len() as f64 / (duration_ms / 1000.0);
+            super::filtering::Median::filter_gyro_forward_backward(t.imu_mf, sample_rate, &mut filtered);
+        }
+        filtered
+    }
+    fn transform_imu_axes(imu: &mut [TimeIMU], t: &IMUTransforms) {
+        for x in imu.iter_mut() {
+            if let Some(g) = x.gyro.as_mut() { t.transform_axes(g, false); }
+            if let Some(a) = x.accl.as_mut() { t.transform_axes(a, true); }
+            if let Some(m) = x.magn.as_mut() { t.transform_axes(m, false); }
+        }
+    }
+    fn integration_key(&self) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(self.data_key());
+        hasher.write_u64(self.imu_transforms.filters_key());
+        hasher.write_u64(self.imu_transforms.axes_key());
+        hasher.write_u8(self.imu_transforms.has_any() as u8);
+        hasher.write_u8(self.gap_repair as u8);
+        if let Ok(v) = bincode::serialize(&self.integration_method) { hasher.write(&v); }
+        if let Ok(v) = bincode::serialize(&self.magnetometer) { hasher.write(&v); }
+        hasher.write_u8(self.use_gravity_vectors as u8);
+        hasher.write_i32(self.horizon_lock_integration_method);
+        hasher.finish()
+    }
+
+    pub fn set_magnetometer_params(&mut self, params: magnetometer::MagnetometerParams) -> bool {
+        if self.magnetometer != params {
+            self.magnetometer = params;
+            self.integrate();
+            return true;
+        }
+        false
+    }
+
+    /// Switches the integration method and re-generates `quaternions` if it changed
+    pub fn set_integration_method(&mut self, method: IntegrationMethod) -> bool {
+        if self.integration_method != method {
+            self.integration_method = method;
+            self.integrate();
+            return true;
+        }
+        false
+    }
+
+    pub fn recompute_smoothness(&self, alg: &dyn SmoothingAlgorithm, horizon_lock: super::smoothing::horizon::HorizonLock, axis_lock: &super::smoothing::axis_lock::AxisLock, compute_params: &crate::ComputeParams) -> (TimeQuat, (f64, f64, f64)) {
         let file_metadata = self.file_metadata.read();
         let mut smoothed_quaternions = self.quaternions.clone();
 
@@ -565,6 +820,8 @@ impl GyroSource {
             *q *= additional_rotation;
         }
 
+        let params = super::smoothing::ParameterLookup::from_keyframes(alg, &compute_params.keyframes);
+
         if true {
             // Lock horizon, then smooth
             // log::info!("Lock horizon:{}, then smooth: {}", horizon_lock.lock_enabled, alg.get_name());
@@ -573,13 +830,17 @@ impl GyroSource {
             // log::info!("compute_params fovs: {:?}", compute_params.fovs);
             // log::info!("compute_params minimal_fovs: {:?}", compute_params.minimal_fovs);
             // horizon_lock.lock(&mut smoothed_quaternions, &self.quaternions, &file_metadata.gravity_vectors, self.use_gravity_vectors, self.integration_method, compute_params);
-            smoothed_quaternions = alg.smooth(&smoothed_quaternions, self.duration_ms, compute_params);
+            smoothed_quaternions = alg.smooth(&smoothed_quaternions, self.duration_ms, &params, compute_params);
             // log::info!("post quaternions: len = {},  post 10: {:?}", smoothed_quaternions.len(), smoothed_quaternions.iter().take(10).collect::<Vec<_>>());
         } else {
             // Smooth, then lock horizon
-            smoothed_quaternions = alg.smooth(&smoothed_quaternions, self.duration_ms, compute_params);
-            horizon_lock.lock(&mut smoothed_quaternions, &self.quaternions, &file_metadata.gravity_vectors, self.use_gravity_vectors, self.integration_method, compute_params);
+            smoothed_quaternions = alg.smooth(&smoothed_quaternions, self.duration_ms, &params, compute_params);
+            horizon_lock.lock(&mut smoothed_quaternions, &self.quaternions, &file_metadata.gravity_vectors, self.use_gravity_vectors, &self.gravity_reference, self.integration_method.index(), compute_params);
         }
+        if compute_params.cancel_token.is_cancelled() {
+            return (smoothed_quaternions, Default::default()); // Discarded by the caller anyway
+        }
+        axis_lock.apply(&mut smoothed_quaternions, compute_params);
 
         let max_angles = crate::Smoothing::get_max_angles(&self.quaternions, &smoothed_quaternions, compute_params);
 
@@ -613,10 +874,23 @@ impl GyroSource {
     pub fn clear_offsets(&mut self) {
         self.offsets.clear();
         self.offsets_adjusted.clear();
+        self.drift_fit = None;
     }
     pub fn get_offsets(&self) -> &BTreeMap<i64, f64> {
         &self.offsets
     }
+    /// Linear fit of the offsets with the clock drift and the residuals, `None` with fewer than two offsets
+    pub fn get_drift_fit(&self) -> Option<&DriftFit> {
+        self.drift_fit.as_ref()
+    }
+    pub fn set_offset_interpolation(&mut self, v: OffsetInterpolation) -> bool {
+        if self.offset_interpolation != v {
+            self.offset_interpolation = v;
+            self.adjust_offsets();
+            return true;
+        }
+        false
+    }
     pub fn get_offsets_plus_linear(&self) -> BTreeMap<i64, (f64, f64)> {
         self.offsets.iter().map(|(k, v)| (*k, (*v, self.offsets_linear.get(k).copied().unwrap_or(*v)))).collect()
     }
@@ -647,13 +921,27 @@ impl GyroSource {
         }
     }
 
+    // Offsets computed over data with missing samples are unreliable, so they are left out of the line fit if there are enough other ones
+    fn offsets_for_fitting(&self) -> BTreeMap<i64, f64> {
+        let Some(analysis) = self.sample_rate_analysis.as_ref().filter(|x| !x.gaps.is_empty()) else { return self.offsets.clone(); };
+        let valid = self.offsets.iter().filter(|(k, v)| {
+            let gyro_ts = self.time_mapping.video_to_gyro(**k as f64 / 1000.0 - **v);
+            !analysis.overlaps_gap(gyro_ts - SYNC_POINT_HALF_WINDOW_MS, gyro_ts + SYNC_POINT_HALF_WINDOW_MS)
+        }).map(|(k, v)| (*k, *v)).collect::<BTreeMap<i64, f64>>();
+        if valid.len() < self.offsets.len() {
+            log::info!("Ignoring {} sync points overlapping gaps in the motion data", self.offsets.len() - valid.len());
+        }
+        if valid.len() > 1 { valid } else { self.offsets.clone() }
+    }
+
     pub fn adjust_offsets(&mut self) {
         if self.prevent_recompute { return; }
         log::info!("gyro_source::adjust_offsets, offsets: {:?}", self.offsets);
+        let offsets = self.offsets_for_fitting();
         // Calculate line fit
-        if self.offsets.len() > 1 {
-            let len = self.offsets.len();
-            let keys: Vec<i64> = self.offsets.keys().copied().collect();
+        if offsets.len() > 1 {
+            let len = offsets.len();
+            let keys: Vec<i64> = offsets.keys().copied().collect();
 
             #[derive(Default)]
             struct Params {
@@ -669,13 +957,13 @@ impl GyroSource {
                 for j in 0..len {
                     if i != j {
                         // 选取两点拟合直线
-                        let i_offset = self.offsets.get(&keys[i]).unwrap();
-                        let j_offset = self.offsets.get(&keys[j]).unwrap();
+                        let i_offset = offsets.get(&keys[i]).unwrap();
+                        let j_offset = offsets.get(&keys[j]).unwrap();
                         let slope = (j_offset - i_offset) / (keys[j] - keys[i]) as f64;
                         let intersect = i_offset - keys[i] as f64 * slope;
 
                         // 计算所有点与拟合直线的误差，并保留误差小于5ms的点
-                        let within_error: BTreeMap<i64, f64> = self.offsets.iter().filter_map(|(k, v)| {
+                        let within_error: BTreeMap<i64, f64> = offsets.iter().filter_map(|(k, v)| {
                             if ((*k as f64 * slope + intersect) - *v).abs() < max_fitting_error {
                                 Some((*k, *v))
                             } else {
@@ -716,7 +1004,7 @@ impl GyroSource {
                     self.offsets_linear.insert(*k, fitted);
                 }
             } else {
-                if let Some(solution) = Self::line_fit(&self.offsets) {
+                if let Some(solution) = Self::line_fit(&offsets) {
                     for (k, _) in &self.offsets {
                         let fitted = *k as f64 * solution[0] + solution[1];
                         self.offsets_linear.insert(*k, fitted);
@@ -727,6 +1015,15 @@ impl GyroSource {
             self.offsets_linear = self.offsets.clone();
         }
 
+        self.drift_fit = DriftFit::fit(&offsets, &self.offsets);
+        if let Some(fit) = &self.drift_fit {
+            log::info!("gyro_source::adjust_offsets, clock drift: {:.2} ppm, residuals RMS: {:.3} ms, max: {:.3} ms", fit.drift_ppm, fit.rms_ms, fit.max_ms);
+            if self.offset_interpolation == OffsetInterpolation::DriftFit {
+                // Show the line which is used
+                self.offsets_linear = self.offsets.keys().map(|k| (*k, fit.slope * *k as f64 + fit.intercept)).collect();
+            }
+        }
+
         log::info!("gyro_source::offsets_adjusted, offsets_linear: {:?}", self.offsets_linear);
 
         self.offsets_adjusted = self.offsets.iter().map(|(k, v)| (*k + (*v * 1000.0).round() as i64, *v)).collect::<BTreeMap<i64, f64>>();
@@ -734,44 +1031,97 @@ impl GyroSource {
     }
 
     pub fn apply_transforms(&mut self) {
+        let data_key = self.data_key();
         let file_metadata = self.file_metadata.read();
 
-        if self.imu_transforms.has_any() {
-            self.raw_imu = file_metadata.raw_imu.clone();
-            for x in self.raw_imu.iter_mut() {
-                if let Some(g) = x.gyro.as_mut() {
-                    self.imu_transforms.transform(g, false);
-                }
-                if let Some(a) = x.accl.as_mut() {
-                    self.imu_transforms.transform(a, true);
-                }
-                if let Some(m) = x.magn.as_mut() {
-                    self.imu_transforms.transform(m, false);
-                }
+        if self.transforms_cache.analysis_key != Some(data_key) {
+            self.sample_rate_analysis = SampleRateAnalysis::analyze(&file_metadata.raw_imu, gaps::GAP_FACTOR);
+            self.transforms_cache.analysis_key = Some(data_key);
+        }
+        let repair_gaps = self.gap_repair != GapRepair::None && self.sample_rate_analysis.as_ref().is_some_and(|x| !x.gaps.is_empty());
+
+        if self.imu_transforms.has_any() || repair_gaps {
+            let t = &self.imu_transforms;
+            let gaps = self.sample_rate_analysis.as_ref().filter(|_| repair_gaps).map(|x| (x, self.gap_repair));
+
+            let filters_key = Self::filters_key(data_key, t, gaps.map(|x| x.1));
+            if self.transforms_cache.filters_key != Some(filters_key) {
+                let filtered = Self::filter_imu(&file_metadata.raw_imu, gaps, t, self.duration_ms);
+                self.transforms_cache.filtered_imu = Arc::new(filtered);
+                self.transforms_cache.filters_key = Some(filters_key);
+                self.transforms_cache.axes_key = None;
             }
-            if self.imu_transforms.imu_lpf > 0.0 && !file_metadata.raw_imu.is_empty() && self.duration_ms > 0.0 {
-                let sample_rate = file_metadata.raw_imu.len() as f64 / (self.duration_ms / 1000.0);
-                if let Err(e) = super::filtering::Lowpass::filter_gyro_forward_backward(self.imu_transforms.imu_lpf, sample_rate, &mut self.raw_imu) {
-                    log::error!("Filter error {:?}", e);
+
+            // Orientation and rotation are linear per-sample remaps, so they are cheap to apply on the filtered data
+            let axes_key = filters_key ^ t.axes_key();
+            if self.transforms_cache.axes_key != Some(axes_key) || self.raw_imu.is_empty() {
+                self.raw_imu = (*self.transforms_cache.filtered_imu).clone();
+                if !t.rotate_before_filtering() {
+                    Self::transform_imu_axes(&mut self.raw_imu, t);
                 }
-            }
-            if self.imu_transforms.imu_mf > 0 && !file_metadata.raw_imu.is_empty() && self.duration_ms > 0.0 {
-                let sample_rate = file_metadata.raw_imu.len() as f64 / (self.duration_ms / 1000.0);
-                super::filtering::Median::filter_gyro_forward_backward(self.imu_transforms.imu_mf, sample_rate, &mut self.raw_imu);
+                self.transforms_cache.axes_key = Some(axes_key);
             }
         } else {
             self.raw_imu.clear();
+            self.transforms_cache.filters_key = None;
+            self.transforms_cache.filtered_imu = Default::default();
+            self.transforms_cache.axes_key = None;
         }
 
+        let needs_integration = self.transforms_cache.integration_key != Some(self.integration_key()) || self.quaternions.is_empty();
         drop(file_metadata);
 
-        self.integrate();
+        if needs_integration {
+            self.integrate();
+        }
+    }
+
+    /// Lightweight alternative to changing `imu_orientation` and calling `apply_transforms`, used for guessing the orientation.
+    /// Same as `orientation_source().quats()`
+    pub fn quats_for_orientation(&self, orientation: &str, ranges_ms: Option<&[(f64, f64)]>) -> TimeQuat {
+        self.orientation_source().quats(orientation, ranges_ms)
     }
 
-    fn quat_at_timestamp(&self, quats: &TimeQuat, mut timestamp_ms: f64) -> Quat64 {
+    /// Snapshot of the motion data for integrating it with other IMU orientations, so the lock on the source doesn't have to be held meanwhile
+    pub fn orientation_source(&self) -> OrientationSource {
+        let data_key = self.data_key();
+        let file_metadata = self.file_metadata.read();
+        let analysis = if self.transforms_cache.analysis_key == Some(data_key) {
+            self.sample_rate_analysis.clone()
+        } else {
+            SampleRateAnalysis::analyze(&file_metadata.raw_imu, gaps::GAP_FACTOR)
+        };
+        let gaps = analysis.filter(|x| self.gap_repair != GapRepair::None && !x.gaps.is_empty()).map(|x| (x, self.gap_repair));
+
+        let t = &self.imu_transforms;
+        let (imu, prefiltered) = if t.rotate_before_filtering() {
+            // The filters have to run again for every orientation
+            (Arc::new(file_metadata.raw_imu.clone()), false)
+        } else if self.transforms_cache.filters_key == Some(Self::filters_key(data_key, t, gaps.as_ref().map(|x| x.1))) {
+            (self.transforms_cache.filtered_imu.clone(), true)
+        } else {
+            (Arc::new(Self::filter_imu(&file_metadata.raw_imu, gaps.as_ref().map(|x| (&x.0, x.1)), t, self.duration_ms)), true)
+        };
+        OrientationSource {
+            imu,
+            prefiltered,
+            gaps,
+            transforms: t.clone(),
+            integration_method: self.integration_method,
+            magnetometer: self.magnetometer,
+            duration_ms: self.duration_ms,
+        }
+    }
+
+    fn quat_at_timestamp(&self, quats: &TimeQuat, resampled: Option<&UniformQuats>, mut timestamp_ms: f64) -> Quat64 {
         if quats.len() < 2 || self.duration_ms <= 0.0 { return Quat64::identity(); }
 
-        timestamp_ms -= self.offset_at_video_timestamp(timestamp_ms);
+        // Resampled data is in gyro time, so the offsets and time mapping don't invalidate it
+        timestamp_ms = self.video_to_gyro_timestamp(timestamp_ms);
+
+        if let Some(resampled) = resampled.filter(|x| x.matches(quats)) {
+            return resampled.at(timestamp_ms * 1000.0);
+        }
 
         if let Some(&first_ts) = quats.keys().next() {
             if let Some(&last_ts) = quats.keys().next_back() {
@@ -792,8 +1142,8 @@ impl GyroSource {
         Quat64::identity()
     }
 
-    pub fn      org_quat_at_timestamp(&self, timestamp_ms: f64) -> Quat64 { self.quat_at_timestamp(&self.quaternions,          timestamp_ms) }
-    pub fn smoothed_quat_at_timestamp(&self, timestamp_ms: f64) -> Quat64 { self.quat_at_timestamp(&self.smoothed_quaternions, timestamp_ms) }
+    pub fn      org_quat_at_timestamp(&self, timestamp_ms: f64) -> Quat64 { self.quat_at_timestamp(&self.quaternions,          self.resampled_quaternions.as_ref(),          timestamp_ms) }
+    pub fn smoothed_quat_at_timestamp(&self, timestamp_ms: f64) -> Quat64 { self.quat_at_timestamp(&self.smoothed_quaternions, self.resampled_smoothed_quaternions.as_ref(), timestamp_ms) }
 
     pub fn offset_at_timestamp(offsets: &BTreeMap<i64, f64>, timestamp_ms: f64) -> f64 {
         match offsets.len() {
@@ -821,8 +1171,69 @@ impl GyroSource {
             }
         }
     }
-    pub fn offset_at_video_timestamp(&self, timestamp_ms: f64) -> f64 { Self::offset_at_timestamp(&self.offsets_adjusted, timestamp_ms) }
-    pub fn offset_at_gyro_timestamp (&self, timestamp_ms: f64) -> f64 { Self::offset_at_timestamp(&self.offsets, timestamp_ms) }
+    pub fn offset_at_video_timestamp(&self, timestamp_ms: f64) -> f64 { offset_model::interpolate(&self.offsets_adjusted, self.offset_interpolation, self.drift_fit.as_ref().map(DriftFit::adjusted_line), timestamp_ms) }
+    pub fn offset_at_gyro_timestamp (&self, timestamp_ms: f64) -> f64 { offset_model::interpolate(&self.offsets,          self.offset_interpolation, self.drift_fit.as_ref().map(DriftFit::line),          timestamp_ms) }
+    /// Interpolation of the offsets in gyro time, for lookups without access to the `GyroSource`, see `KeyframeManager::update_gyro`
+    pub fn offset_lookup(&self) -> (OffsetInterpolation, Option<(f64, f64)>) { (self.offset_interpolation, self.drift_fit.as_ref().map(DriftFit::line)) }
+
+    // Sync offsets are in video time, the time mapping is applied after them
+    pub fn video_to_gyro_timestamp(&self, timestamp_ms: f64) -> f64 {
+        self.time_mapping.video_to_gyro(timestamp_ms - self.offset_at_video_timestamp(timestamp_ms))
+    }
+    pub fn gyro_to_video_timestamp(&self, timestamp_ms: f64) -> f64 {
+        let ts = self.time_mapping.gyro_to_video(timestamp_ms);
+        ts + self.offset_at_gyro_timestamp(ts)
+    }
+
+    pub fn has_gps(&self) -> bool {
+        self.file_metadata.read().has_gps()
+    }
+    /// GPS position at the video timestamp, with the sync offsets and time mapping applied. `None` if there's no fix at that time
+    pub fn gps_at_video_timestamp(&self, timestamp_ms: f64) -> Option<gps::GpsSample> {
+        gps::interpolate(&self.file_metadata.read().gps, self.video_to_gyro_timestamp(timestamp_ms))
+    }
+
+    pub fn set_time_mapping(&mut self, mapping: TimeMapping) -> bool {
+        if self.time_mapping != mapping {
+            self.time_mapping = mapping;
+            return true;
+        }
+        false
+    }
+    /// Constant rate mapping from the capture frame rate stored in the file, for slow motion conformed to `video_fps`
+    pub fn time_mapping_from_metadata(&self, video_fps: f64) -> Option<TimeMapping> {
+        let capture_fps = self.file_metadata.read().frame_rate?;
+        TimeMapping::constant_rate(TimeMapping::rate_from_fps(capture_fps, video_fps)?)
+    }
+
+    /// Returns a copy with all motion data moved to video time, so the offsets found by sync are in video time as well
+    pub fn in_video_time(&self) -> Self {
+        let mut ret = self.clone();
+        if self.time_mapping.is_identity() { return ret; }
+
+        let mapping = &self.time_mapping;
+        let retime_imu = |imu: &[TimeIMU]| imu.iter().map(|x| {
+            let rate = mapping.rate_at_gyro(x.timestamp_ms);
+            TimeIMU {
+                timestamp_ms: mapping.gyro_to_video(x.timestamp_ms),
+                gyro: x.gyro.map(|g| [g[0] * rate, g[1] * rate, g[2] * rate]),
+                ..x.clone()
+            }
+        }).collect::<Vec<_>>();
+        let retime_quats = |quats: &TimeQuat| quats.iter().map(|(ts, q)| ((mapping.gyro_to_video(*ts as f64 / 1000.0) * 1000.0).round() as i64, *q)).collect::<TimeQuat>();
+
+        let mut md = self.file_metadata.read().clone();
+        md.raw_imu = retime_imu(&md.raw_imu);
+        md.quaternions = retime_quats(&md.quaternions);
+        for x in md.gps.iter_mut() { x.timestamp_ms = mapping.gyro_to_video(x.timestamp_ms); }
+        ret.file_metadata = md.into();
+        ret.raw_imu = retime_imu(&self.raw_imu);
+        ret.quaternions = retime_quats(&self.quaternions);
+        ret.smoothed_quaternions = retime_quats(&self.smoothed_quaternions);
+        ret.time_mapping = TimeMapping::default();
+        ret.resample_quaternions();
+        ret
+    }
 
     pub fn get_checksum(&self) -> u64 {
         use std::hash::Hasher;
@@ -836,6 +1247,7 @@ impl GyroSource {
         hasher.write(self.file_url.as_bytes());
         hasher.write_u64(self.duration_ms.to_bits());
         hasher.write_u64(self.imu_transforms.imu_lpf.to_bits());
+        hasher.write_u64(self.imu_transforms.acc_lpf.to_bits());
         hasher.write_i32(self.imu_transforms.imu_mf);
         hasher.write_usize(self.raw_imu.len());
         hasher.write_usize(file_metadata.raw_imu.len());
@@ -844,12 +1256,20 @@ impl GyroSource {
         hasher.write_usize(file_metadata.image_orientations.as_ref().map(|v| v.len()).unwrap_or_default());
         hasher.write_usize(file_metadata.lens_positions.len());
         hasher.write_usize(file_metadata.lens_params.len());
+        hasher.write_usize(file_metadata.focal_lengths.len());
         hasher.write_u32(if self.use_gravity_vectors { 1 } else { 0 });
-        hasher.write_usize(self.integration_method);
+        hasher.write_usize(self.integration_method.index());
+        if let Ok(v) = bincode::serialize(&self.integration_method) { hasher.write(&v); }
+        if let Ok(v) = bincode::serialize(&self.magnetometer) { hasher.write(&v); }
+        for (v, g) in self.time_mapping.points() {
+            hasher.write_u64(v.to_bits());
+            hasher.write_u64(g.to_bits());
+        }
         for (ts, v) in &self.offsets {
             hasher.write_i64(*ts);
             hasher.write_u64(v.to_bits());
         }
+        hasher.write_u8(self.offset_interpolation as u8);
         if let Some((ts, q)) = self.quaternions.first_key_value() {
             let v = q.as_vector();
             hasher.write_i64(*ts);
@@ -888,9 +1308,27 @@ impl GyroSource {
         }
     }
 
+    /// Looks for segments where the camera is static and estimates the gyro bias from them
+    pub fn detect_static_bias(&self, params: &StaticBiasParams) -> Option<StaticBiasEstimate> {
+        StaticBiasEstimate::detect(&self.file_metadata.read().raw_imu, params)
+    }
+
+    /// Ranges with missing samples, as (last sample before, first sample after) gyro timestamps in ms
+    pub fn gaps(&self) -> &[(f64, f64)] {
+        self.sample_rate_analysis.as_ref().map(|x| x.gaps.as_slice()).unwrap_or_default()
+    }
+    pub fn set_gap_repair(&mut self, v: GapRepair) -> bool {
+        if self.gap_repair != v {
+            self.gap_repair = v;
+            self.apply_transforms();
+            return true;
+        }
+        false
+    }
+
     pub fn find_bias(&self, timestamp_start: f64, timestamp_stop: f64) -> (f64, f64, f64) {
-        let ts_start = timestamp_start - self.offset_at_video_timestamp(timestamp_start);
-        let ts_stop = timestamp_stop - self.offset_at_video_timestamp(timestamp_stop);
+        let ts_start = self.video_to_gyro_timestamp(timestamp_start);
+        let ts_stop = self.video_to_gyro_timestamp(timestamp_stop);
         let mut bias_vals = [0.0, 0.0, 0.0];
         let mut n = 0;
 
@@ -913,3 +1351,205 @@ impl GyroSource {
         (bias_vals[0], bias_vals[1], bias_vals[2])
     }
 }
+
+/// Motion data with the filters already applied, integrated with a different IMU orientation on every call.
+/// Gives the same quaternions as setting `imu_orientation` and calling `apply_transforms` on the source it was taken from
+#[derive(Clone)]
+pub struct OrientationSource {
+    imu: Arc<Vec<TimeIMU>>, // Filtered in sensor axes, or the raw data if the rotation has to be applied before filtering
+    prefiltered: bool,
+    gaps: Option<(SampleRateAnalysis, GapRepair)>,
+    transforms: IMUTransforms,
+    integration_method: IntegrationMethod,
+    magnetometer: magnetometer::MagnetometerParams,
+    duration_ms: f64,
+}
+
+impl OrientationSource {
+    /// Integrates only within `ranges_ms` (in gyro time) if provided, each range from its own initial state
+    pub fn quats(&self, orientation: &str, ranges_ms: Option<&[(f64, f64)]>) -> TimeQuat {
+        let mut transforms = self.transforms.clone();
+        transforms.imu_orientation = Some(orientation.to_string());
+
+        let filtered;
+        let base: &[TimeIMU] = if self.prefiltered {
+            &self.imu
+        } else {
+            filtered = GyroSource::filter_imu(&self.imu, self.gaps.as_ref().map(|x| (&x.0, x.1)), &transforms, self.duration_ms);
+            &filtered
+        };
+
+        let mut ranges = ranges_ms.map(|x| x.to_vec()).unwrap_or_else(|| vec![(f64::MIN, f64::MAX)]);
+        ranges.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(ranges.len());
+        for r in ranges {
+            match merged.last_mut() {
+                Some(last) if r.0 <= last.1 => { last.1 = last.1.max(r.1); }
+                _ => merged.push(r)
+            }
+        }
+
+        // The file quaternions don't depend on the IMU orientation, so the orientations can only be told apart from the gyro
+        let method = match self.integration_method {
+            IntegrationMethod::FileQuaternions => IntegrationMethod::GyroOnly,
+            method => method
+        };
+
+        let mut quats = TimeQuat::new();
+        for (from, to) in merged {
+            let start = base.partition_point(|x| x.timestamp_ms < from);
+            let end = base.partition_point(|x| x.timestamp_ms <= to);
+            if end < start + 2 { continue; }
+            let mut imu = base[start..end].to_vec();
+            if self.prefiltered {
+                GyroSource::transform_imu_axes(&mut imu, &transforms);
+            }
+            // Same sample time as for the whole data
+            let duration_ms = if imu.len() == base.len() { self.duration_ms } else { self.duration_ms * imu.len() as f64 / base.len() as f64 };
+            let mut range_quats = method.integrate(&imu, duration_ms);
+            if self.magnetometer.enabled && method.uses_accelerometer() {
+                if let Some(calib) = magnetometer::MagnetometerCalibration::fit(&imu) {
+                    magnetometer::correct_yaw(&mut range_quats, &imu, &calib, &self.magnetometer);
+                }
+            }
+            quats.extend(range_quats);
+        }
+        quats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(integration_method: IntegrationMethod) -> GyroSource {
+        // 20s at 200 Hz, with some vibration
+        let raw_imu = (0..4000).map(|i| {
+            let t = i as f64 / 200.0;
+            TimeIMU {
+                timestamp_ms: i as f64 * 5.0,
+                gyro: Some([20.0 * (t * 1.3).sin(), 15.0 * (t * 0.7).cos(), 10.0 * (t * 2.1).sin() + 3.0 * (t * 37.0).sin()]),
+                accl: Some([0.1 * (t * 0.5).sin(), -0.2 * t.cos(), 1.0]),
+                magn: None
+            }
+        }).collect::<Vec<_>>();
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = 20000.0;
+        gyro.load_from_telemetry(FileMetadata { raw_imu, ..Default::default() });
+        gyro.set_integration_method(integration_method);
+        gyro
+    }
+    fn full_path(gyro: &GyroSource, orientation: &str) -> TimeQuat {
+        let mut clone = gyro.clone();
+        clone.imu_transforms.imu_orientation = Some(orientation.into());
+        clone.apply_transforms();
+        clone.quaternions
+    }
+
+    #[test]
+    fn quats_for_orientation() {
+        let methods = [IntegrationMethod::VQF, IntegrationMethod::GyroOnly, IntegrationMethod::Complementary { alpha: ComplementaryIntegrator::DEFAULT_ALPHA }];
+        for method in methods {
+            // Filters from the cache, rotation which has to be applied before the median filter, and no transforms at all
+            for setup in 0..3 {
+                let mut gyro = source(method);
+                let t = &mut gyro.imu_transforms;
+                match setup {
+                    0 => { t.imu_lpf = 20.0; t.acc_lpf = 5.0; t.imu_mf = 3; t.gyro_bias = Some([0.1, -0.2, 0.3]); },
+                    1 => { t.imu_mf = 3; t.set_imu_rotation(10.0, 0.0, 5.0); },
+                    _ => { }
+                }
+                gyro.apply_transforms();
+                for orientation in ["XYZ", "zYx", "yxZ"] {
+                    let full = full_path(&gyro, orientation);
+                    let fast = gyro.quats_for_orientation(orientation, None);
+                    assert_eq!(fast.len(), full.len());
+                    for ((t1, q1), (t2, q2)) in fast.iter().zip(full.iter()) {
+                        assert_eq!(t1, t2);
+                        assert!(q1.angle_to(q2) < 1e-9, "{method:?}, setup {setup}, {orientation} at {t1}");
+                    }
+                }
+            }
+        }
+
+        // Only within the ranges, overlapping ones are merged
+        let gyro = source(IntegrationMethod::VQF);
+        let quats = gyro.quats_for_orientation("zYx", Some(&[(6000.0, 8000.0), (1000.0, 2000.0), (1500.0, 3000.0)]));
+        assert!(quats.keys().all(|ts| (1_000_000..=3_000_000).contains(ts) || (6_000_000..=8_000_000).contains(ts)));
+        assert_eq!(quats.len(), 401 + 401);
+
+        // The content is checked, not just the allocation
+        let mut gyro = source(IntegrationMethod::GyroOnly);
+        gyro.imu_transforms.imu_lpf = 20.0;
+        gyro.apply_transforms();
+        let before = gyro.quats_for_orientation("XYZ", None);
+        let modified = gyro.file_metadata.read().raw_imu.iter().map(|x| TimeIMU { gyro: x.gyro.map(|g| [g[0] * 2.0, g[1], g[2]]), ..x.clone() }).collect();
+        gyro.file_metadata.set_raw_imu(modified);
+        let after = gyro.quats_for_orientation("XYZ", None);
+        assert!(before.values().zip(after.values()).any(|(a, b)| a.angle_to(b) > 1e-3));
+    }
+
+    #[test]
+    fn data_generation() {
+        let mut gyro = source(IntegrationMethod::GyroOnly);
+        gyro.apply_transforms();
+        let key = gyro.data_key();
+        assert_eq!(gyro.data_key(), key);
+        assert_eq!(gyro.clone().data_key(), key);
+
+        // Same contents, but new metadata
+        let md = gyro.file_metadata.read().clone();
+        gyro.file_metadata = md.into();
+        assert_ne!(gyro.data_key(), key);
+
+        // Modified in place, seen by the clones too
+        let clone = gyro.clone();
+        let raw_imu = gyro.file_metadata.read().raw_imu.clone();
+        gyro.file_metadata.set_raw_imu(raw_imu);
+        assert_eq!(clone.data_key(), gyro.data_key());
+        assert_ne!(gyro.integration_key(), gyro.transforms_cache.integration_key.unwrap());
+
+        let key = gyro.data_key();
+        gyro.duration_ms += 1.0;
+        assert_ne!(gyro.data_key(), key);
+    }
+
+    #[test]
+    #[ignore] // Timing, run with `cargo test --release -- --ignored --nocapture`
+    fn quats_for_orientation_speed() {
+        let mut gyro = source(IntegrationMethod::VQF);
+        gyro.imu_transforms.imu_lpf = 20.0;
+        gyro.imu_transforms.imu_mf = 3;
+        let start = std::time::Instant::now();
+        gyro.apply_transforms();
+        let first = start.elapsed();
+
+        // Nothing changed, everything comes from the cache
+        let start = std::time::Instant::now();
+        for _ in 0..10 { gyro.apply_transforms(); }
+        let cached = start.elapsed() / 10;
+        println!("apply_transforms: {first:?}, cached: {cached:?}");
+        assert!(cached * 20 < first, "{cached:?} vs {first:?}");
+
+        // Only the orientation changed, the filters come from the cache
+        let start = std::time::Instant::now();
+        full_path(&gyro, "Xyz");
+        let orientation_only = start.elapsed();
+        // Integrated again, so only the filtering is saved
+        println!("apply_transforms with a new orientation: {orientation_only:?}");
+        let orientations = ["YxZ", "Xyz", "XZy", "Zxy", "zyX", "yxZ", "ZXY", "zYx"];
+        let ranges = [(2000.0, 4000.0), (12000.0, 14000.0)];
+
+        let start = std::time::Instant::now();
+        for o in orientations { full_path(&gyro, o); }
+        let full = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let source = gyro.orientation_source();
+        for o in orientations { source.quats(o, Some(&ranges)); }
+        let fast = start.elapsed();
+
+        println!("Full path: {full:?}, orientation source: {fast:?}");
+        assert!(fast * 2 < full, "{fast:?} vs {full:?}");
+    }
+}
@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// How the sync offsets are interpolated between the sync points. The same model is used for every lookup of the offset
+// (rendering, optical flow, keyframes), so what's previewed is what's synced.
+// Cameras with a free running gyro clock drift linearly against the video, for them a line through all the points
+// is more robust than following every noisy point. The residuals of that line show whether the camera actually drifts.
+
+use std::collections::BTreeMap;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum OffsetInterpolation {
+    Step,     // Hold the offset of the previous point
+    #[default]
+    Linear,
+    Spline,   // Monotone cubic, doesn't overshoot between the points
+    DriftFit, // Least squares line through all the points, extrapolated past the first and last one
+}
+impl From<&str> for OffsetInterpolation {
+    fn from(v: &str) -> Self {
+        match v.to_ascii_lowercase().as_str() {
+            "step"      => Self::Step,
+            "spline"    => Self::Spline,
+            "drift_fit" | "driftfit" => Self::DriftFit,
+            _ => Self::Linear
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DriftFit {
+    pub slope: f64,     // ms of offset per us
+    pub intercept: f64, // ms
+    pub drift_ppm: f64, // How much faster the gyro clock runs than the video clock
+    pub rms_ms: f64,    // Of the residuals of the points used for the fit
+    pub max_ms: f64,
+    pub points: usize,  // Used for the fit
+    pub residuals: Vec<(i64, f64)>, // Per sync point: timestamp_us, offset - fitted in ms
+}
+
+impl DriftFit {
+    /// Line through `used` (<timestamp in us, offset in ms>), with the residuals of all the points in `offsets`. `None` with fewer than two points
+    pub fn fit(used: &BTreeMap<i64, f64>, offsets: &BTreeMap<i64, f64>) -> Option<Self> {
+        if used.len() < 2 { return None; }
+        let n = used.len() as f64;
+        // Centered, the timestamps in us are too large to square directly
+        let mean_ts = used.keys().map(|x| *x as f64).sum::<f64>() / n;
+        let mean_offset = used.values().sum::<f64>() / n;
+        let (mut stt, mut sto) = (0.0, 0.0);
+        for (ts, offset) in used {
+            let dt = *ts as f64 - mean_ts;
+            stt += dt * dt;
+            sto += dt * (offset - mean_offset);
+        }
+        if !(stt > 0.0) { return None; }
+        let slope = sto / stt;
+        let intercept = mean_offset - slope * mean_ts;
+
+        let residual = |ts: i64, offset: f64| offset - (slope * ts as f64 + intercept);
+        let used_residuals = used.iter().map(|(k, v)| residual(*k, *v).abs()).collect::<Vec<_>>();
+        Some(Self {
+            slope,
+            intercept,
+            drift_ppm: slope * 1e9, // ms per us -> ppm
+            rms_ms: (used_residuals.iter().map(|x| x * x).sum::<f64>() / n).sqrt(),
+            max_ms: used_residuals.iter().copied().fold(0.0, f64::max),
+            points: used.len(),
+            residuals: offsets.iter().map(|(k, v)| (*k, residual(*k, *v))).collect(),
+        })
+    }
+
+    /// (slope, intercept) of the offset in the time of the offsets
+    pub fn line(&self) -> (f64, f64) {
+        (self.slope, self.intercept)
+    }
+    /// (slope, intercept) of the offset in the time of the adjusted offsets (timestamp + offset).
+    /// From `v = t + 1000 * (a * t + b)` and `offset = a * t + b`
+    pub fn adjusted_line(&self) -> (f64, f64) {
+        let d = 1.0 + 1000.0 * self.slope;
+        (self.slope / d, self.intercept / d)
+    }
+}
+
+/// Offset in ms at `timestamp_ms` from `offsets` (<timestamp in us, offset in ms>).
+/// `line` is the (slope, intercept) of the drift fit in the same time as `offsets`, without it `DriftFit` falls back to linear
+pub fn interpolate(offsets: &BTreeMap<i64, f64>, mode: OffsetInterpolation, line: Option<(f64, f64)>, timestamp_ms: f64) -> f64 {
+    match mode {
+        OffsetInterpolation::Step => {
+            let timestamp_us = (timestamp_ms * 1000.0) as i64;
+            offsets.range(..=timestamp_us).next_back().or_else(|| offsets.iter().next()).map(|x| *x.1).unwrap_or_default()
+        },
+        OffsetInterpolation::Spline if offsets.len() > 2 => spline(offsets, timestamp_ms * 1000.0),
+        OffsetInterpolation::DriftFit if offsets.len() > 1 && line.is_some() => {
+            let (slope, intercept) = line.unwrap();
+            slope * timestamp_ms * 1000.0 + intercept
+        },
+        _ => super::GyroSource::offset_at_timestamp(offsets, timestamp_ms)
+    }
+}
+
+// Tangent at `p` of the monotone piecewise cubic (PCHIP), zero at local extrema
+fn pchip_tangent(prev: Option<(f64, f64)>, p: (f64, f64), next: Option<(f64, f64)>) -> f64 {
+    let secant = |a: (f64, f64), b: (f64, f64)| (b.1 - a.1) / (b.0 - a.0);
+    match (prev, next) {
+        (Some(a), Some(b)) => {
+            let (d0, d1) = (secant(a, p), secant(p, b));
+            if d0 * d1 <= 0.0 { return 0.0; }
+            let (h0, h1) = (p.0 - a.0, b.0 - p.0);
+            let (w1, w2) = (2.0 * h1 + h0, h1 + 2.0 * h0);
+            (w1 + w2) / (w1 / d0 + w2 / d1)
+        },
+        (Some(a), None) => secant(a, p),
+        (None, Some(b)) => secant(p, b),
+        (None, None) => 0.0
+    }
+}
+
+fn spline(offsets: &BTreeMap<i64, f64>, timestamp_us: f64) -> f64 {
+    let point = |(k, v): (&i64, &f64)| (*k as f64, *v);
+    let split = timestamp_us.floor() as i64;
+    let mut before = offsets.range(..=split).rev().map(point);
+    let mut after = offsets.range(split + 1..).map(point);
+    let (p1, p2) = match (before.next(), after.next()) {
+        (Some(p1), Some(p2)) => (p1, p2),
+        // Extrapolated with the last segment, like the linear interpolation
+        (Some(p), None) => return p.1 + pchip_tangent(before.next(), p, None) * (timestamp_us - p.0),
+        (None, Some(p)) => return p.1 + pchip_tangent(None, p, after.next()) * (timestamp_us - p.0),
+        (None, None) => return 0.0
+    };
+    let m1 = pchip_tangent(before.next(), p1, Some(p2));
+    let m2 = pchip_tangent(Some(p1), p2, after.next());
+
+    // Cubic Hermite
+    let h = p2.0 - p1.0;
+    let t = (timestamp_us - p1.0) / h;
+    let (t2, t3) = (t * t, t * t * t);
+    (2.0 * t3 - 3.0 * t2 + 1.0) * p1.1 + (t3 - 2.0 * t2 + t) * h * m1 + (-2.0 * t3 + 3.0 * t2) * p2.1 + (t3 - t2) * h * m2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        let offsets: BTreeMap<i64, f64> = [(1_000_000, 10.0), (2_000_000, 12.0), (3_000_000, 12.0), (4_000_000, 20.0)].into_iter().collect();
+        let at = |mode, ts_ms| interpolate(&offsets, mode, None, ts_ms);
+
+        // All of them go through the points
+        for mode in [OffsetInterpolation::Step, OffsetInterpolation::Linear, OffsetInterpolation::Spline] {
+            for (k, v) in &offsets {
+                assert!((at(mode, *k as f64 / 1000.0) - v).abs() < 1e-9, "{mode:?} at {k}");
+            }
+        }
+        assert_eq!(at(OffsetInterpolation::Step, 2999.0), 12.0);
+        assert_eq!(at(OffsetInterpolation::Step, 1999.0), 10.0);
+        assert_eq!(at(OffsetInterpolation::Step, 500.0), 10.0);
+        assert!((at(OffsetInterpolation::Linear, 1500.0) - 11.0).abs() < 1e-9);
+
+        // Monotone: flat between the equal points, within the range of the neighbours elsewhere
+        for ts in (1000..4000).step_by(50) {
+            let v = at(OffsetInterpolation::Spline, ts as f64);
+            if (2000..=3000).contains(&ts) { assert!((v - 12.0).abs() < 1e-9, "{ts}: {v}"); }
+            assert!((10.0..=20.0).contains(&v), "{ts}: {v}");
+        }
+        // Smooth through the points, unlike the linear
+        let slope = |mode, ts: f64| (at(mode, ts + 1.0) - at(mode, ts - 1.0)) / 2.0;
+        assert!((slope(OffsetInterpolation::Spline, 2000.0) - 0.0).abs() < 1e-3);
+        assert!(slope(OffsetInterpolation::Linear, 2000.0) > 5e-4);
+
+        // Without a fit the drift mode is linear
+        assert_eq!(at(OffsetInterpolation::DriftFit, 1500.0), at(OffsetInterpolation::Linear, 1500.0));
+    }
+
+    #[test]
+    fn drift() {
+        // 50 ppm with a bit of noise, and one bad point which isn't used for the fit
+        let noise = [0.3, -0.2, 0.1, -0.3, 0.2, -0.1];
+        let mut used = BTreeMap::new();
+        for (i, n) in noise.iter().enumerate() {
+            let ts = i as i64 * 10_000_000 + 500_000;
+            used.insert(ts, 25.0 + ts as f64 * 50e-9 + n);
+        }
+        let mut offsets = used.clone();
+        offsets.insert(35_000_000, 60.0);
+
+        let fit = DriftFit::fit(&used, &offsets).unwrap();
+        assert!((fit.drift_ppm - 50.0).abs() < 10.0, "{fit:?}");
+        assert!((fit.intercept - 25.0).abs() < 0.3, "{fit:?}");
+        assert!(fit.rms_ms > 0.1 && fit.rms_ms < 0.3);
+        assert!(fit.max_ms <= 0.35);
+        assert_eq!(fit.points, 6);
+        assert_eq!(fit.residuals.len(), 7);
+        assert!(fit.residuals.iter().find(|x| x.0 == 35_000_000).unwrap().1 > 30.0);
+
+        // Extrapolated
+        let line = Some(fit.line());
+        let v = interpolate(&offsets, OffsetInterpolation::DriftFit, line, 100_000.0);
+        assert!((v - (fit.slope * 100_000_000.0 + fit.intercept)).abs() < 1e-9);
+
+        // The adjusted line gives the same offset at the adjusted timestamp
+        let (a, b) = fit.adjusted_line();
+        for ts_us in [0.0, 12_345_678.0, 80_000_000.0] {
+            let offset = fit.slope * ts_us + fit.intercept;
+            let adjusted_ts = ts_us + offset * 1000.0;
+            assert!((a * adjusted_ts + b - offset).abs() < 1e-9);
+        }
+
+        assert!(DriftFit::fit(&[(0, 1.0)].into_iter().collect(), &offsets).is_none());
+    }
+}
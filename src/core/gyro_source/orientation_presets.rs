@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Default IMU orientations for cameras which don't store it in the metadata.
+// The built-in list is compiled in, entries from `imu_orientations.json` in the settings directory take precedence over it.
+
+use parking_lot::RwLock;
+use std::path::PathBuf;
+use crate::camera_identifier::CameraIdentifier;
+
+static BUILTIN_PRESETS: &str = include_str!("orientation_presets.json");
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct OrientationPreset {
+    // Case insensitive patterns, `*` matches any sequence of characters and an empty pattern matches everything
+    pub brand: String,
+    pub model: String,
+    pub firmware: String,
+
+    pub imu_orientation: String,
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+struct PresetsFile {
+    presets: Vec<OrientationPreset>,
+}
+
+#[derive(Default)]
+struct Presets {
+    builtin: Vec<OrientationPreset>,
+    user: Vec<OrientationPreset>,
+    loaded: bool,
+}
+static PRESETS: RwLock<Presets> = RwLock::new(Presets { builtin: Vec::new(), user: Vec::new(), loaded: false });
+
+impl OrientationPreset {
+    pub fn for_camera(id: &CameraIdentifier, firmware: &str, imu_orientation: &str) -> Self {
+        Self {
+            brand: id.brand.clone(),
+            model: id.model.clone(),
+            firmware: firmware.to_owned(),
+            imu_orientation: imu_orientation.to_owned(),
+        }
+    }
+
+    pub fn matches(&self, brand: &str, model: &str, firmware: &str) -> bool {
+        matches_pattern(&self.brand, brand) && matches_pattern(&self.model, model) && matches_pattern(&self.firmware, firmware)
+    }
+
+    // Number of literal characters in the patterns, more specific entries win
+    fn specificity(&self) -> usize {
+        [&self.brand, &self.model, &self.firmware].iter().map(|x| x.chars().filter(|c| *c != '*').count()).sum()
+    }
+}
+
+/// Firmware version as stored in the metadata, if the parser provides it
+pub fn firmware_from_metadata(md: &super::FileMetadata) -> String {
+    md.additional_data.get("firmware").and_then(|x| x.as_str()).unwrap_or_default().to_owned()
+}
+
+pub fn user_presets_path() -> PathBuf {
+    crate::settings::data_dir().join("imu_orientations.json")
+}
+
+fn ensure_loaded() {
+    if PRESETS.read().loaded { return; }
+
+    let mut presets = PRESETS.write();
+    if presets.loaded { return; }
+    match serde_json::from_str::<PresetsFile>(BUILTIN_PRESETS) {
+        Ok(f) => presets.builtin = f.presets,
+        Err(e) => log::error!("Failed to parse built-in IMU orientation presets: {e:?}")
+    }
+    let path = user_presets_path();
+    if let Ok(data) = std::fs::read_to_string(&path) {
+        match serde_json::from_str::<PresetsFile>(&data) {
+            Ok(f) => presets.user = f.presets,
+            Err(e) => log::error!("Failed to parse {}: {e:?}", path.display())
+        }
+    }
+    presets.loaded = true;
+}
+
+/// Finds the IMU orientation for this camera. User entries are checked first, then the most specific matching entry is used
+pub fn lookup(brand: &str, model: &str, firmware: &str) -> Option<String> {
+    ensure_loaded();
+    let presets = PRESETS.read();
+    let best = |list: &[OrientationPreset]| list.iter().filter(|x| x.matches(brand, model, firmware)).max_by_key(|x| x.specificity()).map(|x| x.imu_orientation.clone());
+    best(&presets.user).or_else(|| best(&presets.builtin))
+}
+
+pub fn lookup_for_camera(id: &CameraIdentifier, firmware: &str) -> Option<String> {
+    if id.brand.is_empty() && id.model.is_empty() { return None; }
+    lookup(&id.brand, &id.model, firmware)
+}
+
+/// Adds an entry to the user database, replacing an existing one with the same patterns, and saves the user file
+pub fn add(preset: OrientationPreset) -> std::io::Result<()> {
+    ensure_loaded();
+    let mut presets = PRESETS.write();
+    presets.user.retain(|x| !(x.brand.eq_ignore_ascii_case(&preset.brand) && x.model.eq_ignore_ascii_case(&preset.model) && x.firmware.eq_ignore_ascii_case(&preset.firmware)));
+    log::info!("Saving IMU orientation {} for {} {} {}", preset.imu_orientation, preset.brand, preset.model, preset.firmware);
+    presets.user.push(preset);
+
+    let json = serde_json::to_string_pretty(&PresetsFile { presets: presets.user.clone() })?;
+    std::fs::write(user_presets_path(), json)
+}
+
+/// Exports the user entries, or all entries if `include_builtin` is set, in the same format as the user file
+pub fn export(include_builtin: bool) -> String {
+    ensure_loaded();
+    let presets = PRESETS.read();
+    let mut list = presets.user.clone();
+    if include_builtin {
+        list.extend(presets.builtin.iter().cloned());
+    }
+    serde_json::to_string_pretty(&PresetsFile { presets: list }).unwrap_or_default()
+}
+
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let value = value.to_ascii_lowercase();
+    if pattern.is_empty() || pattern == "*" { return true; }
+
+    let parts = pattern.split('*').collect::<Vec<_>>();
+    if parts.len() == 1 { return pattern == value; }
+
+    let mut rest = value.as_str();
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() { continue; }
+        if i == 0 {
+            if !rest.starts_with(part) { return false; }
+            rest = &rest[part.len()..];
+        } else if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else {
+            match rest.find(part) {
+                Some(pos) => rest = &rest[pos + part.len()..],
+                None => return false
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("", "anything"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("Thumb", "thumb"));
+        assert!(!matches_pattern("Thumb", "Thumb Pro"));
+        assert!(matches_pattern("Thumb*", "Thumb Pro"));
+        assert!(matches_pattern("thumb*", "THUMB"));
+        assert!(matches_pattern("*Pro", "Thumb Pro"));
+        assert!(!matches_pattern("*Pro", "Pro Thumb"));
+        assert!(matches_pattern("v1.*.3*", "v1.2.3 beta"));
+        assert!(!matches_pattern("v1.*.3*", "v1.2.4"));
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(matches_pattern("a*b*c", "a-x-b-y-c"));
+        assert!(!matches_pattern("a*b*c", "acb"));
+        // The prefix and the suffix can't overlap
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn specificity() {
+        let preset = |brand: &str, model: &str, firmware: &str, io: &str| OrientationPreset { brand: brand.into(), model: model.into(), firmware: firmware.into(), imu_orientation: io.into() };
+        let list = [preset("Runcam", "", "", "XYZ"), preset("Runcam", "Thumb*", "", "Yxz"), preset("Runcam", "Thumb*", "2.*", "yxZ")];
+        let best = |model: &str, firmware: &str| list.iter().filter(|x| x.matches("RunCam", model, firmware)).max_by_key(|x| x.specificity()).map(|x| x.imu_orientation.as_str());
+        assert_eq!(best("Thumb Pro", "2.1"), Some("yxZ"));
+        assert_eq!(best("Thumb Pro", "1.0"), Some("Yxz"));
+        assert_eq!(best("5 Orange", "2.1"), Some("XYZ"));
+    }
+
+    #[test]
+    fn builtin() {
+        let file = serde_json::from_str::<PresetsFile>(BUILTIN_PRESETS).unwrap();
+        assert!(!file.presets.is_empty());
+        for x in &file.presets {
+            let axes = x.imu_orientation.to_ascii_lowercase();
+            assert!(axes.len() == 3 && ['x', 'y', 'z'].iter().all(|c| axes.contains(*c)), "{x:?}");
+            assert!(!x.brand.is_empty(), "{x:?}");
+        }
+    }
+}
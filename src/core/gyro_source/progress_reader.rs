@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Progress and cancellation for the parsers which read the telemetry file as a stream.
+// Once cancelled, every read fails, so the parser stops at its next read no matter where it is in the file
+
+use std::io::{ Error, Read, Result };
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::SeqCst } };
+
+const MAX_READ: usize = 1024 * 1024;
+
+pub struct ProgressReader<R: Read, F: Fn(f64)> {
+    inner: R,
+    total: usize,
+    read: usize,
+    reported: usize,
+    progress_cb: F,
+    cancel_flag: Arc<AtomicBool>,
+}
+
+impl<R: Read, F: Fn(f64)> ProgressReader<R, F> {
+    pub fn new(inner: R, total: usize, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Self {
+        Self { inner, total, read: 0, reported: 0, progress_cb, cancel_flag }
+    }
+}
+
+impl<R: Read, F: Fn(f64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.cancel_flag.load(SeqCst) {
+            return Err(Error::other("Cancelled"));
+        }
+        // Small enough reads to check the flag regularly, also when the caller asks for the whole file at once
+        let len = buf.len().min(MAX_READ);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.read += n;
+        // Reported every 1% of the file
+        if self.total > 0 && (self.read - self.reported) * 100 >= self.total {
+            self.reported = self.read;
+            (self.progress_cb)((self.read as f64 / self.total as f64).min(1.0));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem;
+    use crate::gyro_source::{ GyroSource, gcsv::{ GcsvData, GcsvExportOptions } };
+    use std::cell::Cell;
+    use std::time::{ Duration, Instant };
+
+    // Quaternion gcsv of a slow pan at 1 kHz
+    fn write_log(name: &str, duration_s: f64) -> String {
+        let mut gyro = GyroSource::new();
+        gyro.integration_method = crate::imu_integration::IntegrationMethod::GyroOnly;
+        gyro.duration_ms = duration_s * 1000.0;
+        gyro.load_from_telemetry(crate::gyro_source::FileMetadata {
+            raw_imu: (0..(duration_s * 1000.0) as usize).map(|i| crate::gyro_source::TimeIMU {
+                timestamp_ms: i as f64,
+                gyro: Some([0.0, 0.0, 10.0 + (i as f64 / 100.0).sin()]),
+                accl: Some([0.0, 0.0, 1.0]),
+                magn: None
+            }).collect(),
+            ..Default::default()
+        });
+        let path = std::env::temp_dir().join(format!("gyroflow_{}_{name}.gcsv", std::process::id()));
+        std::fs::write(&path, gyro.to_gcsv(&GcsvExportOptions { data: GcsvData::Quaternions, ..Default::default() })).unwrap();
+        filesystem::path_to_url(&path.to_string_lossy())
+    }
+
+    #[test]
+    fn cancel_while_reading() {
+        let data = vec![0u8; 10_000_000];
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let last = Cell::new(0.0);
+        let mut reader = ProgressReader::new(&data[..], data.len(), |p| {
+            last.set(p);
+            if p > 0.2 { cancel_flag.store(true, SeqCst); }
+        }, cancel_flag.clone());
+        let mut buf = [0u8; 4096];
+        let err = loop {
+            match reader.read(&mut buf) {
+                Ok(0) => panic!("Read the whole buffer"),
+                Ok(_) => { },
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.to_string(), "Cancelled");
+        assert!(last.get() > 0.2 && last.get() < 0.25, "{}", last.get());
+    }
+
+    #[test]
+    fn cancelled_load_keeps_state() {
+        let small = write_log("small", 5.0);
+        let large = write_log("large", 300.0);
+
+        // Cancelled in the middle of the file
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let last = Cell::new(0.0);
+        let started = Instant::now();
+        let result = GyroSource::parse_telemetry_file(&large, &Default::default(), (1920, 1080), 30.0, |p| {
+            last.set(p);
+            if p > 0.1 { cancel_flag.store(true, SeqCst); }
+        }, cancel_flag.clone());
+        assert!(matches!(result, Err(crate::GyroflowCoreError::Cancelled)));
+        assert!(last.get() < 0.5, "{}", last.get());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let stab = crate::StabilizationManager::default();
+        stab.init_from_video_data(5000.0, 30.0, 150, (1920, 1080));
+        stab.load_gyro_data(&small, true, &Default::default(), |_| (), Arc::new(AtomicBool::new(false))).unwrap();
+        let (checksum, quats) = { let gyro = stab.gyro.read(); (gyro.get_checksum(), gyro.quaternions.len()) };
+        assert!(quats > 0);
+
+        let started = Instant::now();
+        let result = stab.load_gyro_data(&large, true, &Default::default(), |_| (), Arc::new(AtomicBool::new(true)));
+        assert!(matches!(result, Err(crate::GyroflowCoreError::Cancelled)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let gyro = stab.gyro.read();
+        assert_eq!((gyro.get_checksum(), gyro.quaternions.len()), (checksum, quats));
+        assert_eq!(gyro.file_url, small);
+
+        for url in [small, large] { let _ = std::fs::remove_file(filesystem::url_to_path(&url)); }
+    }
+}
@@ -0,0 +1,316 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Second gyro stream recorded by another device, eg. the flight controller blackbox next to the camera's internal IMU.
+// The streams differ by a fixed time offset and a fixed rotation, both estimated from the angular velocities where the recordings overlap.
+
+use super::*;
+use super::gcsv::sample_at;
+
+const COARSE_RATE_HZ: f64 = 50.0;
+const FINE_RATE_HZ: f64 = 200.0;
+const MIN_OVERLAP_MS: f64 = 2000.0;
+const MIN_ROTATION_DPS: f64 = 5.0; // Slower samples don't constrain the rotation
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum GyroStream {
+    #[default]
+    Primary,
+    Secondary,
+    Fused, // Average of both where they overlap
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SecondaryGyro {
+    pub file_url: String,
+    pub file_load_options: FileLoadOptions,
+    pub time_offset_ms: f64, // Secondary sample at `t` is at `t + time_offset_ms` in the primary gyro time
+    pub relative_rotation: Option<[[f64; 3]; 3]>, // Row major, maps the secondary axes to the primary sensor axes
+    pub residual_dps: f64, // RMS difference of the aligned angular velocities
+
+    #[serde(skip)]
+    pub raw_imu: Vec<TimeIMU>,
+}
+
+impl SecondaryGyro {
+    pub fn new(file_url: &str, file_load_options: &FileLoadOptions, md: &FileMetadata) -> Self {
+        Self {
+            file_url: file_url.to_owned(),
+            file_load_options: file_load_options.clone(),
+            raw_imu: md.raw_imu.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn rotation(&self) -> Matrix3<f64> {
+        self.relative_rotation.map(|r| Matrix3::from_row_slice(&r.concat())).unwrap_or_else(Matrix3::identity)
+    }
+
+    /// Secondary samples moved to the primary gyro time and sensor axes
+    pub fn aligned_imu(&self) -> Vec<TimeIMU> {
+        let r = self.rotation();
+        let rotate = |v: [f64; 3]| { let v = r * Vector3::new(v[0], v[1], v[2]); [v.x, v.y, v.z] };
+        self.raw_imu.iter().map(|x| TimeIMU {
+            timestamp_ms: x.timestamp_ms + self.time_offset_ms,
+            gyro: x.gyro.map(rotate),
+            accl: x.accl.map(rotate),
+            magn: None
+        }).collect()
+    }
+
+    /// Estimates `time_offset_ms` within `±max_offset_ms` of the current value, and then `relative_rotation` at that offset.
+    /// The offset is found from the magnitude of angular velocity, which doesn't depend on the rotation. Returns the residual in deg/s
+    pub fn estimate_alignment(&mut self, primary: &[TimeIMU], max_offset_ms: f64) -> Option<f64> {
+        let magnitude = |imu: &[TimeIMU], rate: f64| -> (f64, Vec<f64>) {
+            let start = imu.first().map(|x| x.timestamp_ms).unwrap_or_default();
+            let end = imu.last().map(|x| x.timestamp_ms).unwrap_or_default();
+            let count = ((end - start) * rate / 1000.0).max(0.0) as usize;
+            (start, (0..count).map(|i| sample_at(imu, start + i as f64 * 1000.0 / rate).0.map(|g| Vector3::from(g).norm()).unwrap_or_default()).collect())
+        };
+        let correlate = |p: &[f64], s: &[f64], lag: isize| -> Option<f64> {
+            let from = (-lag).max(0) as usize;
+            let to = (p.len() as isize).min(s.len() as isize - lag).max(0) as usize;
+            if to <= from + 2 { return None; }
+            let n = (to - from) as f64;
+            let (mut sp, mut ss, mut spp, mut sss, mut sps) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for i in from..to {
+                let (a, b) = (p[i], s[(i as isize + lag) as usize]);
+                sp += a; ss += b; spp += a * a; sss += b * b; sps += a * b;
+            }
+            let cov = sps - sp * ss / n;
+            let var = ((spp - sp * sp / n) * (sss - ss * ss / n)).sqrt();
+            if var > 0.0 { Some(cov / var) } else { None }
+        };
+        // Best offset at `rate` within `[from_ms, to_ms]`
+        let search = |rate: f64, from_ms: f64, to_ms: f64| -> Option<f64> {
+            let (p_start, p) = magnitude(primary, rate);
+            let (s_start, s) = magnitude(&self.raw_imu, rate);
+            let dt = 1000.0 / rate;
+            let min_overlap = (MIN_OVERLAP_MS / dt) as usize;
+            // p[i] is at the same time as s[i + lag] for offset = p_start - s_start - lag * dt
+            let lag_of = |offset: f64| ((p_start - s_start - offset) / dt).round() as isize;
+            let (lag_from, lag_to) = (lag_of(to_ms), lag_of(from_ms));
+            let scores = (lag_from..=lag_to).map(|lag| {
+                let overlap = (p.len() as isize).min(s.len() as isize - lag) - (-lag).max(0);
+                (lag, if overlap >= min_overlap as isize { correlate(&p, &s, lag) } else { None })
+            }).collect::<Vec<_>>();
+            let best = scores.iter().enumerate().filter_map(|(i, (_, c))| Some((i, (*c)?))).max_by(|a, b| a.1.total_cmp(&b.1))?;
+            // Parabolic interpolation between the neighbouring lags
+            let mut lag = scores[best.0].0 as f64;
+            if let (Some((_, Some(l))), Some((_, Some(r)))) = (best.0.checked_sub(1).and_then(|i| scores.get(i)), scores.get(best.0 + 1)) {
+                let denom = l - 2.0 * best.1 + r;
+                if denom.abs() > 1e-12 { lag += (0.5 * (l - r) / denom).clamp(-0.5, 0.5); }
+            }
+            Some(p_start - s_start - lag * dt)
+        };
+
+        let coarse = search(COARSE_RATE_HZ, self.time_offset_ms - max_offset_ms, self.time_offset_ms + max_offset_ms)?;
+        let fine_window = 2000.0 / COARSE_RATE_HZ;
+        let offset = search(FINE_RATE_HZ, coarse - fine_window, coarse + fine_window).unwrap_or(coarse);
+
+        // Rotation from the angular velocity vectors (Kabsch), reflections are allowed because sensor axes conventions can be left-handed
+        let mut h = Matrix3::<f64>::zeros();
+        let mut pairs = Vec::new();
+        let start = primary.first().map(|x| x.timestamp_ms).unwrap_or_default();
+        let end = primary.last().map(|x| x.timestamp_ms).unwrap_or_default();
+        let (s_first, s_last) = (self.raw_imu.first().map(|x| x.timestamp_ms + offset)?, self.raw_imu.last().map(|x| x.timestamp_ms + offset)?);
+        let mut t = start.max(s_first);
+        while t <= end.min(s_last) {
+            if let (Some(p), Some(s)) = (sample_at(primary, t).0, sample_at(&self.raw_imu, t - offset).0) {
+                let (p, s) = (Vector3::from(p), Vector3::from(s));
+                if p.norm() > MIN_ROTATION_DPS && s.norm() > MIN_ROTATION_DPS {
+                    h += s * p.transpose();
+                    pairs.push((p, s));
+                }
+            }
+            t += 1000.0 / FINE_RATE_HZ;
+        }
+        if pairs.len() < 50 {
+            log::warn!("Not enough motion in the overlapping part to align the secondary gyro");
+            return None;
+        }
+        let svd = h.svd(true, true);
+        let mut sv = svd.singular_values.iter().copied().collect::<Vec<_>>();
+        sv.sort_by(|a, b| b.total_cmp(a));
+        if sv[1] < sv[0] * 0.01 {
+            log::warn!("Motion is around a single axis only, the secondary gyro rotation can't be estimated");
+            return None;
+        }
+        let r = svd.v_t?.transpose() * svd.u?.transpose();
+
+        let residual = (pairs.iter().map(|(p, s)| (p - r * s).norm_squared()).sum::<f64>() / pairs.len() as f64).sqrt();
+        log::info!("Secondary gyro aligned: offset {offset:.2} ms, residual {residual:.3} deg/s, rotation {r:?}");
+
+        self.time_offset_ms = offset;
+        self.relative_rotation = Some([
+            [r[(0, 0)], r[(0, 1)], r[(0, 2)]],
+            [r[(1, 0)], r[(1, 1)], r[(1, 2)]],
+            [r[(2, 0)], r[(2, 1)], r[(2, 2)]],
+        ]);
+        self.residual_dps = residual;
+        Some(residual)
+    }
+}
+
+impl GyroSource {
+    /// Metadata of the main file, regardless of the selected stream
+    pub fn primary_file_metadata(&self) -> ReadOnlyFileMetadata {
+        self.primary_metadata.clone().unwrap_or_else(|| self.file_metadata.clone())
+    }
+
+    pub fn attach_secondary(&mut self, mut secondary: SecondaryGyro, estimate_alignment: bool, max_offset_ms: f64) -> Option<f64> {
+        let residual = if estimate_alignment {
+            secondary.estimate_alignment(&self.primary_file_metadata().read().raw_imu, max_offset_ms)
+        } else {
+            None
+        };
+        self.secondary_gyro = Some(secondary);
+        self.update_gyro_stream();
+        residual
+    }
+    pub fn detach_secondary(&mut self) {
+        self.secondary_gyro = None;
+        self.gyro_stream = GyroStream::Primary;
+        self.update_gyro_stream();
+    }
+
+    pub fn estimate_secondary_alignment(&mut self, max_offset_ms: f64) -> Option<f64> {
+        let primary = self.primary_file_metadata();
+        let residual = self.secondary_gyro.as_mut()?.estimate_alignment(&primary.read().raw_imu, max_offset_ms);
+        if residual.is_some() { self.update_gyro_stream(); }
+        residual
+    }
+    pub fn set_secondary_alignment(&mut self, time_offset_ms: f64, relative_rotation: Option<[[f64; 3]; 3]>) -> bool {
+        let Some(secondary) = self.secondary_gyro.as_mut() else { return false; };
+        if secondary.time_offset_ms != time_offset_ms || secondary.relative_rotation != relative_rotation {
+            secondary.time_offset_ms = time_offset_ms;
+            secondary.relative_rotation = relative_rotation;
+            self.update_gyro_stream();
+            return true;
+        }
+        false
+    }
+
+    pub fn set_gyro_stream(&mut self, stream: GyroStream) -> bool {
+        if self.gyro_stream != stream {
+            self.gyro_stream = stream;
+            self.update_gyro_stream();
+            return true;
+        }
+        false
+    }
+    /// Copy which uses `stream` for the motion, eg. to run sync on the other stream
+    pub fn with_gyro_stream(&self, stream: GyroStream) -> Self {
+        let mut ret = self.clone();
+        ret.set_gyro_stream(stream);
+        ret
+    }
+
+    // Swaps the metadata for one with the raw IMU data of the selected stream, and re-integrates
+    fn update_gyro_stream(&mut self) {
+        let primary = self.primary_file_metadata();
+        let stream_imu = match (&self.secondary_gyro, self.gyro_stream) {
+            (Some(secondary), GyroStream::Secondary | GyroStream::Fused) if !secondary.raw_imu.is_empty() => {
+                let p_md = primary.read();
+                let aligned = secondary.aligned_imu();
+                let first = p_md.raw_imu.first().map(|x| x.timestamp_ms).unwrap_or_default();
+                let last = p_md.raw_imu.last().map(|x| x.timestamp_ms).unwrap_or(self.duration_ms);
+                let (a_first, a_last) = (aligned[0].timestamp_ms, aligned[aligned.len() - 1].timestamp_ms);
+                let imu = if self.gyro_stream == GyroStream::Secondary {
+                    aligned.iter().filter(|x| x.timestamp_ms >= first && x.timestamp_ms <= last).map(|x| TimeIMU {
+                        accl: x.accl.or_else(|| sample_at(&p_md.raw_imu, x.timestamp_ms).1),
+                        ..x.clone()
+                    }).collect::<Vec<_>>()
+                } else {
+                    p_md.raw_imu.iter().map(|x| {
+                        let mut x = x.clone();
+                        if x.timestamp_ms >= a_first && x.timestamp_ms <= a_last {
+                            if let (Some(p), Some(s)) = (x.gyro, sample_at(&aligned, x.timestamp_ms).0) {
+                                x.gyro = Some([(p[0] + s[0]) / 2.0, (p[1] + s[1]) / 2.0, (p[2] + s[2]) / 2.0]);
+                            }
+                        }
+                        x
+                    }).collect()
+                };
+                if imu.is_empty() { log::warn!("Secondary gyro doesn't overlap the primary one, using the primary stream"); }
+                Some(imu).filter(|x| !x.is_empty())
+            },
+            _ => None
+        };
+
+        match stream_imu {
+            Some(imu) => {
+                let mut md = primary.read().clone();
+                md.raw_imu = imu;
+                md.quaternions.clear(); // Integrate from the selected stream
+                if self.integration_method == IntegrationMethod::FileQuaternions {
+                    self.integration_method = IntegrationMethod::VQF;
+                }
+                self.primary_metadata = Some(primary);
+                self.file_metadata = md.into();
+            },
+            None => {
+                if let Some(primary) = self.primary_metadata.take() {
+                    self.file_metadata = primary;
+                }
+            }
+        }
+        self.apply_transforms();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Angular velocity in deg/s at `t_ms`, not periodic so there's only one matching offset
+    fn motion(t_ms: f64) -> Vector3<f64> {
+        let t = t_ms / 1000.0;
+        Vector3::new(40.0 * (1.3 * t).sin() + 15.0 * (3.7 * t).sin(), 30.0 * (0.9 * t).cos() + 10.0 * (5.1 * t).sin(), 25.0 * (2.3 * t + 1.0).sin())
+    }
+    fn imu(rate_hz: f64, duration_ms: f64, gyro: impl Fn(f64) -> Vector3<f64>) -> Vec<TimeIMU> {
+        (0..(duration_ms * rate_hz / 1000.0) as usize).map(|i| {
+            let ts = i as f64 * 1000.0 / rate_hz;
+            let g = gyro(ts);
+            TimeIMU { timestamp_ms: ts, gyro: Some([g.x, g.y, g.z]), accl: None, magn: None }
+        }).collect()
+    }
+
+    #[test]
+    fn estimate_alignment() {
+        let offset = 347.3;
+        let rotation = *Rotation3::from_euler_angles(0.3, -1.2, 2.0).matrix();
+        let primary = imu(500.0, 30000.0, motion);
+        // Different rate, in its own clock and axes
+        let raw_imu = imu(400.0, 25000.0, |ts| rotation.transpose() * motion(ts + offset));
+
+        let mut secondary = SecondaryGyro { raw_imu: raw_imu.clone(), ..Default::default() };
+        let residual = secondary.estimate_alignment(&primary, 2000.0).unwrap();
+        assert!((secondary.time_offset_ms - offset).abs() < 2.0, "{}", secondary.time_offset_ms);
+        assert!(residual < 1.0, "{residual}");
+        assert_eq!(secondary.residual_dps, residual);
+        let estimated = secondary.rotation();
+        assert!(Rotation3::from_matrix(&(estimated * rotation.transpose())).angle() < 1.0f64.to_radians(), "{estimated:?}");
+
+        // The aligned samples match the primary ones
+        for x in secondary.aligned_imu().iter().step_by(97) {
+            let diff = Vector3::from(x.gyro.unwrap()) - motion(x.timestamp_ms);
+            assert!(diff.norm() < 1.0, "{} {diff:?}", x.timestamp_ms);
+        }
+
+        // Outside of the search range
+        let mut secondary = SecondaryGyro { raw_imu: raw_imu.clone(), time_offset_ms: 5000.0, ..Default::default() };
+        assert!(secondary.estimate_alignment(&primary, 1000.0).map_or(true, |_| (secondary.time_offset_ms - offset).abs() > 100.0));
+
+        // Rotation around a single axis doesn't constrain the other ones
+        let single_axis = |t: f64| Vector3::new(0.0, 0.0, 40.0 * (1.3 * t / 1000.0).sin() + 15.0 * (3.7 * t / 1000.0).sin());
+        let mut secondary = SecondaryGyro { raw_imu: imu(400.0, 25000.0, |ts| single_axis(ts + offset)), ..Default::default() };
+        assert!(secondary.estimate_alignment(&imu(500.0, 30000.0, single_axis), 2000.0).is_none());
+        assert!(secondary.relative_rotation.is_none());
+
+        // No motion
+        let mut secondary = SecondaryGyro { raw_imu: imu(400.0, 25000.0, |_| Vector3::zeros()), ..Default::default() };
+        assert!(secondary.estimate_alignment(&primary, 2000.0).is_none());
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Maps video time to gyro time, eg. for conformed slow motion or speed ramps done in camera.
+// Control points are (video_ms, gyro_ms), the mapping is linear between them and extrapolated using the first and last segment.
+// No control points means identity, a single point is a constant offset.
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TimeMapping {
+    points: Vec<(f64, f64)>,
+}
+
+impl TimeMapping {
+    /// Control points must be strictly increasing in both video and gyro time. Returns `None` otherwise
+    pub fn from_points(mut points: Vec<(f64, f64)>) -> Option<Self> {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let valid = points.iter().all(|x| x.0.is_finite() && x.1.is_finite())
+                 && points.windows(2).all(|w| w[1].0 > w[0].0 && w[1].1 > w[0].1);
+        if !valid { return None; }
+        Some(Self { points })
+    }
+
+    /// Gyro time runs `rate` times faster than video time, eg. 0.2 for 120 fps footage conformed to 24 fps
+    pub fn constant_rate(rate: f64) -> Option<Self> {
+        if !rate.is_finite() || rate <= 0.0 { return None; }
+        if (rate - 1.0).abs() < 1e-9 { return Some(Self::default()); }
+        Some(Self { points: vec![(0.0, 0.0), (1000.0, 1000.0 * rate)] })
+    }
+
+    /// Rate factor for footage captured at `capture_fps` and played back at `playback_fps`
+    pub fn rate_from_fps(capture_fps: f64, playback_fps: f64) -> Option<f64> {
+        if capture_fps > 0.0 && playback_fps > 0.0 {
+            Some(playback_fps / capture_fps)
+        } else {
+            None
+        }
+    }
+
+    pub fn points(&self) -> &[(f64, f64)] { &self.points }
+
+    pub fn is_identity(&self) -> bool {
+        self.points.iter().all(|x| x.0 == x.1)
+    }
+
+    pub fn video_to_gyro(&self, video_ms: f64) -> f64 {
+        Self::interpolate(&self.points, video_ms, |x| x.0, |x| x.1)
+    }
+    pub fn gyro_to_video(&self, gyro_ms: f64) -> f64 {
+        Self::interpolate(&self.points, gyro_ms, |x| x.1, |x| x.0)
+    }
+
+    /// Local slope of the mapping, ie. how many gyro milliseconds pass in one video millisecond
+    pub fn rate_at_video(&self, video_ms: f64) -> f64 {
+        if self.points.len() < 2 { return 1.0; }
+        let (a, b) = self.segment(video_ms, |x| x.0);
+        (b.1 - a.1) / (b.0 - a.0)
+    }
+    pub fn rate_at_gyro(&self, gyro_ms: f64) -> f64 {
+        if self.points.len() < 2 { return 1.0; }
+        let (a, b) = self.segment(gyro_ms, |x| x.1);
+        (b.1 - a.1) / (b.0 - a.0)
+    }
+
+    fn segment(&self, v: f64, from: impl Fn(&(f64, f64)) -> f64) -> ((f64, f64), (f64, f64)) {
+        let i = self.points.partition_point(|x| from(x) <= v).clamp(1, self.points.len() - 1);
+        (self.points[i - 1], self.points[i])
+    }
+
+    fn interpolate(points: &[(f64, f64)], v: f64, from: impl Fn(&(f64, f64)) -> f64, to: impl Fn(&(f64, f64)) -> f64) -> f64 {
+        match points.len() {
+            0 => v,
+            1 => v - from(&points[0]) + to(&points[0]),
+            len => {
+                let i = points.partition_point(|x| from(x) <= v).clamp(1, len - 1);
+                let (a, b) = (&points[i - 1], &points[i]);
+                let fract = (v - from(a)) / (from(b) - from(a));
+                to(a) + (to(b) - to(a)) * fract
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping() {
+        let identity = TimeMapping::default();
+        assert!(identity.is_identity());
+        assert_eq!(identity.video_to_gyro(1234.5), 1234.5);
+        assert_eq!(identity.gyro_to_video(1234.5), 1234.5);
+        assert_eq!(identity.rate_at_video(100.0), 1.0);
+
+        // Constant offset
+        let offset = TimeMapping::from_points(vec![(100.0, 150.0)]).unwrap();
+        assert!(!offset.is_identity());
+        assert_eq!(offset.video_to_gyro(1000.0), 1050.0);
+        assert_eq!(offset.gyro_to_video(1050.0), 1000.0);
+
+        // 120 fps conformed to 24 fps
+        let rate = TimeMapping::rate_from_fps(120.0, 24.0).unwrap();
+        assert!((rate - 0.2).abs() < 1e-12);
+        let slowmo = TimeMapping::constant_rate(rate).unwrap();
+        assert!((slowmo.video_to_gyro(5000.0) - 1000.0).abs() < 1e-9);
+        assert!((slowmo.gyro_to_video(1000.0) - 5000.0).abs() < 1e-9);
+        assert!((slowmo.rate_at_video(5000.0) - 0.2).abs() < 1e-12);
+        assert!(TimeMapping::constant_rate(1.0).unwrap().is_identity());
+        assert!(TimeMapping::constant_rate(0.0).is_none());
+        assert!(TimeMapping::rate_from_fps(0.0, 24.0).is_none());
+
+        // Speed ramp: real time, 4x slow motion, real time. Points are sorted
+        let ramp = TimeMapping::from_points(vec![(3000.0, 1500.0), (0.0, 0.0), (1000.0, 1000.0), (5000.0, 3500.0)]).unwrap();
+        assert_eq!(ramp.points()[0], (0.0, 0.0));
+        let expected = [(500.0, 500.0), (1000.0, 1000.0), (2000.0, 1250.0), (3000.0, 1500.0), (4000.0, 2500.0), (-1000.0, -1000.0), (6000.0, 4500.0)];
+        for (video, gyro) in expected {
+            assert!((ramp.video_to_gyro(video) - gyro).abs() < 1e-9, "{video}");
+            assert!((ramp.gyro_to_video(gyro) - video).abs() < 1e-9, "{gyro}");
+        }
+        assert!((ramp.rate_at_video(2000.0) - 0.25).abs() < 1e-12);
+        assert!((ramp.rate_at_gyro(1250.0) - 0.25).abs() < 1e-12);
+        assert!((ramp.rate_at_video(4000.0) - 1.0).abs() < 1e-12);
+
+        // Round trip
+        for i in 0..100 {
+            let video = i as f64 * 73.1 - 500.0;
+            assert!((ramp.gyro_to_video(ramp.video_to_gyro(video)) - video).abs() < 1e-9);
+        }
+
+        // Not monotonic
+        assert!(TimeMapping::from_points(vec![(0.0, 0.0), (1000.0, 500.0), (2000.0, 400.0)]).is_none());
+        assert!(TimeMapping::from_points(vec![(0.0, 0.0), (0.0, 500.0)]).is_none());
+        assert!(TimeMapping::from_points(vec![(0.0, f64::NAN)]).is_none());
+    }
+}
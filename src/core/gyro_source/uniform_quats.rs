@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+use super::{ Quat64, TimeQuat };
+
+// Resampled data further than this from the source at any source sample is discarded
+const MAX_ERROR_RAD: f64 = 0.01 * std::f64::consts::PI / 180.0;
+
+// Quaternions resampled to a uniform rate, so the lookup is an index and a single slerp instead of two BTreeMap range queries.
+// The BTreeMap stays the source of truth, this is only a cache and is rebuilt every time the source changes.
+#[derive(Clone, Debug)]
+pub struct UniformQuats {
+    start_us: i64,
+    end_us: i64,
+    step_us: f64,
+    quats: Vec<Quat64>,
+    source_len: usize,
+}
+
+impl UniformQuats {
+    /// Resamples `source` at `rate_hz`, or at the source rate if it's higher. Returns `None` if there's not enough data,
+    /// the rate is 0 (disabled) or the resampled data doesn't match the source within `MAX_ERROR_RAD`
+    pub fn build(source: &TimeQuat, rate_hz: f64) -> Option<Self> {
+        if source.len() < 2 || !(rate_hz > 0.0) { return None; }
+        let start_us = *source.keys().next()?;
+        let end_us = *source.keys().next_back()?;
+        if end_us <= start_us { return None; }
+
+        let source_rate = (source.len() - 1) as f64 / ((end_us - start_us) as f64 / 1_000_000.0);
+        let step_us = 1_000_000.0 / rate_hz.max(source_rate);
+        let count = ((end_us - start_us) as f64 / step_us).ceil() as usize + 1;
+
+        let mut quats = Vec::with_capacity(count);
+        let mut iter = source.iter();
+        let (mut a_ts, mut a) = iter.next()?;
+        let (mut b_ts, mut b) = iter.next()?;
+        for i in 0..count {
+            let ts = (start_us as f64 + i as f64 * step_us).min(end_us as f64);
+            while ts > *b_ts as f64 {
+                match iter.next() {
+                    Some(next) => { (a_ts, a) = (b_ts, b); (b_ts, b) = next; },
+                    None => break
+                }
+            }
+            let fract = ((ts - *a_ts as f64) / (*b_ts - *a_ts) as f64).clamp(0.0, 1.0);
+            quats.push(a.slerp(b, fract));
+        }
+
+        let ret = Self { start_us, end_us, step_us, quats, source_len: source.len() };
+
+        let max_error = source.iter().map(|(ts, q)| ret.at(*ts as f64).angle_to(q)).fold(0.0, f64::max);
+        if max_error > MAX_ERROR_RAD {
+            log::debug!("Resampled quaternions differ from the source by {:.4} deg, using the source directly", max_error * 180.0 / std::f64::consts::PI);
+            return None;
+        }
+        Some(ret)
+    }
+
+    /// Cheap check that this was built from `source`
+    pub fn matches(&self, source: &TimeQuat) -> bool {
+        self.source_len == source.len() &&
+            source.keys().next() == Some(&self.start_us) &&
+            source.keys().next_back() == Some(&self.end_us)
+    }
+
+    pub fn at(&self, timestamp_us: f64) -> Quat64 {
+        let ts = timestamp_us.clamp(self.start_us as f64, self.end_us as f64);
+        let pos = (ts - self.start_us as f64) / self.step_us;
+        let i = (pos.floor() as usize).min(self.quats.len() - 2);
+
+        let t0 = self.start_us as f64 + i as f64 * self.step_us;
+        let t1 = (t0 + self.step_us).min(self.end_us as f64);
+        let fract = if t1 > t0 { ((ts - t0) / (t1 - t0)).clamp(0.0, 1.0) } else { 0.0 };
+        if fract == 0.0 { return self.quats[i]; }
+        self.quats[i].slerp(&self.quats[i + 1], fract)
+    }
+
+    /// Uniformly spaced (timestamp_us, quaternion) pairs
+    pub fn iter(&self) -> impl Iterator<Item = (i64, &Quat64)> + '_ {
+        self.quats.iter().enumerate().map(|(i, q)| (((self.start_us as f64 + i as f64 * self.step_us).round() as i64).min(self.end_us), q))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::GyroSource;
+    use nalgebra::Vector3;
+
+    // Irregularly sampled motion around a moving axis, between 200 and 300 Hz
+    fn test_quats() -> TimeQuat {
+        let mut quats = TimeQuat::new();
+        let mut ts = 0.0;
+        let mut i = 0;
+        while ts < 5_000_000.0 {
+            let t = ts / 1_000_000.0;
+            let axis = Vector3::new((t * 1.3).sin(), (t * 0.7).cos(), 0.5);
+            quats.insert(ts as i64, Quat64::from_scaled_axis(axis.normalize() * (t * 2.0).sin() * 1.5));
+            ts += if i % 3 == 0 { 5000.0 } else { 3333.0 };
+            i += 1;
+        }
+        quats
+    }
+
+    #[test]
+    fn resampled_lookup_matches_source() {
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = 5000.0;
+        gyro.quaternions = test_quats();
+        gyro.resample_quaternions();
+        assert!(gyro.resampled_quaternions().is_some());
+
+        let mut reference = gyro.clone();
+        reference.quaternion_resample_rate = 0.0;
+        reference.resample_quaternions();
+        assert!(reference.resampled_quaternions().is_none());
+
+        let mut max_error: f64 = 0.0;
+        let mut ts = -10.0;
+        while ts < 5010.0 {
+            max_error = max_error.max(gyro.org_quat_at_timestamp(ts).angle_to(&reference.org_quat_at_timestamp(ts)));
+            ts += 0.37;
+        }
+        assert!(max_error < MAX_ERROR_RAD, "max error: {} deg", max_error * 180.0 / std::f64::consts::PI);
+    }
+
+    #[test]
+    fn stale_resampling_is_not_used() {
+        let mut quats = test_quats();
+        let resampled = UniformQuats::build(&quats, 1000.0).unwrap();
+        assert!(resampled.matches(&quats));
+        quats.insert(6_000_000, Quat64::identity());
+        assert!(!resampled.matches(&quats));
+    }
+}
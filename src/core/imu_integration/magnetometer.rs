@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Heading drift correction using the magnetometer.
+// The readings are calibrated with an ellipsoid fit over the whole clip (hard and soft iron),
+// and then the integrated yaw is slowly pulled toward the magnetic heading.
+
+use nalgebra::*;
+use super::{ TimeIMU, TimeQuat };
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MagnetometerParams {
+    pub enabled: bool,
+    pub time_constant_s: f64,    // How fast the heading is pulled toward the magnetic estimate
+    pub max_norm_deviation: f64, // Readings with the field magnitude further than this (fraction) from the calibrated norm are ignored
+}
+impl Default for MagnetometerParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            time_constant_s: 20.0,
+            max_norm_deviation: 0.1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MagnetometerCalibration {
+    pub offset: [f64; 3],         // Hard iron
+    pub soft_iron: [[f64; 3]; 3], // Row major
+    pub field_norm: f64,
+}
+
+impl MagnetometerCalibration {
+    const MIN_SAMPLES: usize = 100;
+    const MAX_AXIS_RATIO: f64 = 3.0;
+
+    /// Fits an ellipsoid to the magnetometer readings. Returns `None` when there's not enough data
+    /// or the camera didn't rotate enough to constrain the fit
+    pub fn fit(imu_data: &[TimeIMU]) -> Option<Self> {
+        let samples = imu_data.iter().filter_map(|x| x.magn).map(|m| Vector3::new(m[0], m[1], m[2])).collect::<Vec<_>>();
+        if samples.len() < Self::MIN_SAMPLES { return None; }
+
+        // Normalize for numerical stability
+        let scale = samples.iter().map(|x| x.norm()).sum::<f64>() / samples.len() as f64;
+        if !(scale > 0.0) || !scale.is_finite() { return None; }
+
+        // Least squares fit of Ax² + By² + Cz² + 2Dxy + 2Exz + 2Fyz + 2Gx + 2Hy + 2Iz = 1
+        let mut ata = SMatrix::<f64, 9, 9>::zeros();
+        let mut atb = SVector::<f64, 9>::zeros();
+        for s in &samples {
+            let (x, y, z) = (s.x / scale, s.y / scale, s.z / scale);
+            let row = SVector::<f64, 9>::from_column_slice(&[x * x, y * y, z * z, 2.0 * x * y, 2.0 * x * z, 2.0 * y * z, 2.0 * x, 2.0 * y, 2.0 * z]);
+            ata += row * row.transpose();
+            atb += row;
+        }
+        let p = ata.lu().solve(&atb)?;
+
+        let m = Matrix3::new(p[0], p[3], p[4],
+                             p[3], p[1], p[5],
+                             p[4], p[5], p[2]);
+        let b = Vector3::new(p[6], p[7], p[8]);
+        let center = -(m.try_inverse()? * b);
+        let k = 1.0 + (center.transpose() * m * center)[0];
+        if !(k > 0.0) { return None; }
+
+        let eigen = SymmetricEigen::new(m / k);
+        let (min_ev, max_ev) = eigen.eigenvalues.iter().fold((f64::MAX, f64::MIN), |(a, b), &x| (a.min(x), b.max(x)));
+        if !(min_ev > 0.0) || (max_ev / min_ev).sqrt() > Self::MAX_AXIS_RATIO { return None; }
+
+        // Maps the ellipsoid to a sphere with the mean radius
+        let radius = eigen.eigenvalues.iter().product::<f64>().powf(-1.0 / 6.0);
+        let sqrt_ev = Matrix3::from_diagonal(&eigen.eigenvalues.map(|x| x.sqrt()));
+        let w = eigen.eigenvectors * sqrt_ev * eigen.eigenvectors.transpose() * radius;
+
+        let offset = center * scale;
+        Some(Self {
+            offset: [offset.x, offset.y, offset.z],
+            soft_iron: [
+                [w[(0, 0)], w[(0, 1)], w[(0, 2)]],
+                [w[(1, 0)], w[(1, 1)], w[(1, 2)]],
+                [w[(2, 0)], w[(2, 1)], w[(2, 2)]],
+            ],
+            field_norm: radius * scale,
+        })
+    }
+
+    pub fn apply(&self, m: &[f64; 3]) -> Vector3<f64> {
+        let w = Matrix3::from_row_slice(&self.soft_iron.concat());
+        w * (Vector3::new(m[0], m[1], m[2]) - Vector3::from(self.offset))
+    }
+}
+
+/// Pulls the heading of the integrated orientations toward the magnetic heading.
+/// `imu_data` must be the same data the quaternions were integrated from
+pub fn correct_yaw(quats: &mut TimeQuat, imu_data: &[TimeIMU], calib: &MagnetometerCalibration, params: &MagnetometerParams) {
+    if quats.is_empty() || params.time_constant_s <= 0.0 { return; }
+
+    let heading_of = |q: &UnitQuaternion<f64>, m: &[f64; 3]| -> Option<f64> {
+        let m = calib.apply(m);
+        if ((m.norm() - calib.field_norm) / calib.field_norm).abs() > params.max_norm_deviation { return None; }
+
+        // Body frame as used by the integrators
+        let world = q * Vector3::new(-m[1], m[0], m[2]);
+        if world.xy().norm() < 0.1 * calib.field_norm { return None; } // Too close to vertical for a usable heading
+        Some(world.y.atan2(world.x))
+    };
+    let wrap = |a: f64| (a + std::f64::consts::PI).rem_euclid(std::f64::consts::TAU) - std::f64::consts::PI;
+
+    let mut correction = 0.0;
+    let mut reference: Option<f64> = None;
+    let mut prev_ts: Option<f64> = None;
+    let mut rejected = 0;
+
+    let mut samples = imu_data.iter().filter_map(|x| Some((x.timestamp_ms, x.magn?))).peekable();
+    for (ts_us, q) in quats.iter_mut() {
+        let ts = *ts_us as f64 / 1000.0;
+        let dt = (ts - prev_ts.unwrap_or(ts)) / 1000.0;
+        prev_ts = Some(ts);
+
+        // Use the last magnetometer reading up to this quaternion
+        let mut magn = None;
+        while let Some(&(mts, m)) = samples.peek() {
+            if mts > ts { break; }
+            magn = Some(m);
+            samples.next();
+        }
+
+        let corrected = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), correction) * *q;
+        if let Some(m) = magn {
+            match heading_of(&corrected, &m) {
+                Some(heading) => {
+                    match reference {
+                        None => { reference = Some(heading); },
+                        Some(reference) => {
+                            let err = wrap(reference - heading);
+                            correction = wrap(correction + err * (dt / params.time_constant_s).min(1.0));
+                        }
+                    }
+                }
+                None => { rejected += 1; }
+            }
+        }
+        *q = UnitQuaternion::from_axis_angle(&Vector3::z_axis(), correction) * *q;
+    }
+    if rejected > 0 {
+        log::debug!("Magnetometer: rejected {rejected} samples");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imu(ts: f64, m: Vector3<f64>) -> TimeIMU {
+        TimeIMU { timestamp_ms: ts, gyro: Some([0.0; 3]), accl: Some([0.0, 0.0, 1.0]), magn: Some([m.x, m.y, m.z]) }
+    }
+
+    #[test]
+    fn calibration() {
+        // Field of 50 uT seen from evenly spread directions, through hard and soft iron distortion
+        let distortion = Matrix3::new(1.2, 0.1, 0.0,
+                                      0.1, 0.9, 0.05,
+                                      0.0, 0.05, 1.0);
+        let offset = Vector3::new(10.0, -5.0, 3.0);
+        let n = 500;
+        let data = (0..n).map(|i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / n as f64;
+            let phi = i as f64 * std::f64::consts::PI * (3.0 - 5.0f64.sqrt());
+            let dir = Vector3::new((1.0 - z * z).sqrt() * phi.cos(), (1.0 - z * z).sqrt() * phi.sin(), z);
+            imu(i as f64, distortion * dir * 50.0 + offset)
+        }).collect::<Vec<_>>();
+
+        let calib = MagnetometerCalibration::fit(&data).unwrap();
+        for a in 0..3 {
+            assert!((calib.offset[a] - offset[a]).abs() < 1e-3, "{:?}", calib.offset);
+        }
+        // Calibrated readings are on a sphere
+        for x in &data {
+            let norm = calib.apply(&x.magn.unwrap()).norm();
+            assert!((norm - calib.field_norm).abs() / calib.field_norm < 1e-6, "{norm} {}", calib.field_norm);
+        }
+        assert!((calib.field_norm - 50.0).abs() < 5.0);
+
+        // Not enough samples
+        assert!(MagnetometerCalibration::fit(&data[..50]).is_none());
+        let no_magn = data.iter().map(|x| TimeIMU { magn: None, ..x.clone() }).collect::<Vec<_>>();
+        assert!(MagnetometerCalibration::fit(&no_magn).is_none());
+    }
+
+    #[test]
+    fn heading_correction() {
+        // Static camera, the integrated yaw drifts 0.5°/s for 20s
+        let calib = MagnetometerCalibration { offset: [0.0; 3], soft_iron: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]], field_norm: 50.0 };
+        // North at the heading of 0 with the integrators axes, and pointing down
+        let magn = Vector3::new(0.0, -40.0, -30.0);
+        let drift = 0.5f64.to_radians() / 1000.0; // per ms
+        let mut quats = TimeQuat::new();
+        let mut data = Vec::new();
+        for i in 0..2000 {
+            let ts = i as f64 * 10.0;
+            quats.insert((ts * 1000.0) as i64, UnitQuaternion::from_axis_angle(&Vector3::z_axis(), drift * ts));
+            data.push(imu(ts, magn));
+        }
+        let yaw = |q: &UnitQuaternion<f64>| { let v = q * Vector3::x(); v.y.atan2(v.x).to_degrees() };
+        assert!((yaw(quats.values().last().unwrap()) - 10.0).abs() < 0.1);
+
+        let params = MagnetometerParams { enabled: true, time_constant_s: 2.0, ..Default::default() };
+        let mut corrected = quats.clone();
+        correct_yaw(&mut corrected, &data, &calib, &params);
+        // Follows the drift with a lag of about rate * time constant
+        let last = yaw(corrected.values().last().unwrap());
+        assert!(last.abs() < 2.0, "{last}");
+        assert!(corrected.values().all(|q| yaw(q).abs() < 2.0));
+
+        // Readings with a wrong field strength (eg. magnetic interference) are ignored
+        let disturbed = data.iter().map(|x| imu(x.timestamp_ms, magn * 1.5)).collect::<Vec<_>>();
+        let mut unchanged = quats.clone();
+        correct_yaw(&mut unchanged, &disturbed, &calib, &params);
+        assert!((yaw(unchanged.values().last().unwrap()) - 10.0).abs() < 0.1);
+    }
+}
@@ -4,6 +4,7 @@
 mod complementary_v2;
 mod complementary;
 mod vqf;
+pub mod magnetometer;
 
 use std::collections::BTreeMap;
 use nalgebra::*;
@@ -12,6 +13,59 @@ use ahrs::{Ahrs, Madgwick, Mahony};
 
 // TODO: Magnetometer calculations are disabled in Complementary and VQF. Figure out what's wrong and enable them
 
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum IntegrationMethod {
+    FileQuaternions,
+    Complementary { alpha: f64 },
+    VQF,
+    GyroOnly,
+    GyroAccel,
+    Mahony { kp: f64, ki: f64 },
+    Madgwick { beta: f64 },
+}
+impl Default for IntegrationMethod {
+    fn default() -> Self { Self::VQF }
+}
+impl IntegrationMethod {
+    /// Index as used in the UI and in the project file
+    pub fn from_index(index: usize) -> Self {
+        match index {
+            0 => Self::FileQuaternions,
+            1 => Self::Complementary { alpha: ComplementaryIntegrator::DEFAULT_ALPHA },
+            3 => Self::GyroOnly,
+            4 => Self::GyroAccel,
+            5 => Self::Mahony { kp: MahonyIntegrator::DEFAULT_KP, ki: MahonyIntegrator::DEFAULT_KI },
+            6 => Self::Madgwick { beta: MadgwickIntegrator::DEFAULT_BETA },
+            _ => Self::VQF,
+        }
+    }
+    pub fn index(&self) -> usize {
+        match self {
+            Self::FileQuaternions    => 0,
+            Self::Complementary { .. } => 1,
+            Self::VQF                => 2,
+            Self::GyroOnly           => 3,
+            Self::GyroAccel          => 4,
+            Self::Mahony { .. }      => 5,
+            Self::Madgwick { .. }    => 6,
+        }
+    }
+    pub fn uses_accelerometer(&self) -> bool {
+        !matches!(self, Self::FileQuaternions | Self::GyroOnly)
+    }
+    pub fn integrate(&self, imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        match *self {
+            Self::FileQuaternions           => TimeQuat::new(),
+            Self::Complementary { alpha }   => ComplementaryIntegrator  ::integrate_with_alpha(imu_data, duration_ms, alpha),
+            Self::VQF                       => VQFIntegrator            ::integrate(imu_data, duration_ms),
+            Self::GyroOnly                  => SimpleGyroIntegrator     ::integrate(imu_data, duration_ms),
+            Self::GyroAccel                 => SimpleGyroAccelIntegrator::integrate(imu_data, duration_ms),
+            Self::Mahony { kp, ki }         => MahonyIntegrator         ::integrate_with_gains(imu_data, duration_ms, kp, ki),
+            Self::Madgwick { beta }         => MadgwickIntegrator       ::integrate_with_beta(imu_data, duration_ms, beta),
+        }
+    }
+}
+
 pub trait GyroIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat;
 }
@@ -59,11 +113,22 @@ use complementary_v2::ComplementaryFilterV2;
 
 impl GyroIntegrator for ComplementaryIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        Self::integrate_with_alpha(imu_data, duration_ms, Self::DEFAULT_ALPHA)
+    }
+}
+impl ComplementaryIntegrator {
+    pub const DEFAULT_ALPHA: f64 = 0.0004;
+
+    /// `alpha` is the accelerometer gain of the filter, in range [0, 1]
+    pub fn integrate_with_alpha(imu_data: &[TimeIMU], duration_ms: f64, alpha: f64) -> TimeQuat {
         if imu_data.is_empty() { return BTreeMap::new(); }
         let mut quats = BTreeMap::new();
         let sample_time_ms = duration_ms / imu_data.len() as f64;
 
         let mut f = ComplementaryFilterV2::default();
+        if !f.set_gain_acc(alpha) {
+            log::warn!("Invalid complementary filter gain {alpha}, using {}", f.gain_acc);
+        }
         // Limit initial settle time for short videos
         f.set_initial_settle_time((duration_ms / 1000.0 * 0.05).min(2.0));
         //f.set_orientation(init_pos_q.scalar(), -init_pos_q.vector()[0], -init_pos_q.vector()[1], -init_pos_q.vector()[2]);
@@ -239,13 +304,21 @@ impl GyroIntegrator for SimpleGyroAccelIntegrator {
 
 impl GyroIntegrator for MahonyIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        Self::integrate_with_gains(imu_data, duration_ms, Self::DEFAULT_KP, Self::DEFAULT_KI)
+    }
+}
+impl MahonyIntegrator {
+    pub const DEFAULT_KP: f64 = 0.5;
+    pub const DEFAULT_KI: f64 = 0.0;
+
+    pub fn integrate_with_gains(imu_data: &[TimeIMU], duration_ms: f64, kp: f64, ki: f64) -> TimeQuat {
         if imu_data.is_empty() { return BTreeMap::new(); }
 
         let mut quats = BTreeMap::new();
         let init_pos = UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0);
         let sample_time_s = duration_ms / 1000.0 / imu_data.len() as f64;
 
-        let mut ahrs = Mahony::new_with_quat(sample_time_s, 0.5, 0.0, init_pos);
+        let mut ahrs = Mahony::new_with_quat(sample_time_s, kp, ki, init_pos);
         let mut prev_time = imu_data[0].timestamp_ms - sample_time_s;
         for v in imu_data {
             if let Some(g) = v.gyro.as_ref() {
@@ -283,13 +356,20 @@ impl GyroIntegrator for MahonyIntegrator {
 
 impl GyroIntegrator for MadgwickIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        Self::integrate_with_beta(imu_data, duration_ms, Self::DEFAULT_BETA)
+    }
+}
+impl MadgwickIntegrator {
+    pub const DEFAULT_BETA: f64 = 0.02;
+
+    pub fn integrate_with_beta(imu_data: &[TimeIMU], duration_ms: f64, beta: f64) -> TimeQuat {
         if imu_data.is_empty() { return BTreeMap::new(); }
 
         let mut quats = BTreeMap::new();
         let init_pos = UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0);
         let sample_time_s = duration_ms / 1000.0 / imu_data.len() as f64;
 
-        let mut ahrs = Madgwick::new_with_quat(sample_time_s, 0.02, init_pos);
+        let mut ahrs = Madgwick::new_with_quat(sample_time_s, beta, init_pos);
         let mut prev_time = imu_data[0].timestamp_ms - sample_time_s;
         for v in imu_data {
             if let Some(g) = v.gyro.as_ref() {
@@ -320,3 +400,51 @@ impl GyroIntegrator for MadgwickIntegrator {
         quats
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stationary camera tilted in pitch and roll. Gyro reads zero, accelerometer reads only gravity
+    fn tilted_static_imu(tilt: &Quat64, rate_hz: f64, duration_s: f64) -> Vec<TimeIMU> {
+        // Body frame as used by the integrators is (-y, x, z) of the raw data
+        let acc_body = tilt.inverse() * Vector3::new(0.0, 0.0, 1.0);
+        let raw_acc = [acc_body.y, -acc_body.x, acc_body.z];
+        (0..(rate_hz * duration_s) as usize).map(|i| TimeIMU {
+            // Slightly jittered timestamps
+            timestamp_ms: i as f64 * 1000.0 / rate_hz + if i % 3 == 0 { 0.7 } else { 0.0 },
+            gyro: Some([0.0, 0.0, 0.0]),
+            accl: Some(raw_acc),
+            magn: None
+        }).collect()
+    }
+
+    #[test]
+    fn fused_methods_converge_to_tilt() {
+        let tilt = Quat64::from_euler_angles(25.0 * DEG2RAD, -10.0 * DEG2RAD, 0.0);
+        let imu = tilted_static_imu(&tilt, 100.0, 120.0);
+        let duration_ms = imu.last().unwrap().timestamp_ms + 10.0;
+        let acc_body = tilt.inverse() * Vector3::new(0.0, 0.0, 1.0);
+
+        for method in [
+            IntegrationMethod::from_index(1),
+            IntegrationMethod::Mahony { kp: 0.5, ki: 0.0 },
+            IntegrationMethod::Madgwick { beta: 0.1 },
+        ] {
+            let quats = method.integrate(&imu, duration_ms);
+            assert_eq!(quats.len(), imu.len());
+            let q = quats.values().next_back().unwrap();
+            let up = q * acc_body;
+            let error_deg = up.angle(&Vector3::new(0.0, 0.0, 1.0)) / DEG2RAD;
+            assert!(error_deg < 1.0, "{method:?} tilt error: {error_deg:.3} deg");
+        }
+    }
+
+    #[test]
+    fn index_roundtrip() {
+        for i in 0..7 {
+            assert_eq!(IntegrationMethod::from_index(i).index(), i);
+        }
+        assert_eq!(IntegrationMethod::from_index(100), IntegrationMethod::VQF);
+    }
+}
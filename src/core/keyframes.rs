@@ -27,6 +27,7 @@ define_keyframes! {
     Fov,                         "#8ee6ea", "FOV",                              |v| format!("{:.2}", v),
     VideoRotation,               "#eae38e", "Video rotation",                   |v| format!("{:.1}°", v),
     ZoomingSpeed,                "#32e595", "Zooming speed",                    |v| format!("{:.2}s", v),
+    ZoomingLookAhead,            "#2bc986", "Zooming look-ahead",               |v| format!("{:.2}s", v),
     ZoomingCenterX,              "#6fefb6", "Zooming center offset X",          |v| format!("{:.0}%", v * 100.0),
     ZoomingCenterY,              "#5ddba2", "Zooming center offset Y",          |v| format!("{:.0}%", v * 100.0),
     MaxZoom,                     "#184CC5", "Zoom limit",                       |v| format!("{:.0}%", v),
@@ -38,10 +39,21 @@ define_keyframes! {
     AdditionalTranslationZ,      "#e98fbc", "Additional 3D translation Z",      |v| format!("{:.0}px", v),
     BackgroundMargin,            "#6e5ddb", "Background margin",                |v| format!("{:.0}%", v),
     BackgroundFeather,           "#9d93e1", "Background feather",               |v| format!("{:.0}%", v),
+    BackgroundColorR,            "#e05d5d", "Background red",                   |v| format!("{:.0}%", v * 100.0),
+    BackgroundColorG,            "#5de07a", "Background green",                 |v| format!("{:.0}%", v * 100.0),
+    BackgroundColorB,            "#5d8be0", "Background blue",                  |v| format!("{:.0}%", v * 100.0),
+    BackgroundAlpha,             "#b3b3b3", "Background opacity",               |v| format!("{:.0}%", v * 100.0),
     LockHorizonAmount,           "#ed7789", "Horizon lock amount",              |v| format!("{:.0}%", v),
     LockHorizonRoll,             "#e86176", "Horizon lock roll correction",     |v| format!("{:.1}°", v),
+    HorizonCompensationPitch,    "#d94f64", "Horizon compensation pitch",       |v| format!("{:.2}°", v),
+    HorizonCompensationRoll,     "#c73d52", "Horizon compensation roll",        |v| format!("{:.2}°", v),
+    AxisLockPitch,               "#f0a35e", "Locked pitch",                     |v| format!("{:.1}°", v),
+    AxisLockYaw,                 "#e8934a", "Locked yaw",                       |v| format!("{:.1}°", v),
+    AxisLockRoll,                "#df8336", "Locked roll",                      |v| format!("{:.1}°", v),
     LensCorrectionStrength,      "#e8ae61", "Lens correction strength",         |v| format!("{:.0}%", v * 100.0),
     LightRefractionCoeff,        "#CD7F19", "Light refraction coefficient",     |v| format!("{:.3}",  v),
+    RollingShutterStrength,      "#b5651d", "Rolling shutter correction strength", |v| format!("{:.0}%", v * 100.0),
+    ResidualCorrectionStrength,  "#a0522d", "Residual wobble correction strength", |v| format!("{:.0}%", v * 100.0),
 
     SmoothingParamTimeConstant,  "#94ea8e", "Max smoothness",                   |v| format!("{:.2}", v),
     SmoothingParamTimeConstant2, "#89df82", "Max smoothness at high velocity",  |v| format!("{:.2}", v),
@@ -49,17 +61,70 @@ define_keyframes! {
     SmoothingParamPitch,         "#59c451", "Pitch smoothness",                 |v| format!("{:.2}", v),
     SmoothingParamRoll,          "#51c485", "Roll smoothness",                  |v| format!("{:.2}", v),
     SmoothingParamYaw,           "#88c451", "Yaw smoothness",                   |v| format!("{:.2}", v),
+    SmoothingParamVelocityThreshold, "#a5e89f", "Responsiveness threshold",     |v| format!("{:.0}°/s", v),
+    SmoothingParamAdaptationSpeed,   "#6bd163", "Adaptation speed",             |v| format!("{:.2}s", v),
+    SmoothingParamDriftTimeConstant, "#5fb858", "Tripod re-centering time",     |v| format!("{:.1}s", v),
 
     VideoSpeed,                  "#f6e926", "Video speed",                      |v| format!("{:.1}%", v * 100.0),
 }
 
+/// Whether the values of `a` can be used for `b` as they are, ie. they have the same unit and scale
+pub fn keyframes_compatible(a: &KeyframeType, b: &KeyframeType) -> bool {
+    let unit = |kf: &KeyframeType| {
+        let s = keyframe_format_value(kf, 1.0);
+        let number_len = s.find(|c: char| !c.is_ascii_digit() && c != '.' && c != '-').unwrap_or(s.len());
+        (s[..number_len].parse::<f64>().unwrap_or_default(), s[number_len..].to_owned())
+    };
+    unit(a) == unit(b)
+}
+
+/// Keyframes of each of the changed types, after the change
+pub type KeyframeSets = BTreeMap<KeyframeType, BTreeMap<i64, Keyframe>>;
+
+/// What happens with the existing keyframes of the imported types
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, ::serde::Serialize, ::serde::Deserialize)]
+pub enum KeyframeImportPolicy {
+    #[default]
+    Replace,      // Existing keyframes are removed
+    Merge,        // Imported keyframes overwrite the existing ones at the same timestamp
+    KeepExisting, // Existing keyframes are kept at the same timestamp
+}
+
+// Standalone keyframes file, for reusing the keyframes in other clips.
+// `time` is in microseconds, or 0 - 1 over the trim range when `normalized`
+#[derive(::serde::Serialize, ::serde::Deserialize)]
+struct KeyframesDocument {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    normalized: bool,
+    keyframes: BTreeMap<String, Vec<ExportedKeyframe>>,
+}
+#[derive(::serde::Serialize, ::serde::Deserialize)]
+struct ExportedKeyframe {
+    time: f64,
+    value: f64,
+    #[serde(default)]
+    easing: Easing,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bezier: Option<[f64; 4]>,
+}
+
+#[derive(Default, Debug, Clone, ::serde::Serialize)]
+pub struct KeyframeImport {
+    pub imported: Vec<KeyframeType>,
+    pub unknown: Vec<String>, // Parameters in the file which don't exist in this version
+}
+
 #[derive(Default, Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, ::serde::Serialize, ::serde::Deserialize)]
 pub enum Easing {
     #[default]
     NoEasing, // Linear
     EaseIn,
     EaseOut,
-    EaseInOut
+    EaseInOut,
+    Hold,   // The value doesn't change until the next keyframe
+    Bezier, // Cubic bezier with the `bezier` handles of the keyframe, until the next keyframe
 }
 
 #[derive(Debug, Copy, Clone, Default, ::serde::Serialize, ::serde::Deserialize)]
@@ -67,17 +132,86 @@ pub struct Keyframe {
     #[serde(default = "default_id")]
     pub id: u32,
     pub value: f64,
-    pub easing: Easing
+    #[serde(default)]
+    pub easing: Easing,
+    // (x1, y1, x2, y2) control points in the normalized time and value of the segment to the next keyframe, like the CSS `cubic-bezier`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bezier: Option<[f64; 4]>,
 }
 fn default_id() -> u32 { fastrand::u32(1..) }
 
+const BEZIER_ITERATIONS: usize = 40;
+
+impl Keyframe {
+    /// Value between this keyframe and the `next` one, at `alpha` (0 - 1) of the time between them
+    pub fn interpolate_to(&self, next: &Keyframe, alpha: f64) -> f64 {
+        match (self.easing, self.bezier) {
+            (Easing::Hold, _) => self.value,
+            (Easing::Bezier, Some(handles)) => self.value + (next.value - self.value) * cubic_bezier(&handles, alpha),
+            _ => Easing::get(&self.easing, &next.easing, alpha).interpolate(self.value, next.value, alpha)
+        }
+    }
+}
+
+/// Cubic bezier from (0, 0) to (1, 1) with the control points (x1, y1) and (x2, y2), evaluated at the time `x`.
+/// The times of the control points are clamped to 0 - 1, so the time along the curve always increases and there's one value for each time.
+/// The values aren't limited, so the curve can overshoot
+pub fn cubic_bezier(handles: &[f64; 4], x: f64) -> f64 {
+    let (x1, y1, x2, y2) = (handles[0].clamp(0.0, 1.0), handles[1], handles[2].clamp(0.0, 1.0), handles[3]);
+    let curve = |p1: f64, p2: f64, t: f64| 3.0 * (1.0 - t) * (1.0 - t) * t * p1 + 3.0 * (1.0 - t) * t * t * p2 + t * t * t;
+
+    let x = x.clamp(0.0, 1.0);
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..BEZIER_ITERATIONS {
+        let mid = (low + high) / 2.0;
+        if curve(x1, x2, mid) < x { low = mid; } else { high = mid; }
+    }
+    curve(y1, y2, (low + high) / 2.0)
+}
+
 #[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct KeyframeManager {
     keyframes: BTreeMap<KeyframeType, BTreeMap<i64, Keyframe>>,
     gyro_offsets: BTreeMap<i64, f64>,
+    #[serde(default)]
+    gyro_offset_lookup: (crate::gyro_source::OffsetInterpolation, Option<(f64, f64)>),
     #[serde(skip)]
     custom_provider: Option<Arc<Mutex<dyn FnMut(&KeyframeManager, &KeyframeType, f64) -> Option<f64> + Send + 'static>>>,
     pub timestamp_scale: Option<f64>,
+    #[serde(skip)]
+    pub frame_grid: Option<FrameGrid>, // When set, new and moved keyframes are snapped to the nearest frame
+}
+
+/// Timestamps of the frames in the keyframe timeline, for snapping the keyframes to the frames
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameGrid {
+    /// Constant frame rate of the video file. With the frame rate overridden, the frames are rendered at `timestamp_at_frame(frame, fps * scale)`
+    /// and looked up in the keyframes at that timestamp times the scale, which is rounded the same way here
+    Constant { fps: f64, timestamp_scale: Option<f64> },
+    /// Timestamps of every frame, in microseconds, sorted. For variable frame rate videos
+    Variable(Vec<i64>),
+}
+
+impl FrameGrid {
+    /// Timestamp of the nearest frame. When exactly between two frames, the earlier one
+    pub fn snap(&self, timestamp_us: i64) -> i64 {
+        match self {
+            Self::Constant { fps, timestamp_scale } => {
+                if *fps <= 0.0 { return timestamp_us; }
+                let scale = timestamp_scale.unwrap_or(1.0);
+                let frame = (timestamp_us as f64 * fps / 1_000_000.0).round() as i32;
+                (crate::timestamp_at_frame(frame, fps * scale) * 1000.0 * scale).round() as i64
+            }
+            Self::Variable(timestamps) => {
+                let i = timestamps.partition_point(|x| *x < timestamp_us);
+                match (i.checked_sub(1).and_then(|i| timestamps.get(i)), timestamps.get(i)) {
+                    (Some(prev), Some(next)) => if timestamp_us - prev <= next - timestamp_us { *prev } else { *next },
+                    (Some(x), None) | (None, Some(x)) => *x,
+                    (None, None) => timestamp_us
+                }
+            }
+        }
+    }
 }
 
 impl KeyframeManager {
@@ -95,13 +229,17 @@ impl KeyframeManager {
     pub fn set_custom_provider(&mut self, cb: impl FnMut(&KeyframeManager, &KeyframeType, f64) -> Option<f64> + Send + 'static) {
         self.custom_provider = Some(Arc::new(Mutex::new(cb)));
     }
+    fn snap_to_frame(&self, timestamp_us: i64) -> i64 {
+        self.frame_grid.as_ref().map(|grid| grid.snap(timestamp_us)).unwrap_or(timestamp_us)
+    }
     pub fn set(&mut self, typ: &KeyframeType, mut timestamp_us: i64, value: f64) {
         let kf = Keyframe {
             id: default_id(),
             value,
-            easing: Easing::EaseInOut
+            easing: Easing::EaseInOut,
+            bezier: None,
         };
-        timestamp_us = self.get_closest_timestamp(typ, timestamp_us);
+        timestamp_us = self.get_closest_timestamp(typ, self.snap_to_frame(timestamp_us));
         if let Some(x) = self.keyframes.get_mut(typ) {
             match x.entry(timestamp_us) {
                 Entry::Occupied(o) => { o.into_mut().value = value; }
@@ -119,11 +257,24 @@ impl KeyframeManager {
             }
         }
     }
+    /// Sets the bezier handles of the segment starting at the keyframe, see `Keyframe::bezier`
+    pub fn set_bezier(&mut self, typ: &KeyframeType, mut timestamp_us: i64, handles: [f64; 4]) {
+        timestamp_us = self.get_closest_timestamp(typ, timestamp_us);
+        if let Some(kf) = self.keyframes.get_mut(typ).and_then(|x| x.get_mut(&timestamp_us)) {
+            kf.easing = Easing::Bezier;
+            kf.bezier = Some([handles[0].clamp(0.0, 1.0), handles[1], handles[2].clamp(0.0, 1.0), handles[3]]);
+        }
+    }
+    pub fn bezier(&self, typ: &KeyframeType, mut timestamp_us: i64) -> Option<[f64; 4]> {
+        timestamp_us = self.get_closest_timestamp(typ, timestamp_us);
+        self.keyframes.get(typ)?.get(&timestamp_us)?.bezier
+    }
     pub fn easing(&self, typ: &KeyframeType, mut timestamp_us: i64) -> Option<Easing> {
         timestamp_us = self.get_closest_timestamp(typ, timestamp_us);
         Some(self.keyframes.get(typ)?.get(&timestamp_us)?.easing)
     }
     pub fn set_timestamp(&mut self, typ: &KeyframeType, id: u32, timestamp_us: i64) {
+        let timestamp_us = self.snap_to_frame(timestamp_us);
         if let Some(x) = self.keyframes.get_mut(typ) {
             let mut copy = None;
             for (ts, kf) in x.iter() {
@@ -180,6 +331,8 @@ impl KeyframeManager {
                 if let Some(&first_ts) = keyframes.keys().next() {
                     if let Some(&last_ts) = keyframes.keys().next_back() {
                         let timestamp_us = (timestamp_ms * 1000.0 * self.timestamp_scale.unwrap_or(1.0)).round() as i64;
+                        // A keyframe on the frame takes effect on that frame, also when the frame timestamp was rounded to the next microsecond
+                        let timestamp_us = keyframes.range(timestamp_us - 1..=timestamp_us + 1).next().map(|x| *x.0).unwrap_or(timestamp_us);
                         let lookup_ts = timestamp_us.min(last_ts).max(first_ts);
                         if let Some(offs1) = keyframes.range(..=lookup_ts).next_back() {
                             if *offs1.0 == lookup_ts {
@@ -188,8 +341,7 @@ impl KeyframeManager {
                             if let Some(offs2) = keyframes.range(lookup_ts..).next() {
                                 let time_delta = (offs2.0 - offs1.0) as f64;
                                 let alpha = (timestamp_us - offs1.0) as f64 / time_delta;
-                                let e = Easing::get(&offs1.1.easing, &offs2.1.easing, alpha);
-                                return Some(e.interpolate(offs1.1.value, offs2.1.value, alpha));
+                                return Some(offs1.1.interpolate_to(offs2.1, alpha));
                             }
                         }
                     }
@@ -200,11 +352,25 @@ impl KeyframeManager {
         }
     }
 
+    fn gyro_offset_at(&self, timestamp_ms: f64) -> f64 {
+        let (mode, line) = self.gyro_offset_lookup;
+        crate::gyro_source::interpolate_offset(&self.gyro_offsets, mode, line, timestamp_ms)
+    }
+
     pub fn value_at_gyro_timestamp(&self, typ: &KeyframeType, mut timestamp_ms: f64) -> Option<f64> {
-        timestamp_ms += GyroSource::offset_at_timestamp(&self.gyro_offsets, timestamp_ms);
+        timestamp_ms += self.gyro_offset_at(timestamp_ms);
         self.value_at_video_timestamp(typ, timestamp_ms)
     }
 
+    /// Whether the gyro timestamp is between the first and the last keyframe of `typ`
+    pub fn is_within_keyframes(&self, typ: &KeyframeType, mut timestamp_ms: f64) -> bool {
+        timestamp_ms += self.gyro_offset_at(timestamp_ms);
+        let Some(keyframes) = self.keyframes.get(typ) else { return false; };
+        let (Some(first), Some(last)) = (keyframes.keys().next(), keyframes.keys().next_back()) else { return false; };
+        let timestamp_us = (timestamp_ms * 1000.0 * self.timestamp_scale.unwrap_or(1.0)).round() as i64;
+        timestamp_us >= *first && timestamp_us <= *last
+    }
+
     pub fn get_keyframes(&self, typ: &KeyframeType) -> Option<&BTreeMap<i64, Keyframe>> {
         self.keyframes.get(typ)
     }
@@ -213,11 +379,109 @@ impl KeyframeManager {
         self.keyframes.iter().filter(|(_, v)| !v.is_empty()).map(|(k, _)| k).collect()
     }
 
+    fn keyframe_sets(&self, types: &[KeyframeType]) -> KeyframeSets {
+        types.iter().map(|typ| (*typ, self.keyframes.get(typ).cloned().unwrap_or_default())).collect()
+    }
+    /// Moves all existing keyframes to the nearest frame, eg. for projects made before the snapping was enabled
+    pub fn quantize_all(&mut self, grid: &FrameGrid) -> KeyframeSets {
+        let types = self.keyframes.keys().copied().collect::<Vec<_>>();
+        self.retime(&types, |ts| grid.snap(ts))
+    }
+    fn retime(&mut self, types: &[KeyframeType], f: impl Fn(i64) -> i64) -> KeyframeSets {
+        for typ in types {
+            if let Some(x) = self.keyframes.get_mut(typ) {
+                // In the order of the timestamps, so when two keyframes end up at the same timestamp, the later one is kept
+                *x = std::mem::take(x).into_iter().map(|(ts, kf)| (f(ts), kf)).collect();
+            }
+        }
+        self.keyframe_sets(types)
+    }
+    /// Moves all keyframes of `types` by `delta_us`
+    pub fn shift(&mut self, types: &[KeyframeType], delta_us: i64) -> KeyframeSets {
+        self.retime(types, |ts| ts.saturating_add(delta_us))
+    }
+    /// Scales the timestamps of all keyframes of `types` around `anchor_us`, eg. after changing the clip speed.
+    /// Keyframes which end up at the same timestamp are merged into the later one
+    pub fn scale(&mut self, types: &[KeyframeType], anchor_us: i64, factor: f64) -> KeyframeSets {
+        if factor <= 0.0 || !factor.is_finite() { return self.keyframe_sets(types); }
+        self.retime(types, |ts| anchor_us + ((ts - anchor_us) as f64 * factor).round() as i64)
+    }
+    /// Replaces the keyframes of `to` with the ones of `from`, with the values mapped by `map`.
+    /// `None` if the types aren't compatible or `from` doesn't have any keyframes
+    pub fn copy_type(&mut self, from: &KeyframeType, to: &KeyframeType, map: impl Fn(f64) -> f64) -> Option<KeyframeSets> {
+        if !keyframes_compatible(from, to) { return None; }
+        let source = self.keyframes.get(from).filter(|x| !x.is_empty())?;
+        let copy = source.iter().map(|(ts, kf)| (*ts, Keyframe { id: default_id(), value: map(kf.value), ..*kf })).collect();
+        self.keyframes.insert(*to, copy);
+        Some(self.keyframe_sets(&[*to]))
+    }
+    /// Sets the keyframes of each type in `sets`, eg. to undo one of the operations above
+    pub fn replace(&mut self, sets: &KeyframeSets) {
+        for (typ, keyframes) in sets {
+            self.keyframes.insert(*typ, keyframes.clone());
+        }
+    }
+    /// Standalone JSON with the keyframes of `types`. With `normalized_range_us`, the timestamps are saved as 0 - 1 over that range
+    pub fn export_json(&self, types: &[KeyframeType], normalized_range_us: Option<(i64, i64)>) -> serde_json::Value {
+        let time = |ts: i64| match normalized_range_us {
+            Some((from, to)) => (ts - from) as f64 / (to - from).max(1) as f64,
+            None => ts as f64
+        };
+        let keyframes = types.iter().filter_map(|typ| {
+            let list = self.keyframes.get(typ).filter(|x| !x.is_empty())?.iter().map(|(ts, kf)| ExportedKeyframe {
+                time: time(*ts),
+                value: kf.value,
+                easing: kf.easing,
+                bezier: kf.bezier,
+            }).collect();
+            Some((typ.to_string(), list))
+        }).collect();
+        serde_json::to_value(KeyframesDocument { version: 1, normalized: normalized_range_us.is_some(), keyframes }).unwrap_or(serde_json::Value::Null)
+    }
+    /// Imports the output of `export_json`. Normalized timestamps are mapped onto `range_us`, ie. the trim range of this clip
+    pub fn import_json(&mut self, v: &serde_json::Value, range_us: (i64, i64), policy: KeyframeImportPolicy) -> serde_json::Result<KeyframeImport> {
+        let doc: KeyframesDocument = serde_json::from_value(v.clone())?;
+        let mut ret = KeyframeImport::default();
+        for (name, list) in doc.keyframes {
+            let Ok(typ) = KeyframeType::from_str(&name) else {
+                ret.unknown.push(name);
+                continue;
+            };
+            let incoming = list.into_iter().map(|x| {
+                let timestamp_us = self.snap_to_frame(if doc.normalized { range_us.0 + (x.time * (range_us.1 - range_us.0) as f64).round() as i64 } else { x.time.round() as i64 });
+                // Keyframes closer than 1 ms are the same keyframe, like with `set`
+                let timestamp_us = if policy == KeyframeImportPolicy::Replace { timestamp_us } else { self.get_closest_timestamp(&typ, timestamp_us) };
+                (timestamp_us, Keyframe { id: default_id(), value: x.value, easing: x.easing, bezier: x.bezier })
+            }).collect::<Vec<_>>();
+
+            let existing = self.keyframes.entry(typ).or_default();
+            match policy {
+                KeyframeImportPolicy::Replace      => *existing = incoming.into_iter().collect(),
+                KeyframeImportPolicy::Merge        => existing.extend(incoming),
+                KeyframeImportPolicy::KeepExisting => for (ts, kf) in incoming { existing.entry(ts).or_insert(kf); },
+            }
+            ret.imported.push(typ);
+        }
+        Ok(ret)
+    }
+    /// Removes the keyframes of `types` between `from_us` and `to_us`, inclusive
+    pub fn remove_range(&mut self, types: &[KeyframeType], from_us: i64, to_us: i64) -> KeyframeSets {
+        for typ in types {
+            if let Some(x) = self.keyframes.get_mut(typ) {
+                x.retain(|ts, _| *ts < from_us || *ts > to_us);
+            }
+        }
+        self.keyframe_sets(types)
+    }
+
     pub fn update_gyro(&mut self, gyro: &GyroSource) {
         self.gyro_offsets = gyro.get_offsets().clone();
+        self.gyro_offset_lookup = gyro.offset_lookup();
     }
     pub fn clear(&mut self) {
+        let frame_grid = self.frame_grid.take();
         *self = Self::new();
+        self.frame_grid = frame_grid;
     }
 
     pub fn clear_type(&mut self, key: &KeyframeType) {
@@ -265,6 +529,10 @@ impl FromStr for KeyframeType {
 impl ToString for KeyframeType {
     fn to_string(&self) -> String { format!("{:?}", self) }
 }
+impl FromStr for KeyframeImportPolicy {
+    type Err = serde_json::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> { serde_json::from_str(&format!("\"{}\"", s)) }
+}
 impl FromStr for Easing {
     type Err = serde_json::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> { serde_json::from_str(&format!("\"{}\"", s)) }
@@ -297,3 +565,228 @@ impl Easing {
         a * (1.0 - x) + b * x
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bezier_with_overshoot() {
+        // Back and forth in value, while the time always goes forward
+        let handles = [0.3, 1.6, 0.7, -0.6];
+        assert!(cubic_bezier(&handles, 0.0).abs() < 1e-9 && (cubic_bezier(&handles, 1.0) - 1.0).abs() < 1e-9);
+        let values = (0..=100).map(|i| cubic_bezier(&handles, i as f64 / 100.0)).collect::<Vec<_>>();
+        assert!(values.iter().any(|v| *v > 1.0) && values.iter().any(|v| *v < 0.0));
+        // One value for each time, without jumps
+        assert!(values.iter().zip(values.iter().skip(1)).all(|(a, b)| (a - b).abs() < 0.1));
+        // Linear with the handles on the diagonal
+        assert!((cubic_bezier(&[0.25, 0.25, 0.75, 0.75], 0.3) - 0.3).abs() < 1e-9);
+        // Times out of range are clamped
+        assert!(cubic_bezier(&[-1.0, 0.0, 2.0, 1.0], 0.5).is_finite());
+
+        let mut kf = KeyframeManager::new();
+        kf.set(&KeyframeType::Fov, 0, 1.0);
+        kf.set(&KeyframeType::Fov, 1_000_000, 2.0);
+        kf.set_bezier(&KeyframeType::Fov, 0, handles);
+        assert_eq!(kf.easing(&KeyframeType::Fov, 0), Some(Easing::Bezier));
+        let v = kf.value_at_video_timestamp(&KeyframeType::Fov, 300.0).unwrap();
+        assert!((v - (1.0 + cubic_bezier(&handles, 0.3))).abs() < 1e-9);
+    }
+
+    #[test]
+    fn consecutive_holds() {
+        let mut kf = KeyframeManager::new();
+        for (ts, v) in [(0, 1.0), (1_000_000, 2.0), (2_000_000, 3.0)] {
+            kf.set(&KeyframeType::VideoRotation, ts, v);
+        }
+        kf.set_easing(&KeyframeType::VideoRotation, 0, Easing::Hold);
+        kf.set_easing(&KeyframeType::VideoRotation, 1_000_000, Easing::Hold);
+        let at = |ms: f64| kf.value_at_video_timestamp(&KeyframeType::VideoRotation, ms).unwrap();
+        assert_eq!(at(0.0), 1.0);
+        assert_eq!(at(999.0), 1.0);
+        assert_eq!(at(1000.0), 2.0);
+        assert_eq!(at(1999.0), 2.0);
+        assert_eq!(at(2000.0), 3.0);
+        assert_eq!(at(5000.0), 3.0);
+    }
+
+    #[test]
+    fn old_projects_are_linear() {
+        let mut kf = KeyframeManager::new();
+        kf.deserialize(&serde_json::json!({ "Fov": { "0": { "value": 1.0 }, "1000000": { "value": 2.0 } } }));
+        assert_eq!(kf.easing(&KeyframeType::Fov, 0), Some(Easing::NoEasing));
+        assert!((kf.value_at_video_timestamp(&KeyframeType::Fov, 250.0).unwrap() - 1.25).abs() < 1e-9);
+        // Handles are saved only when set
+        assert!(!kf.serialize().to_string().contains("bezier"));
+    }
+
+    fn fov_ramp() -> KeyframeManager {
+        let mut kf = KeyframeManager::new();
+        kf.set(&KeyframeType::Fov, 2_000_000, 1.0);
+        kf.set(&KeyframeType::Fov, 4_000_000, 1.5);
+        kf.set_easing(&KeyframeType::Fov, 2_000_000, Easing::Hold);
+        kf.set(&KeyframeType::Fov, 6_000_000, 2.0);
+        kf.set_bezier(&KeyframeType::Fov, 4_000_000, [0.2, 0.0, 0.3, 1.2]);
+        kf.set(&KeyframeType::VideoRotation, 3_000_000, 10.0);
+        kf
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let org = fov_ramp();
+        let json = org.export_json(&[KeyframeType::Fov, KeyframeType::VideoRotation, KeyframeType::ZoomingCenterX], None);
+        assert_eq!(json["keyframes"].as_object().unwrap().len(), 2);
+
+        let mut kf = KeyframeManager::new();
+        kf.set(&KeyframeType::Fov, 0, 5.0);
+        let result = kf.import_json(&json, (0, 1), KeyframeImportPolicy::Replace).unwrap();
+        assert_eq!(result.imported, vec![KeyframeType::Fov, KeyframeType::VideoRotation]);
+        assert!(result.unknown.is_empty());
+        for typ in [KeyframeType::Fov, KeyframeType::VideoRotation] {
+            let (a, b) = (org.get_keyframes(&typ).unwrap(), kf.get_keyframes(&typ).unwrap());
+            assert_eq!(a.keys().collect::<Vec<_>>(), b.keys().collect::<Vec<_>>());
+            for (x, y) in a.values().zip(b.values()) {
+                assert!(x.value == y.value && x.easing == y.easing && x.bezier == y.bezier);
+            }
+        }
+        for ms in [0.0, 2500.0, 4100.0, 5000.0, 7000.0] {
+            assert_eq!(org.value_at_video_timestamp(&KeyframeType::Fov, ms), kf.value_at_video_timestamp(&KeyframeType::Fov, ms));
+        }
+    }
+
+    #[test]
+    fn import_normalized_into_other_clip() {
+        // Exported over the trim range 2 - 6 s, imported into a clip trimmed to 10 - 30 s
+        let json = fov_ramp().export_json(&[KeyframeType::Fov], Some((2_000_000, 6_000_000)));
+        assert_eq!(json["keyframes"]["Fov"][1]["time"], 0.5);
+
+        let mut kf = KeyframeManager::new();
+        kf.set(&KeyframeType::Fov, 20_000_400, 9.0);
+        kf.set(&KeyframeType::Fov, 40_000_000, 9.0);
+        kf.import_json(&json, (10_000_000, 30_000_000), KeyframeImportPolicy::KeepExisting).unwrap();
+        assert_eq!(kf.get_keyframes(&KeyframeType::Fov).unwrap().keys().copied().collect::<Vec<_>>(), vec![10_000_000, 20_000_400, 30_000_000, 40_000_000]);
+        assert_eq!(kf.value_at_video_timestamp(&KeyframeType::Fov, 20000.4), Some(9.0));
+
+        kf.import_json(&json, (10_000_000, 30_000_000), KeyframeImportPolicy::Merge).unwrap();
+        assert_eq!(kf.get_keyframes(&KeyframeType::Fov).unwrap().len(), 4);
+        assert_eq!(kf.value_at_video_timestamp(&KeyframeType::Fov, 20000.4), Some(1.5));
+        assert_eq!(kf.easing(&KeyframeType::Fov, 10_000_000), Some(Easing::Hold));
+
+        // Unknown parameters are reported, the rest is imported
+        let mut json = json;
+        json["keyframes"]["SomethingNew"] = serde_json::json!([{ "time": 0.0, "value": 1.0 }]);
+        let result = kf.import_json(&json, (0, 1_000_000), KeyframeImportPolicy::Replace).unwrap();
+        assert_eq!(result.unknown, vec!["SomethingNew".to_string()]);
+        assert_eq!(kf.get_keyframes(&KeyframeType::Fov).unwrap().keys().copied().collect::<Vec<_>>(), vec![0, 500_000, 1_000_000]);
+        assert!(kf.import_json(&serde_json::json!({ "keyframes": 1 }), (0, 1), KeyframeImportPolicy::Replace).is_err());
+    }
+
+    #[test]
+    fn snapping_to_frames() {
+        let grid = FrameGrid::Constant { fps: 30.0, timestamp_scale: None };
+        assert_eq!(grid.snap(0), 0);
+        assert_eq!(grid.snap(40_000), 33_333);
+        assert_eq!(grid.snap(55_000), 66_667);
+        assert_eq!(grid.snap(-10_000), 0);
+
+        let mut kf = KeyframeManager::new();
+        kf.frame_grid = Some(grid.clone());
+        kf.set(&KeyframeType::Fov, 1_010_000, 1.0);
+        assert_eq!(kf.get_keyframes(&KeyframeType::Fov).unwrap().keys().copied().collect::<Vec<_>>(), vec![1_000_000]);
+        let id = kf.id(&KeyframeType::Fov, 1_000_000).unwrap();
+        kf.set_timestamp(&KeyframeType::Fov, id, 1_990_000);
+        assert_eq!(kf.get_keyframes(&KeyframeType::Fov).unwrap().keys().copied().collect::<Vec<_>>(), vec![2_000_000]);
+
+        // Migration of unsnapped keyframes, the two which end up on the same frame are merged
+        let mut kf = KeyframeManager::new();
+        for (ts, v) in [(10_000, 1.0), (12_000, 2.0), (101_000, 3.0)] {
+            kf.set(&KeyframeType::Fov, ts, v);
+        }
+        let sets = kf.quantize_all(&grid);
+        assert_eq!(sets[&KeyframeType::Fov].iter().map(|(ts, x)| (*ts, x.value)).collect::<Vec<_>>(), vec![(0, 2.0), (100_000, 3.0)]);
+    }
+
+    #[test]
+    fn snapping_with_overridden_frame_rate() {
+        // 29.97 fps video, rendered as 24 fps, so the frames are at `frame / 24` and looked up at that times the scale
+        let (fps, scale) = (30000.0 / 1001.0, 24.0 / (30000.0 / 1001.0));
+        let mut kf = KeyframeManager::new();
+        kf.timestamp_scale = Some(scale);
+        kf.frame_grid = Some(FrameGrid::Constant { fps, timestamp_scale: Some(scale) });
+        kf.set(&KeyframeType::Fov, 0, 1.0);
+        kf.set_easing(&KeyframeType::Fov, 0, Easing::Hold);
+        for frame in [1, 7, 13, 100, 1001, 12345] {
+            // Somewhere within the frame before
+            kf.set(&KeyframeType::Fov, ((frame as f64 - 0.4) * 1_000_000.0 / fps) as i64, 2.0);
+            let at_frame = |frame: i32| kf.value_at_video_timestamp(&KeyframeType::Fov, crate::timestamp_at_frame(frame, fps * scale)).unwrap();
+            assert_eq!(at_frame(frame - 1), 1.0, "{frame}");
+            assert_eq!(at_frame(frame), 2.0, "{frame}");
+            kf.clear_type(&KeyframeType::Fov);
+            kf.set(&KeyframeType::Fov, 0, 1.0);
+            kf.set_easing(&KeyframeType::Fov, 0, Easing::Hold);
+        }
+    }
+
+    #[test]
+    fn snapping_to_variable_frame_rate() {
+        let grid = FrameGrid::Variable(vec![0, 33_333, 66_667, 110_000, 130_000]);
+        assert_eq!(grid.snap(-5), 0);
+        assert_eq!(grid.snap(50_000), 33_333);
+        assert_eq!(grid.snap(100_000), 110_000);
+        // Exactly between two frames
+        assert_eq!(grid.snap(120_000), 110_000);
+        assert_eq!(grid.snap(200_000), 130_000);
+        assert_eq!(grid.snap(66_667), 66_667);
+        assert_eq!(FrameGrid::Variable(Vec::new()).snap(12_345), 12_345);
+
+        // A keyframe on the frame takes effect on that frame, also with the frame timestamps rounded the other way
+        let mut kf = KeyframeManager::new();
+        kf.frame_grid = Some(grid);
+        kf.set(&KeyframeType::Fov, 0, 1.0);
+        kf.set_easing(&KeyframeType::Fov, 0, Easing::Hold);
+        kf.set(&KeyframeType::Fov, 108_000, 2.0);
+        assert_eq!(kf.value_at_video_timestamp(&KeyframeType::Fov, 109.9988), Some(2.0));
+        assert_eq!(kf.value_at_video_timestamp(&KeyframeType::Fov, 66.667), Some(1.0));
+    }
+
+    #[test]
+    fn bulk_operations() {
+        let mut kf = KeyframeManager::new();
+        for (ts, v) in [(0, 1.0), (1_000_000, 2.0), (1_000_400, 3.0), (3_000_000, 4.0)] {
+            kf.keyframes.entry(KeyframeType::Fov).or_default().insert(ts, Keyframe { id: default_id(), value: v, easing: Easing::EaseInOut, bezier: None });
+        }
+        kf.set(&KeyframeType::VideoRotation, 500_000, 90.0);
+        let timestamps = |kf: &KeyframeManager| kf.get_keyframes(&KeyframeType::Fov).unwrap().keys().copied().collect::<Vec<_>>();
+
+        let sets = kf.shift(&[KeyframeType::Fov], -500_000);
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[&KeyframeType::Fov].keys().copied().collect::<Vec<_>>(), vec![-500_000, 500_000, 500_400, 2_500_000]);
+        assert_eq!(kf.get_keyframes(&KeyframeType::VideoRotation).unwrap().keys().next(), Some(&500_000));
+
+        // Slowed down twice around the second keyframe
+        kf.scale(&[KeyframeType::Fov], 500_000, 2.0);
+        assert_eq!(timestamps(&kf), vec![-1_500_000, 500_000, 500_800, 4_500_000]);
+        // Sped up so much that the two close keyframes end up at the same timestamp, the later one is kept
+        kf.scale(&[KeyframeType::Fov], 500_000, 0.0001);
+        assert_eq!(timestamps(&kf), vec![499_800, 500_000, 500_400]);
+        assert_eq!(kf.get_keyframes(&KeyframeType::Fov).unwrap()[&500_000].value, 3.0);
+        // Invalid factors don't change anything
+        assert_eq!(kf.scale(&[KeyframeType::Fov], 0, 0.0)[&KeyframeType::Fov].len(), 3);
+        assert_eq!(kf.scale(&[KeyframeType::Fov], 0, f64::NAN)[&KeyframeType::Fov].len(), 3);
+
+        // Range is inclusive
+        let sets = kf.remove_range(&[KeyframeType::Fov, KeyframeType::VideoRotation], 500_000, 500_400);
+        assert_eq!(timestamps(&kf), vec![499_800]);
+        assert!(sets[&KeyframeType::VideoRotation].is_empty());
+
+        // Smoothness parameters have the same unit, the rotation doesn't
+        kf.set(&KeyframeType::SmoothingParamPitch, 0, 0.5);
+        kf.set(&KeyframeType::SmoothingParamPitch, 1_000_000, 1.0);
+        let sets = kf.copy_type(&KeyframeType::SmoothingParamPitch, &KeyframeType::SmoothingParamRoll, |v| v * 2.0).unwrap();
+        assert_eq!(sets[&KeyframeType::SmoothingParamRoll].values().map(|x| x.value).collect::<Vec<_>>(), vec![1.0, 2.0]);
+        assert_ne!(kf.id(&KeyframeType::SmoothingParamRoll, 0), kf.id(&KeyframeType::SmoothingParamPitch, 0));
+        assert!(kf.copy_type(&KeyframeType::SmoothingParamPitch, &KeyframeType::VideoRotation, |v| v).is_none());
+        assert!(keyframes_compatible(&KeyframeType::LensCorrectionStrength, &KeyframeType::ZoomingCenterX));
+        assert!(!keyframes_compatible(&KeyframeType::LensCorrectionStrength, &KeyframeType::BackgroundMargin));
+    }
+}
@@ -20,6 +20,40 @@ pub struct Dimensions { pub w: usize, pub h: usize }
 #[allow(non_snake_case)]
 pub struct CameraParams { pub RMS_error: f64, pub camera_matrix: Vec<[f64; 3]>, pub distortion_coeffs: Vec<f64>, pub radial_distortion_limit: Option<f64> }
 
+// Calibration of a zoom lens at another focal length than the main one in the profile
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct FocalLengthCalibration {
+    pub focal_length: f64, // mm
+    pub calib_dimension: Dimensions,
+    pub num_images: usize,
+    pub fisheye_params: CameraParams,
+}
+
+// Calibration of the same lens in another video mode (sensor crop, resolution or format), stored in the same profile file
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct VideoModeCalibration {
+    pub video_mode: String, // eg. "Linear 2.7K 4:3", compared with the lens and camera settings from the clip's metadata
+    pub calib_dimension: Dimensions,
+    pub orig_dimension: Dimensions,
+    pub input_horizontal_stretch: f64,
+    pub input_vertical_stretch: f64,
+    pub fps: f64,
+    pub frame_readout_time: Option<f64>,
+    pub num_images: usize,
+    pub fisheye_params: CameraParams,
+}
+
+#[derive(Clone, Debug)]
+pub struct VideoModeSelection {
+    pub profile: LensProfile,
+    pub video_mode: String,
+    // The calibration was done at a different resolution and was scaled by an integer factor to the clip's size.
+    // Sensor crop can differ between such modes, so the result should be checked
+    pub scaled: bool,
+}
+
 #[derive(Deserialize, Serialize, Default, Clone, Debug)]
 #[serde(default)]
 pub struct LensProfile {
@@ -58,6 +92,7 @@ pub struct LensProfile {
 
     pub calibrator_version: String,
     pub date: String,
+    pub calibration_board: Option<String>, // Target used by the calibrator, eg. "chessboard" or "charuco 12x9 DICT_5X5_100 30.00/22.00"
 
     pub compatible_settings: Vec<serde_json::Value>,
 
@@ -73,6 +108,18 @@ pub struct LensProfile {
     pub crop_factor: Option<f64>,
     pub global_shutter: bool,
 
+    // The footage this profile describes is mirrored relative to the sensor, see `flipped`
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+
+    // Sorted by focal length. Used together with the main calibration when the clip has per-frame focal length
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub focal_length_calibrations: Vec<FocalLengthCalibration>,
+
+    // Other video modes of the same camera and lens, the main calibration is the mode described by `lens_model` and `camera_setting`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub video_modes: Vec<VideoModeCalibration>,
+
     // Skip these fields, make sure to update in `get_json_value`
     pub path_to_file: String,
     pub optimal_fov: Option<f64>,
@@ -136,6 +183,8 @@ impl LensProfile {
         self.optimal_fov = None;
 
         self.asymmetrical = cal.asymmetrical;
+        self.calibration_board = Some(cal.board.description());
+        (self.flip_horizontal, self.flip_vertical) = cal.input_flipped;
 
         self.fisheye_params = CameraParams {
             RMS_error: cal.rms,
@@ -149,6 +198,23 @@ impl LensProfile {
         self.name = self.get_name();
     }
 
+    /// Appends the current calibration as another focal length point of a zoom lens profile
+    #[cfg(feature = "opencv")]
+    pub fn add_focal_length_from_calibrator(&mut self, cal: &LensCalibrator, focal_length: f64) -> std::result::Result<(), crate::GyroflowCoreError> {
+        let mut point = self.clone();
+        point.set_from_calibrator(cal);
+        point.focal_length = Some(focal_length);
+        self.add_focal_length_calibration(&point)
+    }
+
+    /// Appends the current calibration as another video mode of this profile
+    #[cfg(feature = "opencv")]
+    pub fn add_video_mode_from_calibrator(&mut self, cal: &LensCalibrator, video_mode: &str) -> std::result::Result<(), crate::GyroflowCoreError> {
+        let mut mode = self.clone();
+        mode.set_from_calibrator(cal);
+        self.add_video_mode_calibration(&mode, video_mode)
+    }
+
     pub fn get_json_value(&self) -> Result<serde_json::Value, serde_json::error::Error> {
         let mut v = serde_json::to_value(&self)?;
         if let Some(obj) = v.as_object_mut() {
@@ -261,7 +327,73 @@ impl LensProfile {
         for (_, x) in ret.parsed_interpolations.iter_mut() {
             *x = x.swapped();
         }
+        for x in ret.focal_length_calibrations.iter_mut() {
+            std::mem::swap(&mut x.calib_dimension.w, &mut x.calib_dimension.h);
+            if x.fisheye_params.camera_matrix.len() == 3 {
+                let (mut mtrx0, mut mtrx1) = (x.fisheye_params.camera_matrix[0], x.fisheye_params.camera_matrix[1]);
+                std::mem::swap(&mut mtrx0[0], &mut mtrx1[1]);
+                std::mem::swap(&mut mtrx0[2], &mut mtrx1[2]);
+                x.fisheye_params.camera_matrix[0] = mtrx0;
+                x.fisheye_params.camera_matrix[1] = mtrx1;
+            }
+        }
+        for x in ret.video_modes.iter_mut() {
+            std::mem::swap(&mut x.calib_dimension.w, &mut x.calib_dimension.h);
+            std::mem::swap(&mut x.orig_dimension.w, &mut x.orig_dimension.h);
+            std::mem::swap(&mut x.input_horizontal_stretch, &mut x.input_vertical_stretch);
+            if x.fisheye_params.camera_matrix.len() == 3 {
+                let (mut mtrx0, mut mtrx1) = (x.fisheye_params.camera_matrix[0], x.fisheye_params.camera_matrix[1]);
+                std::mem::swap(&mut mtrx0[0], &mut mtrx1[1]);
+                std::mem::swap(&mut mtrx0[2], &mut mtrx1[2]);
+                x.fisheye_params.camera_matrix[0] = mtrx0;
+                x.fisheye_params.camera_matrix[1] = mtrx1;
+            }
+        }
+
+        ret
+    }
 
+    /// Profile for the same lens in footage mirrored horizontally and/or vertically, eg. from a front-facing camera or with in-camera flip.
+    /// Mirrors the principal point, the skew and the tangential terms, and toggles `flip_horizontal`/`flip_vertical`
+    /// which make the frame transform mirror the camera rotations as well
+    pub fn flipped(&self, horizontal: bool, vertical: bool) -> LensProfile {
+        let mut ret = self.clone();
+        if !horizontal && !vertical { return ret; }
+
+        ret.flip_horizontal ^= horizontal;
+        ret.flip_vertical   ^= vertical;
+        let model = DistortionModel::from_name(self.distortion_model.as_deref().unwrap_or("opencv_fisheye"));
+        let flip_params = |params: &mut CameraParams, size: &Dimensions| {
+            if params.camera_matrix.len() == 3 {
+                if horizontal { params.camera_matrix[0][2] = size.w as f64 - params.camera_matrix[0][2]; }
+                if vertical   { params.camera_matrix[1][2] = size.h as f64 - params.camera_matrix[1][2]; }
+                if horizontal != vertical { params.camera_matrix[0][1] *= -1.0; }
+            }
+            // x -> -x changes the sign of the terms which are odd in x in the distorted x and even in x in the distorted y, and vice versa
+            let k = &mut params.distortion_coeffs;
+            let mut negate = |i: usize| if let Some(v) = k.get_mut(i) { *v = -*v; };
+            match model.id() {
+                "opencv_standard" => {
+                    if horizontal { negate(3); negate(8);  negate(9);  }
+                    if vertical   { negate(2); negate(10); negate(11); }
+                },
+                "insta360" => {
+                    if horizontal { negate(4); }
+                    if vertical   { negate(3); }
+                },
+                _ => { } // Radially symmetric
+            }
+        };
+        flip_params(&mut ret.fisheye_params, &ret.calib_dimension);
+        for x in ret.focal_length_calibrations.iter_mut() {
+            flip_params(&mut x.fisheye_params, &x.calib_dimension);
+        }
+        for x in ret.video_modes.iter_mut() {
+            flip_params(&mut x.fisheye_params, &x.calib_dimension);
+        }
+        for (_, x) in ret.parsed_interpolations.iter_mut() {
+            *x = x.flipped(horizontal, vertical);
+        }
         ret
     }
 
@@ -548,6 +680,69 @@ impl LensProfile {
         cpy
     }
 
+    /// Adds the calibration of `other` as another focal length point, replacing an existing one at the same focal length
+    pub fn add_focal_length_calibration(&mut self, other: &LensProfile) -> std::result::Result<(), crate::GyroflowCoreError> {
+        let Some(focal_length) = other.focal_length.filter(|x| *x > 0.0) else {
+            return Err(crate::GyroflowCoreError::InvalidLensProfile("Focal length of the calibration is not set".into()));
+        };
+        if self.focal_length.is_none() {
+            return Err(crate::GyroflowCoreError::InvalidLensProfile("Focal length of the lens profile is not set".into()));
+        }
+        if self.distortion_model != other.distortion_model || self.fisheye_params.distortion_coeffs.len() != other.fisheye_params.distortion_coeffs.len() {
+            return Err(crate::GyroflowCoreError::InvalidLensProfile(format!("Distortion model mismatch: {:?} / {:?}", self.distortion_model, other.distortion_model)));
+        }
+        if self.calib_dimension.w * other.calib_dimension.h != self.calib_dimension.h * other.calib_dimension.w {
+            return Err(crate::GyroflowCoreError::InvalidLensProfile(format!("Aspect ratio mismatch: {}x{} / {}x{}", self.calib_dimension.w, self.calib_dimension.h, other.calib_dimension.w, other.calib_dimension.h)));
+        }
+        let point = FocalLengthCalibration {
+            focal_length,
+            calib_dimension: other.calib_dimension.clone(),
+            num_images: other.num_images,
+            fisheye_params: other.fisheye_params.clone(),
+        };
+        if self.focal_length.is_some_and(|x| (x - focal_length).abs() < 0.01) {
+            self.calib_dimension = point.calib_dimension;
+            self.num_images = point.num_images;
+            self.fisheye_params = point.fisheye_params;
+        } else {
+            self.focal_length_calibrations.retain(|x| (x.focal_length - focal_length).abs() >= 0.01);
+            self.focal_length_calibrations.push(point);
+            self.focal_length_calibrations.sort_by(|a, b| a.focal_length.total_cmp(&b.focal_length));
+        }
+        self.init();
+        Ok(())
+    }
+
+    /// Lens at an arbitrary focal length, linearly interpolated between the two closest calibrations and held outside of the calibrated range.
+    /// Camera matrices are normalized to `calib_dimension` of this profile
+    pub fn get_lens_at_focal_length(&self, focal_length: f64) -> LensProfile {
+        let Some(main_fl) = self.focal_length else { return self.clone(); };
+        if self.focal_length_calibrations.is_empty() || self.fisheye_params.camera_matrix.len() != 3 { return self.clone(); }
+
+        let scale = |dim: &Dimensions| if dim.w > 0 { self.calib_dimension.w as f64 / dim.w as f64 } else { 1.0 };
+        let mut points = vec![(main_fl, 1.0, &self.fisheye_params)];
+        points.extend(self.focal_length_calibrations.iter().filter(|x| x.fisheye_params.camera_matrix.len() == 3).map(|x| (x.focal_length, scale(&x.calib_dimension), &x.fisheye_params)));
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let i = points.partition_point(|x| x.0 < focal_length);
+        let (p1, p2) = (points[i.saturating_sub(1)], points[i.min(points.len() - 1)]);
+        let fract = if p2.0 > p1.0 { ((focal_length - p1.0) / (p2.0 - p1.0)).clamp(0.0, 1.0) } else { 0.0 };
+        let lerp = |a: f64, b: f64| a * (1.0 - fract) + b * fract;
+
+        let mut cpy = self.clone();
+        let mtrx = &mut cpy.fisheye_params.camera_matrix;
+        for (r, c) in [(0, 0), (1, 1), (0, 2), (1, 2)] {
+            mtrx[r][c] = lerp(p1.2.camera_matrix[r][c] * p1.1, p2.2.camera_matrix[r][c] * p2.1);
+        }
+        if p1.2.distortion_coeffs.len() == p2.2.distortion_coeffs.len() {
+            cpy.fisheye_params.distortion_coeffs = p1.2.distortion_coeffs.iter().zip(p2.2.distortion_coeffs.iter()).map(|(a, b)| lerp(*a, *b)).collect();
+        }
+        cpy.fisheye_params.RMS_error = lerp(p1.2.RMS_error, p2.2.RMS_error);
+        cpy.focal_length = Some(lerp(p1.0, p2.0));
+        cpy.init();
+        cpy
+    }
+
     pub fn resolve_interpolations(&mut self, db: &crate::lens_profile_database::LensProfileDatabase) {
         if !self.parsed_interpolations.is_empty() {
             return; // Already resolved
@@ -601,3 +796,399 @@ impl LensProfile {
         }
     }
 }
+
+// Profiles scoring at least this much are loaded automatically, otherwise the best ones are offered to the user
+pub const AUTO_APPLY_SCORE: f64 = 0.8;
+
+// Mutually exclusive settings, if the camera and the profile name a different one from the same group, it's not the same lens mode
+const EXCLUSIVE_SETTINGS: &[&[&str]] = &[
+    &["wide", "linear", "superview", "hyperview", "narrow", "medium", "max", "horizon"],
+    &["dcinelike", "normal", "dlog", "dlogm", "hlg"],
+    &["eis", "noeis", "eisy", "eisn"],
+];
+
+fn match_tokens(s: &str) -> Vec<String> {
+    s.to_ascii_lowercase()
+        .replace('-', "")
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+        .filter(|x| !x.is_empty())
+        .map(|x| match x {
+            "super" => "superview",
+            "hyper" => "hyperview",
+            // Same lenses as HERO11 Black
+            "hero12" | "hero13" => "hero11",
+            _ => x
+        }.to_owned())
+        .collect()
+}
+
+fn model_tokens(model: &str, brand: &[String]) -> Vec<String> {
+    let mut tokens = match_tokens(model);
+    tokens.retain(|x| !brand.contains(x));
+    if tokens.iter().any(|x| x == "hero11") { tokens.retain(|x| x != "mini"); }
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+// 1.0 for the same model, a fraction of the token overlap for a similar name and 0 when the model numbers don't match
+fn model_score(a: &[String], b: &[String]) -> f64 {
+    if a.is_empty() || b.is_empty() { return 0.0; }
+    if a == b { return 1.0; }
+    let has_digit = |x: &String| x.chars().any(|c| c.is_ascii_digit());
+    let weight = |x: &String| if has_digit(x) { 3.0 } else { 1.0 };
+    if a.iter().any(|x| has_digit(x) && !b.contains(x)) && b.iter().any(|x| has_digit(x) && !a.contains(x)) {
+        return 0.0;
+    }
+    let common = a.iter().filter(|x| b.contains(x)).map(weight).sum::<f64>();
+    let total = a.iter().map(weight).sum::<f64>() + b.iter().map(weight).sum::<f64>();
+    0.5 * (2.0 * common / total)
+}
+
+// 1.0 when the lens mode matches, 0.5 when there's nothing to compare and 0 when the settings contradict each other
+fn lens_score(camera: &[String], profile: &[String], camera_fl: Option<f64>, profile_fl: Option<f64>) -> f64 {
+    if let (Some(a), Some(b)) = (camera_fl, profile_fl) {
+        if a > 0.0 && b > 0.0 && ((a - b).abs() / b) > 0.05 { return 0.0; }
+    }
+    let mut matched = false;
+    for group in EXCLUSIVE_SETTINGS {
+        let pick = |tokens: &[String]| { let mut v = tokens.iter().filter(|x| group.contains(&x.as_str())).cloned().collect::<Vec<_>>(); v.sort(); v.dedup(); v };
+        let (a, b) = (pick(camera), pick(profile));
+        if a.is_empty() || b.is_empty() { continue; }
+        if a != b { return 0.0; }
+        matched = true;
+    }
+    if matched { 1.0 } else { 0.5 }
+}
+
+// Same frame rate within 1%, or an integer multiple of it for slow motion modes
+fn fps_score(camera: f64, profile: f64) -> f64 {
+    if profile <= 0.0 || camera <= 0.0 { return 0.75; }
+    let ratio = camera.max(profile) / camera.min(profile);
+    if ratio < 1.01 { return 1.0; }
+    let multiple = ratio.round();
+    if multiple >= 2.0 && multiple <= 8.0 && (ratio / multiple - 1.0).abs() < 0.01 { return 0.9; }
+    0.0
+}
+
+impl LensProfile {
+    /// How well this profile fits the clip, 0 - 1. Only the same resolution can score above `AUTO_APPLY_SCORE`,
+    /// a different aspect ratio or brand rules the profile out
+    pub fn match_score(&self, camera: &crate::camera_identifier::CameraIdentifier) -> f64 {
+        if !self.identifier.is_empty() && self.identifier == camera.get_identifier_for_autoload() { return 1.0; }
+
+        let (w, h) = (self.calib_dimension.w, self.calib_dimension.h);
+        let (vw, vh) = if (camera.video_width > camera.video_height) == (w > h) { (camera.video_width, camera.video_height) } else { (camera.video_height, camera.video_width) };
+        if w == 0 || h == 0 || vw == 0 || vh == 0 || w * vh != h * vw { return 0.0; }
+        let resolution = if w == vw && h == vh { 1.0 } else { 0.75 };
+
+        let brand = match_tokens(&camera.brand);
+        let profile_brand = match_tokens(&self.camera_brand);
+        if brand.is_empty() || brand != profile_brand { return 0.0; }
+
+        let model = model_score(&model_tokens(&camera.model, &brand), &model_tokens(&self.camera_model, &brand));
+        if model <= 0.0 { return 0.0; }
+
+        let lens = lens_score(
+            &match_tokens(&format!("{} {} {} {}", camera.lens_model, camera.lens_info, camera.camera_setting, camera.additional)),
+            &match_tokens(&format!("{} {} {}", self.lens_model, self.camera_setting, self.note)),
+            camera.focal_length,
+            self.focal_length
+        );
+
+        let fps = fps_score(camera.fps as f64 / 1000.0, self.fps);
+
+        let score = (0.45 * model + 0.3 * lens + 0.25 * fps) * resolution;
+        if self.official { score } else { score * 0.92 }
+    }
+
+    /// Adds `other` as the calibration of `video_mode`, replacing the one with the same mode and resolution
+    pub fn add_video_mode_calibration(&mut self, other: &LensProfile, video_mode: &str) -> std::result::Result<(), crate::GyroflowCoreError> {
+        if video_mode.trim().is_empty() {
+            return Err(crate::GyroflowCoreError::InvalidLensProfile("Video mode name is not set".into()));
+        }
+        if self.distortion_model != other.distortion_model || self.fisheye_params.distortion_coeffs.len() != other.fisheye_params.distortion_coeffs.len() {
+            return Err(crate::GyroflowCoreError::InvalidLensProfile(format!("Distortion model mismatch: {:?} / {:?}", self.distortion_model, other.distortion_model)));
+        }
+        let mode = VideoModeCalibration {
+            video_mode: video_mode.trim().to_owned(),
+            calib_dimension: other.calib_dimension.clone(),
+            orig_dimension: other.orig_dimension.clone(),
+            input_horizontal_stretch: other.input_horizontal_stretch,
+            input_vertical_stretch: other.input_vertical_stretch,
+            fps: other.fps,
+            frame_readout_time: other.frame_readout_time,
+            num_images: other.num_images,
+            fisheye_params: other.fisheye_params.clone(),
+        };
+        let key = |x: &VideoModeCalibration| (match_tokens(&x.video_mode), x.calib_dimension.w, x.calib_dimension.h);
+        let new_key = key(&mode);
+        self.video_modes.retain(|x| key(x) != new_key);
+        self.video_modes.push(mode);
+        Ok(())
+    }
+
+    /// Picks the calibration of the clip's video mode from the main one and `video_modes`.
+    /// The resolution has to match exactly or differ by an integer factor and the mode settings can't contradict the clip's metadata.
+    /// Returns `None` if the profile doesn't have other video modes or none of them fits
+    pub fn select_video_mode(&self, camera: &crate::camera_identifier::CameraIdentifier) -> Option<VideoModeSelection> {
+        if self.video_modes.is_empty() { return None; }
+
+        let camera_tokens = match_tokens(&format!("{} {} {} {}", camera.lens_model, camera.lens_info, camera.camera_setting, camera.additional));
+        let main = VideoModeCalibration {
+            video_mode: format!("{} {}", self.lens_model, self.camera_setting).trim().to_owned(),
+            calib_dimension: self.calib_dimension.clone(),
+            orig_dimension: self.orig_dimension.clone(),
+            input_horizontal_stretch: self.input_horizontal_stretch,
+            input_vertical_stretch: self.input_vertical_stretch,
+            fps: self.fps,
+            frame_readout_time: self.frame_readout_time,
+            num_images: self.num_images,
+            fisheye_params: self.fisheye_params.clone(),
+        };
+
+        let mut best: Option<((u8, f64, usize, f64), &VideoModeCalibration, usize)> = None;
+        for mode in std::iter::once(&main).chain(self.video_modes.iter()) {
+            let (w, h) = (mode.calib_dimension.w, mode.calib_dimension.h);
+            let (vw, vh) = if (camera.video_width > camera.video_height) == (w > h) { (camera.video_width, camera.video_height) } else { (camera.video_height, camera.video_width) };
+            if w == 0 || h == 0 || vw == 0 || vh == 0 || w * vh != h * vw { continue; }
+            // 1 for the same size, the scale factor for an integer multiple and 0 otherwise
+            let factor = if w == vw { 1 } else if vw % w == 0 && vh % h == 0 { vw / w } else if w % vw == 0 && h % vh == 0 { w / vw } else { 0 };
+            if factor == 0 { continue; }
+
+            let mode_tokens = match_tokens(&mode.video_mode);
+            let settings = lens_score(&camera_tokens, &mode_tokens, None, None);
+            if settings <= 0.0 { continue; }
+            let common = mode_tokens.iter().filter(|x| camera_tokens.contains(x)).count();
+            let fps = fps_score(camera.fps as f64 / 1000.0, mode.fps);
+
+            let rank = (u8::from(factor == 1), settings, common, fps);
+            if best.as_ref().map(|(b, ..)| rank > *b).unwrap_or(true) {
+                best = Some((rank, mode, factor));
+            }
+        }
+
+        let (_, mode, factor) = best?;
+        let mut profile = self.clone();
+        profile.video_modes.clear();
+        profile.calib_dimension = mode.calib_dimension.clone();
+        profile.orig_dimension = mode.orig_dimension.clone();
+        profile.input_horizontal_stretch = mode.input_horizontal_stretch;
+        profile.input_vertical_stretch = mode.input_vertical_stretch;
+        profile.fps = mode.fps;
+        profile.frame_readout_time = mode.frame_readout_time.or(self.frame_readout_time);
+        profile.num_images = mode.num_images;
+        profile.fisheye_params = mode.fisheye_params.clone();
+        profile.optimal_fov = None;
+        if !std::ptr::eq(mode, &main) {
+            profile.camera_setting = mode.video_mode.clone();
+            profile.output_dimension = None;
+            profile.compatible_settings.clear();
+        }
+
+        let scaled = factor != 1;
+        if scaled {
+            let (w, h) = (mode.calib_dimension.w, mode.calib_dimension.h);
+            let (vw, vh) = if (camera.video_width > camera.video_height) == (w > h) { (camera.video_width, camera.video_height) } else { (camera.video_height, camera.video_width) };
+            let ratio = vw as f64 / w as f64;
+            for row in profile.fisheye_params.camera_matrix.iter_mut().take(2) {
+                for v in row.iter_mut() { *v *= ratio; }
+            }
+            profile.calib_dimension = Dimensions { w: vw, h: vh };
+            profile.orig_dimension = Dimensions { w: (profile.orig_dimension.w as f64 * ratio).round() as usize, h: (profile.orig_dimension.h as f64 * ratio).round() as usize };
+            if let Some(ref mut out) = profile.output_dimension {
+                *out = Dimensions { w: (out.w as f64 * ratio).round() as usize, h: (out.h as f64 * ratio).round() as usize };
+            }
+        }
+        profile.init();
+
+        Some(VideoModeSelection { profile, video_mode: mode.video_mode.clone(), scaled })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(focal_length: f64, w: usize, fx: f64, k1: f64) -> LensProfile {
+        LensProfile {
+            focal_length: Some(focal_length),
+            calib_dimension: Dimensions { w, h: w * 9 / 16 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[fx, 0.0, w as f64 / 2.0], [0.0, fx, w as f64 * 9.0 / 32.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![k1, 0.0, 0.0, 0.0],
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn focal_length_interpolation() {
+        let mut lens = calibration(24.0, 3840, 2000.0, 0.1);
+        lens.add_focal_length_calibration(&calibration(70.0, 1920, 3000.0, 0.01)).unwrap();
+        lens.add_focal_length_calibration(&calibration(50.0, 3840, 4000.0, 0.03)).unwrap();
+        assert!(lens.add_focal_length_calibration(&LensProfile { focal_length: None, ..calibration(35.0, 3840, 3000.0, 0.0) }).is_err());
+        assert_eq!(lens.focal_length_calibrations.iter().map(|x| x.focal_length).collect::<Vec<_>>(), vec![50.0, 70.0]);
+
+        let at = |fl: f64| { let l = lens.get_lens_at_focal_length(fl); (l.fisheye_params.camera_matrix[0][0], l.fisheye_params.distortion_coeffs[0], l.focal_length.unwrap()) };
+        assert_eq!(at(24.0), (2000.0, 0.1, 24.0));
+        assert_eq!(at(10.0), (2000.0, 0.1, 24.0));
+        let (fx, k1, fl) = at(37.0);
+        assert!((fx - 3000.0).abs() < 1e-9 && (k1 - 0.065).abs() < 1e-9 && (fl - 37.0).abs() < 1e-9);
+        // Calibrated at half the resolution
+        let (fx, k1, _) = at(100.0);
+        assert!((fx - 6000.0).abs() < 1e-9 && (k1 - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn video_mode_selection() {
+        let mode = |w: usize, h: usize, fx: f64| {
+            let mut lens = calibration(0.0, w, fx, 0.1);
+            lens.calib_dimension = Dimensions { w, h };
+            lens.fps = 30.0;
+            lens
+        };
+        let mut profile = mode(3840, 2160, 1800.0);
+        profile.focal_length = None;
+        profile.lens_model = "Wide".into();
+        profile.add_video_mode_calibration(&mode(2704, 1520, 1500.0), "Linear").unwrap();
+        profile.add_video_mode_calibration(&mode(4000, 3000, 1900.0), "Wide 4:3").unwrap();
+        profile.add_video_mode_calibration(&mode(4000, 3000, 2000.0), "wide 4:3").unwrap();
+        assert_eq!(profile.video_modes.len(), 2);
+
+        let camera = |lens_info: &str, video_width: usize, video_height: usize| crate::camera_identifier::CameraIdentifier {
+            lens_info: lens_info.into(), video_width, video_height, fps: 30000, ..Default::default()
+        };
+        let fx = |x: &VideoModeSelection| x.profile.fisheye_params.camera_matrix[0][0];
+
+        let x = profile.select_video_mode(&camera("Linear", 2704, 1520)).unwrap();
+        assert!(!x.scaled && x.video_mode == "Linear" && fx(&x) == 1500.0);
+        let x = profile.select_video_mode(&camera("Wide", 3840, 2160)).unwrap();
+        assert!(!x.scaled && x.video_mode == "Wide" && fx(&x) == 1800.0);
+        // Portrait clip
+        let x = profile.select_video_mode(&camera("Wide", 3000, 4000)).unwrap();
+        assert!(!x.scaled && fx(&x) == 2000.0);
+
+        // Integer factor
+        let x = profile.select_video_mode(&camera("Wide", 1920, 1080)).unwrap();
+        assert!(x.scaled && x.video_mode == "Wide" && fx(&x) == 900.0);
+        assert_eq!((x.profile.calib_dimension.w, x.profile.calib_dimension.h), (1920, 1080));
+        let x = profile.select_video_mode(&camera("", 2000, 1500)).unwrap();
+        assert!(x.scaled && fx(&x) == 1000.0);
+
+        // Contradicting lens mode or no integer factor
+        assert!(profile.select_video_mode(&camera("Linear", 3840, 2160)).is_none());
+        assert!(profile.select_video_mode(&camera("Wide", 2560, 1440)).is_none());
+
+        // Survives saving
+        let loaded = LensProfile::from_json(&profile.get_json().unwrap()).unwrap();
+        assert_eq!(loaded.video_modes.iter().map(|x| x.video_mode.as_str()).collect::<Vec<_>>(), vec!["Linear", "wide 4:3"]);
+    }
+
+    #[test]
+    fn flip() {
+        use crate::stabilization::KernelParams;
+        let mut lens = calibration(24.0, 3840, 2000.0, -0.2);
+        lens.distortion_model = Some("opencv_standard".into());
+        lens.asymmetrical = true;
+        lens.fisheye_params.camera_matrix[0][2] = 1950.0;
+        lens.fisheye_params.camera_matrix[1][2] = 1050.0;
+        lens.fisheye_params.distortion_coeffs = vec![-0.2, 0.05, 0.002, -0.003, 0.01, 0.0, 0.0, 0.0, 0.001, -0.0005, 0.0015, 0.0002];
+
+        let undistort = |lens: &LensProfile, u: f64, v: f64| {
+            let m = &lens.fisheye_params.camera_matrix;
+            let params = KernelParams { k: lens.get_distortion_coeffs().map(|x| x as f32), ..Default::default() };
+            DistortionModel::from_name("opencv_standard").undistort_point((((u - m[0][2]) / m[0][0]) as f32, ((v - m[1][2]) / m[1][1]) as f32), &params).unwrap()
+        };
+        for (h, v) in [(true, false), (false, true), (true, true)] {
+            let flipped = lens.flipped(h, v);
+            assert_eq!((flipped.flip_horizontal, flipped.flip_vertical), (h, v));
+            assert_eq!(flipped.flipped(h, v).get_json_value().unwrap(), lens.get_json_value().unwrap());
+
+            for (u, v2) in [(100.0, 200.0), (1950.0, 1050.0), (3700.0, 1900.0), (2500.0, 400.0)] {
+                let (x, y) = undistort(&lens, u, v2);
+                let (fx, fy) = undistort(&flipped, if h { 3840.0 - u } else { u }, if v { 2160.0 - v2 } else { v2 });
+                assert_eq!((fx, fy), (if h { -x } else { x }, if v { -y } else { y }), "{h} {v} ({u}, {v2})");
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Expected { AutoApply, Candidate, NoMatch }
+
+    #[test]
+    fn profile_matching() {
+        use crate::camera_identifier::CameraIdentifier;
+        use Expected::*;
+        let camera = |brand: &str, model: &str, lens_info: &str, setting: &str, w: usize, h: usize, fps: f64| CameraIdentifier {
+            brand: brand.into(), model: model.into(), lens_info: lens_info.into(), camera_setting: setting.into(),
+            video_width: w, video_height: h, fps: (fps * 1000.0).round() as usize,
+            ..Default::default()
+        };
+        let profile = |brand: &str, model: &str, lens: &str, setting: &str, w: usize, h: usize, fps: f64, official: bool| LensProfile {
+            camera_brand: brand.into(), camera_model: model.into(), lens_model: lens.into(), camera_setting: setting.into(),
+            calib_dimension: Dimensions { w, h }, fps, official,
+            ..Default::default()
+        };
+
+        let cases = [
+            // Same camera and mode
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  AutoApply),
+            (camera("GoPro", "HERO11 Black", "Linear", "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "Linear", "", 3840, 2160, 30.0,  true),  AutoApply),
+            (camera("GoPro", "HERO12 Black", "Wide",   "", 3840, 2160, 59.94),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 59.94, true),  AutoApply),
+            (camera("GoPro", "HERO11 Black Mini", "Wide", "", 2704, 1520, 60.0), profile("GoPro", "HERO11 Black", "Wide",   "", 2704, 1520, 60.0,  true),  AutoApply),
+            (camera("GoPro", "HERO8 Black",  "Super",  "", 2704, 1520, 59.94),   profile("GoPro", "HERO8 Black",  "SuperView", "", 2704, 1520, 59.94, true), AutoApply),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, false), AutoApply),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 2160, 3840, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  AutoApply),
+            (camera("DJI", "Mini 3 Pro", "", "D-Cinelike", 3840, 2160, 30.0),    profile("DJI", "Mini 3 Pro", "", "4K D-Cinelike", 3840, 2160, 30.0, true), AutoApply),
+            (camera("DJI", "Mini 3 Pro", "", "Normal", 3840, 2160, 30.0),        profile("DJI", "Mini 3 Pro", "", "4K Normal", 3840, 2160, 30.0, true),     AutoApply),
+            (camera("Sony", "ILCE-7SM3", "24.00 mm", "", 3840, 2160, 23.976),    profile("Sony", "ILCE-7SM3", "", "", 3840, 2160, 23.976, true),            AutoApply),
+            (camera("RunCam", "Thumb Pro", "wide", "", 2704, 1520, 50.0),        profile("Runcam", "Thumb Pro", "Wide", "", 2704, 1520, 50.0, false),       AutoApply),
+
+            // GoPro Linear vs Wide, Mini 3 Pro D-Cinelike vs Normal
+            (camera("GoPro", "HERO11 Black", "Linear", "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  Candidate),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "Linear", "", 3840, 2160, 29.97, true),  Candidate),
+            (camera("GoPro", "HERO11 Black", "Max Wide", "", 3840, 2160, 29.97), profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  Candidate),
+            (camera("GoPro", "HERO11 Black", "Hyper",  "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "SuperView", "", 3840, 2160, 29.97, true), Candidate),
+            (camera("DJI", "Mini 3 Pro", "", "D-Cinelike", 3840, 2160, 30.0),    profile("DJI", "Mini 3 Pro", "", "4K Normal", 3840, 2160, 30.0, true),     Candidate),
+            (camera("DJI", "Mini 3 Pro", "", "Normal", 3840, 2160, 30.0),        profile("DJI", "Mini 3 Pro", "", "4K D-Cinelike", 3840, 2160, 30.0, true), Candidate),
+
+            // Close, but not close enough to apply without asking
+            (camera("DJI", "Mini 3", "", "", 3840, 2160, 30.0),                  profile("DJI", "Mini 3 Pro", "", "", 3840, 2160, 30.0, true),              Candidate),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 1920, 1080, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  Candidate),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 25.0),    profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  Candidate),
+            (camera("Sony", "ILCE-7SM3", "", "", 3840, 2160, 119.88),            profile("Sony", "ILCE-7SM3", "", "", 3840, 2160, 29.97, false),            Candidate),
+            (camera("Insta360", "GO 3", "Linear", "", 2560, 1440, 30.0),         profile("Insta360", "GO 3", "", "", 2560, 1440, 30.0, false),              Candidate),
+
+            // Slow motion at an integer multiple of the calibrated frame rate
+            (camera("Sony", "ILCE-7SM3", "", "", 3840, 2160, 119.88),            profile("Sony", "ILCE-7SM3", "", "", 3840, 2160, 29.97, true),             AutoApply),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 2704, 1520, 240.0),   profile("GoPro", "HERO11 Black", "Wide",   "", 2704, 1520, 60.0,  true),  AutoApply),
+
+            // Different camera or image geometry
+            (camera("GoPro", "HERO10 Black", "Wide",   "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  NoMatch),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 4000, 3000, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  NoMatch),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 5312, 2988, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 5312, 4648, 29.97, true),  NoMatch),
+            (camera("DJI", "Mini 4 Pro", "", "", 3840, 2160, 30.0),              profile("DJI", "Mini 3 Pro", "", "", 3840, 2160, 30.0, true),              NoMatch),
+            (camera("DJI", "Avata", "", "", 3840, 2160, 30.0),                   profile("DJI", "Mini 3 Pro", "", "", 3840, 2160, 30.0, true),              NoMatch),
+            (camera("Sony", "ILCE-7SM3", "", "", 3840, 2160, 29.97),             profile("Sony", "ILCE-7M4", "", "", 3840, 2160, 29.97, true),              NoMatch),
+            (camera("Sony", "ILCE-7SM3", "", "", 3840, 2160, 29.97),             profile("Panasonic", "ILCE-7SM3", "", "", 3840, 2160, 29.97, true),        NoMatch),
+            (camera("", "", "", "", 3840, 2160, 29.97),                          profile("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97, true),  NoMatch),
+            (camera("GoPro", "HERO11 Black", "Wide",   "", 3840, 2160, 29.97),   profile("GoPro", "HERO11 Black", "Wide",   "", 0, 0, 29.97, true),        NoMatch),
+        ];
+        for (camera, profile, expected) in &cases {
+            let score = profile.match_score(camera);
+            let result = if score >= AUTO_APPLY_SCORE { AutoApply } else if score > 0.0 { Candidate } else { NoMatch };
+            assert_eq!(&result, expected, "{} {} {} {} {}x{}@{} vs {}: {score}", camera.brand, camera.model, camera.lens_info, camera.camera_setting, camera.video_width, camera.video_height, camera.fps, profile.get_name());
+        }
+
+        // The right mode wins over the other one from the same camera
+        fn best<'a>(camera: &CameraIdentifier, profiles: &'a [LensProfile]) -> &'a LensProfile {
+            profiles.iter().max_by(|a, b| a.match_score(camera).total_cmp(&b.match_score(camera))).unwrap()
+        }
+        let dji = [profile("DJI", "Mini 3 Pro", "", "4K Normal", 3840, 2160, 30.0, true), profile("DJI", "Mini 3 Pro", "", "4K D-Cinelike", 3840, 2160, 30.0, false)];
+        assert_eq!(best(&camera("DJI", "Mini 3 Pro", "", "D-Cinelike", 3840, 2160, 30.0), &dji).camera_setting, "4K D-Cinelike");
+        let gopro = [profile("GoPro", "HERO11 Black", "Wide", "", 3840, 2160, 29.97, true), profile("GoPro", "HERO11 Black", "Linear", "", 3840, 2160, 29.97, false)];
+        assert_eq!(best(&camera("GoPro", "HERO11 Black", "Linear", "", 3840, 2160, 29.97), &gopro).lens_model, "Linear");
+    }
+}
@@ -4,6 +4,8 @@
 use std::cmp::Ordering;
 use std::collections::{ HashSet, HashMap, BTreeMap };
 use crate::LensProfile;
+use crate::camera_identifier::CameraIdentifier;
+use serde::Serialize;
 use std::path::PathBuf;
 use std::io::Read;
 
@@ -16,6 +18,14 @@ enum DataSource {
     SerdeValue(serde_json::Value)
 }
 
+#[derive(Serialize, Clone, Debug)]
+pub struct LensProfileCandidate {
+    pub id: String, // Key for `get_by_id` and `load_lens_profile`
+    pub name: String,
+    pub official: bool,
+    pub score: f64,
+}
+
 #[derive(Default)]
 pub struct LensProfileDatabase {
     preset_map: HashMap<String, String>,
@@ -381,6 +391,15 @@ impl LensProfileDatabase {
     pub fn get_by_id(&self, id: &str) -> Option<&LensProfile> {
         self.map.get(id)
     }
+    /// All profiles which can fit the camera, best first
+    pub fn find_candidates(&self, camera: &CameraIdentifier) -> Vec<LensProfileCandidate> {
+        let mut ret = self.map.iter().filter_map(|(k, v)| {
+            let score = v.match_score(camera);
+            (score > 0.0).then(|| LensProfileCandidate { id: k.clone(), name: v.get_display_name(), official: v.official, score })
+        }).collect::<Vec<_>>();
+        ret.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+        ret
+    }
     pub fn find(&self, filename_or_id: &str) -> Option<&LensProfile> {
         if let Some(l) = self.map.get(filename_or_id) {
             Some(l)
@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Comparison of two profiles of the same camera and combining parts of them.
+// Camera matrices are compared at the resolution of `self`, the other profile is scaled to it.
+
+use serde::Serialize;
+use crate::lens_profile::LensProfile;
+use crate::stabilization::{ KernelParams, distortion_models::DistortionModel };
+use crate::GyroflowCoreError;
+
+bitflags::bitflags! {
+    #[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ProfileFields: u32 {
+        const FOCAL_LENGTH    = 1 << 0; // fx, fy
+        const PRINCIPAL_POINT = 1 << 1; // cx, cy
+        const DISTORTION      = 1 << 2; // Distortion model, coefficients, asymmetry and the calibration RMS
+        const READOUT_TIME    = 1 << 3; // Frame readout time and direction
+        const METADATA        = 1 << 4; // Camera, lens and setting names, note and author
+    }
+}
+
+// Fields compared as metadata, in the profile json
+const METADATA_FIELDS: &[&str] = &[
+    "camera_brand", "camera_model", "lens_model", "camera_setting", "note", "calibrated_by", "calib_dimension",
+    "distortion_model", "digital_lens", "asymmetrical", "fps", "focal_length", "crop_factor", "global_shutter",
+    "input_horizontal_stretch", "input_vertical_stretch", "frame_readout_direction", "official",
+];
+
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct MetadataDiff {
+    pub field: String,
+    pub this: serde_json::Value,
+    pub other: serde_json::Value,
+}
+
+/// Deltas are `other - self`
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct ProfileDiff {
+    pub focal_length: [f64; 2],    // fx, fy in pixels at the resolution of `self`
+    pub principal_point: [f64; 2], // cx, cy in pixels at the resolution of `self`
+    pub distortion_coeffs: Vec<f64>, // Empty if the distortion models are different
+    pub frame_readout_time: Option<f64>, // ms, if both profiles have it
+    pub rms_error: f64,
+    pub resolution_scale: f64, // The other profile was scaled by this
+    pub metadata: Vec<MetadataDiff>,
+}
+
+#[derive(Default, Clone, Copy, Debug, Serialize)]
+pub struct ProfileDisplacement {
+    pub max_px: f64,
+    pub rms_px: f64,
+    pub points: usize,
+}
+impl ProfileDisplacement {
+    // Below a pixel everywhere the profiles give practically the same result
+    pub const NEGLIGIBLE_PX: f64 = 1.0;
+
+    pub fn is_negligible(&self) -> bool { self.max_px < Self::NEGLIGIBLE_PX }
+}
+
+impl LensProfile {
+    fn scale_to(&self, other: &LensProfile) -> f64 {
+        if other.calib_dimension.w > 0 { self.calib_dimension.w as f64 / other.calib_dimension.w as f64 } else { 1.0 }
+    }
+
+    fn same_aspect(&self, other: &LensProfile) -> bool {
+        self.calib_dimension.w * other.calib_dimension.h == self.calib_dimension.h * other.calib_dimension.w
+    }
+
+    pub fn diff(&self, other: &LensProfile) -> ProfileDiff {
+        let scale = self.scale_to(other);
+        let mut ret = ProfileDiff {
+            resolution_scale: scale,
+            rms_error: other.fisheye_params.RMS_error - self.fisheye_params.RMS_error,
+            ..Default::default()
+        };
+
+        let (a, b) = (&self.fisheye_params.camera_matrix, &other.fisheye_params.camera_matrix);
+        if a.len() == 3 && b.len() == 3 {
+            ret.focal_length    = [b[0][0] * scale - a[0][0], b[1][1] * scale - a[1][1]];
+            ret.principal_point = [b[0][2] * scale - a[0][2], b[1][2] * scale - a[1][2]];
+        }
+
+        if self.distortion_model == other.distortion_model {
+            let (a, b) = (&self.fisheye_params.distortion_coeffs, &other.fisheye_params.distortion_coeffs);
+            ret.distortion_coeffs = (0..a.len().max(b.len())).map(|i| b.get(i).unwrap_or(&0.0) - a.get(i).unwrap_or(&0.0)).collect();
+        }
+
+        if let (Some(a), Some(b)) = (self.frame_readout_time, other.frame_readout_time) {
+            ret.frame_readout_time = Some(b - a);
+        }
+
+        if let (Ok(a), Ok(b)) = (self.get_json_value(), other.get_json_value()) {
+            for field in METADATA_FIELDS {
+                let (a, b) = (a.get(*field).cloned().unwrap_or_default(), b.get(*field).cloned().unwrap_or_default());
+                if a != b {
+                    ret.metadata.push(MetadataDiff { field: field.to_string(), this: a, other: b });
+                }
+            }
+        }
+        ret
+    }
+
+    /// Copy of this profile with the `fields` taken from `other`. Camera matrix values are scaled to the resolution of this profile
+    pub fn merge(&self, other: &LensProfile, fields: ProfileFields) -> Result<LensProfile, GyroflowCoreError> {
+        if !self.same_aspect(other) {
+            return Err(GyroflowCoreError::InvalidLensProfile(format!("Aspect ratio mismatch: {}x{} / {}x{}", self.calib_dimension.w, self.calib_dimension.h, other.calib_dimension.w, other.calib_dimension.h)));
+        }
+        let scale = self.scale_to(other);
+        let mut ret = self.clone();
+
+        let matrix_fields = fields & (ProfileFields::FOCAL_LENGTH | ProfileFields::PRINCIPAL_POINT);
+        if !matrix_fields.is_empty() {
+            if ret.fisheye_params.camera_matrix.len() != 3 || other.fisheye_params.camera_matrix.len() != 3 {
+                return Err(GyroflowCoreError::InvalidLensProfile("Camera matrix is missing".into()));
+            }
+            let (a, b) = (&mut ret.fisheye_params.camera_matrix, &other.fisheye_params.camera_matrix);
+            if fields.contains(ProfileFields::FOCAL_LENGTH) {
+                a[0][0] = b[0][0] * scale;
+                a[1][1] = b[1][1] * scale;
+                a[0][1] = b[0][1] * scale;
+            }
+            if fields.contains(ProfileFields::PRINCIPAL_POINT) {
+                a[0][2] = b[0][2] * scale;
+                a[1][2] = b[1][2] * scale;
+            }
+        }
+        if fields.contains(ProfileFields::DISTORTION) {
+            ret.distortion_model = other.distortion_model.clone();
+            ret.fisheye_params.distortion_coeffs = other.fisheye_params.distortion_coeffs.clone();
+            ret.fisheye_params.RMS_error = other.fisheye_params.RMS_error;
+            ret.asymmetrical = other.asymmetrical;
+        }
+        if fields.contains(ProfileFields::READOUT_TIME) {
+            ret.frame_readout_time = other.frame_readout_time;
+            ret.frame_readout_direction = other.frame_readout_direction;
+        }
+        if fields.contains(ProfileFields::METADATA) {
+            ret.camera_brand   = other.camera_brand.clone();
+            ret.camera_model   = other.camera_model.clone();
+            ret.lens_model     = other.lens_model.clone();
+            ret.camera_setting = other.camera_setting.clone();
+            ret.note           = other.note.clone();
+            ret.calibrated_by  = other.calibrated_by.clone();
+        }
+        if !fields.is_empty() {
+            // It's not the calibration anyone published anymore
+            ret.official = false;
+            ret.checksum = None;
+            ret.optimal_fov = None;
+            ret.name = ret.get_name();
+        }
+        ret.init();
+        Ok(ret)
+    }
+
+    /// How far apart the two profiles put the pixels: undistorts a grid of points at the resolution of this profile with both
+    /// and projects the results back with the camera matrix of this profile.
+    /// Returns `None` when the aspect ratios are different or there's no camera matrix
+    pub fn displacement(&self, other: &LensProfile) -> Option<ProfileDisplacement> {
+        const GRID: (usize, usize) = (32, 18);
+        if !self.same_aspect(other) || self.fisheye_params.camera_matrix.len() != 3 || other.fisheye_params.camera_matrix.len() != 3 { return None; }
+        let (w, h) = (self.calib_dimension.w as f64, self.calib_dimension.h as f64);
+        if w <= 0.0 || h <= 0.0 { return None; }
+
+        let undistort = |lens: &LensProfile, scale: f64, (u, v): (f64, f64)| -> Option<(f64, f64)> {
+            let m = &lens.fisheye_params.camera_matrix;
+            let (fx, fy, cx, cy) = (m[0][0] * scale, m[1][1] * scale, m[0][2] * scale, m[1][2] * scale);
+            let params = KernelParams {
+                f: [fx as f32, fy as f32],
+                c: [cx as f32, cy as f32],
+                k: lens.get_distortion_coeffs().map(|x| x as f32),
+                ..Default::default()
+            };
+            let model = DistortionModel::from_name(lens.distortion_model.as_deref().unwrap_or("opencv_fisheye"));
+            let (x, y) = model.undistort_point((((u - cx) / fx) as f32, ((v - cy) / fy) as f32), &params)?;
+            if !x.is_finite() || !y.is_finite() { return None; }
+            Some((x as f64, y as f64))
+        };
+
+        let m = &self.fisheye_params.camera_matrix;
+        let scale = self.scale_to(other);
+        let (mut max, mut sum, mut points) = (0.0f64, 0.0, 0);
+        for j in 0..=GRID.1 {
+            for i in 0..=GRID.0 {
+                let pt = (i as f64 / GRID.0 as f64 * w, j as f64 / GRID.1 as f64 * h);
+                let (Some(a), Some(b)) = (undistort(self, 1.0, pt), undistort(other, scale, pt)) else { continue; };
+                let d = (((a.0 - b.0) * m[0][0]).powi(2) + ((a.1 - b.1) * m[1][1]).powi(2)).sqrt();
+                max = max.max(d);
+                sum += d * d;
+                points += 1;
+            }
+        }
+        if points == 0 { return None; }
+        Some(ProfileDisplacement { max_px: max, rms_px: (sum / points as f64).sqrt(), points })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lens_profile::{ CameraParams, Dimensions };
+
+    fn profile(w: usize, fx: f64, k: [f64; 4]) -> LensProfile {
+        let mut lens = LensProfile::default();
+        lens.camera_brand = "GoPro".into();
+        lens.calib_dimension = Dimensions { w, h: w * 9 / 16 };
+        lens.frame_readout_time = Some(8.0);
+        lens.fisheye_params = CameraParams {
+            camera_matrix: vec![[fx, 0.0, w as f64 / 2.0], [0.0, fx, w as f64 * 9.0 / 32.0], [0.0, 0.0, 1.0]],
+            distortion_coeffs: k.to_vec(),
+            RMS_error: 0.5,
+            ..Default::default()
+        };
+        lens.init();
+        lens
+    }
+
+    #[test]
+    fn diff_and_displacement() {
+        let a = profile(3840, 1800.0, [0.05, 0.01, -0.005, 0.001]);
+        // Same lens calibrated at half the resolution
+        let b = profile(1920, 900.0, [0.05, 0.01, -0.005, 0.001]);
+        let diff = a.diff(&b);
+        assert_eq!(diff.resolution_scale, 2.0);
+        assert_eq!(diff.focal_length, [0.0, 0.0]);
+        assert!(diff.distortion_coeffs.iter().all(|x| *x == 0.0));
+        assert_eq!(diff.metadata.iter().map(|x| x.field.as_str()).collect::<Vec<_>>(), vec!["calib_dimension"]);
+        let d = a.displacement(&b).unwrap();
+        assert!(d.is_negligible() && d.points == 33 * 19, "{d:?}");
+
+        let mut c = profile(3840, 1830.0, [0.08, 0.01, -0.005, 0.001]);
+        c.camera_model = "HERO11 Black".into();
+        c.frame_readout_time = Some(9.5);
+        let diff = a.diff(&c);
+        assert_eq!(diff.focal_length, [30.0, 30.0]);
+        assert!((diff.distortion_coeffs[0] - 0.03).abs() < 1e-12);
+        assert_eq!(diff.frame_readout_time, Some(1.5));
+        assert_eq!(diff.metadata[0].field, "camera_model");
+        let d = a.displacement(&c).unwrap();
+        assert!(!d.is_negligible() && d.max_px > d.rms_px && d.rms_px > 0.0, "{d:?}");
+
+        c.calib_dimension = Dimensions { w: 4000, h: 3000 };
+        assert!(a.displacement(&c).is_none());
+    }
+
+    #[test]
+    fn merge() {
+        let a = profile(3840, 1800.0, [0.05, 0.01, -0.005, 0.001]);
+        let mut b = profile(1920, 950.0, [0.06, 0.02, -0.004, 0.002]);
+        b.frame_readout_time = Some(12.0);
+        b.camera_model = "HERO11 Black".into();
+
+        let merged = a.merge(&b, ProfileFields::DISTORTION | ProfileFields::READOUT_TIME).unwrap();
+        assert_eq!(merged.fisheye_params.distortion_coeffs, b.fisheye_params.distortion_coeffs);
+        assert_eq!(merged.fisheye_params.camera_matrix, a.fisheye_params.camera_matrix);
+        assert_eq!(merged.frame_readout_time, Some(12.0));
+        assert_eq!(merged.camera_model, a.camera_model);
+
+        let merged = a.merge(&b, ProfileFields::FOCAL_LENGTH | ProfileFields::METADATA).unwrap();
+        assert_eq!(merged.fisheye_params.camera_matrix[0][0], 1900.0);
+        assert_eq!(merged.fisheye_params.camera_matrix[0][2], 1920.0);
+        assert_eq!(merged.camera_model, "HERO11 Black");
+        assert!(merged.diff(&a.merge(&b, ProfileFields::empty()).unwrap()).focal_length[0] < 0.0);
+        b.calib_dimension = Dimensions { w: 4000, h: 3000 };
+        assert!(a.merge(&b, ProfileFields::all()).is_err());
+    }
+}
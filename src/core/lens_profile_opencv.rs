@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Import of camera calibrations made with OpenCV (calibrateCamera / fisheye::calibrate), stored with cv::FileStorage as YAML or JSON,
+// or in the ROS camera_info format.
+
+use serde_json::{ Map, Value };
+use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+use crate::GyroflowCoreError;
+
+const CAMERA_MATRIX_KEYS: &[&str] = &["camera_matrix", "cameraMatrix", "K"];
+const DIST_COEFFS_KEYS:   &[&str] = &["distortion_coefficients", "dist_coeffs", "distCoeffs", "distortion_coeffs", "D"];
+const RMS_KEYS:           &[&str] = &["avg_reprojection_error", "rms", "reprojection_error"];
+
+impl LensProfile {
+    pub fn from_opencv_file(url: &str) -> Result<Self, GyroflowCoreError> {
+        let data = crate::filesystem::read_to_string(url)?;
+        let mut profile = Self::from_opencv_data(&data)?;
+        profile.path_to_file = url.to_owned();
+        Ok(profile)
+    }
+
+    pub fn from_opencv_data(data: &str) -> Result<Self, GyroflowCoreError> {
+        let obj = if data.trim_start().starts_with('{') {
+            match serde_json::from_str(data)? {
+                Value::Object(obj) => obj,
+                _ => return Err(GyroflowCoreError::InvalidLensProfile("Expected a JSON object".into()))
+            }
+        } else {
+            parse_yaml(data)?
+        };
+
+        let find = |keys: &[&str]| keys.iter().find_map(|k| obj.get(*k));
+        let k = find(CAMERA_MATRIX_KEYS).and_then(matrix_data).filter(|x| x.len() == 9)
+            .ok_or_else(|| GyroflowCoreError::InvalidLensProfile("Missing 3x3 camera matrix".into()))?;
+        let mut d = find(DIST_COEFFS_KEYS).and_then(matrix_data)
+            .ok_or_else(|| GyroflowCoreError::InvalidLensProfile("Missing distortion coefficients".into()))?;
+
+        let size = obj.get("image_size").or_else(|| obj.get("imageSize")).and_then(matrix_data).filter(|x| x.len() == 2)
+            .or_else(|| Some(vec![number(obj.get("image_width").or_else(|| obj.get("width"))?)?, number(obj.get("image_height").or_else(|| obj.get("height"))?)?]))
+            .ok_or_else(|| GyroflowCoreError::InvalidLensProfile("Missing image size".into()))?;
+        let (w, h) = (size[0].round() as usize, size[1].round() as usize);
+        if w == 0 || h == 0 { return Err(GyroflowCoreError::InvalidLensProfile(format!("Invalid image size: {w}x{h}"))); }
+
+        let explicit_fisheye = match obj.get("distortion_model").and_then(|x| x.as_str()) {
+            Some("fisheye") | Some("equidistant") | Some("kannala_brandt") => Some(true),
+            Some("plumb_bob") | Some("rational_polynomial") | Some("pinhole") | Some("standard") => Some(false),
+            Some(other) => return Err(GyroflowCoreError::InvalidLensProfile(format!("Unknown distortion model \"{other}\""))),
+            None => obj.get("fisheye_model").or_else(|| obj.get("fisheye")).and_then(|x| x.as_bool().or_else(|| x.as_f64().map(|x| x != 0.0)))
+        };
+        // calibrateCamera always writes at least 5 coefficients, fisheye::calibrate exactly 4
+        let fisheye = explicit_fisheye.unwrap_or(d.len() == 4);
+
+        let unsupported = || GyroflowCoreError::InvalidLensProfile(format!(
+            "Unsupported distortion coefficients for the {} model, found {}: {:?}. Expected {}",
+            if fisheye { "fisheye" } else { "pinhole" }, d.len(), d,
+            if fisheye { "4 (k1, k2, k3, k4)" } else { "4, 5, 8 or 12 (k1, k2, p1, p2[, k3[, k4, k5, k6[, s1, s2, s3, s4]]]), tilt terms (tauX, tauY) are not supported" }
+        ));
+        if fisheye {
+            if d.len() != 4 { return Err(unsupported()); }
+        } else {
+            match d.len() {
+                4 | 5 | 8 | 12 => { },
+                14 if d[12] == 0.0 && d[13] == 0.0 => { d.truncate(12); },
+                _ => return Err(unsupported())
+            }
+        }
+
+        let text = |key: &str| obj.get(key).and_then(|x| x.as_str()).map(str::to_owned);
+        let mut profile = LensProfile::default();
+        profile.camera_brand   = text("camera_brand").unwrap_or_default();
+        profile.camera_model   = text("camera_model").or_else(|| text("camera_name")).unwrap_or_default();
+        profile.lens_model     = text("lens_model").unwrap_or_default();
+        profile.camera_setting = text("camera_setting").unwrap_or_default();
+        profile.calibrated_by  = text("calibrated_by").unwrap_or_default();
+        profile.note           = text("note").unwrap_or_default();
+        profile.identifier     = text("identifier").unwrap_or_default();
+        profile.date           = text("calibration_time").or_else(|| text("date")).unwrap_or_default();
+        profile.fps            = obj.get("fps").and_then(number).unwrap_or_default();
+        profile.num_images     = obj.get("nr_of_frames").or_else(|| obj.get("num_images")).and_then(number).unwrap_or_default() as usize;
+        profile.calib_dimension = Dimensions { w, h };
+        profile.orig_dimension  = Dimensions { w, h };
+        profile.input_horizontal_stretch = 1.0;
+        profile.input_vertical_stretch   = 1.0;
+        // OpenCV doesn't force the principal point to the image center
+        profile.asymmetrical = (k[2] - w as f64 / 2.0).abs() > 1.0 || (k[5] - h as f64 / 2.0).abs() > 1.0;
+        profile.distortion_model = Some(if fisheye { "opencv_fisheye" } else { "opencv_standard" }.into());
+        profile.fisheye_params = CameraParams {
+            RMS_error: find(RMS_KEYS).and_then(number).unwrap_or_default(),
+            camera_matrix: vec![[k[0], k[1], k[2]], [k[3], k[4], k[5]], [k[6], k[7], k[8]]],
+            distortion_coeffs: d,
+            radial_distortion_limit: None
+        };
+        profile.calibrator_version = "OpenCV".into();
+        profile.init();
+        profile.name = profile.get_name();
+        Ok(profile)
+    }
+}
+
+fn number(v: &Value) -> Option<f64> {
+    v.as_f64().or_else(|| v.as_str()?.trim().parse().ok())
+}
+
+// `!!opencv-matrix` / `opencv-matrix` object with `data`, ROS `{ rows, cols, data }` or a plain (nested) array
+fn matrix_data(v: &Value) -> Option<Vec<f64>> {
+    fn flatten(v: &Value, out: &mut Vec<f64>) -> Option<()> {
+        match v {
+            Value::Array(a) => { for x in a { flatten(x, out)?; } },
+            x => out.push(number(x)?)
+        }
+        Some(())
+    }
+    let data = match v {
+        Value::Object(o) => o.get("data")?,
+        x => x
+    };
+    let mut ret = Vec::new();
+    flatten(data, &mut ret)?;
+    Some(ret)
+}
+
+// Subset of YAML written by cv::FileStorage and ROS: nested block mappings, scalars and flow sequences, which can span multiple lines
+fn parse_yaml(data: &str) -> Result<Map<String, Value>, GyroflowCoreError> {
+    let mut stack: Vec<(usize, String, Map<String, Value>)> = vec![(0, String::new(), Map::new())];
+    let mut lines = data.lines().enumerate();
+    while let Some((line_no, line)) = lines.next() {
+        let line = strip_comment(line);
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('%') || trimmed.starts_with("---") || trimmed.starts_with("...") { continue; }
+        let indent = line.len() - line.trim_start().len();
+
+        let Some((key, rest)) = trimmed.split_once(':') else {
+            return Err(GyroflowCoreError::InvalidLensProfile(format!("Invalid YAML at line {}: {trimmed}", line_no + 1)));
+        };
+        let key = key.trim().trim_matches('"').to_owned();
+        let mut value = rest.trim().to_owned();
+        if value.starts_with('!') {
+            value = value.split_once(char::is_whitespace).map(|x| x.1.trim().to_owned()).unwrap_or_default();
+        }
+        while value.matches('[').count() > value.matches(']').count() {
+            let Some((_, next)) = lines.next() else {
+                return Err(GyroflowCoreError::InvalidLensProfile(format!("Unterminated sequence for \"{key}\"")));
+            };
+            value.push(' ');
+            value.push_str(strip_comment(next).trim());
+        }
+
+        while stack.len() > 1 && indent <= stack.last().unwrap().0 {
+            let (_, k, map) = stack.pop().unwrap();
+            stack.last_mut().unwrap().2.insert(k, Value::Object(map));
+        }
+        if value.is_empty() {
+            stack.push((indent, key, Map::new()));
+        } else {
+            stack.last_mut().unwrap().2.insert(key, yaml_value(&value));
+        }
+    }
+    while stack.len() > 1 {
+        let (_, k, map) = stack.pop().unwrap();
+        stack.last_mut().unwrap().2.insert(k, Value::Object(map));
+    }
+    Ok(stack.pop().map(|x| x.2).unwrap_or_default())
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_quotes = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' if !in_quotes && (i == 0 || line[..i].ends_with(char::is_whitespace)) => return &line[..i],
+            _ => { }
+        }
+    }
+    line
+}
+
+fn yaml_value(v: &str) -> Value {
+    if v.starts_with('[') {
+        return Value::Array(v.replace(['[', ']'], " ").split(',').map(str::trim).filter(|x| !x.is_empty()).map(yaml_value).collect());
+    }
+    if v.len() >= 2 && ((v.starts_with('"') && v.ends_with('"')) || (v.starts_with('\'') && v.ends_with('\''))) {
+        return Value::String(v[1..v.len() - 1].to_owned());
+    }
+    match v {
+        "true" | "True" => return Value::Bool(true),
+        "false" | "False" => return Value::Bool(false),
+        _ => { }
+    }
+    match v.parse::<f64>().ok().and_then(serde_json::Number::from_f64) {
+        Some(n) if v.parse::<i64>().is_err() => Value::Number(n),
+        _ => v.parse::<i64>().map(Value::from).unwrap_or_else(|_| Value::String(v.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stabilization::{ KernelParams, distortion_models::DistortionModel };
+
+    const PINHOLE_YAML: &str = r#"%YAML:1.0
+---
+calibration_time: "Tue 12 Mar 2024 10:21:05"
+nr_of_frames: 24
+image_width: 1920
+image_height: 1080
+camera_brand: "Sony"
+# Rational model
+camera_matrix: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 1.4378e+03, 0., 9.6713e+02, 0., 1.4392e+03,
+       5.3528e+02, 0., 0., 1. ]
+distortion_coefficients: !!opencv-matrix
+   rows: 1
+   cols: 8
+   dt: d
+   data: [ -0.2481, 0.1162, 1.2e-04, -3.1e-04, -0.0281, 0.0143, -0.0032, 0.0011 ]
+avg_reprojection_error: 0.4211
+"#;
+
+    const FISHEYE_JSON: &str = r#"{
+    "image_width": 2704, "image_height": 1520,
+    "camera_matrix": { "type_id": "opencv-matrix", "rows": 3, "cols": 3, "dt": "d", "data": [ 1186.1, 0.0, 1352.0, 0.0, 1186.1, 760.0, 0.0, 0.0, 1.0 ] },
+    "distortion_coefficients": { "type_id": "opencv-matrix", "rows": 4, "cols": 1, "dt": "d", "data": [ 0.0436, 0.0157, -0.0108, 0.0021 ] }
+}"#;
+
+    // Forward projection from OpenCV's projectPoints / fisheye::projectPoints
+    fn distort(fisheye: bool, k: &[f64], x: f64, y: f64) -> (f64, f64) {
+        if fisheye {
+            let r = (x * x + y * y).sqrt();
+            if r == 0.0 { return (x, y); }
+            let theta = r.atan();
+            let t2 = theta * theta;
+            let theta_d = theta * (1.0 + k[0] * t2 + k[1] * t2 * t2 + k[2] * t2 * t2 * t2 + k[3] * t2 * t2 * t2 * t2);
+            (x * theta_d / r, y * theta_d / r)
+        } else {
+            let k = { let mut v = [0.0; 12]; v[..k.len()].copy_from_slice(k); v };
+            let r2 = x * x + y * y;
+            let radial = (1.0 + k[0] * r2 + k[1] * r2 * r2 + k[4] * r2 * r2 * r2) / (1.0 + k[5] * r2 + k[6] * r2 * r2 + k[7] * r2 * r2 * r2);
+            (x * radial + 2.0 * k[2] * x * y + k[3] * (r2 + 2.0 * x * x),
+             y * radial + k[2] * (r2 + 2.0 * y * y) + 2.0 * k[3] * x * y)
+        }
+    }
+
+    // Pixel -> undistorted normalized coordinates, like undistortPoints without R and P
+    fn check_round_trip(profile: &LensProfile, fisheye: bool) {
+        let m = &profile.fisheye_params.camera_matrix;
+        let d = &profile.fisheye_params.distortion_coeffs;
+        let params = KernelParams { k: profile.get_distortion_coeffs().map(|x| x as f32), ..Default::default() };
+        let model = DistortionModel::from_name(profile.distortion_model.as_deref().unwrap());
+        for (x, y) in [(0.0, 0.0), (0.1, -0.05), (-0.3, 0.2), (0.45, 0.25), (-0.5, -0.28)] {
+            let (xd, yd) = distort(fisheye, d, x, y);
+            let (u, v) = (m[0][0] * xd + m[0][2], m[1][1] * yd + m[1][2]);
+            let point = (((u - m[0][2]) / m[0][0]) as f32, ((v - m[1][2]) / m[1][1]) as f32);
+            let (ux, uy) = model.undistort_point(point, &params).unwrap();
+            assert!((ux as f64 - x).abs() < 1e-4 && (uy as f64 - y).abs() < 1e-4, "({x}, {y}) -> ({ux}, {uy})");
+        }
+    }
+
+    #[test]
+    fn pinhole_yaml() {
+        let profile = LensProfile::from_opencv_data(PINHOLE_YAML).unwrap();
+        assert_eq!(profile.distortion_model.as_deref(), Some("opencv_standard"));
+        assert_eq!((profile.calib_dimension.w, profile.calib_dimension.h), (1920, 1080));
+        assert_eq!(profile.fisheye_params.distortion_coeffs.len(), 8);
+        assert_eq!(profile.fisheye_params.camera_matrix[1], [0.0, 1439.2, 535.28]);
+        assert_eq!((profile.camera_brand.as_str(), profile.num_images, profile.fisheye_params.RMS_error), ("Sony", 24, 0.4211));
+        assert!(profile.asymmetrical);
+        check_round_trip(&profile, false);
+    }
+
+    #[test]
+    fn fisheye_json() {
+        let profile = LensProfile::from_opencv_data(FISHEYE_JSON).unwrap();
+        assert_eq!(profile.distortion_model.as_deref(), Some("opencv_fisheye"));
+        assert!(!profile.asymmetrical);
+        check_round_trip(&profile, true);
+    }
+
+    #[test]
+    fn unsupported_coefficients() {
+        let data = PINHOLE_YAML.replace("cols: 8", "cols: 6").replace(", -0.0032, 0.0011", "");
+        let err = LensProfile::from_opencv_data(&data).unwrap_err().to_string();
+        assert!(err.contains("found 6"), "{err}");
+
+        let data = FISHEYE_JSON.replace("\"image_width\"", "\"distortion_model\": \"plumb_bob\", \"image_width\"");
+        assert_eq!(LensProfile::from_opencv_data(&data).unwrap().distortion_model.as_deref(), Some("opencv_standard"));
+    }
+}
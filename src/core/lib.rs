@@ -5,6 +5,8 @@ pub mod gyro_source;
 pub mod imu_integration;
 pub mod lens_profile;
 pub mod lens_profile_database;
+pub mod lens_profile_diff;
+mod lens_profile_opencv;
 #[cfg(feature = "opencv")]
 pub mod calibration;
 pub mod synchronization;
@@ -18,6 +20,19 @@ pub mod smoothing;
 pub mod filtering;
 pub mod filesystem;
 pub mod gyro_export;
+pub mod project_migration;
+pub mod project_integrity;
+pub mod project_summary;
+pub mod plugin_api;
+pub mod timeline_import;
+pub mod frame_cache;
+pub mod preview_quality;
+pub mod processing_report;
+pub mod state_checksum;
+pub mod logging;
+pub mod benchmark;
+pub mod camera_export;
+pub mod graph_data;
 pub mod settings;
 
 pub mod gpu;
@@ -30,7 +45,8 @@ use std::collections::BTreeMap;
 use keyframes::*;
 use parking_lot::{ RwLock, RwLockUpgradableReadGuard };
 use nalgebra::Vector4;
-use gyro_source::{ GyroSource, Quat64, TimeQuat, TimeVec };
+use gyro_source::{ GyroSource, Quat64, StaticBiasEstimate, TimeMapping, TimeQuat, TimeVec };
+use imu_integration::IntegrationMethod;
 use stabilization_params::{ ReadoutDirection, StabilizationParams };
 use lens_profile::LensProfile;
 use lens_profile_database::LensProfileDatabase;
@@ -50,6 +66,9 @@ use calibration::LensCalibrator;
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+/// How long `recompute_threaded` waits for more changes before computing
+pub const RECOMPUTE_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(25);
+
 lazy_static::lazy_static! {
     static ref THREAD_POOL: rayon::ThreadPool = rayon::ThreadPoolBuilder::new().build().unwrap();
 }
@@ -63,6 +82,7 @@ pub struct InputFile {
     pub image_sequence_start: i32,
     pub preset_name: Option<String>,
     pub preset_output_size: Option<(usize, usize)>,
+    pub preserved_project_data: Option<serde_json::Value>, // Fields of a project from a newer version, written back when saving
 }
 
 #[derive(Clone)]
@@ -91,9 +111,11 @@ pub struct StabilizationManager {
     #[cfg(feature = "opencv")]
     pub lens_calibrator: Arc<RwLock<Option<LensCalibrator>>>,
 
+    /// Generation of the parameters, bumped on every change. Background recomputes are cancelled when it moves past theirs
     pub current_compute_id: Arc<AtomicU64>,
     pub smoothing_checksum: Arc<AtomicU64>,
     pub zooming_checksum: Arc<AtomicU64>,
+    pub zooming_revision: Arc<AtomicU64>,
     pub prevent_recompute: Arc<AtomicBool>,
     pub smoothing_invalidated: Arc<AtomicBool>,
     pub zooming_invalidated: Arc<AtomicBool>,
@@ -110,6 +132,12 @@ pub struct StabilizationManager {
     pub params: Arc<RwLock<StabilizationParams>>,
 
     pub sync_data: Arc<RwLock<SyncData>>,
+
+    pub graph_data: Arc<RwLock<Option<(u64, Arc<graph_data::GraphData>)>>>,
+    pub fov_cache: Arc<RwLock<zooming::fov_iterative::FovCache>>,
+    pub preview_quality: Arc<RwLock<preview_quality::PreviewQuality>>,
+    /// Results of the automatic sync, the rejected points included, for the processing report
+    pub sync_points: Arc<RwLock<Vec<processing_report::SyncPointReport>>>,
 }
 
 impl Default for StabilizationManager {
@@ -127,6 +155,11 @@ impl Default for StabilizationManager {
             current_compute_id: Arc::new(AtomicU64::new(0)),
             smoothing_checksum: Arc::new(AtomicU64::new(0)),
             zooming_checksum: Arc::new(AtomicU64::new(0)),
+            zooming_revision: Arc::new(AtomicU64::new(0)),
+            graph_data: Arc::new(RwLock::new(None)),
+            fov_cache: Arc::new(RwLock::new(Default::default())),
+            preview_quality: Arc::new(RwLock::new(Default::default())),
+            sync_points: Arc::new(RwLock::new(Vec::new())),
             prevent_recompute: Arc::new(AtomicBool::new(false)),
             smoothing_invalidated: Arc::new(AtomicBool::new(false)),
             zooming_invalidated: Arc::new(AtomicBool::new(false)),
@@ -163,24 +196,25 @@ impl StabilizationManager {
         }
         if duration_ms < 10000.0 { // If the video is shorter than 10s, use Complementary
             let mut gyro_source = self.gyro.write();
-            gyro_source.integration_method = 1; // Complementary
+            gyro_source.integration_method = IntegrationMethod::from_index(1); // Complementary
         }
 
         self.pose_estimator.sync_results.write().clear();
         self.keyframes.write().clear();
+        self.update_keyframe_frame_grid();
     }
 
+    /// Parses the telemetry of `url` and integrates it. The data is loaded into a copy of the `GyroSource`,
+    /// so the current one is left untouched when it fails or is cancelled (`GyroflowCoreError::Cancelled`)
     pub fn load_gyro_data<F: Fn(f64)>(&self, url: &str, is_main_video: bool, options: &gyro_source::FileLoadOptions, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> std::result::Result<(), GyroflowCoreError> {
-        {
+        let mut gyro = {
             let params = self.params.read();
-            let mut gyro = self.gyro.write();
+            let mut gyro = self.gyro.read().clone();
             gyro.init_from_params(&params);
             gyro.clear();
             gyro.file_url = url.to_string();
-            gyro.file_metadata = Default::default();
-        }
-        self.invalidate_smoothing();
-        self.invalidate_zooming();
+            gyro
+        };
 
         let last_progress = std::cell::RefCell::new(std::time::Instant::now());
         let progress_cb = |p| {
@@ -199,15 +233,55 @@ impl StabilizationManager {
         log::info!("fps: {}, size: {:?}", fps, size);
 
         let cancel_flag2 = cancel_flag.clone();
-        let mut md = GyroSource::parse_telemetry_file(url, options, size, fps, progress_cb, cancel_flag2)?;
+        let mut md = GyroSource::parse_telemetry_file(url, options, size, fps, |p| progress_cb(p * 0.9), cancel_flag2)?;
+        if cancel_flag.load(SeqCst) { return Err(GyroflowCoreError::Cancelled); }
         if md.detected_source.as_ref().map(|v| v.starts_with("GoPro ")).unwrap_or_default() {
             // If gopro reports rolling shutter value, it already applied it, ie. the video is already corrected
             md.frame_readout_time = None;
             println!("md.frame_readout_time: {:?}", md.frame_readout_time);
         }
 
+        // Changes of the video parameters from the metadata, applied when the data is loaded
+        let mut readout_direction = md.frame_readout_direction;
+        let fps_override = md.frame_rate.filter(|md_fps| is_main_video && (md_fps - fps).abs() > 1.0);
         if is_main_video {
-            if let Some(ref lens) = md.lens_profile {   // None
+            if md.detected_source.as_ref().map(|v| v.starts_with("Blackmagic ")).unwrap_or_default() {
+                if let Some(rot) = md.additional_data.get("rotation").and_then(|x| x.as_u64()) {
+                    if rot == 90 || rot == 270 {
+                        log::info!("Using horizontal rolling shutter correction");
+                        if rot == 90 {
+                            readout_direction = ReadoutDirection::RightToLeft;
+                            md.imu_orientation = Some("xYz".into());
+                        } else {
+                            readout_direction = ReadoutDirection::LeftToRight;
+                            md.imu_orientation = Some("Xyz".into());
+                        }
+                    }
+                    if rot == 180 {
+                        readout_direction = ReadoutDirection::BottomToTop;
+                        md.imu_orientation = Some("YXz".into());
+                    }
+                }
+            }
+        } else {
+            log::info!("Not a main video, clearing {} per-frame offsets", md.per_frame_time_offsets.len());
+            md.per_frame_time_offsets.clear();
+        }
+        if let Some(md_fps) = fps_override {
+            // Same duration as after `override_video_fps` below, the data is integrated with it
+            gyro.duration_ms = self.params.read().duration_ms * fps / md_fps;
+        }
+        let lens_profile = md.lens_profile.clone();
+        let frame_readout_time = md.frame_readout_time;
+        let camera_id = md.camera_identifier.clone();
+
+        if !gyro.load_from_telemetry_with_progress(md, |p| progress_cb(0.9 + p * 0.1), &cancel_flag) {
+            return Err(GyroflowCoreError::Cancelled);
+        }
+        gyro.file_load_options = options.clone();
+
+        if is_main_video {
+            if let Some(ref lens) = lens_profile {   // None
                 let mut l = self.lens.write();
                 if let Some(lens_str) = lens.as_str() {
                     let mut db = self.lens_profile_db.read();
@@ -229,42 +303,15 @@ impl StabilizationManager {
                     l.resolve_interpolations(&db);
                 }
             }
-            if let Some(md_fps) = md.frame_rate {   // None
-                let fps = self.params.read().fps;
-                if (md_fps - fps).abs() > 1.0 {
-                    self.override_video_fps(md_fps, false);
-                }
+            if let Some(md_fps) = fps_override {   // None
+                self.override_video_fps(md_fps, false);
             }
-            self.params.write().frame_readout_direction = md.frame_readout_direction;   // TopToBottom
-            if md.detected_source.as_ref().map(|v| v.starts_with("Blackmagic ")).unwrap_or_default() {
-                if let Some(rot) = md.additional_data.get("rotation").and_then(|x| x.as_u64()) {
-                    if rot == 90 || rot == 270 {
-                        log::info!("Using horizontal rolling shutter correction");
-                        if rot == 90 {
-                            self.params.write().frame_readout_direction = ReadoutDirection::RightToLeft;
-                            md.imu_orientation = Some("xYz".into());
-                        } else {
-                            self.params.write().frame_readout_direction = ReadoutDirection::LeftToRight;
-                            md.imu_orientation = Some("Xyz".into());
-                        }
-                    }
-                    if rot == 180 {
-                        self.params.write().frame_readout_direction = ReadoutDirection::BottomToTop;
-                        md.imu_orientation = Some("YXz".into());
-                    }
-                }
-            }
-            self.params.write().frame_readout_time = md.frame_readout_time.unwrap_or_default();
-        } else {
-            log::info!("Not a main video, clearing {} per-frame offsets", md.per_frame_time_offsets.len());
-            md.per_frame_time_offsets.clear();
-        }
-        let camera_id = md.camera_identifier.clone();
-        if !cancel_flag.load(SeqCst) {
-            let mut gyro = self.gyro.write();
-            gyro.load_from_telemetry(md);
-            gyro.file_load_options = options.clone();
+            self.params.write().frame_readout_direction = readout_direction;   // TopToBottom
+            self.params.write().frame_readout_time = frame_readout_time.unwrap_or_default();
         }
+        *self.gyro.write() = gyro;
+        self.invalidate_smoothing();
+        self.invalidate_zooming();
 
         if let Some(id) = camera_id {
             *self.camera_id.write() = Some(id);
@@ -272,6 +319,58 @@ impl StabilizationManager {
         Ok(())
     }
 
+    /// Loads motion data from another file and joins it at `time_offset_ms`, eg. for videos split into multiple chapters
+    pub fn append_gyro_data<F: Fn(f64)>(&self, url: &str, time_offset_ms: f64, options: &gyro_source::FileLoadOptions, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> std::result::Result<(), GyroflowCoreError> {
+        let (fps, size) = {
+            let params = self.params.read();
+            (params.fps, params.size)
+        };
+        let md = GyroSource::parse_telemetry_file(url, options, size, fps, progress_cb, cancel_flag.clone())?;
+        if !cancel_flag.load(SeqCst) {
+            self.gyro.write().append(GyroSource::from_telemetry(url, md), time_offset_ms);
+            self.invalidate_smoothing();
+            self.invalidate_zooming();
+        }
+        Ok(())
+    }
+
+    /// Loads a second gyro stream (eg. a flight controller log) and estimates its alignment to the main one. Returns the alignment residual in deg/s
+    pub fn load_secondary_gyro_data<F: Fn(f64)>(&self, url: &str, options: &gyro_source::FileLoadOptions, max_offset_ms: f64, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> std::result::Result<Option<f64>, GyroflowCoreError> {
+        let (fps, size) = {
+            let params = self.params.read();
+            (params.fps, params.size)
+        };
+        let md = GyroSource::parse_telemetry_file(url, options, size, fps, progress_cb, cancel_flag.clone())?;
+        if md.raw_imu.is_empty() {
+            return Err(GyroflowCoreError::InvalidData);
+        }
+        if cancel_flag.load(SeqCst) { return Ok(None); }
+
+        let residual = self.gyro.write().attach_secondary(gyro_source::SecondaryGyro::new(url, options, &md), true, max_offset_ms);
+        self.invalidate_smoothing();
+        self.invalidate_zooming();
+        Ok(residual)
+    }
+    pub fn remove_secondary_gyro_data(&self) {
+        self.gyro.write().detach_secondary();
+        self.invalidate_smoothing();
+        self.invalidate_zooming();
+    }
+    pub fn set_gyro_stream(&self, stream: gyro_source::GyroStream) {
+        if self.gyro.write().set_gyro_stream(stream) {
+            self.invalidate_smoothing();
+            self.invalidate_zooming();
+        }
+    }
+    pub fn estimate_secondary_gyro_alignment(&self, max_offset_ms: f64) -> Option<f64> {
+        let residual = self.gyro.write().estimate_secondary_alignment(max_offset_ms);
+        if residual.is_some() {
+            self.invalidate_smoothing();
+            self.invalidate_zooming();
+        }
+        residual
+    }
+
     pub fn load_lens_profile(&self, url: &str) -> Result<(), crate::GyroflowCoreError> {
         let url = if (url.starts_with('/') || url.starts_with('\\') || (url.len() > 3 && &url[1..2] == ":")) && !url.contains("://") && !url.starts_with('{') {
             crate::filesystem::path_to_url(url)
@@ -284,8 +383,16 @@ impl StabilizationManager {
             (Ok(()), true)
         } else if url.starts_with('{') {
             (self.lens.write().load_from_data(&url), false)
+        } else if url.ends_with(".yml") || url.ends_with(".yaml") {
+            (LensProfile::from_opencv_file(&url).map(|x| *self.lens.write() = x), false)
         } else {
-            (self.lens.write().load_from_file(&url), false)
+            let mut lens = self.lens.write();
+            let mut result = lens.load_from_file(&url);
+            if result.is_ok() && lens.fisheye_params.camera_matrix.is_empty() {
+                // Not a Gyroflow lens profile, try OpenCV calibration format
+                result = LensProfile::from_opencv_file(&url).map(|x| *lens = x);
+            }
+            (result, false)
         };
         let (width, height, aspect, id, fps) = {
             let params = self.params.read();
@@ -294,6 +401,18 @@ impl StabilizationManager {
 
         let mut lens = self.lens.write();
 
+        // Pick the calibration of the clip's video mode from a multi-mode profile
+        if let Some(camera) = self.camera_id.read().as_ref() {
+            if let Some(selection) = lens.select_video_mode(camera) {
+                if selection.scaled {
+                    log::warn!("Lens profile has no calibration for {}x{}, scaled the \"{}\" mode", camera.video_width, camera.video_height, selection.video_mode);
+                } else {
+                    log::info!("Selected the \"{}\" video mode of the lens profile", selection.video_mode);
+                }
+                *lens = selection.profile;
+            }
+        }
+
         // Check if the lens profile needs to be swapped for vertical
         let lens_aspect_swapped = ((lens.calib_dimension.h * 100) as f64 / lens.calib_dimension.w.max(1) as f64).round() as u32;
         if (width == lens.calib_dimension.h && height == lens.calib_dimension.w) || lens_aspect_swapped == aspect {
@@ -417,34 +536,38 @@ impl StabilizationManager {
         false
     }
 
-    pub fn recompute_adaptive_zoom_static(compute_params: &ComputeParams, params: &RwLock<StabilizationParams>) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>) {
+    /// `revision` is the `zooming_revision` from before `compute_params` were created
+    pub fn recompute_adaptive_zoom_static(compute_params: &ComputeParams, params: &RwLock<StabilizationParams>, fov_cache: &RwLock<zooming::fov_iterative::FovCache>, revision: u64) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>) {
         let (frames, fps, method) = {
             let params = params.read();
             (params.frame_count, params.get_scaled_fps(), params.adaptive_zoom_method)
         };
         let timestamps = (0..frames).map(|i| (i, i as f64 * 1000.0 / fps)).collect::<Vec<(usize, f64)>>();
 
-        zooming::calculate_fovs(compute_params, &timestamps, method.into())
+        // Not locked during the computation, another one may be running at the same time. The cache is only valid for its own key anyway
+        let mut cache = fov_cache.read().clone();
+        let ret = zooming::calculate_fovs_cached(compute_params, &timestamps, method.into(), &mut cache, revision);
+        *fov_cache.write() = cache;
+        ret
     }
     pub fn recompute_adaptive_zoom(&self) {
+        let zooming_revision = self.zooming_revision.load(SeqCst);
         let mut params: ComputeParams = stabilization::ComputeParams::from_manager(self);
         params.calculate_camera_fovs();
 
         let lens_fov_adjustment = params.lens.optimal_fov.unwrap_or(1.0);
-        let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &self.params);
+        let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &self.params, &self.fov_cache, zooming_revision);
         params.fovs = fovs;
         params.minimal_fovs = minimal_fovs;
 
-        let (max_zoom_param, max_zoom_max, max_zoom_iters, scaling_factor) = {
+        let (max_zoom_max, max_zoom_iters) = {
             let mut stab_params = self.params.write();
             stab_params.set_fovs( params.fovs.clone(), lens_fov_adjustment);
             stab_params.minimal_fovs = params.minimal_fovs.clone();
             stab_params.zooming_debug_points = debug_points;
             (
-                stab_params.max_zoom.unwrap_or(0.0),
                 params.keyframes.get_keyframes(&KeyframeType::MaxZoom).map(|x| x.iter().map(|x| x.1.value).max_by(|a, b| a.total_cmp(b)).unwrap_or(stab_params.max_zoom.unwrap_or(0.0))).unwrap_or(stab_params.max_zoom.unwrap_or(0.0)),
-                stab_params.max_zoom_iterations,
-                stab_params.size.0 as f64 / stab_params.output_size.0 as f64
+                stab_params.max_zoom_iterations
             )
         };
 
@@ -458,15 +581,7 @@ impl StabilizationManager {
             for iter in 0..max_zoom_iters {
                 let mut any_above_limit = false;
                 for (i, fov) in params.fovs.iter().enumerate() {
-                    let ts = crate::timestamp_at_frame(i as i32, params.scaled_fps);
-                    let mut zoom_limit = params.keyframes.value_at_video_timestamp(&KeyframeType::MaxZoom, ts).unwrap_or(max_zoom_param) / 100.0;
-
-                    if params.video_speed_affects_zooming_limit && (params.video_speed != 1.0 || params.keyframes.is_keyframed(&KeyframeType::VideoSpeed)) {
-                        let vid_speed = params.keyframes.value_at_video_timestamp(&KeyframeType::VideoSpeed, ts).unwrap_or(params.video_speed).abs();
-                        zoom_limit *= (1.0 + ((vid_speed - 1.0) / 4.0)).min(1.8);
-                    }
-
-                    let fov_limit = 1.0 / (zoom_limit * scaling_factor);
+                    let fov_limit = zooming::max_zoom_fov_limit(&params, i);
                     if *fov < fov_limit {
                         any_above_limit = true;
                         params.smoothing_fov_limit_per_frame[i] *= (*fov / fov_limit).min(*thresholds.get(iter).unwrap_or(thresholds.last().unwrap()));
@@ -485,15 +600,16 @@ impl StabilizationManager {
                     let smoothing = self.smoothing.read();
                     let horizon_lock = smoothing.horizon_lock.clone();
 
-                    let (quats, max_angles) = self.gyro.read().recompute_smoothness(smoothing.current().as_ref(), horizon_lock, &params);
+                    let (mut quats, max_angles) = self.gyro.read().recompute_smoothness(smoothing.current().as_ref(), horizon_lock, &smoothing.axis_lock, &params);
+                    zooming::zoom_budget::limit_corrections(&params, &mut quats);
                     let mut gyro = self.gyro.write();
                     gyro.max_angles = max_angles;
-                    gyro.smoothed_quaternions = quats;
+                    gyro.set_smoothed_quaternions(quats);
                 }
 
                 // Zooming
                 let lens_fov_adjustment = params.lens.optimal_fov.unwrap_or(1.0);
-                let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &self.params);
+                let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &self.params, &self.fov_cache, zooming_revision);
                 params.fovs = fovs;
                 params.minimal_fovs = minimal_fovs;
                 {
@@ -504,6 +620,19 @@ impl StabilizationManager {
                 }
             }
         }
+        // What's still above the limit is clamped to it
+        let mut overflow = if max_zoom_max > 50.0 {
+            zooming::clamp_to_max_zoom(&mut params)
+        } else {
+            Vec::new()
+        };
+        let center_scale = zooming::limit_center_offset(&params, &mut overflow);
+        let mut stab_params = self.params.write();
+        if !overflow.is_empty() {
+            stab_params.set_fovs(params.fovs.clone(), lens_fov_adjustment);
+        }
+        stab_params.zoom_overflow = overflow;
+        stab_params.zoom_center_scale = center_scale;
     }
 
     pub fn recompute_smoothness(&self) {
@@ -513,10 +642,11 @@ impl StabilizationManager {
         let smoothing = self.smoothing.read();
         let horizon_lock = smoothing.horizon_lock.clone();  // false
 
-        let (quats, max_angles) = self.gyro.read().recompute_smoothness(smoothing.current().as_ref(), horizon_lock, &params);
+        let (mut quats, max_angles) = self.gyro.read().recompute_smoothness(smoothing.current().as_ref(), horizon_lock, &smoothing.axis_lock, &params);
+        zooming::zoom_budget::limit_corrections(&params, &mut quats);
         let mut gyro = self.gyro.write();
         gyro.max_angles = max_angles;
-        gyro.smoothed_quaternions = quats;
+        gyro.set_smoothed_quaternions(quats);
     }
 
     pub fn recompute_undistortion(&self) {
@@ -531,12 +661,15 @@ impl StabilizationManager {
     }
 
     pub fn invalidate_ongoing_computations(&self) {
-        self.current_compute_id.store(fastrand::u64(..), SeqCst);
+        self.current_compute_id.fetch_add(1, SeqCst);
     }
 
+    /// Callback gets the generation of this recompute (also returned from here) and whether it was
+    /// discarded because the parameters changed in the meantime
     pub fn recompute_threaded<F: Fn((u64, bool)) + Send + Sync + Clone + 'static>(&self, cb: F) -> u64 {
         //self.recompute_smoothness();
         //self.recompute_adaptive_zoom();
+        let zooming_revision = self.zooming_revision.load(SeqCst);
         let mut params = stabilization::ComputeParams::from_manager(self);
         params.calculate_camera_fovs();
 
@@ -544,63 +677,70 @@ impl StabilizationManager {
         let stabilization_params = self.params.clone();
         let gyro = self.gyro.clone();
 
-        let compute_id = fastrand::u64(..);
-        self.current_compute_id.store(compute_id, SeqCst);
+        let compute_id = self.current_compute_id.fetch_add(1, SeqCst) + 1;
+        params.cancel_token = stabilization::ComputeToken::new(self.current_compute_id.clone(), compute_id);
+        let token = params.cancel_token.clone();
 
-        let mut gyro_checksum = gyro.read().get_checksum();
+        // Taken before the computation, so changes made in the meantime are picked up by the next one
+        let mut state = state_checksum::StateChecksum::from_manager(self);
 
         let prevent_recompute = self.prevent_recompute.clone();
-        let current_compute_id = self.current_compute_id.clone();
         let smoothing_checksum = self.smoothing_checksum.clone();
         let zooming_checksum = self.zooming_checksum.clone();
+        let fov_cache = self.fov_cache.clone();
 
         let stabilization = self.stabilization.clone();
         THREAD_POOL.spawn(move || {
-            // std::thread::sleep(std::time::Duration::from_millis(20));
             if prevent_recompute.load(SeqCst) { return cb((compute_id, true)); } // we're still loading, don't recompute
-            if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
+
+            // Coalesce rapid changes (eg. dragging a slider), only the last one within the debounce time gets computed
+            let started = std::time::Instant::now();
+            while started.elapsed() < RECOMPUTE_DEBOUNCE {
+                if token.is_cancelled() { return cb((compute_id, true)); }
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+            if token.is_cancelled() { return cb((compute_id, true)); }
 
             let mut smoothing_changed = false;
-            if smoothing.read().get_state_checksum(gyro_checksum) != smoothing_checksum.load(SeqCst) {
-                let (mut smoothing, horizon_lock) = {
+            if state.smoothing_key() != smoothing_checksum.load(SeqCst) {
+                let (mut smoothing, horizon_lock, axis_lock) = {
                     let lock = smoothing.read();
-                    (lock.current().clone(), lock.horizon_lock.clone())
+                    (lock.current().clone(), lock.horizon_lock.clone(), lock.axis_lock.clone())
                 };
 
-                let (quats, max_angles) = gyro.read().recompute_smoothness(smoothing.as_mut(), horizon_lock, &params);
+                let (mut quats, max_angles) = gyro.read().recompute_smoothness(smoothing.as_mut(), horizon_lock, &axis_lock, &params);
+                zooming::zoom_budget::limit_corrections(&params, &mut quats);
 
-                if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
-                if gyro_checksum != gyro.read().get_checksum() { return cb((compute_id, true)); }
+                if token.is_cancelled() { return cb((compute_id, true)); }
+                if state.gyro != gyro.read().get_checksum() { return cb((compute_id, true)); }
 
                 let mut lib_gyro = gyro.write();
                 lib_gyro.max_angles = max_angles;
-                lib_gyro.smoothed_quaternions = quats;
+                lib_gyro.set_smoothed_quaternions(quats);
                 lib_gyro.smoothing_status = smoothing.get_status_json();
-                gyro_checksum = lib_gyro.get_checksum();
+                state.gyro = lib_gyro.get_checksum();
                 smoothing_changed = true;
             }
-            smoothing_checksum.store(smoothing.read().get_state_checksum(gyro_checksum), SeqCst);
+            smoothing_checksum.store(state.smoothing_key(), SeqCst);
 
-            if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
+            if token.is_cancelled() { return cb((compute_id, true)); }
 
-            if smoothing_changed || zooming::get_checksum(&params) != zooming_checksum.load(SeqCst) {
-                let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &stabilization_params);
+            if smoothing_changed || state.zooming_key() != zooming_checksum.load(SeqCst) {
+                let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &stabilization_params, &fov_cache, zooming_revision);
                 params.fovs = fovs;
                 params.minimal_fovs = minimal_fovs;
 
-                if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
+                if token.is_cancelled() { return cb((compute_id, true)); }
 
-                let (max_zoom_param, max_zoom_max, max_zoom_iters, scaling_factor) = {
+                let (max_zoom_max, max_zoom_iters) = {
                     let mut stab_params = stabilization_params.write();
                     stab_params.set_fovs(params.fovs.clone(), params.lens.optimal_fov.unwrap_or(1.0));
                     stab_params.minimal_fovs = params.minimal_fovs.clone();
                     stab_params.zooming_debug_points = debug_points;
-                    zooming_checksum.store(zooming::get_checksum(&params), SeqCst);
+                    zooming_checksum.store(state.zooming_key(), SeqCst);
                     (
-                        stab_params.max_zoom.unwrap_or(0.0),
                         params.keyframes.get_keyframes(&KeyframeType::MaxZoom).map(|x| x.iter().map(|x| x.1.value).max_by(|a, b| a.total_cmp(b)).unwrap_or(stab_params.max_zoom.unwrap_or(0.0))).unwrap_or(stab_params.max_zoom.unwrap_or(0.0)),
-                        stab_params.max_zoom_iterations,
-                        stab_params.size.0 as f64 / stab_params.output_size.0 as f64
+                        stab_params.max_zoom_iterations
                     )
                 };
 
@@ -614,15 +754,7 @@ impl StabilizationManager {
                     for iter in 0..max_zoom_iters {
                         let mut any_above_limit = false;
                         for (i, fov) in params.fovs.iter().enumerate() {
-                            let ts = crate::timestamp_at_frame(i as i32, params.scaled_fps);
-                            let mut zoom_limit = params.keyframes.value_at_video_timestamp(&KeyframeType::MaxZoom, ts).unwrap_or(max_zoom_param) / 100.0;
-
-                            if params.video_speed_affects_zooming_limit && (params.video_speed != 1.0 || params.keyframes.is_keyframed(&KeyframeType::VideoSpeed)) {
-                                let vid_speed = params.keyframes.value_at_video_timestamp(&KeyframeType::VideoSpeed, ts).unwrap_or(params.video_speed).abs();
-                                zoom_limit *= (1.0 + ((vid_speed - 1.0) / 4.0)).min(1.8);
-                            }
-
-                            let fov_limit = 1.0 / (zoom_limit * scaling_factor);
+                            let fov_limit = zooming::max_zoom_fov_limit(&params, i);
                             if *fov < fov_limit {
                                 any_above_limit = true;
                                 params.smoothing_fov_limit_per_frame[i] *= (*fov / fov_limit).min(*thresholds.get(iter).unwrap_or(thresholds.last().unwrap()));
@@ -636,45 +768,62 @@ impl StabilizationManager {
                             break;
                         }
 
-                        if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
+                        if token.is_cancelled() { return cb((compute_id, true)); }
 
                         // Smoothing
-                        let (mut smoothing, horizon_lock) = {
+                        let (mut smoothing, horizon_lock, axis_lock) = {
                             let lock = smoothing.read();
-                            (lock.current().clone(), lock.horizon_lock.clone())
+                            (lock.current().clone(), lock.horizon_lock.clone(), lock.axis_lock.clone())
                         };
-                        let (quats, max_angles) = gyro.read().recompute_smoothness(smoothing.as_mut(), horizon_lock, &params);
+                        let (mut quats, max_angles) = gyro.read().recompute_smoothness(smoothing.as_mut(), horizon_lock, &axis_lock, &params);
+                        zooming::zoom_budget::limit_corrections(&params, &mut quats);
 
-                        if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
+                        if token.is_cancelled() { return cb((compute_id, true)); }
 
                         {
                             let mut lib_gyro = gyro.write();
                             lib_gyro.max_angles = max_angles;
-                            lib_gyro.smoothed_quaternions = quats;
+                            lib_gyro.set_smoothed_quaternions(quats);
                             lib_gyro.smoothing_status = smoothing.get_status_json();
                         }
 
                         // Zooming
-                        let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &stabilization_params);
+                        let (fovs, minimal_fovs, debug_points) = Self::recompute_adaptive_zoom_static(&params, &stabilization_params, &fov_cache, zooming_revision);
                         params.fovs = fovs;
                         params.minimal_fovs = minimal_fovs;
 
-                        if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
+                        if token.is_cancelled() { return cb((compute_id, true)); }
 
                         {
                             let mut stab_params = stabilization_params.write();
                             stab_params.set_fovs(params.fovs.clone(), params.lens.optimal_fov.unwrap_or(1.0));
                             stab_params.minimal_fovs = params.minimal_fovs.clone();
                             stab_params.zooming_debug_points = debug_points;
-                            zooming_checksum.store(zooming::get_checksum(&params), SeqCst);
+                            zooming_checksum.store(state.zooming_key(), SeqCst);
                         }
                     }
                 }
+                // What's still above the limit is clamped to it
+                let mut overflow = if max_zoom_max > 50.0 {
+                    zooming::clamp_to_max_zoom(&mut params)
+                } else {
+                    Vec::new()
+                };
+                params.zoom_center_scale = zooming::limit_center_offset(&params, &mut overflow);
+                let mut stab_params = stabilization_params.write();
+                if !overflow.is_empty() {
+                    stab_params.set_fovs(params.fovs.clone(), params.lens.optimal_fov.unwrap_or(1.0));
+                }
+                stab_params.zoom_overflow = overflow;
+                stab_params.zoom_center_scale = params.zoom_center_scale.clone();
             }
 
-            if current_compute_id.load(SeqCst) != compute_id { return cb((compute_id, true)); }
-
-            stabilization.write().set_compute_params(params);
+            {
+                // Checked under the lock, so stale results are never applied
+                let mut stabilization = stabilization.write();
+                if token.is_cancelled() { drop(stabilization); return cb((compute_id, true)); }
+                stabilization.set_compute_params(params);
+            }
 
             cb((compute_id, false));
         });
@@ -753,7 +902,7 @@ impl StabilizationManager {
                 if let Some(ref cal) = *lock {
                     let points = cal.all_matches.read();
                     if let Some(entry) = points.get(&(frame as i32)) {
-                        calibration::drawing::draw_chessboard_corners(cal.width, cal.height, p.size.0, p.size.1, drawing, (cal.columns, cal.rows), &entry.points, true, y_inverted);
+                        calibration::drawing::draw_chessboard_corners(cal.width, cal.height, p.size.0, p.size.1, drawing, (cal.columns, cal.rows), &entry.points, entry.ids.is_empty(), y_inverted);
                     }
                 }
             }
@@ -777,14 +926,7 @@ impl StabilizationManager {
         }
     }
 
-    pub fn process_pixels<T: PixelType>(&self, mut timestamp_us: i64, frame: Option<usize>, buffers: &mut Buffers) -> Result<stabilization::ProcessedInfo, GyroflowCoreError> {
-        if let gpu::BufferSource::Cpu { buffer } = &buffers.input.data  { if buffer.is_empty() { return Err(GyroflowCoreError::InputBufferEmpty); } }
-        if let gpu::BufferSource::Cpu { buffer } = &buffers.output.data { if buffer.is_empty() { return Err(GyroflowCoreError::OutputBufferEmpty); } }
-
-        if let Some(scale) = self.params.read().fps_scale {
-            timestamp_us = (timestamp_us as f64 / scale).round() as i64;
-        }
-
+    pub(crate) fn recompute_if_invalidated(&self) {
         if self.smoothing_invalidated.load(SeqCst) {
             self.recompute_smoothness();
             self.smoothing_invalidated.store(false, SeqCst);
@@ -797,6 +939,17 @@ impl StabilizationManager {
             self.recompute_undistortion();
             self.undistortion_invalidated.store(false, SeqCst);
         }
+    }
+
+    pub fn process_pixels<T: PixelType>(&self, mut timestamp_us: i64, frame: Option<usize>, buffers: &mut Buffers) -> Result<stabilization::ProcessedInfo, GyroflowCoreError> {
+        if let gpu::BufferSource::Cpu { buffer } = &buffers.input.data  { if buffer.is_empty() { return Err(GyroflowCoreError::InputBufferEmpty); } }
+        if let gpu::BufferSource::Cpu { buffer } = &buffers.output.data { if buffer.is_empty() { return Err(GyroflowCoreError::OutputBufferEmpty); } }
+
+        if let Some(scale) = self.params.read().fps_scale {
+            timestamp_us = (timestamp_us as f64 / scale).round() as i64;
+        }
+
+        self.recompute_if_invalidated();
 
         let (use_cache, hash, current_hash) = {
             let stab = self.stabilization.read();
@@ -823,6 +976,7 @@ impl StabilizationManager {
 
     pub fn trim_ranges(&self) -> Vec<(f64, f64)> { self.params.read().trim_ranges.clone() }
     pub fn set_trim_ranges(&self, v: Vec<(f64, f64)>) {
+        let v = stabilization_params::merge_trim_ranges(v);
         self.params.write().trim_ranges = if v.first() == Some(&(0.0, 1.0)) {
            Vec::new()
         } else {
@@ -837,17 +991,58 @@ impl StabilizationManager {
     pub fn set_stab_enabled          (&self, v: bool) { self.params.write().stab_enabled           = v; }
     pub fn set_frame_readout_time    (&self, v: f64)  { self.params.write().frame_readout_time     = v; }
     pub fn set_frame_readout_direction(&self, v: impl Into<ReadoutDirection>) { self.params.write().frame_readout_direction = v.into(); }
-    pub fn set_adaptive_zoom         (&self, v: f64)  { self.params.write().adaptive_zoom_window   = v; self.invalidate_zooming(); }
-    pub fn set_zooming_center_x      (&self, v: f64)  { self.params.write().adaptive_zoom_center_offset.0 = v; self.invalidate_zooming(); }
-    pub fn set_zooming_center_y      (&self, v: f64)  { self.params.write().adaptive_zoom_center_offset.1 = v; self.invalidate_zooming(); }
+    /// Readout time and direction which take precedence over the ones from the lens profile and the metadata, eg. when the camera reports them wrong
+    pub fn set_frame_readout_time_override(&self, v: Option<f64>) { self.params.write().frame_readout_time_override = v.map(f64::abs); }
+    pub fn set_frame_readout_direction_override(&self, v: Option<ReadoutDirection>) { self.params.write().frame_readout_direction_override = v; }
+    pub fn set_rolling_shutter_strength(&self, v: f64) { self.params.write().rolling_shutter_strength = v.clamp(0.0, 2.0); }
+    pub fn set_residual_correction_strength(&self, v: f64) { self.params.write().residual_correction_strength = v.clamp(0.0, 2.0); }
+    /// Replaces the residual wobble corrections in the analyzed ranges, the ones from the other ranges are kept
+    pub fn set_residual_corrections(&self, result: &synchronization::ResidualWobbleResult) {
+        let mut params = self.params.write();
+        params.residual_corrections.retain(|ts, _| !result.ranges_us.iter().any(|(from, to)| ts >= from && ts <= to));
+        params.residual_corrections.extend(result.corrections.iter().map(|(k, v)| (*k, *v)));
+    }
+    /// Removes the residual wobble corrections in `range_us`, or all of them
+    pub fn clear_residual_corrections(&self, range_us: Option<(i64, i64)>) {
+        let mut params = self.params.write();
+        match range_us {
+            Some((from, to)) => params.residual_corrections.retain(|ts, _| *ts < from || *ts > to),
+            None => params.residual_corrections.clear()
+        }
+    }
+    pub fn set_adaptive_zoom(&self, v: f64) {
+        let mut params = self.params.write();
+        // The correction is limited only without zooming
+        if params.limit_to_zoom_budget && zooming::zoom_budget::is_no_zoom(params.adaptive_zoom_window) != zooming::zoom_budget::is_no_zoom(v) {
+            self.invalidate_smoothing();
+        }
+        params.adaptive_zoom_window = v;
+        self.invalidate_zoom_filtering();
+    }
+    pub fn set_adaptive_zoom_look_ahead(&self, v: f64) { self.params.write().adaptive_zoom_look_ahead = v; self.invalidate_zoom_filtering(); }
+    pub fn set_static_zoom_percentile(&self, v: f64) { self.params.write().static_zoom_percentile = v; self.invalidate_zoom_filtering(); }
+    pub fn set_zooming_center_x      (&self, v: f64)  { self.params.write().adaptive_zoom_center_offset.0 = v; self.invalidate_zoom_filtering(); }
+    pub fn set_zooming_center_y      (&self, v: f64)  { self.params.write().adaptive_zoom_center_offset.1 = v; self.invalidate_zoom_filtering(); }
     pub fn set_additional_rotation_x (&self, v: f64)  { self.params.write().additional_rotation.0  = v; self.invalidate_smoothing(); }
     pub fn set_additional_rotation_y (&self, v: f64)  { self.params.write().additional_rotation.1  = v; self.invalidate_smoothing(); }
     pub fn set_additional_rotation_z (&self, v: f64)  { self.params.write().additional_rotation.2  = v; self.invalidate_smoothing(); }
+    pub fn set_horizon_compensation_pitch(&self, v: f64) { self.params.write().horizon_compensation.0 = v; self.invalidate_zooming(); }
+    pub fn set_horizon_compensation_roll (&self, v: f64) { self.params.write().horizon_compensation.1 = v; self.invalidate_zooming(); }
+    pub fn set_horizon_compensation_fov  (&self, v: f64) { self.params.write().horizon_compensation_fov = v; self.invalidate_zooming(); }
     pub fn set_additional_translation_x(&self, v: f64){ self.params.write().additional_translation.0 = v; self.invalidate_zooming(); }
     pub fn set_additional_translation_y(&self, v: f64){ self.params.write().additional_translation.1 = v; self.invalidate_zooming(); }
     pub fn set_additional_translation_z(&self, v: f64){ self.params.write().additional_translation.2 = v; self.invalidate_zooming(); }
-    pub fn set_zooming_method        (&self, v: i32)  { self.params.write().adaptive_zoom_method   = v;        self.invalidate_zooming(); }
-    pub fn set_fov                   (&self, v: f64)  { self.params.write().fov                    = v; }
+    pub fn set_zooming_method        (&self, v: i32)  { self.params.write().adaptive_zoom_method   = v;        self.invalidate_zoom_filtering(); }
+    pub fn set_fov(&self, v: f64) {
+        let mut params = self.params.write();
+        params.fov = v;
+        if params.limit_to_zoom_budget && zooming::zoom_budget::is_no_zoom(params.adaptive_zoom_window) {
+            self.invalidate_smoothing();
+        } else if params.horizon_compensation.0 != 0.0 {
+            // The compensated pitch depends on the FOV
+            self.invalidate_zooming();
+        }
+    }
     pub fn set_fov_overview          (&self, v: bool) { self.params.write().fov_overview           = v; }
     pub fn set_show_safe_area        (&self, v: bool) { self.params.write().show_safe_area         = v; }
     pub fn set_lens_correction_amount(&self, v: f64)  { self.params.write().lens_correction_amount = v; self.invalidate_zooming(); }
@@ -864,6 +1059,18 @@ impl StabilizationManager {
         params.max_zoom_iterations = iters.max(1);
         self.invalidate_smoothing();
     }
+    pub fn set_limit_to_zoom_budget(&self, v: bool) {
+        self.params.write().limit_to_zoom_budget = v;
+        self.invalidate_smoothing();
+    }
+    /// Makes the synchronization and zooming produce bit-identical results for the same input, for comparing parameter changes and regression tests.
+    /// The RANSAC sampling is seeded from the frame timestamps, ties in the parallel offset searches are broken by the offset
+    /// and the zooming doesn't reuse the FOVs from the previous computation. The sync speed is practically the same,
+    /// but the zooming searches all frames on every change instead of only the changed ones, so keyframed edits are slower
+    pub fn set_deterministic(&self, v: bool) {
+        self.params.write().deterministic = v;
+        self.invalidate_zooming();
+    }
 
     pub fn set_video_speed(&self, v: f64, link_with_smoothness: bool, link_with_zooming: bool, link_with_zooming_limit: bool) {
         let mut params = self.params.write();
@@ -902,12 +1109,21 @@ impl StabilizationManager {
         self.invalidate_zooming();
     }
     pub fn invalidate_zooming(&self) {
+        self.zooming_revision.fetch_add(1, SeqCst);
+        self.invalidate_zoom_filtering();
+    }
+    /// For the parameters which don't change the FOV needed by each frame, so the cached per-frame FOVs are reused
+    pub fn invalidate_zoom_filtering(&self) {
         self.invalidate_ongoing_computations();
         self.zooming_checksum.store(0, SeqCst);
     }
+    /// Of everything that affects the rendered output, per section
+    pub fn get_state_checksum(&self) -> state_checksum::StateChecksum {
+        state_checksum::StateChecksum::from_manager(self)
+    }
 
-    pub fn invalidate_blocking_smoothing(&self) { self.invalidate_ongoing_computations(); self.smoothing_invalidated.store(true, SeqCst); self.zooming_invalidated.store(true, SeqCst); self.undistortion_invalidated.store(true, SeqCst); }
-    pub fn invalidate_blocking_zooming(&self) { self.invalidate_ongoing_computations(); self.zooming_invalidated.store(true, SeqCst); self.undistortion_invalidated.store(true, SeqCst); }
+    pub fn invalidate_blocking_smoothing(&self) { self.invalidate_ongoing_computations(); self.zooming_revision.fetch_add(1, SeqCst); self.smoothing_invalidated.store(true, SeqCst); self.zooming_invalidated.store(true, SeqCst); self.undistortion_invalidated.store(true, SeqCst); }
+    pub fn invalidate_blocking_zooming(&self) { self.invalidate_ongoing_computations(); self.zooming_revision.fetch_add(1, SeqCst); self.zooming_invalidated.store(true, SeqCst); self.undistortion_invalidated.store(true, SeqCst); }
     pub fn invalidate_blocking_undistortion(&self) { self.invalidate_ongoing_computations(); self.undistortion_invalidated.store(true, SeqCst); }
 
     pub fn set_digital_lens_name(&self, v: String) {
@@ -930,6 +1146,20 @@ impl StabilizationManager {
         }
         self.invalidate_zooming();
     }
+    pub fn set_lens_flip(&self, horizontal: bool, vertical: bool) {
+        self.params.write().lens_flip = (horizontal, vertical);
+        #[cfg(feature = "opencv")]
+        if let Some(ref mut calib) = *self.lens_calibrator.write() {
+            calib.input_flipped = (horizontal, vertical);
+        }
+        self.invalidate_zooming();
+    }
+    #[cfg(feature = "opencv")]
+    pub fn set_calibration_board(&self, board: calibration::charuco::CalibrationBoard) {
+        if let Some(ref mut calib) = *self.lens_calibrator.write() {
+            calib.set_board(board);
+        }
+    }
     pub fn set_lens_is_asymmetrical(&self, v: bool) {
         self.lens.write().asymmetrical = v;
         #[cfg(feature = "opencv")]
@@ -954,12 +1184,32 @@ impl StabilizationManager {
         self.keyframes.write().update_gyro(&self.gyro.read());
         self.invalidate_zooming();
     }
+    pub fn set_offset_interpolation(&self, v: gyro_source::OffsetInterpolation) {
+        let mut gyro = self.gyro.write();
+        if gyro.set_offset_interpolation(v) {
+            self.keyframes.write().update_gyro(&gyro);
+            drop(gyro);
+            self.invalidate_zooming();
+        }
+    }
     pub fn offset_at_video_timestamp(&self, timestamp_us: i64) -> f64 {
         self.gyro.read().offset_at_video_timestamp(timestamp_us as f64 / 1000.0)
     }
 
     pub fn set_imu_lpf(&self, lpf: f64) {
-        self.gyro.write().imu_transforms.imu_lpf = lpf;
+        let acc_lpf = self.gyro.read().imu_transforms.acc_lpf;
+        self.set_imu_filters(lpf, acc_lpf);
+    }
+    pub fn set_imu_acc_lpf(&self, lpf: f64) {
+        let gyro_lpf = self.gyro.read().imu_transforms.imu_lpf;
+        self.set_imu_filters(gyro_lpf, lpf);
+    }
+    fn set_imu_filters(&self, gyro_lpf: f64, acc_lpf: f64) {
+        if self.gyro.write().set_imu_lpf(gyro_lpf, acc_lpf) {
+            // Sync point ranking is computed from the filtered gyro data
+            *self.sync_data.write() = SyncData::default();
+            self.invalidate_smoothing();
+        }
     }
     pub fn set_imu_median_filter(&self, size: i32) {
         self.gyro.write().imu_transforms.imu_mf = size;
@@ -973,9 +1223,172 @@ impl StabilizationManager {
     pub fn set_imu_orientation(&self, orientation: String) {
         self.gyro.write().imu_transforms.imu_orientation = Some(orientation);
     }
+    /// Stores `orientation` as the default for the camera of the loaded file in the user presets
+    pub fn save_imu_orientation_preset(&self, orientation: &str) -> bool {
+        let Some(id) = self.camera_id.read().clone() else { return false; };
+        if id.brand.is_empty() && id.model.is_empty() { return false; }
+        let firmware = gyro_source::orientation_presets::firmware_from_metadata(&self.gyro.read().file_metadata.read());
+        match gyro_source::orientation_presets::add(gyro_source::orientation_presets::OrientationPreset::for_camera(&id, &firmware, orientation)) {
+            Ok(_) => true,
+            Err(e) => { log::error!("Failed to save the IMU orientation preset: {e:?}"); false }
+        }
+    }
+    pub fn export_gcsv(&self, url: &str, options: &gyro_source::GcsvExportOptions) -> Result<(), GyroflowCoreError> {
+        self.gyro.read().export_gcsv(url, options)
+    }
     pub fn set_imu_bias(&self, bx: f64, by: f64, bz: f64) {
         self.gyro.write().imu_transforms.gyro_bias = Some([bx, by, bz]);
     }
+    pub fn get_detected_gyro_bias(&self) -> Option<StaticBiasEstimate> {
+        self.gyro.read().detected_bias.clone()
+    }
+    /// Sets the offsets found by the automatic sync, `(timestamp, offset, cost)` in ms. Points in frames without enough features are skipped,
+    /// all of them are kept in `sync_points`
+    pub fn apply_sync_offsets(&self, offsets: &[(f64, f64, f64)]) {
+        let mut gyro = self.gyro.write();
+        let mut sync_points = self.sync_points.write();
+        gyro.prevent_recompute = true;
+        for &(timestamp, offset, cost) in offsets {
+            log::info!("Setting offset at {:.4}: {:.4} (cost {:.4})", timestamp, offset, cost);
+            let new_ts = ((timestamp - offset) * 1000.0) as i64;
+            let mut point = processing_report::SyncPointReport { timestamp_ms: new_ts as f64 / 1000.0, offset_ms: offset, cost: Some(cost), accepted: true, rejection: None };
+            { // Check the offset
+                let sync_data = self.sync_data.read();
+                if !sync_data.rank.is_empty() {
+                    let index = ((timestamp - offset) / (sync_data.ratio * 1000.0)).round() as usize;
+                    if index < sync_data.rank.len() && sync_data.rank[index] < 20.0 {
+                        point.accepted = false;
+                        point.rejection = Some(format!("Not enough features in the frame (rank {:.1})", sync_data.rank[index]));
+                    }
+                }
+            }
+            if point.accepted {
+                // Remove existing offsets within 100ms range
+                gyro.remove_offsets_near(new_ts, 100.0);
+                gyro.set_offset(new_ts, offset);
+            }
+            sync_points.retain(|x| (x.timestamp_ms - point.timestamp_ms).abs() >= 100.0);
+            sync_points.push(point);
+        }
+        sync_points.sort_by(|a, b| a.timestamp_ms.total_cmp(&b.timestamp_ms));
+        gyro.prevent_recompute = false;
+        gyro.adjust_offsets();
+        self.keyframes.write().update_gyro(&gyro);
+    }
+
+    /// Summary of the current state of the processing, see `processing_report`. The render section is left for the caller
+    pub fn processing_report(&self) -> processing_report::ProcessingReport {
+        use processing_report::*;
+        let params = self.params.read();
+        let gyro = self.gyro.read();
+        let lens = self.lens.read();
+        let camera_id = self.camera_id.read().clone();
+
+        // Offsets in use, with the results of the sync where they came from it, and the rejected ones
+        let recorded = self.sync_points.read();
+        let mut points = gyro.get_offsets().iter().map(|(ts, offset)| {
+            let timestamp_ms = *ts as f64 / 1000.0;
+            let cost = recorded.iter().find(|x| x.accepted && (x.timestamp_ms - timestamp_ms).abs() < 0.001 && x.offset_ms == *offset).and_then(|x| x.cost);
+            SyncPointReport { timestamp_ms, offset_ms: *offset, cost, accepted: true, rejection: None }
+        }).collect::<Vec<_>>();
+        points.extend(recorded.iter().filter(|x| !x.accepted).cloned());
+        points.sort_by(|a, b| a.timestamp_ms.total_cmp(&b.timestamp_ms));
+
+        let smoothing = {
+            let smoothing = self.smoothing.read();
+            let parameters = match smoothing.current().get_parameters_json() {
+                serde_json::Value::Array(arr) => arr.iter().filter_map(|x| Some((x.get("name")?.as_str()?.to_string(), x.get("value")?.as_f64()?))).collect(),
+                _ => Vec::new()
+            };
+            SmoothingReport {
+                method: smoothing.current().get_name(),
+                parameters,
+                horizon_lock_amount: if smoothing.horizon_lock.lock_enabled { smoothing.horizon_lock.horizonlockpercent } else { 0.0 },
+                horizon_roll: smoothing.horizon_lock.horizonroll,
+            }
+        };
+        let (readout_time_ms, direction) = params.effective_readout();
+
+        ProcessingReport {
+            version: REPORT_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            input_file: self.input_file.read().url.clone(),
+            video: VideoReport {
+                width: params.size.0,
+                height: params.size.1,
+                fps: params.get_scaled_fps(),
+                frame_count: params.frame_count,
+                duration_ms: params.get_scaled_duration_ms(),
+            },
+            camera: camera_id.as_ref().map(|x| CameraReport {
+                brand: x.brand.clone(),
+                model: x.model.clone(),
+                lens_model: x.lens_model.clone(),
+                identifier: x.identifier.clone(),
+            }).unwrap_or_default(),
+            lens: LensReport {
+                name: if lens.calib_dimension.w > 0 { lens.get_display_name() } else { String::new() },
+                calibrated_by: lens.calibrated_by.clone(),
+                official: lens.official,
+                distortion_model: lens.distortion_model.clone().unwrap_or_default(),
+                checksum: lens.checksum.clone(),
+                match_score: camera_id.as_ref().filter(|_| lens.calib_dimension.w > 0).map(|x| lens.match_score(x)),
+            },
+            sync: SyncReport { points, interpolation: gyro.offset_interpolation, drift: gyro.get_drift_fit().cloned() },
+            rolling_shutter: RollingShutterReport {
+                readout_time_ms,
+                direction,
+                overridden: params.frame_readout_time_override.is_some() || params.frame_readout_direction_override.is_some(),
+                strength: params.rolling_shutter_strength,
+            },
+            gyro_bias: GyroBiasReport {
+                applied: gyro.imu_transforms.gyro_bias,
+                detected: gyro.detected_bias.clone(),
+            },
+            smoothing,
+            crop: CropReport::from_minimal_fovs(&params.minimal_fovs),
+            render: None,
+        }
+    }
+
+    pub fn has_gps(&self) -> bool {
+        self.gyro.read().has_gps()
+    }
+    /// GPS position at the video timestamp, `None` if there's no GPS data or no fix at that time
+    pub fn get_gps_at_timestamp(&self, timestamp_ms: f64) -> Option<gyro_source::gps::GpsSample> {
+        self.gyro.read().gps_at_video_timestamp(timestamp_ms)
+    }
+    pub fn get_gyro_gaps(&self) -> Vec<(f64, f64)> {
+        self.gyro.read().gaps().to_vec()
+    }
+    pub fn set_gyro_gap_repair(&self, v: gyro_source::GapRepair) {
+        if self.gyro.write().set_gap_repair(v) {
+            *self.sync_data.write() = SyncData::default();
+            self.invalidate_smoothing();
+        }
+    }
+    pub fn set_magnetometer_correction(&self, enabled: bool, time_constant_s: f64) {
+        let params = imu_integration::magnetometer::MagnetometerParams { enabled, time_constant_s, ..self.gyro.read().magnetometer };
+        if self.gyro.write().set_magnetometer_params(params) {
+            self.invalidate_smoothing();
+        }
+    }
+    pub fn set_gyro_time_mapping(&self, mapping: TimeMapping) {
+        if self.gyro.write().set_time_mapping(mapping) {
+            self.invalidate_smoothing();
+            self.invalidate_zooming();
+        }
+    }
+    /// Sets a constant rate time mapping from the capture frame rate found in the motion data file. Returns false if the file doesn't contain it
+    pub fn set_gyro_time_mapping_from_metadata(&self) -> bool {
+        let fps = self.params.read().fps;
+        let mapping = self.gyro.read().time_mapping_from_metadata(fps);
+        if let Some(mapping) = mapping {
+            self.set_gyro_time_mapping(mapping);
+            return true;
+        }
+        false
+    }
     pub fn recompute_gyro(&self) {
         self.gyro.write().apply_transforms();
         self.invalidate_smoothing();
@@ -1009,6 +1422,29 @@ impl StabilizationManager {
     pub fn set_gpu_decoding(&self, v: bool) {
         self.gpu_decoding.store(v, SeqCst);
     }
+
+    // Preview quality. Each of these returns the new level when it changed, then the preview has to be resized for it.
+    // The interpolation of the preview kernel follows the level
+    pub fn set_preview_quality_mode(&self, mode: preview_quality::PreviewQualityMode) -> Option<preview_quality::PreviewQualityLevel> {
+        self.update_preview_quality(|q| q.set_mode(mode))
+    }
+    pub fn set_preview_playing(&self, playing: bool) -> Option<preview_quality::PreviewQualityLevel> {
+        self.update_preview_quality(|q| q.set_playing(playing))
+    }
+    pub fn preview_frame_processed(&self, elapsed_ms: f64) -> Option<preview_quality::PreviewQualityLevel> {
+        let frame_duration_ms = 1000.0 / self.params.read().get_scaled_fps().max(1.0);
+        self.update_preview_quality(|q| { q.frame_processed(elapsed_ms, frame_duration_ms); })
+    }
+    fn update_preview_quality(&self, cb: impl FnOnce(&mut preview_quality::PreviewQuality)) -> Option<preview_quality::PreviewQualityLevel> {
+        let mut quality = self.preview_quality.write();
+        let prev = quality.active_level();
+        cb(&mut quality);
+        let level = quality.active_level();
+        if level == prev { return None; }
+
+        self.stabilization.write().interpolation_override = quality.interpolation_override();
+        Some(level)
+    }
     pub fn set_smoothing_method(&self, index: usize) -> serde_json::Value {
         let mut smooth = self.smoothing.write();
         smooth.set_current(index);
@@ -1025,6 +1461,30 @@ impl StabilizationManager {
         self.smoothing.write().horizon_lock.set_horizon(lock_percent, roll);
         self.invalidate_smoothing();
     }
+    pub fn set_axis_lock(&self, pitch: bool, yaw: bool, roll: bool, horizon_reference: bool) {
+        let mut smoothing = self.smoothing.write();
+        (smoothing.axis_lock.pitch, smoothing.axis_lock.yaw, smoothing.axis_lock.roll) = (pitch, yaw, roll);
+        smoothing.axis_lock.horizon_reference = horizon_reference;
+        self.invalidate_smoothing();
+    }
+    pub fn set_axis_lock_target(&self, pitch: f64, yaw: f64, roll: f64) {
+        self.smoothing.write().axis_lock.target = (pitch, yaw, roll);
+        self.invalidate_smoothing();
+    }
+    pub fn set_horizon_gravity_reference(&self, strength: f64) {
+        self.smoothing.write().horizon_lock.gravity_reference_strength = strength.clamp(0.0, 1.0);
+        self.invalidate_smoothing();
+    }
+    pub fn get_gravity_reference(&self) -> Vec<gyro_source::gravity_reference::GravitySample> {
+        self.gyro.read().gravity_reference.clone()
+    }
+    pub fn set_integration_method(&self, method: IntegrationMethod) {
+        self.invalidate_ongoing_computations();
+        if self.gyro.write().set_integration_method(method) {
+            *self.sync_data.write() = SyncData::default();
+            self.invalidate_smoothing();
+        }
+    }
     pub fn set_use_gravity_vectors(&self, v: bool) {
         self.gyro.write().set_use_gravity_vectors(v);
         self.invalidate_smoothing();
@@ -1033,6 +1493,56 @@ impl StabilizationManager {
         self.gyro.write().set_horizon_lock_integration_method(v);
         self.invalidate_smoothing();
     }
+    /// Frames which need more zoom than the static zoom, as (frame, timestamp in ms, needed zoom / static zoom)
+    pub fn get_static_zoom_exceeding_frames(&self) -> Vec<(usize, f64, f64)> {
+        let params = self.params.read();
+        if params.adaptive_zoom_window >= -0.9 { return Vec::new(); }
+        let fps = params.get_scaled_fps();
+        zooming::exceeding_frames(&params.fovs, &params.minimal_fovs, &params.trim_ranges).into_iter()
+            .map(|(frame, excess)| (frame, timestamp_at_frame(frame as i32, fps), excess))
+            .collect()
+    }
+    /// Time ranges (in ms) of the frames where the zoom limit shows the outside of the image
+    pub fn get_zoom_overflow_ranges(&self) -> Vec<(f64, f64)> {
+        let params = self.params.read();
+        let fps = params.get_scaled_fps();
+        let mut ranges: Vec<(f64, f64)> = Vec::new();
+        for (frame, _) in params.zoom_overflow.iter().enumerate().filter(|x| *x.1) {
+            let (from, to) = (timestamp_at_frame(frame as i32, fps), timestamp_at_frame(frame as i32 + 1, fps));
+            match ranges.last_mut() {
+                Some(last) if (last.1 - from).abs() < 0.001 => last.1 = to,
+                _ => ranges.push((from, to))
+            }
+        }
+        ranges
+    }
+    /// Zoom of every frame as used for rendering. Empty until the zooming is computed
+    pub fn get_zoom_data(&self) -> Vec<zooming::zoom_data::ZoomFrame> {
+        zooming::zoom_data::per_frame(&stabilization::ComputeParams::from_manager(self))
+    }
+    pub fn export_zoom_data(&self, url: &str, csv: bool) -> Result<(), GyroflowCoreError> {
+        let frames = self.get_zoom_data();
+        let data = if csv { zooming::zoom_data::to_csv(&frames) } else { zooming::zoom_data::to_json(&frames)? };
+        Ok(filesystem::write(url, data.as_bytes())?)
+    }
+    pub fn get_camera_transforms(&self, convention: camera_export::AxisConvention, with_homography: bool) -> Vec<camera_export::CameraFrame> {
+        camera_export::per_frame(&stabilization::ComputeParams::from_manager(self), convention, with_homography)
+    }
+    /// Per-frame camera orientations, correction and FOV for Blender, After Effects etc. in the `convention` axes
+    pub fn export_camera_transforms(&self, url: &str, csv: bool, convention: camera_export::AxisConvention, with_homography: bool) -> Result<(), GyroflowCoreError> {
+        let frames = self.get_camera_transforms(convention, with_homography);
+        let data = if csv { camera_export::to_csv(&frames) } else { camera_export::to_json(&frames)? };
+        Ok(filesystem::write(url, data.as_bytes())?)
+    }
+    /// Frames where the zooming center was moved toward the middle to fit the zoom limit, as (frame, timestamp in ms, fraction of the offset)
+    pub fn get_zoom_center_limits(&self) -> Vec<(usize, f64, f64)> {
+        let params = self.params.read();
+        let fps = params.get_scaled_fps();
+        params.zoom_center_scale.iter().enumerate()
+            .filter(|(_, scale)| **scale < 1.0)
+            .map(|(frame, scale)| (frame, timestamp_at_frame(frame as i32, fps), *scale))
+            .collect()
+    }
     pub fn get_smoothing_max_angles(&self) -> (f64, f64, f64) {
         self.gyro.read().max_angles
     }
@@ -1042,6 +1552,41 @@ impl StabilizationManager {
     pub fn get_smoothing_algs(&self) -> Vec<String> {
         self.smoothing.read().get_names()
     }
+    /// Id, name and parameter descriptors of all smoothing algorithms
+    pub fn get_smoothing_algs_json(&self) -> serde_json::Value {
+        self.smoothing.read().get_algorithms_json()
+    }
+    /// Original and smoothed orientation, correction and crop factor sampled over the video time range.
+    /// Cached until the smoothing or zooming changes, so it can be refreshed after every parameter change
+    pub fn get_graph_data(&self, from_ms: f64, to_ms: f64, samples: usize) -> Arc<graph_data::GraphData> {
+        use std::hash::Hasher;
+        let smoothing_checksum = self.smoothing_checksum.load(SeqCst);
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(smoothing_checksum);
+        hasher.write_u64(self.zooming_checksum.load(SeqCst));
+        hasher.write_u64(from_ms.to_bits());
+        hasher.write_u64(to_ms.to_bits());
+        hasher.write_usize(samples);
+        let key = hasher.finish();
+
+        if let Some((cached_key, data)) = self.graph_data.read().as_ref() {
+            if *cached_key == key { return data.clone(); }
+        }
+
+        let (minimal_fovs, fps) = {
+            let params = self.params.read();
+            (params.minimal_fovs.clone(), params.get_scaled_fps())
+        };
+        let data = Arc::new(graph_data::GraphData::compute(&self.gyro.read(), &minimal_fovs, fps, from_ms, to_ms, samples));
+        // Not cached while the smoothing is being computed
+        if smoothing_checksum != 0 {
+            *self.graph_data.write() = Some((key, data.clone()));
+        }
+        data
+    }
+    pub fn register_smoothing_algorithm(&self, alg: Box<dyn smoothing::SmoothingAlgorithm>) -> usize {
+        self.smoothing.write().register_algorithm(alg)
+    }
 
     pub fn get_cloned(&self) -> StabilizationManager {
         StabilizationManager {
@@ -1051,6 +1596,7 @@ impl StabilizationManager {
             keyframes:  Arc::new(RwLock::new(self.keyframes.read().clone())),
             smoothing:  Arc::new(RwLock::new(self.smoothing.read().clone())),
             input_file: Arc::new(RwLock::new(self.input_file.read().clone())),
+            sync_points: Arc::new(RwLock::new(self.sync_points.read().clone())),
             lens_profile_db: self.lens_profile_db.clone(),
 
             // NOT cloned:
@@ -1062,6 +1608,7 @@ impl StabilizationManager {
             // zooming_checksum
             // prevent_recompute
             // camera_id
+            // preview_quality
             ..Default::default()
         }
     }
@@ -1082,6 +1629,7 @@ impl StabilizationManager {
         self.invalidate_smoothing();
         *self.input_file.write() = InputFile::default();
         *self.camera_id.write() = None;
+        self.sync_points.write().clear();
 
         *self.gyro.write() = GyroSource::new();
         self.keyframes.write().clear();
@@ -1100,6 +1648,7 @@ impl StabilizationManager {
             self.gyro.write().init_from_params(&params);
             self.keyframes.write().timestamp_scale = params.fps_scale;
         }
+        self.update_keyframe_frame_grid();
 
         if recompute {
             self.stabilization.write().set_compute_params(stabilization::ComputeParams::from_manager(self));
@@ -1122,8 +1671,17 @@ impl StabilizationManager {
     }
 
     pub fn export_gyroflow_file(&self, url: &str, typ: GyroflowProjectType, additional_data: &str) -> Result<(), GyroflowCoreError> {
+        {
+            // A project from a newer version is read-only, it can only be saved as a new file
+            let input_file = self.input_file.read();
+            if let Some(preserved) = &input_file.preserved_project_data {
+                if input_file.project_file_url.as_deref() == Some(url) {
+                    return Err(GyroflowCoreError::ReadOnlyProject(project_migration::file_version(preserved)));
+                }
+            }
+        }
         let data = self.export_gyroflow_data(typ, additional_data, Some(url))?;
-        filesystem::write(url, data.as_bytes())?;
+        filesystem::write_atomic(url, data.as_bytes())?;
 
         self.input_file.write().project_file_url = Some(url.to_string());
 
@@ -1133,7 +1691,7 @@ impl StabilizationManager {
         let gyro = self.gyro.read();
         let params = self.params.read();
 
-        let (smoothing_name, smoothing_params, horizon_amount, horizon_roll) = {
+        let (smoothing_id, smoothing_name, smoothing_params, horizon_amount, horizon_roll, horizon_gravity_reference, axis_lock) = {
             let smoothing_lock = self.smoothing.read();
             let smoothing = smoothing_lock.current();
 
@@ -1153,7 +1711,7 @@ impl StabilizationManager {
                 horizon_amount = 0.0;
             }
 
-            (smoothing.get_name(), parameters, horizon_amount, smoothing_lock.horizon_lock.horizonroll)
+            (smoothing.get_id(), smoothing.get_name(), parameters, horizon_amount, smoothing_lock.horizon_lock.horizonroll, smoothing_lock.horizon_lock.gravity_reference_strength, smoothing_lock.axis_lock.clone())
         };
 
         let input_file = self.input_file.read().clone();
@@ -1162,7 +1720,7 @@ impl StabilizationManager {
 
         let mut obj = serde_json::json!({
             "title": "Gyroflow data file",
-            "version": 3,
+            "version": project_migration::CURRENT_VERSION,
             "app_version": env!("CARGO_PKG_VERSION").to_string(),
             "videofile": input_file.url,
             "calibration_data": self.lens.read().get_json_value().unwrap_or_else(|_| serde_json::json!({})),
@@ -1175,6 +1733,7 @@ impl StabilizationManager {
             "background_margin":          params.background_margin,
             "background_margin_feather":  params.background_margin_feather,
             "light_refraction_coefficient": params.light_refraction_coefficient,
+            "lens_flip": [params.lens_flip.0, params.lens_flip.1],
 
             "video_info": {
                 "width":       params.size.0,
@@ -1191,17 +1750,29 @@ impl StabilizationManager {
             "stabilization": {
                 "fov":                    params.fov,
                 "method":                 smoothing_name,
+                "method_id":              smoothing_id,
                 "smoothing_params":       smoothing_params,
                 "frame_readout_time":     params.frame_readout_time.abs(),
                 "frame_readout_direction": params.frame_readout_direction,
+                "frame_readout_time_override": params.frame_readout_time_override,
+                "frame_readout_direction_override": params.frame_readout_direction_override,
+                "rolling_shutter_strength": params.rolling_shutter_strength,
+                "residual_correction_strength": params.residual_correction_strength,
+                "residual_corrections":   params.residual_corrections,
                 "adaptive_zoom_window":   params.adaptive_zoom_window,
+                "adaptive_zoom_look_ahead": params.adaptive_zoom_look_ahead,
+                "static_zoom_percentile": params.static_zoom_percentile,
                 "adaptive_zoom_center_offset": params.adaptive_zoom_center_offset,
                 "adaptive_zoom_method":   params.adaptive_zoom_method,
                 "additional_rotation":    params.additional_rotation,
                 "additional_translation": params.additional_translation,
+                "horizon_compensation":   params.horizon_compensation,
+                "horizon_compensation_fov": params.horizon_compensation_fov,
                 "lens_correction_amount": params.lens_correction_amount,
                 "horizon_lock_amount":    horizon_amount,
                 "horizon_lock_roll":      horizon_roll,
+                "horizon_lock_gravity_reference": horizon_gravity_reference,
+                "axis_lock":              axis_lock,
                 "use_gravity_vectors":    gyro.use_gravity_vectors,
                 "horizon_lock_integration_method": gyro.horizon_lock_integration_method,
                 "video_speed":                   params.video_speed,
@@ -1210,21 +1781,34 @@ impl StabilizationManager {
                 "video_speed_affects_zooming_limit": params.video_speed_affects_zooming_limit,
                 "max_zoom":               params.max_zoom,
                 "max_zoom_iterations":    params.max_zoom_iterations,
+                "limit_to_zoom_budget":   params.limit_to_zoom_budget,
             },
             "gyro_source": {
                 "filepath":           gyro.file_url,
                 "lpf":                gyro.imu_transforms.imu_lpf,
+                "acc_lpf":            gyro.imu_transforms.acc_lpf,
                 "mf":                 gyro.imu_transforms.imu_mf,
                 "rotation":           gyro.imu_transforms.imu_rotation_angles,
                 "acc_rotation":       gyro.imu_transforms.acc_rotation_angles,
                 "imu_orientation":    gyro.imu_transforms.imu_orientation,
                 "gyro_bias":          gyro.imu_transforms.gyro_bias,
-                "integration_method": gyro.integration_method,
+                "detected_bias":      gyro.detected_bias,
+                "gap_repair":         gyro.gap_repair,
+                "has_gps":            gyro.has_gps(),
+                "segments":           gyro.segments,
+                "time_mapping":       gyro.time_mapping,
+                "magnetometer":       gyro.magnetometer,
+                "secondary_gyro":     gyro.secondary_gyro,
+                "gyro_stream":        gyro.gyro_stream,
+                "integration_method": gyro.integration_method.index(),
+                "integration_params": gyro.integration_method,
                 "sample_index":       gyro.file_load_options.sample_index,
                 "detected_source":    gyro.file_metadata.read().detected_source,
             },
 
             "offsets": gyro.get_offsets(), // timestamp, offset value
+            "offset_interpolation": gyro.offset_interpolation,
+            "offset_drift": gyro.get_drift_fit(),
             "keyframes": self.keyframes.read().serialize(),
 
             // "trim_ranges": params.trim_ranges,
@@ -1232,6 +1816,11 @@ impl StabilizationManager {
         });
 
         util::merge_json(&mut obj, &serde_json::from_str(additional_data).unwrap_or_default());
+        if let Some(preserved) = &input_file.preserved_project_data {
+            project_migration::restore_preserved(&mut obj, preserved);
+            // It has the fields of the newer version, so it's still a file of that version
+            obj["version"] = project_migration::file_version(preserved).into();
+        }
 
         #[cfg(any(target_os = "macos", target_os = "ios"))]
         if let serde_json::Value::Object(ref mut obj) = obj {
@@ -1247,7 +1836,8 @@ impl StabilizationManager {
         }
 
         if let Some(serde_json::Value::Object(ref mut obj)) = obj.get_mut("gyro_source") {
-            let file_metadata = gyro.file_metadata.read();
+            let primary_metadata = gyro.primary_file_metadata();
+            let file_metadata = primary_metadata.read();
             if typ == GyroflowProjectType::Simple {
                 if let Ok(val) = serde_json::to_value(file_metadata.thin()) {
                     obj.insert("file_metadata".into(), val);
@@ -1262,12 +1852,11 @@ impl StabilizationManager {
                 let mut imu_timestamps = Vec::with_capacity(gyro.quaternions.len());
                 let mut imu_timestamps_final = Vec::with_capacity(gyro.quaternions.len());
                 for (t, _) in &gyro.quaternions {
-                    let mut timestamp_ms = *t as f64 / 1000.0;
-                    timestamp_ms += gyro.offset_at_gyro_timestamp(timestamp_ms);
+                    let timestamp_ms = gyro.gyro_to_video_timestamp(*t as f64 / 1000.0);
 
                     imu_timestamps.push(timestamp_ms);
 
-                    let frame = ((timestamp_ms - params.frame_readout_time / 2.0) * (params.get_scaled_fps() / 1000.0)).ceil() as usize;
+                    let frame = ((timestamp_ms - params.effective_readout().0 / 2.0) * (params.get_scaled_fps() / 1000.0)).ceil() as usize;
                     imu_timestamps_final.push(timestamp_ms - file_metadata.per_frame_time_offsets.get(frame).unwrap_or(&0.0));
                 }
                 util::compress_to_base91_cbor(&imu_timestamps)           .and_then(|s| obj.insert("synced_imu_timestamps" .into(), serde_json::Value::String(s)));
@@ -1278,6 +1867,8 @@ impl StabilizationManager {
             }
         }
 
+        project_integrity::sign(&mut obj);
+
         Ok(serde_json::to_string_pretty(&obj)?)
     }
 
@@ -1317,8 +1908,35 @@ impl StabilizationManager {
         }
         result
     }
+    /// Loads the project without its embedded motion data and reads it again from the source file instead.
+    /// Used after `GyroflowCoreError::EmbeddedDataCorrupted`, the rest of the project is kept
+    pub fn import_gyroflow_file_reloading_telemetry<F: Fn(f64)>(&self, url: &str, progress_cb: F, cancel_flag: Arc<AtomicBool>, is_plugin: bool) -> std::result::Result<serde_json::Value, GyroflowCoreError> {
+        let data = filesystem::read(url)?;
+        let mut obj: serde_json::Value = serde_json::from_slice(&data).map_err(Self::map_project_json_error)?;
+        if let Err(e) = project_integrity::verify(&obj) {
+            ::log::warn!("Reloading the motion data from the source url={url} error={e}");
+            project_integrity::strip_embedded(&mut obj, &e);
+        }
+        let data = serde_json::to_vec(&obj)?;
+
+        let mut is_preset = false;
+        let result = self.import_gyroflow_data(&data, true, Some(url), progress_cb, cancel_flag, &mut is_preset, is_plugin);
+        if !is_preset && result.is_ok() {
+            self.input_file.write().project_file_url = Some(url.to_string());
+        }
+        result
+    }
+    fn map_project_json_error(e: serde_json::Error) -> GyroflowCoreError {
+        if e.is_eof() { GyroflowCoreError::ProjectFileTruncated } else { e.into() }
+    }
     pub fn import_gyroflow_data<F: Fn(f64)>(&self, data: &[u8], blocking: bool, url: Option<&str>, progress_cb: F, cancel_flag: Arc<AtomicBool>, is_preset: &mut bool, is_plugin: bool) -> std::result::Result<serde_json::Value, GyroflowCoreError> {
-        let mut obj: serde_json::Value = serde_json::from_slice(&data)?;
+        let mut obj: serde_json::Value = serde_json::from_slice(&data).map_err(Self::map_project_json_error)?;
+        if let Err(error) = project_integrity::verify(&obj) {
+            let source_url = project_integrity::telemetry_source(&obj).filter(|x| filesystem::exists(x));
+            return Err(GyroflowCoreError::EmbeddedDataCorrupted { error, source_url });
+        }
+        let migration = project_migration::migrate(&mut obj);
+        let preserved = migration.read_only.then(|| project_migration::preserved_fields(&obj));
         if let serde_json::Value::Object(ref mut obj) = obj {
             let mut output_size = None;
             let mut org_video_url = obj.get("videofile").and_then(|x| x.as_str()).unwrap_or(&"").to_string();
@@ -1364,6 +1982,8 @@ impl StabilizationManager {
 
                 self.gyro.write().init_from_params(&params);
                 self.keyframes.write().timestamp_scale = params.fps_scale;
+                drop(params);
+                self.update_keyframe_frame_grid();
             }
             if let Some(serde_json::Value::Object(ref mut obj)) = obj.get_mut("gyro_source") {
                 let mut org_gyro_url = obj.get("filepath").and_then(|x| x.as_str()).unwrap_or(&"").to_string();
@@ -1391,6 +2011,7 @@ impl StabilizationManager {
                 let is_compressed = obj.get("raw_imu").map(|x| x.is_string()).unwrap_or_default();
                 let is_main_video = org_gyro_url == org_video_url;
 
+                let mut loaded_from_file = false;
                 let built_in_gyro: std::io::Result<crate::gyro_source::FileMetadata> = util::decompress_from_base91_cbor(obj.get("file_metadata").and_then(|x| x.as_str()).unwrap_or_default());
 
                 // Load IMU data only if it's from another file or we are sure that built_in_gyro contains motion data
@@ -1463,28 +2084,63 @@ impl StabilizationManager {
                         let mut gyro = self.gyro.write();
                         gyro.load_from_telemetry(md);
                     } else if filesystem::exists(&gyro_url) && blocking {
-                        if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &Default::default(), progress_cb, cancel_flag) {
+                        loaded_from_file = true;
+                        if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &Default::default(), progress_cb, cancel_flag.clone()) {
                             ::log::warn!("Failed to load gyro data from {:?}: {:?}", gyro_url, e);
                         }
                     }
                 } else if filesystem::exists(&gyro_url) && blocking {
-                    if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &Default::default(), progress_cb, cancel_flag) {
+                    loaded_from_file = true;
+                    if let Err(e) = self.load_gyro_data(&gyro_url, is_main_video, &Default::default(), progress_cb, cancel_flag.clone()) {
                         ::log::warn!("Failed to load gyro data from {:?}: {:?}", gyro_url, e);
                     }
                 }
 
+                // Embedded motion data is already joined, otherwise load the remaining files
+                let segments: Vec<gyro_source::GyroSegment> = obj.get("segments").and_then(|x| serde_json::from_value(x.clone()).ok()).unwrap_or_default();
+                if !loaded_from_file {
+                    self.gyro.write().segments = segments;
+                } else if segments.len() > 1 {
+                    for segment in segments.iter().skip(1) {
+                        if let Err(e) = self.append_gyro_data(&segment.file_url, segment.time_offset_ms, &Default::default(), |_| (), cancel_flag.clone()) {
+                            ::log::warn!("Failed to append gyro data from {:?}: {:?}", segment.file_url, e);
+                        }
+                    }
+                }
+
+                // Secondary stream is always loaded from its file
+                let secondary = obj.get("secondary_gyro").and_then(|x| serde_json::from_value::<gyro_source::SecondaryGyro>(x.clone()).ok()).and_then(|mut secondary| {
+                    let (fps, size) = { let params = self.params.read(); (params.fps, params.size) };
+                    match GyroSource::parse_telemetry_file(&secondary.file_url, &secondary.file_load_options, size, fps, |_| (), cancel_flag.clone()) {
+                        Ok(md) => { secondary.raw_imu = md.raw_imu; Some(secondary) },
+                        Err(e) => { ::log::warn!("Failed to load secondary gyro data from {:?}: {:?}", secondary.file_url, e); None }
+                    }
+                });
+
                 let mut gyro = self.gyro.write();
                 if !org_gyro_url.is_empty() {
                     gyro.file_url = gyro_url.clone();
                 }
 
                 if let Some(v) = obj.get("lpf").and_then(|x| x.as_f64()) { gyro.imu_transforms.imu_lpf = v; }
+                if let Some(v) = obj.get("acc_lpf").and_then(|x| x.as_f64()) { gyro.imu_transforms.acc_lpf = v; }
                 if let Some(v) = obj.get("mf").and_then(|x| x.as_i64()) { gyro.imu_transforms.imu_mf = v as _; }
-                if let Some(v) = obj.get("integration_method").and_then(|x| x.as_u64()) { gyro.integration_method = v as usize; }
+                if let Some(v) = obj.get("integration_method").and_then(|x| x.as_u64()) { gyro.integration_method = IntegrationMethod::from_index(v as usize); }
+                if let Some(v) = obj.get("integration_params").and_then(|x| serde_json::from_value::<IntegrationMethod>(x.clone()).ok()) {
+                    if v.index() == gyro.integration_method.index() { gyro.integration_method = v; }
+                }
                 if let Some(v) = obj.get("imu_orientation").and_then(|x| x.as_str()) { gyro.imu_transforms.imu_orientation = Some(v.to_string()); }
                 if let Some(v) = obj.get("rotation")     { let v: [f64; 3] = serde_json::from_value(v.clone()).unwrap_or_default(); gyro.imu_transforms.set_imu_rotation(v[0], v[1], v[2]); }
                 if let Some(v) = obj.get("acc_rotation") { let v: [f64; 3] = serde_json::from_value(v.clone()).unwrap_or_default(); gyro.imu_transforms.set_acc_rotation(v[0], v[1], v[2]); }
                 if let Some(v) = obj.get("gyro_bias")    { gyro.imu_transforms.gyro_bias = serde_json::from_value(v.clone()).ok(); }
+                if let Some(v) = obj.get("magnetometer").and_then(|x| serde_json::from_value(x.clone()).ok()) { gyro.magnetometer = v; }
+                if let Some(v) = obj.get("time_mapping").and_then(|x| serde_json::from_value(x.clone()).ok()) { gyro.time_mapping = v; }
+                if let Some(v) = obj.get("detected_bias") { gyro.detected_bias = serde_json::from_value(v.clone()).ok().flatten(); }
+                if let Some(v) = obj.get("gap_repair").and_then(|x| serde_json::from_value(x.clone()).ok()) { gyro.gap_repair = v; }
+                if let Some(secondary) = secondary {
+                    gyro.gyro_stream = obj.get("gyro_stream").and_then(|x| serde_json::from_value(x.clone()).ok()).unwrap_or_default();
+                    gyro.attach_secondary(secondary, false, 0.0);
+                }
 
                 obj.remove("raw_imu");
                 obj.remove("quaternions");
@@ -1505,11 +2161,18 @@ impl StabilizationManager {
                 if let Some(v) = obj.get("frame_readout_time")    .and_then(|x| x.as_f64()) { params.frame_readout_time      = v; if v < 0.0 { params.frame_readout_direction = ReadoutDirection::BottomToTop; } }
                 if let Some(v) = obj.get("frame_readout_direction").and_then(|x| x.as_i64()) { params.frame_readout_direction = (v as i32).into(); }
                 if let Some(v) = obj.get("frame_readout_direction").and_then(|x| x.as_str()) { params.frame_readout_direction = v.into(); }
+                if let Some(v) = obj.get("frame_readout_time_override")     { params.frame_readout_time_override      = v.as_f64().map(f64::abs); }
+                if let Some(v) = obj.get("frame_readout_direction_override") { params.frame_readout_direction_override = serde_json::from_value(v.clone()).ok(); }
+                if let Some(v) = obj.get("rolling_shutter_strength").and_then(|x| x.as_f64()) { params.rolling_shutter_strength = v.clamp(0.0, 2.0); }
+                if let Some(v) = obj.get("residual_correction_strength").and_then(|x| x.as_f64()) { params.residual_correction_strength = v.clamp(0.0, 2.0); }
+                if let Some(v) = obj.get("residual_corrections")  { params.residual_corrections    = serde_json::from_value(v.clone()).unwrap_or_default(); }
                 if let Some(v) = obj.get("adaptive_zoom_window")  .and_then(|x| x.as_f64()) { params.adaptive_zoom_window    = v; }
+                if let Some(v) = obj.get("adaptive_zoom_look_ahead").and_then(|x| x.as_f64()) { params.adaptive_zoom_look_ahead = v; }
+                if let Some(v) = obj.get("static_zoom_percentile").and_then(|x| x.as_f64()) { params.static_zoom_percentile = v; }
                 if let Some(v) = obj.get("lens_correction_amount").and_then(|x| x.as_f64()) { params.lens_correction_amount  = v; }
-                if let Some(v) = obj.get("horizontal_rs")         .and_then(|x| x.as_bool()) { if v { params.frame_readout_direction = if params.frame_readout_time < 0.0 { ReadoutDirection::RightToLeft } else { ReadoutDirection::LeftToRight }; } }
                 if let Some(v) = obj.get("max_zoom")              .and_then(|x| x.as_f64()) { params.max_zoom                = Some(v); }
                 if let Some(v) = obj.get("max_zoom_iterations")   .and_then(|x| x.as_i64()) { params.max_zoom_iterations     = v as _; }
+                if let Some(v) = obj.get("limit_to_zoom_budget")  .and_then(|x| x.as_bool()) { params.limit_to_zoom_budget   = v; }
 
                 if let Some(v) = obj.get("video_speed").and_then(|x| x.as_f64()) { params.video_speed = v; }
                 if let Some(v) = obj.get("video_speed_affects_smoothing")    .and_then(|x| x.as_bool()) { params.video_speed_affects_smoothing     = v; }
@@ -1529,6 +2192,13 @@ impl StabilizationManager {
                         x.get(2).and_then(|x| x.as_f64()).unwrap_or_default()
                     );
                 }
+                if let Some(x) = obj.get("horizon_compensation").and_then(|x| x.as_array()) {
+                    params.horizon_compensation = (
+                        x.get(0).and_then(|x| x.as_f64()).unwrap_or_default(),
+                        x.get(1).and_then(|x| x.as_f64()).unwrap_or_default()
+                    );
+                }
+                if let Some(v) = obj.get("horizon_compensation_fov").and_then(|x| x.as_f64()) { params.horizon_compensation_fov = v; }
                 if let Some(x) = obj.get("additional_translation").and_then(|x| x.as_array()) {
                     params.additional_translation = (
                         x.get(0).and_then(|x| x.as_f64()).unwrap_or_default(),
@@ -1540,19 +2210,25 @@ impl StabilizationManager {
                     params.adaptive_zoom_method = zooming_method as i32;
                 }
 
-                if let Some(method) = obj.get("method").and_then(|x| x.as_str()) {
-                    let method_idx = self.get_smoothing_algs()
-                        .iter().enumerate()
-                        .find(|(_, m)| method == m.as_str())
-                        .map(|(idx, _)| idx)
-                        .unwrap_or(1);
-
-                    self.smoothing.write().set_current(method_idx);
+                // Older projects have only the name
+                let method_id = obj.get("method_id").and_then(|x| x.as_str());
+                let method = obj.get("method").and_then(|x| x.as_str());
+                let mut method_found = true;
+                if method_id.is_some() || method.is_some() {
+                    let mut smoothing = self.smoothing.write();
+                    let method_idx = method_id.and_then(|id| smoothing.index_of(id))
+                        .or_else(|| method.and_then(|name| smoothing.index_of_name(name)));
+                    if method_idx.is_none() {
+                        log::warn!("Smoothing algorithm {} is not available, using the default one", method_id.or(method).unwrap_or_default());
+                        method_found = false;
+                    }
+                    smoothing.set_current(method_idx.unwrap_or(1));
                 }
 
                 let mut smoothing = self.smoothing.write();
                 let empty_vec = Vec::new();
-                let smoothing_params = obj.get("smoothing_params").and_then(|x| x.as_array()).unwrap_or(&empty_vec);
+                // Parameters of a missing algorithm don't apply to the default one
+                let smoothing_params = obj.get("smoothing_params").and_then(|x| x.as_array()).filter(|_| method_found).unwrap_or(&empty_vec);
                 let smoothing_alg = smoothing.current_mut();
                 for param in smoothing_params {
                     (|| -> Option<()> {
@@ -1567,6 +2243,12 @@ impl StabilizationManager {
                         smoothing.horizon_lock.set_horizon(horizon_amount, horizon_roll);
                     }
                 }
+                if let Some(v) = obj.get("horizon_lock_gravity_reference").and_then(|x| x.as_f64()) {
+                    smoothing.horizon_lock.gravity_reference_strength = v;
+                }
+                if let Some(v) = obj.get("axis_lock").and_then(|x| serde_json::from_value(x.clone()).ok()) {
+                    smoothing.axis_lock = v;
+                }
                 if let Some(v) = obj.get("use_gravity_vectors").and_then(|x| x.as_bool()) {
                     self.gyro.write().set_use_gravity_vectors(v);
                 }
@@ -1600,6 +2282,13 @@ impl StabilizationManager {
                 }
             }
 
+            // Projects saved before it was added are linear, a preset without it keeps the current one
+            let offset_interpolation = obj.get("offset_interpolation").and_then(|x| serde_json::from_value(x.clone()).ok())
+                .or_else(|| (!*is_preset).then_some(gyro_source::OffsetInterpolation::Linear));
+            if let Some(v) = offset_interpolation {
+                let mut gyro = self.gyro.write();
+                if gyro.set_offset_interpolation(v) { self.keyframes.write().update_gyro(&gyro); }
+            }
             if let Some(serde_json::Value::Object(offsets)) = obj.get("offsets") {
                 let mut gyro = self.gyro.write();
                 gyro.set_offsets(offsets.iter().filter_map(|(k, v)| Some((k.parse().ok()?, v.as_f64()?))).collect());
@@ -1611,12 +2300,6 @@ impl StabilizationManager {
                 self.keyframes.write().deserialize(keyframes);
             }
 
-            if let Some(start) = obj.get("trim_start").and_then(|x| x.as_f64()) {
-                if let Some(end) = obj.get("trim_end").and_then(|x| x.as_f64()) {
-                    self.params.write().trim_ranges = vec![(start, end)];
-                }
-            }
-
             let duration_ms = self.params.read().duration_ms.max(1.0);
             if let Some(ranges) = obj.get("trim_ranges_ms").and_then(|x| x.as_array()) {
                 let ranges = ranges.iter().filter_map(|x| {
@@ -1627,9 +2310,9 @@ impl StabilizationManager {
                         None
                     }
                 }).collect::<Vec<_>>();
-                self.params.write().trim_ranges = ranges;
+                self.params.write().trim_ranges = stabilization_params::merge_trim_ranges(ranges);
             } else if let Some(ranges) = obj.get("trim_ranges").and_then(|x| x.as_array()) {
-                // Deprecated
+                // Older projects without the video duration
                 let ranges = ranges.iter().filter_map(|x| {
                     let x = x.as_array()?;
                     if x.len() == 2 {
@@ -1638,7 +2321,7 @@ impl StabilizationManager {
                         None
                     }
                 }).collect::<Vec<_>>();
-                self.params.write().trim_ranges = ranges;
+                self.params.write().trim_ranges = stabilization_params::merge_trim_ranges(ranges);
             }
 
             {
@@ -1657,10 +2340,16 @@ impl StabilizationManager {
                 if let Some(v) = obj.get("background_margin").and_then(|x| x.as_f64()) { params.background_margin = v; }
                 if let Some(v) = obj.get("background_margin_feather").and_then(|x| x.as_f64()) { params.background_margin_feather = v; }
                 if let Some(v) = obj.get("light_refraction_coefficient").and_then(|x| x.as_f64()) { params.light_refraction_coefficient = v; }
+                if let Some(v) = obj.get("lens_flip").and_then(|x| x.as_array()) {
+                    params.lens_flip = (v.first().and_then(|x| x.as_bool()).unwrap_or_default(), v.get(1).and_then(|x| x.as_bool()).unwrap_or_default());
+                }
             }
 
             {
                 let mut input_file = self.input_file.write();
+                if !*is_preset {
+                    input_file.preserved_project_data = preserved;
+                }
                 if *is_preset {
                     if let Some(name) = obj.get("name").and_then(|x| x.as_str()) {
                         input_file.preset_name = Some(name.into());
@@ -1684,6 +2373,7 @@ impl StabilizationManager {
                     }
                 }
             }
+            obj.insert("project_migration".into(), serde_json::to_value(&migration).unwrap_or_default());
 
             if blocking {
                 self.recompute_gyro();
@@ -1756,8 +2446,9 @@ impl StabilizationManager {
                 file_metadata.lens_profile.as_ref().map(|y| y.is_object()).unwrap_or_default()
             };
 
-            let id_str = self.camera_id.read().as_ref().map(|v| v.get_identifier_for_autoload()).unwrap_or_default();
-            if !id_str.is_empty() && !has_builtin_profile {
+            let camera_id = self.camera_id.read().clone();
+            if let (Some(camera_id), false) = (camera_id, has_builtin_profile) {
+                let id_str = camera_id.get_identifier_for_autoload();
                 let mut db = self.lens_profile_db.read();
                 if !db.loaded {
                     drop(db);
@@ -1767,8 +2458,23 @@ impl StabilizationManager {
                     }
                     db = self.lens_profile_db.read();
                 }
-                if db.contains_id(&id_str) {
-                    match self.load_lens_profile(&id_str) {
+                let lens_id = if !id_str.is_empty() && db.contains_id(&id_str) {
+                    Some(id_str)
+                } else {
+                    let candidates = db.find_candidates(&camera_id);
+                    match candidates.first() {
+                        Some(c) if c.score >= lens_profile::AUTO_APPLY_SCORE => Some(c.id.clone()),
+                        _ => {
+                            if !candidates.is_empty() {
+                                log::info!("No lens profile matched with enough confidence, closest: {:?}", &candidates[..candidates.len().min(3)]);
+                            }
+                            None
+                        }
+                    }
+                };
+                drop(db);
+                if let Some(lens_id) = lens_id {
+                    match self.load_lens_profile(&lens_id) {
                         Ok(_) => {
                             let (fr, frd) = { let lens = self.lens.read(); (lens.frame_readout_time, lens.frame_readout_direction) };
                             if let Some(fr) = fr {
@@ -1819,6 +2525,13 @@ impl StabilizationManager {
         self.keyframes.write().set_easing(typ, timestamp_us, easing);
         self.keyframes_updated(typ);
     }
+    pub fn set_keyframe_bezier(&self, typ: &KeyframeType, timestamp_us: i64, handles: [f64; 4]) {
+        self.keyframes.write().set_bezier(typ, timestamp_us, handles);
+        self.keyframes_updated(typ);
+    }
+    pub fn keyframe_bezier(&self, typ: &KeyframeType, timestamp_us: i64) -> Option<[f64; 4]> {
+        self.keyframes.read().bezier(typ, timestamp_us)
+    }
     pub fn keyframe_easing(&self, typ: &KeyframeType, timestamp_us: i64) -> Option<Easing> {
         self.keyframes.read().easing(typ, timestamp_us)
     }
@@ -1837,6 +2550,78 @@ impl StabilizationManager {
         self.keyframes.write().clear_type(typ);
         self.keyframes_updated(typ);
     }
+    pub fn shift_keyframes(&self, types: &[KeyframeType], delta_us: i64) -> KeyframeSets {
+        let sets = self.keyframes.write().shift(types, delta_us);
+        self.keyframe_sets_updated(&sets);
+        sets
+    }
+    pub fn scale_keyframes(&self, types: &[KeyframeType], anchor_us: i64, factor: f64) -> KeyframeSets {
+        let sets = self.keyframes.write().scale(types, anchor_us, factor);
+        self.keyframe_sets_updated(&sets);
+        sets
+    }
+    pub fn copy_keyframes(&self, from: &KeyframeType, to: &KeyframeType, map: impl Fn(f64) -> f64) -> Option<KeyframeSets> {
+        let sets = self.keyframes.write().copy_type(from, to, map)?;
+        self.keyframe_sets_updated(&sets);
+        Some(sets)
+    }
+    pub fn remove_keyframes_in_range(&self, types: &[KeyframeType], from_us: i64, to_us: i64) -> KeyframeSets {
+        let sets = self.keyframes.write().remove_range(types, from_us, to_us);
+        self.keyframe_sets_updated(&sets);
+        sets
+    }
+    pub fn replace_keyframes(&self, sets: &KeyframeSets) {
+        self.keyframes.write().replace(sets);
+        self.keyframe_sets_updated(sets);
+    }
+    // Trim range of the whole clip in microseconds, from the start of the first range to the end of the last one
+    fn keyframes_trim_range_us(&self) -> (i64, i64) {
+        let params = self.params.read();
+        let start = params.trim_ranges.first().map(|x| x.0).unwrap_or(0.0);
+        let end = params.trim_ranges.last().map(|x| x.1).unwrap_or(1.0);
+        ((start * params.duration_ms * 1000.0).round() as i64, (end * params.duration_ms * 1000.0).round() as i64)
+    }
+    /// Saves the keyframes of `types` to a standalone file. When `normalized`, the timestamps are relative to the trim range
+    pub fn export_keyframes(&self, url: &str, types: &[KeyframeType], normalized: bool) -> Result<(), GyroflowCoreError> {
+        let range = normalized.then(|| self.keyframes_trim_range_us());
+        let json = self.keyframes.read().export_json(types, range);
+        Ok(filesystem::write(url, serde_json::to_string_pretty(&json)?.as_bytes())?)
+    }
+    pub fn import_keyframes(&self, url: &str, policy: KeyframeImportPolicy) -> Result<KeyframeImport, GyroflowCoreError> {
+        let json: serde_json::Value = serde_json::from_slice(&filesystem::read(url)?)?;
+        let range = self.keyframes_trim_range_us();
+        let result = self.keyframes.write().import_json(&json, range, policy)?;
+        if !result.unknown.is_empty() {
+            log::warn!("Unknown keyframe types: {:?}", result.unknown);
+        }
+        for typ in &result.imported {
+            self.keyframes_updated(typ);
+        }
+        Ok(result)
+    }
+    fn keyframe_sets_updated(&self, sets: &KeyframeSets) {
+        for typ in sets.keys() {
+            self.keyframes_updated(typ);
+        }
+    }
+    fn keyframe_frame_grid(&self) -> FrameGrid {
+        let params = self.params.read();
+        FrameGrid::Constant { fps: params.fps, timestamp_scale: params.fps_scale }
+    }
+    fn update_keyframe_frame_grid(&self) {
+        let grid = self.params.read().keyframe_quantization.then(|| self.keyframe_frame_grid());
+        self.keyframes.write().frame_grid = grid;
+    }
+    /// Snaps new and moved keyframes to the nearest frame
+    pub fn set_keyframe_quantization(&self, enabled: bool) {
+        self.params.write().keyframe_quantization = enabled;
+        self.update_keyframe_frame_grid();
+    }
+    pub fn quantize_all_keyframes(&self) -> KeyframeSets {
+        let sets = self.keyframes.write().quantize_all(&self.keyframe_frame_grid());
+        self.keyframe_sets_updated(&sets);
+        sets
+    }
     pub fn keyframe_value_at_video_timestamp(&self, typ: &KeyframeType, timestamp_ms: f64) -> Option<f64> {
         self.keyframes.read().value_at_video_timestamp(typ, timestamp_ms)
     }
@@ -1844,14 +2629,27 @@ impl StabilizationManager {
         self.keyframes.read().is_keyframed(typ)
     }
     fn keyframes_updated(&self, typ: &KeyframeType) {
+        let correction_limited = {
+            let params = self.params.read();
+            params.limit_to_zoom_budget && zooming::zoom_budget::is_no_zoom(params.adaptive_zoom_window)
+        };
+        let horizon_compensated = self.params.read().horizon_compensation.0 != 0.0 || self.keyframes.read().is_keyframed(&KeyframeType::HorizonCompensationPitch);
         match typ {
-            KeyframeType::VideoRotation |
+            KeyframeType::Fov if correction_limited => self.invalidate_smoothing(),
+            KeyframeType::Fov if horizon_compensated => self.invalidate_zooming(),
+            // Only the frames where the zooming center or the lens correction changed are searched again
             KeyframeType::ZoomingSpeed |
+            KeyframeType::ZoomingLookAhead |
+            KeyframeType::ZoomingCenterX |
+            KeyframeType::ZoomingCenterY |
+            KeyframeType::LensCorrectionStrength => self.invalidate_zoom_filtering(),
+
+            KeyframeType::VideoRotation |
             KeyframeType::AdditionalTranslationX |
             KeyframeType::AdditionalTranslationY |
             KeyframeType::AdditionalTranslationZ |
-            KeyframeType::ZoomingCenterX |
-            KeyframeType::ZoomingCenterY => self.invalidate_zooming(),
+            KeyframeType::HorizonCompensationPitch |
+            KeyframeType::HorizonCompensationRoll => self.invalidate_zooming(),
 
             KeyframeType::LockHorizonAmount |
             KeyframeType::LockHorizonRoll |
@@ -1863,7 +2661,13 @@ impl StabilizationManager {
             KeyframeType::SmoothingParamSmoothness |
             KeyframeType::SmoothingParamPitch |
             KeyframeType::SmoothingParamRoll |
-            KeyframeType::SmoothingParamYaw => self.invalidate_smoothing(),
+            KeyframeType::SmoothingParamYaw |
+            KeyframeType::SmoothingParamVelocityThreshold |
+            KeyframeType::SmoothingParamAdaptationSpeed |
+            KeyframeType::SmoothingParamDriftTimeConstant |
+            KeyframeType::AxisLockPitch |
+            KeyframeType::AxisLockYaw |
+            KeyframeType::AxisLockRoll => self.invalidate_smoothing(),
             _ => { }
         }
     }
@@ -1879,8 +2683,11 @@ impl StabilizationManager {
             }
         };
 
-        if let Some(mut optsync) = synchronization::optimsync::OptimSync::new(&self.gyro.read()) {
-            let (points, rank, ratio) = optsync.run(target_sync_points, trim_ranges);
+        let gyro = self.gyro.read();
+        let gaps = gyro.gaps().iter().map(|x| (x.0 / 1000.0, x.1 / 1000.0)).collect::<Vec<_>>();
+        if let Some(mut optsync) = synchronization::optimsync::OptimSync::new(&gyro) {
+            drop(gyro);
+            let (points, rank, ratio) = optsync.run(target_sync_points, trim_ranges, &gaps);
             {
                 let mut sync_data = self.sync_data.write();
                 sync_data.rank = rank;
@@ -1947,6 +2754,9 @@ pub enum GyroflowCoreError {
     #[error("Invalid data")]
     InvalidData,
 
+    #[error("Invalid lens profile: {0}")]
+    InvalidLensProfile(String),
+
     #[error("JSON error {0:?}")]
     JSONError(#[from] serde_json::Error),
 
@@ -1956,6 +2766,121 @@ pub enum GyroflowCoreError {
     #[error("IO error {0:?}")]
     IOError(#[from] std::io::Error),
 
+    #[error("The project file is truncated")]
+    ProjectFileTruncated,
+
+    #[error("The project was saved by a newer version of Gyroflow (file format version {0}), save it as a new file")]
+    ReadOnlyProject(u64),
+
+    #[error("Embedded gyro data corrupted: {error}")]
+    EmbeddedDataCorrupted { error: project_integrity::IntegrityError, source_url: Option<String> },
+
+    #[error("Cancelled")]
+    Cancelled,
+
     #[error("Unknown error")]
     Unknown
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+    use crate::gyro_source::Quat64;
+
+    fn test_manager() -> StabilizationManager {
+        let mgr = StabilizationManager::default();
+        *mgr.lens.write() = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.02, -0.01, 0.0, 0.0],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        {
+            let mut params = mgr.params.write();
+            params.fps = 30.0;
+            params.frame_count = 300;
+            params.duration_ms = 10000.0;
+        }
+        {
+            let mut gyro = mgr.gyro.write();
+            gyro.duration_ms = 10000.0;
+            gyro.quaternions = (0..=2000).map(|i| (i * 5_000, Quat64::from_euler_angles(0.002 * i as f64, (i as f64 * 0.1).sin() * 0.05, 0.0))).collect();
+        }
+        mgr.set_size(1920, 1080);
+        mgr.set_output_size(1920, 1080);
+        mgr
+    }
+
+    #[test]
+    fn rapid_changes_publish_only_the_last_state() {
+        let mgr = test_manager();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut generations = Vec::new();
+        for i in 0..50 {
+            mgr.set_additional_rotation_z(i as f64 * 0.1);
+            let tx = tx.clone();
+            generations.push(mgr.recompute_threaded(move |result| { let _ = tx.send(result); }));
+        }
+        assert!(generations.windows(2).all(|x| x[1] > x[0]));
+
+        let results = (0..50).map(|_| rx.recv_timeout(std::time::Duration::from_secs(60)).unwrap()).collect::<Vec<_>>();
+        let published = results.iter().filter(|(_, discarded)| !discarded).map(|(generation, _)| *generation).collect::<Vec<_>>();
+        assert_eq!(published, [*generations.last().unwrap()]);
+        assert_eq!(mgr.stabilization.read().compute_params().additional_rotation.2, 49.0 * 0.1);
+    }
+
+    #[test]
+    fn preview_quality_keeps_the_configured_interpolation() {
+        use preview_quality::{ PreviewQualityLevel, PreviewQualityMode };
+        use stabilization::Interpolation;
+        let mgr = test_manager();
+        mgr.stabilization.write().interpolation = Interpolation::Lanczos4;
+
+        // Playback too slow for realtime
+        mgr.set_preview_playing(true);
+        let levels = (0..100).filter_map(|_| mgr.preview_frame_processed(500.0)).collect::<Vec<_>>();
+        assert_eq!(levels, [PreviewQualityLevel::Half, PreviewQualityLevel::Quarter]);
+        assert!(matches!(mgr.stabilization.read().active_interpolation(), Interpolation::Bilinear));
+        assert!(matches!(mgr.stabilization.read().interpolation, Interpolation::Lanczos4));
+
+        // Changed by a project loaded while degraded
+        mgr.stabilization.write().interpolation = Interpolation::Bicubic;
+        assert_eq!(mgr.set_preview_quality_mode(PreviewQualityMode::Half), Some(PreviewQualityLevel::Half));
+        assert!(matches!(mgr.stabilization.read().active_interpolation(), Interpolation::Bilinear));
+
+        // Paused frame at full quality
+        assert_eq!(mgr.set_preview_playing(false), Some(PreviewQualityLevel::Full));
+        assert!(matches!(mgr.stabilization.read().active_interpolation(), Interpolation::Bicubic));
+    }
+
+    #[test]
+    fn processing_report_sync_points() {
+        let mgr = test_manager();
+        {
+            let mut sync_data = mgr.sync_data.write();
+            sync_data.rank = vec![100.0; 1000];
+            sync_data.rank[((3000.0 - 20.0) / 16.0_f64).round() as usize] = 5.0;
+        }
+        mgr.apply_sync_offsets(&[(1000.0, 10.0, 0.5), (3000.0, 20.0, 1.5), (5000.0, 12.0, 0.7)]);
+        mgr.gyro.write().set_offset(8_000_000, 11.0);
+        assert_eq!(mgr.gyro.read().get_offsets().len(), 3);
+
+        let report = mgr.processing_report();
+        let points = report.sync.points.iter().map(|x| (x.timestamp_ms, x.offset_ms, x.cost, x.accepted)).collect::<Vec<_>>();
+        assert_eq!(points, [(990.0, 10.0, Some(0.5), true), (2980.0, 20.0, Some(1.5), false), (4988.0, 12.0, Some(0.7), true), (8000.0, 11.0, None, true)]);
+        assert!(report.sync.points[1].rejection.is_some());
+        assert_eq!(report.video.frame_count, 300);
+        assert_eq!(report.rolling_shutter.strength, 1.0);
+        assert!(report.render.is_none());
+
+        // Kept in the copy the render queue makes, and cleared with the project
+        assert_eq!(mgr.get_cloned().processing_report().sync.points, report.sync.points);
+        mgr.clear();
+        assert!(mgr.processing_report().sync.points.is_empty());
+    }
+}
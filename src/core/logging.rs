@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Logger front-end used by the app and the CLI. It sits in front of the actual output sinks (terminal, gyroflow.log) and:
+// - filters the records with per-module levels, which can be changed at runtime (eg. `info,gyroflow_core::synchronization=trace`)
+// - keeps the most recent records in memory, so they can be attached to a bug report
+// - optionally mirrors every record as a JSON line to a file
+//
+// Messages are plain English with the values as `key=value` pairs, eg. `Sync finished fps=29.97 frames=120`.
+// The pairs are extracted into `fields` of the stored records, so they can be filtered without parsing the text again.
+// Per-frame code should use `log_throttled!` so it doesn't flood the log during playback or rendering.
+
+use std::collections::{ BTreeMap, VecDeque };
+use std::io::Write;
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering::Relaxed };
+use log::{ LevelFilter, Log, Metadata, Record };
+use parking_lot::{ Mutex, RwLock };
+
+pub const DEFAULT_LEVELS: &str = "debug,mp4parse=warn,wgpu=warn,wgpu_core=warn,wgpu_hal=warn,naga=warn,akaze=warn,ureq=warn,rustls=warn";
+pub const DEFAULT_BUFFER_CAPACITY: usize = 5000;
+
+static LEVELS: RwLock<LevelSpec> = RwLock::new(LevelSpec { default: LevelFilter::Debug, modules: Vec::new() });
+static BUFFER: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::new());
+static BUFFER_CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_CAPACITY);
+static JSON_FILE: Mutex<Option<std::fs::File>> = Mutex::new(None);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelSpec {
+    pub default: LevelFilter,
+    // Sorted by the module path length, so the most specific one matches first
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl LevelSpec {
+    // Same syntax as `RUST_LOG`: comma separated `module=level` entries and an optional level without a module as the default
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut ret = Self { default: LevelFilter::Debug, modules: Vec::new() };
+        for part in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let parse_level = |x: &str| x.trim().parse::<LevelFilter>().map_err(|_| format!("Invalid log level: {x}"));
+            match part.split_once('=') {
+                Some((module, level)) => {
+                    let level = parse_level(level)?;
+                    let module = module.trim().to_owned();
+                    ret.modules.retain(|(m, _)| *m != module);
+                    ret.modules.push((module, level));
+                },
+                None => { ret.default = parse_level(part)?; }
+            }
+        }
+        ret.modules.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        Ok(ret)
+    }
+
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .find(|(m, _)| target.strip_prefix(m.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::")))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, std::cmp::max)
+    }
+}
+
+impl std::fmt::Display for LevelSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.default.as_str().to_ascii_lowercase())?;
+        for (module, level) in self.modules.iter().rev() {
+            write!(f, ",{module}={}", level.as_str().to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct LogRecord {
+    pub timestamp_ms: u64, // Unix time
+    pub level: &'static str,
+    pub target: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl LogRecord {
+    fn from_record(record: &Record) -> Self {
+        let message = record.args().to_string();
+        Self {
+            timestamp_ms: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or_default(),
+            level: record.level().as_str(),
+            target: record.target().to_owned(),
+            fields: parse_fields(&message),
+            message,
+        }
+    }
+}
+
+// `key=value` pairs, where the key is a lowercase identifier and the value ends at a whitespace
+pub fn parse_fields(message: &str) -> BTreeMap<String, String> {
+    message.split_whitespace()
+        .filter_map(|token| token.split_once('='))
+        .map(|(key, value)| (key, value.trim_end_matches([',', ';'])))
+        .filter(|(key, value)| !key.is_empty() && !value.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.'))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+}
+
+struct Logger {
+    sinks: Vec<Box<dyn Log>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= LEVELS.read().level_for(metadata.target())
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) { return; }
+        for sink in &self.sinks {
+            sink.log(record);
+        }
+        store(LogRecord::from_record(record));
+    }
+    fn flush(&self) {
+        for sink in &self.sinks {
+            sink.flush();
+        }
+    }
+}
+
+fn store(record: LogRecord) {
+    if let Some(file) = JSON_FILE.lock().as_mut() {
+        if let Ok(mut line) = serde_json::to_vec(&record) {
+            line.push(b'\n');
+            let _ = file.write_all(&line);
+        }
+    }
+    let capacity = BUFFER_CAPACITY.load(Relaxed);
+    let mut buffer = BUFFER.lock();
+    while !buffer.is_empty() && buffer.len() >= capacity {
+        buffer.pop_front();
+    }
+    if capacity > 0 {
+        buffer.push_back(record);
+    }
+}
+
+// Installs the logger with the given output sinks. The sinks should accept everything (`LevelFilter::Trace`), the filtering is done here.
+// Initial levels are taken from `GYROFLOW_LOG` if set, otherwise from `levels`
+pub fn init(sinks: Vec<Box<dyn Log>>, levels: &str) -> Result<(), log::SetLoggerError> {
+    let from_env = std::env::var("GYROFLOW_LOG").ok();
+    let spec = from_env.as_deref().and_then(|x| LevelSpec::parse(x).map_err(|e| eprintln!("GYROFLOW_LOG: {e}")).ok())
+        .or_else(|| LevelSpec::parse(levels).ok())
+        .unwrap_or_else(|| LevelSpec::parse(DEFAULT_LEVELS).unwrap());
+    let max_level = spec.max_level();
+    *LEVELS.write() = spec;
+    log::set_boxed_logger(Box::new(Logger { sinks }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+pub fn set_levels(spec: &str) -> Result<(), String> {
+    let spec = LevelSpec::parse(spec)?;
+    log::set_max_level(spec.max_level());
+    *LEVELS.write() = spec;
+    Ok(())
+}
+pub fn set_module_level(module: &str, level: LevelFilter) {
+    let mut spec = LEVELS.read().clone();
+    spec.modules.retain(|(m, _)| m != module);
+    spec.modules.push((module.to_owned(), level));
+    spec.modules.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    log::set_max_level(spec.max_level());
+    *LEVELS.write() = spec;
+}
+pub fn levels() -> String { LEVELS.read().to_string() }
+
+// Most recent records, oldest first. `limit` of 0 returns the whole buffer
+pub fn recent_records(limit: usize) -> Vec<LogRecord> {
+    let buffer = BUFFER.lock();
+    let skip = if limit > 0 { buffer.len().saturating_sub(limit) } else { 0 };
+    buffer.iter().skip(skip).cloned().collect()
+}
+pub fn clear_records() { BUFFER.lock().clear(); }
+pub fn set_buffer_capacity(capacity: usize) {
+    BUFFER_CAPACITY.store(capacity, Relaxed);
+    let mut buffer = BUFFER.lock();
+    while buffer.len() > capacity {
+        buffer.pop_front();
+    }
+}
+
+// Appends every record as a JSON line to `path`. `None` stops writing
+pub fn set_json_file(path: Option<&std::path::Path>) -> std::io::Result<()> {
+    let file = match path {
+        Some(path) => Some(std::fs::OpenOptions::new().create(true).append(true).open(path)?),
+        None => None
+    };
+    *JSON_FILE.lock() = file;
+    Ok(())
+}
+
+// Returns `Some(number of suppressed calls)` if at least `interval_ms` passed since the last time it returned `Some` for the given call site
+pub fn throttle(last_ms: &AtomicU64, suppressed: &AtomicU64, interval_ms: u64) -> Option<u64> {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    let now = START.get_or_init(std::time::Instant::now).elapsed().as_millis() as u64 + 1; // 0 means never logged
+    let last = last_ms.load(Relaxed);
+    if (last == 0 || now.saturating_sub(last) >= interval_ms) && last_ms.compare_exchange(last, now, Relaxed, Relaxed).is_ok() {
+        Some(suppressed.swap(0, Relaxed))
+    } else {
+        suppressed.fetch_add(1, Relaxed);
+        None
+    }
+}
+
+// Logs at most once every `interval_ms` from the call site, appending the number of suppressed messages
+#[macro_export]
+macro_rules! log_throttled {
+    ($interval_ms:expr, $lvl:expr, $($arg:tt)+) => {{
+        static LAST: ::std::sync::atomic::AtomicU64 = ::std::sync::atomic::AtomicU64::new(0);
+        static SUPPRESSED: ::std::sync::atomic::AtomicU64 = ::std::sync::atomic::AtomicU64::new(0);
+        if ::log::log_enabled!($lvl) {
+            match $crate::logging::throttle(&LAST, &SUPPRESSED, $interval_ms) {
+                Some(0) => ::log::log!($lvl, $($arg)+),
+                Some(suppressed) => ::log::log!($lvl, "{} suppressed={}", format_args!($($arg)+), suppressed),
+                None => { }
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_spec() {
+        let spec = LevelSpec::parse("warn, gyroflow_core=info,gyroflow_core::synchronization=trace,wgpu=off").unwrap();
+        assert_eq!(spec.level_for("gyroflow"), LevelFilter::Warn);
+        assert_eq!(spec.level_for("gyroflow_core::stabilization"), LevelFilter::Info);
+        assert_eq!(spec.level_for("gyroflow_core::synchronization::autosync"), LevelFilter::Trace);
+        assert_eq!(spec.level_for("wgpu"), LevelFilter::Off);
+        assert_eq!(spec.level_for("wgpu_core::device"), LevelFilter::Warn);
+        assert_eq!(spec.max_level(), LevelFilter::Trace);
+        assert_eq!(LevelSpec::parse(&spec.to_string()).unwrap(), spec);
+        assert!(LevelSpec::parse("gyroflow=loud").is_err());
+    }
+
+    #[test]
+    fn fields() {
+        let fields = parse_fields("Sync finished fps=29.97, frames=120 Some text=, key: value");
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields["fps"], "29.97");
+        assert_eq!(fields["frames"], "120");
+    }
+
+    #[test]
+    fn ring_buffer() {
+        set_buffer_capacity(3);
+        for i in 0..10 {
+            store(LogRecord { timestamp_ms: 0, level: "INFO", target: "test".into(), message: format!("i={i}"), fields: parse_fields(&format!("i={i}")) });
+        }
+        let records = recent_records(0);
+        assert_eq!(records.iter().map(|x| x.fields["i"].as_str()).collect::<Vec<_>>(), ["7", "8", "9"]);
+        assert_eq!(recent_records(1)[0].message, "i=9");
+        set_buffer_capacity(DEFAULT_BUFFER_CAPACITY);
+    }
+
+    #[test]
+    fn throttled() {
+        let (last, suppressed) = (AtomicU64::new(0), AtomicU64::new(0));
+        assert_eq!(throttle(&last, &suppressed, 60_000), Some(0));
+        assert_eq!(throttle(&last, &suppressed, 60_000), None);
+        assert_eq!(throttle(&last, &suppressed, 60_000), None);
+        assert_eq!(throttle(&last, &suppressed, 0), Some(2));
+    }
+}
@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+//! Per-frame undistortion data for plugin hosts which warp the image themselves.
+//!
+//! The layout of [`PluginFrameTransform`] is stable: fields are only ever appended and the caller sets `struct_size`
+//! to the size of its own definition, so only that many bytes are written and older plugin binaries keep working.
+//! The matrices and the [`KernelParams`] are the same which are uploaded to the GPU for rendering the frame, in RGBA float
+//! pixels with the input and output buffers of the size set on the manager.
+//!
+//! The data is computed from one snapshot of the parameters, a concurrent change is either fully visible or not at all.
+//! See `examples/plugin_frame_transform.rs` for a consumer. The C entry point is built into a library by `plugin_ffi`,
+//! with the declarations in `plugin_ffi/gyroflow_plugin.h`.
+
+use crate::stabilization::{ KernelParams, RGBAf };
+use crate::gpu::{ BufferDescription, BufferSource, Buffers };
+use crate::{ GyroflowCoreError, StabilizationManager };
+
+/// Bumped when fields are added to `PluginFrameTransform` or the layout of `KernelParams` changes
+pub const PLUGIN_API_VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PluginFrameTransform {
+    /// Set by the caller to the size of its definition of this struct
+    pub struct_size: u32,
+    /// `PLUGIN_API_VERSION` of the library which filled it
+    pub api_version: u32,
+    pub timestamp_us: i64,
+    pub frame: u32,
+    /// Rows of the rolling shutter correction, 1 without it. Can be more than `matrices_capacity`
+    pub matrix_count: u32,
+    /// w, x, y, z. Rotation of the middle row of the frame, in the kernel's camera space (x right, y down, z forward)
+    pub correction_quat: [f64; 4],
+    /// As `KernelParams::fov`, 1 is the whole input frame, lower is zoomed in
+    pub fov: f64,
+    /// Input camera matrix in pixels
+    pub focal_length: [f64; 2],
+    pub principal_point: [f64; 2],
+    pub distortion_coeffs: [f64; 12],
+    /// Id of the distortion model, nul-terminated
+    pub distortion_model: [u8; 32],
+    /// Caller's buffer for `matrix_count` rows of 14 floats, as the kernels read them. Can be null
+    pub matrices: *mut [f32; 14],
+    pub matrices_capacity: u32,
+    /// Size of the caller's `KernelParams` buffer, only that many bytes are written
+    pub kernel_params_size: u32,
+    /// Packed kernel parameters of the GPU path. Can be null
+    pub kernel_params: *mut KernelParams,
+}
+
+impl Default for PluginFrameTransform {
+    fn default() -> Self {
+        Self {
+            struct_size: std::mem::size_of::<Self>() as u32,
+            api_version: PLUGIN_API_VERSION,
+            timestamp_us: 0,
+            frame: 0,
+            matrix_count: 0,
+            correction_quat: [1.0, 0.0, 0.0, 0.0],
+            fov: 1.0,
+            focal_length: [0.0; 2],
+            principal_point: [0.0; 2],
+            distortion_coeffs: [0.0; 12],
+            distortion_model: [0; 32],
+            matrices: std::ptr::null_mut(),
+            matrices_capacity: 0,
+            kernel_params_size: 0,
+            kernel_params: std::ptr::null_mut(),
+        }
+    }
+}
+
+// Buffers of the manager's sizes for the kernel parameters, the pixels are never accessed
+fn plugin_buffers(size: (usize, usize), output_size: (usize, usize)) -> Buffers<'static> {
+    let pixel = std::mem::size_of::<RGBAf>();
+    Buffers {
+        input:  BufferDescription { size: (size.0, size.1, size.0 * pixel), rect: None, rotation: None, data: BufferSource::None, texture_copy: false },
+        output: BufferDescription { size: (output_size.0, output_size.1, output_size.0 * pixel), rect: None, rotation: None, data: BufferSource::None, texture_copy: false },
+    }
+}
+
+impl StabilizationManager {
+    /// Undistortion data of the frame at `timestamp_us`, as the GPU path would use for RGBA float pixels.
+    /// The matrices are written to `matrices` up to its length and `out.matrix_count` is the number needed.
+    /// The pointers in `out` are left as they are, they are only used by `gyroflow_fill_frame_transform`.
+    ///
+    /// It blocks: the undistortion is recomputed first if the parameters were changed, and it waits up to 30 s
+    /// for a concurrent change of the parameters to finish. Don't call it from a real-time thread
+    pub fn fill_frame_transform(&self, mut timestamp_us: i64, out: &mut PluginFrameTransform, matrices: &mut [[f32; 14]], kernel_params: Option<&mut KernelParams>) -> Result<(), GyroflowCoreError> {
+        if let Some(scale) = self.params.read().fps_scale {
+            timestamp_us = (timestamp_us as f64 / scale).round() as i64;
+        }
+        self.recompute_if_invalidated();
+
+        // The parameters can't change while the lock is held
+        let stab = self.stabilization.try_read_for(std::time::Duration::from_millis(30000)).ok_or(GyroflowCoreError::Unknown)?;
+        let params = stab.compute_params();
+        let timestamp_ms = timestamp_us as f64 / 1000.0;
+        let frame = crate::frame_at_timestamp(timestamp_ms, params.scaled_fps).max(0) as usize;
+
+        let transform = stab.get_frame_transform_at::<RGBAf>(timestamp_us, Some(frame), &plugin_buffers(stab.size, stab.output_size));
+        let (f, c, k, fov) = (transform.kernel_params.f, transform.kernel_params.c, transform.kernel_params.k, transform.kernel_params.fov);
+
+        let correction = {
+            let gyro = params.gyro.read();
+            let ts = timestamp_ms + gyro.file_metadata.read().per_frame_time_offsets.get(frame).unwrap_or(&0.0);
+            gyro.smoothed_quat_at_timestamp(ts)
+        };
+        let video_rotation = params.keyframes.value_at_video_timestamp(&crate::keyframes::KeyframeType::VideoRotation, timestamp_ms).unwrap_or(params.video_rotation);
+        let r = if params.suppress_rotation {
+            nalgebra::Matrix3::identity()
+        } else {
+            let horizon_compensation = crate::stabilization::FrameTransform::get_horizon_compensation(params, frame, true, timestamp_ms, params.framebuffer_inverted);
+            crate::stabilization::FrameTransform::kernel_rotation(params, &correction, &nalgebra::Matrix3::new_rotation(video_rotation.to_radians()), horizon_compensation.as_ref())
+        };
+        let q = crate::gyro_source::Quat64::from_rotation_matrix(&nalgebra::Rotation3::from_matrix_unchecked(r));
+
+        let mut distortion_model = [0u8; 32];
+        let id = params.distortion_model.id().as_bytes();
+        distortion_model[..id.len().min(31)].copy_from_slice(&id[..id.len().min(31)]);
+
+        out.api_version = PLUGIN_API_VERSION;
+        out.timestamp_us = timestamp_us;
+        out.frame = frame as u32;
+        out.matrix_count = transform.matrices.len() as u32;
+        out.correction_quat = [q.w, q.i, q.j, q.k];
+        out.fov = fov as f64;
+        out.focal_length = [f[0] as f64, f[1] as f64];
+        out.principal_point = [c[0] as f64, c[1] as f64];
+        out.distortion_coeffs = k.map(|x| x as f64);
+        out.distortion_model = distortion_model;
+
+        for (dst, src) in matrices.iter_mut().zip(&transform.matrices) {
+            *dst = *src;
+        }
+        if let Some(kernel_params) = kernel_params {
+            *kernel_params = transform.kernel_params;
+        }
+        Ok(())
+    }
+}
+
+/// C entry point of `StabilizationManager::fill_frame_transform`, exported by the `plugin_ffi` library. `manager` is from `Arc::as_ptr`.
+/// Returns 0 on success, -1 for invalid arguments and -2 when the data couldn't be computed.
+/// It blocks like `fill_frame_transform`
+///
+/// # Safety
+/// `manager` must be a valid `StabilizationManager` and `out` must point to at least `out.struct_size` writable bytes,
+/// with `matrices` and `kernel_params` valid for their capacity and size, or null
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_fill_frame_transform(manager: *const StabilizationManager, timestamp_us: i64, out: *mut PluginFrameTransform) -> i32 {
+    if manager.is_null() || out.is_null() { return -1; }
+    let full_size = std::mem::size_of::<PluginFrameTransform>();
+    let size = (*out).struct_size as usize;
+    if size < std::mem::offset_of!(PluginFrameTransform, timestamp_us) { return -1; }
+
+    // Fields the caller doesn't have stay at the defaults
+    let mut ret = PluginFrameTransform::default();
+    std::ptr::copy_nonoverlapping(out as *const u8, &mut ret as *mut _ as *mut u8, size.min(full_size));
+    let has_field = |offset: usize, len: usize| offset + len <= size;
+
+    let matrices = if !ret.matrices.is_null() && has_field(std::mem::offset_of!(PluginFrameTransform, matrices_capacity), 4) {
+        std::slice::from_raw_parts_mut(ret.matrices, ret.matrices_capacity as usize)
+    } else {
+        &mut [][..]
+    };
+    let mut kernel_params = KernelParams::default();
+    let wants_kernel_params = !ret.kernel_params.is_null() && has_field(std::mem::offset_of!(PluginFrameTransform, kernel_params), std::mem::size_of::<usize>());
+
+    let result = (*manager).fill_frame_transform(timestamp_us, &mut ret, matrices, wants_kernel_params.then_some(&mut kernel_params));
+    if let Err(e) = result {
+        log::error!("gyroflow_fill_frame_transform: {e:?}");
+        return -2;
+    }
+    if wants_kernel_params {
+        let len = (ret.kernel_params_size as usize).min(std::mem::size_of::<KernelParams>());
+        std::ptr::copy_nonoverlapping(&kernel_params as *const _ as *const u8, ret.kernel_params as *mut u8, len);
+    }
+    ret.struct_size = size as u32;
+    std::ptr::copy_nonoverlapping(&ret as *const _ as *const u8, out as *mut u8, size.min(full_size));
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+    use crate::gyro_source::Quat64;
+    use crate::stabilization::{ Interpolation, Stabilization };
+
+    fn manager(width: usize, height: usize) -> StabilizationManager {
+        let mgr = StabilizationManager::default();
+        *mgr.lens.write() = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.02, -0.01, 0.0, 0.0],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        {
+            let mut params = mgr.params.write();
+            params.fps = 30.0;
+            params.frame_count = 60;
+            params.duration_ms = 2000.0;
+            params.frame_readout_time = 10.0;
+        }
+        {
+            let mut gyro = mgr.gyro.write();
+            gyro.duration_ms = 2000.0;
+            gyro.quaternions = (0..=200).map(|i| (i * 10_000, Quat64::from_euler_angles(0.002 * i as f64, 0.003 * i as f64, 0.0))).collect();
+            let corrections = gyro.quaternions.iter().map(|(ts, q)| (*ts, Quat64::from_euler_angles(0.0, 0.0, 0.1) * *q)).collect();
+            gyro.set_smoothed_quaternions(corrections);
+        }
+        mgr.stabilization.write().interpolation = Interpolation::Bilinear;
+        mgr.set_size(width, height);
+        mgr.set_output_size(width, height);
+        mgr.recompute_undistortion();
+        mgr
+    }
+
+    #[test]
+    fn matches_gpu_path() {
+        let mgr = manager(1920, 1080);
+        let timestamp_us = 500_000;
+        let mut out = PluginFrameTransform::default();
+        let mut matrices = vec![[0f32; 14]; 1080];
+        let mut kernel_params = KernelParams::default();
+        mgr.fill_frame_transform(timestamp_us, &mut out, &mut matrices, Some(&mut kernel_params)).unwrap();
+
+        let stab = mgr.stabilization.read();
+        assert_eq!(out.frame, 15);
+        assert!(out.matrix_count > 1 && out.matrix_count as usize <= matrices.len());
+        let matrices = &matrices[..out.matrix_count as usize];
+        assert_eq!(kernel_params.matrix_count as u32, out.matrix_count);
+        assert_eq!(out.fov as f32, kernel_params.fov);
+        assert_eq!(&out.distortion_model[..stab.compute_params().distortion_model.id().len()], stab.compute_params().distortion_model.id().as_bytes());
+
+        // The middle row is the correction alone
+        let q = out.correction_quat;
+        let r = *Quat64::from_quaternion(nalgebra::Quaternion::new(q[0], q[1], q[2], q[3])).to_rotation_matrix().matrix();
+        let mut new_k = nalgebra::Matrix3::identity();
+        new_k[(0, 0)] = out.focal_length[0] / out.fov;
+        new_k[(1, 1)] = out.focal_length[1] / out.fov;
+        new_k[(0, 2)] = 960.0;
+        new_k[(1, 2)] = 540.0;
+        let m = (new_k * r).try_inverse().unwrap();
+        let middle = &matrices[matrices.len() / 2];
+        for i in 0..9 {
+            assert!((m[(i / 3, i % 3)] as f32 - middle[i]).abs() < 1e-3, "{i}: {m} {middle:?}");
+        }
+    }
+
+    #[test]
+    fn renders_as_process_pixels() {
+        // The kernel fed with the plugin's data draws the same frame as the renderer with the data it uploads itself
+        let (w, h) = (480, 270);
+        let mgr = manager(w, h);
+        let timestamp_us = 500_000;
+        let mut out = PluginFrameTransform::default();
+        let mut matrices = vec![[0f32; 14]; h];
+        let mut kernel_params = KernelParams::default();
+        mgr.fill_frame_transform(timestamp_us, &mut out, &mut matrices, Some(&mut kernel_params)).unwrap();
+
+        let stride = w * std::mem::size_of::<RGBAf>();
+        let input = (0..w * h).flat_map(|i| [(i % w) as f32, (i / w) as f32, (i % 97) as f32, 255.0]).flat_map(f32::to_ne_bytes).collect::<Vec<u8>>();
+        let stab = mgr.stabilization.read();
+        let render = |f: &dyn Fn(&mut Buffers) -> bool| -> Vec<u8> {
+            let mut input = input.clone();
+            let mut output = vec![0u8; stride * h];
+            let mut buffers = Buffers {
+                input:  BufferDescription { size: (w, h, stride), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+                output: BufferDescription { size: (w, h, stride), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+            };
+            assert!(f(&mut buffers));
+            drop(buffers);
+            output
+        };
+        let rendered = render(&|buffers| stab.process_pixels::<RGBAf>(timestamp_us, Some(out.frame as usize), buffers, None).is_ok());
+        let from_plugin = render(&|buffers| {
+            let compute_params = stab.compute_params();
+            Stabilization::undistort_image_cpu::<2, RGBAf>(buffers, &kernel_params, &compute_params.distortion_model, compute_params.digital_lens.as_ref(), &matrices[..out.matrix_count as usize], stab.drawing.get_buffer(), &[])
+        });
+        assert!(rendered.iter().any(|x| *x != 0));
+        assert!(rendered == from_plugin, "The frame drawn with the plugin's data differs");
+    }
+
+    // Offsets of the fields in gyroflow_plugin.h
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn header_layout() {
+        use std::mem::{ offset_of, size_of };
+        assert_eq!(offset_of!(PluginFrameTransform, timestamp_us), 8);
+        assert_eq!(offset_of!(PluginFrameTransform, correction_quat), 24);
+        assert_eq!(offset_of!(PluginFrameTransform, distortion_model), 192);
+        assert_eq!(offset_of!(PluginFrameTransform, matrices), 224);
+        assert_eq!(offset_of!(PluginFrameTransform, kernel_params), 240);
+        assert_eq!(size_of::<PluginFrameTransform>(), 248);
+    }
+
+    #[test]
+    fn older_struct_size() {
+        let mgr = manager(1920, 1080);
+        // A caller which only knows the fields up to the FOV
+        let size = std::mem::offset_of!(PluginFrameTransform, focal_length);
+        let mut out = PluginFrameTransform { struct_size: size as u32, fov: -1.0, ..Default::default() };
+        out.focal_length = [-1.0; 2];
+        assert_eq!(unsafe { gyroflow_fill_frame_transform(&mgr, 500_000, &mut out) }, 0);
+        assert!(out.fov > 0.0 && out.matrix_count > 1);
+        assert_eq!(out.struct_size as usize, size);
+        assert_eq!(out.focal_length, [-1.0; 2]);
+
+        out.struct_size = 4;
+        assert_eq!(unsafe { gyroflow_fill_frame_transform(&mgr, 500_000, &mut out) }, -1);
+        assert_eq!(unsafe { gyroflow_fill_frame_transform(std::ptr::null(), 500_000, &mut out) }, -1);
+    }
+}
@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Per-frame undistortion data for plugin hosts which warp the image themselves, see src/core/plugin_api.rs.
+// Fields are only ever appended. Set struct_size to sizeof(GyroflowFrameTransform), only that many bytes are written
+
+#ifndef GYROFLOW_PLUGIN_H
+#define GYROFLOW_PLUGIN_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define GYROFLOW_PLUGIN_API_VERSION 1
+
+// StabilizationManager of gyroflow-core, from Arc::as_ptr
+typedef struct GyroflowStabilizationManager GyroflowStabilizationManager;
+// Packed kernel parameters of the GPU path, as the kernels read them. The layout is the one of the api_version
+typedef struct GyroflowKernelParams GyroflowKernelParams;
+
+typedef struct GyroflowFrameTransform {
+    uint32_t struct_size;         // Set by the caller to sizeof(GyroflowFrameTransform)
+    uint32_t api_version;         // GYROFLOW_PLUGIN_API_VERSION of the library which filled it
+    int64_t  timestamp_us;
+    uint32_t frame;
+    uint32_t matrix_count;        // Rows of the rolling shutter correction, 1 without it. Can be more than matrices_capacity
+    double   correction_quat[4];  // w, x, y, z. Rotation of the middle row of the frame, in the kernel's camera space (x right, y down, z forward)
+    double   fov;                 // 1 is the whole input frame, lower is zoomed in
+    double   focal_length[2];     // Input camera matrix in pixels
+    double   principal_point[2];
+    double   distortion_coeffs[12];
+    char     distortion_model[32]; // Id of the distortion model, nul-terminated
+    float  (*matrices)[14];       // Caller's buffer for matrix_count rows of 14 floats. Can be null
+    uint32_t matrices_capacity;
+    uint32_t kernel_params_size;  // Size of the caller's kernel_params buffer, only that many bytes are written
+    GyroflowKernelParams *kernel_params; // Can be null
+} GyroflowFrameTransform;
+
+// Fills `out` with the data of the frame at `timestamp_us`. Returns 0 on success, -1 for invalid arguments and -2 when the data couldn't be computed.
+// It blocks: it recomputes the undistortion if the parameters changed, and waits for a concurrent change of them to finish.
+// Don't call it from a real-time thread
+int32_t gyroflow_fill_frame_transform(const GyroflowStabilizationManager *manager, int64_t timestamp_us, GyroflowFrameTransform *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif // GYROFLOW_PLUGIN_H
@@ -0,0 +1,8 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+//! The C entry points of gyroflow-core for the plugin hosts which aren't written in Rust.
+//! They're defined in gyroflow-core with `#[no_mangle]`, this crate only builds them into a shared and a static library.
+//! The declarations are in `gyroflow_plugin.h`, keep it in sync with `gyroflow_core::plugin_api`
+
+pub use gyroflow_core::plugin_api::{ gyroflow_fill_frame_transform, PluginFrameTransform, PLUGIN_API_VERSION };
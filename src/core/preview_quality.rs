@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Internal resolution of the preview, lowered when the frames can't be processed in realtime.
+// In the automatic mode the level follows the average processing time of the played frames: it goes down when a frame
+// takes most of the frame duration and goes back up only when the next level is expected to fit with a margin,
+// and it stays at a level for a while after every switch, so it doesn't flip back and forth.
+// The paused (and seeked) frame is always rendered at full quality. Only the preview is affected, rendering makes its own kernels.
+
+use crate::stabilization::Interpolation;
+
+const SMOOTHING: f64 = 0.2;           // Weight of the last frame in the average processing time
+const DEGRADE_AT: f64 = 0.85;         // Of the frame duration
+const UPGRADE_AT: f64 = 0.5;          // Of the frame duration, for the time expected at the higher level
+const MIN_FRAMES_AT_LEVEL: usize = 15;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreviewQualityMode {
+    #[default]
+    Auto,
+    Full,
+    Half,
+    Quarter
+}
+impl From<i32> for PreviewQualityMode {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::Full,
+            2 => Self::Half,
+            3 => Self::Quarter,
+            _ => Self::Auto
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PreviewQualityLevel {
+    #[default]
+    Full,
+    Half,
+    Quarter
+}
+impl PreviewQualityLevel {
+    /// Of the preview resolution, in each dimension
+    pub fn scale(&self) -> f64 {
+        match self {
+            Self::Full    => 1.0,
+            Self::Half    => 0.5,
+            Self::Quarter => 0.25,
+        }
+    }
+    fn lower(&self) -> Self {
+        match self {
+            Self::Full => Self::Half,
+            _ => Self::Quarter
+        }
+    }
+    fn higher(&self) -> Self {
+        match self {
+            Self::Quarter => Self::Half,
+            _ => Self::Full
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Full    => "Full",
+            Self::Half    => "Half",
+            Self::Quarter => "Quarter",
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct PreviewQuality {
+    pub mode: PreviewQualityMode,
+    pub playing: bool,
+    auto_level: PreviewQualityLevel,
+    avg_ms: Option<f64>,
+    frames_at_level: usize,
+}
+
+impl PreviewQuality {
+    pub fn set_mode(&mut self, mode: PreviewQualityMode) {
+        self.mode = mode;
+        self.reset();
+    }
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+        // The average of the full quality frames doesn't apply to the current level and vice versa
+        self.avg_ms = None;
+        self.frames_at_level = 0;
+    }
+    pub fn reset(&mut self) {
+        self.auto_level = PreviewQualityLevel::Full;
+        self.avg_ms = None;
+        self.frames_at_level = 0;
+    }
+
+    /// Level the preview should be rendered at now
+    pub fn active_level(&self) -> PreviewQualityLevel {
+        if !self.playing { return PreviewQualityLevel::Full; }
+        match self.mode {
+            PreviewQualityMode::Auto    => self.auto_level,
+            PreviewQualityMode::Full    => PreviewQualityLevel::Full,
+            PreviewQualityMode::Half    => PreviewQualityLevel::Half,
+            PreviewQualityMode::Quarter => PreviewQualityLevel::Quarter,
+        }
+    }
+
+    /// Interpolation used instead of the configured one at the active level, `None` at full quality
+    pub fn interpolation_override(&self) -> Option<Interpolation> {
+        if self.active_level() == PreviewQualityLevel::Full {
+            None
+        } else {
+            Some(Interpolation::Bilinear)
+        }
+    }
+
+    /// Records the processing time of a played frame. Returns the new level if it changed
+    pub fn frame_processed(&mut self, elapsed_ms: f64, frame_duration_ms: f64) -> Option<PreviewQualityLevel> {
+        if !self.playing || self.mode != PreviewQualityMode::Auto || !elapsed_ms.is_finite() || frame_duration_ms <= 0.0 { return None; }
+
+        let avg = self.avg_ms.map_or(elapsed_ms, |avg| avg + (elapsed_ms - avg) * SMOOTHING);
+        self.avg_ms = Some(avg);
+        self.frames_at_level += 1;
+        if self.frames_at_level < MIN_FRAMES_AT_LEVEL { return None; }
+
+        let level = self.auto_level;
+        let new_level = if avg > frame_duration_ms * DEGRADE_AT {
+            level.lower()
+        } else {
+            // The processing time scales with the number of pixels
+            let ratio = level.scale() / level.higher().scale();
+            if avg / (ratio * ratio) < frame_duration_ms * UPGRADE_AT { level.higher() } else { level }
+        };
+        if new_level == level { return None; }
+
+        self.auto_level = new_level;
+        self.avg_ms = None;
+        self.frames_at_level = 0;
+        Some(new_level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(q: &mut PreviewQuality, frames: usize, elapsed_ms: f64) -> Vec<PreviewQualityLevel> {
+        (0..frames).filter_map(|_| q.frame_processed(elapsed_ms, 1000.0 / 30.0)).collect()
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut q = PreviewQuality::default();
+        q.set_playing(true);
+        assert_eq!(play(&mut q, 100, 10.0), []);
+
+        // Too slow at full, still too slow at half
+        assert_eq!(play(&mut q, MIN_FRAMES_AT_LEVEL, 40.0), [PreviewQualityLevel::Half]);
+        assert_eq!(play(&mut q, MIN_FRAMES_AT_LEVEL * 2, 40.0), [PreviewQualityLevel::Quarter]);
+        assert_eq!(play(&mut q, 100, 40.0), []);
+
+        // Fits at quarter, but half would take 4x as long
+        assert_eq!(play(&mut q, 100, 8.0), []);
+        assert_eq!(play(&mut q, MIN_FRAMES_AT_LEVEL, 3.0), [PreviewQualityLevel::Half]);
+        assert_eq!(q.active_level(), PreviewQualityLevel::Half);
+
+        // A single slow frame doesn't switch
+        assert_eq!(play(&mut q, 100, 5.0), []);
+        assert_eq!(q.frame_processed(100.0, 1000.0 / 30.0), None);
+        assert_eq!(play(&mut q, 100, 5.0), []);
+    }
+
+    #[test]
+    fn paused_and_manual() {
+        let mut q = PreviewQuality::default();
+        q.set_playing(true);
+        play(&mut q, MIN_FRAMES_AT_LEVEL, 100.0);
+        assert_eq!(q.active_level(), PreviewQualityLevel::Half);
+        assert!(matches!(q.interpolation_override(), Some(Interpolation::Bilinear)));
+
+        q.set_playing(false);
+        assert_eq!(q.active_level(), PreviewQualityLevel::Full);
+        assert!(q.interpolation_override().is_none());
+        assert_eq!(play(&mut q, 100, 100.0), []);
+
+        q.set_mode(PreviewQualityMode::from(3));
+        q.set_playing(true);
+        assert_eq!(q.active_level(), PreviewQualityLevel::Quarter);
+        assert_eq!(play(&mut q, 100, 1.0), []);
+        q.set_mode(PreviewQualityMode::Full);
+        assert_eq!(q.active_level(), PreviewQualityLevel::Full);
+    }
+}
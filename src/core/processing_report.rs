@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Summary of how a clip was processed, written next to the rendered file so the result can be traced back later.
+// The field names are part of the file format: fields can be added, but existing ones keep their name and meaning,
+// otherwise `REPORT_VERSION` is bumped. Times are in milliseconds of the video, angles in degrees.
+
+use crate::graph_data::GraphData;
+use crate::gyro_source::{ StaticBiasEstimate, OffsetInterpolation, DriftFit };
+use crate::stabilization_params::ReadoutDirection;
+use std::fmt::Write;
+
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ProcessingReport {
+    pub version: u32,
+    pub app_version: String,
+    pub input_file: String,
+    pub video: VideoReport,
+    pub camera: CameraReport,
+    pub lens: LensReport,
+    pub sync: SyncReport,
+    pub rolling_shutter: RollingShutterReport,
+    pub gyro_bias: GyroBiasReport,
+    pub smoothing: SmoothingReport,
+    pub crop: CropReport,
+    pub render: Option<RenderReport>, // Only in the reports of rendered files
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct VideoReport {
+    pub width: usize,
+    pub height: usize,
+    pub fps: f64,
+    pub frame_count: usize,
+    pub duration_ms: f64,
+}
+
+/// Detected from the metadata of the clip, empty when it wasn't
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CameraReport {
+    pub brand: String,
+    pub model: String,
+    pub lens_model: String,
+    pub identifier: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct LensReport {
+    pub name: String, // Empty when no profile is loaded
+    pub calibrated_by: String,
+    pub official: bool,
+    pub distortion_model: String,
+    pub checksum: Option<String>,
+    pub match_score: Option<f64>, // 0..1, how well the profile matches the detected camera (`LensProfile::match_score`). `None` without a detected camera
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SyncPointReport {
+    pub timestamp_ms: f64,
+    pub offset_ms: f64,    // Gyro time minus video time
+    pub cost: Option<f64>, // Of the optical flow match, lower is better. `None` for offsets loaded from a project or added by hand
+    pub accepted: bool,
+    pub rejection: Option<String>, // Why it wasn't applied
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SyncReport {
+    pub points: Vec<SyncPointReport>, // Sorted by timestamp, the rejected ones included
+    pub interpolation: OffsetInterpolation,
+    pub drift: Option<DriftFit>, // Linear fit of the accepted points. `None` with fewer than two
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RollingShutterReport {
+    pub readout_time_ms: f64, // The effective one, 0 when there's no correction
+    pub direction: ReadoutDirection,
+    pub overridden: bool, // Readout time or direction set by the user instead of the one from the camera or the estimation
+    pub strength: f64,    // 1 is the full correction
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GyroBiasReport {
+    pub applied: Option<[f64; 3]>, // deg/s, added to the raw readings
+    pub detected: Option<StaticBiasEstimate>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SmoothingReport {
+    pub method: String,
+    pub parameters: Vec<(String, f64)>,
+    pub horizon_lock_amount: f64, // 0..100, 0 when disabled
+    pub horizon_roll: f64,
+}
+
+/// Crop factor of every frame, 1 is no crop
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CropReport {
+    pub max: f64,
+    pub mean: f64,
+    pub frames: usize, // With a computed crop, the others are not included
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RenderReport {
+    pub output_path: String,
+    pub codec: String,
+    pub width: usize,
+    pub height: usize,
+    pub settings: String, // As displayed in the render queue
+    pub encode: Option<EncodeReport>,
+}
+
+/// From the last statistics of the render
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct EncodeReport {
+    pub frames: usize,
+    pub fps: f64,
+    pub decode_fps: f64,
+    pub process_fps: f64,
+    pub encode_fps: f64,
+    pub bitrate_kbps: f64,
+    pub dropped_frames: usize,
+    pub duplicated_frames: usize,
+}
+
+impl CropReport {
+    pub fn from_minimal_fovs(minimal_fovs: &[f64]) -> Self {
+        let crops = minimal_fovs.iter().filter(|x| x.is_finite() && **x > 0.0).map(|x| 1.0 / x).collect::<Vec<_>>();
+        if crops.is_empty() {
+            return Self { max: 1.0, mean: 1.0, frames: 0 };
+        }
+        Self {
+            max: crops.iter().copied().fold(1.0, f64::max),
+            mean: crops.iter().sum::<f64>() / crops.len() as f64,
+            frames: crops.len(),
+        }
+    }
+}
+
+impl ProcessingReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Single page with everything inline, `graph` is drawn as the orientation and crop charts
+    pub fn to_html(&self, graph: &GraphData) -> String {
+        let mut html = String::new();
+        let title = format!("Gyroflow processing report - {}", crate::filesystem::get_filename(&self.input_file));
+        let _ = write!(html, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title>\n", escape(&title));
+        html.push_str("<style>body{font-family:sans-serif;background:#1e1e1e;color:#ddd;margin:2em}table{border-collapse:collapse;margin-bottom:1.5em}\
+                       td,th{border:1px solid #444;padding:3px 8px;text-align:left}th{background:#2a2a2a}h2{margin-top:1.5em}.rejected{color:#e66}svg{background:#262626}</style>\n</head><body>\n");
+        let _ = writeln!(html, "<h1>{}</h1>", escape(&title));
+
+        let mut section = |name: &str, rows: Vec<(&str, String)>| {
+            let _ = writeln!(html, "<h2>{name}</h2><table>");
+            for (k, v) in rows {
+                let _ = writeln!(html, "<tr><th>{k}</th><td>{}</td></tr>", escape(&v));
+            }
+            html.push_str("</table>\n");
+        };
+        let opt = |v: Option<f64>| v.map(|v| format!("{v:.3}")).unwrap_or_else(|| "-".into());
+        let vec3 = |v: [f64; 3]| format!("{:.4}, {:.4}, {:.4}", v[0], v[1], v[2]);
+
+        section("Video", vec![
+            ("File",       self.input_file.clone()),
+            ("Resolution", format!("{}x{}", self.video.width, self.video.height)),
+            ("Frame rate", format!("{:.3}", self.video.fps)),
+            ("Frames",     self.video.frame_count.to_string()),
+            ("Duration",   format!("{:.3} s", self.video.duration_ms / 1000.0)),
+            ("Version",    format!("Gyroflow {}, report {}", self.app_version, self.version)),
+        ]);
+        section("Camera and lens", vec![
+            ("Camera",         format!("{} {}", self.camera.brand, self.camera.model).trim().to_string()),
+            ("Lens",           self.camera.lens_model.clone()),
+            ("Profile",        self.lens.name.clone()),
+            ("Calibrated by",  self.lens.calibrated_by.clone()),
+            ("Official",       self.lens.official.to_string()),
+            ("Model",          self.lens.distortion_model.clone()),
+            ("Match score",    opt(self.lens.match_score)),
+        ]);
+        section("Rolling shutter and gyro bias", vec![
+            ("Readout time",  format!("{:.3} ms{}", self.rolling_shutter.readout_time_ms, if self.rolling_shutter.overridden { " (override)" } else { "" })),
+            ("Direction",     format!("{:?}", self.rolling_shutter.direction)),
+            ("Strength",      format!("{:.0}%", self.rolling_shutter.strength * 100.0)),
+            ("Applied bias",  self.gyro_bias.applied.map(vec3).unwrap_or_else(|| "-".into())),
+            ("Detected bias", self.gyro_bias.detected.as_ref().map(|x| format!("{} ({} samples)", vec3(x.bias), x.num_samples)).unwrap_or_else(|| "-".into())),
+        ]);
+        section("Smoothing and crop", [
+            vec![("Method", self.smoothing.method.clone())],
+            self.smoothing.parameters.iter().map(|(k, v)| (k.as_str(), format!("{v}"))).collect(),
+            vec![
+                ("Horizon lock", format!("{:.0}%, roll {:.1}°", self.smoothing.horizon_lock_amount, self.smoothing.horizon_roll)),
+                ("Max crop",     format!("{:.3}", self.crop.max)),
+                ("Mean crop",    format!("{:.3}", self.crop.mean)),
+            ]
+        ].concat());
+        if let Some(render) = &self.render {
+            let mut rows = vec![
+                ("Output",     render.output_path.clone()),
+                ("Codec",      render.codec.clone()),
+                ("Resolution", format!("{}x{}", render.width, render.height)),
+                ("Settings",   render.settings.clone()),
+            ];
+            if let Some(e) = &render.encode {
+                rows.extend([
+                    ("Frames",        e.frames.to_string()),
+                    ("Speed",         format!("{:.1} fps (decode {:.1}, process {:.1}, encode {:.1})", e.fps, e.decode_fps, e.process_fps, e.encode_fps)),
+                    ("Bitrate",       format!("{:.0} kbps", e.bitrate_kbps)),
+                    ("Dropped / duplicated frames", format!("{} / {}", e.dropped_frames, e.duplicated_frames)),
+                ]);
+            }
+            section("Render", rows);
+        }
+
+        html.push_str("<h2>Sync points</h2><table><tr><th>Time</th><th>Offset</th><th>Cost</th><th>Status</th></tr>\n");
+        for p in &self.sync.points {
+            let status = if p.accepted { "accepted".to_string() } else { format!("rejected: {}", p.rejection.as_deref().unwrap_or_default()) };
+            let _ = writeln!(html, "<tr{}><td>{:.3} s</td><td>{:.3} ms</td><td>{}</td><td>{}</td></tr>",
+                if p.accepted { "" } else { " class=\"rejected\"" }, p.timestamp_ms / 1000.0, p.offset_ms, opt(p.cost), escape(&status));
+        }
+        html.push_str("</table>\n");
+        if let Some(d) = &self.sync.drift {
+            let _ = writeln!(html, "<p>Interpolation: {:?}, clock drift: {:.2} ppm, residuals RMS: {:.3} ms, max: {:.3} ms ({} points)</p>", self.sync.interpolation, d.drift_ppm, d.rms_ms, d.max_ms, d.points);
+        }
+
+        if !graph.timestamps_ms.is_empty() {
+            html.push_str("<h2>Orientation</h2>\n");
+            for (i, axis) in ["Pitch", "Yaw", "Roll"].iter().enumerate() {
+                html.push_str(&svg_chart(axis, &graph.timestamps_ms, &[
+                    ("original", "#8888ff", graph.original.iter().map(|x| x[i]).collect()),
+                    ("smoothed", "#ff8844", graph.smoothed.iter().map(|x| x[i]).collect()),
+                ]));
+            }
+            html.push_str("<h2>Correction and crop</h2>\n");
+            html.push_str(&svg_chart("Correction (°)", &graph.timestamps_ms, &[("correction", "#44cc88", graph.correction.clone())]));
+            html.push_str(&svg_chart("Crop factor", &graph.timestamps_ms, &[("crop", "#dddd44", graph.crop_factor.clone())]));
+        }
+        html.push_str("</body></html>\n");
+        html
+    }
+}
+
+fn svg_chart(title: &str, timestamps_ms: &[f64], series: &[(&str, &str, Vec<f64>)]) -> String {
+    let (w, h, margin) = (900.0, 160.0, 4.0);
+    let (t0, t1) = (timestamps_ms.first().copied().unwrap_or_default(), timestamps_ms.last().copied().unwrap_or_default());
+    let values = series.iter().flat_map(|x| x.2.iter().copied()).filter(|x| x.is_finite());
+    let (min, max) = values.fold((f64::MAX, f64::MIN), |(min, max), v| (min.min(v), max.max(v)));
+    let (min, max) = if min > max { (0.0, 1.0) } else if max - min < 1e-9 { (min - 0.5, max + 0.5) } else { (min, max) };
+
+    let mut svg = format!("<div><b>{}</b> <small>{min:.3} .. {max:.3}</small><br><svg width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">", escape(title));
+    for (name, color, data) in series {
+        let points = timestamps_ms.iter().zip(data).filter(|(_, v)| v.is_finite()).map(|(t, v)| {
+            let x = if t1 > t0 { (t - t0) / (t1 - t0) * w } else { 0.0 };
+            let y = margin + (1.0 - (v - min) / (max - min)) * (h - margin * 2.0);
+            format!("{x:.1},{y:.1}")
+        }).collect::<Vec<_>>().join(" ");
+        let _ = write!(svg, "<polyline fill=\"none\" stroke=\"{color}\" stroke-width=\"1\" points=\"{points}\"><title>{name}</title></polyline>");
+    }
+    svg.push_str("</svg></div>\n");
+    svg
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_fields() {
+        let report = ProcessingReport {
+            version: REPORT_VERSION,
+            input_file: "file:///clips/<test>.mp4".into(),
+            sync: SyncReport { points: vec![
+                SyncPointReport { timestamp_ms: 1000.0, offset_ms: 12.5, cost: Some(0.3), accepted: true, rejection: None },
+                SyncPointReport { timestamp_ms: 2000.0, offset_ms: 40.0, cost: Some(2.0), accepted: false, rejection: Some("Low rank".into()) },
+            ], ..Default::default() },
+            crop: CropReport::from_minimal_fovs(&[1.0, 0.8, 0.5, 0.0]),
+            ..Default::default()
+        };
+        assert_eq!(report.crop, CropReport { max: 2.0, mean: (1.0 + 1.25 + 2.0) / 3.0, frames: 3 });
+        assert_eq!(CropReport::from_minimal_fovs(&[]), CropReport { max: 1.0, mean: 1.0, frames: 0 });
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        let mut keys = json.as_object().unwrap().keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["app_version", "camera", "crop", "gyro_bias", "input_file", "lens", "render", "rolling_shutter", "smoothing", "sync", "version", "video"]);
+        assert_eq!(json["sync"]["points"][1]["rejection"], "Low rank");
+        assert_eq!(json["crop"]["max"], 2.0);
+        // Older reports and ones with unknown fields still load
+        assert_eq!(serde_json::from_str::<ProcessingReport>(&report.to_json()).unwrap(), report);
+        assert_eq!(serde_json::from_str::<ProcessingReport>(r#"{ "version": 1, "future": true }"#).unwrap().version, 1);
+
+        let graph = GraphData { timestamps_ms: vec![0.0, 500.0, 1000.0], original: vec![[0.0; 3], [1.0; 3], [2.0; 3]], smoothed: vec![[0.0; 3]; 3], correction: vec![0.0, 1.0, 2.0], crop_factor: vec![1.0; 3] };
+        let html = report.to_html(&graph);
+        assert!(html.contains("&lt;test&gt;.mp4") && !html.contains("<test>"));
+        assert_eq!(html.matches("<svg").count(), 5);
+        assert!(html.contains("points=\"0.0,156.0 450.0,80.0 900.0,4.0\""));
+        assert!(html.contains("rejected: Low rank"));
+    }
+}
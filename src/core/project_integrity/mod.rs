@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Integrity of the motion and sync data embedded in the .gyroflow project files.
+// Files get truncated by cloud sync or a crash in the middle of saving, and a broken blob used to either fail
+// with a confusing error or, worse, load only part of the gyro data. Every embedded blob is saved with its length
+// and CRC32 in `gyro_source.integrity`, and they are verified before anything is loaded.
+// Files saved before this have no integrity info, for them the blobs which are loaded are checked to decompress.
+
+use serde_json::{ Map, Value };
+
+pub const INTEGRITY_KEY: &str = "integrity";
+
+// Compressed blobs in `gyro_source`
+const BLOB_FIELDS: &[&str] = &[
+    "file_metadata", "raw_imu", "quaternions", "image_orientations", "gravity_vectors",
+    "synced_imu_timestamps", "synced_imu_timestamps_with_per_frame_offset", "integrated_quaternions", "smoothed_quaternions", "adaptive_zoom_fovs",
+];
+// Blobs read by the loader, the rest is only written for external tools
+const LOADED_BLOB_FIELDS: &[&str] = &[ "file_metadata", "raw_imu", "quaternions", "image_orientations", "gravity_vectors" ];
+// The sync offsets are plain JSON at the top level, stored under this name
+const OFFSETS_FIELD: &str = "offsets";
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BlobInfo {
+    pub length: usize,
+    pub crc32: u32,
+}
+impl BlobInfo {
+    pub fn of(data: &[u8]) -> Self {
+        Self { length: data.len(), crc32: crc32fast::hash(data) }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum IntegrityError {
+    #[error("{field} is truncated ({actual} of {expected} bytes)")]
+    Truncated { field: String, expected: usize, actual: usize },
+
+    #[error("{field} has an invalid checksum")]
+    ChecksumMismatch { field: String },
+
+    #[error("{field} is missing")]
+    Missing { field: String },
+
+    #[error("{field} can't be decoded")]
+    Undecodable { field: String },
+}
+impl IntegrityError {
+    pub fn field(&self) -> &str {
+        match self {
+            Self::Truncated { field, .. } | Self::ChecksumMismatch { field } | Self::Missing { field } | Self::Undecodable { field } => field
+        }
+    }
+}
+
+fn offsets_data(obj: &Value) -> Option<String> {
+    obj.get(OFFSETS_FIELD).filter(|x| x.as_object().is_some_and(|x| !x.is_empty())).map(|x| x.to_string())
+}
+
+/// Stores the length and checksum of every embedded blob and of the sync offsets. Called last when saving
+pub fn sign(obj: &mut Value) {
+    let offsets = offsets_data(obj);
+    let Some(Value::Object(gyro_source)) = obj.get_mut("gyro_source") else { return; };
+    let mut integrity = Map::new();
+    for field in BLOB_FIELDS {
+        if let Some(blob) = gyro_source.get(*field).and_then(|x| x.as_str()) {
+            integrity.insert(field.to_string(), serde_json::to_value(BlobInfo::of(blob.as_bytes())).unwrap());
+        }
+    }
+    if let Some(offsets) = offsets {
+        integrity.insert(OFFSETS_FIELD.to_string(), serde_json::to_value(BlobInfo::of(offsets.as_bytes())).unwrap());
+    }
+    if integrity.is_empty() {
+        gyro_source.remove(INTEGRITY_KEY);
+    } else {
+        gyro_source.insert(INTEGRITY_KEY.into(), Value::Object(integrity));
+    }
+}
+
+/// Checks the embedded data against the stored integrity info, or for older files, that it can be decompressed
+pub fn verify(obj: &Value) -> Result<(), IntegrityError> {
+    let Some(gyro_source) = obj.get("gyro_source").and_then(|x| x.as_object()) else { return Ok(()); };
+    match gyro_source.get(INTEGRITY_KEY).and_then(|x| x.as_object()) {
+        Some(integrity) => {
+            let offsets = offsets_data(obj);
+            for (field, info) in integrity {
+                let Ok(info) = serde_json::from_value::<BlobInfo>(info.clone()) else {
+                    return Err(IntegrityError::Undecodable { field: format!("{INTEGRITY_KEY}.{field}") });
+                };
+                let data = if field == OFFSETS_FIELD { offsets.clone() } else { gyro_source.get(field).and_then(|x| x.as_str()).map(str::to_owned) };
+                let Some(data) = data else { return Err(IntegrityError::Missing { field: field.clone() }); };
+                if data.len() != info.length {
+                    return Err(IntegrityError::Truncated { field: field.clone(), expected: info.length, actual: data.len() });
+                }
+                if crc32fast::hash(data.as_bytes()) != info.crc32 {
+                    return Err(IntegrityError::ChecksumMismatch { field: field.clone() });
+                }
+            }
+        },
+        None => {
+            for field in LOADED_BLOB_FIELDS {
+                if let Some(blob) = gyro_source.get(*field).and_then(|x| x.as_str()) {
+                    if crate::util::decompress_from_base91(blob).is_none() {
+                        return Err(IntegrityError::Undecodable { field: field.to_string() });
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the embedded motion data, so it's read again from the source file. The sync offsets are only removed if they are the broken part
+pub fn strip_embedded(obj: &mut Value, error: &IntegrityError) {
+    if error.field() == OFFSETS_FIELD {
+        if let Some(obj) = obj.as_object_mut() { obj.remove(OFFSETS_FIELD); }
+    }
+    if let Some(Value::Object(gyro_source)) = obj.get_mut("gyro_source") {
+        for field in BLOB_FIELDS {
+            gyro_source.remove(*field);
+        }
+        gyro_source.remove(INTEGRITY_KEY);
+    }
+}
+
+/// Url of the file the motion data was read from
+pub fn telemetry_source(obj: &Value) -> Option<String> {
+    let gyro_url = obj.get("gyro_source").and_then(|x| x.get("filepath")).and_then(|x| x.as_str()).filter(|x| !x.is_empty());
+    gyro_url.or_else(|| obj.get("videofile").and_then(|x| x.as_str()).filter(|x| !x.is_empty())).map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ StabilizationManager, GyroflowProjectType, GyroflowCoreError };
+    use crate::gyro_source::{ FileMetadata, TimeIMU };
+    use std::sync::{ Arc, atomic::AtomicBool };
+
+    fn signed_project() -> Value {
+        let stab = StabilizationManager::default();
+        {
+            let mut params = stab.params.write();
+            params.fps = 30.0;
+            params.frame_count = 60;
+            params.duration_ms = 2000.0;
+        }
+        stab.gyro.write().init_from_params(&stab.params.read());
+        let raw_imu = (0..400).map(|i| TimeIMU { timestamp_ms: i as f64 * 5.0, gyro: Some([(i as f64 * 0.1).sin(), 0.5, -0.2]), accl: Some([0.0, 0.0, 1.0]), magn: None }).collect();
+        stab.gyro.write().load_from_telemetry(FileMetadata { raw_imu, detected_source: Some("Test".into()), ..Default::default() });
+        stab.gyro.write().set_offset(1_000_000, 12.5);
+        let mut obj: Value = serde_json::from_str(&stab.export_gyroflow_data(GyroflowProjectType::WithGyroData, "{}", None).unwrap()).unwrap();
+        obj["videofile"] = "".into();
+        obj["gyro_source"]["filepath"] = "file:///nonexistent/clip.gcsv".into();
+        obj
+    }
+    fn import(data: &[u8]) -> Result<Value, GyroflowCoreError> {
+        let mut is_preset = false;
+        StabilizationManager::default().import_gyroflow_data(data, false, None, |_| (), Arc::new(AtomicBool::new(false)), &mut is_preset, false)
+    }
+    fn blob_field(obj: &Value) -> String {
+        obj["gyro_source"]["file_metadata"].as_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn valid() {
+        let obj = signed_project();
+        assert!(obj["gyro_source"][INTEGRITY_KEY]["file_metadata"]["length"].as_u64().unwrap() > 0);
+        assert!(obj["gyro_source"][INTEGRITY_KEY][OFFSETS_FIELD].is_object());
+        assert_eq!(verify(&obj), Ok(()));
+        assert!(import(obj.to_string().as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn truncated_file() {
+        let data = signed_project().to_string();
+        assert!(matches!(import(&data.as_bytes()[..data.len() * 2 / 3]), Err(GyroflowCoreError::ProjectFileTruncated)));
+
+        // Older file without the integrity info
+        let data = include_str!("../project_migration/fixtures/v2.gyroflow");
+        assert!(matches!(import(&data.as_bytes()[..data.len() / 2]), Err(GyroflowCoreError::ProjectFileTruncated)));
+    }
+
+    #[test]
+    fn truncated_blob() {
+        let mut obj = signed_project();
+        let blob = blob_field(&obj);
+        obj["gyro_source"]["file_metadata"] = blob[..blob.len() / 2].into();
+        assert_eq!(verify(&obj), Err(IntegrityError::Truncated { field: "file_metadata".into(), expected: blob.len(), actual: blob.len() / 2 }));
+        match import(obj.to_string().as_bytes()) {
+            Err(GyroflowCoreError::EmbeddedDataCorrupted { error, source_url }) => {
+                assert_eq!(error.field(), "file_metadata");
+                assert_eq!(source_url, None); // Not reachable
+            },
+            x => panic!("Unexpected result: {x:?}")
+        }
+    }
+
+    #[test]
+    fn bit_flipped_blob() {
+        let mut obj = signed_project();
+        let mut blob = blob_field(&obj).into_bytes();
+        let i = blob.len() / 2;
+        blob[i] = if blob[i] == b'A' { b'B' } else { b'A' };
+        obj["gyro_source"]["file_metadata"] = String::from_utf8(blob).unwrap().into();
+        assert_eq!(verify(&obj), Err(IntegrityError::ChecksumMismatch { field: "file_metadata".into() }));
+        assert!(matches!(import(obj.to_string().as_bytes()), Err(GyroflowCoreError::EmbeddedDataCorrupted { .. })));
+    }
+
+    #[test]
+    fn missing_blob() {
+        let mut obj = signed_project();
+        obj["gyro_source"].as_object_mut().unwrap().remove("file_metadata");
+        assert_eq!(verify(&obj), Err(IntegrityError::Missing { field: "file_metadata".into() }));
+    }
+
+    #[test]
+    fn modified_offsets() {
+        let mut obj = signed_project();
+        obj[OFFSETS_FIELD]["1000000"] = 13.5.into();
+        let error = verify(&obj).unwrap_err();
+        assert_eq!(error, IntegrityError::ChecksumMismatch { field: OFFSETS_FIELD.into() });
+
+        strip_embedded(&mut obj, &error);
+        assert!(obj.get(OFFSETS_FIELD).is_none() && obj["gyro_source"].get("file_metadata").is_none());
+        assert_eq!(verify(&obj), Ok(()));
+    }
+
+    #[test]
+    fn invalid_integrity_info() {
+        let mut obj = signed_project();
+        obj["gyro_source"][INTEGRITY_KEY]["file_metadata"] = "garbage".into();
+        assert_eq!(verify(&obj), Err(IntegrityError::Undecodable { field: format!("{INTEGRITY_KEY}.file_metadata") }));
+    }
+
+    #[test]
+    fn atomic_save() {
+        let dir = std::env::temp_dir().join(format!("gyroflow-integrity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("project.gyroflow");
+        std::fs::write(&path, "previous").unwrap();
+
+        let data = signed_project().to_string();
+        crate::filesystem::write_atomic(&crate::filesystem::path_to_url(path.to_str().unwrap()), data.as_bytes()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), data);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1); // No temporary file left behind
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_file() {
+        let mut obj = signed_project();
+        obj["gyro_source"].as_object_mut().unwrap().remove(INTEGRITY_KEY);
+        assert_eq!(verify(&obj), Ok(()));
+
+        let blob = blob_field(&obj);
+        obj["gyro_source"]["file_metadata"] = blob[..blob.len() - 10].into();
+        assert_eq!(verify(&obj), Err(IntegrityError::Undecodable { field: "file_metadata".into() }));
+    }
+}
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Versioning of the .gyroflow project files. The loader only understands the current format, so older files are upgraded
+// step by step before loading, and every step reports what it changed. Files from a newer version are loaded as they are,
+// but flagged read-only, and the fields this version doesn't write are kept, so saving the project again doesn't lose them.
+
+use serde_json::{ Map, Value };
+
+pub const CURRENT_VERSION: u64 = 3;
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MigrationAction {
+    pub from_version: u64,
+    pub to_version: u64,
+    pub description: String,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MigrationReport {
+    pub file_version: u64,
+    pub read_only: bool, // Written by a newer version
+    pub actions: Vec<MigrationAction>,
+}
+
+// Motion data and other fields derived from the loaded files, which are written again from the current state
+const DERIVED_FIELDS: &[(&str, &str)] = &[
+    ("gyro_source", "file_metadata"), ("gyro_source", "raw_imu"), ("gyro_source", "quaternions"), ("gyro_source", "image_orientations"),
+    ("gyro_source", "gravity_vectors"), ("gyro_source", "integrated_quaternions"), ("gyro_source", "smoothed_quaternions"),
+    ("gyro_source", "adaptive_zoom_fovs"), ("gyro_source", "synced_imu_timestamps"), ("gyro_source", "synced_imu_timestamps_with_per_frame_offset"),
+    ("gyro_source", "filepath_bookmark"), ("", "videofile_bookmark"),
+];
+
+/// Version of the file, the ones without it are the oldest format
+pub fn file_version(obj: &Value) -> u64 {
+    obj.get("version").and_then(|x| x.as_u64()).unwrap_or(1)
+}
+
+/// Upgrades `obj` to the current version in place
+pub fn migrate(obj: &mut Value) -> MigrationReport {
+    let file_version = file_version(obj);
+    let mut report = MigrationReport { file_version, read_only: file_version > CURRENT_VERSION, actions: Vec::new() };
+    let Value::Object(obj) = obj else { return report; };
+    if report.read_only {
+        log::warn!("Project file version {file_version} is newer than the supported {CURRENT_VERSION}, unknown fields will be kept as they are");
+        return report;
+    }
+
+    let steps: [fn(&mut Map<String, Value>) -> Vec<String>; 2] = [v1_to_v2, v2_to_v3];
+    for version in file_version.max(1)..CURRENT_VERSION {
+        for description in steps[version as usize - 1](obj) {
+            log::info!("Project migration {version} -> {}: {description}", version + 1);
+            report.actions.push(MigrationAction { from_version: version, to_version: version + 1, description });
+        }
+    }
+    obj.insert("version".into(), CURRENT_VERSION.into());
+    report
+}
+
+// The trim range was a single start and end, and the horizontal and inverted readout were a flag and a negative readout time
+fn v1_to_v2(obj: &mut Map<String, Value>) -> Vec<String> {
+    let mut actions = Vec::new();
+    if let (Some(start), Some(end)) = (obj.get("trim_start").and_then(|x| x.as_f64()), obj.get("trim_end").and_then(|x| x.as_f64())) {
+        obj.insert("trim_ranges".into(), serde_json::json!([[start, end]]));
+        actions.push(format!("Converted the trim start and end ({start:.3} - {end:.3}) to a trim range"));
+    }
+    obj.remove("trim_start");
+    obj.remove("trim_end");
+
+    if let Some(Value::Object(stab)) = obj.get_mut("stabilization") {
+        let horizontal = stab.remove("horizontal_rs").and_then(|x| x.as_bool()).unwrap_or_default();
+        let readout_time = stab.get("frame_readout_time").and_then(|x| x.as_f64()).unwrap_or_default();
+        if !stab.contains_key("frame_readout_direction") && (horizontal || readout_time < 0.0) {
+            let direction = match (horizontal, readout_time < 0.0) {
+                (true, true) => "RightToLeft",
+                (true, false) => "LeftToRight",
+                _ => "BottomToTop",
+            };
+            stab.insert("frame_readout_direction".into(), direction.into());
+            stab.insert("frame_readout_time".into(), readout_time.abs().into());
+            actions.push(format!("Set the rolling shutter direction to {direction}"));
+        }
+    }
+    actions
+}
+
+// The trim ranges were fractions of the duration
+fn v2_to_v3(obj: &mut Map<String, Value>) -> Vec<String> {
+    let duration_ms = obj.get("video_info").and_then(|x| x.get("duration_ms")).and_then(|x| x.as_f64()).unwrap_or_default();
+    match obj.get("trim_ranges").and_then(|x| x.as_array()) {
+        Some(ranges) if duration_ms > 0.0 && !obj.contains_key("trim_ranges_ms") => {
+            let ranges_ms = ranges.iter().filter_map(|x| {
+                let x = x.as_array()?;
+                Some(serde_json::json!([x.first()?.as_f64()? * duration_ms, x.get(1)?.as_f64()? * duration_ms]))
+            }).collect::<Vec<_>>();
+            let description = format!("Converted {} trim range(s) to milliseconds", ranges_ms.len());
+            obj.insert("trim_ranges_ms".into(), Value::Array(ranges_ms));
+            obj.remove("trim_ranges");
+            vec![description]
+        },
+        // Loaded as fractions of the duration of the video
+        Some(_) if duration_ms <= 0.0 => vec!["Kept the trim ranges as fractions of the video duration, which is unknown".into()],
+        _ => Vec::new()
+    }
+}
+
+/// Fields of a file from a newer version to keep when saving, without the motion data which is written from the loaded state
+pub fn preserved_fields(obj: &Value) -> Value {
+    let mut ret = obj.clone();
+    for (section, key) in DERIVED_FIELDS {
+        let target = if section.is_empty() { Some(&mut ret) } else { ret.get_mut(*section) };
+        if let Some(Value::Object(target)) = target {
+            target.remove(*key);
+        }
+    }
+    ret
+}
+
+/// Adds the preserved fields which are missing in `obj`, the current values always take precedence
+pub fn restore_preserved(obj: &mut Value, preserved: &Value) {
+    if let (Value::Object(obj), Value::Object(preserved)) = (obj, preserved) {
+        for (k, v) in preserved {
+            match obj.get_mut(k) {
+                Some(current) => restore_preserved(current, v),
+                None => { obj.insert(k.clone(), v.clone()); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ GyroflowProjectType, StabilizationManager };
+    use std::sync::{ Arc, atomic::AtomicBool };
+
+    fn import(stab: &StabilizationManager, data: &str) -> Value {
+        let mut is_preset = false;
+        stab.import_gyroflow_data(data.as_bytes(), false, None, |_| (), Arc::new(AtomicBool::new(false)), &mut is_preset, false).unwrap()
+    }
+    fn round_trip(data: &str) -> (Value, Value) {
+        let stab = StabilizationManager::default();
+        let loaded = import(&stab, data);
+        let mut saved: Value = serde_json::from_str(&stab.export_gyroflow_data(GyroflowProjectType::Simple, "{}", None).unwrap()).unwrap();
+        assert_eq!(migrate(&mut saved), MigrationReport { file_version: CURRENT_VERSION, read_only: false, actions: Vec::new() });
+        (loaded, saved)
+    }
+
+    #[test]
+    fn v1_upgrade() {
+        let mut obj: Value = serde_json::from_str(include_str!("fixtures/v1.gyroflow")).unwrap();
+        let report = migrate(&mut obj);
+        assert_eq!(report.file_version, 1);
+        assert!(!report.read_only);
+        assert_eq!(report.actions.iter().map(|x| (x.from_version, x.to_version)).collect::<Vec<_>>(), [(1, 2), (1, 2), (2, 3)]);
+        assert_eq!(obj["version"], CURRENT_VERSION);
+        assert!(obj.get("trim_start").is_none() && obj.get("trim_ranges").is_none());
+        assert_eq!(obj["trim_ranges_ms"], serde_json::json!([[3003.0, 27027.0]]));
+        assert_eq!(obj["stabilization"]["frame_readout_direction"], "RightToLeft");
+        assert_eq!(obj["stabilization"]["frame_readout_time"], 8.9);
+
+        let (loaded, saved) = round_trip(include_str!("fixtures/v1.gyroflow"));
+        assert_eq!(loaded["project_migration"]["actions"].as_array().unwrap().len(), 3);
+        assert_eq!(saved["stabilization"]["frame_readout_direction"], "RightToLeft");
+        assert_eq!(saved["stabilization"]["frame_readout_time"], 8.9);
+        let trim = saved["trim_ranges_ms"][0].as_array().unwrap().iter().map(|x| x.as_f64().unwrap()).collect::<Vec<_>>();
+        assert!((trim[0] - 3003.0).abs() < 1e-6 && (trim[1] - 27027.0).abs() < 1e-6, "{trim:?}");
+    }
+
+    #[test]
+    fn v2_upgrade() {
+        let mut obj: Value = serde_json::from_str(include_str!("fixtures/v2.gyroflow")).unwrap();
+        let report = migrate(&mut obj);
+        assert_eq!(report.actions, [MigrationAction { from_version: 2, to_version: 3, description: "Converted 2 trim range(s) to milliseconds".into() }]);
+        assert_eq!(obj["trim_ranges_ms"], serde_json::json!([[0.0, 6000.0], [12000.0, 18000.0]]));
+        assert_eq!(obj["stabilization"]["frame_readout_direction"], "BottomToTop");
+
+        let (_, saved) = round_trip(include_str!("fixtures/v2.gyroflow"));
+        assert_eq!(saved["trim_ranges_ms"].as_array().unwrap().len(), 2);
+        assert_eq!(saved["stabilization"]["frame_readout_direction"], "BottomToTop");
+        assert_eq!(saved["stabilization"]["frame_readout_time"], 15.2);
+        assert_eq!(saved["video_info"]["num_frames"], 600);
+    }
+
+    #[test]
+    fn newer_version_keeps_unknown_fields() {
+        let mut obj: Value = serde_json::from_str(include_str!("fixtures/v2.gyroflow")).unwrap();
+        obj["version"] = 99.into();
+        obj["future_section"] = serde_json::json!({ "a": 1 });
+        obj["stabilization"]["future_param"] = 0.5.into();
+        obj["gyro_source"]["smoothed_quaternions"] = "stale".into();
+        let data = obj.to_string();
+
+        let report = migrate(&mut obj.clone());
+        assert!(report.read_only && report.actions.is_empty());
+
+        let stab = StabilizationManager::default();
+        let loaded = import(&stab, &data);
+        assert_eq!(loaded["project_migration"]["read_only"], true);
+        let saved: Value = serde_json::from_str(&stab.export_gyroflow_data(GyroflowProjectType::Simple, "{}", None).unwrap()).unwrap();
+        assert_eq!(saved["version"], 99);
+        assert_eq!(saved["future_section"], obj["future_section"]);
+        assert_eq!(saved["stabilization"]["future_param"], 0.5);
+        assert_ne!(saved["gyro_source"]["smoothed_quaternions"], "stale");
+        assert_eq!(saved["stabilization"]["frame_readout_time"], 15.2);
+    }
+
+    #[test]
+    fn newer_version_is_read_only() {
+        let mut obj: Value = serde_json::from_str(include_str!("fixtures/v2.gyroflow")).unwrap();
+        obj["version"] = 99.into();
+        let dir = std::env::temp_dir().join(format!("gyroflow_read_only_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let url = |name: &str| crate::filesystem::path_to_url(&dir.join(name).to_string_lossy());
+        std::fs::write(dir.join("newer.gyroflow"), obj.to_string()).unwrap();
+
+        let stab = StabilizationManager::default();
+        stab.import_gyroflow_file(&url("newer.gyroflow"), false, |_| (), Arc::new(AtomicBool::new(false)), false).unwrap();
+        let err = stab.export_gyroflow_file(&url("newer.gyroflow"), GyroflowProjectType::Simple, "{}").unwrap_err();
+        assert!(matches!(err, crate::GyroflowCoreError::ReadOnlyProject(99)), "{err:?}");
+        assert_eq!(std::fs::read_to_string(dir.join("newer.gyroflow")).unwrap(), obj.to_string());
+
+        // Saved as a new file, which keeps the version of the fields it has
+        stab.export_gyroflow_file(&url("copy.gyroflow"), GyroflowProjectType::Simple, "{}").unwrap();
+        let saved: Value = serde_json::from_slice(&std::fs::read(dir.join("copy.gyroflow")).unwrap()).unwrap();
+        assert_eq!(saved["version"], 99);
+
+        // A project of the current version can be overwritten
+        let stab = StabilizationManager::default();
+        std::fs::write(dir.join("current.gyroflow"), include_str!("fixtures/v2.gyroflow")).unwrap();
+        stab.import_gyroflow_file(&url("current.gyroflow"), false, |_| (), Arc::new(AtomicBool::new(false)), false).unwrap();
+        stab.export_gyroflow_file(&url("current.gyroflow"), GyroflowProjectType::Simple, "{}").unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Header of a .gyroflow project without loading it, for listing many projects at once.
+// Only the small fields are deserialized, the embedded motion data and the per-frame data are skipped over without
+// decoding them, so the time is just the scan of the file. The heavy sections can be loaded later with `load_section`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use serde::de::{ self, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor };
+use crate::GyroflowCoreError;
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct OutputSummary {
+    pub codec: String,
+    pub output_folder: String,
+    pub output_filename: String,
+    pub output_width: usize,
+    pub output_height: usize,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ProjectSummary {
+    pub version: u64,
+    pub app_version: String,
+    pub videofile: String,
+    pub gyro_file: String,
+    pub width: usize,
+    pub height: usize,
+    pub fps: f64,
+    pub num_frames: usize,
+    pub duration_ms: f64,
+    pub trim_ranges_ms: Vec<(f64, f64)>,
+    pub output: Option<OutputSummary>,
+    pub lens_profile: Option<String>,
+    pub smoothing_method: String,
+    pub has_motion_data: bool,    // Embedded gyro data, so the project loads without the original files
+    pub has_processed_data: bool, // Embedded integrated and smoothed quaternions
+    pub sync_points: usize,
+}
+
+impl ProjectSummary {
+    pub fn is_synced(&self) -> bool { self.sync_points > 0 }
+}
+
+// How much data a field has, without keeping it
+#[derive(Default, Clone, Copy, PartialEq)]
+enum Embedded {
+    #[default]
+    Empty,
+    Compressed, // base91 string
+    Items,      // Non-empty array or object
+}
+impl<'de> serde::Deserialize<'de> for Embedded {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EmbeddedVisitor;
+        impl<'de> Visitor<'de> for EmbeddedVisitor {
+            type Value = Embedded;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("any value") }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Embedded, E> { Ok(if v.is_empty() { Embedded::Empty } else { Embedded::Compressed }) }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Embedded, A::Error> {
+                let mut ret = Embedded::Empty;
+                while seq.next_element::<IgnoredAny>()?.is_some() { ret = Embedded::Items; }
+                Ok(ret)
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Embedded, A::Error> {
+                let mut ret = Embedded::Empty;
+                while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() { ret = Embedded::Items; }
+                Ok(ret)
+            }
+            fn visit_bool<E: de::Error>(self, _: bool) -> Result<Embedded, E> { Ok(Embedded::Empty) }
+            fn visit_i64<E: de::Error>(self, _: i64) -> Result<Embedded, E> { Ok(Embedded::Empty) }
+            fn visit_u64<E: de::Error>(self, _: u64) -> Result<Embedded, E> { Ok(Embedded::Empty) }
+            fn visit_f64<E: de::Error>(self, _: f64) -> Result<Embedded, E> { Ok(Embedded::Empty) }
+            fn visit_unit<E: de::Error>(self) -> Result<Embedded, E> { Ok(Embedded::Empty) }
+        }
+        deserializer.deserialize_any(EmbeddedVisitor)
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct VideoInfoHeader {
+    width: usize,
+    height: usize,
+    fps: f64,
+    num_frames: usize,
+    duration_ms: f64,
+}
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct LensHeader {
+    name: String,
+    camera_brand: String,
+    camera_model: String,
+    lens_model: String,
+}
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct StabilizationHeader {
+    method: String,
+}
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct GyroHeader {
+    filepath: String,
+    file_metadata: Embedded,
+    raw_imu: Embedded,
+    quaternions: Embedded,
+    integrated_quaternions: Embedded,
+    smoothed_quaternions: Embedded,
+}
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct Header {
+    version: Option<u64>,
+    app_version: String,
+    videofile: String,
+    video_info: VideoInfoHeader,
+    calibration_data: Option<LensHeader>,
+    stabilization: StabilizationHeader,
+    gyro_source: GyroHeader,
+    offsets: BTreeMap<String, IgnoredAny>,
+    output: Option<OutputSummary>,
+    trim_ranges_ms: Option<Vec<(f64, f64)>>,
+    trim_ranges: Option<Vec<(f64, f64)>>, // Fractions of the duration, in older versions
+    trim_start: Option<f64>,
+    trim_end: Option<f64>,
+}
+
+pub fn load_metadata_only(url: &str) -> Result<ProjectSummary, GyroflowCoreError> {
+    Ok(summary_from_data(&crate::filesystem::read(url)?)?)
+}
+
+pub fn summary_from_data(data: &[u8]) -> serde_json::Result<ProjectSummary> {
+    let header: Header = serde_json::from_slice(data)?;
+    let duration_ms = header.video_info.duration_ms;
+    let trim_ranges_ms = header.trim_ranges_ms
+        .or_else(|| header.trim_ranges.map(|x| x.into_iter().map(|(a, b)| (a * duration_ms, b * duration_ms)).collect()))
+        .or_else(|| Some(vec![(header.trim_start? * duration_ms, header.trim_end? * duration_ms)]))
+        .unwrap_or_default();
+    let lens_profile = header.calibration_data.and_then(|x| {
+        let name = [x.camera_brand, x.camera_model, x.lens_model].into_iter().filter(|x| !x.is_empty()).collect::<Vec<_>>().join(" ");
+        Some(if name.is_empty() { x.name } else { name }).filter(|x| !x.is_empty())
+    });
+    let gyro = &header.gyro_source;
+    Ok(ProjectSummary {
+        version: header.version.unwrap_or(1),
+        app_version: header.app_version,
+        videofile: header.videofile,
+        gyro_file: gyro.filepath.clone(),
+        width: header.video_info.width,
+        height: header.video_info.height,
+        fps: header.video_info.fps,
+        num_frames: header.video_info.num_frames,
+        duration_ms,
+        trim_ranges_ms,
+        output: header.output,
+        lens_profile,
+        smoothing_method: header.stabilization.method,
+        // The simple projects have only the thin metadata object, without the motion
+        has_motion_data: gyro.file_metadata == Embedded::Compressed || gyro.raw_imu != Embedded::Empty || gyro.quaternions != Embedded::Empty,
+        has_processed_data: gyro.integrated_quaternions != Embedded::Empty && gyro.smoothed_quaternions != Embedded::Empty,
+        sync_points: header.offsets.len(),
+    })
+}
+
+/// One top-level section of the project, eg. `gyro_source` with the motion data, skipping over the rest
+pub fn load_section(data: &[u8], name: &str) -> serde_json::Result<Option<serde_json::Value>> {
+    struct SectionVisitor<'a>(&'a str);
+    impl<'de> Visitor<'de> for SectionVisitor<'_> {
+        type Value = Option<serde_json::Value>;
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("a project object") }
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut ret = None;
+            while let Some(key) = map.next_key::<std::borrow::Cow<str>>()? {
+                if ret.is_none() && key == self.0 {
+                    ret = Some(map.next_value()?);
+                } else {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+            Ok(ret)
+        }
+    }
+    let mut deserializer = serde_json::Deserializer::from_slice(data);
+    let ret = deserializer.deserialize_map(SectionVisitor(name))?;
+    deserializer.end()?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(file_metadata: serde_json::Value) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "title": "Gyroflow data file",
+            "version": 3,
+            "app_version": "1.6.0",
+            "videofile": "file:///videos/GX010123.MP4",
+            "calibration_data": { "name": "GoPro_HERO9", "camera_brand": "GoPro", "camera_model": "HERO9 Black", "lens_model": "Wide" },
+            "video_info": { "width": 3840, "height": 2160, "num_frames": 1800, "fps": 59.94, "duration_ms": 30030.0 },
+            "stabilization": { "method": "Default", "smoothing_params": [{ "name": "smoothness", "value": 0.5 }] },
+            "gyro_source": {
+                "filepath": "file:///videos/GX010123.MP4",
+                "file_metadata": file_metadata,
+                "integrated_quaternions": "c\"}X:".repeat(200_000),
+                "smoothed_quaternions": "c\"}X:".repeat(200_000),
+            },
+            "offsets": { "1000000": 12.5, "20000000": 13.0 },
+            "keyframes": {},
+            "output": { "codec": "H.265/HEVC", "output_folder": "file:///renders/", "output_filename": "GX010123_stabilized.mp4", "output_width": 3840, "output_height": 2160, "bitrate": 150.0 },
+            "trim_ranges_ms": [[1000.0, 5000.0]],
+        })).unwrap()
+    }
+
+    #[test]
+    fn summary_without_motion_data() {
+        let data = project(serde_json::Value::String("AB\"CD#$".repeat(1_000_000)));
+        let summary = summary_from_data(&data).unwrap();
+        assert_eq!(summary.version, 3);
+        assert_eq!(summary.videofile, "file:///videos/GX010123.MP4");
+        assert_eq!((summary.width, summary.height, summary.num_frames), (3840, 2160, 1800));
+        assert_eq!(summary.trim_ranges_ms, vec![(1000.0, 5000.0)]);
+        assert_eq!(summary.lens_profile.as_deref(), Some("GoPro HERO9 Black Wide"));
+        assert_eq!(summary.output.as_ref().unwrap().codec, "H.265/HEVC");
+        assert!(summary.has_motion_data && summary.has_processed_data && summary.is_synced());
+        assert_eq!(summary.sync_points, 2);
+
+        let json = serde_json::to_string(&summary).unwrap();
+        assert_eq!(serde_json::from_str::<ProjectSummary>(&json).unwrap(), summary);
+
+        // Thin metadata of a simple project
+        assert!(!summary_from_data(&project(serde_json::json!({ "camera_identifier": null, "frame_readout_time": 8.9 }))).unwrap().has_motion_data);
+
+        let gyro = load_section(&data, "gyro_source").unwrap().unwrap();
+        assert_eq!(gyro["file_metadata"].as_str().unwrap().len(), 7_000_000);
+        assert!(load_section(&data, "unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn older_trim_ranges() {
+        let mut obj: serde_json::Value = serde_json::from_slice(&project(serde_json::Value::Null)).unwrap();
+        let obj = obj.as_object_mut().unwrap();
+        obj.remove("trim_ranges_ms");
+        obj.insert("trim_start".into(), 0.5.into());
+        obj.insert("trim_end".into(), 1.0.into());
+        let summary = summary_from_data(&serde_json::to_vec(obj).unwrap()).unwrap();
+        assert_eq!(summary.trim_ranges_ms, vec![(15015.0, 30030.0)]);
+        assert!(!summary.has_motion_data);
+    }
+}
@@ -0,0 +1,246 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Plain 3D smoothing with the time constant changing with the angular velocity of the camera.
+// The velocity vector is averaged over a window (the adaptation speed), so the jitter cancels out and only the deliberate motion remains.
+// The time constant is `base / (1 + (velocity / threshold)²)`, so at the threshold velocity it's half of the base
+// and it changes continuously with the velocity, without switching between the regimes
+
+use super::*;
+
+use crate::keyframes::*;
+use std::collections::BTreeMap;
+
+const RAD_TO_DEG: f64 = 180.0 / std::f64::consts::PI;
+
+#[derive(Clone)]
+pub struct Adaptive {
+    pub time_constant: f64,
+    pub velocity_threshold: f64, // deg/s
+    pub adaptation_speed: f64,   // s
+    pub trim_range_only: bool,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self { Self {
+        time_constant: 1.0,
+        velocity_threshold: 30.0,
+        adaptation_speed: 0.3,
+        trim_range_only: true,
+    } }
+}
+
+pub fn adaptive_time_constant(base: f64, velocity: f64, threshold: f64) -> f64 {
+    if threshold <= 0.0 { return base; }
+    base / (1.0 + (velocity / threshold).powi(2))
+}
+
+impl SmoothingAlgorithm for Adaptive {
+    fn get_id(&self) -> String { "adaptive".to_owned() }
+    fn get_name(&self) -> String { "Velocity adaptive".to_owned() }
+
+    fn set_parameter(&mut self, name: &str, val: f64) {
+        match name {
+            "time_constant" => self.time_constant = val,
+            "velocity_threshold" => self.velocity_threshold = val,
+            "adaptation_speed" => self.adaptation_speed = val,
+            "trim_range_only" => self.trim_range_only = val > 0.1,
+            _ => log::error!("Invalid parameter name: {}", name)
+        }
+    }
+    fn get_parameter(&self, name: &str) -> f64 {
+        match name {
+            "time_constant" => self.time_constant,
+            "velocity_threshold" => self.velocity_threshold,
+            "adaptation_speed" => self.adaptation_speed,
+            "trim_range_only" => if self.trim_range_only { 1.0 } else { 0.0 },
+            _ => 0.0
+        }
+    }
+
+    fn get_parameters_json(&self) -> serde_json::Value {
+        serde_json::json!([
+            {
+                "name": "time_constant",
+                "description": "Smoothness",
+                "type": "SliderWithField",
+                "from": 0.01,
+                "to": 10.0,
+                "value": self.time_constant,
+                "default": 1.0,
+                "unit": "s",
+                "keyframe": "SmoothingParamTimeConstant"
+            },
+            {
+                "name": "velocity_threshold",
+                "description": "Responsiveness threshold",
+                "type": "SliderWithField",
+                "from": 1.0,
+                "to": 300.0,
+                "value": self.velocity_threshold,
+                "default": 30.0,
+                "precision": 0,
+                "unit": "°/s",
+                "keyframe": "SmoothingParamVelocityThreshold"
+            },
+            {
+                "name": "adaptation_speed",
+                "description": "Adaptation speed",
+                "type": "SliderWithField",
+                "from": 0.01,
+                "to": 2.0,
+                "value": self.adaptation_speed,
+                "default": 0.3,
+                "unit": "s",
+                "keyframe": "SmoothingParamAdaptationSpeed"
+            },
+            {
+                "name": "trim_range_only",
+                "description": "Only within trim range",
+                "advanced": true,
+                "type": "CheckBox",
+                "default": self.trim_range_only,
+                "value": if self.trim_range_only { 1.0 } else { 0.0 },
+            },
+        ])
+    }
+    fn get_status_json(&self) -> serde_json::Value {
+        serde_json::json!([])
+    }
+
+    fn get_checksum(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(self.time_constant.to_bits());
+        hasher.write_u64(self.velocity_threshold.to_bits());
+        hasher.write_u64(self.adaptation_speed.to_bits());
+        hasher.write_u8(self.trim_range_only as u8);
+        hasher.finish()
+    }
+
+    fn smooth(&self, quats: &TimeQuat, duration_ms: f64, params: &ParameterLookup, compute_params: &ComputeParams) -> TimeQuat {
+        if quats.is_empty() || duration_ms <= 0.0 { return quats.clone(); }
+
+        let keyframes = &compute_params.keyframes;
+        let sample_rate: f64 = quats.len() as f64 / (duration_ms / 1000.0);
+        let get_alpha = |time_constant: f64| {
+            if time_constant > 0.0 { 1.0 - (-(1.0 / sample_rate) / time_constant).exp() } else { 1.0 }
+        };
+
+        let quats = Smoothing::get_trimmed_quats(quats, compute_params.scaled_duration_ms, self.trim_range_only, &compute_params.trim_ranges);
+        let quats = quats.as_ref();
+
+        let speed_affects = compute_params.video_speed_affects_smoothing && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed));
+        let constant = params.is_constant() && !speed_affects;
+        let constant_params = params.at(0);
+
+        // (base time constant, threshold, velocity alpha) for each sample
+        let values: BTreeMap<i64, (f64, f64, f64)> = quats.keys().map(|ts| {
+            let p = if constant { Cow::Borrowed(&constant_params) } else { Cow::Owned(params.at(*ts)) };
+            let base = p.get("time_constant") * Smoothing::video_speed_at(compute_params, *ts).unwrap_or(1.0);
+            (*ts, (base, p.get("velocity_threshold"), get_alpha(p.get("adaptation_speed"))))
+        }).collect();
+
+        // Angular velocity vectors in deg/s, averaged forward and backward, so the window is centered
+        let mut prev_quat = *quats.values().next().unwrap();
+        let mut velocity: BTreeMap<i64, Vector3<f64>> = quats.iter().map(|(ts, q)| {
+            let v = (prev_quat.inverse() * q).scaled_axis() * sample_rate * RAD_TO_DEG;
+            prev_quat = *q;
+            (*ts, v)
+        }).collect();
+        let mut prev_velocity = Vector3::zeros();
+        for (ts, vel) in velocity.iter_mut() {
+            let alpha = values[ts].2;
+            *vel = prev_velocity * (1.0 - alpha) + *vel * alpha;
+            prev_velocity = *vel;
+        }
+        for (ts, vel) in velocity.iter_mut().rev() {
+            let alpha = values[ts].2;
+            *vel = prev_velocity * (1.0 - alpha) + *vel * alpha;
+            prev_velocity = *vel;
+        }
+
+        let alpha_per_timestamp: BTreeMap<i64, f64> = velocity.iter().map(|(ts, vel)| {
+            let (base, threshold, _) = values[ts];
+            (*ts, get_alpha(adaptive_time_constant(base, vel.norm(), threshold)))
+        }).collect();
+
+        let mut q = *quats.values().next().unwrap();
+        let smoothed1: TimeQuat = quats.iter().map(|(ts, x)| {
+            q = q.slerp(x, alpha_per_timestamp[ts]);
+            (*ts, q)
+        }).collect();
+
+        // Reverse pass
+        let mut q = *smoothed1.values().next_back().unwrap();
+        smoothed1.iter().rev().map(|(ts, x)| {
+            q = q.slerp(x, alpha_per_timestamp[ts]);
+            (*ts, q)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 200.0;
+
+    // 12 seconds of alternating 2 s segments: handheld shake, a 60°/s pan with a bit of shake, shake, pan back...
+    fn pans_and_shake() -> TimeQuat {
+        (0..(12.0 * SAMPLE_RATE) as i64).map(|i| {
+            let t = i as f64 / SAMPLE_RATE;
+            let segment = (t / 2.0) as i64;
+            let mut yaw = 0.0;
+            for s in 0..=segment {
+                let direction = if (s / 2) % 2 == 0 { 1.0 } else { -1.0 };
+                if s % 2 == 1 { yaw += 60.0 * direction * if s == segment { t - s as f64 * 2.0 } else { 2.0 }; }
+            }
+            let shake = 0.5 * (2.0 * std::f64::consts::PI * 7.0 * t).sin() + 0.3 * (2.0 * std::f64::consts::PI * 11.3 * t + 1.0).sin();
+            yaw += if segment % 2 == 0 { shake } else { shake * 0.15 };
+            ((i as f64 * 1_000_000.0 / SAMPLE_RATE).round() as i64, Quat64::from_axis_angle(&Vector3::z_axis(), yaw.to_radians()))
+        }).collect()
+    }
+
+    // Rotation rate of the output in deg/s
+    fn rates(quats: &TimeQuat) -> Vec<f64> {
+        quats.values().zip(quats.values().skip(1)).map(|(a, b)| a.angle_to(b) * RAD_TO_DEG * SAMPLE_RATE).collect()
+    }
+    // Largest change of the rate over half of the shake period, in the middle second of the segment
+    fn jitter(rates: &[f64], segment: usize) -> f64 {
+        let range = ((segment as f64 * 2.0 + 0.5) * SAMPLE_RATE) as usize..((segment as f64 * 2.0 + 1.5) * SAMPLE_RATE) as usize;
+        range.map(|i| (rates[i] - rates[i - 7]).abs()).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn pans_and_shake_transitions() {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        params.trim_ranges.clear();
+        let quats = pans_and_shake();
+        let duration_ms = quats.len() as f64 * 1000.0 / SAMPLE_RATE;
+
+        let smooth = |alg: &dyn SmoothingAlgorithm| rates(&alg.smooth(&quats, duration_ms, &ParameterLookup::from_keyframes(alg, &params.keyframes), &params));
+        let adaptive = smooth(&Adaptive::default());
+        let plain_fast = smooth(&super::super::plain::Plain { time_constant: 0.2, trim_range_only: true });
+        let plain_slow = smooth(&super::super::plain::Plain { time_constant: 1.0, trim_range_only: true });
+
+        for segment in 0..6 {
+            let range = ((segment as f64 * 2.0 + 0.5) * SAMPLE_RATE) as usize..((segment as f64 * 2.0 + 1.5) * SAMPLE_RATE) as usize;
+            if segment % 2 == 1 {
+                // Pans keep up like with the low smoothness, while the base smoothness lags far behind
+                assert!(adaptive[range.clone()].iter().all(|x| (x - 60.0).abs() < 6.0), "segment {segment}");
+                assert!(plain_slow[range].iter().any(|x| (x - 60.0).abs() > 15.0));
+            } else {
+                // Shake is smoothed a lot more than with the low smoothness
+                assert!(jitter(&adaptive, segment) < jitter(&plain_fast, segment) * 0.5, "segment {segment}: {} {}", jitter(&adaptive, segment), jitter(&plain_fast, segment));
+            }
+        }
+
+        // No snapping between the regimes: the rate changes no faster than with the plain smoothing at the fast time constant
+        let max_acceleration = |rates: &[f64]| rates.iter().zip(rates.iter().skip(1)).skip(10).map(|(a, b)| (b - a).abs() * SAMPLE_RATE).fold(0.0, f64::max);
+        assert!(max_acceleration(&adaptive) < max_acceleration(&plain_fast) * 1.5, "{} {}", max_acceleration(&adaptive), max_acceleration(&plain_fast));
+
+        assert!((adaptive_time_constant(1.0, 30.0, 30.0) - 0.5).abs() < 1e-9);
+        assert_eq!(adaptive_time_constant(1.0, 0.0, 30.0), 1.0);
+        assert_eq!(adaptive_time_constant(1.0, 100.0, 0.0), 1.0);
+    }
+}
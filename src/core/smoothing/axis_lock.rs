@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Locks the chosen axes of the smoothed orientation, independently of the smoothing algorithm.
+// The orientation is decomposed into yaw, pitch and roll in the gravity-aligned frame (the same one as the horizon lock uses),
+// the locked angles are replaced with the targets and the orientation is composed back.
+// When looking straight up or down, yaw and roll are the same rotation and can't be separated,
+// so the lock fades out within `GIMBAL_FADE` of ±90° pitch and at the pole the smoothed orientation is used as is
+
+use super::*;
+use std::f64::consts::FRAC_PI_2;
+
+const GIMBAL_FADE: f64 = 5.0; // deg
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AxisLock {
+    pub pitch: bool,
+    pub yaw: bool,
+    pub roll: bool,
+    pub target: (f64, f64, f64), // pitch, yaw, roll in degrees
+    pub horizon_reference: bool, // Targets are angles to the horizon, otherwise they're relative to the orientation at the start
+}
+
+impl Default for AxisLock {
+    fn default() -> Self { Self {
+        pitch: false,
+        yaw: false,
+        roll: false,
+        target: (0.0, 0.0, 0.0),
+        horizon_reference: true,
+    } }
+}
+
+fn initial_quat() -> Quat64 {
+    Quat64::from_axis_angle(&Vector3::y_axis(), FRAC_PI_2) * Quat64::from_axis_angle(&Vector3::z_axis(), FRAC_PI_2)
+}
+
+/// Orientation from (pitch, yaw, roll) in radians
+pub fn compose(pitch: f64, yaw: f64, roll: f64) -> Quat64 {
+    initial_quat()
+        * Quat64::from_axis_angle(&Vector3::y_axis(), yaw)
+        * Quat64::from_axis_angle(&Vector3::x_axis(), pitch)
+        * Quat64::from_axis_angle(&Vector3::z_axis(), roll)
+}
+
+/// (pitch, yaw, roll) in radians. At ±90° pitch the yaw is 0 and the roll is the whole rotation around the view axis
+pub fn decompose(q: &Quat64) -> (f64, f64, f64) {
+    let view = q * Vector3::z_axis();
+    let pitch = (-view.z).clamp(-1.0, 1.0).asin();
+    let yaw = if view.x.abs() < 1e-12 && view.y.abs() < 1e-12 { 0.0 } else { view.y.atan2(view.x) };
+    let roll = (compose(pitch, yaw, 0.0).inverse() * q).euler_angles().2;
+    (pitch, yaw, roll)
+}
+
+impl AxisLock {
+    pub fn is_enabled(&self) -> bool { self.pitch || self.yaw || self.roll }
+
+    pub fn get_checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u8(self.pitch as u8);
+        hasher.write_u8(self.yaw as u8);
+        hasher.write_u8(self.roll as u8);
+        hasher.write_u64(self.target.0.to_bits());
+        hasher.write_u64(self.target.1.to_bits());
+        hasher.write_u64(self.target.2.to_bits());
+        hasher.write_u8(self.horizon_reference as u8);
+        hasher.finish()
+    }
+
+    pub fn apply(&self, quats: &mut TimeQuat, compute_params: &ComputeParams) {
+        if !self.is_enabled() || quats.is_empty() { return; }
+        let keyframes = &compute_params.keyframes;
+
+        // Relative targets start from the orientation at the beginning of the trim range
+        let start = if self.horizon_reference {
+            (0.0, 0.0, 0.0)
+        } else {
+            let start_us = (compute_params.trim_ranges.first().map(|x| x.0).unwrap_or_default() * compute_params.scaled_duration_ms * 1000.0).round() as i64;
+            decompose(quats.range(start_us..).next().or_else(|| quats.iter().next_back()).unwrap().1)
+        };
+
+        for (ts, q) in quats.iter_mut() {
+            let timestamp_ms = *ts as f64 / 1000.0;
+            let target = |typ: KeyframeType, default: f64| keyframes.value_at_gyro_timestamp(&typ, timestamp_ms).unwrap_or(default).to_radians();
+
+            let (pitch, yaw, roll) = decompose(q);
+            let locked = compose(
+                if self.pitch { start.0 + target(KeyframeType::AxisLockPitch, self.target.0) } else { pitch },
+                if self.yaw   { start.1 + target(KeyframeType::AxisLockYaw,   self.target.1) } else { yaw },
+                if self.roll  { start.2 + target(KeyframeType::AxisLockRoll,  self.target.2) } else { roll },
+            );
+            let amount = ((FRAC_PI_2 - pitch.abs()) / GIMBAL_FADE.to_radians()).clamp(0.0, 1.0);
+            *q = q.slerp(&locked, amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 100.0;
+
+    fn quats(f: impl Fn(f64) -> (f64, f64, f64)) -> TimeQuat {
+        (0..(5.0 * SAMPLE_RATE) as i64).map(|i| {
+            let (pitch, yaw, roll) = f(i as f64 / SAMPLE_RATE);
+            ((i as f64 * 1_000_000.0 / SAMPLE_RATE).round() as i64, compose(pitch.to_radians(), yaw.to_radians(), roll.to_radians()))
+        }).collect()
+    }
+    fn params() -> ComputeParams {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        params.trim_ranges.clear();
+        params
+    }
+
+    #[test]
+    fn decomposition() {
+        for (pitch, yaw, roll) in [(10.0, 20.0, 30.0), (-45.0, 170.0, -5.0), (80.0, -90.0, 60.0), (0.0, 0.0, 0.0)] {
+            let (p, y, r) = decompose(&compose(f64::to_radians(pitch), f64::to_radians(yaw), f64::to_radians(roll)));
+            assert!((p.to_degrees() - pitch).abs() < 1e-6 && (y.to_degrees() - yaw).abs() < 1e-6 && (r.to_degrees() - roll).abs() < 1e-6, "{pitch} {yaw} {roll}: {p} {y} {r}");
+        }
+    }
+
+    #[test]
+    fn lock_roll_only() {
+        let org = quats(|t| (5.0 * t.sin(), 30.0 * (0.5 * t).sin(), 4.0 + 10.0 * (3.0 * t).sin()));
+        let mut locked = org.clone();
+        AxisLock { roll: true, ..Default::default() }.apply(&mut locked, &params());
+        for (a, b) in org.values().zip(locked.values()) {
+            let (a, b) = (decompose(a), decompose(b));
+            assert!(b.2.abs() < 1e-9);
+            assert!((a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9);
+        }
+
+        // Relative to the start, with a keyframed target
+        let mut params = params();
+        params.keyframes.set(&KeyframeType::AxisLockRoll, 0, 2.0);
+        let mut locked = org.clone();
+        AxisLock { roll: true, horizon_reference: false, ..Default::default() }.apply(&mut locked, &params);
+        let start_roll = decompose(org.values().next().unwrap()).2;
+        assert!(locked.values().all(|q| (decompose(q).2 - start_roll - 2.0_f64.to_radians()).abs() < 1e-9));
+    }
+
+    #[test]
+    fn gimbal_lock() {
+        // Tilting up to the zenith and staying there
+        let org = quats(|t| ((30.0 * t).min(90.0), 20.0, 15.0 * (2.0 * t).sin()));
+        let mut locked = org.clone();
+        AxisLock { yaw: true, roll: true, ..Default::default() }.apply(&mut locked, &params());
+
+        assert!(locked.values().all(|q| q.coords.iter().all(|x| x.is_finite())));
+        for ((ts, a), b) in org.iter().zip(locked.values()) {
+            let pitch = decompose(a).0.to_degrees();
+            if pitch > 90.0 - 1e-4 {
+                // Untouched at the pole
+                assert!(a.angle_to(b) < 1e-5, "{ts}");
+            } else if pitch < 90.0 - GIMBAL_FADE {
+                let (_, yaw, roll) = decompose(b);
+                assert!(yaw.abs() < 1e-6 && roll.abs() < 1e-6, "{ts}");
+            }
+        }
+        assert!(org.range(3_000_000..).all(|(_, q)| decompose(q).0.to_degrees() > 90.0 - 1e-4));
+        // Continuous through the fade
+        let max_step = locked.values().zip(locked.values().skip(1)).map(|(a, b)| a.angle_to(b).to_degrees()).fold(0.0, f64::max);
+        assert!(max_step < 10.0, "{max_step}");
+    }
+}
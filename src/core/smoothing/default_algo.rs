@@ -11,12 +11,18 @@
 // 8. Smooth distance
 // 9. Normalize distance again and change range to 0.5 - 1.0
 // 10. Perform plain 3D smoothing, on the last smoothed quaternions, with varying alpha, interpolated between 1s and 0.1s smoothness based on previously calculated velocity multiplied by the distance
+//
+// The reverse passes make it zero-phase. With the look-ahead window shorter than the clip, each reverse pass only looks that far ahead
+// (the window is split between the passes, because they add up) and the normalizations use the maximum so far,
+// so the output at any time doesn't depend on the input more than the look-ahead later.
+// With the window of the whole clip (or 0), it's the same as without it.
 
 use std::collections::BTreeMap;
 
 use super::*;
 use nalgebra::*;
 use crate::keyframes::*;
+use rayon::iter::{ ParallelIterator, IntoParallelIterator };
 
 const MAX_VELOCITY: f64 = 500.0;
 // Use 120 diagonal FOV as reference. Anything below (long focal length) scales the smoothness down. Anything above (short focal length) scales the smoothness up.
@@ -35,6 +41,7 @@ pub struct DefaultAlgo {
     pub trim_range_only: bool,
     pub max_smoothness: f64,
     pub alpha_0_1s: f64,
+    pub look_ahead: f64, // s, 0 for the whole clip
 }
 
 impl Default for DefaultAlgo {
@@ -47,11 +54,58 @@ impl Default for DefaultAlgo {
         second_pass: true,
         trim_range_only: true,
         max_smoothness: 1.0,
-        alpha_0_1s: 0.1
+        alpha_0_1s: 0.1,
+        look_ahead: 0.0
     } }
 }
 
+/// Backward recursion `state = step(i, state, values[i])` from the end down to each sample, starting with the last value.
+/// With `look_ahead`, the recursion for each sample starts at most that many samples ahead of it instead of at the end of the clip.
+/// Without `step_last`, the starting value is kept as is
+fn reverse_pass<T: Copy + Send + Sync>(values: &[T], look_ahead: Option<usize>, step_last: bool, step: impl Fn(usize, T, T) -> T + Sync) -> Vec<T> {
+    let n = values.len();
+    if n == 0 { return Vec::new(); }
+    match look_ahead {
+        Some(k) if k + 1 < n => (0..n).into_par_iter().map(|i| {
+            let end = (i + k).min(n - 1);
+            let mut state = values[end];
+            if step_last { state = step(end, state, values[end]); }
+            for j in (i..end).rev() {
+                state = step(j, state, values[j]);
+            }
+            state
+        }).collect(),
+        _ => {
+            let mut ret = values.to_vec();
+            let mut state = values[n - 1];
+            for i in (0..n).rev() {
+                if i < n - 1 || step_last { state = step(i, state, values[i]); }
+                ret[i] = state;
+            }
+            ret
+        }
+    }
+}
+
+/// Maximum of each component over the whole clip, or up to each sample when `causal`
+fn max_values(values: impl Iterator<Item = Vector3<f64>>, per_axis: bool, causal: bool) -> Vec<Vector3<f64>> {
+    let components = if per_axis { 3 } else { 1 };
+    // Starting above 0, so the first samples don't divide by 0
+    let mut max = Vector3::from_element(if causal { f64::MIN_POSITIVE } else { 0.0 });
+    let mut ret = values.map(|v| {
+        for c in 0..components {
+            if v[c] > max[c] { max[c] = v[c]; }
+        }
+        max
+    }).collect::<Vec<_>>();
+    if !causal {
+        ret.iter_mut().for_each(|x| *x = max);
+    }
+    ret
+}
+
 impl SmoothingAlgorithm for DefaultAlgo {
+    fn get_id(&self) -> String { "default".to_owned() }
     fn get_name(&self) -> String { "Default".to_owned() }
 
     fn set_parameter(&mut self, name: &str, val: f64) {
@@ -65,6 +119,7 @@ impl SmoothingAlgorithm for DefaultAlgo {
             "trim_range_only"  => self.trim_range_only = val > 0.1,
             "max_smoothness"   => self.max_smoothness = val,
             "alpha_0_1s"       => self.alpha_0_1s = val,
+            "look_ahead"       => self.look_ahead = val,
             _ => log::error!("Invalid parameter name: {}", name)
         }
     }
@@ -79,6 +134,7 @@ impl SmoothingAlgorithm for DefaultAlgo {
             "trim_range_only"  => if self.trim_range_only { 1.0 } else { 0.0 },
             "max_smoothness"   => self.max_smoothness,
             "alpha_0_1s"       => self.alpha_0_1s,
+            "look_ahead"       => self.look_ahead,
             _ => 0.0
         }
     }
@@ -189,6 +245,18 @@ impl SmoothingAlgorithm for DefaultAlgo {
                 "precision": 3,
                 "unit": "s",
                 "keyframe": "SmoothingParamTimeConstant2"
+            },
+            {
+                "name": "look_ahead",
+                "description": "Look-ahead window (0 = whole clip)",
+                "advanced": true,
+                "type": "SliderWithField",
+                "from": 0.0,
+                "to": 10.0,
+                "value": self.look_ahead,
+                "default": 0.0,
+                "precision": 2,
+                "unit": "s"
             }
         ])
     }
@@ -207,10 +275,15 @@ impl SmoothingAlgorithm for DefaultAlgo {
         hasher.write_u64(self.alpha_0_1s.to_bits());
         hasher.write_u8(if self.per_axis { 1 } else { 0 });
         hasher.write_u8(if self.second_pass { 1 } else { 0 });
+        hasher.write_u64(self.look_ahead.to_bits());
         hasher.finish()
     }
 
-    fn smooth(&self, quats: &TimeQuat, duration_ms: f64, compute_params: &ComputeParams) -> TimeQuat { // TODO Result<>?
+    fn get_look_ahead(&self) -> Option<f64> {
+        (self.look_ahead > 0.0).then_some(self.look_ahead)
+    }
+
+    fn smooth(&self, quats: &TimeQuat, duration_ms: f64, params: &ParameterLookup, compute_params: &ComputeParams) -> TimeQuat { // TODO Result<>?
         if quats.is_empty() || duration_ms <= 0.0 { return quats.clone(); }
 
         let sample_rate: f64 = quats.len() as f64 / (duration_ms / 1000.0);
@@ -226,36 +299,38 @@ impl SmoothingAlgorithm for DefaultAlgo {
         let quats = Smoothing::get_trimmed_quats(quats, compute_params.scaled_duration_ms, self.trim_range_only, &compute_params.trim_ranges);
         let quats = quats.as_ref();
 
-        let get_keyframed_param = |typ: &KeyframeType, def: f64, cb: &dyn Fn(f64) -> f64| -> BTreeMap<i64, f64> {
-            let mut ret = BTreeMap::<i64, f64>::new();
-            if keyframes.is_keyframed(typ) || (compute_params.video_speed_affects_smoothing && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed))) {
-                ret = quats.iter().map(|(ts, _)| {
-                    let timestamp_ms = *ts as f64 / 1000.0;
-                    let mut val = keyframes.value_at_gyro_timestamp(typ, timestamp_ms).unwrap_or(def);
-                    if compute_params.video_speed_affects_smoothing {
-                        let vid_speed = keyframes.value_at_gyro_timestamp(&KeyframeType::VideoSpeed, timestamp_ms).unwrap_or(compute_params.video_speed).abs();
-                        if typ == &KeyframeType::SmoothingParamTimeConstant || typ == &KeyframeType::SmoothingParamTimeConstant2 {
-                            val *= 1.0 + ((vid_speed - 1.0) / 2.0);
-                        } else {
-                            val *= vid_speed;
-                        }
+        let speed_affects = compute_params.video_speed_affects_smoothing && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed));
+        let get_keyframed_param = |name: &str, cb: &dyn Fn(f64) -> f64| -> BTreeMap<i64, f64> {
+            if params.is_constant() && !speed_affects { return BTreeMap::new(); }
+            params.per_timestamp(quats, |ts, p| {
+                let mut val = p.get(name);
+                if let Some(vid_speed) = Smoothing::video_speed_at(compute_params, ts) {
+                    if name == "max_smoothness" || name == "alpha_0_1s" {
+                        val *= 1.0 + ((vid_speed - 1.0) / 2.0);
+                    } else {
+                        val *= vid_speed;
                     }
-                    (*ts, cb(val))
-                }).collect();
-            }
-            ret
+                }
+                cb(val)
+            })
         };
 
-        let alpha_smoothness_per_timestamp = get_keyframed_param(&KeyframeType::SmoothingParamTimeConstant, self.max_smoothness, &get_alpha);
-        let alpha_0_1s_per_timestamp = get_keyframed_param(&KeyframeType::SmoothingParamTimeConstant2, self.alpha_0_1s, &get_alpha);
-        let smoothness_per_timestamp = get_keyframed_param(&KeyframeType::SmoothingParamSmoothness, self.smoothness, &noop);
-        let smoothness_pitch_per_timestamp = get_keyframed_param(&KeyframeType::SmoothingParamPitch, self.smoothness_pitch, &noop);
-        let smoothness_yaw_per_timestamp = get_keyframed_param(&KeyframeType::SmoothingParamYaw, self.smoothness_yaw, &noop);
-        let smoothness_roll_per_timestamp = get_keyframed_param(&KeyframeType::SmoothingParamRoll, self.smoothness_roll, &noop);
+        let alpha_smoothness_per_timestamp = get_keyframed_param("max_smoothness", &get_alpha);
+        let alpha_0_1s_per_timestamp = get_keyframed_param("alpha_0_1s", &get_alpha);
+        let smoothness_per_timestamp = get_keyframed_param("smoothness", &noop);
+        let smoothness_pitch_per_timestamp = get_keyframed_param("smoothness_pitch", &noop);
+        let smoothness_yaw_per_timestamp = get_keyframed_param("smoothness_yaw", &noop);
+        let smoothness_roll_per_timestamp = get_keyframed_param("smoothness_roll", &noop);
 
         let alpha_smoothness = get_alpha(self.max_smoothness);
         let alpha_0_1s = get_alpha(self.alpha_0_1s);
 
+        // Look-ahead of each reverse pass in samples, `None` for the whole clip
+        let windowed = self.look_ahead > 0.0 && self.look_ahead < duration_ms / 1000.0;
+        let reverse_passes = if self.second_pass { 4.0 } else { 2.0 };
+        let look_ahead = windowed.then(|| (self.look_ahead / reverse_passes * sample_rate).floor() as usize);
+        let timestamps = quats.keys().copied().collect::<Vec<i64>>();
+
         // Calculate velocity
         let mut velocity = BTreeMap::<i64, Vector3<f64>>::new();
 
@@ -278,16 +353,19 @@ impl SmoothingAlgorithm for DefaultAlgo {
             prev_quat = *quat;
         }
 
-        // Smooth velocity
+        // Smooth velocity, with the time constant at each sample, so the keyframes apply to their part of the clip
         let mut prev_velocity = *velocity.iter().next().unwrap().1; // First velocity
-        for (_timestamp, vel) in velocity.iter_mut().skip(1) {
-            *vel = prev_velocity * (1.0 - alpha_0_1s) + *vel * alpha_0_1s;
-            prev_velocity = *vel;
-        }
-        for (_timestamp, vel) in velocity.iter_mut().rev().skip(1) {
+        for (timestamp, vel) in velocity.iter_mut().skip(1) {
+            let alpha_0_1s = *alpha_0_1s_per_timestamp.get(timestamp).unwrap_or(&alpha_0_1s);
             *vel = prev_velocity * (1.0 - alpha_0_1s) + *vel * alpha_0_1s;
             prev_velocity = *vel;
         }
+        let values = velocity.values().copied().collect::<Vec<_>>();
+        let smoothed_velocity = reverse_pass(&values, look_ahead, false, |i, prev_velocity, vel| {
+            let alpha_0_1s = *alpha_0_1s_per_timestamp.get(&timestamps[i]).unwrap_or(&alpha_0_1s);
+            prev_velocity * (1.0 - alpha_0_1s) + vel * alpha_0_1s
+        });
+        velocity.values_mut().zip(smoothed_velocity).for_each(|(vel, v)| *vel = v);
 
         // Normalize velocity
         for (ts, vel) in velocity.iter_mut() {
@@ -361,8 +439,9 @@ impl SmoothingAlgorithm for DefaultAlgo {
         }).collect();
 
         // Reverse pass
-        let mut q = *smoothed1.iter().next_back().unwrap().1;
-        let smoothed2: TimeQuat = smoothed1.into_iter().rev().map(|(ts, x)| {
+        let values = smoothed1.values().copied().collect::<Vec<_>>();
+        let smoothed2: TimeQuat = timestamps.iter().copied().zip(reverse_pass(&values, look_ahead, true, |i, mut q: Quat64, x| {
+            let ts = timestamps[i];
             let alpha_smoothness = alpha_smoothness_per_timestamp.get(&ts).unwrap_or(&alpha_smoothness);
             let alpha_0_1s = alpha_0_1s_per_timestamp.get(&ts).unwrap_or(&alpha_0_1s);
             let ratio = velocity[&ts];
@@ -383,8 +462,8 @@ impl SmoothingAlgorithm for DefaultAlgo {
                 let val = alpha_smoothness * (1.0 - ratio[0]) + alpha_0_1s * ratio[0];
                 q = q.slerp(&x, val.min(1.0));
             }
-            (ts, q)
-        }).collect();
+            q
+        })).collect();
 
         if !self.second_pass {
             return smoothed2;
@@ -392,7 +471,6 @@ impl SmoothingAlgorithm for DefaultAlgo {
 
         // Calculate distance
         let mut distance = BTreeMap::<i64, Vector3<f64>>::new();
-        let mut max_distance = Vector3::from_element(0.0);
         for (ts, quat) in smoothed2.iter() {
             let dist = quats[ts].inverse() * quat;
             if self.per_axis {
@@ -402,17 +480,14 @@ impl SmoothingAlgorithm for DefaultAlgo {
                     euler.1.abs(),
                     euler.2.abs()
                 ));
-                if euler.0.abs() > max_distance[0] { max_distance[0] = euler.0.abs(); }
-                if euler.1.abs() > max_distance[1] { max_distance[1] = euler.1.abs(); }
-                if euler.2.abs() > max_distance[2] { max_distance[2] = euler.2.abs(); }
             } else {
                 distance.insert(*ts, Vector3::from_element(dist.angle()));
-                if dist.angle() > max_distance[0] { max_distance[0] = dist.angle(); }
             }
         }
+        let max_distance = max_values(distance.values().copied(), self.per_axis, windowed);
 
         // Normalize distance and discard under 0.5
-        for (_ts, dist) in distance.iter_mut() {
+        for (dist, max_distance) in distance.values_mut().zip(max_distance) {
             dist[0] /= max_distance[0];
             if dist[0] < 0.5 { dist[0] = 0.0; }
             if self.per_axis {
@@ -425,27 +500,23 @@ impl SmoothingAlgorithm for DefaultAlgo {
 
         // Smooth distance
         let mut prev_dist = *distance.iter().next().unwrap().1;
-        for (_timestamp, dist) in distance.iter_mut().skip(1) {
-            *dist = prev_dist * (1.0 - alpha_0_1s) + *dist * alpha_0_1s;
-            prev_dist = *dist;
-        }
-        for (_timestamp, dist) in distance.iter_mut().rev().skip(1) {
+        for (timestamp, dist) in distance.iter_mut().skip(1) {
+            let alpha_0_1s = *alpha_0_1s_per_timestamp.get(timestamp).unwrap_or(&alpha_0_1s);
             *dist = prev_dist * (1.0 - alpha_0_1s) + *dist * alpha_0_1s;
             prev_dist = *dist;
         }
+        let values = distance.values().copied().collect::<Vec<_>>();
+        let smoothed_distance = reverse_pass(&values, look_ahead, false, |i, prev_dist, dist| {
+            let alpha_0_1s = *alpha_0_1s_per_timestamp.get(&timestamps[i]).unwrap_or(&alpha_0_1s);
+            prev_dist * (1.0 - alpha_0_1s) + dist * alpha_0_1s
+        });
+        distance.values_mut().zip(smoothed_distance).for_each(|(dist, d)| *dist = d);
 
         // Get max distance
-        max_distance = Vector3::from_element(0.0);
-        for (_ts, dist) in distance.iter_mut() {
-            if dist[0] > max_distance[0] { max_distance[0] = dist[0]; }
-            if self.per_axis {
-                if dist[1] > max_distance[1] { max_distance[1] = dist[1]; }
-                if dist[2] > max_distance[2] { max_distance[2] = dist[2]; }
-            }
-        }
+        let max_distance = max_values(distance.values().copied(), self.per_axis, windowed);
 
         // Normalize distance and change range to 0.5 - 1.0
-        for (_ts, dist) in distance.iter_mut() {
+        for (dist, max_distance) in distance.values_mut().zip(max_distance) {
             dist[0] /= max_distance[0];
             dist[0] = (dist[0] + 1.0) / 2.0;
             if self.per_axis {
@@ -485,8 +556,9 @@ impl SmoothingAlgorithm for DefaultAlgo {
         }).collect();
 
         // Reverse pass
-        let mut q = *smoothed1.iter().next_back().unwrap().1;
-        smoothed1.into_iter().rev().map(|(ts, x)| {
+        let values = smoothed1.values().copied().collect::<Vec<_>>();
+        timestamps.iter().copied().zip(reverse_pass(&values, look_ahead, true, |i, mut q: Quat64, x| {
+            let ts = timestamps[i];
             let alpha_smoothness = alpha_smoothness_per_timestamp.get(&ts).unwrap_or(&alpha_smoothness);
             let alpha_0_1s = alpha_0_1s_per_timestamp.get(&ts).unwrap_or(&alpha_0_1s);
             let vel_ratio = velocity[&ts];
@@ -508,7 +580,81 @@ impl SmoothingAlgorithm for DefaultAlgo {
                 let val = alpha_smoothness * (1.0 - vel_ratio[0] * dist_ratio[0]) + alpha_0_1s * vel_ratio[0] * dist_ratio[0];
                 q = q.slerp(&x, val.min(1.0));
             }
-            (ts, q)
+            q
+        })).collect()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 100.0;
+
+    // Slow pan with handheld shake, 6 seconds
+    fn handheld() -> TimeQuat {
+        (0..(6.0 * SAMPLE_RATE) as i64).map(|i| {
+            let t = i as f64 / SAMPLE_RATE;
+            let shake = 0.5 * (2.0 * std::f64::consts::PI * 7.0 * t).sin() + 0.3 * (2.0 * std::f64::consts::PI * 2.3 * t).cos();
+            ((i as f64 * 1_000_000.0 / SAMPLE_RATE).round() as i64, Quat64::from_euler_angles(0.2 * shake.to_radians(), (5.0 * t + shake).to_radians(), 0.0))
         }).collect()
     }
-}
\ No newline at end of file
+    fn smooth(alg: &DefaultAlgo, quats: &TimeQuat) -> TimeQuat {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        params.trim_ranges.clear();
+        let duration_ms = quats.len() as f64 * 1000.0 / SAMPLE_RATE;
+        alg.smooth(quats, duration_ms, &ParameterLookup::from_keyframes(alg, &params.keyframes), &params)
+    }
+
+    #[test]
+    fn reverse_pass_window() {
+        let values = (0..50).map(|i| ((i * 7) % 11) as f64).collect::<Vec<_>>();
+        let step = |_: usize, prev: f64, x: f64| prev * 0.8 + x * 0.2;
+        for step_last in [false, true] {
+            // Same as the loop over the whole clip
+            let mut expected = values.clone();
+            let mut prev = values[49];
+            for (i, x) in expected.iter_mut().enumerate().rev() {
+                if i < 49 || step_last { *x = step(i, prev, *x); }
+                prev = *x;
+            }
+            assert_eq!(reverse_pass(&values, None, step_last, step), expected);
+            assert_eq!(reverse_pass(&values, Some(48), step_last, step)[1..], expected[1..]);
+
+            // Windowed doesn't depend on values further ahead
+            let windowed = reverse_pass(&values, Some(5), step_last, step);
+            let mut changed = values.clone();
+            changed[30] = 100.0;
+            let windowed_changed = reverse_pass(&changed, Some(5), step_last, step);
+            assert_eq!(windowed[..25], windowed_changed[..25]);
+            assert_ne!(windowed[25], windowed_changed[25]);
+        }
+    }
+
+    #[test]
+    fn full_window() {
+        let quats = handheld();
+        let full = smooth(&DefaultAlgo::default(), &quats);
+        assert_eq!(full.len(), quats.len());
+        assert_eq!(smooth(&DefaultAlgo { look_ahead: 6.0, ..Default::default() }, &quats), full);
+        assert_eq!(smooth(&DefaultAlgo { look_ahead: 100.0, ..Default::default() }, &quats), full);
+    }
+
+    #[test]
+    fn bounded_latency() {
+        let alg = DefaultAlgo { look_ahead: 0.5, ..Default::default() };
+        let quats = handheld();
+        let smoothed = smooth(&alg, &quats);
+
+        // Turned away after 3 s
+        let changed: TimeQuat = quats.iter().map(|(ts, q)| (*ts, if *ts >= 3_000_000 { q * Quat64::from_euler_angles(0.0, 0.0, 0.3) } else { *q })).collect();
+        let smoothed_changed = smooth(&alg, &changed);
+        for (ts, q) in smoothed.range(..2_500_000) {
+            assert_eq!(*q, smoothed_changed[ts], "{ts}");
+        }
+        assert!(smoothed[&2_990_000].angle_to(&smoothed_changed[&2_990_000]) > 0.0);
+
+        // Still smooths the shake
+        let max_step = |quats: &TimeQuat| quats.values().zip(quats.values().skip(1)).map(|(a, b)| a.angle_to(b)).fold(0.0, f64::max);
+        assert!(max_step(&smoothed) < max_step(&quats) * 0.5, "{} {}", max_step(&smoothed), max_step(&quats));
+    }
+}
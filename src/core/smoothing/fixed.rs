@@ -3,7 +3,6 @@
 
 use super::*;
 use nalgebra::*;
-use crate::keyframes::*;
 
 #[derive(Default, Clone)]
 pub struct Fixed {
@@ -13,6 +12,7 @@ pub struct Fixed {
 }
 
 impl SmoothingAlgorithm for Fixed {
+    fn get_id(&self) -> String { "fixed".to_owned() }
     fn get_name(&self) -> String { "Fixed camera".to_owned() }
 
     fn set_parameter(&mut self, name: &str, val: f64) {
@@ -79,11 +79,9 @@ impl SmoothingAlgorithm for Fixed {
         hasher.finish()
     }
 
-    fn smooth(&self, quats: &TimeQuat, duration: f64, compute_params: &ComputeParams) -> TimeQuat {
+    fn smooth(&self, quats: &TimeQuat, duration: f64, params: &ParameterLookup, _compute_params: &ComputeParams) -> TimeQuat {
         if quats.is_empty() || duration <= 0.0 { return quats.clone(); }
 
-        let keyframes = &compute_params.keyframes;
-
         fn quat_for_rpy(roll: f64, pitch: f64, yaw: f64) -> Quat64 {
             const DEG2RAD: f64 = std::f64::consts::PI / 180.0;
             let x_axis = nalgebra::Vector3::<f64>::x_axis();
@@ -105,17 +103,10 @@ impl SmoothingAlgorithm for Fixed {
 
         let fixed_quat = quat_for_rpy(self.roll, self.pitch, self.yaw);
 
-        let is_keyframed = keyframes.is_keyframed(&KeyframeType::SmoothingParamRoll)
-                        || keyframes.is_keyframed(&KeyframeType::SmoothingParamPitch)
-                        || keyframes.is_keyframed(&KeyframeType::SmoothingParamYaw);
-
         quats.iter().map(|x| {
-            if is_keyframed {
-                let timestamp_ms = *x.0 as f64 / 1000.0;
-                let r = keyframes.value_at_gyro_timestamp(&KeyframeType::SmoothingParamRoll, timestamp_ms).unwrap_or(self.roll);
-                let p = keyframes.value_at_gyro_timestamp(&KeyframeType::SmoothingParamPitch, timestamp_ms).unwrap_or(self.pitch);
-                let y = keyframes.value_at_gyro_timestamp(&KeyframeType::SmoothingParamYaw, timestamp_ms).unwrap_or(self.yaw);
-                (*x.0, quat_for_rpy(r, p, y))
+            if !params.is_constant() {
+                let p = params.at(*x.0);
+                (*x.0, quat_for_rpy(p.get("roll"), p.get("pitch"), p.get("yaw")))
             } else {
                 (*x.0, fixed_quat)
             }
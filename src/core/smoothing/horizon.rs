@@ -4,6 +4,10 @@
 use super::*;
 use nalgebra::*;
 use crate::keyframes::*;
+use crate::gyro_source::gravity_reference::{ self, GravitySample };
+
+// The horizon is undefined when looking straight up or down, so the lock fades out within this distance from the pole
+const POLE_FADE: f64 = 5.0; // deg
 
 pub fn lock_horizon_angle(q: &UnitQuaternion<f64>, roll_correction: f64) -> UnitQuaternion<f64> {
     // z axis points in view direction, use as reference
@@ -25,11 +29,23 @@ pub fn lock_horizon_angle(q: &UnitQuaternion<f64>, roll_correction: f64) -> Unit
     initial_quat * rot_yaw * rot_pitch * rot_roll
 }
 
+/// Moves the smoothed orientation toward the locked one by `amount` (0 - 1), along the shortest path between them
+pub fn blend_locked(smoothed: &UnitQuaternion<f64>, locked: &UnitQuaternion<f64>, amount: f64) -> UnitQuaternion<f64> {
+    let amount = amount.clamp(0.0, 1.0);
+    if amount <= 0.0 { return *smoothed; }
+    if amount >= 1.0 { return *locked; }
+    // Half a turn apart (eg. 180° roll correction), every path is as short, so go around the view axis
+    smoothed.try_slerp(locked, amount, 1e-9)
+        .unwrap_or_else(|| smoothed * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), std::f64::consts::PI * amount))
+}
+
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct HorizonLock {
     pub lock_enabled: bool,
     pub horizonlockpercent: f64,
     pub horizonroll: f64,
+    #[serde(default)]
+    pub gravity_reference_strength: f64, // 0 - 1, how much to pull toward the accelerometer gravity between the low-dynamics windows
 }
 
 impl Default for HorizonLock {
@@ -37,6 +53,7 @@ impl Default for HorizonLock {
         lock_enabled: false,
         horizonlockpercent: 100.0,
         horizonroll: 0.0,
+        gravity_reference_strength: 0.0,
     } }
 }
 
@@ -50,10 +67,21 @@ impl HorizonLock {
         let mut hasher = std::collections::hash_map::DefaultHasher::new();
         hasher.write_u64(self.horizonlockpercent.to_bits());
         hasher.write_u64(self.horizonroll.to_bits());
+        hasher.write_u64(self.gravity_reference_strength.to_bits());
         hasher.finish()
     }
 
-    pub fn lock(&self, quats: &mut TimeQuat, org_quats: &TimeQuat, grav: &Option<crate::gyro_source::TimeVec>, use_grav: bool, _int_method: usize, compute_params: &ComputeParams) {
+    /// Roll correction in radians and the lock amount (0 - 1) at the gyro timestamp, with the keyframes applied
+    fn params_at(&self, timestamp_us: i64, compute_params: &ComputeParams) -> (f64, f64) {
+        let keyframes = &compute_params.keyframes;
+        let timestamp_ms = timestamp_us as f64 / 1000.0;
+        let video_rotation = keyframes.value_at_gyro_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(compute_params.video_rotation);
+        let horizonroll = keyframes.value_at_gyro_timestamp(&KeyframeType::LockHorizonRoll, timestamp_ms).unwrap_or(self.horizonroll) + video_rotation;
+        let horizonlockpercent = keyframes.value_at_gyro_timestamp(&KeyframeType::LockHorizonAmount, timestamp_ms).unwrap_or(self.horizonlockpercent);
+        (horizonroll.to_radians(), (horizonlockpercent / 100.0).clamp(0.0, 1.0))
+    }
+
+    pub fn lock(&self, quats: &mut TimeQuat, org_quats: &TimeQuat, grav: &Option<crate::gyro_source::TimeVec>, use_grav: bool, gravity_reference: &[GravitySample], _int_method: usize, compute_params: &ComputeParams) {
         let keyframes = &compute_params.keyframes;
         if self.lock_enabled || keyframes.is_keyframed(&KeyframeType::LockHorizonAmount) {
             if let Some(gvec) = grav {
@@ -70,27 +98,34 @@ impl HorizonLock {
                         let correction = ori.inverse() * smoothed_ori.to_rotation_matrix();
                         let angle_corr = (-correction[(0, 1)]).simd_atan2(correction[(0, 0)]);
 
-                        let timestamp_ms = *ts as f64 / 1000.0;
-                        let video_rotation = keyframes.value_at_gyro_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(compute_params.video_rotation);
-                        let horizonroll = keyframes.value_at_gyro_timestamp(&KeyframeType::LockHorizonRoll, timestamp_ms).unwrap_or(self.horizonroll) + video_rotation;
-                        let horizonlockpercent = keyframes.value_at_gyro_timestamp(&KeyframeType::LockHorizonAmount, timestamp_ms).unwrap_or(self.horizonlockpercent);
+                        let (horizonroll, amount) = self.params_at(*ts, compute_params);
+                        // Gravity along the view axis
+                        let from_pole = gv[0].hypot(gv[1]).simd_atan2(gv[2].abs());
+                        let amount = amount * (from_pole / POLE_FADE.to_radians()).clamp(0.0, 1.0);
 
                         // let gv_corrected = corr.inverse() * correction * corr * gv; // Alternative matrix approach
                         // let locked_ori = smoothed_ori.to_rotation_matrix() * Rotation3::from_axis_angle(&z_axis, gv_corrected[0].simd_atan2(gv_corrected[1]) + horizonroll * std::f64::consts::PI / 180.0);
-                        let locked_ori = smoothed_ori.to_rotation_matrix() * Rotation3::from_axis_angle(&z_axis, -angle_corr + gv[0].simd_atan2(gv[1]) + horizonroll * std::f64::consts::PI / 180.0);
-                        *smoothed_ori = UnitQuaternion::from_rotation_matrix(&locked_ori).slerp(&smoothed_ori, 1.0 - horizonlockpercent / 100.0)
+                        let locked_ori = smoothed_ori.to_rotation_matrix() * Rotation3::from_axis_angle(&z_axis, -angle_corr + gv[0].simd_atan2(gv[1]) + horizonroll);
+                        *smoothed_ori = blend_locked(smoothed_ori, &UnitQuaternion::from_rotation_matrix(&locked_ori), amount);
                     }
                     return;
                 }
             }
 
+            // Drift of the integrated orientation, lock the corrected orientation and then move it back,
+            // so the final rotation (smoothed⁻¹ * org) includes the correction
+            let corrections = gravity_reference::drift_corrections(org_quats, gravity_reference, self.gravity_reference_strength);
+
             for (ts, smoothed_ori) in quats.iter_mut() {
-                let timestamp_ms = *ts as f64 / 1000.0;
-                let video_rotation = keyframes.value_at_gyro_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(compute_params.video_rotation);
-                let horizonroll = keyframes.value_at_gyro_timestamp(&KeyframeType::LockHorizonRoll, timestamp_ms).unwrap_or(self.horizonroll) + video_rotation;
-                let horizonlockpercent = keyframes.value_at_gyro_timestamp(&KeyframeType::LockHorizonAmount, timestamp_ms).unwrap_or(self.horizonlockpercent);
+                let (horizonroll, amount) = self.params_at(*ts, compute_params);
+
+                let correction = gravity_reference::correction_at(&corrections, *ts);
+                let corrected = correction * *smoothed_ori;
+                let pitch = (-(corrected * Vector3::z_axis()).z).clamp(-1.0, 1.0).asin();
+                let amount = amount * ((std::f64::consts::FRAC_PI_2 - pitch.abs()) / POLE_FADE.to_radians()).clamp(0.0, 1.0);
 
-                *smoothed_ori = lock_horizon_angle(smoothed_ori, horizonroll * std::f64::consts::PI / 180.0).slerp(&smoothed_ori, 1.0 - horizonlockpercent / 100.0);
+                let locked = correction.inverse() * lock_horizon_angle(&corrected, horizonroll);
+                *smoothed_ori = blend_locked(smoothed_ori, &locked, amount);
             }
         }
     }
@@ -122,3 +157,68 @@ impl HorizonLock {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smoothing::axis_lock::{ compose, decompose };
+
+    fn run(lock: &HorizonLock, quats: &TimeQuat, params: &ComputeParams) -> TimeQuat {
+        let mut locked = quats.clone();
+        lock.lock(&mut locked, quats, &None, false, &[], 0, params);
+        locked
+    }
+
+    #[test]
+    fn keyframed_amount() {
+        let quats: TimeQuat = (0..=40).map(|i| (i * 100_000, compose(10f64.to_radians(), 20f64.to_radians(), 30f64.to_radians()))).collect();
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        // Released at the start, fully locked at 4 s
+        params.keyframes.set(&KeyframeType::LockHorizonAmount, 0, 0.0);
+        params.keyframes.set(&KeyframeType::LockHorizonAmount, 4_000_000, 100.0);
+        let lock = HorizonLock { lock_enabled: false, horizonroll: 5.0, ..Default::default() };
+        let locked = run(&lock, &quats, &params);
+
+        let target = compose(10f64.to_radians(), 20f64.to_radians(), 5f64.to_radians());
+        for ((ts, org), q) in quats.iter().zip(locked.values()) {
+            let amount = params.keyframes.value_at_gyro_timestamp(&KeyframeType::LockHorizonAmount, *ts as f64 / 1000.0).unwrap() / 100.0;
+            // On the rotation between the smoothed and the locked orientation
+            let total = org.angle_to(&target);
+            assert!((org.angle_to(q) - amount * total).abs() < 1e-9, "{ts}");
+            assert!((q.angle_to(&target) - (1.0 - amount) * total).abs() < 1e-9, "{ts}");
+            let (pitch, yaw, _) = decompose(q);
+            assert!((pitch.to_degrees() - 10.0).abs() < 1e-6 && (yaw.to_degrees() - 20.0).abs() < 1e-6, "{ts}");
+        }
+        assert!((decompose(locked.values().next_back().unwrap()).2.to_degrees() - 5.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pole() {
+        // Diving until looking straight down
+        let quats: TimeQuat = (0..=100).map(|i| (i * 10_000, compose((-80.0 - i as f64 * 0.1).to_radians(), 20f64.to_radians(), 15f64.to_radians()))).collect();
+        let params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        let locked = run(&HorizonLock { lock_enabled: true, ..Default::default() }, &quats, &params);
+
+        assert!(locked.values().all(|q| q.coords.iter().all(|x| x.is_finite())));
+        for ((ts, org), q) in quats.iter().zip(locked.values()) {
+            let pitch = decompose(org).0.to_degrees();
+            if pitch < -90.0 + 1e-4 {
+                assert!(org.angle_to(q) < 1e-5, "{ts}");
+            } else if pitch > -90.0 + POLE_FADE {
+                assert!(decompose(q).2.abs() < 1e-6, "{ts}");
+            }
+        }
+        let max_step = locked.values().zip(locked.values().skip(1)).map(|(a, b)| a.angle_to(b).to_degrees()).fold(0.0, f64::max);
+        assert!(max_step < 5.0, "{max_step}");
+    }
+
+    #[test]
+    fn half_turn() {
+        let q = compose(0.1, 0.2, 0.0);
+        let flipped = q * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), std::f64::consts::PI);
+        let half = blend_locked(&q, &flipped, 0.5);
+        assert!((q.angle_to(&half) - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+        assert!((half.angle_to(&flipped) - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+        assert_eq!(blend_locked(&q, &flipped, 2.0), flipped);
+    }
+}
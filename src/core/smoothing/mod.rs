@@ -2,10 +2,14 @@
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
 pub mod horizon;
+pub mod axis_lock;
 pub mod none;
 pub mod plain;
 pub mod fixed;
 pub mod default_algo;
+pub mod per_axis;
+pub mod adaptive;
+pub mod tripod;
 
 pub use nalgebra::*;
 use super::gyro_source::{ TimeQuat, Quat64 };
@@ -15,9 +19,17 @@ use std::borrow::Cow;
 
 use std::hash::Hasher;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 use crate::ComputeParams;
+use crate::keyframes::{ KeyframeManager, KeyframeType };
 
-pub trait SmoothingAlgorithm: DynClone {
+/// Algorithms are cloned with the `Smoothing` for every computation and `smooth` is called on the clones from the worker threads,
+/// possibly at the same time. Implementations must be `Send + Sync`, shouldn't keep any state between the `smooth` calls
+/// and should give the same result for the same parameters, because the result is cached by `get_checksum`
+pub trait SmoothingAlgorithm: DynClone + Send + Sync {
+    /// Stable identifier stored in the project files. Unlike the name, it must never change
+    fn get_id(&self) -> String;
     fn get_name(&self) -> String;
 
     fn get_parameters_json(&self) -> serde_json::Value;
@@ -27,21 +39,160 @@ pub trait SmoothingAlgorithm: DynClone {
 
     fn get_checksum(&self) -> u64;
 
-    fn smooth(&self, quats: &TimeQuat, duration: f64, _compute_params: &ComputeParams) -> TimeQuat;
+    /// How far after each sample (in seconds) the input can still change the smoothed result. `None` when it's the whole clip
+    fn get_look_ahead(&self) -> Option<f64> { None }
+
+    /// Parsed from `get_parameters_json` by default
+    fn get_parameter_descriptors(&self) -> Vec<ParameterDescriptor> {
+        ParameterDescriptor::from_json(&self.get_parameters_json())
+    }
+
+    /// `params` gives the values of the numeric parameters at each gyro timestamp, with the keyframes applied
+    fn smooth(&self, quats: &TimeQuat, duration: f64, params: &ParameterLookup, _compute_params: &ComputeParams) -> TimeQuat;
 }
 clone_trait_object!(SmoothingAlgorithm);
 
+/// Description of one parameter, so the frontends can build the controls without knowing the algorithm
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct ParameterDescriptor {
+    pub name: String,
+    pub description: String,
+    #[serde(rename = "type")]
+    pub typ: String, // SliderWithField, Slider, NumberField, CheckBox or QML
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub default: f64,
+    pub unit: String,
+    pub keyframe: Option<String>,
+    pub advanced: bool,
+}
+impl ParameterDescriptor {
+    pub fn from_json(json: &serde_json::Value) -> Vec<Self> {
+        let serde_json::Value::Array(arr) = json else { return Vec::new(); };
+        let as_f64 = |v: &serde_json::Value| v.as_f64().or_else(|| v.as_bool().map(|x| if x { 1.0 } else { 0.0 }));
+        arr.iter().filter_map(|v| {
+            let str = |key: &str| v.get(key).and_then(|x| x.as_str()).map(str::to_owned);
+            Some(Self {
+                name: str("name")?,
+                description: str("description").unwrap_or_default(),
+                typ: str("type").unwrap_or_default(),
+                from: v.get("from").and_then(as_f64),
+                to: v.get("to").and_then(as_f64),
+                default: v.get("default").and_then(as_f64).unwrap_or_default(),
+                unit: str("unit").unwrap_or_default(),
+                keyframe: str("keyframe"),
+                advanced: v.get("advanced").and_then(|x| x.as_bool()).unwrap_or_default(),
+            })
+        }).collect()
+    }
+}
+
+/// Values of the numeric parameters of an algorithm at one timestamp
+#[derive(Default, Clone, Debug)]
+pub struct ParameterSet(HashMap<String, f64>);
+impl ParameterSet {
+    pub fn get(&self, name: &str) -> f64 { self.0.get(name).copied().unwrap_or(0.0) }
+    pub fn set(&mut self, name: &str, value: f64) { self.0.insert(name.to_owned(), value); }
+}
+
+/// Timestamp (us) -> parameter set
+pub struct ParameterLookup<'a> {
+    lookup: Box<dyn Fn(i64) -> ParameterSet + 'a>,
+    constant: bool,
+}
+impl<'a> ParameterLookup<'a> {
+    pub fn new(constant: bool, lookup: impl Fn(i64) -> ParameterSet + 'a) -> Self {
+        Self { lookup: Box::new(lookup), constant }
+    }
+    pub fn constant(params: ParameterSet) -> Self {
+        Self::new(true, move |_| params.clone())
+    }
+    /// Current values of the algorithm, and the keyframes of the parameters which have the `keyframe` type in `get_parameters_json`
+    pub fn from_keyframes(alg: &dyn SmoothingAlgorithm, keyframes: &'a KeyframeManager) -> Self {
+        let mut values = ParameterSet::default();
+        let mut keyframed = Vec::new();
+        if let serde_json::Value::Array(arr) = alg.get_parameters_json() {
+            for v in arr {
+                let Some(name) = v.get("name").and_then(|x| x.as_str()) else { continue; };
+                values.set(name, alg.get_parameter(name));
+                if let Some(typ) = v.get("keyframe").and_then(|x| x.as_str()).and_then(|x| KeyframeType::from_str(x).ok()) {
+                    if keyframes.is_keyframed(&typ) {
+                        keyframed.push((name.to_owned(), typ));
+                    }
+                }
+            }
+        }
+        if keyframed.is_empty() {
+            return Self::constant(values);
+        }
+        Self::new(false, move |timestamp_us| {
+            let mut ret = values.clone();
+            for (name, typ) in &keyframed {
+                if let Some(v) = keyframes.value_at_gyro_timestamp(typ, timestamp_us as f64 / 1000.0) {
+                    ret.set(name, v);
+                }
+            }
+            ret
+        })
+    }
+
+    pub fn at(&self, timestamp_us: i64) -> ParameterSet { (self.lookup)(timestamp_us) }
+    pub fn is_constant(&self) -> bool { self.constant }
+
+    /// `cb` evaluated at every timestamp of `quats`. Parameters are looked up only once if they don't change
+    pub fn per_timestamp(&self, quats: &TimeQuat, cb: impl Fn(i64, &ParameterSet) -> f64) -> BTreeMap<i64, f64> {
+        if self.constant {
+            let params = self.at(0);
+            quats.keys().map(|ts| (*ts, cb(*ts, &params))).collect()
+        } else {
+            quats.keys().map(|ts| (*ts, cb(*ts, &self.at(*ts)))).collect()
+        }
+    }
+}
+
+// Algorithms registered at runtime. Every new `Smoothing` gets them after the built-in ones, in the order of registration
+static REGISTERED: parking_lot::RwLock<Vec<Box<dyn SmoothingAlgorithm>>> = parking_lot::RwLock::new(Vec::new());
+static REGISTER_BUILTIN: std::sync::Once = std::sync::Once::new();
+
+fn register_globally(alg: &dyn SmoothingAlgorithm) {
+    let id = alg.get_id();
+    let mut registered = REGISTERED.write();
+    if let Some(existing) = registered.iter_mut().find(|x| x.get_id() == id) {
+        *existing = dyn_clone::clone_box(alg);
+    } else {
+        registered.push(dyn_clone::clone_box(alg));
+    }
+}
+
 struct Algs(Vec<Box<dyn SmoothingAlgorithm>>);
 impl Default for Algs {
     fn default() -> Self {
-        Self(vec![
+        REGISTER_BUILTIN.call_once(|| {
+            register_globally(&self::tripod::Tripod::default());
+        });
+        let mut algs: Vec<Box<dyn SmoothingAlgorithm>> = vec![
             Box::new(self::none::None::default()),
             Box::new(self::default_algo::DefaultAlgo::default()),
             Box::new(self::plain::Plain::default()),
-            Box::new(self::fixed::Fixed::default())
-        ])
+            Box::new(self::fixed::Fixed::default()),
+            Box::new(self::per_axis::PerAxis::default()),
+            Box::new(self::adaptive::Adaptive::default()),
+        ];
+        algs.extend(REGISTERED.read().iter().cloned());
+        Self(algs)
+    }
+}
+impl Algs {
+    fn insert(&mut self, alg: Box<dyn SmoothingAlgorithm>) -> usize {
+        let id = alg.get_id();
+        if let Some(i) = self.0.iter().position(|x| x.get_id() == id) {
+            self.0[i] = alg;
+            i
+        } else {
+            self.0.push(alg);
+            self.0.len() - 1
+        }
     }
-
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -50,7 +201,8 @@ pub struct Smoothing {
     algs: Algs,
     current_id: usize,
 
-    pub horizon_lock: horizon::HorizonLock
+    pub horizon_lock: horizon::HorizonLock,
+    pub axis_lock: axis_lock::AxisLock,
 }
 unsafe impl Send for Smoothing { }
 unsafe impl Sync for Smoothing { }
@@ -63,6 +215,7 @@ impl Default for Smoothing {
             current_id: 1,
 
             horizon_lock: horizon::HorizonLock::default(),
+            axis_lock: axis_lock::AxisLock::default(),
         }
     }
 }
@@ -72,6 +225,7 @@ impl Clone for Smoothing {
         let mut ret = Self::default();
         ret.current_id = self.current_id;
         ret.horizon_lock = self.horizon_lock.clone();
+        ret.axis_lock = self.axis_lock.clone();
 
         let parameters = self.current().get_parameters_json();
         if let serde_json::Value::Array(ref arr) = parameters {
@@ -96,6 +250,20 @@ impl Smoothing {
         self.current_id = id.min(self.algs.0.len() - 1);
     }
 
+    /// Adds the algorithm to this manager and to every one created later, including the clones used for the computation.
+    /// An algorithm with the same id is replaced. Returns the index of the algorithm
+    pub fn register_algorithm(&mut self, alg: Box<dyn SmoothingAlgorithm>) -> usize {
+        register_globally(alg.as_ref());
+        self.algs.insert(alg)
+    }
+
+    pub fn index_of(&self, id: &str) -> Option<usize> {
+        self.algs.0.iter().position(|x| x.get_id() == id)
+    }
+    pub fn index_of_name(&self, name: &str) -> Option<usize> {
+        self.algs.0.iter().position(|x| x.get_name() == name)
+    }
+
     pub fn current(&self) -> &Box<dyn SmoothingAlgorithm> {
         &self.algs.0[self.current_id]
     }
@@ -109,12 +277,23 @@ impl Smoothing {
         hasher.write_usize(self.current_id);
         hasher.write_u64(self.algs.0[self.current_id].get_checksum());
         hasher.write_u64(self.horizon_lock.get_checksum());
+        hasher.write_u64(self.axis_lock.get_checksum());
         hasher.finish()
     }
 
     pub fn get_names(&self) -> Vec<String> {
         self.algs.0.iter().map(|x| x.get_name()).collect()
     }
+    pub fn get_ids(&self) -> Vec<String> {
+        self.algs.0.iter().map(|x| x.get_id()).collect()
+    }
+    pub fn get_algorithms_json(&self) -> serde_json::Value {
+        serde_json::Value::Array(self.algs.0.iter().map(|x| serde_json::json!({
+            "id": x.get_id(),
+            "name": x.get_name(),
+            "parameters": x.get_parameter_descriptors()
+        })).collect())
+    }
 
     pub fn get_trimmed_quats<'a>(quats: &'a TimeQuat, duration_ms: f64, trim_range_only: bool, trim_ranges: &[(f64, f64)]) -> Cow<'a, TimeQuat> {
         if trim_range_only && !trim_ranges.is_empty() {
@@ -157,6 +336,13 @@ impl Smoothing {
         }
     }
 
+    /// Video speed at the timestamp if it should change the smoothness
+    pub fn video_speed_at(compute_params: &ComputeParams, timestamp_us: i64) -> Option<f64> {
+        if !compute_params.video_speed_affects_smoothing { return None; }
+        let speed = compute_params.keyframes.value_at_gyro_timestamp(&KeyframeType::VideoSpeed, timestamp_us as f64 / 1000.0).unwrap_or(compute_params.video_speed).abs();
+        Some(speed)
+    }
+
     pub fn get_max_angles(quats: &TimeQuat, smoothed_quats: &TimeQuat, params: &ComputeParams) -> (f64, f64, f64) { // -> (pitch, yaw, roll) in deg
         let ranges = params.trim_ranges.iter().map(|x| ((x.0 * params.scaled_duration_ms * 1000.0) as i64, (x.1 * params.scaled_duration_ms * 1000.0) as i64)).collect::<Vec<_>>();
         let identity_quat = Quat64::identity();
@@ -180,3 +366,81 @@ impl Smoothing {
         (max_pitch * RAD2DEG, max_yaw * RAD2DEG, max_roll * RAD2DEG)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframed_parameters() {
+        let alg = plain::Plain::default();
+        let mut keyframes = KeyframeManager::new();
+        let constant = ParameterLookup::from_keyframes(&alg, &keyframes);
+        assert!(constant.is_constant());
+        assert_eq!(constant.at(5_000_000).get("time_constant"), alg.time_constant);
+        assert_eq!(constant.at(0).get("trim_range_only"), 1.0);
+
+        // Action section with low smoothness between the calm ones
+        keyframes.set(&KeyframeType::SmoothingParamTimeConstant, 0, 0.8);
+        keyframes.set(&KeyframeType::SmoothingParamTimeConstant, 2_000_000, 0.2);
+        keyframes.set(&KeyframeType::SmoothingParamTimeConstant, 4_000_000, 0.8);
+        let lookup = ParameterLookup::from_keyframes(&alg, &keyframes);
+        assert!(!lookup.is_constant());
+        assert_eq!(lookup.at(2_000_000).get("time_constant"), 0.2);
+        assert!((lookup.at(1_000_000).get("time_constant") - 0.5).abs() < 1e-9);
+        assert_eq!(lookup.at(10_000_000).get("time_constant"), 0.8);
+        assert_eq!(lookup.at(1_000_000).get("trim_range_only"), 1.0);
+
+        let quats: TimeQuat = (0..5).map(|i| (i * 1_000_000, Quat64::identity())).collect();
+        let values = lookup.per_timestamp(&quats, |_, p| p.get("time_constant"));
+        assert_eq!(values.values().map(|x| (x * 10.0).round() / 10.0).collect::<Vec<_>>(), vec![0.8, 0.5, 0.2, 0.5, 0.8]);
+    }
+
+    #[derive(Clone, Default)]
+    struct TestPlugin { strength: f64 }
+    impl SmoothingAlgorithm for TestPlugin {
+        fn get_id(&self) -> String { "test_plugin".to_owned() }
+        fn get_name(&self) -> String { "Test plugin".to_owned() }
+        fn get_parameters_json(&self) -> serde_json::Value {
+            serde_json::json!([{ "name": "strength", "description": "Strength", "type": "SliderWithField", "from": 0.0, "to": 2.0, "value": self.strength, "default": 1.0, "unit": "s" }])
+        }
+        fn get_status_json(&self) -> serde_json::Value { serde_json::json!([]) }
+        fn set_parameter(&mut self, name: &str, val: f64) { if name == "strength" { self.strength = val; } }
+        fn get_parameter(&self, name: &str) -> f64 { if name == "strength" { self.strength } else { 0.0 } }
+        fn get_checksum(&self) -> u64 { self.strength.to_bits() }
+        fn smooth(&self, quats: &TimeQuat, _duration: f64, _params: &ParameterLookup, _compute_params: &ComputeParams) -> TimeQuat { quats.clone() }
+    }
+
+    #[test]
+    fn registered_algorithms() {
+        let mut smoothing = Smoothing::default();
+        let tripod = smoothing.index_of("tripod").unwrap();
+        assert_eq!(smoothing.index_of_name("Tripod"), Some(tripod));
+
+        let idx = smoothing.register_algorithm(Box::new(TestPlugin { strength: 0.5 }));
+        assert_eq!(smoothing.index_of("test_plugin"), Some(idx));
+        // Registering again replaces it
+        assert_eq!(smoothing.register_algorithm(Box::new(TestPlugin::default())), idx);
+        assert_eq!(smoothing.get_ids().iter().filter(|x| *x == "test_plugin").count(), 1);
+
+        // Available in the new managers and the clones, with the current parameters
+        assert_eq!(Smoothing::default().index_of("test_plugin"), Some(idx));
+        smoothing.set_current(idx);
+        smoothing.current_mut().set_parameter("strength", 1.5);
+        let cloned = smoothing.clone();
+        assert_eq!(cloned.current().get_id(), "test_plugin");
+        assert_eq!(cloned.current().get_parameter("strength"), 1.5);
+        assert_eq!(smoothing.index_of("missing_plugin"), None);
+
+        let descriptors = cloned.current().get_parameter_descriptors();
+        assert_eq!(descriptors.len(), 1);
+        assert_eq!((descriptors[0].name.as_str(), descriptors[0].typ.as_str()), ("strength", "SliderWithField"));
+        assert_eq!((descriptors[0].from, descriptors[0].to, descriptors[0].default), (Some(0.0), Some(2.0), 1.0));
+
+        // CheckBox defaults are booleans
+        let descriptors = tripod::Tripod::default().get_parameter_descriptors();
+        let hard_lock = descriptors.iter().find(|x| x.name == "hard_lock").unwrap();
+        assert_eq!((hard_lock.typ.as_str(), hard_lock.default), ("CheckBox", 0.0));
+        assert!(descriptors.iter().find(|x| x.name == "trim_range_only").unwrap().advanced);
+    }
+}
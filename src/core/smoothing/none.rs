@@ -7,6 +7,7 @@ use super::*;
 pub struct None;
 
 impl SmoothingAlgorithm for None {
+    fn get_id(&self) -> String { "none".to_owned() }
     fn get_name(&self) -> String { "No smoothing".to_owned() }
 
     fn get_parameters_json(&self) -> serde_json::Value { serde_json::json!([]) }
@@ -15,5 +16,5 @@ impl SmoothingAlgorithm for None {
     fn get_parameter(&self, _name: &str) -> f64 { 0.0 }
 
     fn get_checksum(&self) -> u64 { 0 }
-    fn smooth(&self, quats: &TimeQuat, _duration: f64, _: &ComputeParams) -> TimeQuat { quats.clone() }
+    fn smooth(&self, quats: &TimeQuat, _duration: f64, _: &ParameterLookup, _: &ComputeParams) -> TimeQuat { quats.clone() }
 }
@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Smooths yaw, pitch and roll separately, each with its own time constant, with an option to lock the roll to the horizon.
+// The angles are in the same frame as the horizon lock uses, so yaw is the pan, pitch is the tilt and roll is around the view direction.
+// Pitch close to ±90° (camera looking straight up or down) makes the yaw and roll undefined, so it's not meant for such shots
+
+use super::*;
+
+use crate::keyframes::*;
+use crate::gyro_source::gravity_reference;
+use std::collections::BTreeMap;
+use std::f64::consts::{ PI, FRAC_PI_2 };
+
+#[derive(Clone)]
+pub struct PerAxis {
+    pub yaw_time_constant: f64,
+    pub pitch_time_constant: f64,
+    pub roll_time_constant: f64,
+    pub lock_roll: bool,
+    pub use_gravity: bool,
+    pub trim_range_only: bool,
+}
+
+impl Default for PerAxis {
+    fn default() -> Self { Self {
+        yaw_time_constant: 1.0,
+        pitch_time_constant: 1.0,
+        roll_time_constant: 1.0,
+        lock_roll: true,
+        use_gravity: false,
+        trim_range_only: true,
+    } }
+}
+
+// Same as `horizon::lock_horizon_angle`: the orientation is `initial * yaw(y) * pitch(x) * roll(z)`
+fn initial_quat() -> Quat64 {
+    UnitQuaternion::from_axis_angle(&Vector3::y_axis(), FRAC_PI_2) * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), FRAC_PI_2)
+}
+
+/// Returns (yaw, pitch, roll) in radians
+pub fn to_angles(q: &Quat64) -> (f64, f64, f64) {
+    let m = (initial_quat().inverse() * q).to_rotation_matrix();
+    (m[(0, 2)].atan2(m[(2, 2)]), (-m[(1, 2)]).clamp(-1.0, 1.0).asin(), m[(1, 0)].atan2(m[(1, 1)]))
+}
+
+pub fn from_angles(yaw: f64, pitch: f64, roll: f64) -> Quat64 {
+    initial_quat()
+        * UnitQuaternion::from_axis_angle(&Vector3::y_axis(), yaw)
+        * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), pitch)
+        * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), roll)
+}
+
+// Adds multiples of 2π, so there's no jump when the angle crosses ±180°
+fn unwrap_angle(prev: f64, angle: f64) -> f64 {
+    prev + (angle - prev + PI).rem_euclid(2.0 * PI) - PI
+}
+
+impl SmoothingAlgorithm for PerAxis {
+    fn get_id(&self) -> String { "per_axis".to_owned() }
+    fn get_name(&self) -> String { "Per axis with horizon lock".to_owned() }
+
+    fn set_parameter(&mut self, name: &str, val: f64) {
+        match name {
+            "yaw_time_constant" => self.yaw_time_constant = val,
+            "pitch_time_constant" => self.pitch_time_constant = val,
+            "roll_time_constant" => self.roll_time_constant = val,
+            "lock_roll" => self.lock_roll = val > 0.1,
+            "use_gravity" => self.use_gravity = val > 0.1,
+            "trim_range_only" => self.trim_range_only = val > 0.1,
+            _ => log::error!("Invalid parameter name: {}", name)
+        }
+    }
+    fn get_parameter(&self, name: &str) -> f64 {
+        match name {
+            "yaw_time_constant" => self.yaw_time_constant,
+            "pitch_time_constant" => self.pitch_time_constant,
+            "roll_time_constant" => self.roll_time_constant,
+            "lock_roll" => if self.lock_roll { 1.0 } else { 0.0 },
+            "use_gravity" => if self.use_gravity { 1.0 } else { 0.0 },
+            "trim_range_only" => if self.trim_range_only { 1.0 } else { 0.0 },
+            _ => 0.0
+        }
+    }
+
+    fn get_parameters_json(&self) -> serde_json::Value {
+        serde_json::json!([
+            {
+                "name": "yaw_time_constant",
+                "description": "Yaw smoothness",
+                "type": "SliderWithField",
+                "from": 0.01,
+                "to": 10.0,
+                "value": self.yaw_time_constant,
+                "default": 1.0,
+                "unit": "s",
+                "keyframe": "SmoothingParamYaw"
+            },
+            {
+                "name": "pitch_time_constant",
+                "description": "Pitch smoothness",
+                "type": "SliderWithField",
+                "from": 0.01,
+                "to": 10.0,
+                "value": self.pitch_time_constant,
+                "default": 1.0,
+                "unit": "s",
+                "keyframe": "SmoothingParamPitch"
+            },
+            {
+                "name": "roll_time_constant",
+                "description": "Roll smoothness",
+                "type": "SliderWithField",
+                "from": 0.01,
+                "to": 10.0,
+                "value": self.roll_time_constant,
+                "default": 1.0,
+                "unit": "s",
+                "keyframe": "SmoothingParamRoll"
+            },
+            {
+                "name": "lock_roll",
+                "description": "Lock roll to the horizon",
+                "type": "CheckBox",
+                "default": self.lock_roll,
+                "value": if self.lock_roll { 1.0 } else { 0.0 },
+                "custom_qml": "Connections { function onCheckedChanged() {
+                    root.getParamElement('roll_time_constant-label').visible = !root.getParamElement('lock_roll').checked;
+                }}"
+            },
+            {
+                "name": "use_gravity",
+                "description": "Use the accelerometer for the horizon",
+                "advanced": true,
+                "type": "CheckBox",
+                "default": self.use_gravity,
+                "value": if self.use_gravity { 1.0 } else { 0.0 },
+            },
+            {
+                "name": "trim_range_only",
+                "description": "Only within trim range",
+                "advanced": true,
+                "type": "CheckBox",
+                "default": self.trim_range_only,
+                "value": if self.trim_range_only { 1.0 } else { 0.0 },
+            },
+        ])
+    }
+    fn get_status_json(&self) -> serde_json::Value {
+        serde_json::json!([])
+    }
+
+    fn get_checksum(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(self.yaw_time_constant.to_bits());
+        hasher.write_u64(self.pitch_time_constant.to_bits());
+        hasher.write_u64(self.roll_time_constant.to_bits());
+        hasher.write_u8(self.lock_roll as u8);
+        hasher.write_u8(self.use_gravity as u8);
+        hasher.write_u8(self.trim_range_only as u8);
+        hasher.finish()
+    }
+
+    fn smooth(&self, quats: &TimeQuat, duration_ms: f64, params: &ParameterLookup, compute_params: &ComputeParams) -> TimeQuat {
+        if quats.is_empty() || duration_ms <= 0.0 { return quats.clone(); }
+
+        let keyframes = &compute_params.keyframes;
+        let sample_rate: f64 = quats.len() as f64 / (duration_ms / 1000.0);
+        let get_alpha = |time_constant: f64| {
+            if time_constant > 0.0 { 1.0 - (-(1.0 / sample_rate) / time_constant).exp() } else { 1.0 }
+        };
+
+        let quats = Smoothing::get_trimmed_quats(quats, compute_params.scaled_duration_ms, self.trim_range_only, &compute_params.trim_ranges);
+        let quats = quats.as_ref();
+
+        // Drift of the integrated orientation from the accelerometer, same as in the horizon lock
+        let corrections = if self.lock_roll && self.use_gravity {
+            let gyro = compute_params.gyro.read_recursive();
+            gravity_reference::drift_corrections(quats, &gyro.gravity_reference, 1.0)
+        } else {
+            TimeQuat::new()
+        };
+
+        // Continuous angles for each sample
+        let mut prev = (0.0, 0.0, 0.0);
+        let mut angles: BTreeMap<i64, [f64; 3]> = quats.iter().enumerate().map(|(i, (ts, q))| {
+            let (yaw, pitch, roll) = to_angles(&(gravity_reference::correction_at(&corrections, *ts) * q));
+            if i > 0 {
+                prev = (unwrap_angle(prev.0, yaw), pitch, unwrap_angle(prev.2, roll));
+            } else {
+                prev = (yaw, pitch, roll);
+            }
+            (*ts, [prev.0, prev.1, prev.2])
+        }).collect();
+
+        let axes = [
+            ("yaw_time_constant",   self.yaw_time_constant),
+            ("pitch_time_constant", self.pitch_time_constant),
+            ("roll_time_constant",  self.roll_time_constant),
+        ];
+        let speed_keyframed = compute_params.video_speed_affects_smoothing && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed));
+        for (axis, (name, time_constant)) in axes.iter().enumerate() {
+            if axis == 2 && self.lock_roll { continue; }
+
+            let alpha = get_alpha(*time_constant);
+            let mut alpha_per_timestamp = BTreeMap::<i64, f64>::new();
+            if !params.is_constant() || speed_keyframed {
+                alpha_per_timestamp = params.per_timestamp(quats, |ts, p| get_alpha(p.get(name) * Smoothing::video_speed_at(compute_params, ts).unwrap_or(1.0)));
+            }
+
+            let mut prev = angles.values().next().unwrap()[axis];
+            for (ts, a) in angles.iter_mut() {
+                let alpha = *alpha_per_timestamp.get(ts).unwrap_or(&alpha);
+                a[axis] = prev * (1.0 - alpha) + a[axis] * alpha;
+                prev = a[axis];
+            }
+            // Reverse pass
+            for (ts, a) in angles.iter_mut().rev() {
+                let alpha = *alpha_per_timestamp.get(ts).unwrap_or(&alpha);
+                a[axis] = prev * (1.0 - alpha) + a[axis] * alpha;
+                prev = a[axis];
+            }
+        }
+
+        angles.into_iter().map(|(ts, [yaw, pitch, roll])| {
+            let roll = if self.lock_roll {
+                let timestamp_ms = ts as f64 / 1000.0;
+                let video_rotation = keyframes.value_at_gyro_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(compute_params.video_rotation);
+                video_rotation * PI / 180.0
+            } else {
+                roll
+            };
+            (ts, gravity_reference::correction_at(&corrections, ts).inverse() * from_angles(yaw, pitch, roll))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotating_camera(rate_dps: f64, roll: f64) -> TimeQuat {
+        // 10 seconds at 100 Hz, starting at 170° so the yaw crosses ±180° right away
+        (0..1000).map(|i| {
+            let t = i as f64 / 100.0;
+            (i * 10000, from_angles((170.0 + rate_dps * t).to_radians(), (5.0 * t.sin()).to_radians(), roll.to_radians()))
+        }).collect()
+    }
+
+    #[test]
+    fn angles_roundtrip() {
+        for (yaw, pitch, roll) in [(0.3, -0.2, 0.1), (3.0, 0.5, -2.5), (-3.1, -1.2, 3.1)] {
+            let (y, p, r) = to_angles(&from_angles(yaw, pitch, roll));
+            assert!((y - yaw).abs() < 1e-9 && (p - pitch).abs() < 1e-9 && (r - roll).abs() < 1e-9, "{y} {p} {r}");
+        }
+        assert!((unwrap_angle(3.1, -3.1) - (2.0 * PI - 3.1)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn yaw_wraparound() {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        params.trim_ranges.clear();
+        params.video_rotation = 0.0;
+
+        let quats = rotating_camera(90.0, 10.0);
+        let alg = PerAxis { lock_roll: false, yaw_time_constant: 0.5, ..Default::default() };
+        let smoothed = alg.smooth(&quats, 10000.0, &ParameterLookup::from_keyframes(&alg, &params.keyframes), &params);
+        assert_eq!(smoothed.len(), quats.len());
+
+        // Constant rotation is kept as is, without a jump when the yaw wraps
+        let step = (90.0f64 / 100.0).to_radians();
+        for ((_, a), (_, b)) in smoothed.iter().zip(smoothed.iter().skip(1)).skip(100).take(800) {
+            assert!(a.angle_to(b) < step * 1.2, "{} at {:?}", a.angle_to(b).to_degrees(), to_angles(a));
+        }
+        for ((_, a), (_, b)) in smoothed.iter().zip(quats.iter()).skip(300).take(400) {
+            assert!(a.angle_to(b) < 2.0f64.to_radians(), "{}", a.angle_to(b).to_degrees());
+        }
+
+        // Locked roll and a keyframed yaw
+        params.keyframes.set(&KeyframeType::SmoothingParamYaw, 0, 0.5);
+        params.keyframes.set(&KeyframeType::SmoothingParamYaw, 10_000_000, 2.0);
+        let alg = PerAxis { lock_roll: true, ..Default::default() };
+        let smoothed = alg.smooth(&quats, 10000.0, &ParameterLookup::from_keyframes(&alg, &params.keyframes), &params);
+        for (_, q) in smoothed.iter() {
+            assert!(to_angles(q).2.abs() < 1e-6);
+        }
+        for ((_, a), (_, b)) in smoothed.iter().zip(smoothed.iter().skip(1)).skip(300).take(400) {
+            assert!(a.angle_to(b) < step * 1.2);
+        }
+    }
+}
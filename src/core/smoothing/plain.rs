@@ -20,6 +20,7 @@ impl Default for Plain {
 }
 
 impl SmoothingAlgorithm for Plain {
+    fn get_id(&self) -> String { "plain".to_owned() }
     fn get_name(&self) -> String { "Plain 3D".to_owned() }
 
     fn set_parameter(&mut self, name: &str, val: f64) {
@@ -70,7 +71,7 @@ impl SmoothingAlgorithm for Plain {
         hasher.finish()
     }
 
-    fn smooth(&self, quats: &TimeQuat, duration_ms: f64, compute_params: &ComputeParams) -> TimeQuat { // TODO Result<>?
+    fn smooth(&self, quats: &TimeQuat, duration_ms: f64, params: &ParameterLookup, compute_params: &ComputeParams) -> TimeQuat { // TODO Result<>?
         if quats.is_empty() || duration_ms <= 0.0 { return quats.clone(); }
 
         let keyframes = &compute_params.keyframes;
@@ -89,19 +90,11 @@ impl SmoothingAlgorithm for Plain {
         let quats = quats.as_ref();
 
         let mut alpha_per_timestamp = BTreeMap::<i64, f64>::new();
-        if keyframes.is_keyframed(&KeyframeType::SmoothingParamTimeConstant) || (compute_params.video_speed_affects_smoothing && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed))) {
-            alpha_per_timestamp = quats.iter().map(|(ts, _)| {
-                let timestamp_ms = *ts as f64 / 1000.0;
-
-                let mut val = keyframes.value_at_gyro_timestamp(&KeyframeType::SmoothingParamTimeConstant, timestamp_ms).unwrap_or(self.time_constant);
-
-                if compute_params.video_speed_affects_smoothing {
-                    let vid_speed = keyframes.value_at_gyro_timestamp(&KeyframeType::VideoSpeed, timestamp_ms).unwrap_or(compute_params.video_speed).abs();
-                    val *= vid_speed;
-                }
-
-                (*ts, get_alpha(val))
-            }).collect();
+        if !params.is_constant() || (compute_params.video_speed_affects_smoothing && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed))) {
+            alpha_per_timestamp = params.per_timestamp(quats, |ts, p| {
+                let val = p.get("time_constant") * Smoothing::video_speed_at(compute_params, ts).unwrap_or(1.0);
+                get_alpha(val)
+            });
         }
 
         let mut scalers: BTreeMap<i64, f64> = quats.iter().map(|x| {
@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Locked camera which slowly follows deliberate re-aims.
+// The lock starts at the camera orientation at the lock start, then converges toward the long-term average orientation
+// with the drift time constant, so all short-term motion is removed but a re-aim over several seconds is followed.
+// With the hard lock, the re-centering happens only between the first and the last drift keyframe
+
+use super::*;
+
+use crate::keyframes::*;
+
+// Orientation at the lock start is the average of this long part of the clip
+const INITIAL_LOCK_US: i64 = 500_000;
+
+#[derive(Clone)]
+pub struct Tripod {
+    pub drift_time_constant: f64,
+    pub lock_start: f64, // s
+    pub hard_lock: bool,
+    pub trim_range_only: bool,
+}
+
+impl Default for Tripod {
+    fn default() -> Self { Self {
+        drift_time_constant: 10.0,
+        lock_start: 0.0,
+        hard_lock: false,
+        trim_range_only: true,
+    } }
+}
+
+impl SmoothingAlgorithm for Tripod {
+    fn get_id(&self) -> String { "tripod".to_owned() }
+    fn get_name(&self) -> String { "Tripod".to_owned() }
+
+    fn set_parameter(&mut self, name: &str, val: f64) {
+        match name {
+            "drift_time_constant" => self.drift_time_constant = val,
+            "lock_start" => self.lock_start = val,
+            "hard_lock" => self.hard_lock = val > 0.1,
+            "trim_range_only" => self.trim_range_only = val > 0.1,
+            _ => log::error!("Invalid parameter name: {}", name)
+        }
+    }
+    fn get_parameter(&self, name: &str) -> f64 {
+        match name {
+            "drift_time_constant" => self.drift_time_constant,
+            "lock_start" => self.lock_start,
+            "hard_lock" => if self.hard_lock { 1.0 } else { 0.0 },
+            "trim_range_only" => if self.trim_range_only { 1.0 } else { 0.0 },
+            _ => 0.0
+        }
+    }
+
+    fn get_parameters_json(&self) -> serde_json::Value {
+        serde_json::json!([
+            {
+                "name": "drift_time_constant",
+                "description": "Re-centering time",
+                "type": "SliderWithField",
+                "from": 0.5,
+                "to": 60.0,
+                "value": self.drift_time_constant,
+                "default": 10.0,
+                "precision": 1,
+                "unit": "s",
+                "keyframe": "SmoothingParamDriftTimeConstant"
+            },
+            {
+                "name": "lock_start",
+                "description": "Lock start",
+                "type": "SliderWithField",
+                "from": 0.0,
+                "to": 10.0,
+                "value": self.lock_start,
+                "default": 0.0,
+                "precision": 2,
+                "unit": "s"
+            },
+            {
+                "name": "hard_lock",
+                "description": "Re-center only between keyframes",
+                "type": "CheckBox",
+                "default": self.hard_lock,
+                "value": if self.hard_lock { 1.0 } else { 0.0 },
+            },
+            {
+                "name": "trim_range_only",
+                "description": "Only within trim range",
+                "advanced": true,
+                "type": "CheckBox",
+                "default": self.trim_range_only,
+                "value": if self.trim_range_only { 1.0 } else { 0.0 },
+            },
+        ])
+    }
+    fn get_status_json(&self) -> serde_json::Value {
+        serde_json::json!([])
+    }
+
+    fn get_checksum(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(self.drift_time_constant.to_bits());
+        hasher.write_u64(self.lock_start.to_bits());
+        hasher.write_u8(self.hard_lock as u8);
+        hasher.write_u8(self.trim_range_only as u8);
+        hasher.finish()
+    }
+
+    fn smooth(&self, quats: &TimeQuat, duration_ms: f64, params: &ParameterLookup, compute_params: &ComputeParams) -> TimeQuat {
+        if quats.is_empty() || duration_ms <= 0.0 { return quats.clone(); }
+
+        let keyframes = &compute_params.keyframes;
+        let sample_rate: f64 = quats.len() as f64 / (duration_ms / 1000.0);
+        let get_alpha = |time_constant: f64| {
+            if time_constant > 0.0 { 1.0 - (-(1.0 / sample_rate) / time_constant).exp() } else { 1.0 }
+        };
+
+        let quats = Smoothing::get_trimmed_quats(quats, compute_params.scaled_duration_ms, self.trim_range_only, &compute_params.trim_ranges);
+        let quats = quats.as_ref();
+
+        // Everything before the lock start is ignored, the camera may be pointing somewhere else there
+        let last_ts = *quats.keys().next_back().unwrap();
+        let start_ts = quats.range((self.lock_start * 1_000_000.0).round() as i64..).next().map(|x| *x.0).unwrap_or(last_ts);
+        let locked_part: TimeQuat = quats.range(start_ts..).map(|(ts, q)| (*ts, *q)).collect();
+
+        let alpha_per_timestamp = params.per_timestamp(&locked_part, |ts, p| {
+            get_alpha(p.get("drift_time_constant") * Smoothing::video_speed_at(compute_params, ts).unwrap_or(1.0))
+        });
+
+        // Long-term average orientation, zero phase
+        let mut q = *locked_part.values().next().unwrap();
+        let average1: TimeQuat = locked_part.iter().map(|(ts, x)| {
+            q = q.slerp(x, alpha_per_timestamp[ts]);
+            (*ts, q)
+        }).collect();
+        let mut q = *average1.values().next_back().unwrap();
+        let average: TimeQuat = average1.iter().rev().map(|(ts, x)| {
+            q = q.slerp(x, alpha_per_timestamp[ts]);
+            (*ts, q)
+        }).collect();
+
+        let mut lock = *locked_part.values().next().unwrap();
+        for (n, q) in locked_part.range(..start_ts + INITIAL_LOCK_US).map(|x| x.1).enumerate() {
+            lock = lock.slerp(q, 1.0 / (n + 1) as f64);
+        }
+        let initial_lock = lock;
+
+        // The lock converges toward the average
+        let mut ret: TimeQuat = average.iter().map(|(ts, average)| {
+            if !self.hard_lock || keyframes.is_within_keyframes(&KeyframeType::SmoothingParamDriftTimeConstant, *ts as f64 / 1000.0) {
+                lock = lock.slerp(average, alpha_per_timestamp[ts]);
+            }
+            (*ts, lock)
+        }).collect();
+        for ts in quats.range(..start_ts).map(|x| *x.0) {
+            ret.insert(ts, initial_lock);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 100.0;
+
+    // Pointing away for the first second, then handheld at 0°, re-aimed to 30° between 10 and 15 s, 30 s total
+    fn handheld_with_reaim() -> TimeQuat {
+        (0..(30.0 * SAMPLE_RATE) as i64).map(|i| {
+            let t = i as f64 / SAMPLE_RATE;
+            let aim = if t < 1.0 { 90.0 } else { 30.0 * ((t - 10.0) / 5.0).clamp(0.0, 1.0) };
+            let shake = 0.5 * (2.0 * std::f64::consts::PI * 7.0 * t).sin() + 0.3 * (2.0 * std::f64::consts::PI * 2.3 * t).cos();
+            ((i as f64 * 1_000_000.0 / SAMPLE_RATE).round() as i64, Quat64::from_axis_angle(&Vector3::z_axis(), (aim + shake).to_radians()))
+        }).collect()
+    }
+    fn yaw(q: &Quat64) -> f64 { q.scaled_axis().z.to_degrees() }
+
+    #[test]
+    fn follows_reaim() {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        params.trim_ranges.clear();
+        let quats = handheld_with_reaim();
+        let duration_ms = quats.len() as f64 * 1000.0 / SAMPLE_RATE;
+        let smooth = |alg: &Tripod, params: &ComputeParams| alg.smooth(&quats, duration_ms, &ParameterLookup::from_keyframes(alg, &params.keyframes), params);
+
+        let smoothed = smooth(&Tripod { drift_time_constant: 3.0, lock_start: 1.0, ..Default::default() }, &params);
+        assert_eq!(smoothed.len(), quats.len());
+        // Locked to the orientation after the lock start, also before it
+        assert!(smoothed.range(..2_000_000).all(|(_, q)| yaw(q).abs() < 0.5), "{}", yaw(&smoothed[&0]));
+        // No short-term motion
+        for ((_, a), (_, b)) in smoothed.iter().zip(smoothed.iter().skip(1)) {
+            assert!(a.angle_to(b).to_degrees() * SAMPLE_RATE < 10.0);
+        }
+        // Followed the re-aim
+        assert!((yaw(smoothed.values().next_back().unwrap()) - 30.0).abs() < 2.0, "{}", yaw(smoothed.values().next_back().unwrap()));
+
+        // Hard lock without keyframes never moves
+        let hard = Tripod { drift_time_constant: 3.0, lock_start: 1.0, hard_lock: true, ..Default::default() };
+        let smoothed = smooth(&hard, &params);
+        assert!(smoothed.values().all(|q| yaw(q).abs() < 0.5));
+
+        // Re-centering only between the keyframes
+        params.keyframes.set(&KeyframeType::SmoothingParamDriftTimeConstant, 9_000_000, 2.0);
+        params.keyframes.set(&KeyframeType::SmoothingParamDriftTimeConstant, 22_000_000, 2.0);
+        let smoothed = smooth(&hard, &params);
+        assert!(smoothed.range(..9_000_000).all(|(_, q)| yaw(q).abs() < 0.5));
+        let after = smoothed.range(22_000_000..).map(|(_, q)| yaw(q)).collect::<Vec<_>>();
+        assert!((after[0] - 30.0).abs() < 2.0, "{}", after[0]);
+        assert!(after.iter().all(|x| (x - after[0]).abs() < 1e-6));
+    }
+}
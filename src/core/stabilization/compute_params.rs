@@ -5,18 +5,40 @@ use super::StabilizationManager;
 use super::distortion_models::DistortionModel;
 use crate::stabilization_params::ReadoutDirection;
 use crate::GyroSource;
-use crate::keyframes::KeyframeManager;
+use crate::keyframes::{ KeyframeManager, KeyframeType };
 use crate::lens_profile::LensProfile;
 use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering::SeqCst };
+use std::collections::BTreeMap;
 use parking_lot::RwLock;
 
+/// Cancellation token of a background recompute. It's cancelled as soon as the manager's compute generation moves past it,
+/// ie. when any parameter changes or another recompute is started. The default one is never cancelled
+#[derive(Default, Clone)]
+pub struct ComputeToken {
+    generation: u64,
+    current: Option<Arc<AtomicU64>>,
+}
+impl ComputeToken {
+    pub fn new(current: Arc<AtomicU64>, generation: u64) -> Self {
+        Self { generation, current: Some(current) }
+    }
+    pub fn generation(&self) -> u64 { self.generation }
+    pub fn is_cancelled(&self) -> bool {
+        self.current.as_ref().is_some_and(|x| x.load(SeqCst) != self.generation)
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct ComputeParams {
     pub gyro: Arc<RwLock<GyroSource>>,
     pub fovs: Vec<f64>,
     pub minimal_fovs: Vec<f64>,
+    pub zoom_center_scale: Vec<f64>,
     pub keyframes: KeyframeManager,
     pub lens: LensProfile,
+    pub focal_lengths: BTreeMap<i64, f64>, // Focal length in mm where it changes, by timestamp in us. For clips zooming during the shot and lenses with `focal_length_calibrations`
+    pub crops: BTreeMap<i64, f64>, // Per-frame sensor crop by timestamp in us, for clips with digital zoom during the shot
     pub camera_diagonal_fovs: Vec<f64>,
 
     pub frame_count: usize,
@@ -38,24 +60,36 @@ pub struct ComputeParams {
     pub background_mode: crate::stabilization_params::BackgroundMode,
     pub background_margin: f64,
     pub background_margin_feather: f64,
-    pub frame_readout_time: f64,
+    pub frame_readout_time: f64, // With the overrides applied
     pub frame_readout_direction: ReadoutDirection,
+    pub rolling_shutter_strength: Option<f64>, // 1 when not set
+    pub residual_corrections: crate::synchronization::ResidualCorrections,
+    pub residual_correction_strength: Option<f64>, // 1 when not set
     pub trim_ranges: Vec<(f64, f64)>,
     pub scaled_fps: f64,
     pub scaled_duration_ms: f64,
     pub adaptive_zoom_window: f64,
+    pub adaptive_zoom_look_ahead: f64,
+    pub static_zoom_percentile: f64,
     pub adaptive_zoom_center_offset: (f64, f64),
     pub adaptive_zoom_method: i32,
     pub additional_rotation: (f64, f64, f64),
     pub additional_translation: (f64, f64, f64),
+    pub horizon_compensation: (f64, f64),
+    pub horizon_compensation_fov: f64,
     pub framebuffer_inverted: bool,
     pub suppress_rotation: bool,
     pub fov_algorithm_margin: f32,
     pub smoothing_fov_limit_per_frame: Vec<f64>,
     pub max_zoom: Option<f64>,
     pub max_zoom_iterations: usize,
+    pub limit_to_zoom_budget: bool,
+    pub smoothing_look_ahead: Option<f64>,
 
     pub zooming_debug_points: bool,
+    pub deterministic: bool,
+
+    pub cancel_token: ComputeToken,
 
     pub distortion_model: DistortionModel,
     pub digital_lens: Option<DistortionModel>,
@@ -65,21 +99,38 @@ impl ComputeParams {
     pub fn from_manager(mgr: &StabilizationManager) -> Self {
         let params = mgr.params.read();
 
-        let lens = mgr.lens.read().clone();
+        let mut lens = mgr.lens.read().clone();
+        if lens.flip_horizontal != params.lens_flip.0 || lens.flip_vertical != params.lens_flip.1 {
+            lens = lens.flipped(lens.flip_horizontal != params.lens_flip.0, lens.flip_vertical != params.lens_flip.1);
+        }
 
         let distortion_model = DistortionModel::from_name(lens.distortion_model.as_deref().unwrap_or("opencv_fisheye"));
         let digital_lens = lens.digital_lens.as_ref().map(|x| DistortionModel::from_name(&x));
 
         let digital_lens_params = lens.digital_lens_params.clone();
 
+        let (focal_lengths, crops) = {
+            let gyro = mgr.gyro.read();
+            let md = gyro.file_metadata.read();
+            // Empty when constant, then the lens profile is used as is
+            let focal_lengths = if md.focal_lengths.len() > 1 || !lens.focal_length_calibrations.is_empty() { md.focal_lengths.clone() } else { BTreeMap::new() };
+            let crops: BTreeMap<i64, f64> = md.lens_params.keys().filter_map(|ts| Some((*ts, md.crop_at(*ts)?))).collect();
+            let first_crop = crops.values().next().copied().unwrap_or_default();
+            (focal_lengths, if crops.values().any(|x| (x - first_crop).abs() > 1e-6) { crops } else { BTreeMap::new() })
+        };
+
         Self {
             gyro: mgr.gyro.clone(),
             lens,
+            focal_lengths,
+            crops,
             camera_diagonal_fovs: Vec::new(),
 
             smoothing_fov_limit_per_frame: Vec::new(),
             max_zoom: params.max_zoom.clone(),
             max_zoom_iterations: params.max_zoom_iterations,
+            limit_to_zoom_budget: params.limit_to_zoom_budget,
+            smoothing_look_ahead: mgr.smoothing.read().current().get_look_ahead(),
 
             frame_count: params.frame_count,
             fov_scale: params.fov,
@@ -87,6 +138,7 @@ impl ComputeParams {
             show_safe_area: params.show_safe_area,
             fovs: params.fovs.clone(),
             minimal_fovs: params.minimal_fovs.clone(),
+            zoom_center_scale: params.zoom_center_scale.clone(),
             width: params.size.0.max(1),
             height: params.size.1.max(1),
             output_width: params.output_size.0.max(1),
@@ -99,15 +151,22 @@ impl ComputeParams {
             lens_correction_amount: params.lens_correction_amount,
             light_refraction_coefficient: params.light_refraction_coefficient,
             framebuffer_inverted: params.framebuffer_inverted,
-            frame_readout_time: params.frame_readout_time,
-            frame_readout_direction: params.frame_readout_direction,
+            frame_readout_time: params.effective_readout().0,
+            frame_readout_direction: params.effective_readout().1,
+            rolling_shutter_strength: Some(params.rolling_shutter_strength),
+            residual_corrections: params.residual_corrections.clone(),
+            residual_correction_strength: Some(params.residual_correction_strength),
             trim_ranges: params.trim_ranges.clone(),
             scaled_fps: params.get_scaled_fps(),
             scaled_duration_ms: params.get_scaled_duration_ms(),
             adaptive_zoom_window: params.adaptive_zoom_window,
+            adaptive_zoom_look_ahead: params.adaptive_zoom_look_ahead,
+            static_zoom_percentile: params.static_zoom_percentile,
             adaptive_zoom_center_offset: params.adaptive_zoom_center_offset,
             additional_rotation: params.additional_rotation,
             additional_translation: params.additional_translation,
+            horizon_compensation: params.horizon_compensation,
+            horizon_compensation_fov: params.horizon_compensation_fov,
             adaptive_zoom_method: params.adaptive_zoom_method,
             video_speed: params.video_speed,
             video_speed_affects_smoothing: params.video_speed_affects_smoothing,
@@ -122,12 +181,53 @@ impl ComputeParams {
 
             keyframes: mgr.keyframes.read().clone(),
 
-            zooming_debug_points: false
+            zooming_debug_points: false,
+            deterministic: params.deterministic,
+
+            cancel_token: ComputeToken::default()
         }
     }
 
+    /// Multiplier of the frame readout time at the video timestamp, with the keyframes applied. The kernels and the sync both use it
+    pub fn rolling_shutter_strength_at(&self, timestamp_ms: f64) -> f64 {
+        let strength = self.keyframes.value_at_video_timestamp(&KeyframeType::RollingShutterStrength, timestamp_ms).or(self.rolling_shutter_strength).unwrap_or(1.0);
+        strength.clamp(0.0, 2.0)
+    }
+    /// Frame readout time in ms at the video timestamp
+    pub fn frame_readout_time_at(&self, timestamp_ms: f64) -> f64 {
+        self.frame_readout_time.abs() * self.rolling_shutter_strength_at(timestamp_ms)
+    }
+    /// Rotation of the stabilized view which removes the residual wobble at the video timestamp, with the strength keyframes applied.
+    /// `None` outside of the analyzed ranges or when the correction is turned off
+    pub fn residual_correction_at(&self, timestamp_ms: f64) -> Option<nalgebra::Matrix3<f64>> {
+        if self.residual_corrections.is_empty() { return None; }
+        // Turning it off sets the strength to 0, the keyframes only vary it while it's on
+        if self.residual_correction_strength.is_some_and(|x| x <= 0.0) { return None; }
+        let strength = self.keyframes.value_at_video_timestamp(&KeyframeType::ResidualCorrectionStrength, timestamp_ms).or(self.residual_correction_strength).unwrap_or(1.0).clamp(0.0, 2.0);
+        if strength <= 0.0 { return None; }
+
+        // Stored per analyzed frame, interpolated in between but not across the gaps between the ranges
+        let ts = (timestamp_ms * 1000.0).round() as i64;
+        let max_gap = (1.5 * 1_000_000.0 / self.scaled_fps.max(1.0)).round() as i64;
+        let (a_ts, a) = self.residual_corrections.range(..=ts).next_back()?;
+        let v = if *a_ts == ts {
+            *a
+        } else {
+            let (b_ts, b) = self.residual_corrections.range(ts..).next()?;
+            if b_ts - a_ts > max_gap { return None; }
+            let f = (ts - a_ts) as f64 / (b_ts - a_ts) as f64;
+            [a[0] + (b[0] - a[0]) * f, a[1] + (b[1] - a[1]) * f, a[2] + (b[2] - a[2]) * f]
+        };
+        Some(*nalgebra::Rotation3::from_scaled_axis(nalgebra::Vector3::from(v) * strength).matrix())
+    }
+    /// Fraction of the frame readout elapsed when the pixel at `point` was read, in a frame of `size`
+    pub fn readout_fraction(&self, point: (f64, f64), size: (usize, usize)) -> f64 {
+        let fraction = if self.frame_readout_direction.is_horizontal() { point.0 / size.0.max(1) as f64 } else { point.1 / size.1.max(1) as f64 };
+        if self.frame_readout_direction.is_inverted() { 1.0 - fraction } else { fraction }
+    }
+
     pub fn calculate_camera_fovs(&mut self) {
-        let frame_count = if self.gyro.read().file_metadata.read().lens_params.len() > 1 {
+        let frame_count = if self.gyro.read().file_metadata.read().lens_params.len() > 1 || self.focal_lengths.len() > 1 {
             self.frame_count
         } else {
             1 // FOV is constant (ie. lens is fixed focal length)
@@ -161,6 +261,7 @@ impl std::fmt::Debug for ComputeParams {
          .field("gyro.acc_rotation", &gyro.imu_transforms.acc_rotation_angles)
          .field("gyro.duration_ms", &gyro.duration_ms)
          .field("gyro.imu_lpf", &gyro.imu_transforms.imu_lpf)
+         .field("gyro.acc_lpf", &gyro.imu_transforms.acc_lpf)
          .field("gyro.imu_mf", &gyro.imu_transforms.imu_mf)
          .field("gyro.gyro_bias", &gyro.imu_transforms.gyro_bias)
          .field("gyro.integration_method", &gyro.integration_method)
@@ -182,15 +283,25 @@ impl std::fmt::Debug for ComputeParams {
          .field("background_margin_feather", &self.background_margin_feather)
          .field("frame_readout_time",        &self.frame_readout_time)
          .field("frame_readout_direction",   &self.frame_readout_direction)
+         .field("rolling_shutter_strength",  &self.rolling_shutter_strength)
+         .field("residual_corrections",      &self.residual_corrections.len())
+         .field("residual_correction_strength", &self.residual_correction_strength)
          .field("trim_ranges",               &self.trim_ranges)
          .field("scaled_fps",                &self.scaled_fps)
          .field("adaptive_zoom_window",      &self.adaptive_zoom_window)
+         .field("adaptive_zoom_look_ahead",  &self.adaptive_zoom_look_ahead)
+         .field("static_zoom_percentile",    &self.static_zoom_percentile)
          .field("adaptive_zoom_center_offset", &self.adaptive_zoom_center_offset)
          .field("additional_rotation",       &self.additional_rotation)
          .field("additional_translation",    &self.additional_translation)
+         .field("horizon_compensation",      &self.horizon_compensation)
+         .field("horizon_compensation_fov",  &self.horizon_compensation_fov)
          .field("adaptive_zoom_method",      &self.adaptive_zoom_method)
+         .field("limit_to_zoom_budget",      &self.limit_to_zoom_budget)
+         .field("smoothing_look_ahead",      &self.smoothing_look_ahead)
          .field("framebuffer_inverted",      &self.framebuffer_inverted)
          .field("zooming_debug_points",      &self.zooming_debug_points)
+         .field("deterministic",             &self.deterministic)
          .field("distortion_model",          &self.distortion_model.id())
          .field("digital_lens",              &self.digital_lens.as_ref().map(|x| x.id()).unwrap_or("None"))
          .finish()
@@ -328,6 +328,10 @@ impl Stabilization {
 
         fn sample_input_at<const I: i32, T: PixelType>(uv: Vector2<f32>, jac: &Vector4<f32>, input: &[u8], params: &KernelParams, bg: &Vector4<f32>, _drawing: &[u8]) -> Vector4<f32> {
             let mut sum = Vector4::from_element(0.0);
+            // With a transparent background, the part of the footprint inside of the source gives the color and the alpha is its coverage
+            let mut covered = Vector4::from_element(0.0);
+            let mut coverage = 0.0;
+            let mut total_weight = 1.0;
             if I > 8 {
                 // find how many pixels we need around that pixel in each direction
                 let trans_size = affine_bbox(jac);
@@ -357,6 +361,8 @@ impl Stabilization {
                             let px1: &T = bytemuck::from_bytes(&input[src_index as usize + (params.bytes_per_pixel * in_x) as usize..src_index as usize + (params.bytes_per_pixel * (in_x + 1)) as usize]);
                             let src_px = PixelType::to_float(*px1);
                             // draw_pixel(&mut src_px, sx + xp, sy + yp, true, params.width, params, drawing);
+                            covered += k * src_px;
+                            coverage += k;
                             src_px
                         } else {
                             *bg
@@ -367,6 +373,7 @@ impl Stabilization {
                     src_index += params.stride;
                 }
                 sum /= sum_div;
+                total_weight = sum_div;
             } else {
                 const INTER_BITS: usize = 5;
                 const INTER_TAB_SIZE: usize = 1 << INTER_BITS;
@@ -391,11 +398,15 @@ impl Stabilization {
                 for yp in 0..I {
                     if sy + yp >= params.source_rect[1] && sy + yp < params.source_rect[1] + params.source_rect[3] {
                         let mut xsum = Vector4::<f32>::from_element(0.0);
+                        let mut xcovered = Vector4::<f32>::from_element(0.0);
+                        let mut xcoverage = 0.0;
                         for xp in 0..I {
                             let pixel = if sx + xp >= params.source_rect[0] && sx + xp < params.source_rect[0] + params.source_rect[2] {
                                 let px1: &T = bytemuck::from_bytes(&input[src_index as usize + (params.bytes_per_pixel * xp) as usize..src_index as usize + (params.bytes_per_pixel * (xp + 1)) as usize]);
                                 let src_px = PixelType::to_float(*px1);
                                 // draw_pixel(&mut src_px, sx + xp, sy + yp, true, params.width, params, drawing);
+                                xcovered += src_px * coeffs_x[xp as usize];
+                                xcoverage += coeffs_x[xp as usize];
                                 src_px
                             } else {
                                 *bg
@@ -404,12 +415,21 @@ impl Stabilization {
                         }
 
                         sum += xsum * coeffs_y[yp as usize];
+                        covered += xcovered * coeffs_y[yp as usize];
+                        coverage += xcoverage * coeffs_y[yp as usize];
                     } else {
                         sum += bg * coeffs_y[yp as usize];
                     }
                     src_index += params.stride as isize;
                 }
             }
+            if (params.flags & 4096) == 4096 { // Transparent background
+                let mut straight = if coverage > 0.001 * total_weight { covered / coverage } else { *bg };
+                if params.alpha_channel >= 0 {
+                    straight[params.alpha_channel as usize] = sum[params.alpha_channel as usize].max(0.0);
+                }
+                sum = straight;
+            }
             Vector4::new(
                 sum.x.min(params.pixel_value_limit),
                 sum.y.min(params.pixel_value_limit),
@@ -632,8 +652,12 @@ impl Stabilization {
 }
 
 pub fn undistort_points_with_rolling_shutter(distorted: &[(f32, f32)], timestamp_ms: f64, frame: Option<usize>, params: &ComputeParams, lens_correction_amount: f64, use_fovs: bool) -> Vec<(f32, f32)> {
+    undistort_points_with_correction(distorted, timestamp_ms, frame, params, lens_correction_amount, use_fovs, None)
+}
+/// `correction` is the rotation from the smoothed to the raw orientation at the frame center, `None` uses the current smoothed quaternions
+pub fn undistort_points_with_correction(distorted: &[(f32, f32)], timestamp_ms: f64, frame: Option<usize>, params: &ComputeParams, lens_correction_amount: f64, use_fovs: bool, correction: Option<&crate::gyro_source::Quat64>) -> Vec<(f32, f32)> {
     if distorted.is_empty() { return Vec::new(); }
-    let (camera_matrix, distortion_coeffs, _p, rotations, is, mesh) = FrameTransform::at_timestamp_for_points(params, distorted, timestamp_ms, frame, use_fovs);
+    let (camera_matrix, distortion_coeffs, _p, rotations, is, mesh) = FrameTransform::at_timestamp_for_points_with_correction(params, distorted, timestamp_ms, frame, use_fovs, correction);
 
     undistort_points(distorted, camera_matrix, &distortion_coeffs, rotations[0], Some(Matrix3::identity()), Some(rotations), params, lens_correction_amount, timestamp_ms, is, mesh)
 }
@@ -801,3 +825,55 @@ pub fn undistort_points(distorted: &[(f32, f32)], camera_matrix: Matrix3<f64>, d
         }
     }).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::BufferDescription;
+    use crate::stabilization::{ KernelParamsFlags, RGBA8 };
+
+    #[test]
+    fn transparent_background_edge() {
+        const SIZE: usize = 8;
+        let mut input = [200u8, 100, 50, 255].repeat(SIZE * SIZE);
+
+        let mut params = KernelParams::default();
+        (params.width, params.height, params.output_width, params.output_height) = (SIZE as i32, SIZE as i32, SIZE as i32, SIZE as i32);
+        (params.stride, params.output_stride) = ((SIZE * 4) as i32, (SIZE * 4) as i32);
+        (params.bytes_per_pixel, params.pix_element_count) = (4, 4);
+        params.matrix_count = 1;
+        params.interpolation = 2;
+        params.f = [1.0, 1.0];
+        params.lens_correction_amount = 1.0;
+        params.light_refraction_coefficient = 1.0;
+        params.source_rect = [0, 0, SIZE as i32, SIZE as i32];
+        params.output_rect = [0, 0, SIZE as i32, SIZE as i32];
+        params.max_pixel_value = 255.0;
+        params.pixel_value_limit = 255.0;
+        params.background = [0.2, 0.4, 0.6, 0.0];
+        params.flags = KernelParamsFlags::TRANSPARENT_BACKGROUND.bits();
+        params.alpha_channel = 3;
+
+        // Shifted by 2.5 px, so the edge of the source is in the middle of the bilinear footprint of the 6th column
+        let mut matrix = [0.0f32; 14];
+        (matrix[0], matrix[4], matrix[8], matrix[2]) = (1.0, 1.0, 1.0, 2.5);
+
+        let mut output = vec![0u8; SIZE * SIZE * 4];
+        let mut buffers = Buffers {
+            input:  BufferDescription { size: (SIZE, SIZE, SIZE * 4), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+            output: BufferDescription { size: (SIZE, SIZE, SIZE * 4), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let model = DistortionModel::from_name("opencv_standard");
+        assert!(Stabilization::undistort_image_cpu::<2, RGBA8>(&mut buffers, &params, &model, None, &[matrix], &[], &[]));
+        drop(buffers);
+
+        let row = &output[3 * SIZE * 4..4 * SIZE * 4];
+        let pixel = |x: usize| &row[x * 4..x * 4 + 4];
+        assert_eq!(pixel(4), [200, 100, 50, 255]);
+        // Partially covered: fractional alpha and the color of the source only, without the background
+        assert_eq!(&pixel(5)[..3], [200, 100, 50]);
+        assert!(pixel(5)[3] > 0 && pixel(5)[3] < 255, "{:?}", pixel(5));
+        assert_eq!(pixel(6)[3], 0);
+        assert_eq!(pixel(7)[3], 0);
+    }
+}
@@ -0,0 +1,1086 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// The `.glsl` files of the distortion models are the only source of their GPU functions. The Qt RHI shaders include them
+// as they are (see qt_gpu/compiled/compile_shaders.sh), and the OpenCL and WGSL versions are translated from them here,
+// so a change in a model can't make the backends disagree.
+//
+// Only the subset of GLSL used by the models is supported: functions of float, int, bool and vec2..4 values, local variables,
+// if/else, for loops, the usual operators, swizzles and the common built-in functions. `params` is the kernel parameters struct,
+// `params.k1`..`params.k3` are the distortion coefficients. Anything else is an error, reported with the line of the source.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::OnceLock;
+use parking_lot::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Backend { OpenCL, Wgsl }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ty { Void, Bool, Int, Float, Vec(u8) }
+
+type Result<T> = std::result::Result<T, String>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok { Ident(String), Num(String), Punct(&'static str) }
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Num(String),
+    Var(String),
+    Field(Box<Expr>, String),
+    Call(String, Vec<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+pub enum Stmt {
+    Decl(Ty, Vec<(String, Option<Expr>)>),
+    Assign(Expr, &'static str, Expr), // Target, "=", "+=", "-=", "*=" or "/="
+    Step(String, &'static str),       // "++" or "--"
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    For(Box<Stmt>, Expr, Box<Stmt>, Vec<Stmt>),
+    Return(Option<Expr>),
+    Break,
+    Expr(Expr),
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub ret: Ty,
+    pub name: String,
+    pub args: Vec<(Ty, String)>,
+    pub body: Vec<Stmt>,
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+const PUNCTS: [&str; 30] = [
+    "==", "!=", "<=", ">=", "&&", "||", "+=", "-=", "*=", "/=", "++", "--",
+    "+", "-", "*", "/", "<", ">", "=", "!", "?", ":", ";", ",", ".", "(", ")", "{", "}", "&",
+];
+
+fn tokenize(src: &str) -> Result<Vec<(Tok, usize)>> {
+    let mut ret = Vec::new();
+    let chars = src.char_indices().collect::<Vec<_>>();
+    let line_at = |pos: usize| src[..pos].matches('\n').count() + 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let (pos, c) = chars[i];
+        let rest = &src[pos..];
+        if c.is_whitespace() {
+            i += 1;
+        } else if rest.starts_with("//") {
+            while i < chars.len() && chars[i].1 != '\n' { i += 1; }
+        } else if rest.starts_with("/*") {
+            let len = rest.find("*/").ok_or_else(|| format!("Line {}: unterminated comment", line_at(pos)))? + 2;
+            while i < chars.len() && chars[i].0 < pos + len { i += 1; }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            ret.push((Tok::Ident(rest[..len].to_string()), line_at(pos)));
+            i += len;
+        } else if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            let mut len = 0;
+            let bytes = rest.as_bytes();
+            while len < bytes.len() {
+                let b = bytes[len];
+                let exp_sign = (b == b'-' || b == b'+') && len > 0 && (bytes[len - 1] == b'e' || bytes[len - 1] == b'E');
+                if b.is_ascii_digit() || b == b'.' || b == b'e' || b == b'E' || exp_sign { len += 1; } else { break; }
+            }
+            // Suffixes of float literals are not needed in GLSL
+            let num = rest[..len].to_string();
+            if rest[len..].starts_with(['f', 'F']) { len += 1; }
+            ret.push((Tok::Num(num), line_at(pos)));
+            i += len;
+        } else if let Some(p) = PUNCTS.iter().find(|p| rest.starts_with(**p)) {
+            ret.push((Tok::Punct(p), line_at(pos)));
+            i += p.len();
+        } else {
+            return Err(format!("Line {}: unexpected character '{c}'", line_at(pos)));
+        }
+    }
+    Ok(ret)
+}
+
+fn type_from_name(name: &str) -> Option<Ty> {
+    Some(match name {
+        "void"  => Ty::Void,
+        "bool"  => Ty::Bool,
+        "int"   => Ty::Int,
+        "float" => Ty::Float,
+        "vec2"  => Ty::Vec(2),
+        "vec3"  => Ty::Vec(3),
+        "vec4"  => Ty::Vec(4),
+        _ => return None
+    })
+}
+
+struct Parser {
+    toks: Vec<(Tok, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> { self.toks.get(self.pos).map(|x| &x.0) }
+    fn peek_at(&self, n: usize) -> Option<&Tok> { self.toks.get(self.pos + n).map(|x| &x.0) }
+    fn line(&self) -> usize { self.toks.get(self.pos).or(self.toks.last()).map(|x| x.1).unwrap_or_default() }
+    fn err<T>(&self, msg: &str) -> Result<T> { Err(format!("Line {}: {msg}", self.line())) }
+
+    fn is(&self, p: &str) -> bool { matches!(self.peek(), Some(Tok::Punct(x)) if *x == p) }
+    fn eat(&mut self, p: &str) -> bool {
+        if self.is(p) { self.pos += 1; true } else { false }
+    }
+    fn expect(&mut self, p: &str) -> Result<()> {
+        if self.eat(p) { Ok(()) } else { self.err(&format!("expected '{p}', found {:?}", self.peek())) }
+    }
+    fn ident(&mut self) -> Result<String> {
+        match self.peek().cloned() {
+            Some(Tok::Ident(x)) => { self.pos += 1; Ok(x) },
+            x => self.err(&format!("expected a name, found {x:?}"))
+        }
+    }
+    fn peek_type(&self) -> Option<Ty> {
+        match self.peek() { Some(Tok::Ident(x)) => type_from_name(x), _ => None }
+    }
+
+    fn functions(&mut self) -> Result<Vec<Function>> {
+        let mut ret = Vec::new();
+        while self.peek().is_some() {
+            let ty = self.peek_type().ok_or_else(|| format!("Line {}: expected a function", self.line()))?;
+            self.pos += 1;
+            let name = self.ident()?;
+            self.expect("(")?;
+            let mut args = Vec::new();
+            while !self.eat(")") {
+                if !args.is_empty() { self.expect(",")?; }
+                let ty = self.peek_type().ok_or_else(|| format!("Line {}: expected an argument type", self.line()))?;
+                self.pos += 1;
+                args.push((ty, self.ident()?));
+            }
+            // Declarations without a body are implemented by the shader including the file
+            if self.eat(";") { continue; }
+            let body = self.block()?;
+            ret.push(Function { ret: ty, name, args, body });
+        }
+        Ok(ret)
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>> {
+        if !self.eat("{") {
+            return Ok(vec![self.statement()?]);
+        }
+        let mut ret = Vec::new();
+        while !self.eat("}") {
+            if self.peek().is_none() { return self.err("expected '}'"); }
+            ret.push(self.statement()?);
+        }
+        Ok(ret)
+    }
+
+    fn statement(&mut self) -> Result<Stmt> {
+        match self.peek().cloned() {
+            Some(Tok::Ident(x)) if x == "if" => {
+                self.pos += 1;
+                self.expect("(")?;
+                let cond = self.expr()?;
+                self.expect(")")?;
+                let then = self.block()?;
+                let otherwise = if matches!(self.peek(), Some(Tok::Ident(x)) if x == "else") { self.pos += 1; self.block()? } else { Vec::new() };
+                Ok(Stmt::If(cond, then, otherwise))
+            },
+            Some(Tok::Ident(x)) if x == "for" => {
+                self.pos += 1;
+                self.expect("(")?;
+                let init = self.simple_statement()?;
+                self.expect(";")?;
+                let cond = self.expr()?;
+                self.expect(";")?;
+                let step = self.simple_statement()?;
+                self.expect(")")?;
+                Ok(Stmt::For(Box::new(init), cond, Box::new(step), self.block()?))
+            },
+            Some(Tok::Ident(x)) if x == "return" => {
+                self.pos += 1;
+                let value = if self.is(";") { None } else { Some(self.expr()?) };
+                self.expect(";")?;
+                Ok(Stmt::Return(value))
+            },
+            Some(Tok::Ident(x)) if x == "break" => {
+                self.pos += 1;
+                self.expect(";")?;
+                Ok(Stmt::Break)
+            },
+            _ => {
+                let ret = self.simple_statement()?;
+                self.expect(";")?;
+                Ok(ret)
+            }
+        }
+    }
+
+    // Declaration, assignment, increment or a call, without the semicolon
+    fn simple_statement(&mut self) -> Result<Stmt> {
+        if let Some(ty) = self.peek_type() {
+            if matches!(self.peek_at(1), Some(Tok::Ident(_))) {
+                self.pos += 1;
+                let mut vars = Vec::new();
+                loop {
+                    let name = self.ident()?;
+                    let value = if self.eat("=") { Some(self.expr()?) } else { None };
+                    vars.push((name, value));
+                    if !self.eat(",") { break; }
+                }
+                return Ok(Stmt::Decl(ty, vars));
+            }
+        }
+        for op in ["++", "--"] {
+            if self.eat(op) { return Ok(Stmt::Step(self.ident()?, op)); }
+        }
+        let target = self.expr()?;
+        for op in ["++", "--"] {
+            if self.eat(op) {
+                return match target { Expr::Var(x) => Ok(Stmt::Step(x, op)), _ => self.err("only variables can be incremented") };
+            }
+        }
+        for op in ["=", "+=", "-=", "*=", "/="] {
+            if self.eat(op) {
+                if !matches!(target, Expr::Var(_) | Expr::Field(..)) { return self.err("invalid assignment target"); }
+                return Ok(Stmt::Assign(target, op, self.expr()?));
+            }
+        }
+        match target {
+            Expr::Call(..) => Ok(Stmt::Expr(target)),
+            _ => self.err("expected a statement")
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let cond = self.binary(0)?;
+        if self.eat("?") {
+            let a = self.expr()?;
+            self.expect(":")?;
+            let b = self.expr()?;
+            return Ok(Expr::Ternary(Box::new(cond), Box::new(a), Box::new(b)));
+        }
+        Ok(cond)
+    }
+
+    fn binary(&mut self, min_prec: u8) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() { Some(Tok::Punct(p)) if precedence(p).is_some() => *p, _ => break };
+            let prec = precedence(op).unwrap();
+            if prec < min_prec { break; }
+            self.pos += 1;
+            let rhs = self.binary(prec + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        for op in ["-", "!"] {
+            if self.eat(op) { return Ok(Expr::Unary(op, Box::new(self.unary()?))); }
+        }
+        self.eat("+");
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr> {
+        let mut e = match self.peek().cloned() {
+            Some(Tok::Num(x)) => { self.pos += 1; Expr::Num(x) },
+            Some(Tok::Ident(x)) => {
+                self.pos += 1;
+                if self.eat("(") {
+                    let mut args = Vec::new();
+                    while !self.eat(")") {
+                        if !args.is_empty() { self.expect(",")?; }
+                        args.push(self.expr()?);
+                    }
+                    Expr::Call(x, args)
+                } else {
+                    Expr::Var(x)
+                }
+            },
+            Some(Tok::Punct("(")) => {
+                self.pos += 1;
+                let e = self.expr()?;
+                self.expect(")")?;
+                e
+            },
+            x => return self.err(&format!("expected an expression, found {x:?}"))
+        };
+        while self.eat(".") {
+            e = Expr::Field(Box::new(e), self.ident()?);
+        }
+        Ok(e)
+    }
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "&"  => 3,
+        "==" | "!=" => 4,
+        "<" | ">" | "<=" | ">=" => 5,
+        "+" | "-" => 6,
+        "*" | "/" => 7,
+        _ => return None
+    })
+}
+
+pub fn parse(src: &str) -> Result<Vec<Function>> {
+    Parser { toks: tokenize(src)?, pos: 0 }.functions()
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Type of the field of the kernel parameters, the same in all backends (see `KernelParams`)
+pub fn params_field_type(name: &str) -> Option<Ty> {
+    Some(match name {
+        "width" | "height" | "stride" | "output_width" | "output_height" | "output_stride" | "matrix_count" | "interpolation" |
+        "background_mode" | "flags" | "bytes_per_pixel" | "pix_element_count" | "distortion_model" | "digital_lens" |
+        "plane_index" | "alpha_channel" => Ty::Int,
+        "fov" | "r_limit" | "lens_correction_amount" | "input_vertical_stretch" | "input_horizontal_stretch" | "background_margin" |
+        "background_margin_feather" | "canvas_scale" | "input_rotation" | "output_rotation" | "max_pixel_value" | "pixel_value_limit" |
+        "light_refraction_coefficient" => Ty::Float,
+        "f" | "c" | "translation2d" => Ty::Vec(2),
+        "k1" | "k2" | "k3" | "background" | "translation3d" | "digital_lens_params" | "safe_area_rect" | "ewa_coeffs_p" | "ewa_coeffs_q" => Ty::Vec(4),
+        _ => return None
+    })
+}
+
+fn swizzle_index(c: char) -> Option<usize> {
+    "xyzw".find(c).or_else(|| "rgba".find(c))
+}
+
+fn builtin_type(name: &str, args: &[Ty]) -> Option<Ty> {
+    let promoted = args.iter().copied().fold(Ty::Int, promote);
+    Some(match name {
+        "length" | "dot" | "distance" => Ty::Float,
+        "min" | "max" | "abs" | "clamp" | "mix" | "sign" => promoted,
+        "sqrt" | "tan" | "atan" | "sin" | "cos" | "asin" | "acos" | "exp" | "log" | "pow" | "floor" | "ceil" | "fract" | "normalize" => if promoted == Ty::Int { Ty::Float } else { promoted },
+        "all" | "any" => Ty::Bool,
+        _ => return type_from_name(name)
+    })
+}
+
+fn promote(a: Ty, b: Ty) -> Ty {
+    match (a, b) {
+        (Ty::Vec(n), _) | (_, Ty::Vec(n)) => Ty::Vec(n),
+        (Ty::Float, _) | (_, Ty::Float) => Ty::Float,
+        (Ty::Bool, Ty::Bool) => Ty::Bool,
+        _ => Ty::Int
+    }
+}
+
+struct Generator<'a> {
+    backend: Backend,
+    functions: HashMap<&'a str, &'a Function>,
+    uses_params: HashMap<&'a str, bool>,
+    scopes: Vec<HashMap<String, Ty>>,
+    out: String,
+    indent: usize,
+}
+
+impl<'a> Generator<'a> {
+    fn var_type(&self, name: &str) -> Option<Ty> {
+        self.scopes.iter().rev().find_map(|x| x.get(name).copied())
+    }
+
+    fn type_of(&self, e: &Expr) -> Result<Ty> {
+        Ok(match e {
+            Expr::Num(x) => if x.contains(['.', 'e', 'E']) { Ty::Float } else { Ty::Int },
+            Expr::Var(x) if x == "true" || x == "false" => Ty::Bool,
+            Expr::Var(x) => self.var_type(x).ok_or_else(|| format!("Unknown variable {x}"))?,
+            Expr::Field(base, field) => {
+                if matches!(&**base, Expr::Var(x) if x == "params") {
+                    return params_field_type(field).ok_or_else(|| format!("Unknown parameter {field}"));
+                }
+                match self.type_of(base)? {
+                    Ty::Vec(n) if field.chars().all(|c| swizzle_index(c).is_some_and(|i| i < n as usize)) && field.len() <= 4 => {
+                        if field.len() == 1 { Ty::Float } else { Ty::Vec(field.len() as u8) }
+                    },
+                    _ => return Err(format!("Invalid field {field}"))
+                }
+            },
+            Expr::Call(name, args) => {
+                if let Some(f) = self.functions.get(name.as_str()) { return Ok(f.ret); }
+                let args = args.iter().map(|x| self.type_of(x)).collect::<Result<Vec<_>>>()?;
+                builtin_type(name, &args).ok_or_else(|| format!("Unknown function {name}"))?
+            },
+            Expr::Unary("!", _) => Ty::Bool,
+            Expr::Unary(_, x) => self.type_of(x)?,
+            Expr::Binary(op, a, b) => match *op {
+                "+" | "-" | "*" | "/" => promote(self.type_of(a)?, self.type_of(b)?),
+                "&" => Ty::Int,
+                _ => Ty::Bool
+            },
+            Expr::Ternary(_, a, b) => promote(self.type_of(a)?, self.type_of(b)?),
+        })
+    }
+
+    fn type_name(&self, ty: Ty) -> &'static str {
+        match (self.backend, ty) {
+            (Backend::OpenCL, Ty::Void)   => "void",
+            (Backend::OpenCL, Ty::Bool)   => "bool",
+            (Backend::OpenCL, Ty::Int)    => "int",
+            (Backend::OpenCL, Ty::Float)  => "float",
+            (Backend::OpenCL, Ty::Vec(2)) => "float2",
+            (Backend::OpenCL, Ty::Vec(3)) => "float3",
+            (Backend::OpenCL, Ty::Vec(_)) => "float4",
+            (Backend::Wgsl, Ty::Void)   => "",
+            (Backend::Wgsl, Ty::Bool)   => "bool",
+            (Backend::Wgsl, Ty::Int)    => "i32",
+            (Backend::Wgsl, Ty::Float)  => "f32",
+            (Backend::Wgsl, Ty::Vec(2)) => "vec2<f32>",
+            (Backend::Wgsl, Ty::Vec(3)) => "vec3<f32>",
+            (Backend::Wgsl, Ty::Vec(_)) => "vec4<f32>",
+        }
+    }
+
+    fn float_literal(&self, x: &str) -> String {
+        let mut x = x.to_string();
+        if !x.contains(['.', 'e', 'E']) { x.push_str(".0"); }
+        if x.ends_with('.') { x.push('0'); }
+        if x.starts_with('.') { x.insert(0, '0'); }
+        if self.backend == Backend::OpenCL { x.push('f'); }
+        x
+    }
+
+    // Converts ints used where a float is expected, GLSL does that implicitly
+    fn expr_as(&self, e: &Expr, target: Ty) -> Result<String> {
+        let ty = self.type_of(e)?;
+        if ty == Ty::Int && matches!(target, Ty::Float | Ty::Vec(_)) {
+            if let Expr::Num(x) = e { return Ok(self.float_literal(x)); }
+            if let Expr::Unary("-", x) = e { if let Expr::Num(x) = &**x { return Ok(format!("-{}", self.float_literal(x))); } }
+            let inner = self.expr(e, 0)?;
+            return Ok(match self.backend {
+                Backend::OpenCL => format!("(float)({inner})"),
+                Backend::Wgsl   => format!("f32({inner})"),
+            });
+        }
+        if ty == Ty::Float && target == Ty::Int {
+            let inner = self.expr(e, 0)?;
+            return Ok(match self.backend {
+                Backend::OpenCL => format!("(int)({inner})"),
+                Backend::Wgsl   => format!("i32({inner})"),
+            });
+        }
+        self.expr(e, 0)
+    }
+
+    // `parent_prec` is the precedence of the surrounding operator, the expression is parenthesized if it binds weaker
+    fn expr(&self, e: &Expr, parent_prec: u8) -> Result<String> {
+        Ok(match e {
+            Expr::Num(x) => if self.type_of(e)? == Ty::Float { self.float_literal(x) } else { x.clone() },
+            Expr::Var(x) => x.clone(),
+            Expr::Field(base, field) => {
+                if let Expr::Var(b) = &**base {
+                    if b == "params" {
+                        return Ok(match self.backend {
+                            Backend::OpenCL => self.opencl_param(field, None),
+                            Backend::Wgsl   => format!("params.{field}"),
+                        });
+                    }
+                }
+                if let (Backend::OpenCL, Expr::Field(params, k)) = (self.backend, &**base) {
+                    if matches!(&**params, Expr::Var(x) if x == "params") && k.len() == 2 && k.starts_with('k') {
+                        return Ok(self.opencl_param(k, Some(field)));
+                    }
+                }
+                format!("{}.{field}", self.expr(base, 10)?)
+            },
+            Expr::Call(name, args) => self.call(name, args)?,
+            Expr::Unary(op, x) => format!("{op}{}", self.expr(x, 9)?),
+            Expr::Binary(op, a, b) => {
+                let prec = precedence(op).unwrap();
+                let (ta, tb) = (self.type_of(a)?, self.type_of(b)?);
+                let arith = promote(ta, tb);
+                // Mixed int and float scalars, the int side is converted
+                let target = match *op {
+                    "&&" | "||" => Ty::Bool,
+                    _ if arith == Ty::Int || *op == "&" => Ty::Int,
+                    _ => Ty::Float
+                };
+                let (sa, sb) = (self.operand(a, target, op, false)?, self.operand(b, target, op, true)?);
+                let s = format!("{sa} {op} {sb}");
+                let vector_compare = matches!(*op, "==" | "!=") && (matches!(ta, Ty::Vec(_)) || matches!(tb, Ty::Vec(_)));
+                if vector_compare {
+                    return Ok(format!("{}({s})", if *op == "==" { "all" } else { "any" }));
+                }
+                if prec < parent_prec { format!("({s})") } else { s }
+            },
+            Expr::Ternary(cond, a, b) => {
+                let ty = promote(self.type_of(a)?, self.type_of(b)?);
+                let (c, a, b) = (self.expr(cond, 0)?, self.expr_as(a, ty)?, self.expr_as(b, ty)?);
+                match self.backend {
+                    Backend::OpenCL => format!("({c} ? {a} : {b})"),
+                    Backend::Wgsl   => format!("select({b}, {a}, {c})"),
+                }
+            },
+        })
+    }
+
+    fn converts(&self, e: &Expr, target: Ty) -> Result<bool> {
+        let ty = self.type_of(e)?;
+        Ok((ty == Ty::Int && matches!(target, Ty::Float | Ty::Vec(_))) || (ty == Ty::Float && target == Ty::Int))
+    }
+
+    // Operand of a binary operator. WGSL doesn't define the precedence between `&&` and `||` and of `&` with the other operators,
+    // so these are always parenthesized
+    fn operand(&self, e: &Expr, target: Ty, parent: &str, right: bool) -> Result<String> {
+        let s = self.expr_as(e, target)?;
+        if let Expr::Binary(op, a, b) = e {
+            let vector_compare = matches!(*op, "==" | "!=") && (matches!(self.type_of(a)?, Ty::Vec(_)) || matches!(self.type_of(b)?, Ty::Vec(_)));
+            if self.converts(e, target)? || vector_compare { return Ok(s); }
+            let (child, prec) = (precedence(op).unwrap(), precedence(parent).unwrap());
+            let mixed = (parent == "||" && *op == "&&") || ((parent == "&") != (*op == "&"));
+            if child < prec || (right && child == prec) || mixed {
+                return Ok(format!("({s})"));
+            }
+        }
+        Ok(s)
+    }
+
+    fn opencl_param(&self, name: &str, swizzle: Option<&str>) -> String {
+        if name.len() == 2 && name.starts_with('k') {
+            let base = (name[1..].parse::<usize>().unwrap_or(1) - 1) * 4;
+            let items = swizzle.unwrap_or("xyzw").chars().map(|c| format!("params->k[{}]", base + swizzle_index(c).unwrap_or(0))).collect::<Vec<_>>();
+            return if items.len() == 1 { items[0].clone() } else { format!("(float{})({})", items.len(), items.join(", ")) };
+        }
+        match swizzle {
+            Some(s) => format!("params->{name}.{s}"),
+            None => format!("params->{name}"),
+        }
+    }
+
+    fn call(&self, name: &str, args: &[Expr]) -> Result<String> {
+        if let Some(f) = self.functions.get(name) {
+            let mut list = args.iter().zip(&f.args).map(|(a, (ty, _))| self.expr_as(a, *ty)).collect::<Result<Vec<_>>>()?;
+            if args.len() != f.args.len() { return Err(format!("Wrong number of arguments to {name}")); }
+            if self.backend == Backend::OpenCL && self.uses_params[name] { list.push("params".into()); }
+            return Ok(format!("{name}({})", list.join(", ")));
+        }
+        let ty = self.type_of(&Expr::Call(name.to_string(), args.to_vec()))?;
+        let scalar = if ty == Ty::Int { Ty::Int } else { Ty::Float };
+        let list = |target: Ty| -> Result<String> {
+            Ok(args.iter().map(|a| {
+                let t = if matches!(self.type_of(a)?, Ty::Vec(_)) { self.type_of(a)? } else { target };
+                self.expr_as(a, t)
+            }).collect::<Result<Vec<_>>>()?.join(", "))
+        };
+        Ok(match (self.backend, name) {
+            (_, "float") => match self.backend { Backend::OpenCL => format!("(float)({})", self.expr(&args[0], 0)?), Backend::Wgsl => format!("f32({})", self.expr(&args[0], 0)?) },
+            (_, "int")   => match self.backend { Backend::OpenCL => format!("(int)({})", self.expr(&args[0], 0)?),   Backend::Wgsl => format!("i32({})", self.expr(&args[0], 0)?) },
+            (_, "bool")  => match self.backend { Backend::OpenCL => format!("(({}) != 0)", self.expr(&args[0], 0)?), Backend::Wgsl => format!("bool({})", self.expr(&args[0], 0)?) },
+            (Backend::OpenCL, "vec2" | "vec3" | "vec4") => format!("({})({})", self.type_name(ty), list(Ty::Float)?),
+            (Backend::Wgsl,   "vec2" | "vec3" | "vec4") => format!("{}({})", self.type_name(ty), list(Ty::Float)?),
+            (Backend::OpenCL, "abs" | "min" | "max") if scalar == Ty::Float => format!("f{name}({})", list(ty)?),
+            (Backend::OpenCL, "abs") => format!("abs({})", list(ty)?),
+            _ => format!("{name}({})", list(if matches!(name, "min" | "max" | "abs" | "clamp" | "sign") { scalar } else { Ty::Float })?),
+        })
+    }
+
+    fn line(&mut self, s: &str) {
+        let _ = writeln!(self.out, "{}{s}", "    ".repeat(self.indent));
+    }
+
+    fn declaration(&self, ty: Ty, name: &str, value: &Option<Expr>) -> Result<String> {
+        let value = value.as_ref().map(|v| self.expr_as(v, ty)).transpose()?;
+        Ok(match (self.backend, value) {
+            (Backend::OpenCL, Some(v)) => format!("{} {name} = {v}", self.type_name(ty)),
+            (Backend::OpenCL, None)    => format!("{} {name}", self.type_name(ty)),
+            (Backend::Wgsl, Some(v))   => format!("var {name}: {} = {v}", self.type_name(ty)),
+            (Backend::Wgsl, None)      => format!("var {name}: {}", self.type_name(ty)),
+        })
+    }
+
+    // Statements usable in the header of a for loop
+    fn simple(&mut self, s: &Stmt) -> Result<String> {
+        Ok(match s {
+            Stmt::Decl(ty, vars) if vars.len() == 1 => {
+                let d = self.declaration(*ty, &vars[0].0, &vars[0].1)?;
+                self.scopes.last_mut().unwrap().insert(vars[0].0.clone(), *ty);
+                d
+            },
+            Stmt::Assign(target, op, value) => {
+                let ty = self.type_of(target)?;
+                format!("{} {op} {}", self.expr(target, 0)?, self.expr_as(value, ty)?)
+            },
+            Stmt::Step(name, op) => match self.backend {
+                Backend::OpenCL => format!("{name}{op}"),
+                Backend::Wgsl   => format!("{name} = {name} {} 1", &op[..1]),
+            },
+            Stmt::Expr(e) => self.expr(e, 0)?,
+            _ => return Err("Unsupported statement in the for loop".into())
+        })
+    }
+
+    fn statements(&mut self, body: &[Stmt], ret: Ty) -> Result<()> {
+        for s in body {
+            match s {
+                Stmt::Decl(ty, vars) => {
+                    for (name, value) in vars {
+                        let d = self.declaration(*ty, name, value)?;
+                        self.line(&format!("{d};"));
+                        self.scopes.last_mut().unwrap().insert(name.clone(), *ty);
+                    }
+                },
+                Stmt::If(cond, then, otherwise) => {
+                    let c = self.expr(cond, 0)?;
+                    self.line(&format!("if ({c}) {{"));
+                    self.nested(then, ret)?;
+                    let mut otherwise = otherwise;
+                    loop {
+                        match otherwise.as_slice() {
+                            [] => { self.line("}"); break; },
+                            [Stmt::If(cond, then, next)] => {
+                                let c = self.expr(cond, 0)?;
+                                self.line(&format!("}} else if ({c}) {{"));
+                                self.nested(then, ret)?;
+                                otherwise = next;
+                            },
+                            _ => {
+                                self.line("} else {");
+                                self.nested(otherwise, ret)?;
+                                self.line("}");
+                                break;
+                            }
+                        }
+                    }
+                },
+                Stmt::For(init, cond, step, body) => {
+                    self.scopes.push(HashMap::new());
+                    let i = self.simple(init)?;
+                    let c = self.expr(cond, 0)?;
+                    let st = self.simple(step)?;
+                    self.line(&format!("for ({i}; {c}; {st}) {{"));
+                    self.nested(body, ret)?;
+                    self.line("}");
+                    self.scopes.pop();
+                },
+                Stmt::Return(Some(e)) => { let v = self.expr_as(e, ret)?; self.line(&format!("return {v};")); },
+                Stmt::Return(None) => self.line("return;"),
+                Stmt::Break => self.line("break;"),
+                Stmt::Expr(Expr::Call(name, _)) if self.backend == Backend::Wgsl && self.functions.get(name.as_str()).is_some_and(|f| f.ret != Ty::Void) => {
+                    let s = self.simple(s)?;
+                    self.line(&format!("_ = {s};"));
+                },
+                _ => { let s = self.simple(s)?; self.line(&format!("{s};")); }
+            }
+        }
+        Ok(())
+    }
+
+    fn nested(&mut self, body: &[Stmt], ret: Ty) -> Result<()> {
+        self.indent += 1;
+        self.scopes.push(HashMap::new());
+        let r = self.statements(body, ret);
+        self.scopes.pop();
+        self.indent -= 1;
+        r
+    }
+
+    fn function(&mut self, f: &Function) -> Result<()> {
+        self.scopes = vec![f.args.iter().map(|(ty, name)| (name.clone(), *ty)).collect()];
+        let assigned = f.args.iter().filter(|(_, name)| assigns(&f.body, name)).map(|x| x.1.clone()).collect::<Vec<_>>();
+        match self.backend {
+            Backend::OpenCL => {
+                let mut args = f.args.iter().map(|(ty, name)| format!("{} {name}", self.type_name(*ty))).collect::<Vec<_>>();
+                if self.uses_params[f.name.as_str()] { args.push("__global KernelParams *params".into()); }
+                let _ = writeln!(self.out, "{} {}({}) {{", self.type_name(f.ret), f.name, args.join(", "));
+            },
+            Backend::Wgsl => {
+                // Arguments are immutable in WGSL
+                let args = f.args.iter().map(|(ty, name)| format!("{}{name}: {}", if assigned.contains(name) { "_" } else { "" }, self.type_name(*ty))).collect::<Vec<_>>();
+                let ret = if f.ret == Ty::Void { String::new() } else { format!(" -> {}", self.type_name(f.ret)) };
+                let _ = writeln!(self.out, "fn {}({}){ret} {{", f.name, args.join(", "));
+                for name in &assigned {
+                    let _ = writeln!(self.out, "    var {name} = _{name};");
+                }
+            }
+        }
+        self.indent = 1;
+        self.statements(&f.body, f.ret)?;
+        self.out.push_str("}\n");
+        Ok(())
+    }
+}
+
+fn assigns(body: &[Stmt], name: &str) -> bool {
+    fn root(e: &Expr) -> Option<&str> {
+        match e { Expr::Var(x) => Some(x), Expr::Field(b, _) => root(b), _ => None }
+    }
+    body.iter().any(|s| match s {
+        Stmt::Assign(target, ..) => root(target) == Some(name),
+        Stmt::Step(x, _) => x == name,
+        Stmt::If(_, a, b) => assigns(a, name) || assigns(b, name),
+        Stmt::For(init, _, step, b) => assigns(std::slice::from_ref(init), name) || assigns(std::slice::from_ref(step), name) || assigns(b, name),
+        _ => false
+    })
+}
+
+fn mentions_params(body: &[Stmt], functions: &HashMap<&str, bool>) -> bool {
+    fn expr(e: &Expr, functions: &HashMap<&str, bool>) -> bool {
+        match e {
+            Expr::Num(_) => false,
+            Expr::Var(x) => x == "params",
+            Expr::Field(b, _) | Expr::Unary(_, b) => expr(b, functions),
+            Expr::Call(name, args) => functions.get(name.as_str()).copied().unwrap_or(false) || args.iter().any(|x| expr(x, functions)),
+            Expr::Binary(_, a, b) => expr(a, functions) || expr(b, functions),
+            Expr::Ternary(a, b, c) => expr(a, functions) || expr(b, functions) || expr(c, functions),
+        }
+    }
+    body.iter().any(|s| match s {
+        Stmt::Decl(_, vars) => vars.iter().any(|(_, v)| v.as_ref().is_some_and(|v| expr(v, functions))),
+        Stmt::Assign(a, _, b) => expr(a, functions) || expr(b, functions),
+        Stmt::Step(..) | Stmt::Break | Stmt::Return(None) => false,
+        Stmt::If(c, a, b) => expr(c, functions) || mentions_params(a, functions) || mentions_params(b, functions),
+        Stmt::For(i, c, st, b) => expr(c, functions) || mentions_params(std::slice::from_ref(i), functions) || mentions_params(std::slice::from_ref(st), functions) || mentions_params(b, functions),
+        Stmt::Return(Some(e)) | Stmt::Expr(e) => expr(e, functions),
+    })
+}
+
+/// Translated functions, generated once per model and backend.
+/// A source which can't be translated is a bug in the model, the error fails the creation of the kernel, so the processing falls back to the CPU
+pub fn translated(src: &'static str, backend: Backend) -> Result<&'static str> {
+    static CACHE: OnceLock<Mutex<HashMap<(&'static str, Backend), Result<&'static str>>>> = OnceLock::new();
+    let mut cache = CACHE.get_or_init(Default::default).lock();
+    cache.entry((src, backend)).or_insert_with(|| {
+        translate(src, backend)
+            .map(|x| &*Box::leak(x.into_boxed_str()))
+            .map_err(|e| format!("Failed to translate the distortion model to {backend:?}: {e}"))
+    }).clone()
+}
+
+/// Translates the GLSL functions of a distortion model to the given backend
+pub fn translate(src: &str, backend: Backend) -> Result<String> {
+    let functions = parse(src)?;
+    let mut uses_params = HashMap::new();
+    for f in &functions {
+        let uses = mentions_params(&f.body, &uses_params);
+        uses_params.insert(f.name.as_str(), uses);
+    }
+    let mut g = Generator {
+        backend,
+        functions: HashMap::new(),
+        uses_params,
+        scopes: Vec::new(),
+        out: String::new(),
+        indent: 0,
+    };
+    for f in &functions {
+        // Only the functions defined before can be called, like in GLSL
+        g.functions.insert(&f.name, f);
+        g.function(f).map_err(|e| format!("{}: {e}", f.name))?;
+        g.out.push('\n');
+    }
+    Ok(g.out)
+}
+
+/// Reference implementation of the GLSL subset, for checking the models in tests without a GPU
+#[cfg(test)]
+pub mod eval {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Value { Bool(bool), Int(i32), Float(f32), Vec(u8, [f32; 4]) }
+
+    impl Value {
+        pub fn float(&self) -> f32 {
+            match *self { Value::Int(x) => x as f32, Value::Float(x) => x, Value::Bool(x) => x as i32 as f32, Value::Vec(_, v) => v[0] }
+        }
+        fn int(&self) -> i32 {
+            match *self { Value::Int(x) => x, Value::Float(x) => x as i32, Value::Bool(x) => x as i32, Value::Vec(_, v) => v[0] as i32 }
+        }
+        fn bool(&self) -> bool {
+            match *self { Value::Bool(x) => x, Value::Int(x) => x != 0, Value::Float(x) => x != 0.0, Value::Vec(..) => false }
+        }
+        pub fn vec(&self) -> Vec<f32> {
+            match *self { Value::Vec(n, v) => v[..n as usize].to_vec(), x => vec![x.float()] }
+        }
+        fn as_type(self, ty: Ty) -> Value {
+            match (ty, self) {
+                (Ty::Float, x) => Value::Float(x.float()),
+                (Ty::Int, x) => Value::Int(x.int()),
+                (Ty::Bool, x) => Value::Bool(x.bool()),
+                (_, x) => x
+            }
+        }
+    }
+
+    fn from_vec(v: &[f32]) -> Value {
+        if v.len() == 1 { return Value::Float(v[0]); }
+        let mut arr = [0.0; 4];
+        arr[..v.len()].copy_from_slice(v);
+        Value::Vec(v.len() as u8, arr)
+    }
+
+    fn componentwise(a: Value, b: Value, f: impl Fn(f32, f32) -> f32) -> Value {
+        let (va, vb) = (a.vec(), b.vec());
+        let n = va.len().max(vb.len());
+        from_vec(&(0..n).map(|i| f(va[i.min(va.len() - 1)], vb[i.min(vb.len() - 1)])).collect::<Vec<_>>())
+    }
+    fn map(a: Value, f: impl Fn(f32) -> f32) -> Value {
+        from_vec(&a.vec().into_iter().map(f).collect::<Vec<_>>())
+    }
+
+    enum Flow { Normal, Break, Return(Option<Value>) }
+
+    pub struct Program<'a> {
+        functions: Vec<Function>,
+        params: &'a dyn Fn(&str) -> Option<Value>,
+    }
+
+    impl<'a> Program<'a> {
+        /// `params` returns the fields of the kernel parameters
+        pub fn new(src: &str, params: &'a dyn Fn(&str) -> Option<Value>) -> Result<Self> {
+            Ok(Self { functions: parse(src)?, params })
+        }
+
+        pub fn call(&self, name: &str, args: &[Value]) -> Result<Value> {
+            let f = self.functions.iter().find(|f| f.name == name).ok_or_else(|| format!("Unknown function {name}"))?;
+            if f.args.len() != args.len() { return Err(format!("Wrong number of arguments to {name}")); }
+            let mut scopes = vec![f.args.iter().zip(args).map(|((ty, name), v)| (name.clone(), v.as_type(*ty))).collect::<HashMap<_, _>>()];
+            match self.block(&f.body, &mut scopes)? {
+                Flow::Return(Some(v)) => Ok(v.as_type(f.ret)),
+                _ if f.ret == Ty::Void => Ok(Value::Bool(false)),
+                _ => Err(format!("{name} didn't return a value"))
+            }
+        }
+
+        fn block(&self, body: &[Stmt], scopes: &mut Vec<HashMap<String, Value>>) -> Result<Flow> {
+            scopes.push(HashMap::new());
+            let mut ret = Flow::Normal;
+            for s in body {
+                ret = self.statement(s, scopes)?;
+                if !matches!(ret, Flow::Normal) { break; }
+            }
+            scopes.pop();
+            Ok(ret)
+        }
+
+        fn statement(&self, s: &Stmt, scopes: &mut Vec<HashMap<String, Value>>) -> Result<Flow> {
+            match s {
+                Stmt::Decl(ty, vars) => {
+                    for (name, value) in vars {
+                        let v = match value {
+                            Some(e) => self.expr(e, scopes)?.as_type(*ty),
+                            None => match ty { Ty::Vec(n) => Value::Vec(*n, [0.0; 4]), t => Value::Int(0).as_type(*t) }
+                        };
+                        scopes.last_mut().unwrap().insert(name.clone(), v);
+                    }
+                },
+                Stmt::Assign(target, op, value) => {
+                    let v = self.expr(value, scopes)?;
+                    let v = match *op {
+                        "=" => v,
+                        _ => self.binary(&op[..1], self.expr(target, scopes)?, v)?
+                    };
+                    self.store(target, v, scopes)?;
+                },
+                Stmt::Step(name, op) => {
+                    let v = self.expr(&Expr::Var(name.clone()), scopes)?;
+                    let v = self.binary(&op[..1], v, Value::Int(1))?;
+                    self.store(&Expr::Var(name.clone()), v, scopes)?;
+                },
+                Stmt::If(cond, then, otherwise) => {
+                    return if self.expr(cond, scopes)?.bool() { self.block(then, scopes) } else { self.block(otherwise, scopes) };
+                },
+                Stmt::For(init, cond, step, body) => {
+                    scopes.push(HashMap::new());
+                    self.statement(init, scopes)?;
+                    let mut ret = Flow::Normal;
+                    while self.expr(cond, scopes)?.bool() {
+                        match self.block(body, scopes)? {
+                            Flow::Break => break,
+                            Flow::Return(v) => { ret = Flow::Return(v); break; },
+                            Flow::Normal => { }
+                        }
+                        self.statement(step, scopes)?;
+                    }
+                    scopes.pop();
+                    return Ok(ret);
+                },
+                Stmt::Return(e) => return Ok(Flow::Return(e.as_ref().map(|e| self.expr(e, scopes)).transpose()?)),
+                Stmt::Break => return Ok(Flow::Break),
+                Stmt::Expr(e) => { self.expr(e, scopes)?; }
+            }
+            Ok(Flow::Normal)
+        }
+
+        fn store(&self, target: &Expr, v: Value, scopes: &mut [HashMap<String, Value>]) -> Result<()> {
+            let (name, swizzle) = match target {
+                Expr::Var(name) => (name, None),
+                Expr::Field(base, s) => match &**base { Expr::Var(name) => (name, Some(s)), _ => return Err("Invalid assignment".into()) },
+                _ => return Err("Invalid assignment".into())
+            };
+            let slot = scopes.iter_mut().rev().find_map(|x| x.get_mut(name)).ok_or_else(|| format!("Unknown variable {name}"))?;
+            match (swizzle, slot) {
+                (None, slot) => {
+                    *slot = match *slot { Value::Int(_) => Value::Int(v.int()), Value::Float(_) => Value::Float(v.float()), Value::Bool(_) => Value::Bool(v.bool()), _ => v };
+                },
+                (Some(s), Value::Vec(_, arr)) => {
+                    let values = v.vec();
+                    for (i, c) in s.chars().enumerate() {
+                        arr[swizzle_index(c).unwrap_or(0)] = values[i.min(values.len() - 1)];
+                    }
+                },
+                _ => return Err(format!("Invalid field assignment of {name}"))
+            }
+            Ok(())
+        }
+
+        fn binary(&self, op: &str, a: Value, b: Value) -> Result<Value> {
+            let ints = matches!((a, b), (Value::Int(_), Value::Int(_)));
+            Ok(match op {
+                "+" | "-" | "*" | "/" if ints => {
+                    let (a, b) = (a.int(), b.int());
+                    Value::Int(match op { "+" => a + b, "-" => a - b, "*" => a * b, _ => a / b })
+                },
+                "+" => componentwise(a, b, |a, b| a + b),
+                "-" => componentwise(a, b, |a, b| a - b),
+                "*" => componentwise(a, b, |a, b| a * b),
+                "/" => componentwise(a, b, |a, b| a / b),
+                "&" => Value::Int(a.int() & b.int()),
+                "==" => Value::Bool(a.vec() == b.vec()),
+                "!=" => Value::Bool(a.vec() != b.vec()),
+                "<"  => Value::Bool(a.float() < b.float()),
+                ">"  => Value::Bool(a.float() > b.float()),
+                "<=" => Value::Bool(a.float() <= b.float()),
+                ">=" => Value::Bool(a.float() >= b.float()),
+                _ => return Err(format!("Unknown operator {op}"))
+            })
+        }
+
+        fn expr(&self, e: &Expr, scopes: &mut Vec<HashMap<String, Value>>) -> Result<Value> {
+            Ok(match e {
+                Expr::Num(x) if x.contains(['.', 'e', 'E']) => Value::Float(x.parse::<f32>().map_err(|e| e.to_string())?),
+                Expr::Num(x) => Value::Int(x.parse::<i32>().map_err(|e| e.to_string())?),
+                Expr::Var(x) if x == "true" || x == "false" => Value::Bool(x == "true"),
+                Expr::Var(x) => *scopes.iter().rev().find_map(|s| s.get(x)).ok_or_else(|| format!("Unknown variable {x}"))?,
+                Expr::Field(base, field) => {
+                    if matches!(&**base, Expr::Var(x) if x == "params") {
+                        return (self.params)(field).ok_or_else(|| format!("Unknown parameter {field}"));
+                    }
+                    let v = self.expr(base, scopes)?.vec();
+                    from_vec(&field.chars().map(|c| v[swizzle_index(c).unwrap_or(0).min(v.len() - 1)]).collect::<Vec<_>>())
+                },
+                Expr::Unary("!", x) => Value::Bool(!self.expr(x, scopes)?.bool()),
+                Expr::Unary(_, x) => match self.expr(x, scopes)? { Value::Int(x) => Value::Int(-x), x => map(x, |x| -x) },
+                Expr::Binary("&&", a, b) => Value::Bool(self.expr(a, scopes)?.bool() && self.expr(b, scopes)?.bool()),
+                Expr::Binary("||", a, b) => Value::Bool(self.expr(a, scopes)?.bool() || self.expr(b, scopes)?.bool()),
+                Expr::Binary(op, a, b) => {
+                    let (a, b) = (self.expr(a, scopes)?, self.expr(b, scopes)?);
+                    self.binary(op, a, b)?
+                },
+                Expr::Ternary(c, a, b) => if self.expr(c, scopes)?.bool() { self.expr(a, scopes)? } else { self.expr(b, scopes)? },
+                Expr::Call(name, args) => {
+                    let args = args.iter().map(|x| self.expr(x, scopes)).collect::<Result<Vec<_>>>()?;
+                    if self.functions.iter().any(|f| &f.name == name) {
+                        return self.call(name, &args);
+                    }
+                    let a = args.first().copied().unwrap_or(Value::Float(0.0));
+                    let b = args.get(1).copied().unwrap_or(Value::Float(0.0));
+                    match name.as_str() {
+                        "vec2" | "vec3" | "vec4" => {
+                            let n = (name.as_bytes()[3] - b'0') as usize;
+                            let mut v = args.iter().flat_map(|x| x.vec()).collect::<Vec<_>>();
+                            if v.len() == 1 { v = vec![v[0]; n]; }
+                            if v.len() != n { return Err(format!("Wrong number of components for {name}")); }
+                            from_vec(&v)
+                        },
+                        "float" => Value::Float(a.float()),
+                        "int"   => Value::Int(a.int()),
+                        "bool"  => Value::Bool(a.bool()),
+                        "length" => Value::Float(a.vec().iter().map(|x| x * x).sum::<f32>().sqrt()),
+                        "distance" => Value::Float(componentwise(a, b, |a, b| a - b).vec().iter().map(|x| x * x).sum::<f32>().sqrt()),
+                        "dot" => Value::Float(a.vec().iter().zip(b.vec()).map(|(a, b)| a * b).sum()),
+                        "normalize" => { let l = a.vec().iter().map(|x| x * x).sum::<f32>().sqrt(); map(a, |x| x / l) },
+                        "min" | "max" | "abs" | "sign" | "clamp" if args.iter().all(|x| matches!(x, Value::Int(_))) => {
+                            let i = args.iter().map(|x| x.int()).collect::<Vec<_>>();
+                            Value::Int(match name.as_str() { "min" => i[0].min(i[1]), "max" => i[0].max(i[1]), "abs" => i[0].abs(), "sign" => i[0].signum(), _ => i[0].clamp(i[1], i[2]) })
+                        },
+                        "min" => componentwise(a, b, f32::min),
+                        "max" => componentwise(a, b, f32::max),
+                        "clamp" => componentwise(componentwise(a, b, f32::max), args[2], f32::min),
+                        "mix" => { let t = args[2]; componentwise(componentwise(a, t, |a, t| a * (1.0 - t)), componentwise(b, t, |b, t| b * t), |a, b| a + b) },
+                        "pow" => componentwise(a, b, f32::powf),
+                        "atan" if args.len() == 2 => componentwise(a, b, f32::atan2),
+                        "abs"   => map(a, f32::abs),
+                        "sign"  => map(a, |x| if x == 0.0 { 0.0 } else { x.signum() }),
+                        "sqrt"  => map(a, f32::sqrt),
+                        "tan"   => map(a, f32::tan),
+                        "atan"  => map(a, f32::atan),
+                        "sin"   => map(a, f32::sin),
+                        "cos"   => map(a, f32::cos),
+                        "asin"  => map(a, f32::asin),
+                        "acos"  => map(a, f32::acos),
+                        "exp"   => map(a, f32::exp),
+                        "log"   => map(a, f32::ln),
+                        "floor" => map(a, f32::floor),
+                        "ceil"  => map(a, f32::ceil),
+                        "fract" => map(a, |x| x - x.floor()),
+                        _ => return Err(format!("Unknown function {name}"))
+                    }
+                },
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "
+        float scale(float r) { return r == 0 ? 1.0 : params.k1.x / r; }
+        vec2 undistort_point(vec2 pos) {
+            if (params.k1 == vec4(0.0, 0.0, 0.0, 0.0)) return pos;
+            for (int i = 0; i < 2; ++i) { pos *= scale(length(pos)) * params.width; }
+            return pos;
+        }";
+
+    #[test]
+    fn backends() {
+        assert_eq!(translate(SRC, Backend::OpenCL).unwrap(), "\
+float scale(float r, __global KernelParams *params) {
+    return (r == 0.0f ? 1.0f : params->k[0] / r);
+}
+
+float2 undistort_point(float2 pos, __global KernelParams *params) {
+    if (all((float4)(params->k[0], params->k[1], params->k[2], params->k[3]) == (float4)(0.0f, 0.0f, 0.0f, 0.0f))) {
+        return pos;
+    }
+    for (int i = 0; i < 2; i++) {
+        pos *= scale(length(pos), params) * (float)(params->width);
+    }
+    return pos;
+}
+
+");
+        assert_eq!(translate(SRC, Backend::Wgsl).unwrap(), "\
+fn scale(r: f32) -> f32 {
+    return select(params.k1.x / r, 1.0, r == 0.0);
+}
+
+fn undistort_point(_pos: vec2<f32>) -> vec2<f32> {
+    var pos = _pos;
+    if (all(params.k1 == vec4<f32>(0.0, 0.0, 0.0, 0.0))) {
+        return pos;
+    }
+    for (var i: i32 = 0; i < 2; i = i + 1) {
+        pos *= scale(length(pos)) * f32(params.width);
+    }
+    return pos;
+}
+
+");
+        assert!(translate("vec2 f(vec2 p) { return p[0]; }", Backend::Wgsl).unwrap_err().starts_with("Line 1"));
+    }
+}
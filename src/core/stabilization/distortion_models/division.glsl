@@ -0,0 +1,30 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+vec2 undistort_point(vec2 pos) {
+    float r2 = pos.x * pos.x + pos.y * pos.y;
+    float d = 1.0 + params.k1.x * r2 + params.k1.y * r2 * r2;
+    if (d <= 0.0) { return vec2(0.0, 0.0); }
+    return pos / d;
+}
+
+vec2 distort_point(float x, float y, float z) {
+    vec2 pos = vec2(x, y) / z;
+    float ru = length(pos);
+    if (ru == 0.0) { return pos; }
+
+    // Newton's method for: ru * (1 + k1 * rd^2 + k2 * rd^4) - rd = 0
+    float rd = ru;
+    for (int i = 0; i < 10; ++i) {
+        float rd2 = rd * rd;
+        float f = ru * (1.0 + params.k1.x * rd2 + params.k1.y * rd2 * rd2) - rd;
+        if (abs(f) < 0.000001) {
+            if (rd < 0.0) { break; }
+            return pos * (rd / ru);
+        }
+        float df = ru * (2.0 * params.k1.x * rd + 4.0 * params.k1.y * rd2 * rd) - 1.0;
+        if (df == 0.0) { break; }
+        rd -= f / df;
+    }
+    return vec2(-99999.0, -99999.0);
+}
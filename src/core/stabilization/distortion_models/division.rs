@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Division model (Fitzgibbon), with one or two parameters: Ru = Rd / (1 + k1 * Rd^2 + k2 * Rd^4)
+// Undistortion is analytic, distortion is solved with Newton's method.
+// With at most 10 iterations it converges to |Ru * (1 + k1 * Rd^2 + k2 * Rd^4) - Rd| < 1e-6 (normalized coordinates, ie. ~0.001 px at 1000 px focal length)
+// everywhere inside the radial distortion limit. Points where it doesn't converge are outside of the valid area of the lens.
+
+use crate::stabilization::KernelParams;
+
+#[derive(Default, Clone)]
+pub struct Division { }
+
+pub const NEWTON_EPS: f64 = 1e-6;
+pub const MAX_ITERATIONS: usize = 10;
+
+impl Division {
+    pub fn undistort_point(&self, point: (f32, f32), params: &KernelParams) -> Option<(f32, f32)> {
+        let r2 = point.0 * point.0 + point.1 * point.1;
+        let d = 1.0 + params.k[0] * r2 + params.k[1] * r2 * r2;
+        if d <= 0.0 { return None; }
+
+        Some((
+            point.0 / d,
+            point.1 / d
+        ))
+    }
+
+    pub fn distort_point(&self, x: f32, y: f32, z: f32, params: &KernelParams) -> (f32, f32) {
+        let x = x / z;
+        let y = y / z;
+        let ru = (x * x + y * y).sqrt();
+        if ru == 0.0 { return (x, y); }
+
+        match Self::distorted_radius(ru as f64, params.k[0] as f64, params.k[1] as f64) {
+            Some(rd) => {
+                let scale = (rd / ru as f64) as f32;
+                (x * scale, y * scale)
+            },
+            None => (-99999.0, -99999.0)
+        }
+    }
+
+    /// Solves `ru * (1 + k1 * rd^2 + k2 * rd^4) - rd = 0` for rd
+    pub fn distorted_radius(ru: f64, k1: f64, k2: f64) -> Option<f64> {
+        let mut rd = ru;
+        for _ in 0..MAX_ITERATIONS {
+            let rd2 = rd * rd;
+            let f = ru * (1.0 + k1 * rd2 + k2 * rd2 * rd2) - rd;
+            if f.abs() < NEWTON_EPS {
+                return if rd >= 0.0 { Some(rd) } else { None };
+            }
+            let df = ru * (2.0 * k1 * rd + 4.0 * k2 * rd2 * rd) - 1.0;
+            if df == 0.0 { return None; }
+            rd -= f / df;
+        }
+        None
+    }
+
+    pub fn adjust_lens_profile(&self, _profile: &mut crate::LensProfile) { }
+
+    pub fn distortion_derivative(&self, theta: f64, k: &[f64]) -> Option<f64> {
+        if k.len() < 2 { return None; }
+        // d(Ru)/d(Rd), the model is valid as long as Ru is increasing
+        Some(match Self::distorted_radius(theta.tan(), k[0], k[1]) {
+            Some(rd) => {
+                let rd2 = rd * rd;
+                1.0 - k[0] * rd2 - 3.0 * k[1] * rd2 * rd2
+            },
+            None => -1.0
+        })
+    }
+
+    pub fn id() -> &'static str { "division" }
+    pub fn name() -> &'static str { "Division" }
+
+    pub fn glsl_functions(&self) -> &'static str { include_str!("division.glsl") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_analytic_inverse() {
+        // With k2 = 0: k1 * ru * rd^2 - rd + ru = 0
+        for k1 in [-0.35f64, -0.1, 0.0, 0.05, 0.2] {
+            for ru in [0.01, 0.2, 0.5, 0.9, 1.1] {
+                let disc = 1.0 - 4.0 * k1 * ru * ru;
+                let analytic = if k1 == 0.0 { Some(ru) } else if disc >= 0.0 { Some((1.0 - disc.sqrt()) / (2.0 * k1 * ru)) } else { None };
+                let numeric = Division::distorted_radius(ru, k1, 0.0);
+                match (analytic, numeric) {
+                    (Some(a), Some(n)) => assert!((a - n).abs() < 1e-5, "k1: {k1}, ru: {ru}, {a} != {n}"),
+                    (None, None) => { },
+                    _ => panic!("k1: {k1}, ru: {ru}, {analytic:?} != {numeric:?}")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut params = KernelParams::default();
+        params.k[0] = -0.25;
+        params.k[1] = 0.03;
+        let model = Division::default();
+        for (x, y) in [(0.0, 0.0), (0.1, 0.05), (-0.4, 0.3), (0.8, -0.6), (1.2, 0.9)] {
+            let (xd, yd) = model.distort_point(x, y, 1.0, &params);
+            let (xu, yu) = model.undistort_point((xd, yd), &params).unwrap();
+            assert!((xu - x).abs() < 1e-5 && (yu - y).abs() < 1e-5, "({x}, {y}) -> ({xd}, {yd}) -> ({xu}, {yu})");
+        }
+    }
+}
@@ -64,79 +64,5 @@ impl GoPro6Superview {
     pub fn id()   -> &'static str { "gopro6_superview" }
     pub fn name() -> &'static str { "GoPro6 Superview" }
 
-    pub fn opencl_functions(&self) -> &'static str {
-        r#"
-        float2 superview(float2 uv) {
-			uv.x *= 1.0f - 0.48f * fabs(uv.x);
-			uv.x *= 0.943396f * (1.0f + 0.157895f * fabs(uv.x));
-			uv.y *= 0.943396f * (1.0f + 0.060000f * fabs(uv.y * 2.0f));
-            return uv;
-        }
-
-        float2 digital_undistort_point(float2 uv, __global KernelParams *params) {
-            float2 out_c2 = (float2)(params->output_width, params->output_height);
-            uv = (uv / out_c2) - 0.5f;
-
-            uv = superview(uv);
-
-            uv = (uv + 0.5f) * out_c2;
-            return uv;
-        }
-        float2 digital_distort_point(float2 uv, __global KernelParams *params) {
-            float2 size = (float2)(params->width, params->height);
-            uv = (uv / size) - 0.5f;
-
-            float2 P = uv;
-            for (int i = 0; i < 12; ++i) {
-                float2 diff = superview(P) - uv;
-                if (fabs(diff.x) < 1e-6f && fabs(diff.y) < 1e-6f) {
-                    break;
-                }
-                P -= diff;
-            }
-
-            uv = (P + 0.5f) * size;
-
-            return uv;
-        }"#
-    }
-    pub fn wgsl_functions(&self) -> &'static str {
-        r#"
-        fn superview(_uv: vec2<f32>) -> vec2<f32> {
-            var uv = _uv;
-			uv.x *= 1.0 - 0.48 * abs(uv.x);
-			uv.x *= 0.943396 * (1.0 + 0.157895 * abs(uv.x));
-			uv.y *= 0.943396 * (1.0 + 0.060000 * abs(uv.y * 2.0));
-            return uv;
-        }
-        fn digital_undistort_point(_uv: vec2<f32>) -> vec2<f32> {
-            let out_c2 = vec2<f32>(f32(params.output_width), f32(params.output_height));
-            var uv = _uv;
-            uv = (uv / out_c2) - 0.5;
-
-            uv = superview(uv);
-
-            uv = (uv + 0.5) * out_c2;
-
-            return uv;
-        }
-        fn digital_distort_point(_uv: vec2<f32>) -> vec2<f32> {
-            let size = vec2<f32>(f32(params.width), f32(params.height));
-            var uv = _uv;
-            uv = (uv / size) - 0.5;
-
-            var P = uv;
-            for (var i: i32 = 0; i < 12; i = i + 1) {
-                let diff = superview(P) - uv;
-                if (abs(diff.x) < 1e-6 && abs(diff.y) < 1e-6) {
-                    break;
-                }
-                P -= diff;
-            }
-
-            uv = (P + 0.5) * size;
-
-            return uv;
-        }"#
-    }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("gopro6_superview.glsl") }
 }
@@ -68,87 +68,5 @@ impl GoProHyperview {
     pub fn id()   -> &'static str { "gopro_hyperview" }
     pub fn name() -> &'static str { "GoPro Hyperview" }
 
-    pub fn opencl_functions(&self) -> &'static str {
-        r#"
-        float2 hyperview(float2 uv) {
-            float x2 = uv.x * uv.x;
-            float y2 = uv.y * uv.y;
-            return (float2)(
-                uv.x * (1.5805143f + x2 * (-8.1668825f + x2 * (74.5198746f + x2 * (-451.5002441f + x2 * (1551.2922363f + x2 * (-2735.5422363f + x2 * 1923.1572266f))))) + y2 * -0.1086027f),
-                uv.y * (1.0238225f + y2 * -0.1025671f + x2 * (-0.2639930f + x2 * 0.2979266f))
-            );
-        }
-
-        float2 digital_undistort_point(float2 uv, __global KernelParams *params) {
-            float2 out_c2 = (float2)(params->output_width, params->output_height);
-            uv = (uv / out_c2) - 0.5f;
-
-            uv = hyperview(uv);
-
-            uv.x = uv.x / 1.555555555f;
-            uv = (uv + 0.5f) * out_c2;
-            return uv;
-        }
-        float2 digital_distort_point(float2 uv, __global KernelParams *params) {
-            float2 size = (float2)(params->width, params->height);
-            uv = (uv / size) - 0.5f;
-            uv.x = uv.x * 1.555555555f;
-
-            float2 P = uv;
-            for (int i = 0; i < 12; ++i) {
-                float2 diff = hyperview(P) - uv;
-                if (fabs(diff.x) < 1e-6f && fabs(diff.y) < 1e-6f) {
-                    break;
-                }
-                P -= diff;
-            }
-
-            uv = (P + 0.5f) * size;
-
-            return uv;
-        }"#
-    }
-    pub fn wgsl_functions(&self) -> &'static str {
-        r#"
-        fn hyperview(uv: vec2<f32>) -> vec2<f32> {
-            let x2 = uv.x * uv.x;
-            let y2 = uv.y * uv.y;
-            return vec2<f32>(
-                uv.x * (1.5805143 + x2 * (-8.1668825 + x2 * (74.5198746 + x2 * (-451.5002441 + x2 * (1551.2922363 + x2 * (-2735.5422363 + x2 * 1923.1572266))))) + y2 * -0.1086027),
-                uv.y * (1.0238225 + y2 * -0.1025671 + x2 * (-0.2639930 + x2 * 0.2979266))
-            );
-        }
-        fn digital_undistort_point(_uv: vec2<f32>) -> vec2<f32> {
-            let out_c2 = vec2<f32>(f32(params.output_width), f32(params.output_height));
-            var uv = _uv;
-            uv = (uv / out_c2) - 0.5;
-
-            uv = hyperview(uv);
-
-            uv.x = uv.x / 1.555555555;
-            uv = (uv + 0.5) * out_c2;
-
-            return uv;
-        }
-        fn digital_distort_point(_uv: vec2<f32>) -> vec2<f32> {
-            let size = vec2<f32>(f32(params.width), f32(params.height));
-            var uv = _uv;
-            uv = (uv / size) - 0.5;
-
-            uv.x = uv.x * 1.555555555;
-
-            var P = uv;
-            for (var i: i32 = 0; i < 12; i = i + 1) {
-                let diff = hyperview(P) - uv;
-                if (abs(diff.x) < 1e-6 && abs(diff.y) < 1e-6) {
-                    break;
-                }
-                P -= diff;
-            }
-
-            uv = (P + 0.5) * size;
-
-            return uv;
-        }"#
-    }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("gopro_hyperview.glsl") }
 }
@@ -70,87 +70,5 @@ impl GoProSuperview {
     pub fn id()   -> &'static str { "gopro_superview" }
     pub fn name() -> &'static str { "GoPro Superview" }
 
-    pub fn opencl_functions(&self) -> &'static str {
-        r#"
-        float2 superview(float2 uv) {
-            float x2 = uv.x * uv.x;
-            float y2 = uv.y * uv.y;
-            return (float2)(
-                uv.x * (1.2100393f + x2 * (-1.2758402f + x2 * 1.7751845f)),
-                uv.y * (0.9364505f + (0.4465308f - 0.7683315f * y2) * y2 + (-0.3574087f + 1.1584653f * y2 + 0.3529348f * x2) * x2)
-            );
-        }
-
-        float2 digital_undistort_point(float2 uv, __global KernelParams *params) {
-            float2 out_c2 = (float2)(params->output_width, params->output_height);
-            uv = (uv / out_c2) - 0.5f;
-
-            uv = superview(uv);
-
-            uv.x = uv.x / 1.333333333f;
-            uv = (uv + 0.5f) * out_c2;
-            return uv;
-        }
-        float2 digital_distort_point(float2 uv, __global KernelParams *params) {
-            float2 size = (float2)(params->width, params->height);
-            uv = (uv / size) - 0.5f;
-            uv.x = uv.x * 1.333333333f;
-
-            float2 P = uv;
-            for (int i = 0; i < 12; ++i) {
-                float2 diff = superview(P) - uv;
-                if (fabs(diff.x) < 1e-6f && fabs(diff.y) < 1e-6f) {
-                    break;
-                }
-                P -= diff;
-            }
-
-            uv = (P + 0.5f) * size;
-
-            return uv;
-        }"#
-    }
-    pub fn wgsl_functions(&self) -> &'static str {
-        r#"
-        fn superview(uv: vec2<f32>) -> vec2<f32> {
-            let x2 = uv.x * uv.x;
-            let y2 = uv.y * uv.y;
-            return vec2<f32>(
-                uv.x * (1.2100393 + x2 * (-1.2758402 + x2 * 1.7751845)),
-                uv.y * (0.9364505 + (0.4465308 - 0.7683315 * y2) * y2 + (-0.3574087 + 1.1584653 * y2 + 0.3529348 * x2) * x2)
-            );
-        }
-        fn digital_undistort_point(_uv: vec2<f32>) -> vec2<f32> {
-            let out_c2 = vec2<f32>(f32(params.output_width), f32(params.output_height));
-            var uv = _uv;
-            uv = (uv / out_c2) - 0.5;
-
-            uv = superview(uv);
-
-            uv.x = uv.x / 1.333333333;
-            uv = (uv + 0.5) * out_c2;
-
-            return uv;
-        }
-        fn digital_distort_point(_uv: vec2<f32>) -> vec2<f32> {
-            let size = vec2<f32>(f32(params.width), f32(params.height));
-            var uv = _uv;
-            uv = (uv / size) - 0.5;
-
-            uv.x = uv.x * 1.333333333;
-
-            var P = uv;
-            for (var i: i32 = 0; i < 12; i = i + 1) {
-                let diff = superview(P) - uv;
-                if (abs(diff.x) < 1e-6 && abs(diff.y) < 1e-6) {
-                    break;
-                }
-                P -= diff;
-            }
-
-            uv = (P + 0.5) * size;
-
-            return uv;
-        }"#
-    }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("gopro_superview.glsl") }
 }
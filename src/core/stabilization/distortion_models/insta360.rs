@@ -55,6 +55,5 @@ impl Insta360 {
     pub fn id() -> &'static str { "insta360" }
     pub fn name() -> &'static str { "Insta360" }
 
-    pub fn opencl_functions(&self) -> &'static str { include_str!("insta360.cl") }
-    pub fn wgsl_functions(&self)   -> &'static str { include_str!("insta360.wgsl") }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("insta360.glsl") }
 }
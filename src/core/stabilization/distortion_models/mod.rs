@@ -8,13 +8,17 @@ mod poly5;
 mod ptlens;
 mod insta360;
 mod sony;
+mod division;
 
 mod gopro_superview;
 mod gopro6_superview;
 mod gopro_hyperview;
 mod digital_stretch;
 
+mod codegen;
+
 use super::KernelParams;
+use codegen::Backend;
 
 macro_rules! impl_models {
     ($($name:ident => $class:ty,)*) => {
@@ -73,8 +77,9 @@ macro_rules! impl_models {
 
             pub fn id(&self)               -> &'static str { match &self.inner { $(DistortionModels::$name(_) => <$class>::id(),)* } }
             pub fn name(&self)             -> &'static str { match &self.inner { $(DistortionModels::$name(_) => <$class>::name(),)* } }
-            pub fn opencl_functions(&self) -> &'static str { match &self.inner { $(DistortionModels::$name(x) => x.opencl_functions(),)* } }
-            pub fn wgsl_functions(&self)   -> &'static str { match &self.inner { $(DistortionModels::$name(x) => x.wgsl_functions(),)* } }
+            pub fn glsl_functions(&self)   -> &'static str { match &self.inner { $(DistortionModels::$name(x) => x.glsl_functions(),)* } }
+            pub fn opencl_functions(&self) -> Result<&'static str, String> { codegen::translated(self.glsl_functions(), Backend::OpenCL) }
+            pub fn wgsl_functions(&self)   -> Result<&'static str, String> { codegen::translated(self.glsl_functions(), Backend::Wgsl) }
 
             pub fn from_name(id: &str) -> Self {
                 $(
@@ -95,6 +100,7 @@ impl_models! {
     PtLens         => ptlens::PtLens,
     Insta360       => insta360::Insta360,
     Sony           => sony::Sony,
+    Division       => division::Division,
 
     // Digital lenses (ie. post-processing)
     GoProSuperview => gopro_superview::GoProSuperview,
@@ -102,3 +108,235 @@ impl_models! {
     GoProHyperview => gopro_hyperview::GoProHyperview,
     DigitalStretch => digital_stretch::DigitalStretch,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codegen::eval::{ Program, Value };
+
+    const PHYSICAL_LENSES: [&str; 8] = ["opencv_fisheye", "opencv_standard", "poly3", "poly5", "ptlens", "insta360", "sony", "division"];
+    const DIGITAL_LENSES: [&str; 4] = ["gopro_superview", "gopro6_superview", "gopro_hyperview", "digital_stretch"];
+    const TOLERANCE: f32 = 1e-5;
+
+    fn test_params(id: &str) -> KernelParams {
+        let mut k = [0.0f32; 12];
+        let coeffs: &[f32] = match id {
+            "opencv_fisheye"  => &[0.05, -0.01, 0.004, -0.001],
+            "opencv_standard" => &[-0.12, 0.03, 0.001, -0.0005, -0.004, 0.002, 0.001, 0.0005, 0.0002, -0.0001, 0.0001, 0.0002],
+            "poly3"           => &[-0.05],
+            "poly5"           => &[-0.05, 0.01],
+            "ptlens"          => &[0.01, -0.03, 0.02],
+            "insta360"        => &[-0.05, 0.01, 0.001, 0.0005, -0.0003, 0.9],
+            "sony"            => &[1.0, 0.02, -0.05, 0.01, 0.002, -0.001, 1.2, 1.2],
+            "division"        => &[-0.1, 0.01],
+            _ => &[]
+        };
+        k[..coeffs.len()].copy_from_slice(coeffs);
+        KernelParams {
+            width: 3840, height: 2160, output_width: 3840, output_height: 2160,
+            k,
+            digital_lens_params: [1.25, 0.9, 0.0, 0.0],
+            ..Default::default()
+        }
+    }
+
+    // Points to check and the results of the CPU implementation, both as `[x, y, distorted x, distorted y]`.
+    // The GPU functions distort the first two values and undistort the other two
+    struct Case {
+        model: DistortionModel,
+        params: KernelParams,
+        digital: bool,
+        points: Vec<[f32; 4]>,
+        expected: Vec<[f32; 4]>,
+        scale: f32, // Digital lenses work in pixels
+    }
+
+    fn cases() -> impl Iterator<Item = Case> {
+        let grid = |from: f32, to: f32| {
+            let at = move |i: usize| from + (to - from) * i as f32 / 16.0;
+            (0..17).flat_map(move |y| (0..17).map(move |x| (at(x), at(y))))
+        };
+        PHYSICAL_LENSES.iter().map(|id| (id, false)).chain(DIGITAL_LENSES.iter().map(|id| (id, true))).map(move |(id, digital)| {
+            let model = DistortionModel::from_name(id);
+            let params = test_params(id);
+            let scale = if digital { params.width as f32 } else { 1.0 };
+            let (mut points, mut expected) = (Vec::new(), Vec::new());
+            // The iterative inverse of the hyperview doesn't converge in the corners
+            for (x, y) in if digital { grid(0.1, 0.9) } else { grid(-0.8, 0.8) } {
+                let (x, y) = if digital { (x * params.width as f32, y * params.height as f32) } else { (x, y) };
+                let distorted = model.distort_point(x, y, 1.0, &params);
+                let undistorted = model.undistort_point(if digital { (x, y) } else { distorted }, &params).unwrap_or_default();
+                let point = if digital { [x, y, x, y] } else { [x, y, distorted.0, distorted.1] };
+                points.push(point);
+                expected.push([distorted.0, distorted.1, undistorted.0, undistorted.1]);
+            }
+            Case { model, params, digital, points, expected, scale }
+        })
+    }
+
+    fn compare(case: &Case, backend: &str, results: &[[f32; 4]]) {
+        for ((point, cpu), gpu) in case.points.iter().zip(&case.expected).zip(results) {
+            let close = cpu.iter().zip(gpu).all(|(a, b)| ((a - b) / case.scale).abs() <= TOLERANCE);
+            assert!(close, "{} {backend} at {point:?}: CPU {cpu:?}, GPU {gpu:?}", case.model.id());
+        }
+    }
+
+    // A model which can't be translated would only fail when its kernel is created
+    #[test]
+    fn all_models_translate() {
+        for id in PHYSICAL_LENSES.iter().chain(&DIGITAL_LENSES) {
+            let model = DistortionModel::from_name(id);
+            assert_eq!(model.id(), *id);
+            for backend in [Backend::OpenCL, Backend::Wgsl] {
+                let functions = codegen::translated(model.glsl_functions(), backend).unwrap_or_else(|e| panic!("{id}: {e}"));
+                assert!(!functions.is_empty(), "{id} {backend:?}");
+            }
+        }
+    }
+
+    // The generated functions of all backends come from the GLSL, so the GLSL has to match the CPU implementation
+    #[test]
+    fn glsl_matches_cpu() {
+        for case in cases() {
+            let params = case.params;
+            let lookup = |name: &str| -> Option<Value> {
+                let vec4 = |x: &[f32]| Value::Vec(4, [x[0], x[1], x[2], x[3]]);
+                let k = params.k;
+                Some(match name {
+                    "k1" => vec4(&k[0..4]),
+                    "k2" => vec4(&k[4..8]),
+                    "k3" => vec4(&k[8..12]),
+                    "width"         => Value::Int(params.width),
+                    "height"        => Value::Int(params.height),
+                    "output_width"  => Value::Int(params.output_width),
+                    "output_height" => Value::Int(params.output_height),
+                    "digital_lens_params" => vec4(&params.digital_lens_params),
+                    _ => return None
+                })
+            };
+            let program = Program::new(case.model.glsl_functions(), &lookup).unwrap();
+            let results = case.points.iter().map(|p| {
+                let distorted = if case.digital {
+                    program.call("digital_distort_point", &[Value::Vec(2, [p[0], p[1], 0.0, 0.0])])
+                } else {
+                    program.call("distort_point", &[Value::Float(p[0]), Value::Float(p[1]), Value::Float(1.0)])
+                }.unwrap().vec();
+                let undistorted = program.call(if case.digital { "digital_undistort_point" } else { "undistort_point" }, &[Value::Vec(2, [p[2], p[3], 0.0, 0.0])]).unwrap().vec();
+                [distorted[0], distorted[1], undistorted[0], undistorted[1]]
+            }).collect::<Vec<_>>();
+            compare(&case, "GLSL", &results);
+        }
+    }
+
+    fn wgsl_module(case: &Case) -> String {
+        let kernel = include_str!("../../gpu/wgpu_undistort.wgsl");
+        let start = kernel.find("struct KernelParams").unwrap();
+        let end = start + kernel[start..].find('}').unwrap() + 1;
+        let (distort, undistort) = if case.digital { ("digital_distort_point(p.xy)", "digital_undistort_point") } else { ("distort_point(p.x, p.y, 1.0)", "undistort_point") };
+        format!("{}
+            @group(0) @binding(0) var<uniform> params: KernelParams;
+            @group(0) @binding(1) var<storage, read> points: array<vec4<f32>>;
+            @group(0) @binding(2) var<storage, read_write> result: array<vec4<f32>>;
+            {}
+            @compute @workgroup_size(64)
+            fn main(@builtin(global_invocation_id) id: vec3<u32>) {{
+                if (id.x >= arrayLength(&points)) {{ return; }}
+                let p = points[id.x];
+                result[id.x] = vec4<f32>({distort}, {undistort}(p.zw));
+            }}", &kernel[start..end], case.model.wgsl_functions().unwrap())
+    }
+
+    #[test]
+    fn generated_wgsl_is_valid() {
+        use wgpu::naga;
+        for case in cases() {
+            let src = wgsl_module(&case);
+            let module = naga::front::wgsl::parse_str(&src).unwrap_or_else(|e| panic!("{}: {}", case.model.id(), e.emit_to_string(&src)));
+            naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+                .validate(&module)
+                .unwrap_or_else(|e| panic!("{}: {}", case.model.id(), e.emit_to_string(&src)));
+        }
+    }
+
+    // Skipped without a GPU
+    #[test]
+    fn wgpu_matches_cpu() {
+        use wgpu::util::DeviceExt;
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let Some(adapter) = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default())) else { return; };
+        let Ok((device, queue)) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None)) else { return; };
+
+        for case in cases() {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor { label: None, source: wgpu::ShaderSource::Wgsl(wgsl_module(&case).into()) });
+            let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: None,
+                layout: None,
+                module: &module,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+            let size = (case.points.len() * std::mem::size_of::<[f32; 4]>()) as u64;
+            let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor { label: None, contents: bytemuck::bytes_of(&case.params), usage: wgpu::BufferUsages::UNIFORM });
+            let points = device.create_buffer_init(&wgpu::util::BufferInitDescriptor { label: None, contents: bytemuck::cast_slice(&case.points), usage: wgpu::BufferUsages::STORAGE });
+            let result = device.create_buffer(&wgpu::BufferDescriptor { label: None, size, usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC, mapped_at_creation: false });
+            let staging = device.create_buffer(&wgpu::BufferDescriptor { label: None, size, usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST, mapped_at_creation: false });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 0, resource: params.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 1, resource: points.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 2, resource: result.as_entire_binding() },
+                ],
+            });
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None, timestamp_writes: None });
+                pass.set_pipeline(&pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups(case.points.len().div_ceil(64) as u32, 1, 1);
+            }
+            encoder.copy_buffer_to_buffer(&result, 0, &staging, 0, size);
+            queue.submit(Some(encoder.finish()));
+
+            let slice = staging.slice(..);
+            slice.map_async(wgpu::MapMode::Read, |_| { });
+            device.poll(wgpu::Maintain::Wait);
+            let results = bytemuck::cast_slice::<u8, [f32; 4]>(&slice.get_mapped_range()).to_vec();
+            compare(&case, "wgpu", &results);
+        }
+    }
+
+    // Skipped without an OpenCL device
+    #[cfg(feature = "use-opencl")]
+    #[test]
+    fn opencl_matches_cpu() {
+        let Some(platform) = ocl::Platform::list().into_iter().find(|p| ocl::Device::list_all(p).is_ok_and(|x| !x.is_empty())) else { return; };
+        let kernel = include_str!("../../gpu/opencl_undistort.cl");
+        let start = kernel.find("typedef struct {").unwrap();
+        let end = start + kernel[start..].find("} KernelParams;").unwrap() + 15;
+
+        for case in cases() {
+            let (distort, undistort) = if case.digital { ("digital_distort_point(p.xy, params)", "digital_undistort_point") } else { ("distort_point(p.x, p.y, 1.0f, params)", "undistort_point") };
+            let src = format!("{}
+                {}
+                __kernel void test_points(__global KernelParams *params, __global const float4 *points, __global float4 *result) {{
+                    float4 p = points[get_global_id(0)];
+                    result[get_global_id(0)] = (float4)({distort}, {undistort}(p.zw, params));
+                }}", &kernel[start..end], case.model.opencl_functions().unwrap());
+
+            let pro_que = ocl::ProQue::builder().platform(platform).src(src).dims(case.points.len()).build().unwrap_or_else(|e| panic!("{}: {e}", case.model.id()));
+            let params = pro_que.buffer_builder::<u8>().len(std::mem::size_of::<KernelParams>()).copy_host_slice(bytemuck::bytes_of(&case.params)).build().unwrap();
+            let points = pro_que.buffer_builder::<f32>().len(case.points.len() * 4).copy_host_slice(bytemuck::cast_slice(&case.points)).build().unwrap();
+            let result = pro_que.buffer_builder::<f32>().len(case.points.len() * 4).build().unwrap();
+            let kernel = pro_que.kernel_builder("test_points").disable_arg_type_check().arg(&params).arg(&points).arg(&result).build().unwrap();
+            unsafe { kernel.enq().unwrap(); }
+
+            let mut results = vec![0.0f32; case.points.len() * 4];
+            result.read(&mut results).enq().unwrap();
+            compare(&case, "OpenCL", bytemuck::cast_slice(&results));
+        }
+    }
+}
@@ -108,6 +108,5 @@ impl OpenCVFisheye {
     pub fn id() -> &'static str { "opencv_fisheye" }
     pub fn name() -> &'static str { "OpenCV Fisheye" }
 
-    pub fn opencl_functions(&self) -> &'static str { include_str!("opencv_fisheye.cl") }
-    pub fn wgsl_functions(&self)   -> &'static str { include_str!("opencv_fisheye.wgsl") }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("opencv_fisheye.glsl") }
 }
@@ -59,6 +59,5 @@ impl OpenCVStandard {
     pub fn id() -> &'static str { "opencv_standard" }
     pub fn name() -> &'static str { "OpenCV Standard" }
 
-    pub fn opencl_functions(&self) -> &'static str { include_str!("opencv_standard.cl") }
-    pub fn wgsl_functions(&self)   -> &'static str { include_str!("opencv_standard.wgsl") }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("opencv_standard.glsl") }
 }
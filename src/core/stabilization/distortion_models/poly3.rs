@@ -80,8 +80,7 @@ impl Poly3 {
     pub fn id() -> &'static str { "poly3" }
     pub fn name() -> &'static str { "Poly3" }
 
-    pub fn opencl_functions(&self) -> &'static str { include_str!("poly3.cl") }
-    pub fn wgsl_functions(&self)   -> &'static str { include_str!("poly3.wgsl") }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("poly3.glsl") }
 }
 
 // TODO
@@ -70,6 +70,5 @@ impl Poly5 {
     pub fn id() -> &'static str { "poly5" }
     pub fn name() -> &'static str { "Poly5" }
 
-    pub fn opencl_functions(&self) -> &'static str { include_str!("poly5.cl") }
-    pub fn wgsl_functions(&self)   -> &'static str { include_str!("poly5.wgsl") }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("poly5.glsl") }
 }
@@ -73,6 +73,5 @@ impl PtLens {
     pub fn id() -> &'static str { "ptlens" }
     pub fn name() -> &'static str { "PTLens" }
 
-    pub fn opencl_functions(&self) -> &'static str { include_str!("ptlens.cl") }
-    pub fn wgsl_functions(&self)   -> &'static str { include_str!("ptlens.wgsl") }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("ptlens.glsl") }
 }
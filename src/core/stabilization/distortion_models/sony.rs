@@ -112,6 +112,5 @@ impl Sony {
     pub fn id() -> &'static str { "sony" }
     pub fn name() -> &'static str { "Sony" }
 
-    pub fn opencl_functions(&self) -> &'static str { include_str!("sony.cl") }
-    pub fn wgsl_functions(&self)   -> &'static str { include_str!("sony.wgsl") }
+    pub fn glsl_functions(&self) -> &'static str { include_str!("sony.glsl") }
 }
@@ -5,7 +5,7 @@ use libc::MAXTTL;
 use nalgebra::Matrix3;
 use super::{ ComputeParams, KernelParams };
 use rayon::iter::{ ParallelIterator, IntoParallelIterator };
-use crate::gyro_source::FileMetadata;
+use crate::gyro_source::{ FileMetadata, Quat64 };
 use crate::keyframes::KeyframeType;
 use crate::util::{ MapClosest, map_coord };
 
@@ -21,7 +21,7 @@ pub struct FrameTransform {
 
 impl FrameTransform {
     fn get_frame_readout_time(params: &ComputeParams, can_invert: bool, timestamp_ms: f64, file_metadata: &FileMetadata) -> f64 {
-        let mut frame_readout_time = params.frame_readout_time.abs();
+        let mut frame_readout_time = params.frame_readout_time_at(timestamp_ms);
         let mut scale = 1.0;
         telemetry_parser::try_block!({
             let val = file_metadata.lens_params.get_closest(&((timestamp_ms * 1000.0).round() as i64), 100000)?; // closest within 100ms
@@ -35,7 +35,7 @@ impl FrameTransform {
         }
         frame_readout_time * scale
     }
-    fn get_new_k(params: &ComputeParams, camera_matrix: &Matrix3<f64>, fov: f64) -> Matrix3<f64> {
+    pub(crate) fn get_new_k(params: &ComputeParams, camera_matrix: &Matrix3<f64>, fov: f64) -> Matrix3<f64> {
         let horizontal_ratio = if params.lens.input_horizontal_stretch > 0.01 { params.lens.input_horizontal_stretch } else { 1.0 };
 
         let img_dim_ratio = 1.0 / horizontal_ratio;
@@ -58,6 +58,57 @@ impl FrameTransform {
         fov
     }
 
+    /// FOV of the output at the frame, the same as `KernelParams::fov` used for rendering
+    pub fn output_fov(params: &ComputeParams, frame: usize, timestamp_ms: f64) -> f64 {
+        let fov = Self::get_fov(params, frame, true, timestamp_ms, false);
+        match params.lens.optimal_fov {
+            Some(adj) if params.fovs.is_empty() => fov * adj,
+            _ => fov
+        }
+    }
+
+    // Pitch and roll compensation, in the output camera space. The pitch is set at the reference FOV and scaled with the zoom,
+    // so it moves the horizon by the same number of output pixels at any FOV. The roll doesn't change with the zoom
+    pub(crate) fn get_horizon_compensation(params: &ComputeParams, frame: usize, use_fovs: bool, timestamp_ms: f64, inverted: bool) -> Option<Matrix3<f64>> {
+        let pitch = params.keyframes.value_at_video_timestamp(&KeyframeType::HorizonCompensationPitch, timestamp_ms).unwrap_or(params.horizon_compensation.0);
+        let roll  = params.keyframes.value_at_video_timestamp(&KeyframeType::HorizonCompensationRoll,  timestamp_ms).unwrap_or(params.horizon_compensation.1);
+        if pitch == 0.0 && roll == 0.0 { return None; }
+
+        let fov_scale = params.keyframes.value_at_video_timestamp(&KeyframeType::Fov, timestamp_ms).unwrap_or(params.fov_scale);
+        let fov = if use_fovs { params.fovs.get(frame).unwrap_or(&1.0) * fov_scale } else { fov_scale };
+        let pitch = (pitch.to_radians().tan() * fov / params.horizon_compensation_fov.max(0.001)).atan();
+
+        // The output is upside down in the inverted framebuffer
+        let sign = if inverted { -1.0 } else { 1.0 };
+        Some(*nalgebra::Rotation3::from_euler_angles(pitch * sign, 0.0, roll.to_radians() * sign).matrix())
+    }
+
+    // Rotations of the sensor as seen in footage mirrored relative to it, ie. M * r * M where M flips the x and/or y axis
+    fn mirror_rotation(params: &ComputeParams, r: &mut Matrix3<f64>) {
+        if !params.lens.flip_horizontal && !params.lens.flip_vertical { return; }
+        let m = Matrix3::from_diagonal(&nalgebra::Vector3::new(if params.lens.flip_horizontal { -1.0 } else { 1.0 }, if params.lens.flip_vertical { -1.0 } else { 1.0 }, 1.0));
+        *r = m * *r * m;
+    }
+
+    /// Rotation in the kernel's camera space (x right, y down, z forward) from the gyro quaternion of a row
+    pub(crate) fn kernel_rotation(params: &ComputeParams, quat: &Quat64, image_rotation: &Matrix3<f64>, horizon_compensation: Option<&Matrix3<f64>>) -> Matrix3<f64> {
+        let mut r = image_rotation * *quat.to_rotation_matrix().matrix();
+
+        // default is false.
+        if params.framebuffer_inverted {
+            r[(0, 2)] *= -1.0; r[(1, 2)] *= -1.0;
+            r[(2, 0)] *= -1.0; r[(2, 1)] *= -1.0;
+        } else {
+            r[(0, 1)] *= -1.0; r[(0, 2)] *= -1.0;
+            r[(1, 0)] *= -1.0; r[(2, 0)] *= -1.0;
+        }
+        Self::mirror_rotation(params, &mut r);
+        if let Some(c) = horizon_compensation {
+            r = c * r;
+        }
+        r
+    }
+
     pub fn get_lens_data_at_timestamp(params: &ComputeParams, timestamp_ms: f64, invert_asym_lens: bool) -> (Matrix3<f64>, [f64; 12], f64, f64, f64, Option<f64>) {
         let mut interpolated_lens = None;
         let gyro = params.gyro.read();
@@ -67,11 +118,37 @@ impl FrameTransform {
                 interpolated_lens = Some(params.lens.get_interpolated_lens_at(*val));
             }
         }
+        // Zoom during the shot. With the calibrations at other focal lengths the distortion changes too, otherwise the profile
+        // is scaled from the focal length at the start of the clip, which is close enough for the focal length but keeps the distortion
+        let timestamp_us = (timestamp_ms * 1000.0).round() as i64;
+        let mut focal_scale = 1.0;
+        let mut frame_focal_length = None;
+        if interpolated_lens.is_none() {
+            // Only the changes are stored, so it's the last one before the frame
+            let fl = params.focal_lengths.range(..=timestamp_us).next_back().or_else(|| params.focal_lengths.iter().next()).map(|x| *x.1);
+            if let Some(fl) = fl.filter(|x| *x > 0.0) {
+                if !params.lens.focal_length_calibrations.is_empty() {
+                    interpolated_lens = Some(params.lens.get_lens_at_focal_length(fl));
+                } else if let Some(reference) = params.focal_lengths.values().next().copied().filter(|x| *x > 0.0) {
+                    focal_scale = fl / reference;
+                    frame_focal_length = Some(fl);
+                }
+            }
+        }
         let lens = interpolated_lens.as_ref().unwrap_or(&params.lens);
 
-        let mut focal_length = lens.focal_length;
+        // Digital zoom during the shot, relative to the crop at the start. The lens model from the metadata below has the crop of the frame already
+        if lens.fisheye_params.distortion_coeffs.len() >= 4 {
+            if let (Some(crop), Some(reference)) = (params.crops.get_closest(&timestamp_us, 100000), params.crops.values().next().filter(|x| **x > 0.0)) { // closest within 100ms
+                focal_scale *= crop / reference;
+            }
+        }
+
+        let mut focal_length = frame_focal_length.or(lens.focal_length);
 
         let mut camera_matrix = lens.get_camera_matrix((params.width, params.height), invert_asym_lens);
+        camera_matrix[(0, 0)] *= focal_scale;
+        camera_matrix[(1, 1)] *= focal_scale;
         let mut distortion_coeffs = lens.get_distortion_coeffs();
 
         let mut radial_distortion_limit = lens.fisheye_params.radial_distortion_limit.unwrap_or_default();
@@ -141,8 +218,15 @@ impl FrameTransform {
         let background_margin = params.keyframes.value_at_video_timestamp(&KeyframeType::BackgroundMargin, timestamp_ms).unwrap_or(params.background_margin);
         let background_feather = params.keyframes.value_at_video_timestamp(&KeyframeType::BackgroundFeather, timestamp_ms).unwrap_or(params.background_margin_feather);
         let lens_correction_amount = params.keyframes.value_at_video_timestamp(&KeyframeType::LensCorrectionStrength, timestamp_ms).unwrap_or(params.lens_correction_amount);   // 1.0
-        let adaptive_zoom_center_x = params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterX, timestamp_ms).unwrap_or(params.adaptive_zoom_center_offset.0);
-        let mut adaptive_zoom_center_y = params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterY, timestamp_ms).unwrap_or(params.adaptive_zoom_center_offset.1);
+        let background = [
+            (KeyframeType::BackgroundColorR, params.background[0]),
+            (KeyframeType::BackgroundColorG, params.background[1]),
+            (KeyframeType::BackgroundColorB, params.background[2]),
+            (KeyframeType::BackgroundAlpha,  params.background[3]),
+        ].map(|(typ, v)| params.keyframes.value_at_video_timestamp(&typ, timestamp_ms).map(|x| x.clamp(0.0, 1.0) as f32).unwrap_or(v));
+        let center_scale = params.zoom_center_scale.get(frame).copied().unwrap_or(1.0); // Limited by the max zoom
+        let adaptive_zoom_center_x = params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterX, timestamp_ms).unwrap_or(params.adaptive_zoom_center_offset.0) * center_scale;
+        let mut adaptive_zoom_center_y = params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterY, timestamp_ms).unwrap_or(params.adaptive_zoom_center_offset.1) * center_scale;
 
         if video_rotation!=0.0 || background_margin!=0.0 || background_feather!=0.0 || adaptive_zoom_center_x!=0.0 || adaptive_zoom_center_y!=0.0 {
             log::debug!("Keyframes at timestamp: {timestamp_ms:.3}, rotation: {video_rotation:.3}, margin: {background_margin:.3}, feather: {background_feather:.3}, zoom center: ({adaptive_zoom_center_x:.3}, {adaptive_zoom_center_y:.3})");
@@ -165,18 +249,17 @@ impl FrameTransform {
             focal_length) = Self::get_lens_data_at_timestamp(params, timestamp_ms, false);
         // ----------- Lens -----------
 
-        let mut fov = Self::get_fov(params, frame, true, timestamp_ms, false);
+        let fov = Self::output_fov(params, frame, timestamp_ms);
         let mut ui_fov = Self::get_fov(params, frame, true, timestamp_ms, true);
         if let Some(adj) = params.lens.optimal_fov {
-            if params.fovs.is_empty() {
-                fov *= adj;
-            } else {
+            if !params.fovs.is_empty() {
                 ui_fov /= adj;
             }
         }
 
         let scaled_k = camera_matrix;
         let new_k = Self::get_new_k(&params, &camera_matrix, fov);
+        let horizon_compensation = Self::get_horizon_compensation(params, frame, true, timestamp_ms, params.framebuffer_inverted);
 
         let gyro = params.gyro.read();
         let file_metadata = gyro.file_metadata.read();
@@ -212,6 +295,7 @@ impl FrameTransform {
 
         let quat1 = gyro.org_quat_at_timestamp(timestamp_ms).inverse();
         let smoothed_quat1 = gyro.smoothed_quat_at_timestamp(timestamp_ms);
+        let residual_correction = params.residual_correction_at(timestamp_ms);
         
         // Only compute 1 matrix if not using rolling shutter correction
         let rows = if frame_readout_time.abs() > 0.0 { if params.frame_readout_direction.is_horizontal() { params.width } else { params.height } } else { 1 };
@@ -229,15 +313,9 @@ impl FrameTransform {
                      * quat1
                      * gyro.org_quat_at_timestamp(quat_time);
                      
-            let mut r = image_rotation * *quat.to_rotation_matrix().matrix();
-
-            // default is false.
-            if params.framebuffer_inverted {
-                r[(0, 2)] *= -1.0; r[(1, 2)] *= -1.0;
-                r[(2, 0)] *= -1.0; r[(2, 1)] *= -1.0;
-            } else {
-                r[(0, 1)] *= -1.0; r[(0, 2)] *= -1.0;
-                r[(1, 0)] *= -1.0; r[(2, 0)] *= -1.0;
+            let mut r = Self::kernel_rotation(params, &quat, &image_rotation, horizon_compensation.as_ref());
+            if let Some(c) = &residual_correction {
+                r = c * r;
             }
 
             // no data.
@@ -307,6 +385,7 @@ impl FrameTransform {
             background_mode:          params.background_mode as i32,
             background_margin:        background_margin as f32,
             background_margin_feather:background_feather as f32,
+            background,
             translation2d: [(adaptive_zoom_center_x * params.width as f64 / fov) as f32, (adaptive_zoom_center_y * params.height as f64 / fov) as f32],
             translation3d: [0.0, 0.0, 0.0, 0.0], // currently unused
             digital_lens_params,
@@ -325,6 +404,10 @@ impl FrameTransform {
     }
 
     pub fn at_timestamp_for_points(params: &ComputeParams, points: &[(f32, f32)], timestamp_ms: f64, frame: Option<usize>, use_fovs: bool) -> (Matrix3<f64>, [f64; 12], Matrix3<f64>, Vec<Matrix3<f64>>, Option<Vec<(f32, f32, f32, f32, f32)>>, Option<Vec<f64>>) { // camera_matrix, dist_coeffs, p, rotations_per_point
+        Self::at_timestamp_for_points_with_correction(params, points, timestamp_ms, frame, use_fovs, None)
+    }
+    /// Like `at_timestamp_for_points`, but with the `correction` (smoothed -> raw rotation at the frame center) instead of the one from the smoothed quaternions
+    pub fn at_timestamp_for_points_with_correction(params: &ComputeParams, points: &[(f32, f32)], timestamp_ms: f64, frame: Option<usize>, use_fovs: bool, correction: Option<&Quat64>) -> (Matrix3<f64>, [f64; 12], Matrix3<f64>, Vec<Matrix3<f64>>, Option<Vec<(f32, f32, f32, f32, f32)>>, Option<Vec<f64>>) {
         // ----------- Keyframes -----------
         let video_rotation = params.keyframes.value_at_video_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(params.video_rotation);
         // ----------- Keyframes -----------
@@ -337,6 +420,7 @@ impl FrameTransform {
 
         let scaled_k = camera_matrix;
         let new_k = Self::get_new_k(params, &camera_matrix, fov);
+        let horizon_compensation = Self::get_horizon_compensation(params, frame, use_fovs, timestamp_ms, false);
 
         let gyro = params.gyro.read();
         let file_metadata = gyro.file_metadata.read();
@@ -357,7 +441,8 @@ impl FrameTransform {
         let image_rotation = Matrix3::new_rotation(video_rotation * (std::f64::consts::PI / 180.0));
 
         let quat1 = gyro.org_quat_at_timestamp(timestamp_ms).inverse();
-        let smoothed_quat1 = gyro.smoothed_quat_at_timestamp(timestamp_ms);
+        let smoothed_quat1 = correction.copied().unwrap_or_else(|| gyro.smoothed_quat_at_timestamp(timestamp_ms));
+        let residual_correction = params.residual_correction_at(timestamp_ms);
 
         // Only compute 1 matrix if not using rolling shutter correction
         let points_iter = if frame_readout_time.abs() > 0.0 { points } else { &[(0.0, 0.0)] };
@@ -375,6 +460,13 @@ impl FrameTransform {
             let mut r = image_rotation * *quat.to_rotation_matrix().matrix();
             r[(0, 1)] *= -1.0; r[(0, 2)] *= -1.0;
             r[(1, 0)] *= -1.0; r[(2, 0)] *= -1.0;
+            Self::mirror_rotation(params, &mut r);
+            if let Some(c) = &horizon_compensation {
+                r = c * r;
+            }
+            if let Some(c) = &residual_correction {
+                r = c * r;
+            }
 
             if params.suppress_rotation {
                 r = Matrix3::identity();
@@ -411,3 +503,167 @@ impl FrameTransform {
         (scaled_k, distortion_coeffs, new_k, rotations, shifts, mesh_correction)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+    use crate::stabilization::distortion_models::DistortionModel;
+
+    // Level pinhole camera, 1080p, zooming from 0.8 to 1.6 FOV over 2 s
+    fn zoom_ramp() -> ComputeParams {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.height, params.output_width, params.output_height) = (1920, 1080, 1920, 1080);
+        params.scaled_fps = 30.0;
+        params.frame_readout_time = 0.0;
+        params.lens = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.0; 5],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        params.distortion_model = DistortionModel::from_name("opencv_standard");
+        params.keyframes.set(&KeyframeType::Fov, 0, 0.8);
+        params.keyframes.set(&KeyframeType::Fov, 2_000_000, 1.6);
+        params
+    }
+
+    // Output row in the middle of the frame, where the rays are level with the camera
+    fn horizon_row(params: &ComputeParams, frame: usize) -> f64 {
+        let m = FrameTransform::at_timestamp(params, frame as f64 * 1000.0 / params.scaled_fps, frame).matrices[0];
+        -(m[3] as f64 * params.output_width as f64 / 2.0 + m[5] as f64) / m[4] as f64
+    }
+
+    #[test]
+    fn horizon_compensation_with_zoom() {
+        let mut params = zoom_ramp();
+        assert!((0..=60).all(|frame| (horizon_row(&params, frame) - 540.0).abs() < 0.5));
+
+        params.horizon_compensation = (3.0, 0.0);
+        params.horizon_compensation_fov = 1.0;
+        let rows = (0..=60).map(|frame| horizon_row(&params, frame)).collect::<Vec<_>>();
+        // Moved by the pitch at the reference FOV and stays there while zooming
+        let offset = 1200.0 * 3.0_f64.to_radians().tan();
+        assert!(rows.iter().all(|row| ((row - 540.0).abs() - offset).abs() < 0.5), "{rows:?}");
+
+        // Keyframed
+        params.keyframes.set(&KeyframeType::HorizonCompensationPitch, 0, 0.0);
+        assert!((horizon_row(&params, 30) - 540.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn keyframed_background() {
+        let mut params = zoom_ramp();
+        params.background = nalgebra::Vector4::new(0.1, 0.2, 0.3, 1.0);
+        params.keyframes.set(&KeyframeType::BackgroundAlpha, 0, 1.0);
+        params.keyframes.set(&KeyframeType::BackgroundAlpha, 1_000_000, 0.0);
+        params.keyframes.set(&KeyframeType::BackgroundColorR, 0, 2.0);
+
+        let background = |ts: f64| FrameTransform::at_timestamp(&params, ts, 0).kernel_params.background;
+        assert_eq!(background(0.0), [1.0, 0.2, 0.3, 1.0]);
+        assert!((background(500.0)[3] - 0.5).abs() < 1e-6);
+        assert_eq!(background(1000.0)[3], 0.0);
+    }
+
+    #[test]
+    fn per_frame_focal_length() {
+        use crate::gyro_source::{ FileMetadata, LensParams };
+        let mut params = zoom_ramp();
+        // Calibrated at another focal length than the one the clip starts with
+        params.lens.focal_length = Some(20.0);
+        let fx = |params: &ComputeParams, ts: f64| FrameTransform::at_timestamp(params, ts, 0).kernel_params.f[0];
+        assert_eq!(fx(&params, 1000.0), 1200.0);
+
+        // Zooming from 24 to 48 mm during the shot, with the same sensor crop everywhere
+        let mut md = FileMetadata::default();
+        md.focal_lengths = [(0, 24.0), (1_000_000, 36.0), (2_000_000, 48.0)].into();
+        for ts in [0, 1_000_000, 2_000_000] {
+            md.lens_params.insert(ts, LensParams { sensor_size_px: Some((4000, 3000)), capture_area_size: Some((4000.0, 2000.0)), ..Default::default() });
+        }
+        assert_eq!(md.crop_at(1_020_000), Some(1.5));
+        assert_eq!(md.crop_at(1_500_000), None); // Too far from any sample
+        let stab = crate::StabilizationManager::default();
+        stab.gyro.write().file_metadata = md.clone().into();
+        let from_metadata = ComputeParams::from_manager(&stab);
+        assert_eq!(from_metadata.focal_lengths, md.focal_lengths);
+        assert!(from_metadata.crops.is_empty()); // Constant
+        params.focal_lengths = from_metadata.focal_lengths;
+
+        // Scaled from the focal length at the start
+        assert_eq!(fx(&params, 0.0), 1200.0);
+        assert_eq!(fx(&params, 500.0), 1200.0);
+        assert_eq!(fx(&params, 1000.0), 1800.0);
+        assert_eq!(fx(&params, 2000.0), 2400.0);
+        assert_eq!(FrameTransform::at_timestamp(&params, 2000.0, 0).focal_length, Some(48.0));
+
+        // A constant focal length isn't used per frame
+        md.focal_lengths = [(0, 28.0)].into();
+        stab.gyro.write().file_metadata = md.into();
+        assert!(ComputeParams::from_manager(&stab).focal_lengths.is_empty());
+
+        // Points used by the sync and the zooming get the same lens
+        let (camera_matrix, ..) = FrameTransform::at_timestamp_for_points(&params, &[(960.0, 540.0)], 2000.0, Some(0), false);
+        assert_eq!(camera_matrix[(0, 0)], 2400.0);
+
+        // Digital zoom to a 1.5x crop after 1 s
+        params.focal_lengths.clear();
+        params.crops = [(0, 1.0), (1_000_000, 1.5)].into();
+        assert_eq!(fx(&params, 0.0), 1200.0);
+        assert_eq!(fx(&params, 1000.0), 1800.0);
+    }
+
+    // Camera turning at 40°/s, a vertical line in the world captured with a 20 ms top to bottom readout
+    #[test]
+    fn rolling_shutter_direction() {
+        use crate::stabilization::undistort_points_with_rolling_shutter;
+        use crate::stabilization_params::ReadoutDirection;
+        let mut params = zoom_ramp();
+        params.keyframes = Default::default();
+        {
+            let axis = nalgebra::Unit::new_normalize(nalgebra::Vector3::new(1.0, 1.0, 1.0));
+            let mut gyro = params.gyro.write();
+            gyro.duration_ms = 2000.0;
+            gyro.quaternions = (0..=2000).map(|i| (i * 1000, Quat64::from_axis_angle(&axis, (i as f64 * 0.04).to_radians()))).collect();
+            gyro.smoothed_quaternions = [(0, Quat64::identity()), (2_000_000, Quat64::identity())].into();
+        }
+        let (timestamp_ms, readout_ms) = (1000.0, 20.0);
+        let rows = (0..=10).map(|i| i as f32 * 108.0).collect::<Vec<_>>();
+
+        // Each row shows the line where it was at the time the row was read
+        let line = rows.iter().map(|&y| {
+            let row_ts = timestamp_ms - readout_ms / 2.0 + readout_ms * y as f64 / 1080.0;
+            let world_x = |x: f32| undistort_points_with_rolling_shutter(&[(x, y)], row_ts, None, &params, 1.0, false)[0].0 - 960.0;
+            let mut x = 960.0;
+            for _ in 0..20 {
+                let d = (world_x(x + 0.5) - world_x(x - 0.5)).max(1e-3);
+                x -= world_x(x) / d;
+            }
+            (x, y)
+        }).collect::<Vec<_>>();
+        let raw_skew = line.iter().map(|p| p.0).fold(f32::MIN, f32::max) - line.iter().map(|p| p.0).fold(f32::MAX, f32::min);
+        assert!(raw_skew > 5.0, "{raw_skew}");
+
+        let residual_skew = |params: &ComputeParams| {
+            let xs = undistort_points_with_rolling_shutter(&line, timestamp_ms, None, params, 1.0, false).iter().map(|p| p.0).collect::<Vec<_>>();
+            xs.iter().copied().fold(f32::MIN, f32::max) - xs.iter().copied().fold(f32::MAX, f32::min)
+        };
+        params.frame_readout_time = readout_ms;
+        assert!(residual_skew(&params) < raw_skew * 0.05, "{} {raw_skew}", residual_skew(&params));
+        params.frame_readout_direction = ReadoutDirection::BottomToTop;
+        assert!(residual_skew(&params) > raw_skew * 1.5, "{} {raw_skew}", residual_skew(&params));
+
+        // Half strength leaves half of it
+        params.frame_readout_direction = ReadoutDirection::TopToBottom;
+        params.keyframes.set(&KeyframeType::RollingShutterStrength, 0, 0.5);
+        assert!((residual_skew(&params) / raw_skew - 0.5).abs() < 0.1, "{} {raw_skew}", residual_skew(&params));
+        assert_eq!(params.frame_readout_time_at(timestamp_ms), 10.0);
+
+        // The sync uses the same timing of the rows
+        assert_eq!(params.readout_fraction((0.0, 270.0), (1920, 1080)), 0.25);
+        params.frame_readout_direction = ReadoutDirection::BottomToTop;
+        assert_eq!(params.readout_fraction((0.0, 270.0), (1920, 1080)), 0.75);
+    }
+}
@@ -7,6 +7,7 @@ use std::cell::RefCell;
 use crate::GyroflowCoreError;
 
 use super::gpu::*;
+use super::frame_cache::{ CacheKind, Cached };
 use super::StabilizationManager;
 use drawing::DrawCanvas;
 
@@ -17,7 +18,7 @@ mod pixel_formats;
 // mod interpolation;
 pub mod distortion_models;
 pub use pixel_formats::*;
-pub use compute_params::ComputeParams;
+pub use compute_params::{ ComputeParams, ComputeToken };
 pub use frame_transform::FrameTransform;
 pub use cpu_undistort::*;
 
@@ -47,7 +48,7 @@ impl From<&str> for Interpolation {
     }
 }
 
-struct ThreadLocalWgpuCache(RefCell<lru::LruCache<u32, wgpu::WgpuWrapper>>);
+struct ThreadLocalWgpuCache(RefCell<lru::LruCache<u32, Cached<wgpu::WgpuWrapper>>>);
 impl Drop for ThreadLocalWgpuCache {
     fn drop(&mut self) {
         // Workaround for a Vulkan hang on device destroy (https://github.com/gfx-rs/wgpu/issues/4973)
@@ -62,7 +63,7 @@ lazy_static::lazy_static! {
 thread_local! {
     static CACHED_WGPU: ThreadLocalWgpuCache = ThreadLocalWgpuCache(RefCell::new(lru::LruCache::new(std::num::NonZeroUsize::new(15).unwrap())));
     #[cfg(feature = "use-opencl")]
-    static CACHED_OPENCL: RefCell<lru::LruCache<u32, opencl::OclWrapper>> = RefCell::new(lru::LruCache::new(std::num::NonZeroUsize::new(15).unwrap()));
+    static CACHED_OPENCL: RefCell<lru::LruCache<u32, Cached<opencl::OclWrapper>>> = RefCell::new(lru::LruCache::new(std::num::NonZeroUsize::new(15).unwrap()));
 }
 
 bitflags::bitflags! {
@@ -80,6 +81,7 @@ bitflags::bitflags! {
         const HAS_MESH_DATA        = 1 << 9; // 512
         const HAS_FPD_DATA         = 1 << 10; // 1024
         const ANY_UNDERWATER       = 1 << 11; // 2048
+        const TRANSPARENT_BACKGROUND = 1 << 12; // 4096
     }
 }
 
@@ -126,7 +128,7 @@ pub struct KernelParams {
     pub pixel_value_limit:        f32, // 16
     pub light_refraction_coefficient: f32, // 4
     pub plane_index:              i32, // 8
-    pub reserved1:                f32, // 12
+    pub alpha_channel:            i32, // 12 - component holding the alpha with a transparent background, -1 if none
     pub reserved2:                f32, // 16
     pub ewa_coeffs_p:             [f32; 4], // 16
     pub ewa_coeffs_q:             [f32; 4], // 16
@@ -158,12 +160,14 @@ pub struct Stabilization {
     pub output_size: (usize, usize), // width, height
 
     pub interpolation: Interpolation,
+    pub interpolation_override: Option<Interpolation>, // Used instead of `interpolation` by the degraded preview, which keeps the configured one
     pub kernel_flags: KernelParamsFlags,
+    pub alpha_channel: Option<usize>, // Component of the pixel which is the alpha, for `KernelParamsFlags::TRANSPARENT_BACKGROUND`
 
     #[cfg(feature = "use-opencl")]
-    cl: Option<opencl::OclWrapper>,
+    cl: Option<Cached<opencl::OclWrapper>>,
 
-    pub wgpu: Option<wgpu::WgpuWrapper>,
+    pub wgpu: Option<Cached<wgpu::WgpuWrapper>>,
 
     pub initialized_backend: BackendType,
 
@@ -190,6 +194,8 @@ impl Stabilization {
         self.stab_data.clear();
         self.compute_params = params;
     }
+    pub fn compute_params(&self) -> &ComputeParams { &self.compute_params }
+    pub fn active_interpolation(&self) -> Interpolation { self.interpolation_override.unwrap_or(self.interpolation) }
 
     fn get_rect(desc: &BufferDescription) -> [i32; 4] {
         let mut ret = [0i32; 4];
@@ -248,22 +254,28 @@ impl Stabilization {
             transform.kernel_params.pixel_value_limit = 1.0;
             transform.kernel_params.max_pixel_value = 1.0;
         }
-        transform.kernel_params.interpolation = self.interpolation as i32;
+        transform.kernel_params.interpolation = self.active_interpolation() as i32;
         transform.kernel_params.width  = self.size.0 as i32;
         transform.kernel_params.height = self.size.1 as i32;
         transform.kernel_params.output_width  = self.output_size.0 as i32;
         transform.kernel_params.output_height = self.output_size.1 as i32;
-        transform.kernel_params.background = [self.compute_params.background[0], self.compute_params.background[1], self.compute_params.background[2], self.compute_params.background[3]];
         transform.kernel_params.bytes_per_pixel = (T::COUNT * T::SCALAR_BYTES) as i32;
         transform.kernel_params.pix_element_count = T::COUNT as i32;
         transform.kernel_params.canvas_scale = self.drawing.scale as f32;
         transform.kernel_params.flags = self.get_kernel_flags(frame, buffers).bits();
+        transform.kernel_params.alpha_channel = self.alpha_channel.map(|x| x as i32).unwrap_or(-1);
+        if self.kernel_flags.contains(KernelParamsFlags::TRANSPARENT_BACKGROUND) {
+            // Nothing is covered outside of the source, also when the background alpha is keyframed
+            if let Some(a) = self.alpha_channel {
+                transform.kernel_params.background[a] = 0.0;
+            }
+        }
 
         transform.kernel_params.stride        = buffers.input.size.2 as i32;
         transform.kernel_params.output_stride = buffers.output.size.2 as i32;
 
         if transform.kernel_params.interpolation > 8 {  // default is 8.
-            let (b, c) = match self.interpolation {
+            let (b, c) = match self.active_interpolation() {
                 Interpolation::RobidouxSharp => (0.2620145, 0.3689927),
                 Interpolation::Robidoux      => (0.3782157, 0.3108921),
                 Interpolation::Mitchell      => (0.3333333, 0.3333333),
@@ -317,14 +329,14 @@ impl Stabilization {
             insert = false;
             if itm.kernel_params.stride        != buffers.input.size.2 as i32 ||
                itm.kernel_params.output_stride != buffers.output.size.2 as i32 {
-                log::warn!("Stride mismatch ({} != {} || {} != {})", itm.kernel_params.stride, buffers.input.size.2, itm.kernel_params.output_stride, buffers.output.size.2);
+                crate::log_throttled!(1000, log::Level::Warn, "Stride mismatch kernel_stride={} input_stride={} kernel_output_stride={} output_stride={}", itm.kernel_params.stride, buffers.input.size.2, itm.kernel_params.output_stride, buffers.output.size.2);
                 insert = true;
             }
             if itm.kernel_params.input_rotation != buffers.input.rotation.unwrap_or(0.0) ||
                itm.kernel_params.output_rotation != buffers.output.rotation.unwrap_or(0.0) ||
                itm.kernel_params.source_rect != Self::get_rect(&buffers.input) ||
                itm.kernel_params.output_rect != Self::get_rect(&buffers.output) {
-                log::warn!("Updating stab params at {timestamp_us}");
+                crate::log_throttled!(1000, log::Level::Warn, "Updating stab params timestamp_us={timestamp_us}");
                 insert = true;
             }
         }
@@ -346,11 +358,11 @@ impl Stabilization {
             buffers.get_checksum(),
             self.compute_params.distortion_model.id(),
             self.compute_params.digital_lens.as_ref().map(|x| x.id()).unwrap_or_default(),
-            self.interpolation as u32,
+            self.active_interpolation() as u32,
             flags.bits(),
             self.size,
             self.output_size,
-            self.interpolation,
+            self.active_interpolation(),
             std::thread::current().id(),
         )
     }
@@ -476,10 +488,11 @@ impl Stabilization {
                     });
                     match cl {
                         Ok(Ok(cl)) => {
+                            let size = cl.memory_size();
                             if self.share_wgpu_instances {
-                                CACHED_OPENCL.with(|x| x.borrow_mut().put(hash, cl));
+                                CACHED_OPENCL.with(|x| x.borrow_mut().put(hash, Cached::new(CacheKind::GpuBuffers, size, cl)));
                             } else {
-                                self.cl = Some(cl);
+                                self.cl = Some(Cached::new(CacheKind::GpuBuffers, size, cl));
                             }
                             self.initialized_backend = BackendType::OpenCL(hash);
                             log::info!("Initialized OpenCL for {:?} -> {:?}, key: {}", buffers.input.size, buffers.output.size, self.get_current_key(buffers));
@@ -519,10 +532,11 @@ impl Stabilization {
                     });
                     match wgpu {
                         Ok(Ok(wgpu)) => {
+                            let size = wgpu.memory_size();
                             if self.share_wgpu_instances {
-                                CACHED_WGPU.with(|x| x.0.borrow_mut().put(hash, wgpu));
+                                CACHED_WGPU.with(|x| x.0.borrow_mut().put(hash, Cached::new(CacheKind::GpuBuffers, size, wgpu)));
                             } else {
-                                self.wgpu = Some(wgpu);
+                                self.wgpu = Some(Cached::new(CacheKind::GpuBuffers, size, wgpu));
                             }
                             self.initialized_backend = BackendType::Wgpu(hash);
                             log::info!("Initialized wgpu for {:?} -> {:?} | key: {}", buffers.input.size, buffers.output.size, self.get_current_key(buffers));
@@ -550,6 +564,19 @@ impl Stabilization {
         }
     }
 
+    // Instances evicted by the frame cache are dropped on the thread which owns them
+    fn drop_evicted_instances() {
+        fn drop_evicted<T>(cache: &mut lru::LruCache<u32, Cached<T>>) {
+            let evicted = cache.iter().filter(|(_, v)| v.entry.is_evicted()).map(|(k, _)| *k).collect::<Vec<_>>();
+            for k in evicted {
+                cache.pop(&k);
+            }
+        }
+        CACHED_WGPU.with(|x| drop_evicted(&mut x.0.borrow_mut()));
+        #[cfg(feature = "use-opencl")]
+        CACHED_OPENCL.with(|x| drop_evicted(&mut x.borrow_mut()));
+    }
+
     pub fn ensure_ready_for_processing<T: PixelType>(&mut self, timestamp_us: i64, frame: Option<usize>, buffers: &mut Buffers) {
         let pending_dev = self.pending_device_change.clone();
         if let Some(dev) = self.pending_device_change.take() {
@@ -557,6 +584,19 @@ impl Stabilization {
             self.update_device(dev, buffers);
         }
 
+        if self.share_wgpu_instances {
+            Self::drop_evicted_instances();
+        }
+        // The own instance is only pinned while it's processing, between the frames it can be evicted. It's created again then
+        #[cfg(feature = "use-opencl")]
+        if self.cl.as_ref().is_some_and(|x| x.entry.is_evicted()) {
+            self.cl = None;
+            self.initialized_backend = BackendType::None;
+        }
+        if self.wgpu.as_ref().is_some_and(|x| x.entry.is_evicted()) {
+            self.wgpu = None;
+            self.initialized_backend = BackendType::None;
+        }
         self.init_backends::<T>(timestamp_us, frame, buffers);
         self.ensure_stab_data_at_timestamp::<T>(timestamp_us, frame, buffers, false);
 
@@ -635,6 +675,7 @@ impl Stabilization {
                         return CACHED_OPENCL.with(|x| {
                             let mut cached = x.borrow_mut();
                             if let Some(cl) = cached.get(&hash) {
+                                let _pin = cl.entry.pin();
                                 if let Err(err) = cl.undistort_image(buffers, &itm, drawing_buffer) {
                                     log::error!("OpenCL error undistort: {:?}", err);
                                 }
@@ -647,6 +688,7 @@ impl Stabilization {
                     }
                 } else {
                     if let Some(ref cl) = self.cl {
+                        let _pin = cl.entry.pin();
                         if let Err(err) = cl.undistort_image(buffers, &itm, drawing_buffer) {
                             log::error!("OpenCL error undistort: {:?}", err);
                         } else {
@@ -666,6 +708,7 @@ impl Stabilization {
                         return CACHED_WGPU.with(|x| {
                             let mut cached = x.0.borrow_mut();
                             if let Some(wgpu) = cached.get(&hash) {
+                                let _pin = wgpu.entry.pin();
                                 wgpu.undistort_image(buffers, &itm, drawing_buffer);
                                 ret.backend = "wgpu";
                                 Ok(ret)
@@ -678,6 +721,7 @@ impl Stabilization {
                     }
                 } else {
                     if let Some(ref wgpu) = self.wgpu {
+                        let _pin = wgpu.entry.pin();
                         wgpu.undistort_image(buffers, &itm, drawing_buffer);
                         ret.backend = "wgpu";
                         return Ok(ret);
@@ -689,7 +733,7 @@ impl Stabilization {
 
             //let ok = Self::undistort_image_cpu_spirv::<T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), &itm.matrices, drawing_buffer);
             // CPU path
-            let ok = match self.interpolation {
+            let ok = match self.active_interpolation() {
                 Interpolation::Bilinear => { Self::undistort_image_cpu::<2, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), &itm.matrices, drawing_buffer, &itm.mesh_data) },
                 Interpolation::Bicubic  => { Self::undistort_image_cpu::<4, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), &itm.matrices, drawing_buffer, &itm.mesh_data) },
                 Interpolation::Lanczos4 => { Self::undistort_image_cpu::<8, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), &itm.matrices, drawing_buffer, &itm.mesh_data) },
@@ -703,7 +747,7 @@ impl Stabilization {
                 return Ok(ret);
             }
         } else {
-            log::warn!("No stab data at {timestamp_us}");
+            crate::log_throttled!(1000, log::Level::Warn, "No stab data timestamp_us={timestamp_us}");
             return Err(GyroflowCoreError::NoStabilizationData(timestamp_us));
         }
         Err(GyroflowCoreError::Unknown)
@@ -71,21 +71,33 @@ pub struct StabilizationParams {
 
     pub frame_readout_time: f64,
     pub frame_readout_direction: ReadoutDirection,
+    pub frame_readout_time_override: Option<f64>, // ms, takes precedence over the value from the lens profile or the metadata
+    pub frame_readout_direction_override: Option<ReadoutDirection>,
+    pub rolling_shutter_strength: f64, // Multiplier of the readout time, 0 - 2
+    pub residual_corrections: crate::synchronization::ResidualCorrections, // From the residual wobble analysis, see `PoseEstimator::residual_wobble`
+    pub residual_correction_strength: f64, // 0 - 2
     pub adaptive_zoom_window: f64,
+    pub adaptive_zoom_look_ahead: f64, // s, 0 for the default
+    pub static_zoom_percentile: f64, // Static zoom covers this % of frames
     pub adaptive_zoom_center_offset: (f64, f64),
     pub adaptive_zoom_method: i32,
     pub additional_rotation: (f64, f64, f64),
     pub additional_translation: (f64, f64, f64),
+    pub horizon_compensation: (f64, f64), // Pitch, roll in degrees, at `horizon_compensation_fov`
+    pub horizon_compensation_fov: f64,
     pub fov: f64,
     pub fov_overview: bool,
     pub max_zoom: Option<f64>,
     pub max_zoom_iterations: usize,
+    pub limit_to_zoom_budget: bool, // Limit the correction so there are no black corners without zooming
+    pub deterministic: bool, // Bit-identical sync and zoom results between runs, see `StabilizationManager::set_deterministic`
     pub show_safe_area: bool,
     pub fovs: Vec<f64>,
     pub minimal_fovs: Vec<f64>,
     pub min_fov: f64,
     pub fps: f64,
     pub fps_scale: Option<f64>,
+    pub keyframe_quantization: bool,
     pub video_speed: f64,
     pub video_speed_affects_smoothing: bool,
     pub video_speed_affects_zooming: bool,
@@ -101,6 +113,7 @@ pub struct StabilizationParams {
 
     pub lens_correction_amount: f64,
     pub light_refraction_coefficient: f64,
+    pub lens_flip: (bool, bool), // Horizontal, vertical. The clip is mirrored relative to the sensor
     pub background_mode: BackgroundMode,
     pub background_margin: f64,
     pub background_margin_feather: f64,
@@ -115,7 +128,9 @@ pub struct StabilizationParams {
     pub of_method: u32,
     pub current_device: i32,
 
-    pub zooming_debug_points: std::collections::BTreeMap<i64, Vec<(f64, f64)>>
+    pub zooming_debug_points: std::collections::BTreeMap<i64, Vec<(f64, f64)>>,
+    pub zoom_overflow: Vec<bool>, // Per frame, the zoom limit shows the outside of the image
+    pub zoom_center_scale: Vec<f64>, // Per frame, fraction of the zooming center offset which fits within the zoom limit
 }
 impl Default for StabilizationParams {
     fn default() -> Self {
@@ -131,12 +146,18 @@ impl Default for StabilizationParams {
             show_optical_flow: true,
             frame_readout_time: 0.0,
             frame_readout_direction: ReadoutDirection::TopToBottom,
+            frame_readout_time_override: None,
+            frame_readout_direction_override: None,
             adaptive_zoom_window: 4.0,
+            adaptive_zoom_look_ahead: 0.0,
+            static_zoom_percentile: 100.0,
             adaptive_zoom_center_offset: (0.0, 0.0),
             adaptive_zoom_method: 1,
 
             additional_rotation: (0.0, 0.0, 0.0),
             additional_translation: (0.0, 0.0, 0.0),
+            horizon_compensation: (0.0, 0.0),
+            horizon_compensation_fov: 1.0,
 
             size: (0, 0),
             output_size: (0, 0),
@@ -145,9 +166,13 @@ impl Default for StabilizationParams {
 
             max_zoom: Some(130.0),
             max_zoom_iterations: 5,
+            limit_to_zoom_budget: true,
 
             lens_correction_amount: 1.0,
             light_refraction_coefficient: 1.0,
+            rolling_shutter_strength: 1.0,
+            residual_corrections: BTreeMap::new(),
+            residual_correction_strength: 1.0,
             background_mode: BackgroundMode::SolidColor,
             background_margin: 0.0,
             background_margin_feather: 0.0,
@@ -158,6 +183,8 @@ impl Default for StabilizationParams {
             trim_ranges: Vec::new(),
 
             zooming_debug_points: BTreeMap::new(),
+            zoom_overflow: Vec::new(),
+            zoom_center_scale: Vec::new(),
 
             background: Vector4::new(0.0, 0.0, 0.0, 0.0),
 
@@ -167,6 +194,7 @@ impl Default for StabilizationParams {
 
             fps: 0.0,
             fps_scale: None,
+            keyframe_quantization: false,
             video_speed: 1.0,
             video_speed_affects_smoothing: true,
             video_speed_affects_zooming: true,
@@ -180,6 +208,11 @@ impl Default for StabilizationParams {
 }
 
 impl StabilizationParams {
+    /// Index of the first trim range shorter than one frame, such range can't be rendered
+    pub fn trim_range_shorter_than_frame(&self) -> Option<usize> {
+        let frame_ms = 1000.0 / self.fps.max(0.001);
+        self.trim_ranges.iter().position(|x| (x.1 - x.0) * self.duration_ms < frame_ms - 0.001)
+    }
     pub fn get_trim_ratio(&self) -> f64 {
         if self.trim_ranges.is_empty() {
             1.0
@@ -267,6 +300,14 @@ impl StabilizationParams {
         timestamp_us
     }
 
+    /// Frame readout time in ms (always positive) and the readout direction, with the overrides applied
+    pub fn effective_readout(&self) -> (f64, ReadoutDirection) {
+        (
+            self.frame_readout_time_override.unwrap_or(self.frame_readout_time).abs(),
+            self.frame_readout_direction_override.unwrap_or(self.frame_readout_direction)
+        )
+    }
+
     pub fn clear(&mut self) {
         *self = StabilizationParams {
             stab_enabled:              self.stab_enabled,
@@ -274,8 +315,12 @@ impl StabilizationParams {
             show_optical_flow:         self.show_optical_flow,
             background:                self.background,
             adaptive_zoom_window:      self.adaptive_zoom_window,
+            adaptive_zoom_look_ahead:  self.adaptive_zoom_look_ahead,
+            static_zoom_percentile:    self.static_zoom_percentile,
             framebuffer_inverted:      self.framebuffer_inverted,
             lens_correction_amount:    self.lens_correction_amount,
+            rolling_shutter_strength:  self.rolling_shutter_strength,
+            residual_correction_strength: self.residual_correction_strength,
             video_speed:               self.video_speed,
             video_speed_affects_smoothing: self.video_speed_affects_smoothing,
             video_speed_affects_zooming:   self.video_speed_affects_zooming,
@@ -291,7 +336,40 @@ impl StabilizationParams {
             show_safe_area:            self.show_safe_area,
             max_zoom:                  self.max_zoom,
             max_zoom_iterations:       self.max_zoom_iterations,
+            keyframe_quantization:     self.keyframe_quantization,
+            limit_to_zoom_budget:      self.limit_to_zoom_budget,
+            deterministic:             self.deterministic,
             ..Self::default()
         };
     }
 }
+
+/// Sorts the trim ranges and merges the ones which touch or overlap, so every part of the clip is in the output only once
+pub fn merge_trim_ranges(mut ranges: Vec<(f64, f64)>) -> Vec<(f64, f64)> {
+    ranges.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut ret: Vec<(f64, f64)> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match ret.last_mut() {
+            Some(last) if range.0 <= last.1 => last.1 = last.1.max(range.1),
+            _ => ret.push(range)
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_ranges() {
+        assert_eq!(merge_trim_ranges(vec![(0.6, 0.8), (0.1, 0.3), (0.3, 0.4), (0.7, 0.9), (0.75, 0.8)]), vec![(0.1, 0.4), (0.6, 0.9)]);
+        assert!(merge_trim_ranges(Vec::new()).is_empty());
+
+        let mut params = StabilizationParams { fps: 30.0, duration_ms: 10_000.0, ..Default::default() };
+        params.trim_ranges = vec![(0.0, 0.1), (0.5, 0.5 + 1.0 / 300.0)];
+        assert_eq!(params.trim_range_shorter_than_frame(), None);
+        params.trim_ranges.push((0.8, 0.8 + 0.5 / 300.0));
+        assert_eq!(params.trim_range_shorter_than_frame(), Some(2));
+    }
+}
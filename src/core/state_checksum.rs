@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Hashes of everything that affects the output, split into sections. Each parameter belongs to exactly one section,
+// and the caches are keyed on the sections they depend on (`smoothing_key`, `zooming_key`), so a change can't be missed
+// by forgetting to invalidate the right cache. Render jobs keep the checksum they were queued with, see `StateChecksum::changed`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use crate::StabilizationManager;
+use crate::keyframes::KeyframeManager;
+use crate::lens_profile::LensProfile;
+use crate::smoothing::Smoothing;
+use crate::stabilization_params::StabilizationParams;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StateChecksum {
+    pub lens: u64,      // Lens profile, lens correction and rolling shutter
+    pub gyro: u64,      // Motion data, IMU transforms, offsets and time mapping
+    pub smoothing: u64, // Algorithm and its parameters, horizon and axis lock, frame rate, video speed and additional rotation
+    pub keyframes: u64,
+    pub zooming: u64,   // FOV, adaptive zoom, max zoom, horizon compensation and additional translation
+    pub trim: u64,
+    pub output: u64,    // Input and output size, background
+}
+
+impl StateChecksum {
+    pub const SECTIONS: [&'static str; 7] = ["lens", "gyro", "smoothing", "keyframes", "zooming", "trim", "output"];
+
+    pub fn from_manager(stab: &StabilizationManager) -> Self {
+        let params = stab.params.read();
+        Self {
+            lens:      lens(&stab.lens.read(), &params),
+            gyro:      stab.gyro.read().get_checksum(),
+            smoothing: smoothing(&stab.smoothing.read(), &params),
+            keyframes: keyframes(&stab.keyframes.read()),
+            zooming:   zooming(&params),
+            trim:      trim(&params),
+            output:    output(&params),
+        }
+    }
+
+    fn sections(&self) -> [u64; 7] {
+        [self.lens, self.gyro, self.smoothing, self.keyframes, self.zooming, self.trim, self.output]
+    }
+
+    /// Of the whole state
+    pub fn total(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for x in self.sections() { hasher.write_u64(x); }
+        hasher.finish()
+    }
+
+    /// Key of the smoothed orientation. The lens is included because the zoom budget limits the correction
+    pub fn smoothing_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for x in [self.lens, self.gyro, self.smoothing, self.keyframes, self.trim] { hasher.write_u64(x); }
+        hasher.finish()
+    }
+
+    /// Key of the adaptive zoom, which is computed from the smoothed orientation
+    pub fn zooming_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for x in [self.smoothing_key(), self.zooming, self.output] { hasher.write_u64(x); }
+        hasher.finish()
+    }
+
+    /// Names of the sections which differ
+    pub fn changed(&self, other: &Self) -> Vec<&'static str> {
+        Self::SECTIONS.iter().zip(self.sections().iter().zip(other.sections().iter())).filter(|(_, (a, b))| a != b).map(|(name, _)| *name).collect()
+    }
+}
+
+fn hash_of<T: serde::Serialize>(v: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    if let Ok(v) = bincode::serialize(v) { hasher.write(&v); }
+    hasher.finish()
+}
+
+pub fn lens(lens: &LensProfile, params: &StabilizationParams) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    if let Ok(mut v) = lens.get_json_value() {
+        // Descriptive fields and the sync settings don't change the output
+        if let Some(obj) = v.as_object_mut() {
+            for key in ["name", "note", "calibrated_by", "camera_brand", "camera_model", "lens_model", "camera_setting", "identifier", "calibrator_version", "date", "official", "compatible_settings", "sync_settings"] {
+                obj.remove(key);
+            }
+        }
+        hasher.write(v.to_string().as_bytes());
+    }
+    hasher.write_u64(hash_of(&(
+        params.lens_correction_amount,
+        params.light_refraction_coefficient,
+        params.lens_flip,
+        params.frame_readout_time,
+        params.frame_readout_direction as u8,
+        params.frame_readout_time_override,
+        params.frame_readout_direction_override.map(|x| x as u8),
+        params.rolling_shutter_strength,
+    )));
+    hasher.finish()
+}
+
+pub fn smoothing(smoothing: &Smoothing, params: &StabilizationParams) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(smoothing.get_state_checksum(0));
+    hasher.write_u64(hash_of(&(
+        params.fps,
+        params.fps_scale,
+        params.video_speed,
+        params.video_speed_affects_smoothing,
+        &params.speed_ramped_timestamps,
+        params.video_rotation,
+        params.additional_rotation,
+        params.limit_to_zoom_budget,
+        // Applied on top of the smoothed orientation, but the zoom has to cover it
+        &params.residual_corrections,
+        params.residual_correction_strength,
+    )));
+    hasher.finish()
+}
+
+pub fn keyframes(keyframes: &KeyframeManager) -> u64 {
+    hash_of(keyframes)
+}
+
+pub fn zooming(params: &StabilizationParams) -> u64 {
+    hash_of(&(
+        params.fov,
+        params.adaptive_zoom_window,
+        params.adaptive_zoom_look_ahead,
+        params.static_zoom_percentile,
+        params.adaptive_zoom_center_offset,
+        params.adaptive_zoom_method,
+        params.max_zoom,
+        params.max_zoom_iterations,
+        params.video_speed_affects_zooming,
+        params.video_speed_affects_zooming_limit,
+        params.horizon_compensation,
+        params.horizon_compensation_fov,
+        params.additional_translation,
+    ))
+}
+
+pub fn trim(params: &StabilizationParams) -> u64 {
+    hash_of(&params.trim_ranges)
+}
+
+pub fn output(params: &StabilizationParams) -> u64 {
+    hash_of(&(
+        params.size,
+        params.output_size,
+        params.frame_count,
+        params.duration_ms,
+        params.stab_enabled,
+        params.background,
+        params.background_mode as u8,
+        params.background_margin,
+        params.background_margin_feather,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyframes::KeyframeType;
+
+    #[test]
+    fn sections() {
+        let stab = StabilizationManager::default();
+        {
+            let mut params = stab.params.write();
+            params.size = (1920, 1080);
+            params.output_size = (1920, 1080);
+            params.fps = 30.0;
+        }
+        let check = |name: &str, change: &dyn Fn(&StabilizationManager), expected: &[&str]| {
+            let before = StateChecksum::from_manager(&stab);
+            assert_eq!(before, StateChecksum::from_manager(&stab), "{name}: not stable");
+            change(&stab);
+            let after = StateChecksum::from_manager(&stab);
+            assert_eq!(before.changed(&after), expected.to_vec(), "{name}");
+            assert_eq!(before.total() != after.total(), !expected.is_empty(), "{name}");
+
+            // The zoom is keyed on everything, the smoothing on everything but the zoom settings and the output
+            let smoothing_expected = expected.iter().any(|x| !["zooming", "output"].contains(x));
+            assert_eq!(before.smoothing_key() != after.smoothing_key(), smoothing_expected, "{name}");
+            assert_eq!(before.zooming_key() != after.zooming_key(), !expected.is_empty(), "{name}");
+        };
+        check("lens correction",   &|s| s.set_lens_correction_amount(0.5), &["lens"]);
+        check("lens stretch",      &|s| s.set_input_horizontal_stretch(1.33), &["lens"]);
+        check("lens coefficients", &|s| s.lens.write().fisheye_params.distortion_coeffs = vec![0.1, 0.0, 0.0, 0.0], &["lens"]);
+        check("readout time",      &|s| s.set_frame_readout_time(20.0), &["lens"]);
+        check("lens name",         &|s| s.lens.write().name = "Other".into(), &[]);
+        check("sync settings",     &|s| s.lens.write().sync_settings = Some(serde_json::json!({ "search_size": 5 })), &[]);
+        check("gyro offset",       &|s| s.gyro.write().set_offset(0, 12.0), &["gyro"]);
+        check("imu orientation",   &|s| s.gyro.write().imu_transforms.imu_orientation = Some("YxZ".into()), &["gyro"]);
+        check("smoothing param",   &|s| s.set_smoothing_param("smoothness", 0.123), &["smoothing"]);
+        check("horizon lock",      &|s| s.set_horizon_lock(50.0, 0.0), &["smoothing"]);
+        check("video rotation",    &|s| s.set_video_rotation(90.0), &["smoothing"]);
+        check("frame rate",        &|s| s.params.write().fps_scale = Some(2.0), &["smoothing"]);
+        check("residual wobble",   &|s| s.set_residual_correction_strength(0.5), &["smoothing"]);
+        check("keyframe",          &|s| s.set_keyframe(&KeyframeType::Fov, 0, 1.2), &["keyframes"]);
+        check("fov",               &|s| s.set_fov(1.2), &["zooming"]);
+        check("zoom look ahead",   &|s| s.set_adaptive_zoom_look_ahead(2.0), &["zooming"]);
+        check("max zoom",          &|s| s.set_max_zoom(150.0, 3), &["zooming"]);
+        check("translation",       &|s| s.set_additional_translation_x(0.1), &["zooming"]);
+        check("trim",              &|s| s.set_trim_ranges(vec![(0.1, 0.9)]), &["trim"]);
+        check("output size",       &|s| s.params.write().output_size = (1280, 720), &["output"]);
+        check("background",        &|s| s.set_background_color(nalgebra::Vector4::new(1.0, 0.0, 0.0, 1.0)), &["output"]);
+        check("preview only",      &|s| { s.set_fov_overview(true); s.set_show_safe_area(true); }, &[]);
+    }
+}
@@ -11,13 +11,18 @@ use crate::StabilizationManager;
 use crate::stabilization::ComputeParams;
 use super::PoseEstimator;
 use super::SyncParams;
+use super::{ SyncError, PartialSync };
+
+// Length of each sampled point when checking if the motion data matches the video
+const CONSISTENCY_TIME_PER_POINT_MS: f64 = 200.0;
 
 pub struct AutosyncProcess {
     frame_count: usize,
     scaled_fps: f64,
     org_fps: f64,
+    duration_ms: f64,
     fps_scale: Option<f64>,
-    mode: String, // synchronize, guess_imu_orientation, estimate_rolling_shutter
+    mode: String, // synchronize, guess_imu_orientation, estimate_rolling_shutter, validate_lens, check_consistency, residual_wobble
     ranges_us: Vec<(i64, i64)>,
     scaled_ranges_us: Vec<(i64, i64)>,
     estimator: Arc<PoseEstimator>,
@@ -26,7 +31,10 @@ pub struct AutosyncProcess {
     compute_params: Arc<RwLock<ComputeParams>>,
     cancel_flag: Arc<AtomicBool>,
     progress_cb: Option<Arc<Box<dyn Fn(f64, usize, usize) + Send + Sync + 'static>>>,
-    finished_cb: Option<Arc<Box<dyn Fn(Either<Vec<(f64, f64, f64)>, Option<(String, f64)>>) + Send + Sync + 'static>>>,
+    finished_cb: Option<Arc<Box<dyn Fn(Either<Result<Vec<(f64, f64, f64)>, PartialSync>, Result<(String, f64), SyncError>>) + Send + Sync + 'static>>>,
+    lens_validation_cb: Option<Arc<Box<dyn Fn(Option<super::LensValidationReport>) + Send + Sync + 'static>>>,
+    consistency_cb: Option<Arc<Box<dyn Fn(super::ConsistencyReport) + Send + Sync + 'static>>>,
+    residual_wobble_cb: Option<Arc<Box<dyn Fn(super::ResidualWobbleResult) + Send + Sync + 'static>>>,
 
     sync_params: SyncParams,
 
@@ -41,52 +49,102 @@ impl AutosyncProcess {
         let org_duration_ms = params.duration_ms;
         let fps_scale = params.fps_scale;
         let duration_ms = params.get_scaled_duration_ms();
+        let deterministic = params.deterministic;
 
         let SyncParams {
             search_size,
             mut time_per_syncpoint,
-            every_nth_frame,
+            mut every_nth_frame,
             ..
         } = sync_params;
+        let residual_wobble = mode == "residual_wobble";
 
+        if mode == "check_consistency" {
+            // Only a few frames at each point for the motion activity
+            time_per_syncpoint = CONSISTENCY_TIME_PER_POINT_MS;
+        }
+        if residual_wobble {
+            // The optical flow is needed between all consecutive frames
+            every_nth_frame = 1;
+        }
         if let Some(scale) = &fps_scale {
             time_per_syncpoint *= scale;
         }
-        let frame_count = ((timestamps_fract.len() as f64 * (time_per_syncpoint / 1000.0) * org_fps).ceil() as usize).min(params.frame_count) / every_nth_frame as usize;
+        let mut frame_count = ((timestamps_fract.len() as f64 * (time_per_syncpoint / 1000.0) * org_fps).ceil() as usize).min(params.frame_count) / every_nth_frame as usize;
+
+        let mut ranges_us: Vec<(i64, i64)> = if residual_wobble {
+            // The timestamps are the start and end of each range
+            timestamps_fract.chunks_exact(2).map(|x| (
+                (x[0].clamp(0.0, 1.0) * org_duration_ms * 1000.0).round() as i64,
+                (x[1].clamp(0.0, 1.0) * org_duration_ms * 1000.0).round() as i64
+            )).filter(|(from, to)| to > from).collect()
+        } else {
+            timestamps_fract.iter().map(|x| {
+                let range = (
+                    ((x * org_duration_ms) - (time_per_syncpoint / 2.0)).max(0.0),
+                    ((x * org_duration_ms) + (time_per_syncpoint / 2.0)).min(org_duration_ms)
+                );
+                ((range.0 * 1000.0).round() as i64, (range.1 * 1000.0).round() as i64)
+            }).collect()
+        };
+        if residual_wobble {
+            frame_count = ((ranges_us.iter().map(|(from, to)| to - from).sum::<i64>() as f64 / 1_000_000.0 * org_fps).ceil() as usize).min(params.frame_count);
+        }
 
         drop(params);
 
         if duration_ms < 10.0 || frame_count < 2 || time_per_syncpoint < 10.0 || search_size < 10.0 { return Err(()); }
 
-        let mut ranges_us: Vec<(i64, i64)> = timestamps_fract.iter().map(|x| {
-            let range = (
-                ((x * org_duration_ms) - (time_per_syncpoint / 2.0)).max(0.0),
-                ((x * org_duration_ms) + (time_per_syncpoint / 2.0)).min(org_duration_ms)
-            );
-            ((range.0 * 1000.0).round() as i64, (range.1 * 1000.0).round() as i64)
-        }).collect();
-
         if mode == "synchronize" && !stab.gyro.read().has_motion() {
             // If no gyro data in file, analyze the entire video
             ranges_us.clear();
             ranges_us.push((0, (org_duration_ms * 1000.0).round() as i64));
         }
+        if deterministic {
+            // Same sync points in the same order, however they were added
+            ranges_us.sort();
+            ranges_us.dedup();
+        }
 
         let scaled_ranges_us = ranges_us.iter().map(|(f, t)| (
             (*f as f64 / fps_scale.unwrap_or(1.0)) as i64,
             (*t as f64 / fps_scale.unwrap_or(1.0)) as i64)
         ).collect();
 
-        let estimator = stab.pose_estimator.clone();
+        let estimator = if mode == "check_consistency" || residual_wobble {
+            // Separate, so the sampled frames don't end up in the sync results and the chart
+            Arc::new(PoseEstimator::default())
+        } else {
+            stab.pose_estimator.clone()
+        };
 
         estimator.every_nth_frame.store(every_nth_frame.max(1) as u32, SeqCst);
         estimator.offset_method.store(sync_params.offset_method as u32, SeqCst);
         estimator.pose_method.store(sync_params.pose_method as u32, SeqCst);
 
         let mut comp_params = ComputeParams::from_manager(stab);
-        comp_params.keyframes.clear();
+        if !residual_wobble {
+            comp_params.keyframes.clear();
+        }
+        // The residual wobble is estimated from the gyro stabilization alone, with the keyframes of the project
+        comp_params.residual_corrections.clear();
         // Make sure we apply full correction for autosync
         comp_params.lens_correction_amount = 1.0;
+        {
+            let gyro = stab.gyro.read();
+            let stream = sync_params.gyro_stream.filter(|s| *s != gyro.gyro_stream && gyro.secondary_gyro.is_some());
+            // Sync in video time, so the offsets are independent of the time mapping
+            if stream.is_some() || !gyro.time_mapping.is_identity() {
+                let mut sync_gyro = match stream {
+                    Some(stream) => gyro.with_gyro_stream(stream),
+                    None => gyro.clone()
+                };
+                if !sync_gyro.time_mapping.is_identity() {
+                    sync_gyro = sync_gyro.in_video_time();
+                }
+                comp_params.gyro = Arc::new(RwLock::new(sync_gyro));
+            }
+        }
 
         let thread_pool = rayon::ThreadPoolBuilder::new()
             .thread_name(move |i| format!("Sync {}", i))
@@ -106,6 +164,7 @@ impl AutosyncProcess {
             frame_count,
             org_fps,
             scaled_fps,
+            duration_ms,
             sync_params,
             mode,
             ranges_us,
@@ -116,6 +175,9 @@ impl AutosyncProcess {
             total_detected_frames: Arc::new(AtomicUsize::new(0)),
             compute_params: Arc::new(RwLock::new(comp_params)),
             finished_cb: None,
+            lens_validation_cb: None,
+            consistency_cb: None,
+            residual_wobble_cb: None,
             progress_cb: None,
             cancel_flag,
             thread_pool
@@ -248,35 +310,42 @@ impl AutosyncProcess {
             }
         };
 
-        if let Some(cb) = &self.finished_cb {
+        let mut consistency = None;
+        let mut residual_wobble = None;
+        if self.mode == "validate_lens" {
+            if let Some(cb) = &self.lens_validation_cb {
+                cb(super::validate_lens(&self.estimator, &scaled_ranges_us, &self.compute_params.read()));
+            }
+        } else if self.mode == "check_consistency" {
+            let motion = super::video_motion(&self.estimator, &scaled_ranges_us);
+            let compute_params = self.compute_params.read();
+            let report = super::check_consistency(&compute_params.gyro.read(), self.duration_ms, self.org_fps, self.fps_scale, &motion, &self.sync_params);
+            log::info!("Motion data consistency: {report:?}");
+            consistency = Some(report);
+        } else if self.mode == "residual_wobble" {
+            if !self.cancel_flag.load(Relaxed) {
+                let result = self.estimator.residual_wobble(&scaled_ranges_us, &self.compute_params.read());
+                log::info!("Residual wobble: {} frame pairs ({} skipped), correction RMS: {:.3}°, max: {:.3}°", result.pairs, result.skipped_pairs, result.rms_deg, result.max_deg);
+                residual_wobble = Some(result);
+            }
+        } else if let Some(cb) = &self.finished_cb {
             if self.mode == "estimate_rolling_shutter" {
                 use super::find_offset::visual_features::find_offsets;
                 cb(Either::Left(find_offsets(&self.estimator, &scaled_ranges_us, &self.sync_params, &self.compute_params.read(), true, progress_cb2, self.cancel_flag.clone())));
             } else if self.mode == "guess_imu_orientation" {
                 use super::find_offset::rs_sync::FindOffsetsRssync;
-                let guessed = FindOffsetsRssync::new(&scaled_ranges_us, self.estimator.sync_results.clone(), &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()).guess_orient();
-                if !self.cancel_flag.load(SeqCst) {
-                    cb(Either::Right(guessed));
-                }
+                let guessed = FindOffsetsRssync::new(&scaled_ranges_us, self.estimator.sync_results.clone(), &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone())
+                    .and_then(|mut x| x.guess_orient());
+                cb(Either::Right(guessed));
             } else {
                 let offsets = self.estimator.find_offsets(&scaled_ranges_us, &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone());
-                if check_negative {
+                if check_negative && !matches!(&offsets, Err(e) if e.error == SyncError::Cancelled) {
                     for_negative.store(true, SeqCst);
                     // Try also negative rough offset
                     let mut sync_params = self.sync_params.clone();
                     sync_params.initial_offset = -sync_params.initial_offset;
                     let offsets2 = self.estimator.find_offsets(&scaled_ranges_us, &sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone());
-                    if offsets2.len() > offsets.len() {
-                        cb(Either::Left(offsets2));
-                    } else if offsets2.len() == offsets.len() {
-                        let sum1: f64 = offsets.iter().map(|(_, _, cost)| *cost).sum();
-                        let sum2: f64 = offsets2.iter().map(|(_, _, cost)| *cost).sum();
-                        if sum1 < sum2 {
-                            cb(Either::Left(offsets));
-                        } else {
-                            cb(Either::Left(offsets2));
-                        }
-                    }
+                    cb(Either::Left(Self::better_offsets(offsets, offsets2)));
                 } else {
                     cb(Either::Left(offsets));
                 }
@@ -286,8 +355,15 @@ impl AutosyncProcess {
             let len = self.total_detected_frames.load(SeqCst);
             cb(1.0, len, len);
         }
+        // After the progress, so the frontend can start the sync right away
+        if let (Some(report), Some(cb)) = (consistency, &self.consistency_cb) {
+            cb(report);
+        }
+        if let (Some(result), Some(cb)) = (residual_wobble, &self.residual_wobble_cb) {
+            cb(result);
+        }
 
-        log::info!("autosync::finished_feeding_frames, 同步处理完成 - FPS: {}, 缩放FPS: {}, 处理的帧数: {}, 同步点数: {}", 
+        log::info!("Finished feeding frames fps={} scaled_fps={} frames={} sync_points={}", 
             self.org_fps,
             self.scaled_fps,
             self.total_read_frames.load(SeqCst),
@@ -295,10 +371,31 @@ impl AutosyncProcess {
         );
     }
 
+    // More sync points wins, then the lower total cost
+    fn better_offsets(a: Result<Vec<(f64, f64, f64)>, PartialSync>, b: Result<Vec<(f64, f64, f64)>, PartialSync>) -> Result<Vec<(f64, f64, f64)>, PartialSync> {
+        match (a, b) {
+            (Ok(a), Ok(b)) => {
+                let cost = |x: &[(f64, f64, f64)]| -> f64 { x.iter().map(|(_, _, cost)| *cost).sum() };
+                if b.len() > a.len() || (b.len() == a.len() && cost(&b) <= cost(&a)) { Ok(b) } else { Ok(a) }
+            },
+            (Err(e), Ok(x)) | (Ok(x), Err(e)) if e.error != SyncError::Cancelled => Ok(x),
+            (_, b) => b,
+        }
+    }
+
     pub fn on_progress<F>(&mut self, cb: F) where F: Fn(f64, usize, usize) + Send + Sync + 'static {
         self.progress_cb = Some(Arc::new(Box::new(cb)));
     }
-    pub fn on_finished<F>(&mut self, cb: F) where F:  Fn(Either<Vec<(f64, f64, f64)>, Option<(String, f64)>>) + Send + Sync + 'static {
+    pub fn on_lens_validation<F>(&mut self, cb: F) where F: Fn(Option<super::LensValidationReport>) + Send + Sync + 'static {
+        self.lens_validation_cb = Some(Arc::new(Box::new(cb)));
+    }
+    pub fn on_consistency_check<F>(&mut self, cb: F) where F: Fn(super::ConsistencyReport) + Send + Sync + 'static {
+        self.consistency_cb = Some(Arc::new(Box::new(cb)));
+    }
+    pub fn on_residual_wobble<F>(&mut self, cb: F) where F: Fn(super::ResidualWobbleResult) + Send + Sync + 'static {
+        self.residual_wobble_cb = Some(Arc::new(Box::new(cb)));
+    }
+    pub fn on_finished<F>(&mut self, cb: F) where F:  Fn(Either<Result<Vec<(f64, f64, f64)>, PartialSync>, Result<(String, f64), SyncError>>) + Send + Sync + 'static {
         self.finished_cb = Some(Arc::new(Box::new(cb)));
     }
 }
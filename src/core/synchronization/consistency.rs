@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Check if the motion data belongs to the video at all, before running the sync.
+// A log from a different take either doesn't cover the video or has different motion, so the sync fails or finds a confident but wrong offset.
+// The durations are compared first (the gyro can be longer, eg. a blackbox log running before and after the recording),
+// then the activity profiles: the angular velocity magnitude from the gyro and the optical flow magnitude from a sparse sampling of frames.
+// Both are proportional to the amount of rotation, so they correlate at the right offset even without a lens profile
+
+use crate::gyro_source::GyroSource;
+use super::{ PoseEstimator, SyncParams };
+
+#[derive(Default, Clone, Debug, serde::Serialize)]
+pub struct ConsistencyReport {
+    pub score: f64, // 0 - consistent, 1 - most likely a different recording
+    pub mismatch: bool,
+    pub reasons: Vec<String>,
+    pub gyro_duration_ms: f64,
+    pub video_duration_ms: f64,
+    pub duration_ratio: f64,
+    pub suggested_fps: Option<f64>,         // When the durations match only at the frame rate from the metadata
+    pub correlation_in_window: Option<f64>, // Best correlation of the activity within the search window
+    pub best_correlation: Option<f64>,      // Best correlation within `MAX_OFFSET_MS`
+    pub best_offset_ms: Option<f64>,
+    pub suggested_offset_ms: Option<f64>,   // When the correlation peak is strong and outside the search window
+}
+
+const MAX_OFFSET_MS: f64 = 60000.0;
+const OFFSET_STEP_MS: f64 = 20.0;
+const BIN_MS: f64 = 10.0;
+const MIN_SAMPLES: usize = 8;
+// Correlation of the activity profiles
+const NO_CORRELATION: f64 = 0.3;
+const STRONG_CORRELATION: f64 = 0.5;
+
+/// Optical flow magnitude of the analyzed frame pairs in `ranges_us`: (timestamp_ms, median displacement in frame widths per second)
+pub fn video_motion(estimator: &PoseEstimator, ranges_us: &[(i64, i64)]) -> Vec<(f64, f64)> {
+    let sync_results = estimator.sync_results.read();
+    sync_results.iter().filter_map(|(ts, fr)| {
+        if !ranges_us.iter().any(|(from, to)| (*from..=*to).contains(ts)) || fr.frame_size.0 == 0 { return None; }
+        let Some(Some(((ts1, pts1), (ts2, pts2)))) = fr.optical_flow.try_borrow().ok().and_then(|x| x.get(&1).cloned()) else { return None; };
+        if pts1.is_empty() || pts1.len() != pts2.len() || ts2 <= ts1 { return None; }
+        let mut displacements = pts1.iter().zip(pts2.iter()).map(|(a, b)| ((b.0 - a.0) as f64).hypot((b.1 - a.1) as f64)).collect::<Vec<_>>();
+        displacements.sort_by(|a, b| a.total_cmp(b));
+        let median = displacements[displacements.len() / 2];
+        let dt_s = (ts2 - ts1) as f64 / 1_000_000.0;
+        Some(((ts1 + ts2) as f64 / 2000.0, median / fr.frame_size.0 as f64 / dt_s))
+    }).collect()
+}
+
+/// `duration_ms` and the timestamps in `video_motion` are in the scaled video time, the same as the sync ranges.
+/// `fps_scale` is the frame rate override, if any
+pub fn check_consistency(gyro: &GyroSource, duration_ms: f64, fps: f64, fps_scale: Option<f64>, video_motion: &[(f64, f64)], sync_params: &SyncParams) -> ConsistencyReport {
+    let file_metadata = gyro.file_metadata.read();
+    let raw_imu = gyro.raw_imu(&file_metadata);
+    // Angular velocity magnitude in deg/s
+    let activity: Vec<(f64, f64)> = if !raw_imu.is_empty() {
+        raw_imu.iter().filter_map(|x| Some((x.timestamp_ms, x.gyro.map(|g| (g[0] * g[0] + g[1] * g[1] + g[2] * g[2]).sqrt())?))).collect()
+    } else {
+        file_metadata.quaternions.iter().zip(file_metadata.quaternions.iter().skip(1)).filter_map(|((t1, q1), (t2, q2))| {
+            let dt_s = (t2 - t1) as f64 / 1_000_000.0;
+            if dt_s <= 0.0 { return None; }
+            Some(((t1 + t2) as f64 / 2000.0, q1.angle_to(q2).to_degrees() / dt_s))
+        }).collect()
+    };
+    // The video is conformed to a different frame rate, but the frame rate wasn't overridden to match
+    let conform_ratio = file_metadata.frame_rate.filter(|md_fps| fps_scale.is_none() && *md_fps > 0.0 && (md_fps - fps).abs() > 1.0).map(|md_fps| (md_fps, fps / md_fps));
+    drop(file_metadata);
+
+    evaluate(&activity, duration_ms, conform_ratio, video_motion, sync_params.initial_offset, sync_params.search_size)
+}
+
+fn evaluate(activity: &[(f64, f64)], video_duration_ms: f64, conform_ratio: Option<(f64, f64)>, video_motion: &[(f64, f64)], initial_offset_ms: f64, search_size_ms: f64) -> ConsistencyReport {
+    let mut report = ConsistencyReport { video_duration_ms, ..Default::default() };
+    if activity.len() < 2 || video_duration_ms <= 0.0 {
+        report.score = 1.0;
+        report.mismatch = true;
+        report.reasons.push("No motion data".into());
+        return report;
+    }
+    let gyro_start = activity[0].0;
+    report.gyro_duration_ms = activity[activity.len() - 1].0 - gyro_start;
+
+    // ----------- Duration -----------
+    let mut ratio = report.gyro_duration_ms / video_duration_ms;
+    if let Some((md_fps, conform_ratio)) = conform_ratio {
+        let conformed = report.gyro_duration_ms / (video_duration_ms * conform_ratio);
+        if (conformed - 1.0).abs() < 0.05 && (ratio - 1.0).abs() > 0.1 {
+            report.reasons.push(format!("Video seems to be conformed from {md_fps:.2} fps, the motion data matches the video duration at that frame rate"));
+            report.suggested_fps = Some(md_fps);
+            ratio = conformed;
+        }
+    }
+    report.duration_ratio = ratio;
+    let duration_score = if ratio < 0.9 {
+        report.reasons.push(format!("Motion data covers only {:.0}% of the video duration", ratio * 100.0));
+        ((0.9 - ratio) / 0.4).min(1.0)
+    } else if ratio > 1.5 {
+        // Logs recorded separately are often longer, so this alone isn't a mismatch
+        report.reasons.push(format!("Motion data is {ratio:.1}× longer than the video"));
+        ((ratio - 1.5) / 3.0).min(1.0) * 0.6
+    } else {
+        0.0
+    };
+
+    // ----------- Motion activity -----------
+    let motion_score = if video_motion.len() < MIN_SAMPLES {
+        report.reasons.push("Not enough video frames to compare the motion".into());
+        0.0
+    } else if std_dev(&video_motion.iter().map(|x| x.1).collect::<Vec<_>>()) < 1e-3 {
+        report.reasons.push("Not enough motion in the video to compare".into());
+        0.0
+    } else {
+        let bins = bin_activity(activity, gyro_start);
+        // Same convention as the sync, the gyro is sampled at `video timestamp - offset`
+        let correlation_at = |offset_ms: f64| -> Option<f64> {
+            let (v, g): (Vec<f64>, Vec<f64>) = video_motion.iter().filter_map(|(ts, m)| {
+                let i = ((ts - offset_ms - gyro_start) / BIN_MS).round();
+                if i < 0.0 { return None; }
+                Some((*m, *bins.get(i as usize)?))
+            }).unzip();
+            if v.len() < MIN_SAMPLES.max(video_motion.len() / 2) { return None; }
+            pearson(&v, &g)
+        };
+        let steps = (MAX_OFFSET_MS / OFFSET_STEP_MS) as i64;
+        let correlations = (-steps..=steps).filter_map(|i| {
+            let offset = initial_offset_ms + i as f64 * OFFSET_STEP_MS;
+            Some((offset, correlation_at(offset)?))
+        }).collect::<Vec<_>>();
+        let best = |pred: &dyn Fn(f64) -> bool| correlations.iter().filter(|x| pred(x.0)).copied().max_by(|a, b| a.1.total_cmp(&b.1));
+        let in_window = |offset: f64| (offset - initial_offset_ms).abs() <= search_size_ms;
+
+        let best_all = best(&|_| true);
+        let best_in_window = best(&in_window);
+        report.best_correlation = best_all.map(|x| x.1);
+        report.best_offset_ms = best_all.map(|x| x.0);
+        report.correlation_in_window = best_in_window.map(|x| x.1);
+
+        match best_all {
+            None => {
+                report.reasons.push("Motion data doesn't overlap with the video at any offset".into());
+                1.0
+            },
+            Some((_, c)) if c < NO_CORRELATION => {
+                report.reasons.push(format!("Motion patterns do not correlate at any offset within ±{:.0} s", MAX_OFFSET_MS / 1000.0));
+                1.0
+            },
+            Some((offset, c)) if !in_window(offset) && c >= STRONG_CORRELATION && c > best_in_window.map_or(0.0, |x| x.1) + 0.1 => {
+                report.reasons.push(format!("Motion correlates best at {:.2} s offset, outside of the search window", offset / 1000.0));
+                report.suggested_offset_ms = Some(offset);
+                0.6
+            },
+            _ => {
+                let c = best_in_window.map_or(0.0, |x| x.1);
+                if c < STRONG_CORRELATION {
+                    report.reasons.push(format!("Motion patterns correlate weakly within the search window ({c:.2})"));
+                }
+                ((STRONG_CORRELATION - c) / (STRONG_CORRELATION - 0.1)).clamp(0.0, 1.0)
+            }
+        }
+    };
+
+    report.score = duration_score.max(motion_score);
+    report.mismatch = report.score >= 0.5;
+    report
+}
+
+// Average in `BIN_MS` bins from `start`, smoothed over a few bins. Empty bins take the previous value
+fn bin_activity(activity: &[(f64, f64)], start: f64) -> Vec<f64> {
+    let len = ((activity[activity.len() - 1].0 - start) / BIN_MS) as usize + 1;
+    let mut sums = vec![(0.0, 0usize); len];
+    for (ts, v) in activity {
+        let i = (((ts - start) / BIN_MS) as usize).min(len - 1);
+        sums[i].0 += v;
+        sums[i].1 += 1;
+    }
+    let mut last = 0.0;
+    let bins = sums.iter().map(|(sum, n)| { if *n > 0 { last = sum / *n as f64; } last }).collect::<Vec<_>>();
+    let half = 2;
+    (0..len).map(|i| {
+        let w = &bins[i.saturating_sub(half)..(i + half + 1).min(len)];
+        w.iter().sum::<f64>() / w.len() as f64
+    }).collect()
+}
+
+fn std_dev(v: &[f64]) -> f64 {
+    let mean = v.iter().sum::<f64>() / v.len().max(1) as f64;
+    (v.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / v.len().max(1) as f64).sqrt()
+}
+
+fn pearson(a: &[f64], b: &[f64]) -> Option<f64> {
+    let n = a.len() as f64;
+    let (ma, mb) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut cov, mut va, mut vb) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b.iter()) {
+        cov += (x - ma) * (y - mb);
+        va += (x - ma).powi(2);
+        vb += (y - mb).powi(2);
+    }
+    let var = (va * vb).sqrt();
+    if var > 1e-12 { Some(cov / var) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bursts of rotation at irregular times, deg/s
+    fn speed(t_ms: f64) -> f64 {
+        let t = t_ms / 1000.0;
+        50.0 * ((t * 0.7).sin() * (t * 1.9).cos()).abs() + 30.0 * (t * 3.1 + (t * 0.5).sin() * 4.0).sin().abs()
+    }
+    fn gyro(duration_ms: f64) -> Vec<(f64, f64)> {
+        (0..(duration_ms as usize / 5)).map(|i| (i as f64 * 5.0, speed(i as f64 * 5.0))).collect()
+    }
+    // Sparse samples of the video, the gyro at `ts - offset`
+    fn video(duration_ms: f64, offset_ms: f64) -> Vec<(f64, f64)> {
+        (0..40).map(|i| {
+            let ts = (i as f64 + 0.5) * duration_ms / 40.0;
+            (ts, speed(ts - offset_ms) * 0.004)
+        }).collect()
+    }
+
+    #[test]
+    fn matching() {
+        let r = evaluate(&gyro(120000.0), 100000.0, None, &video(100000.0, 2000.0), 0.0, 5000.0);
+        assert!(!r.mismatch, "{r:?}");
+        assert!((r.best_offset_ms.unwrap() - 2000.0).abs() <= OFFSET_STEP_MS, "{r:?}");
+        assert!(r.suggested_offset_ms.is_none());
+        assert!(r.correlation_in_window.unwrap() > 0.9);
+    }
+
+    #[test]
+    fn offset_outside_of_window() {
+        let r = evaluate(&gyro(150000.0), 100000.0, None, &video(100000.0, 30000.0), 0.0, 5000.0);
+        assert!(r.mismatch, "{r:?}");
+        assert!((r.suggested_offset_ms.unwrap() - 30000.0).abs() <= OFFSET_STEP_MS, "{r:?}");
+    }
+
+    #[test]
+    fn different_take() {
+        let other = (0..40).map(|i| ((i as f64 + 0.5) * 2500.0, ((i * 7919) % 13) as f64)).collect::<Vec<_>>();
+        let r = evaluate(&gyro(100000.0), 100000.0, None, &other, 0.0, 5000.0);
+        assert!(r.mismatch, "{r:?}");
+        assert!(r.best_correlation.unwrap() < NO_CORRELATION + 0.2);
+
+        let r = evaluate(&gyro(30000.0), 100000.0, None, &video(100000.0, 0.0), 0.0, 5000.0);
+        assert!(r.mismatch, "{r:?}");
+        assert!(r.reasons[0].contains("30%"), "{r:?}");
+    }
+
+    #[test]
+    fn conformed() {
+        // 120 fps played at 30 fps, the gyro is in real time
+        let r = evaluate(&gyro(25000.0), 100000.0, Some((120.0, 0.25)), &[], 0.0, 5000.0);
+        assert_eq!(r.suggested_fps, Some(120.0));
+        assert!(!r.mismatch, "{r:?}");
+    }
+}
@@ -196,7 +196,12 @@ fn solve_ypr_ransac(field: &[MotionEntry], camera: &Camera, timestamp_ms: f64, n
     let mut best_inliers = vec![];
     let target_delta = target_delta.to_radians();
 
-    let rng = &mut rand::rng();
+    // In the deterministic mode the samples depend only on the frame, not on the thread it was processed on
+    let rng = &mut if camera.compute_params.deterministic {
+        StdRng::seed_from_u64(timestamp_ms.to_bits())
+    } else {
+        StdRng::from_rng(&mut rand::rng())
+    };
 
     for _ in 0..num_iters {
         let samples = field.choose_multiple(rng, 3).copied().collect::<Vec<_>>();
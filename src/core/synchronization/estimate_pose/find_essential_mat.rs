@@ -33,6 +33,10 @@ impl EstimatePoseTrait for PoseFindEssentialMat {
 
             let identity = Mat::eye(3, 3, opencv::core::CV_64F)?;
 
+            if params.deterministic {
+                // The RNG of OpenCV is per thread. Seeded from the frame, the result doesn't depend on the thread the frame was processed on
+                opencv::core::set_rng_seed(timestamp_us as i32)?;
+            }
             let mut mask = Mat::default();
             let e = opencv::calib3d::find_essential_mat(&a1_pts, &a2_pts, &identity, opencv::calib3d::LMEDS, 0.999, 0.00001, 4000, &mut mask)?;
 
@@ -35,6 +35,10 @@ impl EstimatePoseTrait for PoseFindHomography {
 
             let mut inliers = Mat::default();
 
+            if params.deterministic {
+                // Per thread RNG, see `PoseFindEssentialMat`
+                opencv::core::set_rng_seed(timestamp_us as i32)?;
+            }
             let homography = opencv::calib3d::find_homography_ext(&a1_pts, &a2_pts, opencv::calib3d::RANSAC, 0.001, &mut inliers, 2000, 0.999)?;
 
             let mut r: Vector<Mat> = Default::default();
@@ -1,8 +1,8 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
-use super::super::{ PoseEstimator, OpticalFlowPoints, FrameResult, SyncParams };
-use crate::gyro_source::{ Quat64, TimeQuat, GyroSource };
+use super::super::{ PoseEstimator, OpticalFlowPoints, FrameResult, SyncParams, SyncError, PartialSync };
+use crate::gyro_source::{ Quat64, GyroSource };
 use crate::stabilization::{ undistort_points_for_optical_flow, ComputeParams };
 use nalgebra::Vector3;
 use rs_sync::SyncProblem;
@@ -14,7 +14,7 @@ use std::sync::{
     Arc,
 };
 
-pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
+pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Result<Vec<(f64, f64, f64)>, PartialSync> { // Vec<(timestamp, offset, cost)>
     let offsets = [(3403.4, 49.07149195073893, 2469.454571794784), (10210.2, 49.02560488334704, 1687.1520000469884), (17000.3165, 49.38418205303837, 1856.5180621988166), (23790.4335, 50.2977951166321, 1681.702319836711), (30597.2335, 50.46544919417218, 3068.1429766153424)].to_vec();
 
     /*
@@ -39,8 +39,7 @@ pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64
             }
         }
 
-        let offsets = super::essential_matrix::find_offsets(estimator, &ranges, &sync_params, params, &progress_cb, cancel_flag.clone());
-        if !offsets.is_empty() {
+        if let Ok(offsets) = super::essential_matrix::find_offsets(estimator, &ranges, &sync_params, params, &progress_cb, cancel_flag.clone()) {
             let median_offset = median(offsets.iter().map(|x| x.1).collect());
             sync_params.initial_offset = median_offset;
             sync_params.initial_offset_inv = false;
@@ -49,19 +48,21 @@ pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64
         }
     }
 
-    let offsets = FindOffsetsRssync::new(ranges, estimator.sync_results.clone(), &sync_params, params, progress_cb, cancel_flag).full_sync();
+    let offsets = FindOffsetsRssync::new(ranges, estimator.sync_results.clone(), &sync_params, params, progress_cb, cancel_flag)?.full_sync()?;
     */
     log::info!("rs-sync::find_offsets completed, offsets: {:?}", offsets);
-    offsets
+    Ok(offsets)
 }
 
 pub struct FindOffsetsRssync<'a> {
     sync: SyncProblem<'a>,
     gyro_source: Arc<RwLock<GyroSource>>,
-    frame_readout_time: f64,
     sync_points: Vec::<(i64, i64)>,
+    readout_times: Vec<f64>, // s, of each sync point
     sync_params: &'a SyncParams,
     is_guess_orient: Arc<AtomicBool>,
+    cancel_flag: Arc<AtomicBool>,
+    skipped_error: Option<SyncError>, // Why the last of the skipped ranges had no usable data
 
     current_sync_point: Arc<AtomicUsize>,
     current_orientation: Arc<AtomicUsize>
@@ -75,7 +76,10 @@ impl FindOffsetsRssync<'_> {
         params: &'a ComputeParams,
         progress_cb: F,
         cancel_flag: Arc<AtomicBool>,
-    ) -> FindOffsetsRssync<'a> {
+    ) -> Result<FindOffsetsRssync<'a>, SyncError> {
+        if params.gyro.read().quaternions.is_empty() {
+            return Err(SyncError::NoGyroData);
+        }
         let matched_points = Self::collect_points(sync_results, ranges);
 
         // used to handle the rolling shutter effect. It represents the time required for the camera sensor to scan the entire frame from start to finish.
@@ -91,10 +95,12 @@ impl FindOffsetsRssync<'_> {
         let mut ret = FindOffsetsRssync {
             sync: SyncProblem::new(),
             gyro_source: params.gyro.clone(),
-            frame_readout_time: frame_readout_time,
             sync_points: Vec::new(),
+            readout_times: Vec::new(),
             sync_params,
             is_guess_orient: Arc::new(AtomicBool::new(false)),
+            cancel_flag: cancel_flag.clone(),
+            skipped_error: None,
             current_sync_point: Arc::new(AtomicUsize::new(0)),
             current_orientation: Arc::new(AtomicUsize::new(0))
         };
@@ -112,9 +118,10 @@ impl FindOffsetsRssync<'_> {
             });
         }
 
-        for range in matched_points {
+        for (range, range_ts) in matched_points.into_iter().zip(ranges) {
             if range.len() < 2 {
                 log::warn!("Not enough data for sync! range.len: {}", range.len());
+                ret.skipped_error = Some(SyncError::NotEnoughFeatures { range: *range_ts, found: range.len(), needed: 2 });
                 continue;
             }
 
@@ -135,13 +142,18 @@ impl FindOffsetsRssync<'_> {
                 let mut tss_a = Vec::new();
                 let mut tss_b = Vec::new();
 
-                assert!(a.len() == b.len());
+                if a.len() != b.len() || a.len() != a_p.len() || b.len() != b_p.len() || frame_size.1 == 0 {
+                    log::warn!("Invalid point pairs {} {} at {a_t}", a.len(), b.len());
+                    continue;
+                }
 
-                // perform rolling shutter time compensation for of feature points
-                let height = frame_size.1 as f64;
-                for (i, (ap, bp)) in a.iter().zip(b.iter()).enumerate() {
-                    let ts_a = a_t as f64 / 1000_000.0 + frame_readout_time * (a_p[i].1 as f64 / height);
-                    let ts_b = b_t as f64 / 1000_000.0 + frame_readout_time * (b_p[i].1 as f64 / height);
+                // perform rolling shutter time compensation for of feature points, the same way as the kernels do
+                let size = (frame_size.0 as usize, frame_size.1 as usize);
+                let readout_a = frame_readout_time * params.rolling_shutter_strength_at(a_t as f64 / 1000.0);
+                let readout_b = frame_readout_time * params.rolling_shutter_strength_at(b_t as f64 / 1000.0);
+                for (((ap, bp), a_p), b_p) in a.iter().zip(b.iter()).zip(a_p.iter()).zip(b_p.iter()) {
+                    let ts_a = a_t as f64 / 1000_000.0 + readout_a * params.readout_fraction((a_p.0 as f64, a_p.1 as f64), size);
+                    let ts_b = b_t as f64 / 1000_000.0 + readout_b * params.readout_fraction((b_p.0 as f64, b_p.1 as f64), size);
 
                     let ap = Vector3::new(ap.0 as f64, ap.1 as f64, 1.0).normalize();
                     let bp = Vector3::new(bp.0 as f64, bp.1 as f64, 1.0).normalize();
@@ -156,21 +168,30 @@ impl FindOffsetsRssync<'_> {
                 ret.sync.set_track_result(a_t, &tss_a, &tss_b, &points3d_a, &points3d_b);
             }
             ret.sync_points.push((from_ts, to_ts));
+            ret.readout_times.push(frame_readout_time * params.rolling_shutter_strength_at((from_ts + to_ts) as f64 / 2000.0));
 
         }
-        ret
+        if ret.sync_points.is_empty() {
+            return Err(ret.skipped_error.unwrap_or_else(|| SyncError::Internal("No sync points".into())));
+        }
+        Ok(ret)
     }
 
-    pub fn full_sync(&mut self) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
+    pub fn full_sync(&mut self) -> Result<Vec<(f64, f64, f64)>, PartialSync> { // Vec<(timestamp, offset, cost)>
         self.is_guess_orient.store(false, SeqCst);
 
         let mut offsets = Vec::new();
+        let mut last_error = self.skipped_error.clone();
         {
             let gyro = self.gyro_source.read();
-            set_quats(&mut self.sync, &gyro.quaternions);
+            match gyro.resampled_quaternions() {
+                Some(resampled) => set_quats(&mut self.sync, resampled.iter()),
+                None => set_quats(&mut self.sync, gyro.quaternions.iter().map(|(ts, q)| (*ts, q)))
+            }
         }
 
-        for (from_ts, to_ts) in &self.sync_points {
+        for ((from_ts, to_ts), readout_time) in self.sync_points.iter().zip(&self.readout_times) {
+            if self.cancel_flag.load(Relaxed) { break; }
 
             let presync_step = 3.0;
             let presync_radius = self.sync_params.search_size;
@@ -187,26 +208,36 @@ impl FindOffsetsRssync<'_> {
                 let offset = delay.1 * 1000.0;
                 // Only accept offsets that are within 90% of search size range
                 if (offset - initial_delay).abs() < presync_radius * 0.9 {
-                    let offset = -offset - (self.frame_readout_time * 1000.0 / 2.0);
+                    let offset = -offset - (readout_time * 1000.0 / 2.0);
                     offsets.push(((from_ts + to_ts) as f64 / 2.0 / 1000.0, offset, delay.0));
                 } else {
                     log::warn!("Sync point out of acceptable range {} < {}", (offset - initial_delay).abs(), presync_radius * 0.9);
+                    last_error = Some(SyncError::OutOfSearchRange);
                 }
+            } else if !self.cancel_flag.load(Relaxed) {
+                last_error = Some(SyncError::Internal(format!("No solution for the range {from_ts} - {to_ts}")));
             }
             self.current_sync_point.fetch_add(1, SeqCst);
         }
-        log::info!("rs-sync::full_sync 同步完成 - 处理了 {} 个匹配点, 时间范围: {}s - {}s", 
-            self.sync_points.len(),
-            self.sync_points[0].0 as f64 / 1000.0 / 1000.0,
-            self.sync_points[self.sync_points.len() - 1].1 as f64 / 1000.0 / 1000.0
-        );
-        offsets
+        if let (Some(first), Some(last)) = (self.sync_points.first(), self.sync_points.last()) {
+            log::info!("Full sync finished sync_points={} start_s={} end_s={}",
+                self.sync_points.len(),
+                first.0 as f64 / 1000.0 / 1000.0,
+                last.1 as f64 / 1000.0 / 1000.0
+            );
+        }
+        PartialSync::result(offsets, self.cancel_flag.load(Relaxed), last_error)
     }
 
-    pub fn guess_orient(&mut self) -> Option<(String, f64)> {
+    pub fn guess_orient(&mut self) -> Result<(String, f64), SyncError> {
         self.is_guess_orient.store(true, SeqCst);
 
-        let mut clone_source = self.gyro_source.read().clone();
+        // Snapshot, so the lock isn't held while trying all the orientations
+        let source = self.gyro_source.read().orientation_source();
+
+        // Only the gyro data around the sync points is needed, with enough margin for the search
+        let margin_ms = self.sync_params.initial_offset.abs() + self.sync_params.search_size + 1000.0;
+        let ranges_ms = self.sync_points.iter().map(|(from_ts, to_ts)| (*from_ts as f64 / 1000.0 - margin_ms, *to_ts as f64 / 1000.0 + margin_ms)).collect::<Vec<_>>();
 
         let possible_orientations = [
             "YxZ", "Xyz", "XZy", "Zxy", "zyX", "yxZ", "ZXY", "zYx", "ZYX", "yXz", "YZX", "XyZ",
@@ -215,26 +246,30 @@ impl FindOffsetsRssync<'_> {
             "Xzy", "XzY", "YzX", "Zyx", "XZY", "yxz", "xzY", "ZyX", "YXZ", "yXZ", "YZx", "ZXy"
         ];
 
-        possible_orientations.iter().map(|orient| {
-            clone_source.imu_transforms.imu_orientation = Some(orient.to_string());
-            clone_source.apply_transforms();
-
-            set_quats(&mut self.sync, &clone_source.quaternions);
+        let best = possible_orientations.iter().filter_map(|orient| {
+            if self.cancel_flag.load(Relaxed) { return None; }
+            set_quats(&mut self.sync, source.quats(orient, Some(&ranges_ms)).iter().map(|(ts, q)| (*ts, q)));
 
-            let total_cost: f64 = self.sync_points.iter().map(|(from_ts, to_ts)| {
+            // An orientation is only comparable if all the sync points have a solution
+            let total_cost: Option<f64> = self.sync_points.iter().map(|(from_ts, to_ts)| {
                 self.sync.pre_sync(
                     -self.sync_params.initial_offset / 1000.0,
                     *from_ts,
                     *to_ts,
                     3.0 / 1000.0,
                     self.sync_params.search_size / 1000.0
-                ).unwrap_or((0.0,0.0))
-            }).map(|v| {v.0}).sum();
+                ).map(|v| v.0)
+            }).sum();
 
             self.current_orientation.fetch_add(1, SeqCst);
 
-            (orient.to_string(), total_cost)
-        }).reduce(|a: (String, f64), b: (String, f64)| -> (String, f64) { if a.1 < b.1 { a } else { b } })
+            Some((orient.to_string(), total_cost?))
+        }).reduce(|a: (String, f64), b: (String, f64)| -> (String, f64) { if a.1 < b.1 { a } else { b } });
+
+        if self.cancel_flag.load(Relaxed) {
+            return Err(SyncError::Cancelled);
+        }
+        best.ok_or_else(|| SyncError::Internal("No orientation has a solution for all the sync points".into()))
     }
 
     fn collect_points(sync_results: Arc<RwLock<BTreeMap<i64, FrameResult>>>, ranges: &[(i64, i64)]) -> Vec<Vec<(((i64, OpticalFlowPoints), (i64, OpticalFlowPoints)), (u32, u32))>> {
@@ -259,7 +294,7 @@ impl FindOffsetsRssync<'_> {
 
 }
 
-fn set_quats(sync: &mut SyncProblem, source_quats: &TimeQuat) {
+fn set_quats<'a>(sync: &mut SyncProblem, source_quats: impl Iterator<Item = (i64, &'a Quat64)>) {
     let mut quats = Vec::new();
     let mut timestamps = Vec::new();
     let rotation = *Quat64::from_scaled_axis(Vector3::new(PI, 0.0, 0.0)).quaternion();
@@ -270,7 +305,7 @@ fn set_quats(sync: &mut SyncProblem, source_quats: &TimeQuat) {
 
         // The expected quaternion format for the rs_sync library is (w, x, y, z)
         quats.push((qv[3], -qv[0], -qv[1], -qv[2])); // w, x, y, z
-        timestamps.push(*ts);
+        timestamps.push(ts);
     }
     sync.set_gyro_quaternions(&timestamps, &quats);
 }
@@ -4,10 +4,10 @@
 use rayon::iter::{ ParallelIterator, IntoParallelIterator };
 use crate::{ stabilization, stabilization::ComputeParams };
 use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::Relaxed } };
-use super::super::{ PoseEstimator, SyncParams };
+use super::super::{ PoseEstimator, SyncParams, SyncError, PartialSync };
 use parking_lot::RwLock;
 
-pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64, i64)], sync_params: &SyncParams, params_arg: &ComputeParams, for_rs: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
+pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64, i64)], sync_params: &SyncParams, params_arg: &ComputeParams, for_rs: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Result<Vec<(f64, f64, f64)>, PartialSync> { // Vec<(timestamp, offset, cost)>
     let mut params = params_arg.clone();
     params.gyro = Arc::new(RwLock::new(params_arg.gyro.read().clone()));
     if !for_rs {
@@ -17,6 +17,7 @@ pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64
     let (w, h) = (params.width as i32, params.height as i32);
 
     let mut final_offsets = Vec::new();
+    let mut last_error = None;
 
     let next_frame_no = 2;
     let fps = params.scaled_fps;
@@ -45,6 +46,10 @@ pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64
                 }
             }
         }
+        if matched_points.is_empty() {
+            last_error = Some(SyncError::NotEnoughFeatures { range: (*from_ts, *to_ts), found: 0, needed: 1 });
+            continue;
+        }
 
         let calculate_distance = |offs, rs: Option<f64>| -> f64 {
             let mut total_dist = 0.0;
@@ -82,27 +87,25 @@ pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64
             total_dist
         };
 
-        let find_min = |a: (f64, f64), b: (f64, f64)| -> (f64, f64) { if a.1 < b.1 { a } else { b } };
+        let deterministic = params.deterministic;
 
         if for_rs { // Estimate rolling shutter
             // First search every 1 ms
             let max_rs = 1000.0 / fps;
             let steps = max_rs as isize;
-            let lowest = (-steps..steps)
+            let lowest = super::super::find_lowest((-steps..steps)
                 .into_par_iter()
                 .map(|i| {
                     (i as f64, calculate_distance(0.0, Some(i as f64)))
-                })
-                .reduce_with(find_min)
+                }), deterministic)
                 .and_then(|lowest| {
                     // Then refine to 0.01 ms
-                    (0..200)
+                    super::super::find_lowest((0..200)
                         .into_par_iter()
                         .map(|i| {
                             let rs = lowest.0 - 1.0 + (i as f64 * 0.01);
                             (rs, calculate_distance(0.0, Some(rs)))
-                        })
-                        .reduce_with(find_min)
+                        }), deterministic)
                 });
             log::debug!("lowest: {:?}", &lowest);
             if let Some(lowest) = lowest {
@@ -111,22 +114,20 @@ pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64
         } else {
             // First search every 1 ms
             let steps = sync_params.search_size as usize;
-            let lowest = (0..steps)
+            let lowest = super::super::find_lowest((0..steps)
                 .into_par_iter()
                 .map(|i| {
                     let offs = sync_params.initial_offset + (-(sync_params.search_size / 2.0) + (i as f64));
                     (offs, calculate_distance(offs, None))
-                })
-                .reduce_with(find_min)
+                }), deterministic)
                 .and_then(|lowest| {
                     // Then refine to 0.01 ms
-                    (0..200)
+                    super::super::find_lowest((0..200)
                         .into_par_iter()
                         .map(|i| {
                             let offs = lowest.0 - 1.0 + (i as f64 * 0.01);
                             (offs, calculate_distance(offs, None))
-                        })
-                        .reduce_with(find_min)
+                        }), deterministic)
                 });
 
             log::debug!("lowest: {:?}", &lowest);
@@ -138,19 +139,20 @@ pub fn find_offsets<F: Fn(f64) + Sync>(estimator: &PoseEstimator, ranges: &[(i64
                     final_offsets.push((middle_timestamp, lowest.0, lowest.1));
                 } else {
                     log::warn!("Sync point out of acceptable range {} < {}", (lowest.0 - sync_params.initial_offset).abs(), sync_params.search_size * 0.9);
+                    last_error = Some(SyncError::OutOfSearchRange);
                 }
             }
         }
 
         if i == ranges.len() - 1 {
-            log::info!("视觉特征匹配完成 - 处理了 {} 个时间范围, 找到 {} 个匹配点", 
+            log::info!("Visual features matching finished ranges={} matched_points={}", 
                 ranges.len(),
                 matched_points.len()
             );
         }
     }
 
-    final_offsets
+    PartialSync::result(final_offsets, cancel_flag.load(Relaxed), last_error)
 }
 
 
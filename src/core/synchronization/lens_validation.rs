@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// How well the lens profile fits the clip, from the optical flow collected by the `PoseEstimator`.
+// The matched points are undistorted with the profile and reprojected with the rotation estimated for each frame pair.
+// With a wrong profile the leftover distortion bends the motion differently across the frame and the residual grows toward the edges.
+// Parallax from camera translation adds to the residual as well, so clips with mostly rotational motion give the most meaningful numbers
+
+use nalgebra::{ Matrix3, Vector3 };
+use crate::stabilization::{ ComputeParams, FrameTransform, undistort_points_for_optical_flow };
+use super::PoseEstimator;
+
+#[derive(Default, Clone, Copy, Debug, serde::Serialize)]
+pub struct RegionResidual {
+    pub rms_px: f64,
+    pub points: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum LensFit { Good, Acceptable, Poor }
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct LensValidationReport {
+    pub pairs: usize,
+    pub rms_px: f64, // In pixels of the optical flow frames (processing resolution)
+    pub all: RegionResidual,
+    pub center: RegionResidual,
+    pub edges: RegionResidual,
+    pub corners: RegionResidual,
+    pub good_threshold_px: f64,
+    pub acceptable_threshold_px: f64,
+    pub fit: LensFit,
+}
+
+// Minimum number of matched points in a frame pair
+const MIN_POINTS: usize = 20;
+
+/// Validates `params.lens` using the frame pairs in `ranges_us` which have the rotation estimated.
+/// Returns `None` if there are no usable pairs
+pub fn validate_lens(estimator: &PoseEstimator, ranges_us: &[(i64, i64)], params: &ComputeParams) -> Option<LensValidationReport> {
+    let mut sums = [(0.0, 0usize); 3]; // center, edges, corners
+    let mut pairs = 0;
+    let mut of_width = 0;
+
+    let sync_results = estimator.sync_results.read();
+    for (ts, fr) in sync_results.iter() {
+        if !ranges_us.iter().any(|(from, to)| (*from..=*to).contains(ts)) { continue; }
+        let Some(rotation) = fr.rotation else { continue; };
+        let Some(Some(((ts1, pts1), (ts2, pts2)))) = fr.optical_flow.try_borrow().ok().and_then(|x| x.get(&1).cloned()) else { continue; };
+        if pts1.len() < MIN_POINTS || pts1.len() != pts2.len() { continue; }
+
+        let size = fr.frame_size;
+        let und1 = undistort_points_for_optical_flow(&pts1, ts1, params, size);
+        let und2 = undistort_points_for_optical_flow(&pts2, ts2, params, size);
+        if und1.len() != pts1.len() || und2.len() != pts2.len() { continue; }
+
+        // Pose methods don't agree on the direction of the rotation, so use the one which fits better
+        let r = *rotation.matrix();
+        let residuals = [pair_residuals(&und1, &und2, &r), pair_residuals(&und1, &und2, &r.transpose())]
+            .into_iter()
+            .flatten()
+            .min_by(|a, b| median(a).total_cmp(&median(b)));
+        let Some(residuals) = residuals else { continue; };
+
+        let (camera_matrix, ..) = FrameTransform::get_lens_data_at_timestamp(params, ts1 as f64 / 1000.0, false);
+        let focal_px = camera_matrix[(0, 0)] * size.0 as f64 / params.width.max(1) as f64;
+        // Optical flow mismatches
+        let outlier_limit = median(&residuals) * 5.0;
+        for ((x, y), residual) in pts1.iter().zip(residuals.iter()) {
+            if *residual > outlier_limit { continue; }
+            let u = ((*x as f64 / size.0 as f64) * 2.0 - 1.0).abs();
+            let v = ((*y as f64 / size.1 as f64) * 2.0 - 1.0).abs();
+            let region = region_index(u, v);
+            let px = residual * focal_px;
+            sums[region].0 += px * px;
+            sums[region].1 += 1;
+        }
+        pairs += 1;
+        of_width = size.0;
+    }
+    drop(sync_results);
+    if pairs == 0 { return None; }
+
+    let region = |(sum, n): (f64, usize)| RegionResidual { rms_px: if n > 0 { (sum / n as f64).sqrt() } else { 0.0 }, points: n };
+    let all = region(sums.iter().fold((0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1)));
+    let (center, edges, corners) = (region(sums[0]), region(sums[1]), region(sums[2]));
+
+    // The calibrator's own reprojection error is the noise floor, scaled to the optical flow resolution
+    let calib_width = params.lens.calib_dimension.w.max(1) as f64;
+    let calib_rms = params.lens.fisheye_params.RMS_error * of_width as f64 / calib_width;
+    let good_threshold_px = (2.0 * calib_rms).max(0.5);
+    let acceptable_threshold_px = good_threshold_px * 2.0;
+
+    let mut fit = if all.rms_px <= good_threshold_px { LensFit::Good } else if all.rms_px <= acceptable_threshold_px { LensFit::Acceptable } else { LensFit::Poor };
+    // Error concentrated in the corners means the distortion doesn't match, even if most points are in the center
+    if corners.points >= MIN_POINTS && center.points >= MIN_POINTS && corners.rms_px > 2.0 * center.rms_px.max(good_threshold_px / 2.0) && fit == LensFit::Good {
+        fit = LensFit::Acceptable;
+    }
+
+    Some(LensValidationReport { pairs, rms_px: all.rms_px, all, center, edges, corners, good_threshold_px, acceptable_threshold_px, fit })
+}
+
+// `u` and `v` are the distances from the center, 0 - 1
+fn region_index(u: f64, v: f64) -> usize {
+    if u > 0.6 && v > 0.6 { 2 } else if u < 0.5 && v < 0.5 { 0 } else { 1 }
+}
+
+fn median(v: &[f64]) -> f64 {
+    let mut v = v.to_vec();
+    v.sort_by(|a, b| a.total_cmp(b));
+    v.get(v.len() / 2).copied().unwrap_or(f64::MAX)
+}
+
+/// Distances between the points in the second frame and the points from the first one rotated with `r`, in normalized coordinates
+pub fn pair_residuals(pts1: &[(f32, f32)], pts2: &[(f32, f32)], r: &Matrix3<f64>) -> Option<Vec<f64>> {
+    pts1.iter().zip(pts2.iter()).map(|(p1, p2)| {
+        let q = r * Vector3::new(p1.0 as f64, p1.1 as f64, 1.0);
+        if q.z <= 1e-6 { return None; }
+        Some(((q.x / q.z - p2.0 as f64).powi(2) + (q.y / q.z - p2.1 as f64).powi(2)).sqrt())
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Rotation3;
+
+    #[test]
+    fn residuals() {
+        let r = *Rotation3::from_euler_angles(0.01, -0.02, 0.005).matrix();
+        let (mut pts1, mut pts2) = (Vec::new(), Vec::new());
+        let (mut pts1_distorted, mut pts2_distorted) = (Vec::new(), Vec::new());
+        // Radial distortion left in the points, like with a wrong profile
+        let distort = |x: f64, y: f64| { let k = 1.0 + 0.05 * (x * x + y * y); ((x * k) as f32, (y * k) as f32) };
+        for i in 0..15 {
+            for j in 0..9 {
+                let p = Vector3::new(i as f64 / 7.0 - 1.0, j as f64 / 4.0 - 1.0, 1.0);
+                let q = r * p;
+                let (x, y) = (q.x / q.z, q.y / q.z);
+                pts1.push((p.x as f32, p.y as f32));
+                pts2.push((x as f32, y as f32));
+                pts1_distorted.push(distort(p.x, p.y));
+                pts2_distorted.push(distort(x, y));
+            }
+        }
+        let exact = pair_residuals(&pts1, &pts2, &r).unwrap();
+        assert!(exact.iter().all(|x| *x < 1e-6), "{exact:?}");
+
+        let distorted = pair_residuals(&pts1_distorted, &pts2_distorted, &r).unwrap();
+        let rms = |pred: &dyn Fn(usize) -> bool| {
+            let v = distorted.iter().enumerate().filter(|(i, _)| pred(*i)).map(|(_, x)| x * x).collect::<Vec<_>>();
+            (v.iter().sum::<f64>() / v.len() as f64).sqrt()
+        };
+        let corner = |i: usize| { let (x, y) = pts1[i]; x.abs() > 0.6 && y.abs() > 0.6 };
+        assert!(rms(&|_| true) > 1e-3);
+        assert!(rms(&corner) > 2.0 * rms(&|i| !corner(i)));
+        assert_eq!(region_index(0.1, 0.2), 0);
+        assert_eq!(region_index(0.9, 0.3), 1);
+        assert_eq!(region_index(0.9, 0.8), 2);
+    }
+}
@@ -8,13 +8,17 @@ use std::sync::atomic::{ AtomicBool, AtomicU32, Ordering::SeqCst };
 use parking_lot::RwLock;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use rayon::iter::{ ParallelIterator, IntoParallelRefIterator };
+use rayon::iter::{ ParallelIterator, IndexedParallelIterator, IntoParallelRefIterator };
 
 use crate::gyro_source::{ Quat64, TimeQuat };
 use crate::stabilization::ComputeParams;
 
 mod optical_flow; pub use optical_flow::*;
 mod estimate_pose; pub use estimate_pose::*;
+mod lens_validation; pub use lens_validation::*;
+mod visual_rotations; pub use visual_rotations::*;
+mod consistency; pub use consistency::*;
+mod residual_wobble; pub use residual_wobble::*;
 mod find_offset { pub mod rs_sync; pub mod essential_matrix; pub mod visual_features; }
 
 use super::gyro_source::TimeIMU;
@@ -43,7 +47,47 @@ pub struct SyncParams {
     pub offset_method: usize,
     pub pose_method: usize,
     pub custom_sync_pattern: serde_json::Value,
-    pub auto_sync_points: bool
+    pub auto_sync_points: bool,
+    pub gyro_stream: Option<crate::gyro_source::GyroStream>, // Sync against this stream instead of the selected one
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SyncError {
+    #[error("No gyro data")]
+    NoGyroData,
+    #[error("Not enough features in the range {range:?}, found {found}, needed {needed}")]
+    NotEnoughFeatures { range: (i64, i64), found: usize, needed: usize }, // Range in microseconds
+    #[error("No movement in the range {range:?}")]
+    NoMotion { range: (i64, i64) },
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Sync point out of the search range")]
+    OutOfSearchRange,
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+/// Offsets found before the sync failed, eg. the sync points done before it was cancelled
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{error}")]
+pub struct PartialSync {
+    pub offsets: Vec<(f64, f64, f64)>, // Vec<(timestamp, offset, cost)>
+    pub error: SyncError,
+}
+impl From<SyncError> for PartialSync {
+    fn from(error: SyncError) -> Self { Self { offsets: Vec::new(), error } }
+}
+impl PartialSync {
+    // Succeeds if any sync point was found, otherwise fails with the reason of the last failed one
+    pub(crate) fn result(offsets: Vec<(f64, f64, f64)>, cancelled: bool, last_error: Option<SyncError>) -> Result<Vec<(f64, f64, f64)>, PartialSync> {
+        if cancelled {
+            Err(Self { offsets, error: SyncError::Cancelled })
+        } else if !offsets.is_empty() {
+            Ok(offsets)
+        } else {
+            Err(Self { offsets, error: last_error.unwrap_or_else(|| SyncError::Internal("No sync points".into())) })
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -56,8 +100,12 @@ pub struct FrameResult {
     pub rotation: Option<Rotation3<f64>>,
     pub quat: Option<Quat64>,
     pub euler: Option<(f64, f64, f64)>,
+    // Key of the lens profile it was estimated with, see `PoseEstimator::essential_rotations`
+    essential_rotation: Option<(u64, Option<FramePairRotation>)>,
 
-    optical_flow: RefCell<BTreeMap<usize, OpticalFlowPairWithTs>>
+    optical_flow: RefCell<BTreeMap<usize, OpticalFlowPairWithTs>>,
+    // The frame can't be decoded again during the analysis, so it stays pinned in the frame cache until `cleanup`
+    cache_entry: Option<Arc<crate::frame_cache::Entry>>,
 }
 unsafe impl Send for FrameResult {}
 unsafe impl Sync for FrameResult {}
@@ -84,6 +132,7 @@ impl PoseEstimator {
         let frame_size = (width, height);
         let contains = self.sync_results.read().contains_key(&timestamp_us);
         if !contains {
+            let cache_entry = Some(Arc::new(crate::frame_cache::track_pinned(crate::frame_cache::CacheKind::Analysis, img.as_raw().len())));
             let result = FrameResult {
                 of_method: OpticalFlowMethod::detect_features(of_method, timestamp_us, img, width, height),
                 frame_no,
@@ -93,7 +142,9 @@ impl PoseEstimator {
                 rotation: None,
                 quat: None,
                 euler: None,
-                optical_flow: Default::default()
+                essential_rotation: None,
+                optical_flow: Default::default(),
+                cache_entry,
             };
             let mut l = self.sync_results.write();
             l.entry(timestamp_us).or_insert(result);
@@ -222,6 +273,7 @@ impl PoseEstimator {
         let mut l = self.sync_results.write();
         for (_, i) in l.iter_mut(){
             i.of_method.cleanup();
+            i.cache_entry = None;
         }
     }
 
@@ -379,12 +431,100 @@ impl PoseEstimator {
         ranges
     }
 
-    pub fn find_offsets<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
+    pub fn find_offsets<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Result<Vec<(f64, f64, f64)>, PartialSync> { // Vec<(timestamp, offset, cost)>
         match self.offset_method.load(SeqCst) {
             0 => find_offset::essential_matrix::find_offsets(&self, ranges, sync_params, params, progress_cb, cancel_flag),
             1 => find_offset::visual_features::find_offsets(&self, ranges,  sync_params, params, false, progress_cb, cancel_flag),
             2 => find_offset::rs_sync::find_offsets(&self, ranges, sync_params, params, progress_cb, cancel_flag),
-            v => { log::error!("Unknown offset method: {v}"); Vec::new() }
+            v => Err(SyncError::Internal(format!("Unknown offset method: {v}")).into())
         }
     }
 }
+
+/// The (offset, cost) with the lowest cost. The costs are computed in parallel, in the deterministic mode
+/// they are reduced in the order of `candidates`, so the result doesn't depend on how the work was split between the threads
+pub fn find_lowest<I: IndexedParallelIterator<Item = (f64, f64)>>(candidates: I, deterministic: bool) -> Option<(f64, f64)> {
+    if deterministic {
+        candidates.collect::<Vec<_>>().into_iter().reduce(|a, b| lowest_cost(a, b, true))
+    } else {
+        candidates.reduce_with(|a, b| lowest_cost(a, b, false))
+    }
+}
+
+/// The (offset, cost) with the lower cost. In the deterministic mode equal costs go to the lower offset
+/// and NaN costs are ordered, so the reduction is the same in any order
+pub fn lowest_cost(a: (f64, f64), b: (f64, f64), deterministic: bool) -> (f64, f64) {
+    if deterministic {
+        if a.1.total_cmp(&b.1).then(a.0.total_cmp(&b.0)).is_le() { a } else { b }
+    } else if a.1 < b.1 {
+        a
+    } else {
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use find_offset::rs_sync::FindOffsetsRssync;
+
+    fn sync_params() -> SyncParams {
+        SyncParams { search_size: 500.0, ..Default::default() }
+    }
+
+    #[test]
+    fn errors_without_data() {
+        let estimator = PoseEstimator::default();
+        let params = ComputeParams::default();
+        let ranges = [(1_000_000, 1_500_000), (3_000_000, 3_500_000)];
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+
+        estimator.offset_method.store(0, SeqCst);
+        assert_eq!(estimator.find_offsets(&ranges, &sync_params(), &params, |_| (), cancel_flag.clone()).unwrap_err().error, SyncError::NoGyroData);
+
+        estimator.offset_method.store(1, SeqCst);
+        assert_eq!(estimator.find_offsets(&ranges, &sync_params(), &params, |_| (), cancel_flag.clone()).unwrap_err().error, SyncError::NotEnoughFeatures { range: ranges[1], found: 0, needed: 1 });
+        assert_eq!(estimator.find_offsets(&[], &sync_params(), &params, |_| (), cancel_flag.clone()).unwrap_err().error, SyncError::Internal("No sync points".into()));
+
+        estimator.offset_method.store(7, SeqCst);
+        assert!(matches!(estimator.find_offsets(&ranges, &sync_params(), &params, |_| (), cancel_flag.clone()).unwrap_err().error, SyncError::Internal(_)));
+
+        cancel_flag.store(true, SeqCst);
+        estimator.offset_method.store(1, SeqCst);
+        assert_eq!(estimator.find_offsets(&ranges, &sync_params(), &params, |_| (), cancel_flag.clone()).unwrap_err().error, SyncError::Cancelled);
+    }
+
+    #[test]
+    fn rs_sync_errors() {
+        let results = Arc::new(RwLock::new(BTreeMap::new()));
+        let params = ComputeParams::default();
+        let ranges = [(1_000_000, 1_500_000), (2_000_000, 1_000_000)];
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        assert_eq!(FindOffsetsRssync::new(&ranges, results.clone(), &sync_params(), &params, |_| (), cancel_flag.clone()).err(), Some(SyncError::NoGyroData));
+
+        params.gyro.write().quaternions.insert(0, Quat64::identity());
+        params.gyro.write().quaternions.insert(5_000_000, Quat64::identity());
+        let err = FindOffsetsRssync::new(&ranges, results.clone(), &sync_params(), &params, |_| (), cancel_flag.clone()).err();
+        assert_eq!(err, Some(SyncError::NotEnoughFeatures { range: ranges[1], found: 0, needed: 2 }));
+
+        let sync = FindOffsetsRssync::new(&[], results, &sync_params(), &params, |_| (), cancel_flag);
+        assert!(matches!(sync.err(), Some(SyncError::Internal(_))));
+    }
+
+    #[test]
+    fn partial_offsets() {
+        let offsets = vec![(1000.0, 12.5, 0.1)];
+        assert_eq!(PartialSync::result(offsets.clone(), false, Some(SyncError::OutOfSearchRange)), Ok(offsets.clone()));
+        assert_eq!(PartialSync::result(offsets.clone(), true, None), Err(PartialSync { offsets, error: SyncError::Cancelled }));
+        assert_eq!(PartialSync::result(Vec::new(), false, Some(SyncError::OutOfSearchRange)).unwrap_err().error, SyncError::OutOfSearchRange);
+    }
+
+    #[test]
+    fn lowest_cost_ties() {
+        // Equal costs, the result must not depend on the order of the reduction
+        assert_eq!(lowest_cost((2.0, 1.0), (1.0, 1.0), true), (1.0, 1.0));
+        assert_eq!(lowest_cost((1.0, 1.0), (2.0, 1.0), true), (1.0, 1.0));
+        assert_eq!(lowest_cost((2.0, 0.5), (1.0, 1.0), true), (2.0, 0.5));
+        assert_eq!(lowest_cost((2.0, 1.0), (1.0, 1.0), false), (1.0, 1.0));
+    }
+}
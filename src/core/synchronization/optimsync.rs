@@ -69,6 +69,7 @@ impl OptimSync {
         &mut self,
         target_sync_points: usize,
         trim_ranges_s: Vec<(f64, f64)>,
+        excluded_ranges_s: &[(f64, f64)],
     ) -> (Vec<f64>, Vec<f32>, f64) {
         let gyro_c32: Vec<Vec<Complex<f32>>> = self
             .gyro
@@ -146,6 +147,11 @@ impl OptimSync {
             if rank[i] < 100.0 || !trim_ranges_s.iter().any(|x| time >= x.0 && time <= x.1) {
                 rank[i] = 0.0;
             }
+            // The whole fft window has to be outside of the excluded ranges (eg. gaps in the gyro data)
+            let window_end = time + fft_size as f64 / self.sample_rate;
+            if excluded_ranges_s.iter().any(|x| x.0 < window_end && x.1 > time) {
+                rank[i] = 0.0;
+            }
         }
         // If the time exceeds 8 seconds, clear the data for the first 2 seconds and last 2 seconds,
         // as most of the distortion occurs from button presses.
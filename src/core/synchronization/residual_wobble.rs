@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Wobble which is left after the gyro stabilization, eg. from the lens mount flexing or rolling shutter which the readout time doesn't fully model.
+// The tracked points are stabilized the same way as the rendered frames (current smoothing, rolling shutter correction, without any previous
+// residual correction), so whatever motion remains between them is what the gyro couldn't see. That motion is fitted with a rotation per frame pair,
+// chained into an orientation of the stabilized view and only its high frequency part is removed, so the intentional motion is untouched.
+// The corrections are rotations of the stabilized view, composed into the frame transform by `ComputeParams::residual_correction_at`.
+
+use std::collections::BTreeMap;
+use nalgebra::{ Matrix3, Rotation3, Vector3 };
+use rayon::iter::{ ParallelIterator, IntoParallelRefIterator };
+
+use crate::gyro_source::Quat64;
+use crate::stabilization::{ ComputeParams, FrameTransform, undistort_points_with_rolling_shutter };
+use super::{ PoseEstimator, OpticalFlowPairWithTs };
+
+/// Per analyzed frame: timestamp in us -> rotation vector (radians) applied to the stabilized view
+pub type ResidualCorrections = BTreeMap<i64, [f64; 3]>;
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct ResidualWobbleResult {
+    pub ranges_us: Vec<(i64, i64)>,
+    pub corrections: ResidualCorrections,
+    pub pairs: usize,         // Frame pairs with a fitted rotation
+    pub skipped_pairs: usize, // Not enough points, or too much motion between the stabilized frames
+    pub rms_deg: f64,         // Of the corrections
+    pub max_deg: f64,
+}
+
+// Fewer points than this don't give a stable rotation
+const MIN_POINTS: usize = 12;
+// More than this between two stabilized frames is intentional motion or a wrong fit, not wobble
+const MAX_PAIR_ANGLE_DEG: f64 = 5.0;
+// Points further than this multiple of the median error from the fit are outliers (moving objects, bad matches)
+const OUTLIER_MEDIAN_SCALE: f64 = 3.0;
+const MIN_OUTLIER_THRESHOLD_RAD: f64 = 0.0002;
+// Motion slower than this (sigma of the low-pass which is subtracted) is kept
+pub const HIGH_PASS_SIGMA_MS: f64 = 60.0;
+// The corrections fade in and out over this many frames at the ends of each analyzed segment, so the output doesn't jump there
+const FADE_FRAMES: usize = 5;
+
+/// Rotation `r` which maps `from` to `to` (`to ≈ r * from`) in the least squares sense (Kabsch)
+fn fit_rotation(from: &[Vector3<f64>], to: &[Vector3<f64>], indices: &[usize]) -> Option<Quat64> {
+    if indices.len() < 3 { return None; }
+    let mut h = Matrix3::<f64>::zeros();
+    for &i in indices {
+        h += from[i] * to[i].transpose();
+    }
+    let svd = h.svd(true, true);
+    let (u, v) = (svd.u?, svd.v_t?.transpose());
+    let d = if (v * u.transpose()).determinant() < 0.0 { -1.0 } else { 1.0 };
+    let r = v * Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, d)) * u.transpose();
+    Some(Quat64::from_rotation_matrix(&Rotation3::from_matrix_unchecked(r)))
+}
+
+/// Rotation between the stabilized directions of the matched points of two frames, with the outliers removed.
+/// Returns the rotation and the number of inliers
+pub fn fit_pair_rotation(from: &[Vector3<f64>], to: &[Vector3<f64>]) -> Option<(Quat64, usize)> {
+    if from.len() != to.len() || from.len() < MIN_POINTS { return None; }
+    let mut indices: Vec<usize> = (0..from.len()).collect();
+    for _ in 0..5 {
+        let q = fit_rotation(from, to, &indices)?;
+        let errors: Vec<f64> = from.iter().zip(to).map(|(a, b)| (q * a).angle(b)).collect();
+        let mut inlier_errors: Vec<f64> = indices.iter().map(|&i| errors[i]).collect();
+        inlier_errors.sort_by(|a, b| a.total_cmp(b));
+        let threshold = (inlier_errors[inlier_errors.len() / 2] * OUTLIER_MEDIAN_SCALE).max(MIN_OUTLIER_THRESHOLD_RAD);
+        let new_indices: Vec<usize> = (0..from.len()).filter(|&i| errors[i] <= threshold).collect();
+        if new_indices.len() < MIN_POINTS { return None; }
+        if new_indices == indices { break; }
+        indices = new_indices;
+    }
+    Some((fit_rotation(from, to, &indices)?, indices.len()))
+}
+
+/// Corrections from the rotations between consecutive stabilized frames (`to ≈ rotation * from`), as (from_ts_us, to_ts_us, rotation).
+/// The pairs split into segments wherever they don't continue, each is handled separately
+pub fn corrections_from_pairs(pairs: &[(i64, i64, Quat64)], sigma_ms: f64) -> ResidualCorrections {
+    let mut segments: Vec<Vec<(i64, Quat64)>> = Vec::new();
+    let mut last_ts = None;
+    for (ts1, ts2, rotation) in pairs {
+        if last_ts != Some(*ts1) {
+            segments.push(vec![(*ts1, Quat64::identity())]);
+        }
+        let segment = segments.last_mut().unwrap();
+        let orientation = *rotation * segment.last().unwrap().1;
+        segment.push((*ts2, orientation));
+        last_ts = Some(*ts2);
+    }
+
+    let sigma_us = (sigma_ms * 1000.0).max(1.0);
+    let mut corrections = ResidualCorrections::new();
+    for segment in &segments {
+        let n = segment.len();
+        for (k, (ts, orientation)) in segment.iter().enumerate() {
+            // Low-pass of the orientation around this frame: weighted linear fit of the rotations relative to it, evaluated at this frame.
+            // Unlike a plain mean it doesn't lag behind a pan at the ends of the segment
+            let (mut s0, mut s1, mut s2) = (0.0, 0.0, 0.0);
+            let (mut sv, mut stv) = (Vector3::zeros(), Vector3::zeros());
+            for (ts_j, orientation_j) in segment {
+                let dt = (ts_j - ts) as f64 / sigma_us;
+                if dt.abs() > 3.0 { continue; }
+                let w = (-0.5 * dt * dt).exp();
+                let v = (orientation.inverse() * orientation_j).scaled_axis();
+                s0 += w; s1 += w * dt; s2 += w * dt * dt;
+                sv += v * w; stv += v * (w * dt);
+            }
+            let det = s0 * s2 - s1 * s1;
+            let at_frame = if det > 1e-6 { (sv * s2 - stv * s1) / det } else { sv / s0.max(1e-9) };
+            let smoothed = orientation * Quat64::from_scaled_axis(at_frame);
+            let fade = ((k + 1) as f64 / (FADE_FRAMES + 1) as f64).min((n - k) as f64 / (FADE_FRAMES + 1) as f64).min(1.0);
+            let correction = (smoothed * orientation.inverse()).scaled_axis() * fade;
+            corrections.insert(*ts, [correction.x, correction.y, correction.z]);
+        }
+    }
+    corrections
+}
+
+/// Directions of the points in the stabilized view, as the output frame would show them with the current stabilization
+fn stabilized_directions(points: &[(f32, f32)], timestamp_us: i64, size: (u32, u32), params: &ComputeParams) -> Vec<Vector3<f64>> {
+    let ratio = (params.width as f32 / size.0.max(1) as f32, params.height as f32 / size.1.max(1) as f32);
+    let full_res: Vec<(f32, f32)> = points.iter().map(|(x, y)| (x * ratio.0, y * ratio.1)).collect();
+    let timestamp_ms = timestamp_us as f64 / 1000.0;
+    let stabilized = undistort_points_with_rolling_shutter(&full_res, timestamp_ms, None, params, 1.0, false);
+
+    let (camera_matrix, _, _, _, _, _) = FrameTransform::get_lens_data_at_timestamp(params, timestamp_ms, false);
+    let fov = params.width as f64 / params.output_width.max(1) as f64;
+    let Some(inv_k) = FrameTransform::get_new_k(params, &camera_matrix, fov).try_inverse() else { return Vec::new(); };
+    stabilized.iter().map(|&(x, y)| (inv_k * Vector3::new(x as f64, y as f64, 1.0)).normalize()).collect()
+}
+
+fn estimate_pair(of: &OpticalFlowPairWithTs, size: (u32, u32), params: &ComputeParams) -> Option<(i64, i64, Quat64)> {
+    let ((ts1, pts1), (ts2, pts2)) = of.as_ref()?;
+    if pts1.len() < MIN_POINTS || pts1.len() != pts2.len() { return None; }
+    let d1 = stabilized_directions(pts1, *ts1, size, params);
+    let d2 = stabilized_directions(pts2, *ts2, size, params);
+    if d1.len() != pts1.len() || d2.len() != pts2.len() { return None; }
+    let (from, to): (Vec<_>, Vec<_>) = d1.into_iter().zip(d2).filter(|(a, b)| a.iter().chain(b.iter()).all(|x| x.is_finite())).unzip();
+
+    let (rotation, _inliers) = fit_pair_rotation(&from, &to)?;
+    if rotation.angle().to_degrees() > MAX_PAIR_ANGLE_DEG { return None; }
+    Some((*ts1, *ts2, rotation))
+}
+
+impl PoseEstimator {
+    /// Corrections of the residual wobble in `ranges_us` (timestamps in us), from the optical flow between consecutive frames.
+    /// `params` should be without any previous residual correction, otherwise only what's left of the wobble is found
+    pub fn residual_wobble(&self, ranges_us: &[(i64, i64)], params: &ComputeParams) -> ResidualWobbleResult {
+        let mut to_compute: Vec<((u32, u32), OpticalFlowPairWithTs)> = Vec::new();
+        {
+            let l = self.sync_results.read();
+            for &(from, to) in ranges_us {
+                for (ts, fr) in l.range(from..=to) {
+                    if fr.frame_size.0 == 0 { continue; }
+                    let of = match fr.optical_flow.try_borrow().ok().and_then(|x| x.get(&1).cloned()) {
+                        Some(of) => of,
+                        None => {
+                            let Some((next_ts, next)) = l.range(ts + 1..).next() else { continue; };
+                            if fr.frame_no + 1 != next.frame_no || *next_ts > to { continue; }
+                            fr.of_method.optical_flow_to(&next.of_method).map(|of| ((*ts, of.0), (*next_ts, of.1)))
+                        }
+                    };
+                    to_compute.push((fr.frame_size, of));
+                }
+            }
+        }
+
+        let computed: Vec<Option<(i64, i64, Quat64)>> = to_compute.par_iter().map(|(size, of)| estimate_pair(of, *size, params)).collect();
+        let mut pairs: Vec<(i64, i64, Quat64)> = computed.iter().flatten().copied().collect();
+        pairs.sort_by_key(|x| x.0);
+
+        let corrections = corrections_from_pairs(&pairs, HIGH_PASS_SIGMA_MS);
+        let angles: Vec<f64> = corrections.values().map(|v| Vector3::from(*v).norm().to_degrees()).collect();
+        ResidualWobbleResult {
+            ranges_us: ranges_us.to_vec(),
+            pairs: pairs.len(),
+            skipped_pairs: computed.len() - pairs.len(),
+            rms_deg: if angles.is_empty() { 0.0 } else { (angles.iter().map(|x| x * x).sum::<f64>() / angles.len() as f64).sqrt() },
+            max_deg: angles.iter().copied().fold(0.0, f64::max),
+            corrections,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_with_outliers() {
+        let rotation = Quat64::from_euler_angles(0.002, -0.004, 0.001);
+        let from: Vec<Vector3<f64>> = (0..100).map(|i| {
+            let (x, y) = ((i % 10) as f64 / 10.0 - 0.45, (i / 10) as f64 / 10.0 - 0.45);
+            Vector3::new(x, y, 1.0).normalize()
+        }).collect();
+        let mut to: Vec<Vector3<f64>> = from.iter().map(|x| rotation * x).collect();
+        // A moving object
+        for x in to.iter_mut().take(15) {
+            *x = (Quat64::from_euler_angles(0.0, 0.03, 0.0) * *x).normalize();
+        }
+        let (fitted, inliers) = fit_pair_rotation(&from, &to).unwrap();
+        assert_eq!(inliers, 85);
+        assert!(fitted.angle_to(&rotation) < 1e-9, "{:?}", fitted.euler_angles());
+
+        assert!(fit_pair_rotation(&from[..5], &to[..5]).is_none());
+    }
+
+    #[test]
+    fn high_pass() {
+        let frame_us = 33_333;
+        // Slow pan with a fast wobble on top
+        let intended = |k: usize| Quat64::from_euler_angles(0.0, k as f64 * 0.005, 0.0);
+        let wobble = |k: usize| Quat64::from_euler_angles((k as f64 * 2.5).sin() * 0.002, 0.0, (k as f64 * 1.9).cos() * 0.001);
+        let orientation = |k: usize| wobble(k) * intended(k);
+        let n = 90;
+        let pairs: Vec<(i64, i64, Quat64)> = (0..n).map(|k| (k as i64 * frame_us, (k + 1) as i64 * frame_us, orientation(k + 1) * orientation(k).inverse())).collect();
+
+        let corrections = corrections_from_pairs(&pairs, HIGH_PASS_SIGMA_MS);
+        assert_eq!(corrections.len(), n + 1);
+
+        let start = orientation(0);
+        let mut before = 0.0f64;
+        let mut after = 0.0f64;
+        for k in 15..n - 15 {
+            let c = Quat64::from_scaled_axis(Vector3::from(corrections[&(k as i64 * frame_us)]));
+            // The chain starts at identity, so the intended motion is relative to the first frame
+            let target = intended(k) * intended(0).inverse();
+            let observed = orientation(k) * start.inverse();
+            let next = orientation(k + 1) * start.inverse();
+            let c_next = Quat64::from_scaled_axis(Vector3::from(corrections[&((k + 1) as i64 * frame_us)]));
+            // Frame to frame jitter of the corrected view compared to the intended motion
+            let intended_step = intended(k + 1) * intended(k).inverse();
+            before = before.max(((next * observed.inverse()) * intended_step.inverse()).angle());
+            after = after.max((((c_next * next) * (c * observed).inverse()) * intended_step.inverse()).angle());
+            // The pan stays
+            assert!((c * observed).angle_to(&target) < 0.0003);
+        }
+        assert!(after < before * 0.3, "before: {before}, after: {after}");
+
+        // Faded out at the end of the segment
+        assert!(Vector3::from(corrections[&(n as i64 * frame_us)]).norm() < 1e-3);
+
+        // A gap starts a new segment
+        let mut gapped = pairs.clone();
+        gapped.remove(40);
+        assert_eq!(corrections_from_pairs(&gapped, HIGH_PASS_SIGMA_MS).len(), n + 1);
+    }
+
+    #[test]
+    fn strength() {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        params.scaled_fps = 30.0;
+        params.residual_corrections = [(0, [0.0, 0.01, 0.0]), (33_333, [0.0, 0.02, 0.0])].into_iter().collect();
+        let angle = |params: &ComputeParams, ts_ms: f64| params.residual_correction_at(ts_ms).map(|m| Rotation3::from_matrix_unchecked(m).angle());
+
+        params.residual_correction_strength = Some(1.0);
+        assert!((angle(&params, 0.0).unwrap() - 0.01).abs() < 1e-9);
+        assert!((angle(&params, 33.3333 / 2.0).unwrap() - 0.015).abs() < 1e-6);
+        // Outside of the analyzed frames
+        assert!(angle(&params, 100.0).is_none());
+
+        params.keyframes.set(&crate::KeyframeType::ResidualCorrectionStrength, 0, 0.5);
+        assert!((angle(&params, 0.0).unwrap() - 0.005).abs() < 1e-9);
+
+        // Turned off, regardless of the keyframes
+        params.residual_correction_strength = Some(0.0);
+        assert!(angle(&params, 0.0).is_none());
+    }
+}
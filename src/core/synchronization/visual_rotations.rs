@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Rotations between consecutive frames from the essential matrix of the optical flow, for analysis outside of the sync,
+// eg. comparing the camera motion seen in the video with the gyro or finding parts where the gyro data is wrong.
+// The points are undistorted with the lens profile first, so the results depend on it and are cached per profile in the `sync_results`.
+// The reprojection error only accounts for the rotation, so parallax from camera translation adds to it.
+
+use std::ops::Range;
+use std::hash::{ Hash, Hasher };
+use nalgebra::{ Matrix3, Rotation3, Vector3 };
+use rayon::iter::{ ParallelIterator, IntoParallelRefIterator };
+
+use crate::gyro_source::{ Quat64, TimeQuat };
+use crate::stabilization::{ ComputeParams, undistort_points_for_optical_flow };
+use super::{ PoseEstimator, OpticalFlowPairWithTs, OpticalFlowTrait, essential_rotation, pair_residuals };
+
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+pub struct FramePairRotation {
+    pub timestamp_us: i64,
+    pub next_timestamp_us: i64,
+    pub quat: Quat64,            // Rotates the undistorted points of the first frame to the second one
+    pub inliers: usize,
+    pub points: usize,
+    pub reprojection_error: f64, // RMS of the inliers, in normalized coordinates
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct TrackAlignment {
+    pub rotation: Quat64,        // From the gyro axes to the camera axes
+    pub rms_deg: f64,
+    pub max_deg: f64,
+    pub errors: Vec<(i64, f64)>, // Per frame pair: timestamp_us, angle between the visual and the aligned gyro rotation in degrees
+}
+
+// The eight point algorithm needs at least 8 matches, use some more for a stable estimate
+const MIN_POINTS: usize = 12;
+// Pairs rotating less than this don't tell much about the axes
+const MIN_ALIGN_ANGLE_DEG: f64 = 0.05;
+
+impl PoseEstimator {
+    /// Rotations between the consecutive analyzed frames which start in `range` (timestamps in us).
+    /// Estimates only the pairs which don't have a result for the current lens profile yet. Pairs which couldn't be estimated are left out
+    pub fn essential_rotations(&self, range: Range<i64>, params: &ComputeParams) -> Vec<FramePairRotation> {
+        let key = lens_key(params);
+        let mut rotations = Vec::new();
+        let mut to_compute: Vec<(i64, (u32, u32), OpticalFlowPairWithTs)> = Vec::new();
+        {
+            let l = self.sync_results.read();
+            for (ts, fr) in l.range(range) {
+                match fr.essential_rotation {
+                    Some((cached_key, result)) if cached_key == key => { rotations.extend(result); continue; }
+                    _ => { }
+                }
+                if fr.frame_size.0 == 0 { continue; }
+                let of = match fr.optical_flow.try_borrow().ok().and_then(|x| x.get(&1).cloned()) {
+                    Some(of) => of,
+                    None => {
+                        let Some((next_ts, next)) = l.range(ts + 1..).next() else { continue; };
+                        if fr.frame_no + 1 != next.frame_no { continue; }
+                        fr.of_method.optical_flow_to(&next.of_method).map(|of| ((*ts, of.0), (*next_ts, of.1)))
+                    }
+                };
+                to_compute.push((*ts, fr.frame_size, of));
+            }
+        }
+
+        let computed: Vec<(i64, Option<FramePairRotation>)> = to_compute.par_iter().map(|(ts, size, of)| {
+            (*ts, of.as_ref().and_then(|((ts1, pts1), (ts2, pts2))| estimate_pair(*ts1, pts1, *ts2, pts2, *size, params)))
+        }).collect();
+
+        {
+            let mut l = self.sync_results.write();
+            for (ts, result) in &computed {
+                if let Some(fr) = l.get_mut(ts) {
+                    fr.essential_rotation = Some((key, *result));
+                }
+            }
+        }
+        rotations.extend(computed.into_iter().filter_map(|x| x.1));
+        rotations.sort_by_key(|x| x.timestamp_us);
+        rotations
+    }
+}
+
+fn lens_key(params: &ComputeParams) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    params.lens.get_json().unwrap_or_default().hash(&mut hasher);
+    params.digital_lens.as_ref().map(|x| x.id()).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn rms(v: &[f64]) -> f64 {
+    if v.is_empty() { return f64::MAX; }
+    (v.iter().map(|x| x * x).sum::<f64>() / v.len() as f64).sqrt()
+}
+
+fn estimate_pair(ts1: i64, pts1: &[(f32, f32)], ts2: i64, pts2: &[(f32, f32)], size: (u32, u32), params: &ComputeParams) -> Option<FramePairRotation> {
+    if pts1.len() < MIN_POINTS || pts1.len() != pts2.len() { return None; }
+    let und1 = undistort_points_for_optical_flow(pts1, ts1, params, size);
+    let und2 = undistort_points_for_optical_flow(pts2, ts2, params, size);
+    if und1.len() != pts1.len() || und2.len() != pts2.len() { return None; }
+
+    let (rotation, inliers) = essential_rotation(&und1, &und2)?;
+    let (in1, in2): (Vec<_>, Vec<_>) = inliers.iter().filter_map(|&i| Some((*und1.get(i)?, *und2.get(i)?))).unzip();
+    if in1.len() < MIN_POINTS { return None; }
+
+    // The direction of the decomposed rotation isn't defined, use the one which maps the first frame to the second
+    let r = *rotation.matrix();
+    let (r, error) = [r, r.transpose()]
+        .into_iter()
+        .filter_map(|r| Some((r, rms(&pair_residuals(&in1, &in2, &r)?))))
+        .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    Some(FramePairRotation {
+        timestamp_us: ts1,
+        next_timestamp_us: ts2,
+        quat: Quat64::from_rotation_matrix(&Rotation3::from_matrix_unchecked(r)),
+        inliers: in1.len(),
+        points: pts1.len(),
+        reprojection_error: error,
+    })
+}
+
+/// Chains the frame pair rotations into a camera orientation track, starting with identity at the first frame.
+/// Missing pairs are treated as no rotation, so the track drifts by whatever happened in between
+pub fn integrate_rotations(rotations: &[FramePairRotation]) -> TimeQuat {
+    let mut track = TimeQuat::new();
+    let mut orientation = Quat64::identity();
+    for r in rotations {
+        track.entry(r.timestamp_us).or_insert(orientation);
+        // The points rotate opposite to the camera
+        orientation *= r.quat.inverse();
+        track.insert(r.next_timestamp_us, orientation);
+    }
+    track
+}
+
+/// Compares the frame pair rotations with the gyro orientation.
+/// `gyro_at` returns the gyro orientation at a video timestamp in ms (eg. `GyroSource::org_quat_at_timestamp`) and the gyro is sampled at `timestamp - offset_ms`.
+/// The axes of the gyro are aligned to the camera with the rotation which fits best, so only the motion is compared.
+/// Returns `None` if there isn't enough motion around at least two axes
+pub fn align_with_gyro<F: Fn(f64) -> Quat64>(rotations: &[FramePairRotation], gyro_at: F, offset_ms: f64) -> Option<TrackAlignment> {
+    // Camera rotation over each pair, from the visual estimate and from the gyro
+    let pairs: Vec<(i64, Quat64, Quat64)> = rotations.iter().map(|r| {
+        let q1 = gyro_at(r.timestamp_us as f64 / 1000.0 - offset_ms);
+        let q2 = gyro_at(r.next_timestamp_us as f64 / 1000.0 - offset_ms);
+        (r.timestamp_us, r.quat.inverse(), q1.inverse() * q2)
+    }).collect();
+
+    // Rotation between the rotation vectors (Kabsch)
+    let mut h = Matrix3::<f64>::zeros();
+    let mut used = 0;
+    for (_, visual, gyro) in &pairs {
+        let (v, g) = (visual.scaled_axis(), gyro.scaled_axis());
+        if v.norm().to_degrees() > MIN_ALIGN_ANGLE_DEG && g.norm().to_degrees() > MIN_ALIGN_ANGLE_DEG {
+            h += g * v.transpose();
+            used += 1;
+        }
+    }
+    if used < 3 { return None; }
+    let svd = h.svd(true, true);
+    let mut sv = svd.singular_values.iter().copied().collect::<Vec<_>>();
+    sv.sort_by(|a, b| b.total_cmp(a));
+    if sv[1] < sv[0] * 0.01 { return None; }
+    let (u, v) = (svd.u?, svd.v_t?.transpose());
+    // Both are proper rotations, so don't allow a reflection
+    let d = if (v * u.transpose()).determinant() < 0.0 { -1.0 } else { 1.0 };
+    let r = v * Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, d)) * u.transpose();
+    let rotation = Quat64::from_rotation_matrix(&Rotation3::from_matrix_unchecked(r));
+
+    let errors: Vec<(i64, f64)> = pairs.iter().map(|(ts, visual, gyro)| {
+        let aligned = rotation * gyro * rotation.inverse();
+        (*ts, (aligned.inverse() * visual).angle().to_degrees())
+    }).collect();
+    let angles = errors.iter().map(|x| x.1).collect::<Vec<_>>();
+
+    Some(TrackAlignment {
+        rotation,
+        rms_deg: rms(&angles),
+        max_deg: angles.iter().copied().fold(0.0, f64::max),
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+    use crate::stabilization::distortion_models::DistortionModel;
+    use super::super::{ FrameResult, OpticalFlowMethod };
+
+    // Pinhole camera without distortion, 1080p
+    fn pinhole(focal: f64) -> ComputeParams {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.height, params.output_width, params.output_height) = (1920, 1080, 1920, 1080);
+        params.frame_readout_time = 0.0;
+        params.lens = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[focal, 0.0, 960.0], [0.0, focal, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.0; 5],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        params.distortion_model = DistortionModel::from_name("opencv_standard");
+        params
+    }
+
+    // Grid of points at different depths in the first frame and the same points in the second frame, in pixels.
+    // The camera rotates by `r` and moves a bit, without the translation the essential matrix is degenerate
+    fn moved_points(r: &Matrix3<f64>, focal: f64) -> (Vec<(f32, f32)>, Vec<(f32, f32)>) {
+        let to_px = |v: Vector3<f64>| ((v.x / v.z * focal + 960.0) as f32, (v.y / v.z * focal + 540.0) as f32);
+        let t = Vector3::new(0.05, -0.02, 0.01);
+        (0..48).map(|i| {
+            let depth = 2.0 + (i * 7 % 5) as f64;
+            let v = Vector3::new((i % 8) as f64 * 0.1 - 0.35, (i / 8) as f64 * 0.08 - 0.2, 1.0) * depth;
+            (to_px(v), to_px(r * v + t))
+        }).unzip()
+    }
+
+    fn frame_result(frame_no: usize, timestamp_us: i64, of: OpticalFlowPairWithTs) -> FrameResult {
+        let img = Arc::new(image::GrayImage::new(32, 32));
+        FrameResult {
+            of_method: OpticalFlowMethod::detect_features(0, timestamp_us, img, 32, 32),
+            frame_no,
+            timestamp_us,
+            gyro_timestamp_us: 0,
+            frame_size: (1920, 1080),
+            rotation: None,
+            quat: None,
+            euler: None,
+            essential_rotation: None,
+            optical_flow: std::cell::RefCell::new(of.map(|of| (1, Some(of))).into_iter().collect()),
+            cache_entry: None,
+        }
+    }
+
+    #[test]
+    fn pair_rotation() {
+        let params = pinhole(1200.0);
+        let r = *Rotation3::from_euler_angles(0.01, -0.02, 0.005).matrix();
+        let (pts1, pts2) = moved_points(&r, 1200.0);
+
+        let result = estimate_pair(0, &pts1, 33_333, &pts2, (1920, 1080), &params).unwrap();
+        let expected = Quat64::from_rotation_matrix(&Rotation3::from_matrix_unchecked(r));
+        assert!(result.quat.angle_to(&expected) < 1e-3, "{:?}", result.quat);
+        assert_eq!((result.timestamp_us, result.next_timestamp_us), (0, 33_333));
+        assert_eq!(result.points, pts1.len());
+        assert!(result.inliers >= MIN_POINTS && result.inliers <= pts1.len());
+        // Only the parallax is left
+        assert!(result.reprojection_error < 0.05, "{}", result.reprojection_error);
+
+        // Too few points or not matching
+        assert!(estimate_pair(0, &pts1[..MIN_POINTS - 1], 33_333, &pts2[..MIN_POINTS - 1], (1920, 1080), &params).is_none());
+        assert!(estimate_pair(0, &pts1, 33_333, &pts2[1..], (1920, 1080), &params).is_none());
+    }
+
+    #[test]
+    fn cached_per_lens() {
+        let r = *Rotation3::from_euler_angles(0.01, -0.02, 0.005).matrix();
+        let (pts1, pts2) = moved_points(&r, 1200.0);
+        let estimator = PoseEstimator::default();
+        {
+            let mut l = estimator.sync_results.write();
+            l.insert(0, frame_result(0, 0, Some(((0, pts1.clone()), (33_333, pts2.clone())))));
+            // Last frame, no pair
+            l.insert(33_333, frame_result(1, 33_333, None));
+        }
+
+        let params = pinhole(1200.0);
+        let first = estimator.essential_rotations(0..100_000, &params);
+        assert_eq!(first.len(), 1);
+        assert_eq!(estimator.sync_results.read()[&0].essential_rotation.map(|x| x.0), Some(lens_key(&params)));
+        // The last frame has no pair
+        assert!(estimator.sync_results.read()[&33_333].essential_rotation.is_none());
+
+        // A new estimate from this flow would be another rotation, so this comes from the cache
+        let r2 = *Rotation3::from_euler_angles(-0.03, 0.01, 0.02).matrix();
+        let (pts1, pts2) = moved_points(&r2, 1000.0);
+        estimator.sync_results.read()[&0].optical_flow.borrow_mut().insert(1, Some(((0, pts1), (33_333, pts2))));
+        let cached = estimator.essential_rotations(0..100_000, &params);
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].quat, first[0].quat);
+
+        // Outside of the range
+        assert!(estimator.essential_rotations(1..100_000, &params).is_empty());
+
+        // Another lens profile estimates it again
+        let params = pinhole(1000.0);
+        let recomputed = estimator.essential_rotations(0..100_000, &params);
+        assert_eq!(recomputed.len(), 1);
+        let expected = Quat64::from_rotation_matrix(&Rotation3::from_matrix_unchecked(r2));
+        assert!(recomputed[0].quat.angle_to(&expected) < 1e-3, "{:?}", recomputed[0].quat);
+        assert_eq!(estimator.sync_results.read()[&0].essential_rotation.map(|x| x.0), Some(lens_key(&params)));
+    }
+
+    #[test]
+    fn integrate_and_align() {
+        let axes = Quat64::from_euler_angles(0.3, -1.2, 2.0);
+        // Gyro orientation at a timestamp in ms, moving around all axes
+        let gyro_at = |ts: f64| {
+            let t = ts / 1000.0;
+            Quat64::from_euler_angles((t * 3.0).sin() * 0.4, (t * 2.0).cos() * 0.3, t * 0.5)
+        };
+        let offset_ms = 12.5;
+        let frame_us = 33_333;
+        let rotations = (0..60i64).map(|i| {
+            let (ts1, ts2) = (i * frame_us, (i + 1) * frame_us);
+            let q = gyro_at(ts1 as f64 / 1000.0 - offset_ms).inverse() * gyro_at(ts2 as f64 / 1000.0 - offset_ms);
+            FramePairRotation {
+                timestamp_us: ts1,
+                next_timestamp_us: ts2,
+                quat: (axes * q * axes.inverse()).inverse(),
+                inliers: 100,
+                points: 100,
+                reprojection_error: 0.0,
+            }
+        }).collect::<Vec<_>>();
+
+        let track = integrate_rotations(&rotations);
+        assert_eq!(track.len(), 61);
+        let start = gyro_at(-offset_ms);
+        let end = gyro_at(60.0 * frame_us as f64 / 1000.0 - offset_ms);
+        let expected = axes * (start.inverse() * end) * axes.inverse();
+        assert!(track.values().next_back().unwrap().angle_to(&expected) < 1e-9);
+
+        let alignment = align_with_gyro(&rotations, gyro_at, offset_ms).unwrap();
+        assert!(alignment.rotation.angle_to(&axes) < 1e-6, "{:?}", alignment.rotation);
+        assert!(alignment.max_deg < 1e-6);
+        assert_eq!(alignment.errors.len(), rotations.len());
+
+        // Wrong offset shows up in the error
+        let misaligned = align_with_gyro(&rotations, gyro_at, offset_ms + 15.0).unwrap();
+        assert!(misaligned.rms_deg > 0.01);
+
+        // Rotation around a single axis can't be aligned
+        let single_axis = rotations.iter().map(|r| FramePairRotation { quat: Quat64::from_euler_angles(0.01, 0.0, 0.0), ..*r }).collect::<Vec<_>>();
+        assert!(align_with_gyro(&single_axis, |ts| Quat64::from_euler_angles(ts / 1000.0, 0.0, 0.0), 0.0).is_none());
+    }
+}
@@ -0,0 +1,326 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Editing timelines (CMX3600 EDL and OpenTimelineIO), for stabilizing only the parts of the source clips which are used.
+// Every video event becomes a range of its source file, extended by the handles. The events are grouped by the resolved
+// source file, so each source is loaded and synchronized once, and the ones which can't be found are reported.
+
+use std::path::{ Path, PathBuf };
+use crate::GyroflowCoreError;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SourceTime {
+    Timecode { hours: u32, minutes: u32, seconds: u32, frames: u32, drop_frame: bool },
+    Seconds(f64),
+}
+impl SourceTime {
+    pub fn parse_timecode(tc: &str) -> Option<Self> {
+        let drop_frame = tc.contains(';');
+        let parts = tc.split([':', ';']).map(|x| x.parse::<u32>().ok()).collect::<Option<Vec<_>>>()?;
+        match parts[..] {
+            [hours, minutes, seconds, frames] if minutes < 60 && seconds < 60 => Some(Self::Timecode { hours, minutes, seconds, frames, drop_frame }),
+            _ => None
+        }
+    }
+
+    /// Seconds from midnight, with the timecode counted at `fps`
+    pub fn seconds(&self, fps: f64) -> f64 {
+        match *self {
+            Self::Seconds(x) => x,
+            Self::Timecode { hours, minutes, seconds, frames, drop_frame } => {
+                let nominal = fps.round().max(1.0) as i64;
+                let total_minutes = (hours * 60 + minutes) as i64;
+                let mut frame = (total_minutes * 60 + seconds as i64) * nominal + frames as i64;
+                if drop_frame && (nominal == 30 || nominal == 60) {
+                    // Frame numbers 0 and 1 (or 0-3 at 60 fps) are skipped every minute, except every tenth minute
+                    let dropped = nominal / 15;
+                    frame -= dropped * (total_minutes - total_minutes / 10);
+                    return frame as f64 * 1001.0 / (nominal as f64 * 1000.0);
+                }
+                frame as f64 / fps.max(1.0)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TimelineEvent {
+    pub name: String,                // Event number of the EDL, clip name of the OTIO
+    pub reel: String,
+    pub clip_name: String,           // Source file name, from `* FROM CLIP NAME:` or the media reference
+    pub source_url: Option<String>,  // Media reference of the OTIO
+    pub source_in: SourceTime,
+    pub source_out: SourceTime,
+    pub media_start: Option<SourceTime>, // Start of the source media, in the same time as `source_in`
+}
+
+impl TimelineEvent {
+    /// Range in the source file in ms, with `handles` frames on both sides. `media_start` is the timecode of the first frame
+    /// when the timeline doesn't have it, and the range is clamped to the `duration_ms` of the file
+    pub fn source_range_ms(&self, fps: f64, media_start: Option<SourceTime>, handles: u32, duration_ms: f64) -> (f64, f64) {
+        let start = self.media_start.or(media_start).map(|x| x.seconds(fps)).unwrap_or_default();
+        let handles_s = handles as f64 / fps.max(1.0);
+        let from = (self.source_in.seconds(fps) - start - handles_s) * 1000.0;
+        let to = (self.source_out.seconds(fps) - start + handles_s) * 1000.0;
+        (from.clamp(0.0, duration_ms), to.clamp(0.0, duration_ms))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TimelineSource {
+    pub path: String,
+    pub events: Vec<TimelineEvent>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TimelineImport {
+    pub sources: Vec<TimelineSource>,
+    pub unresolved: Vec<TimelineEvent>,
+}
+
+pub fn parse(filename: &str, data: &str) -> Result<Vec<TimelineEvent>, GyroflowCoreError> {
+    if filename.to_ascii_lowercase().ends_with(".otio") || data.trim_start().starts_with('{') {
+        parse_otio(data)
+    } else {
+        Ok(parse_edl(data))
+    }
+}
+
+/// Video events of a CMX3600 EDL. Audio-only events and lines which aren't events are skipped
+pub fn parse_edl(data: &str) -> Vec<TimelineEvent> {
+    let mut ret: Vec<TimelineEvent> = Vec::new();
+    let mut last_is_video = false;
+    for line in data.lines() {
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix('*') {
+            let comment = comment.trim();
+            if let (Some(event), true) = (ret.last_mut(), last_is_video) {
+                if let Some(name) = comment.strip_prefix("FROM CLIP NAME:") { event.clip_name = name.trim().to_owned(); }
+            }
+            continue;
+        }
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.len() < 8 || tokens[0].parse::<u32>().is_err() { continue; }
+        let tc = tokens[tokens.len() - 4..].iter().map(|x| SourceTime::parse_timecode(x)).collect::<Option<Vec<_>>>();
+        let Some(tc) = tc else { continue; };
+        last_is_video = tokens[2].starts_with('V') || tokens[2] == "B";
+        if !last_is_video { continue; }
+        // A dissolve has two lines with the same event number, the second one is the incoming clip
+        if ret.last().is_some_and(|x| x.name == tokens[0]) { ret.pop(); }
+        ret.push(TimelineEvent {
+            name: tokens[0].to_owned(),
+            reel: tokens[1].to_owned(),
+            clip_name: String::new(),
+            source_url: None,
+            source_in: tc[0],
+            source_out: tc[1],
+            media_start: None,
+        });
+    }
+    ret
+}
+
+/// Clips of the video tracks of an OpenTimelineIO timeline
+pub fn parse_otio(data: &str) -> Result<Vec<TimelineEvent>, GyroflowCoreError> {
+    fn rational(v: &serde_json::Value) -> Option<f64> {
+        let rate = v.get("rate")?.as_f64()?;
+        Some(v.get("value")?.as_f64()? / rate.max(1e-6))
+    }
+    fn walk(v: &serde_json::Value, video: bool, ret: &mut Vec<TimelineEvent>) {
+        let schema = v.get("OTIO_SCHEMA").and_then(|x| x.as_str()).unwrap_or_default();
+        let video = match v.get("kind").and_then(|x| x.as_str()) {
+            Some(kind) if schema.starts_with("Track.") => kind == "Video",
+            _ => video
+        };
+        if schema.starts_with("Clip.") {
+            if !video { return; }
+            let reference = v.get("media_references")
+                .and_then(|refs| refs.get(v.get("active_media_reference_key").and_then(|x| x.as_str()).unwrap_or("DEFAULT_MEDIA")))
+                .or_else(|| v.get("media_reference"));
+            let source_url = reference.and_then(|x| x.get("target_url")).and_then(|x| x.as_str()).map(|x| x.to_owned());
+            let available_range = reference.and_then(|x| x.get("available_range"));
+            let range = v.get("source_range").or(available_range);
+            let (Some(start), Some(duration)) = (range.and_then(|x| rational(x.get("start_time")?)), range.and_then(|x| rational(x.get("duration")?))) else { return; };
+            let name = v.get("name").and_then(|x| x.as_str()).unwrap_or_default().to_owned();
+            ret.push(TimelineEvent {
+                clip_name: source_url.as_deref().map(|x| x.rsplit(['/', '\\']).next().unwrap_or(x).to_owned()).unwrap_or(name.clone()),
+                name,
+                reel: String::new(),
+                source_url,
+                source_in: SourceTime::Seconds(start),
+                source_out: SourceTime::Seconds(start + duration),
+                media_start: available_range.and_then(|x| rational(x.get("start_time")?)).map(SourceTime::Seconds),
+            });
+            return;
+        }
+        let children = v.get("children").or_else(|| v.get("tracks")).or_else(|| v.get("clips"));
+        match children {
+            Some(serde_json::Value::Array(x)) => x.iter().for_each(|x| walk(x, video, ret)),
+            Some(x @ serde_json::Value::Object(_)) => walk(x, video, ret),
+            _ => { }
+        }
+    }
+    let obj: serde_json::Value = serde_json::from_str(data)?;
+    let mut ret = Vec::new();
+    walk(&obj, true, &mut ret);
+    Ok(ret)
+}
+
+/// Finds the source file of `event`: the path from the timeline if it exists, or a file named after the clip or the reel,
+/// next to the timeline or anywhere in the `search_dirs`
+pub fn resolve_media(event: &TimelineEvent, timeline_dir: Option<&Path>, search_dirs: &[PathBuf]) -> Option<PathBuf> {
+    if let Some(url) = &event.source_url {
+        let path = PathBuf::from(crate::filesystem::url_to_path(&crate::filesystem::path_to_url(url)));
+        if path.is_file() { return Some(path); }
+    }
+    let mut names = vec![event.clip_name.clone()];
+    if let Some(url) = &event.source_url { names.push(url.rsplit(['/', '\\']).next().unwrap_or_default().to_owned()); }
+    let names = names.into_iter().filter(|x| !x.is_empty()).map(|x| x.to_lowercase()).collect::<Vec<_>>();
+    let reel = event.reel.to_lowercase();
+
+    let matches = |path: &Path| -> bool {
+        let name = path.file_name().map(|x| x.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let stem = path.file_stem().map(|x| x.to_string_lossy().to_lowercase()).unwrap_or_default();
+        names.contains(&name) || (names.is_empty() && !reel.is_empty() && reel != "ax" && reel != "bl" && stem == reel)
+    };
+    timeline_dir.into_iter().map(|x| (x, 1)).chain(search_dirs.iter().map(|x| (x.as_path(), usize::MAX))).find_map(|(dir, depth)| {
+        walkdir::WalkDir::new(dir).max_depth(depth).into_iter().flatten()
+            .find(|x| x.file_type().is_file() && matches(x.path()))
+            .map(|x| x.into_path())
+    })
+}
+
+/// Groups the events by their source file, in the order of the timeline
+pub fn group_by_source(events: Vec<TimelineEvent>, resolve: impl Fn(&TimelineEvent) -> Option<PathBuf>) -> TimelineImport {
+    let mut ret = TimelineImport::default();
+    for event in events {
+        match resolve(&event) {
+            Some(path) => {
+                let path = path.to_string_lossy().to_string();
+                match ret.sources.iter_mut().find(|x| x.path == path) {
+                    Some(source) => source.events.push(event),
+                    None => ret.sources.push(TimelineSource { path, events: vec![event] }),
+                }
+            },
+            None => ret.unresolved.push(event)
+        }
+    }
+    for source in &mut ret.sources {
+        // Unique names, they are a part of the output filename
+        let mut seen = std::collections::HashMap::<String, usize>::new();
+        for event in &mut source.events {
+            let count = seen.entry(event.name.clone()).or_default();
+            *count += 1;
+            if *count > 1 { event.name = format!("{}_{}", event.name, count); }
+        }
+    }
+    ret
+}
+
+/// `output_filename` of the whole source with the event name after the name of the source, eg. `C0001_012_stabilized.mp4`
+pub fn event_output_filename(output_filename: &str, source_stem: &str, event_name: &str) -> String {
+    let name = event_name.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect::<String>();
+    match output_filename.strip_prefix(source_stem) {
+        Some(rest) => format!("{source_stem}_{name}{rest}"),
+        None => format!("{name}_{output_filename}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDL: &str = "TITLE: Timeline 1
+FCM: NON-DROP FRAME
+
+001  A001C003 V     C        01:00:10:00 01:00:14:12 00:00:00:00 00:00:04:12
+* FROM CLIP NAME: A001C003_230101.MP4
+
+002  A001C003 AA    C        01:00:20:00 01:00:22:00 00:00:04:12 00:00:06:12
+* FROM CLIP NAME: A001C003_230101.MP4
+
+003  A001C005 V     C        00:00:01:00 00:00:01:00 00:00:04:12 00:00:04:12
+003  A001C005 V     D    012 00:00:02:00 00:00:05:00 00:00:04:12 00:00:07:12
+* FROM CLIP NAME: A001C005.MOV
+
+004  A001C003 V     C        01:00:30:00 01:00:31:00 00:00:07:12 00:00:08:12
+* FROM CLIP NAME: A001C003_230101.MP4
+005  MISSING  V     C        00:00:00:00 00:00:01:00 00:00:08:12 00:00:09:12
+";
+
+    #[test]
+    fn edl_events() {
+        let events = parse_edl(EDL);
+        assert_eq!(events.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), ["001", "003", "004", "005"]);
+        assert_eq!(events[0].clip_name, "A001C003_230101.MP4");
+        assert_eq!(events[1].source_in, SourceTime::parse_timecode("00:00:02:00").unwrap());
+        assert_eq!(events[1].clip_name, "A001C005.MOV");
+
+        // 10 s into a clip which starts at 01:00:00:00, with 12 frames of handles at 24 fps
+        let start = SourceTime::parse_timecode("01:00:00:00");
+        let (from, to) = events[0].source_range_ms(24.0, start, 12, 60_000.0);
+        assert!((from - 9500.0).abs() < 1e-6 && (to - 15000.0).abs() < 1e-6, "{from} {to}");
+        assert_eq!(events[1].source_range_ms(25.0, None, 100, 4000.0), (0.0, 4000.0));
+
+        let dir = std::env::temp_dir().join(format!("gyroflow_timeline_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("card1/CLIP")).unwrap();
+        for name in ["card1/CLIP/A001C003_230101.mp4", "card1/CLIP/A001C005.MOV", "card1/CLIP/A001C005.XML"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let import = group_by_source(events, |x| resolve_media(x, None, &[dir.clone()]));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(import.sources.len(), 2);
+        assert!(import.sources[0].path.ends_with("A001C003_230101.mp4"));
+        assert_eq!(import.sources[0].events.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), ["001", "004"]);
+        assert!(import.sources[1].path.ends_with("A001C005.MOV"));
+        assert_eq!(import.unresolved.len(), 1);
+        assert_eq!(import.unresolved[0].reel, "MISSING");
+
+        assert_eq!(event_output_filename("A001C003_230101_stabilized.mp4", "A001C003_230101", "004"), "A001C003_230101_004_stabilized.mp4");
+    }
+
+    #[test]
+    fn otio_clips() {
+        let otio = serde_json::json!({
+            "OTIO_SCHEMA": "Timeline.1",
+            "name": "Edit",
+            "tracks": { "OTIO_SCHEMA": "Stack.1", "children": [
+                { "OTIO_SCHEMA": "Track.1", "kind": "Video", "children": [
+                    { "OTIO_SCHEMA": "Clip.1", "name": "Opening",
+                      "source_range": { "OTIO_SCHEMA": "TimeRange.1", "start_time": { "rate": 24.0, "value": 86520.0 }, "duration": { "rate": 24.0, "value": 48.0 } },
+                      "media_reference": { "OTIO_SCHEMA": "ExternalReference.1", "target_url": "file:///media/GX010123.MP4",
+                          "available_range": { "start_time": { "rate": 24.0, "value": 86400.0 }, "duration": { "rate": 24.0, "value": 2400.0 } } } },
+                    { "OTIO_SCHEMA": "Gap.1", "source_range": { "start_time": { "rate": 24.0, "value": 0.0 }, "duration": { "rate": 24.0, "value": 24.0 } } },
+                    { "OTIO_SCHEMA": "Clip.2", "name": "Opening",
+                      "source_range": { "start_time": { "rate": 24.0, "value": 240.0 }, "duration": { "rate": 24.0, "value": 24.0 } },
+                      "active_media_reference_key": "DEFAULT_MEDIA",
+                      "media_references": { "DEFAULT_MEDIA": { "OTIO_SCHEMA": "ExternalReference.1", "target_url": "/media/GX010124.MP4" } } },
+                ]},
+                { "OTIO_SCHEMA": "Track.1", "kind": "Audio", "children": [
+                    { "OTIO_SCHEMA": "Clip.1", "name": "Music", "source_range": { "start_time": { "rate": 48000.0, "value": 0.0 }, "duration": { "rate": 48000.0, "value": 48000.0 } },
+                      "media_reference": { "target_url": "/media/music.wav" } }
+                ]}
+            ]}
+        }).to_string();
+
+        let events = parse("edit.otio", &otio).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].clip_name, "GX010123.MP4");
+        assert_eq!(events[0].source_range_ms(24.0, None, 0, 100_000.0), (5000.0, 7000.0));
+        assert_eq!(events[1].source_url.as_deref(), Some("/media/GX010124.MP4"));
+        assert_eq!(events[1].source_range_ms(24.0, None, 24, 100_000.0), (9000.0, 12000.0));
+
+        let import = group_by_source(events, |_| Some(PathBuf::from("/media/GX010123.MP4")));
+        assert_eq!(import.sources[0].events.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), ["Opening", "Opening_2"]);
+    }
+
+    #[test]
+    fn drop_frame_timecode() {
+        // 00:01:00;02 is the 1800th frame at 29.97 fps
+        let tc = SourceTime::parse_timecode("00:01:00;02").unwrap();
+        assert!((tc.seconds(29.97) - 1800.0 * 1001.0 / 30000.0).abs() < 1e-9);
+        let tc = SourceTime::parse_timecode("00:10:00;00").unwrap();
+        assert!((tc.seconds(29.97) - 17982.0 * 1001.0 / 30000.0).abs() < 1e-9);
+    }
+}
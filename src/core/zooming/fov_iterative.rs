@@ -2,10 +2,12 @@
 // Copyright © 2022 Maik <myco at gmx>
 
 use super::*;
-use crate::stabilization::undistort_points_with_rolling_shutter;
+use crate::stabilization::undistort_points_with_correction;
+use crate::gyro_source::Quat64;
 use crate::keyframes::*;
 use parking_lot::RwLock;
 use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+use rayon::slice::ParallelSlice;
 
 /*
 Iterative FOV calculation:
@@ -16,6 +18,16 @@ Iterative FOV calculation:
     - repeat shrinking the rectangle
 */
 
+/// FOVs found for each frame, before the trim ranges are applied, and the zooming center and lens correction they were found with.
+/// Valid for the same `key` (see `geometry_checksum`), then only the frames with different keyframe values need to be searched again
+#[derive(Default, Clone)]
+pub struct FovCache {
+    key: u64,
+    timestamps: Vec<(usize, f64)>,
+    keyframe_values: Vec<(f64, f64, f64)>,
+    fovs: Vec<f64>,
+}
+
 pub struct FovIterative<'a> {
     input_dim: (f32, f32),
     output_dim: (f32, f32),
@@ -29,39 +41,59 @@ impl FieldOfViewAlgorithm for FovIterative<'_> {
     }
 
     fn compute(&self, timestamps: &[(usize, f64)], ranges: &[(f64, f64)]) -> Vec<f64> {
+        self.compute_cached(timestamps, ranges, &mut FovCache::default(), 0)
+    }
+}
+
+impl<'a>  FovIterative<'a> {
+    pub fn compute_cached(&self, timestamps: &[(usize, f64)], ranges: &[(f64, f64)], cache: &mut FovCache, key: u64) -> Vec<f64> {
         if timestamps.is_empty() {
             return Vec::new();
         }
-        let l = (timestamps.len() - 1) as f64;
         let keyframes = &self.compute_params.keyframes;
 
         let rect = self.points_around_rect(self.input_dim.0, self.input_dim.1, 31, 31);
 
         let cp = Point2D(self.input_dim.0 / 2.0, self.input_dim.1 / 2.0);
-        let mut fov_values: Vec<f64> = if keyframes.is_keyframed(&KeyframeType::ZoomingCenterX) || keyframes.is_keyframed(&KeyframeType::ZoomingCenterY) || keyframes.is_keyframed(&KeyframeType::LensCorrectionStrength) {
-            timestamps.into_par_iter()
-                .map(|&(frame, ts)| {
-                    let adaptive_zoom_center_x = self.compute_params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterX, ts).unwrap_or(self.compute_params.adaptive_zoom_center_offset.0);
-                    let adaptive_zoom_center_y = self.compute_params.keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterY, ts).unwrap_or(self.compute_params.adaptive_zoom_center_offset.1);
-                    let lens_correction_amount = self.compute_params.keyframes.value_at_video_timestamp(&KeyframeType::LensCorrectionStrength, ts).unwrap_or(self.compute_params.lens_correction_amount);
-
-                    let kv = (adaptive_zoom_center_x, adaptive_zoom_center_y, lens_correction_amount);
-                    self.find_fov(&rect, ts, frame, &cp, &kv)
-                })
-                .collect()
+        let keyframe_values: Vec<(f64, f64, f64)> = if keyframes.is_keyframed(&KeyframeType::ZoomingCenterX) || keyframes.is_keyframed(&KeyframeType::ZoomingCenterY) || keyframes.is_keyframed(&KeyframeType::LensCorrectionStrength) {
+            timestamps.into_par_iter().map(|&(_, ts)| self.keyframe_values(ts)).collect()
         } else {
-            let kv = (self.compute_params.adaptive_zoom_center_offset.0, self.compute_params.adaptive_zoom_center_offset.1, self.compute_params.lens_correction_amount);
-            timestamps.into_par_iter()
-                .map(|&(frame, ts)| self.find_fov(&rect, ts, frame, &cp, &kv))
-                .collect()
+            vec![(self.compute_params.adaptive_zoom_center_offset.0, self.compute_params.adaptive_zoom_center_offset.1, self.compute_params.lens_correction_amount); timestamps.len()]
         };
 
+        // Debug points are collected during the search, so they need all frames.
+        // In the deterministic mode every frame is searched, so the result doesn't depend on what was computed before
+        let reuse = cache.key == key && cache.timestamps == timestamps && !self.compute_params.zooming_debug_points && !self.compute_params.deterministic;
+        if !reuse {
+            *cache = FovCache { key, timestamps: timestamps.to_vec(), keyframe_values: Vec::new(), fovs: vec![0.0; timestamps.len()] };
+        }
+        let changed = (0..timestamps.len()).filter(|&i| !reuse || cache.keyframe_values[i] != keyframe_values[i]).collect::<Vec<_>>();
+        let cancel_token = &self.compute_params.cancel_token;
+        let (rect, cp, keyframe_values_ref) = (&rect, &cp, &keyframe_values);
+        let found: Vec<f64> = changed.par_chunks(64)
+            .flat_map_iter(|chunk| {
+                let cancelled = cancel_token.is_cancelled();
+                chunk.iter().map(move |&i| if cancelled { 1.0 } else { self.find_fov(rect, timestamps[i].1, timestamps[i].0, cp, &keyframe_values_ref[i], None) })
+            })
+            .collect();
+        if cancel_token.is_cancelled() {
+            // Partial results, don't keep them
+            *cache = FovCache::default();
+            return vec![1.0; timestamps.len()];
+        }
+        log::debug!("FOV searched in {} of {} frames", changed.len(), timestamps.len());
+        for (i, fov) in changed.into_iter().zip(found) {
+            cache.fovs[i] = fov;
+        }
+        cache.keyframe_values = keyframe_values;
+
+        let mut fov_values = cache.fovs.clone();
         if !ranges.is_empty() {
             // Only within render range.
             if let Some(max_fov) = fov_values.iter().copied().reduce(f64::max) {
+                let frame_count = fov_values.len();
                 for (i, v) in fov_values.iter_mut().enumerate() {
-                    let within_range = ranges.iter().any(|r| i >= (l*r.0).floor() as usize && i <= (l*r.1).ceil() as usize);
-                    if !within_range {
+                    if !super::is_frame_in_ranges(i, frame_count, ranges) {
                         *v = max_fov;
                     }
                 }
@@ -70,9 +102,7 @@ impl FieldOfViewAlgorithm for FovIterative<'_> {
 
         fov_values
     }
-}
 
-impl<'a>  FovIterative<'a> {
     pub fn new(compute_params: &'a ComputeParams, org_output_size: (usize, usize)) -> Self {
         let ratio = compute_params.width as f32 / org_output_size.0.max(1) as f32;
         let input_dim = (compute_params.width as f32, compute_params.height as f32);
@@ -88,19 +118,45 @@ impl<'a>  FovIterative<'a> {
         }
     }
 
-    fn find_fov(&self, rect: &[(f32, f32)], ts: f64, frame: usize, center: &Point2D, keyframe_values: &(f64, f64, f64)) -> f64 {
+    // (zooming center x, zooming center y, lens correction amount)
+    fn keyframe_values(&self, ts: f64) -> (f64, f64, f64) {
+        let keyframes = &self.compute_params.keyframes;
+        (
+            keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterX, ts).unwrap_or(self.compute_params.adaptive_zoom_center_offset.0),
+            keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterY, ts).unwrap_or(self.compute_params.adaptive_zoom_center_offset.1),
+            keyframes.value_at_video_timestamp(&KeyframeType::LensCorrectionStrength, ts).unwrap_or(self.compute_params.lens_correction_amount)
+        )
+    }
+
+    /// FOV at which the frame still covers the output, if the camera `correction` (smoothed -> raw rotation) was applied at `ts`.
+    /// This is the same value the adaptive zoom computes from the current smoothed quaternions
+    pub fn required_fov(&self, ts: f64, frame: usize, correction: &Quat64) -> f64 {
+        let rect = self.points_around_rect(self.input_dim.0, self.input_dim.1, 31, 31);
+        let cp = Point2D(self.input_dim.0 / 2.0, self.input_dim.1 / 2.0);
+        self.find_fov(&rect, ts, frame, &cp, &self.keyframe_values(ts), Some(correction))
+    }
+
+    /// FOV at which the frame covers the output with the zooming center at `center_offset` instead of the one from the keyframes
+    pub fn required_fov_at_center(&self, ts: f64, frame: usize, center_offset: (f64, f64)) -> f64 {
+        let rect = self.points_around_rect(self.input_dim.0, self.input_dim.1, 31, 31);
+        let cp = Point2D(self.input_dim.0 / 2.0, self.input_dim.1 / 2.0);
+        let lens_correction_amount = self.keyframe_values(ts).2;
+        self.find_fov(&rect, ts, frame, &cp, &(center_offset.0, center_offset.1, lens_correction_amount), None)
+    }
+
+    fn find_fov(&self, rect: &[(f32, f32)], ts: f64, frame: usize, center: &Point2D, keyframe_values: &(f64, f64, f64), correction: Option<&Quat64>) -> f64 {
         let ts_us = (ts * 1000.0).round() as i64;
 
         let adaptive_zoom_center_x = keyframe_values.0;
         let adaptive_zoom_center_y = keyframe_values.1;
         let lens_correction_amount = keyframe_values.2;
 
-        let mut polygon = undistort_points_with_rolling_shutter(&rect, ts, Some(frame), &self.compute_params, lens_correction_amount, false);
+        let mut polygon = undistort_points_with_correction(&rect, ts, Some(frame), &self.compute_params, lens_correction_amount, false, correction);
         for (x, y) in polygon.iter_mut() {
             *x -= adaptive_zoom_center_x as f32 * self.input_dim.0;
             *y -= adaptive_zoom_center_y as f32 * self.input_dim.1;
         }
-        if self.compute_params.zooming_debug_points {
+        if self.compute_params.zooming_debug_points && correction.is_none() {
             self.debug_points.write().insert(ts_us, polygon.iter().map(|(x, y)| ((x / self.input_dim.0) as f64, (y / self.input_dim.1) as f64)).collect());
         }
 
@@ -119,7 +175,7 @@ impl<'a>  FovIterative<'a> {
                 ];
 
                 let distorted = interpolate_points(&relevant, 30);
-                polygon = undistort_points_with_rolling_shutter(&distorted, ts, Some(frame), &self.compute_params, lens_correction_amount, false);
+                polygon = undistort_points_with_correction(&distorted, ts, Some(frame), &self.compute_params, lens_correction_amount, false, correction);
                 for (x, y) in polygon.iter_mut() {
                     *x -= adaptive_zoom_center_x as f32 * self.input_dim.0;
                     *y -= adaptive_zoom_center_y as f32 * self.input_dim.1;
@@ -3,12 +3,20 @@
 
 pub mod fov_iterative;
 pub mod zoom_dynamic;
+pub mod zoom_budget;
+pub mod zoom_data;
 
 use std::collections::hash_map::DefaultHasher;
 use std::hash::Hasher;
 use std::collections::BTreeMap;
 
 use crate::stabilization::ComputeParams;
+use crate::keyframes::KeyframeType;
+use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+
+const CENTER_SEARCH_ITERATIONS: usize = 10;
+// The limited zooming center is spread over this long part of the clip around the frame
+const CENTER_RELAXATION_MS: f64 = 500.0;
 
 #[derive(Default, Clone, Copy, Debug)]
 pub struct Point2D(f32, f32);
@@ -33,27 +41,25 @@ pub trait FieldOfViewAlgorithm {
 }
 
 pub fn calculate_fovs(compute_params: &ComputeParams, timestamps: &[(usize, f64)], method: ZoomMethod) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>)  {
+    calculate_fovs_cached(compute_params, timestamps, method, &mut fov_iterative::FovCache::default(), 0)
+}
+
+/// Like `calculate_fovs`, but the per-frame FOV search is reused from the `cache` when the geometry didn't change.
+/// `revision` has to change whenever anything the search depends on changes, other than what's in `geometry_checksum`
+pub fn calculate_fovs_cached(compute_params: &ComputeParams, timestamps: &[(usize, f64)], method: ZoomMethod, cache: &mut fov_iterative::FovCache, revision: u64) -> (Vec<f64>, Vec<f64>, BTreeMap<i64, Vec<(f64, f64)>>)  {
     if timestamps.is_empty() {
         return Default::default();
     }
 
-    let mut compute_params = compute_params.clone();
-    compute_params.fov_scale = 1.0;
-    compute_params.fovs.clear();
-    compute_params.minimal_fovs.clear();
-
-    // Use original video dimensions, because this is used to undistort points, and we need to find original image bounding box
-    // Then we can use real `output_dim` to fit the fov
-    let org_output_size = (compute_params.output_width, compute_params.output_height);
-    compute_params.output_width = compute_params.width;
-    compute_params.output_height = compute_params.height;
+    let key = geometry_checksum(compute_params, revision);
+    let (compute_params, org_output_size) = fov_compute_params(compute_params);
 
     let fov_estimator = fov_iterative::FovIterative::new(&compute_params, org_output_size);
-    let mut fov_values = fov_estimator.compute(timestamps, &compute_params.trim_ranges);
+    let mut fov_values = fov_estimator.compute_cached(timestamps, &compute_params.trim_ranges, cache, key);
     let (final_fovs, final_fovs_minimal) = if compute_params.adaptive_zoom_window < -0.9 {
         // Static zoom
         let fov_minimal = fov_values.clone();
-        if let Some(max_f) = fov_values.iter().copied().reduce(f64::min) {
+        if let Some(max_f) = static_fov(&fov_values, compute_params.static_zoom_percentile, &compute_params.trim_ranges) {
             fov_values.iter_mut().for_each(|v| *v = max_f);
         }
         (fov_values, fov_minimal)
@@ -67,25 +73,279 @@ pub fn calculate_fovs(compute_params: &ComputeParams, timestamps: &[(usize, f64)
     (final_fovs, final_fovs_minimal, fov_estimator.get_debug_points())
 }
 
-pub fn get_checksum(compute_params: &ComputeParams) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    for x in &compute_params.lens.get_distortion_coeffs() {
-        hasher.write_u64(x.to_bits());
+pub fn is_frame_in_ranges(frame: usize, frame_count: usize, ranges: &[(f64, f64)]) -> bool {
+    let l = frame_count.saturating_sub(1) as f64;
+    ranges.is_empty() || ranges.iter().any(|r| frame >= (l * r.0).floor() as usize && frame <= (l * r.1).ceil() as usize)
+}
+
+/// FOV of the static zoom, which covers `percentile` % of the frames within the trim ranges. At 100% it's the FOV of the worst frame
+pub fn static_fov(fov_values: &[f64], percentile: f64, ranges: &[(f64, f64)]) -> Option<f64> {
+    let mut sorted = fov_values.iter().enumerate()
+        .filter(|(i, _)| is_frame_in_ranges(*i, fov_values.len(), ranges))
+        .map(|(_, v)| *v)
+        .collect::<Vec<_>>();
+    if sorted.is_empty() { return None; }
+    sorted.sort_by(f64::total_cmp);
+    // Number of frames allowed to need more zoom
+    let exceeding = (sorted.len() as f64 * (1.0 - percentile.clamp(0.0, 100.0) / 100.0)).floor() as usize;
+    Some(sorted[exceeding.min(sorted.len() - 1)])
+}
+
+/// Frames within the trim ranges which need more zoom than they have, as (frame, needed zoom / zoom)
+pub fn exceeding_frames(fovs: &[f64], minimal_fovs: &[f64], ranges: &[(f64, f64)]) -> Vec<(usize, f64)> {
+    fovs.iter().zip(minimal_fovs).enumerate()
+        .filter(|(i, (fov, min))| *fov > *min + 1e-6 && is_frame_in_ranges(*i, fovs.len(), ranges))
+        .map(|(i, (fov, min))| (i, fov / min))
+        .collect()
+}
+
+/// Smallest FOV allowed by the zoom limit at the frame, with its keyframes and the video speed
+pub fn max_zoom_fov_limit(compute_params: &ComputeParams, frame: usize) -> f64 {
+    let keyframes = &compute_params.keyframes;
+    let ts = crate::timestamp_at_frame(frame as i32, compute_params.scaled_fps);
+    let mut zoom_limit = keyframes.value_at_video_timestamp(&KeyframeType::MaxZoom, ts).unwrap_or(compute_params.max_zoom.unwrap_or(0.0)) / 100.0;
+
+    if compute_params.video_speed_affects_zooming_limit && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed)) {
+        let vid_speed = keyframes.value_at_video_timestamp(&KeyframeType::VideoSpeed, ts).unwrap_or(compute_params.video_speed).abs();
+        zoom_limit *= (1.0 + ((vid_speed - 1.0) / 4.0)).min(1.8);
+    }
+
+    let scaling_factor = compute_params.width as f64 / compute_params.output_width as f64;
+    1.0 / (zoom_limit * scaling_factor)
+}
+
+/// Clamps `fovs` to the zoom limit. For each frame, returns whether the clamp made the FOV larger than `minimal_fovs`,
+/// ie. the frame now shows the outside of the image and it's up to the background mode to fill it
+pub fn clamp_to_max_zoom(compute_params: &mut ComputeParams) -> Vec<bool> {
+    let limits = (0..compute_params.fovs.len()).map(|i| max_zoom_fov_limit(compute_params, i)).collect::<Vec<_>>();
+    let minimal_fovs = &compute_params.minimal_fovs;
+    compute_params.fovs.iter_mut().zip(limits).enumerate().map(|(i, (fov, limit))| {
+        if !limit.is_finite() || *fov >= limit { return false; }
+        *fov = limit;
+        minimal_fovs.get(i).is_some_and(|min| limit > *min + 1e-6)
+    }).collect()
+}
+
+/// Zooming center of the frame, from the keyframes
+pub fn center_offset_at(compute_params: &ComputeParams, timestamp_ms: f64) -> (f64, f64) {
+    let keyframes = &compute_params.keyframes;
+    (
+        keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterX, timestamp_ms).unwrap_or(compute_params.adaptive_zoom_center_offset.0),
+        keyframes.value_at_video_timestamp(&KeyframeType::ZoomingCenterY, timestamp_ms).unwrap_or(compute_params.adaptive_zoom_center_offset.1),
+    )
+}
+
+/// Where the zoom limit shows the outside of the image because of the zooming center offset, the center is moved toward the middle
+/// until the frame covers the output at the limit. The fraction is spread over the neighboring frames, so the composition drifts back smoothly.
+/// Returns the fraction of the offset for each frame (empty if nothing was limited) and updates `overflow` to the frames which still show the outside
+pub fn limit_center_offset(compute_params: &ComputeParams, overflow: &mut [bool]) -> Vec<f64> {
+    if !overflow.iter().any(|x| *x) || compute_params.fovs.len() != overflow.len() { return Vec::new(); }
+
+    let fps = compute_params.scaled_fps;
+    let (fov_params, org_output_size) = fov_compute_params(compute_params);
+    let fov_estimator = fov_iterative::FovIterative::new(&fov_params, org_output_size);
+
+    // (fraction of the offset, whether the frame covers the output with it)
+    let limits: Vec<(f64, bool)> = (0..overflow.len()).into_par_iter().map(|frame| {
+        if !overflow[frame] { return (1.0, true); }
+        let ts = crate::timestamp_at_frame(frame as i32, fps);
+        let center = center_offset_at(compute_params, ts);
+        if center == (0.0, 0.0) { return (1.0, false); }
+        let fits = |scale: f64| fov_estimator.required_fov_at_center(ts, frame, (center.0 * scale, center.1 * scale)) >= compute_params.fovs[frame];
+        if !fits(0.0) { return (0.0, false); }
+
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..CENTER_SEARCH_ITERATIONS {
+            let mid = (low + high) / 2.0;
+            if fits(mid) { low = mid; } else { high = mid; }
+        }
+        (low, true)
+    }).collect();
+
+    for (o, (_, fits)) in overflow.iter_mut().zip(limits.iter()) {
+        *o = !fits;
     }
+    let radius = ((CENTER_RELAXATION_MS / 1000.0 * fps).round() as usize).max(1);
+    zoom_budget::relax(&limits.iter().map(|x| x.0).collect::<Vec<_>>(), radius)
+}
+
+/// Parameters for finding the FOV, and the original output size
+pub fn fov_compute_params(compute_params: &ComputeParams) -> (ComputeParams, (usize, usize)) {
+    let mut compute_params = compute_params.clone();
+    compute_params.fov_scale = 1.0;
+    compute_params.fovs.clear();
+    compute_params.minimal_fovs.clear();
 
+    // Use original video dimensions, because this is used to undistort points, and we need to find original image bounding box
+    // Then we can use real `output_dim` to fit the fov
+    let org_output_size = (compute_params.output_width, compute_params.output_height);
+    compute_params.output_width = compute_params.width;
+    compute_params.output_height = compute_params.height;
+
+    (compute_params, org_output_size)
+}
+
+/// Checksum of what the per-frame FOV search depends on and what doesn't invalidate the zooming when changed.
+/// The smoothed quaternions are included, because the max zoom changes them without invalidating anything
+pub fn geometry_checksum(compute_params: &ComputeParams, revision: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(revision);
+    if let Ok(lens) = serde_json::to_string(&compute_params.lens) {
+        hasher.write(lens.as_bytes());
+    }
     hasher.write_usize(compute_params.width);
     hasher.write_usize(compute_params.height);
     hasher.write_usize(compute_params.output_width);
     hasher.write_usize(compute_params.output_height);
     hasher.write_u64(compute_params.scaled_fps.to_bits());
-    hasher.write_u64(compute_params.max_zoom.unwrap_or_default().to_bits());
-    hasher.write_usize(compute_params.max_zoom_iterations);
-    for x in compute_params.trim_ranges.iter() {
-        hasher.write_u64(x.0.to_bits());
-        hasher.write_u64(x.1.to_bits());
-    }
     hasher.write_u64(compute_params.video_rotation.to_bits());
-    hasher.write_u64(compute_params.adaptive_zoom_window.to_bits());
-
+    hasher.write_u64(compute_params.frame_readout_time.to_bits());
+    hasher.write_u8(compute_params.frame_readout_direction as u8);
+    hasher.write_u32(compute_params.fov_algorithm_margin.to_bits());
+    for (ts, q) in compute_params.gyro.read().smoothed_quaternions.iter() {
+        hasher.write_i64(*ts);
+        for x in q.coords.iter() { hasher.write_u64(x.to_bits()); }
+    }
     hasher.finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_zoom_clamp() {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.output_width) = (1920, 1920);
+        params.scaled_fps = 30.0;
+        params.max_zoom = Some(150.0);
+        params.video_speed_affects_zooming_limit = false;
+        // Zoomed in to 1.25x, 2x and 1.6x, with the frame needing 1.25x, 1.8x and 1.4x to cover the output
+        params.fovs = vec![0.8, 0.5, 1.0 / 1.6];
+        params.minimal_fovs = vec![0.8, 1.0 / 1.8, 1.0 / 1.4];
+
+        let overflow = clamp_to_max_zoom(&mut params);
+        assert_eq!(params.fovs[0], 0.8);
+        assert!((params.fovs[1] - 1.0 / 1.5).abs() < 1e-9 && (params.fovs[2] - 1.0 / 1.5).abs() < 1e-9);
+        // Cropped less than needed only in the second frame
+        assert_eq!(overflow, vec![false, true, false]);
+
+        // Keyframed limit
+        params.keyframes.set(&KeyframeType::MaxZoom, 0, 200.0);
+        params.fovs = vec![0.5, 0.4];
+        params.minimal_fovs = vec![0.5, 0.4];
+        assert_eq!(clamp_to_max_zoom(&mut params), vec![false, true]);
+        assert_eq!(params.fovs, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn static_zoom_percentile() {
+        // One bump needing 2x zoom, the rest 1.1x - 1.2x
+        let mut fovs = (0..100).map(|i| 1.0 / (1.1 + 0.001 * i as f64)).collect::<Vec<_>>();
+        fovs[10] = 0.5;
+        assert_eq!(static_fov(&fovs, 100.0, &[]), Some(0.5));
+        let fov = static_fov(&fovs, 99.0, &[]).unwrap();
+        assert_eq!(fov, fovs[99]);
+
+        let exceeding = exceeding_frames(&[fov; 100], &fovs, &[]);
+        assert_eq!(exceeding.len(), 1);
+        assert_eq!(exceeding[0].0, 10);
+        assert!((exceeding[0].1 - fov * 2.0).abs() < 1e-9);
+
+        // Only within the trim range, here without the bump
+        assert_eq!(static_fov(&fovs, 100.0, &[(0.5, 1.0)]), Some(fovs[99]));
+        assert!(exceeding_frames(&[fov; 100], &fovs, &[(0.5, 1.0)]).is_empty());
+        assert_eq!(static_fov(&[], 99.0, &[]), None);
+    }
+
+    // Pinhole camera, 1080p, 30 fps
+    fn pinhole(frames: usize) -> ComputeParams {
+        use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+        use crate::stabilization::distortion_models::DistortionModel;
+
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.height, params.output_width, params.output_height) = (1920, 1080, 1920, 1080);
+        params.frame_count = frames;
+        params.scaled_fps = 30.0;
+        params.scaled_duration_ms = frames as f64 * 1000.0 / 30.0;
+        params.lens = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.0; 5],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        params.distortion_model = DistortionModel::from_name("opencv_standard");
+        params.trim_ranges.clear();
+        params.gyro.write().duration_ms = params.scaled_duration_ms;
+        params
+    }
+
+    #[test]
+    fn cached_search_matches_full() {
+        use crate::gyro_source::{ TimeQuat, Quat64 };
+
+        let frames = 90;
+        let mut params = pinhole(frames);
+        params.adaptive_zoom_window = 2.0;
+        let corrections: TimeQuat = (0..=300).map(|i| (i * 10_000, Quat64::from_euler_angles(0.0, (3.0 * (i as f64 * 0.05).sin()).to_radians(), 0.0))).collect();
+        params.gyro.write().set_smoothed_quaternions(corrections);
+
+        let timestamps = (0..frames).map(|i| (i, i as f64 * 1000.0 / 30.0)).collect::<Vec<_>>();
+        let mut cache = fov_iterative::FovCache::default();
+        let mut check = |params: &ComputeParams| {
+            let (fovs, minimal_fovs, _) = calculate_fovs_cached(params, &timestamps, ZoomMethod::GaussianFilter, &mut cache, 1);
+            let (full_fovs, full_minimal_fovs, _) = calculate_fovs(params, &timestamps, ZoomMethod::GaussianFilter);
+            assert_eq!(fovs, full_fovs);
+            assert_eq!(minimal_fovs, full_minimal_fovs);
+            minimal_fovs
+        };
+        let org = check(&params);
+        assert!(org.iter().any(|x| *x < org[0] - 1e-3));
+
+        // Only the frames after the first keyframe have a different zooming center
+        params.keyframes.set(&KeyframeType::ZoomingCenterX, 1_000_000, 0.0);
+        params.keyframes.set(&KeyframeType::ZoomingCenterX, 2_000_000, 0.05);
+        let moved = check(&params);
+        assert_eq!(moved[..30], org[..30]);
+        assert_ne!(moved[60..], org[60..]);
+
+        params.adaptive_zoom_window = 4.0;
+        params.trim_ranges = vec![(0.2, 0.8)];
+        check(&params);
+    }
+
+    #[test]
+    fn center_offset_within_max_zoom() {
+        let frames = 150;
+        let mut params = pinhole(frames);
+        // Composed to the side between 2.5 and 3 s
+        for (ts, v) in [(2_400_000, 0.0), (2_500_000, 0.2), (3_000_000, 0.2), (3_100_000, 0.0)] {
+            params.keyframes.set(&KeyframeType::ZoomingCenterX, ts, v);
+        }
+        let (fov_params, org_output_size) = fov_compute_params(&params);
+        let estimator = fov_iterative::FovIterative::new(&fov_params, org_output_size);
+        let required = |frame: usize, offset: f64| estimator.required_fov_at_center(frame as f64 * 1000.0 / 30.0, frame, (offset, 0.0));
+
+        // The zoom limit is between the zoom needed in the middle and with the offset
+        let limit = (required(80, 0.0) + required(80, 0.2)) / 2.0;
+        params.fovs = vec![limit; frames];
+        let mut overflow = (0..frames).map(|i| (75..=90).contains(&i)).collect::<Vec<_>>();
+
+        let scale = limit_center_offset(&params, &mut overflow);
+        assert_eq!(scale.len(), frames);
+        assert!(overflow.iter().all(|x| !*x));
+        for (frame, s) in scale.iter().enumerate().take(91).skip(75) {
+            assert!(*s > 0.1 && *s < 0.9, "{frame}: {s}");
+            assert!(required(frame, 0.2 * s) >= limit - 1e-6);
+        }
+        assert!(scale[..10].iter().chain(&scale[140..]).all(|x| *x == 1.0));
+        assert!(scale.iter().zip(scale.iter().skip(1)).all(|(a, b)| (a - b).abs() < 0.1));
+
+        // Nothing to limit
+        let mut overflow = vec![false; frames];
+        assert!(limit_center_offset(&params, &mut overflow).is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Limits the camera correction, so the frame always covers the output when there's no zooming.
+// For every frame, the correction is scaled toward no correction until the FOV required to hide the black corners
+// (the same geometry as the adaptive zoom) fits the FOV set by the user or its keyframes.
+// The per-frame limit is then eroded and averaged over the relaxation window, so it's spread over the neighboring frames
+// without a sudden change of speed, and it's still never above the limit of any frame.
+
+use super::*;
+use super::fov_iterative::FovIterative;
+use crate::gyro_source::{ TimeQuat, Quat64 };
+use crate::keyframes::KeyframeType;
+use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+
+// The limit is spread over this long part of the clip around the frame
+const RELAXATION_MS: f64 = 250.0;
+const SEARCH_ITERATIONS: usize = 10;
+// The correction between the frames is interpolated, so the FOV is checked with a bit of reserve
+const FOV_MARGIN: f64 = 1.003;
+
+pub fn is_no_zoom(adaptive_zoom_window: f64) -> bool {
+    (-0.9..=0.0001).contains(&adaptive_zoom_window)
+}
+pub fn is_enabled(compute_params: &ComputeParams) -> bool {
+    compute_params.limit_to_zoom_budget && is_no_zoom(compute_params.adaptive_zoom_window)
+}
+
+fn correction_at(corrections: &TimeQuat, timestamp_us: i64) -> Quat64 {
+    let prev = corrections.range(..=timestamp_us).next_back();
+    let next = corrections.range(timestamp_us..).next();
+    match (prev, next) {
+        (Some(a), Some(b)) if b.0 > a.0 => a.1.slerp(b.1, (timestamp_us - a.0) as f64 / (b.0 - a.0) as f64),
+        (Some(a), _) => *a.1,
+        (_, Some(b)) => *b.1,
+        _ => Quat64::identity()
+    }
+}
+
+fn limit_at(limits: &BTreeMap<i64, f64>, timestamp_us: i64) -> f64 {
+    let prev = limits.range(..=timestamp_us).next_back();
+    let next = limits.range(timestamp_us..).next();
+    match (prev, next) {
+        (Some(a), Some(b)) if b.0 > a.0 => a.1 + (b.1 - a.1) * (timestamp_us - a.0) as f64 / (b.0 - a.0) as f64,
+        (Some(a), _) => *a.1,
+        (_, Some(b)) => *b.1,
+        _ => 1.0
+    }
+}
+
+/// Fraction of the correction (0 - 1) for each frame at which the frame still covers the output.
+/// `timestamps` are (frame, video timestamp in ms), `gyro_timestamps` are the same in the gyro time
+pub fn frame_limits(compute_params: &ComputeParams, corrections: &TimeQuat, timestamps: &[(usize, f64)], gyro_timestamps: &[f64]) -> Vec<f64> {
+    let (fov_params, org_output_size) = fov_compute_params(compute_params);
+    let fov_estimator = FovIterative::new(&fov_params, org_output_size);
+
+    (0..timestamps.len()).into_par_iter().map(|i| {
+        let (frame, ts) = timestamps[i];
+        let fov_limit = compute_params.keyframes.value_at_video_timestamp(&KeyframeType::Fov, ts).unwrap_or(compute_params.fov_scale) * FOV_MARGIN;
+        let fits = |correction: &Quat64| fov_estimator.required_fov(ts, frame, correction) >= fov_limit;
+
+        let correction = correction_at(corrections, (gyro_timestamps[i] * 1000.0).round() as i64);
+        if fits(&correction) { return 1.0; }
+        let identity = Quat64::identity();
+        if !fits(&identity) { return 0.0; }
+
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..SEARCH_ITERATIONS {
+            let mid = (low + high) / 2.0;
+            if fits(&identity.slerp(&correction, mid)) { low = mid; } else { high = mid; }
+        }
+        low
+    }).collect()
+}
+
+/// Erosion with twice the radius, then two box filters. Every output value is an average of the eroded values
+/// within twice the radius, and each of them is the minimum over a range which includes the output frame, so it's never above the input
+pub fn relax(limits: &[f64], radius: usize) -> Vec<f64> {
+    let n = limits.len();
+    let window = |i: usize, r: usize| i.saturating_sub(r)..(i + r + 1).min(n);
+    let average = |v: &[f64]| -> Vec<f64> {
+        (0..n).map(|i| { let w = &v[window(i, radius)]; w.iter().sum::<f64>() / w.len() as f64 }).collect()
+    };
+    let eroded = (0..n).map(|i| limits[window(i, radius * 2)].iter().copied().fold(1.0, f64::min)).collect::<Vec<_>>();
+    average(&average(&eroded))
+}
+
+/// Scales down the `corrections` (smoothed -> raw rotations by gyro timestamp) where the frame wouldn't cover the output
+pub fn limit_corrections(compute_params: &ComputeParams, corrections: &mut TimeQuat) {
+    if !is_enabled(compute_params) || corrections.len() < 2 || compute_params.frame_count == 0 || compute_params.scaled_fps <= 0.0 { return; }
+
+    let fps = compute_params.scaled_fps;
+    let timestamps = (0..compute_params.frame_count).map(|i| (i, i as f64 * 1000.0 / fps)).collect::<Vec<_>>();
+    let gyro_timestamps = {
+        let gyro = compute_params.gyro.read();
+        timestamps.iter().map(|(_, ts)| gyro.video_to_gyro_timestamp(*ts)).collect::<Vec<_>>()
+    };
+
+    let limits = frame_limits(compute_params, corrections, &timestamps, &gyro_timestamps);
+    if limits.iter().all(|x| *x >= 1.0) { return; }
+    log::debug!("Correction limited in {} frames", limits.iter().filter(|x| **x < 1.0).count());
+
+    let radius = ((RELAXATION_MS / 1000.0 * fps).round() as usize).max(1);
+    let limits = gyro_timestamps.iter()
+        .map(|ts| (ts * 1000.0).round() as i64)
+        .zip(relax(&limits, radius))
+        .collect::<BTreeMap<i64, f64>>();
+
+    let identity = Quat64::identity();
+    for (ts, q) in corrections.iter_mut() {
+        let limit = limit_at(&limits, *ts);
+        if limit < 1.0 {
+            *q = identity.slerp(q, limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+    use crate::stabilization::distortion_models::DistortionModel;
+    use std::f64::consts::PI;
+
+    const FPS: f64 = 30.0;
+    const GYRO_RATE: f64 = 200.0;
+
+    // Pinhole camera, 1080p, zoomed in to 0.8 FOV
+    fn compute_params(frames: usize) -> ComputeParams {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.height, params.output_width, params.output_height) = (1920, 1080, 1920, 1080);
+        params.frame_count = frames;
+        params.scaled_fps = FPS;
+        params.scaled_duration_ms = frames as f64 * 1000.0 / FPS;
+        params.lens = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.0; 5],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        params.distortion_model = DistortionModel::from_name("opencv_standard");
+        params.fov_scale = 0.8;
+        params.adaptive_zoom_window = 0.0;
+        params.limit_to_zoom_budget = true;
+        params.trim_ranges.clear();
+        params.gyro.write().duration_ms = params.scaled_duration_ms;
+        params
+    }
+
+    #[test]
+    fn no_black_corners() {
+        let frames = 300;
+        let params = compute_params(frames);
+        let timestamps = (0..frames).map(|i| (i, i as f64 * 1000.0 / FPS)).collect::<Vec<_>>();
+
+        // Like with a very low smoothness, the correction is most of the handheld motion: a slow sway of ±8° with a shake on top
+        let org: TimeQuat = (0..=(params.scaled_duration_ms / 1000.0 * GYRO_RATE) as i64).map(|i| {
+            let t = i as f64 / GYRO_RATE;
+            let yaw = 8.0 * (2.0 * PI * 0.1 * t).sin() + 0.5 * (2.0 * PI * 7.0 * t).sin();
+            let pitch = (2.0 * PI * 0.3 * t).cos();
+            ((t * 1_000_000.0).round() as i64, Quat64::from_euler_angles(pitch.to_radians(), yaw.to_radians(), 0.0))
+        }).collect();
+
+        let black_corner_frames = |corrections: &TimeQuat| {
+            params.gyro.write().set_smoothed_quaternions(corrections.clone());
+            let (_, minimal_fovs, _) = calculate_fovs(&params, &timestamps, ZoomMethod::GaussianFilter);
+            assert_eq!(minimal_fovs.len(), frames);
+            minimal_fovs.iter().filter(|x| **x < params.fov_scale).count()
+        };
+        let unlimited = black_corner_frames(&org);
+        assert!(unlimited > 24, "{unlimited}");
+
+        let mut limited = org.clone();
+        limit_corrections(&params, &mut limited);
+        assert_eq!(black_corner_frames(&limited), 0);
+        assert_eq!(limited.len(), org.len());
+
+        // Untouched where the frame covers the output, ie. around the middle of the sway
+        assert!(limited[&0].angle_to(&org[&0]) < 1e-9);
+        assert!(limited[&5_000_000].angle_to(&org[&5_000_000]) < 1e-9);
+        assert!(limited[&2_500_000].angle_to(&org[&2_500_000]) > 0.01_f64.to_radians());
+
+        // The limit doesn't add jerks: the correction changes no faster than the original one, with a bit of the limit's own change
+        let max_step = |quats: &TimeQuat| quats.values().zip(quats.values().skip(1)).map(|(a, b)| a.angle_to(b)).fold(0.0, f64::max);
+        assert!(max_step(&limited) < max_step(&org) * 1.5, "{} {}", max_step(&limited), max_step(&org));
+    }
+
+    #[test]
+    fn relaxation_stays_below_limit() {
+        let mut limits = vec![1.0; 100];
+        limits[50] = 0.2;
+        limits[51] = 0.5;
+        let relaxed = relax(&limits, 5);
+        assert!(relaxed.iter().zip(limits.iter()).all(|(r, l)| *r <= *l + 1e-12));
+        assert!(relaxed[..30].iter().all(|x| *x == 1.0));
+        // Spread over the neighbors without steps
+        assert!(relaxed.iter().zip(relaxed.iter().skip(1)).all(|(a, b)| (a - b).abs() < 0.1));
+    }
+}
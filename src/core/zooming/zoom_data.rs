@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Per-frame zoom chosen by the zooming, with the keyframes applied, for plotting and for matching the crop in other software.
+// The FOV is the one used for rendering (1 is the whole input frame, lower is zoomed in)
+// and the center offset is the zoom center keyframes, as a fraction of the frame size
+
+use crate::stabilization::{ ComputeParams, FrameTransform };
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ZoomFrame {
+    pub frame: usize,
+    pub timestamp_ms: f64,
+    pub fov: f64,
+    pub crop_factor: f64,
+    pub center_offset: (f64, f64),
+}
+
+/// Zoom of every frame from the computed FOVs. Empty until the zooming has run
+pub fn per_frame(compute_params: &ComputeParams) -> Vec<ZoomFrame> {
+    let fps = compute_params.scaled_fps;
+    (0..compute_params.fovs.len()).map(|frame| {
+        let timestamp_ms = crate::timestamp_at_frame(frame as i32, fps);
+        let fov = FrameTransform::output_fov(compute_params, frame, timestamp_ms);
+        let center = super::center_offset_at(compute_params, timestamp_ms);
+        let center_scale = compute_params.zoom_center_scale.get(frame).copied().unwrap_or(1.0);
+        ZoomFrame {
+            frame,
+            timestamp_ms,
+            fov,
+            crop_factor: 1.0 / fov,
+            center_offset: (center.0 * center_scale, center.1 * center_scale),
+        }
+    }).collect()
+}
+
+pub fn to_csv(frames: &[ZoomFrame]) -> String {
+    let mut ret = String::from("frame,timestamp_ms,fov,crop_factor,center_offset_x,center_offset_y\n");
+    for x in frames {
+        ret.push_str(&format!("{},{:.3},{:.6},{:.6},{:.6},{:.6}\n", x.frame, x.timestamp_ms, x.fov, x.crop_factor, x.center_offset.0, x.center_offset.1));
+    }
+    ret
+}
+
+pub fn to_json(frames: &[ZoomFrame]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(frames)
+}
+
+pub fn from_json(json: &str) -> serde_json::Result<Vec<ZoomFrame>> {
+    serde_json::from_str(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyframes::KeyframeType;
+
+    #[test]
+    fn round_trip_matches_render() {
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.height, params.output_width, params.output_height) = (1920, 1080, 1920, 1080);
+        params.scaled_fps = 30.0;
+        params.frame_readout_time = 0.0;
+        params.fovs = (0..60).map(|i| 0.7 + i as f64 * 0.005).collect();
+        params.keyframes.set(&KeyframeType::Fov, 0, 1.0);
+        params.keyframes.set(&KeyframeType::Fov, 2_000_000, 1.5);
+        params.keyframes.set(&KeyframeType::ZoomingCenterX, 1_000_000, 0.1);
+
+        let frames = from_json(&to_json(&per_frame(&params)).unwrap()).unwrap();
+        assert_eq!(frames.len(), 60);
+        for x in frames.iter().step_by(7) {
+            let kernel_params = FrameTransform::at_timestamp(&params, x.timestamp_ms, x.frame).kernel_params;
+            assert!((x.fov as f32 - kernel_params.fov).abs() < 1e-6, "{} {}", x.fov, kernel_params.fov);
+            assert!(((x.center_offset.0 * params.width as f64 / x.fov) as f32 - kernel_params.translation2d[0]).abs() < 1e-3);
+        }
+        assert!(frames[59].fov > frames[0].fov * 1.5);
+        assert_eq!(to_csv(&frames).lines().count(), 61);
+    }
+}
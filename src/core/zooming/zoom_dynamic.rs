@@ -9,32 +9,60 @@ struct DataPerTimestamp {
     window: f64,
     frames: usize,
     half_frames: isize,
+    look_ahead: Option<usize>, // frames
     gaussian_window: Vec<f64>
 }
 
+// Half of the window is after the frame, so it's limited to twice the look-ahead of the smoothing
+fn limit_window(compute_params: &ComputeParams, window: f64) -> f64 {
+    match compute_params.smoothing_look_ahead {
+        Some(look_ahead) => window.min(look_ahead * 2.0),
+        None => window
+    }
+}
+
+// How long before a required crop the zoom can start to follow it, in frames. `None` is the default:
+// half of the window with the gaussian filter and the whole clip with the envelope follower
+fn look_ahead_frames(compute_params: &ComputeParams, look_ahead: f64) -> Option<usize> {
+    let look_ahead = match (look_ahead > 0.0, compute_params.smoothing_look_ahead) {
+        (true, Some(smoothing)) => Some(look_ahead.min(smoothing)),
+        (true, None) => Some(look_ahead),
+        (false, smoothing) => smoothing
+    };
+    look_ahead.map(|x| (x * compute_params.scaled_fps).round() as usize)
+}
+
 pub fn compute(compute_params: &ComputeParams, mut fov_values: Vec<f64>, timestamps: &[(usize, f64)], method: ZoomMethod) -> (Vec<f64>, Vec<f64>) {
-    let window = compute_params.adaptive_zoom_window;
+    let window = limit_window(compute_params, compute_params.adaptive_zoom_window);
+    let look_ahead = look_ahead_frames(compute_params, compute_params.adaptive_zoom_look_ahead);
 
     let fov_minimal = fov_values.clone();
 
     let keyframes = &compute_params.keyframes;
 
-    if keyframes.is_keyframed(&KeyframeType::ZoomingSpeed) || (compute_params.video_speed_affects_zooming && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed))) {
+    if keyframes.is_keyframed(&KeyframeType::ZoomingSpeed) || keyframes.is_keyframed(&KeyframeType::ZoomingLookAhead) || (compute_params.video_speed_affects_zooming && (compute_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed))) {
         // Keyframed window
         let mut max_window = 0;
+        let mut max_look_ahead = 0;
         let data_per_timestamp = timestamps.iter().map(|(_frame, ts)| {
             let mut window = keyframes.value_at_video_timestamp(&KeyframeType::ZoomingSpeed, *ts).unwrap_or(window);
+            let mut look_ahead = keyframes.value_at_video_timestamp(&KeyframeType::ZoomingLookAhead, *ts).unwrap_or(compute_params.adaptive_zoom_look_ahead);
             if compute_params.video_speed_affects_zooming {
                 let vid_speed = keyframes.value_at_video_timestamp(&KeyframeType::VideoSpeed, *ts).unwrap_or(compute_params.video_speed).abs();
                 window *= vid_speed;
+                look_ahead *= vid_speed;
             }
-            let frames = get_frames_per_window(compute_params);
+            let window = limit_window(compute_params, window);
+            let frames = frames_per_window(window, compute_params.scaled_fps);
+            let look_ahead = look_ahead_frames(compute_params, look_ahead);
             if frames > max_window { max_window = frames; }
+            max_look_ahead = max_look_ahead.max(look_ahead.unwrap_or(frames / 2));
             DataPerTimestamp {
                 window,
                 fps: compute_params.scaled_fps,
                 frames,
                 half_frames: (frames / 2) as isize,
+                look_ahead,
                 gaussian_window: gaussian_window_normalized(frames, frames as f64 / 6.0)
             }
         }).collect::<Vec<_>>();
@@ -42,25 +70,27 @@ pub fn compute(compute_params: &ComputeParams, mut fov_values: Vec<f64>, timesta
         match method {
             ZoomMethod::GaussianFilter => {
                 let max_window_half = max_window / 2;
-                let fov_values_pad = pad_edge(&fov_values, (max_window_half, max_window_half));
+                let fov_values_pad = pad_edge(&fov_values, (max_window_half, max_look_ahead));
                 let fov_min = min_rolling_dynamic(&fov_values_pad, max_window_half as isize, &data_per_timestamp);
                 let fov_min_pad = pad_edge(&fov_min, (max_window_half, max_window_half));
                 fov_values = convolve_dynamic(&fov_min_pad, max_window_half as isize, &data_per_timestamp);
             },
             ZoomMethod::EnvelopeFollower => {
                 let second_pass_alpha = 1.0 - (-(1.0 / compute_params.scaled_fps) / 0.2).exp();
-                fov_values = envelope_follower(&fov_values, &data_per_timestamp, None);
-                fov_values = envelope_follower(&fov_values, &data_per_timestamp, Some(second_pass_alpha));
+                fov_values = envelope_follower(&fov_values, &data_per_timestamp, None, None);
+                fov_values = envelope_follower(&fov_values, &data_per_timestamp, Some(second_pass_alpha), None);
             }
         }
     } else {
         match method {
             ZoomMethod::GaussianFilter => {
                 // Static window
-                let frames = get_frames_per_window(compute_params);
+                let frames = frames_per_window(window, compute_params.scaled_fps);
+                // The minimum is taken from the look-ahead before the frame, so the zoom starts before the crop is needed
+                let look_ahead = look_ahead.unwrap_or(frames / 2);
 
-                let fov_values_pad = pad_edge(&fov_values, (frames / 2, frames / 2));
-                let fov_min = min_rolling(&fov_values_pad, frames);
+                let fov_values_pad = pad_edge(&fov_values, (frames / 2, look_ahead));
+                let fov_min = min_rolling(&fov_values_pad, frames / 2 + look_ahead + 1);
                 let fov_min_pad = pad_edge(&fov_min, (frames / 2, frames / 2));
 
                 let gaussian = gaussian_window_normalized(frames, frames as f64 / 6.0);
@@ -70,8 +100,8 @@ pub fn compute(compute_params: &ComputeParams, mut fov_values: Vec<f64>, timesta
                 let first_pass_alpha  = 1.0 - (-(1.0 / compute_params.scaled_fps) / window).exp();
                 let second_pass_alpha = 1.0 - (-(1.0 / compute_params.scaled_fps) / 0.2).exp();
 
-                fov_values = envelope_follower(&fov_values, &[], Some(first_pass_alpha));
-                fov_values = envelope_follower(&fov_values, &[], Some(second_pass_alpha));
+                fov_values = envelope_follower(&fov_values, &[], Some(first_pass_alpha), look_ahead);
+                fov_values = envelope_follower(&fov_values, &[], Some(second_pass_alpha), look_ahead);
             }
         }
     }
@@ -79,8 +109,8 @@ pub fn compute(compute_params: &ComputeParams, mut fov_values: Vec<f64>, timesta
     (fov_values, fov_minimal)
 }
 
-fn get_frames_per_window(compute_params: &ComputeParams) -> usize {
-    let mut frames = (compute_params.adaptive_zoom_window * compute_params.scaled_fps).floor() as usize;
+fn frames_per_window(window: f64, fps: f64) -> usize {
+    let mut frames = (window * fps).floor() as usize;
     if frames % 2 == 0 {
         frames += 1;
     }
@@ -130,13 +160,15 @@ fn min_rolling_dynamic(a: &[f64], max_window_half: isize, data_per_timestamp: &[
     let mut ret = Vec::with_capacity(a.len());
 
     for (di, data) in data_per_timestamp.iter().enumerate() {
+        // From half of the window before the frame to the look-ahead after it
         let i = di as isize + (max_window_half - data.half_frames);
-        if i >= 0 && i as usize + data.frames <= a.len() {
+        let len = (data.half_frames + data.look_ahead.map_or(data.half_frames, |x| x as isize) + 1) as usize;
+        if i >= 0 && i as usize + len <= a.len() {
             let i = i as usize;
-            let window = &a[i..i + data.frames];
+            let window = &a[i..i + len];
             ret.push(window.iter().copied().reduce(f64::min).unwrap())
         } else {
-            log::error!("Something went wrong i: {i}, a.len: {}, frames: {}", a.len(), data.frames);
+            log::error!("Something went wrong i: {i}, a.len: {}, frames: {}", a.len(), len);
         }
     }
     ret
@@ -162,7 +194,8 @@ fn convolve_dynamic(a: &[f64], max_window_half: isize, data_per_timestamp: &[Dat
     ret
 }
 
-fn envelope_follower(a: &[f64], data_per_timestamp: &[DataPerTimestamp], alpha: Option<f64>) -> Vec<f64> {
+/// `look_ahead` limits how far ahead the reverse pass reaches, if it's not set per timestamp
+fn envelope_follower(a: &[f64], data_per_timestamp: &[DataPerTimestamp], alpha: Option<f64>, look_ahead: Option<usize>) -> Vec<f64> {
     if a.is_empty() { return Vec::new(); }
 
     let alphas = if let Some(alpha) = alpha {
@@ -173,11 +206,29 @@ fn envelope_follower(a: &[f64], data_per_timestamp: &[DataPerTimestamp], alpha:
         }).collect::<Vec<_>>()
     };
 
-    let mut q = *a.iter().next_back().unwrap();
-    let smoothed_rev = a.iter().zip(&alphas).rev().map(|(&x, coeff)| {
-        q = x.min(x * coeff + q * (1.0-coeff));
-        q
-    }).collect::<Vec<_>>();
+    let look_aheads = if data_per_timestamp.is_empty() {
+        vec![look_ahead; a.len()]
+    } else {
+        data_per_timestamp.iter().map(|dpt| dpt.look_ahead).collect::<Vec<_>>()
+    };
+
+    let smoothed_rev = if look_aheads.iter().all(Option::is_none) {
+        let mut q = *a.iter().next_back().unwrap();
+        a.iter().zip(&alphas).rev().map(|(&x, coeff)| {
+            q = x.min(x * coeff + q * (1.0-coeff));
+            q
+        }).collect::<Vec<_>>()
+    } else {
+        // Started again for each frame, at most the look-ahead after it
+        (0..a.len()).map(|i| {
+            let end = look_aheads[i].map_or(a.len() - 1, |x| (i + x).min(a.len() - 1));
+            let mut q = a[end];
+            for j in (i..=end).rev() {
+                q = a[j].min(a[j] * alphas[j] + q * (1.0-alphas[j]));
+            }
+            q
+        }).collect::<Vec<_>>()
+    };
 
     let mut q = *smoothed_rev.iter().next_back().unwrap();
     let smoothed2 = smoothed_rev.iter().rev().zip(&alphas).map(|(&x, coeff)| {
@@ -187,3 +238,68 @@ fn envelope_follower(a: &[f64], data_per_timestamp: &[DataPerTimestamp], alpha:
 
     smoothed2
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gyro_source::{ TimeQuat, Quat64 };
+    use crate::lens_profile::{ LensProfile, CameraParams, Dimensions };
+    use crate::stabilization::distortion_models::DistortionModel;
+
+    const FPS: f64 = 30.0;
+
+    #[test]
+    fn look_ahead_covers_spike() {
+        let frames = 150;
+        let mut params = ComputeParams::from_manager(&crate::StabilizationManager::default());
+        (params.width, params.height, params.output_width, params.output_height) = (1920, 1080, 1920, 1080);
+        params.frame_count = frames;
+        params.scaled_fps = FPS;
+        params.scaled_duration_ms = frames as f64 * 1000.0 / FPS;
+        params.lens = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.0; 5],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        params.distortion_model = DistortionModel::from_name("opencv_standard");
+        params.adaptive_zoom_window = 2.0;
+        params.trim_ranges.clear();
+        params.gyro.write().duration_ms = params.scaled_duration_ms;
+
+        // Whip of 5° for 0.2 s in the middle of the clip
+        let corrections: TimeQuat = (0..=1000).map(|i| {
+            let ts = i * 5000;
+            let angle = if (2_500_000..2_700_000).contains(&ts) { 5.0_f64 } else { 0.0 };
+            (ts, Quat64::from_euler_angles(0.0, angle.to_radians(), 0.0))
+        }).collect();
+        params.gyro.write().set_smoothed_quaternions(corrections);
+
+        let timestamps = (0..frames).map(|i| (i, i as f64 * 1000.0 / FPS)).collect::<Vec<_>>();
+        let black_corner_frames = |params: &ComputeParams, look_ahead: f64, method: ZoomMethod| {
+            let mut params = params.clone();
+            params.adaptive_zoom_look_ahead = look_ahead;
+            let (fovs, minimal_fovs, _) = calculate_fovs(&params, &timestamps, method);
+            assert_eq!(fovs.len(), frames);
+            fovs.iter().zip(&minimal_fovs).filter(|(f, m)| **f > **m + 1e-6).count()
+        };
+
+        // Half of the window by default, and more than that starts zooming even earlier
+        assert_eq!(black_corner_frames(&params, 0.0, ZoomMethod::GaussianFilter), 0);
+        assert_eq!(black_corner_frames(&params, 1.5, ZoomMethod::GaussianFilter), 0);
+        // The zoom doesn't start in time when the look-ahead is shorter than the ramp
+        assert!(black_corner_frames(&params, 0.1, ZoomMethod::GaussianFilter) > 0);
+
+        assert_eq!(black_corner_frames(&params, 0.0, ZoomMethod::EnvelopeFollower), 0);
+        assert_eq!(black_corner_frames(&params, 0.1, ZoomMethod::EnvelopeFollower), 0);
+
+        // Keyframed look-ahead
+        params.keyframes.set(&KeyframeType::ZoomingLookAhead, 0, 0.1);
+        assert!(black_corner_frames(&params, 0.0, ZoomMethod::GaussianFilter) > 0);
+        params.keyframes.set(&KeyframeType::ZoomingLookAhead, 0, 1.5);
+        assert_eq!(black_corner_frames(&params, 0.0, ZoomMethod::GaussianFilter), 0);
+    }
+}
@@ -17,6 +17,8 @@ pub mod external_sdk;
 #[cfg(any(target_os = "windows", target_os = "macos"))]
 pub mod nle_plugins;
 mod cli;
+#[cfg(feature = "control-server")]
+mod control_server;
 mod resources;
 #[cfg(not(compiled_qml))]
 mod resources_qml;
@@ -13,6 +13,22 @@ cpp! {{
     #include "src/qt_gpu/qrhi_undistort.cpp"
 }}
 
+fn shader_path(stab: &StabilizationManager) -> QString {
+    let lens = stab.lens.read();
+    let distortion_model = lens.distortion_model.as_deref().unwrap_or("opencv_fisheye");
+    let digital_lens = lens.digital_lens.as_ref().map(|x| format!("_{}", x)).unwrap_or_else(|| "".into());
+
+    QString::from(format!(":/src/qt_gpu/compiled/undistort_{}{}.frag.qsb", distortion_model, digital_lens))
+}
+
+/// Whether there's a compiled shader for the current lens model, the preview uses the next pipeline without it
+pub fn has_shader(stab: &StabilizationManager) -> bool {
+    let shader_path = shader_path(stab);
+    cpp!(unsafe [shader_path as "QString"] -> bool as "bool" {
+        return QFile::exists(shader_path);
+    })
+}
+
 pub fn render(mdkplayer: &MDKPlayerWrapper, timestamp: f64, frame: usize, width: u32, height: u32, stab: Arc<StabilizationManager>, buffers: &mut Buffers) -> Option<ProcessedInfo> {
     if stab.prevent_recompute.load(std::sync::atomic::Ordering::SeqCst) { return None; }
 
@@ -22,13 +38,7 @@ pub fn render(mdkplayer: &MDKPlayerWrapper, timestamp: f64, frame: usize, width:
 
     if let Some(p) = stab.params.try_read() {
         output_size = QSize { width: p.output_size.0 as u32, height: p.output_size.1 as u32 };
-        shader_path = {
-            let lens = stab.lens.read();
-            let distortion_model = lens.distortion_model.as_deref().unwrap_or("opencv_fisheye");
-            let digital_lens = lens.digital_lens.as_ref().map(|x| format!("_{}", x)).unwrap_or_else(|| "".into());
-
-            QString::from(format!(":/src/qt_gpu/compiled/undistort_{}{}.frag.qsb", distortion_model, digital_lens))
-        };
+        shader_path = self::shader_path(&stab);
 
         if let Some(scale) = p.fps_scale {
             timestamp_us = (timestamp_us as f64 / scale).round() as i64;
@@ -10,7 +10,9 @@ pub struct AudioResampler {
     buffer_frame: frame::Audio,
     chunk_size: usize,
     src_frame_offset: usize,
-    buffer_frame_offset: usize
+    buffer_frame_offset: usize,
+    pending_silence: usize,
+    pending_skip: usize
 }
 
 impl AudioResampler {
@@ -34,10 +36,43 @@ impl AudioResampler {
             buffer_frame,
             chunk_size,
             src_frame_offset: 0,
-            buffer_frame_offset: 0
+            buffer_frame_offset: 0,
+            pending_silence: 0,
+            pending_skip: 0
         })
     }
 
+    /// Inserts silence before the rest of the current frame. It's placed right before it, so the timestamps stay continuous.
+    /// When the frame is already used up, the silence is appended after it
+    pub fn pad(&mut self, samples: usize) {
+        self.pending_silence += samples;
+    }
+    /// Drops the next samples, also across the frames
+    pub fn skip(&mut self, samples: usize) {
+        self.pending_skip += samples;
+    }
+
+    fn fill_silence(&mut self, samples: usize) {
+        let bytes_per_sample = self.resampler.output().format.bytes();
+        let dest_byte_offset = self.buffer_frame_offset * bytes_per_sample;
+
+        let channels = self.resampler.output().channel_layout.channels().max(1) as usize;
+        if self.resampler.output().format.is_planar() {
+            for c in 0..channels {
+                unsafe {
+                    let dst_ptr = (*self.buffer_frame.as_mut_ptr()).data[c].add(dest_byte_offset);
+                    std::ptr::write_bytes::<u8>(dst_ptr, 0, samples * bytes_per_sample);
+                }
+            }
+        } else {
+            unsafe {
+                let dst_ptr = (*self.buffer_frame.as_mut_ptr()).data[0].add(dest_byte_offset * channels);
+                std::ptr::write_bytes::<u8>(dst_ptr, 0, samples * bytes_per_sample * channels);
+            }
+        }
+        self.buffer_frame_offset += samples;
+    }
+
     pub fn new_frame(&mut self, in_frame: &mut frame::Audio) -> Result<(), Error> {
         self.src_frame = frame::Audio::empty();
         self.src_frame.set_pts(in_frame.pts());
@@ -62,6 +97,31 @@ impl AudioResampler {
 
     pub fn run(&mut self) -> Option<&frame::Audio> {
         let in_frame_samples = self.src_frame.samples();
+        if self.pending_skip > 0 {
+            let skip_samples = self.pending_skip.min(in_frame_samples.saturating_sub(self.src_frame_offset));
+            self.src_frame_offset += skip_samples;
+            self.pending_skip -= skip_samples;
+        }
+        if self.pending_silence > 0 {
+            let copy_samples = (self.chunk_size - self.buffer_frame_offset).min(self.pending_silence);
+            if self.buffer_frame_offset == 0 {
+                let pts = if self.src_frame_offset < in_frame_samples {
+                    self.src_frame.pts().map(|pts| pts + self.src_frame_offset as i64 - self.pending_silence as i64)
+                } else {
+                    // Nothing left in the frame, so the silence goes after the end of the audio, where the last chunk ended
+                    self.buffer_frame.pts().map(|pts| pts + self.chunk_size as i64).or(self.src_frame.pts().map(|pts| pts + self.src_frame_offset as i64))
+                };
+                self.buffer_frame.set_pts(pts);
+            }
+            self.fill_silence(copy_samples);
+            self.pending_silence -= copy_samples;
+
+            if self.buffer_frame_offset >= self.chunk_size {
+                self.buffer_frame.set_samples(self.chunk_size);
+                self.buffer_frame_offset = 0;
+                return Some(&self.buffer_frame);
+            }
+        }
         if self.src_frame_offset < in_frame_samples {
             let buf_space = self.chunk_size - self.buffer_frame_offset;
             let copy_samples = buf_space.min(in_frame_samples - self.src_frame_offset);
@@ -108,23 +168,7 @@ impl AudioResampler {
         if self.buffer_frame_offset > 0 {
             let missing_samples = self.chunk_size - self.buffer_frame_offset;
             if missing_samples > 0 {
-                let bytes_per_sample = self.resampler.output().format.bytes();
-                let dest_byte_offset = self.buffer_frame_offset * bytes_per_sample;
-
-                let channels = self.resampler.output().channel_layout.channels().max(1) as usize;
-                if self.resampler.output().format.is_planar() {
-                    for c in 0..channels {
-                        unsafe {
-                            let dst_ptr = (*self.buffer_frame.as_mut_ptr()).data[c].add(dest_byte_offset);
-                            std::ptr::write_bytes::<u8>(dst_ptr, 0,missing_samples * bytes_per_sample);
-                        }
-                    }
-                } else {
-                    unsafe {
-                        let dst_ptr = (*self.buffer_frame.as_mut_ptr()).data[0].add(dest_byte_offset * channels);
-                        std::ptr::write_bytes::<u8>(dst_ptr, 0, missing_samples * bytes_per_sample * channels);
-                    }
-                }
+                self.fill_silence(missing_samples);
             }
 
             self.buffer_frame.set_samples(self.chunk_size);
@@ -135,4 +179,87 @@ impl AudioResampler {
             None
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: usize = 1024;
+    const FLTP: format::Sample = format::Sample::F32(format::sample::Type::Planar);
+
+    fn resampler() -> AudioResampler {
+        AudioResampler::new((FLTP, ChannelLayout::default(1), 48000), (FLTP, ChannelLayout::default(1), 48000), CHUNK).unwrap()
+    }
+
+    // Every sample holds its own timestamp, so the output shows where each one ended up
+    fn input_frame(pts: i64, samples: usize) -> frame::Audio {
+        let mut frame = frame::Audio::new(FLTP, samples, ChannelLayout::default(1));
+        frame.set_rate(48000);
+        frame.set_pts(Some(pts));
+        for (i, s) in frame.plane_mut::<f32>(0).iter_mut().enumerate() {
+            *s = (pts + i as i64) as f32;
+        }
+        frame
+    }
+
+    fn collect(resampler: &mut AudioResampler, out: &mut Vec<(i64, Vec<f32>)>) {
+        while let Some(chunk) = resampler.run() {
+            out.push((chunk.pts().unwrap(), chunk.plane::<f32>(0)[..chunk.samples()].to_vec()));
+        }
+    }
+
+    // Same order as the transcoder: the gap of the first frame, the frames, and the padding up to the end of the video
+    fn offset_audio(offset: i64, frames: usize, video_end: i64) -> Vec<(i64, Vec<f32>)> {
+        let mut r = resampler();
+        let mut out = Vec::new();
+        for i in 0..frames {
+            r.new_frame(&mut input_frame(offset + i as i64 * 1000, 1000)).unwrap();
+            if i == 0 {
+                if offset > 0 { r.pad(offset as usize); } else { r.skip(-offset as usize); }
+            }
+            collect(&mut r, &mut out);
+        }
+        let missing = video_end - (offset + frames as i64 * 1000);
+        if missing > 0 {
+            r.pad(missing as usize);
+            collect(&mut r, &mut out);
+        }
+        if let Some(chunk) = r.flush() {
+            out.push((chunk.pts().unwrap(), chunk.plane::<f32>(0)[..chunk.samples()].to_vec()));
+        }
+        out
+    }
+
+    fn check(out: &[(i64, Vec<f32>)], audio: std::ops::Range<usize>, total: usize) {
+        assert_eq!(out.len(), total.div_ceil(CHUNK));
+        for (i, (pts, _)) in out.iter().enumerate() {
+            assert_eq!(*pts, (i * CHUNK) as i64);
+        }
+        let samples: Vec<f32> = out.iter().flat_map(|x| x.1.iter().copied()).collect();
+        for (i, s) in samples.iter().enumerate() {
+            let expected = if audio.contains(&i) { i as f32 } else { 0.0 };
+            assert_eq!(*s, expected, "sample {i}");
+        }
+    }
+
+    #[test]
+    fn delayed() {
+        // Silence before the audio, and after it up to the end of the video
+        let out = offset_audio(300, 3, 3500);
+        check(&out, 300..3300, 3500);
+    }
+
+    #[test]
+    fn advanced() {
+        // The first samples are dropped, and the end is padded
+        let out = offset_audio(-300, 3, 3000);
+        check(&out, 0..2700, 3000);
+    }
+
+    #[test]
+    fn padded_over_several_chunks() {
+        let out = offset_audio(2500, 2, 7000);
+        check(&out, 2500..4500, 7000);
+    }
+}
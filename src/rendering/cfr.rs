@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Constant frame rate output of a variable frame rate source.
+// Every output frame is at an exact multiple of the interval and shows the source frame which is the nearest to it,
+// so the frames are only duplicated or dropped, never blended. Each source frame takes the output frames up to the middle
+// between it and the next source frame, which is known from its duration. The output frames are counted from the start,
+// so the rounding doesn't accumulate and the video stays in sync with the untouched audio.
+
+pub struct CfrMapper {
+    interval_us: f64,
+    next_frame: i64, // Index of the next output frame
+}
+
+impl CfrMapper {
+    pub fn new(fps: f64) -> Self {
+        Self { interval_us: 1_000_000.0 / fps, next_frame: 0 }
+    }
+
+    pub fn interval_us(&self) -> f64 { self.interval_us }
+
+    /// Output frames of the source frame at `timestamp_us`, which lasts `duration_us` until the next one.
+    /// Returns the timestamp of the first output frame and the number of them, 0 when the frame is dropped
+    pub fn map(&mut self, timestamp_us: i64, duration_us: i64) -> (i64, i64) {
+        let duration_us = if duration_us > 0 { duration_us as f64 } else { self.interval_us };
+        let until = timestamp_us as f64 + duration_us / 2.0;
+        let first = self.next_frame;
+        while (self.next_frame as f64) * self.interval_us < until {
+            self.next_frame += 1;
+        }
+        (self.timestamp_of(first), self.next_frame - first)
+    }
+
+    fn timestamp_of(&self, frame: i64) -> i64 {
+        (frame as f64 * self.interval_us).round() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vfr_without_drift() {
+        // Phone-like timestamps around 30 fps with jitter, a slow part and a stall
+        let mut timestamps = Vec::new();
+        let mut ts = 0i64;
+        for i in 0..5400 {
+            timestamps.push(ts);
+            ts += match i % 7 { 0 => 28_000, 1 | 4 => 38_500, 2 => 31_000, _ => 33_200 };
+            if i % 900 == 450 { ts += 180_000; }
+            if (2000..2300).contains(&i) { ts += 16_600; }
+        }
+        let end_us = ts;
+
+        let fps = 30000.0 / 1001.0;
+        let mut mapper = CfrMapper::new(fps);
+        let mut output = Vec::new(); // (output timestamp, source timestamp)
+        for (i, &ts) in timestamps.iter().enumerate() {
+            let duration = timestamps.get(i + 1).unwrap_or(&end_us) - ts;
+            let (first, count) = mapper.map(ts, duration);
+            for k in 0..count {
+                output.push((first + (k as f64 * mapper.interval_us()).round() as i64, ts));
+            }
+        }
+
+        // Contiguous output at the exact frame times, covering the whole source
+        for (k, &(out_ts, _)) in output.iter().enumerate() {
+            assert!((out_ts - mapper.timestamp_of(k as i64)).abs() <= 1, "{k}: {out_ts}");
+        }
+        let expected = (end_us as f64 / mapper.interval_us() - 0.5).ceil() as usize;
+        assert!((output.len() as i64 - expected as i64).abs() <= 1, "{} {}", output.len(), expected);
+
+        // Every output frame shows the nearest source frame, also at the end of the clip
+        for &(out_ts, src_ts) in &output {
+            let nearest = timestamps.iter().min_by_key(|&&x| (x - out_ts).abs()).unwrap();
+            assert_eq!((nearest - out_ts).abs(), (src_ts - out_ts).abs(), "{out_ts}");
+        }
+        let (last_out, last_src) = *output.last().unwrap();
+        assert!((last_out - last_src).abs() as f64 <= mapper.interval_us());
+    }
+}
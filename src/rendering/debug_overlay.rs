@@ -0,0 +1,418 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Diagnostic data burned into the exported frames: a scrolling plot of the raw gyro axes around the current frame,
+// the size of the correction, the horizon of the stabilized camera and the sync points with their offsets.
+// It's drawn into an RGBA canvas of the output size and blended on the CPU into the stabilized frame,
+// so it works with every pixel format the renderer writes, except the hardware frames
+
+use ffmpeg_next::{ format::Pixel, frame::Video, util::color::Range };
+use gyroflow_core::StabilizationManager;
+use gyroflow_core::smoothing::axis_lock;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DebugOverlayOptions {
+    pub enabled: bool,
+    pub x: f64,         // Plot area, as a fraction of the output size
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub window_ms: f64, // Part of the clip around the current frame in the plot
+    pub gyro: bool,
+    pub correction: bool,
+    pub horizon: bool,
+    pub sync_points: bool,
+}
+
+impl Default for DebugOverlayOptions {
+    fn default() -> Self { Self {
+        enabled: false,
+        x: 0.02,
+        y: 0.70,
+        width: 0.40,
+        height: 0.26,
+        window_ms: 2000.0,
+        gyro: true,
+        correction: true,
+        horizon: true,
+        sync_points: true,
+    } }
+}
+
+type Rgba = [u8; 4];
+const BACKGROUND: Rgba = [0,   0,   0,   150];
+const CURSOR:     Rgba = [255, 255, 255, 200];
+const AXES:       [Rgba; 3] = [[255, 80, 80, 255], [80, 255, 80, 255], [90, 140, 255, 255]];
+const CORRECTION: Rgba = [255, 220, 0,   255];
+const HORIZON:    Rgba = [0,   255, 255, 180];
+const SYNC_POINT: Rgba = [255, 0,   255, 255];
+
+// 3x5 glyphs, row by row from the top, the most significant bit is the top left pixel
+const FONT_CHARS: &str = "0123456789-.+ms";
+const FONT: [u16; 15] = [
+    0b111_101_101_101_111, 0b010_110_010_010_111, 0b111_001_111_100_111, 0b111_001_111_001_111, 0b101_101_111_001_001,
+    0b111_100_111_001_111, 0b111_100_111_101_111, 0b111_001_001_001_001, 0b111_101_111_101_111, 0b111_101_111_001_111,
+    0b000_000_111_000_000, 0b000_000_000_000_010, 0b000_010_111_010_000, 0b000_101_111_101_101, 0b011_100_010_001_110,
+];
+
+// How the components of a plane are stored
+#[derive(Clone, Copy)]
+enum Sample { U8, U16Le(f64), U16Be(f64), F32 }
+
+pub struct DebugOverlay {
+    options: DebugOverlayOptions,
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgba>,
+}
+
+impl DebugOverlay {
+    pub fn new(options: DebugOverlayOptions) -> Self {
+        Self { options, width: 0, height: 0, pixels: Vec::new() }
+    }
+
+    /// Draws the overlay of the frame at `timestamp_ms` (video time) and blends it into `frame`
+    pub fn apply(&mut self, stab: &StabilizationManager, timestamp_ms: f64, frame: &mut Video) {
+        if !self.options.enabled { return; }
+        self.draw(stab, timestamp_ms, frame.width() as usize, frame.height() as usize);
+        if !self.composite(frame) {
+            log::warn!("Debug overlay is not supported for pixel format {:?}, it's disabled for this render", frame.format());
+            self.options.enabled = false;
+        }
+    }
+
+    pub fn draw(&mut self, stab: &StabilizationManager, timestamp_ms: f64, width: usize, height: usize) {
+        if self.width != width || self.height != height {
+            (self.width, self.height) = (width, height);
+            self.pixels = vec![[0; 4]; width * height];
+        } else {
+            self.pixels.fill([0; 4]);
+        }
+        let o = self.options.clone();
+        let (w, h) = (width as f64, height as f64);
+        let scale = (height / 360).max(1) as i64;
+        let gyro = stab.gyro.read();
+
+        if o.horizon {
+            // Roll of the stabilized camera to the horizon, the correction is smoothed -> original
+            let smoothed = gyro.org_quat_at_timestamp(timestamp_ms) * gyro.smoothed_quat_at_timestamp(timestamp_ms).inverse();
+            let roll = -axis_lock::decompose(&smoothed).2;
+            let (dx, dy) = (roll.cos() * w, roll.sin() * w);
+            for i in -(scale / 2)..=(scale / 2) {
+                self.line(w / 2.0 - dx, h / 2.0 - dy + i as f64, w / 2.0 + dx, h / 2.0 + dy + i as f64, HORIZON);
+            }
+        }
+
+        let (px, py, pw, ph) = ((o.x * w).round(), (o.y * h).round(), (o.width * w).round().max(16.0), (o.height * h).round().max(16.0));
+        if !(o.gyro || o.correction || o.sync_points) { return; }
+        self.fill_rect(px, py, pw, ph, BACKGROUND);
+
+        let window = o.window_ms.max(100.0);
+        let (from_ms, to_ms) = (timestamp_ms - window / 2.0, timestamp_ms + window / 2.0);
+        let x_at = |ts: f64| px + (ts - from_ms) / window * pw;
+        let mid = py + ph / 2.0;
+
+        if o.correction {
+            let samples = (pw as usize / 2).max(2);
+            let values = (0..samples).map(|i| {
+                let ts = from_ms + window * i as f64 / (samples - 1) as f64;
+                (ts, gyro.smoothed_quat_at_timestamp(ts).angle().to_degrees())
+            }).collect::<Vec<_>>();
+            let max = values.iter().map(|x| x.1).fold(1.0, f64::max);
+            let y_at = |v: f64| py + ph - 1.0 - v / max * (ph - 2.0);
+            for (a, b) in values.iter().zip(values.iter().skip(1)) {
+                self.line(x_at(a.0), y_at(a.1), x_at(b.0), y_at(b.1), CORRECTION);
+            }
+            let current = gyro.smoothed_quat_at_timestamp(timestamp_ms).angle().to_degrees();
+            self.text(&format!("{:.1}", current), px + pw - 24.0 * scale as f64, py + 2.0 * scale as f64, scale, CORRECTION);
+        }
+
+        if o.gyro {
+            let file_metadata = gyro.file_metadata.read();
+            let imu = gyro.raw_imu(&file_metadata);
+            let (gyro_from, gyro_to) = (gyro.video_to_gyro_timestamp(from_ms), gyro.video_to_gyro_timestamp(to_ms));
+            let start = imu.partition_point(|x| x.timestamp_ms < gyro_from).saturating_sub(1);
+            let end = imu.partition_point(|x| x.timestamp_ms <= gyro_to).saturating_add(1).min(imu.len());
+            let samples = imu[start..end].iter().filter_map(|x| Some((x.timestamp_ms - gyro_from + from_ms, x.gyro?))).collect::<Vec<_>>();
+            let max = samples.iter().flat_map(|x| x.1.iter().map(|v| v.abs())).fold(10.0, f64::max);
+            let y_at = |v: f64| mid - v / max * (ph / 2.0 - 1.0);
+            for (a, b) in samples.iter().zip(samples.iter().skip(1)) {
+                for (axis, color) in AXES.iter().enumerate() {
+                    let (x0, x1) = (x_at(a.0).clamp(px, px + pw - 1.0), x_at(b.0).clamp(px, px + pw - 1.0));
+                    self.line(x0, y_at(a.1[axis]).clamp(py, py + ph - 1.0), x1, y_at(b.1[axis]).clamp(py, py + ph - 1.0), *color);
+                }
+            }
+        }
+
+        if o.sync_points {
+            let offsets = gyro.get_offsets().range(((from_ms * 1000.0) as i64)..=((to_ms * 1000.0) as i64)).map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+            for (ts_us, offset_ms) in offsets {
+                let x = x_at(ts_us as f64 / 1000.0).round();
+                self.fill_rect(x - (scale / 2) as f64, py, scale as f64, ph / 4.0, SYNC_POINT);
+                self.text(&format!("{:.1}ms", offset_ms), x + 2.0 * scale as f64, py + ph / 4.0 - 5.0 * scale as f64, scale, SYNC_POINT);
+            }
+        }
+
+        self.fill_rect(x_at(timestamp_ms).round(), py, scale as f64, ph, CURSOR);
+    }
+
+    fn blend(&mut self, x: i64, y: i64, color: Rgba) {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 { return; }
+        let p = &mut self.pixels[y as usize * self.width + x as usize];
+        let a = color[3] as u32;
+        let out_a = a + p[3] as u32 * (255 - a) / 255;
+        if out_a == 0 { return; }
+        let mix = |i: usize| ((color[i] as u32 * a + p[i] as u32 * p[3] as u32 * (255 - a) / 255) / out_a) as u8;
+        *p = [mix(0), mix(1), mix(2), out_a as u8];
+    }
+
+    fn fill_rect(&mut self, x: f64, y: f64, w: f64, h: f64, color: Rgba) {
+        let (x, y) = (x.round() as i64, y.round() as i64);
+        for yy in y..y + h.round().max(1.0) as i64 {
+            for xx in x..x + w.round().max(1.0) as i64 {
+                self.blend(xx, yy, color);
+            }
+        }
+    }
+
+    fn line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Rgba) {
+        if !(x0.is_finite() && y0.is_finite() && x1.is_finite() && y1.is_finite()) { return; }
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0) as i64;
+        let mut prev = None;
+        for i in 0..=steps {
+            let t = i as f64 / steps as f64;
+            let p = ((x0 + (x1 - x0) * t).round() as i64, (y0 + (y1 - y0) * t).round() as i64);
+            if prev != Some(p) {
+                self.blend(p.0, p.1, color);
+                prev = Some(p);
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str, x: f64, y: f64, scale: i64, color: Rgba) {
+        let (x, y) = (x.round() as i64, y.round() as i64);
+        for (i, c) in text.chars().enumerate() {
+            let Some(glyph) = FONT_CHARS.find(c).map(|i| FONT[i]) else { continue; };
+            for bit in 0..15 {
+                if glyph & (1 << (14 - bit)) == 0 { continue; }
+                let (gx, gy) = (x + (i as i64 * 4 + bit % 3) * scale, y + (bit / 3) * scale);
+                for yy in gy..gy + scale {
+                    for xx in gx..gx + scale {
+                        self.blend(xx, yy, color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Blends the canvas into the frame. Returns false if the pixel format is not supported
+    pub fn composite(&self, frame: &mut Video) -> bool {
+        if self.width == 0 || self.height == 0 { return true; }
+        let limited = frame.color_range() == Range::MPEG;
+        // BT.709, with the chroma centered at 0.5
+        let yuv = move |p: Rgba| -> [f64; 4] {
+            let (r, g, b) = (p[0] as f64 / 255.0, p[1] as f64 / 255.0, p[2] as f64 / 255.0);
+            let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            let (u, v) = ((b - y) / 1.8556, (r - y) / 1.5748);
+            if limited {
+                [(16.0 + 219.0 * y) / 255.0, (128.0 + 224.0 * u) / 255.0, (128.0 + 224.0 * v) / 255.0, 1.0]
+            } else {
+                [y, u + 0.5, v + 0.5, 1.0]
+            }
+        };
+        let rgb = |p: Rgba| -> [f64; 4] { [p[0] as f64 / 255.0, p[1] as f64 / 255.0, p[2] as f64 / 255.0, 1.0] };
+
+        let bit_depth = |format: Pixel| -> f64 {
+            match format {
+                Pixel::YUV420P10LE | Pixel::YUV422P10LE | Pixel::YUV444P10LE | Pixel::YUVA444P10LE => 1023.0,
+                Pixel::YUV420P12LE | Pixel::YUV422P12LE | Pixel::YUV444P12LE | Pixel::YUVA444P12LE => 4095.0,
+                Pixel::YUV420P14LE | Pixel::YUV422P14LE | Pixel::YUV444P14LE => 16383.0,
+                _ => 65535.0
+            }
+        };
+
+        let format = frame.format();
+        match format {
+            Pixel::YUV420P | Pixel::YUVJ420P => {
+                for plane in 0..3 { self.composite_plane(frame, plane, &[plane], Sample::U8, yuv); }
+            }
+            Pixel::NV12 | Pixel::NV21 => {
+                self.composite_plane(frame, 0, &[0], Sample::U8, yuv);
+                self.composite_plane(frame, 1, if format == Pixel::NV12 { &[1, 2] } else { &[2, 1] }, Sample::U8, yuv);
+            }
+            Pixel::P010LE | Pixel::P016LE | Pixel::P210LE | Pixel::P216LE | Pixel::P410LE | Pixel::P416LE => {
+                // The values are in the most significant bits
+                self.composite_plane(frame, 0, &[0], Sample::U16Le(65535.0), yuv);
+                self.composite_plane(frame, 1, &[1, 2], Sample::U16Le(65535.0), yuv);
+            }
+            Pixel::YUV420P10LE | Pixel::YUV420P12LE | Pixel::YUV420P14LE | Pixel::YUV420P16LE |
+            Pixel::YUV422P10LE | Pixel::YUV422P12LE | Pixel::YUV422P14LE | Pixel::YUV422P16LE |
+            Pixel::YUV444P10LE | Pixel::YUV444P12LE | Pixel::YUV444P14LE | Pixel::YUV444P16LE |
+            Pixel::YUVA444P10LE | Pixel::YUVA444P12LE | Pixel::YUVA444P16LE => {
+                let planes = if matches!(format, Pixel::YUVA444P10LE | Pixel::YUVA444P12LE | Pixel::YUVA444P16LE) { 4 } else { 3 };
+                for plane in 0..planes { self.composite_plane(frame, plane, &[plane], Sample::U16Le(bit_depth(format)), yuv); }
+            }
+            Pixel::AYUV64LE => self.composite_plane(frame, 0, &[3, 0, 1, 2], Sample::U16Le(65535.0), yuv),
+            Pixel::RGB24    => self.composite_plane(frame, 0, &[0, 1, 2], Sample::U8, rgb),
+            Pixel::RGBA     => self.composite_plane(frame, 0, &[0, 1, 2, 3], Sample::U8, rgb),
+            Pixel::RGB48BE  => self.composite_plane(frame, 0, &[0, 1, 2], Sample::U16Be(65535.0), rgb),
+            Pixel::RGBA64BE => self.composite_plane(frame, 0, &[0, 1, 2, 3], Sample::U16Be(65535.0), rgb),
+            Pixel::GBRPF32LE | Pixel::GBRAPF32LE => {
+                // G, B, R and A planes
+                for (plane, component) in [1, 2, 0, 3].into_iter().enumerate().take(if format == Pixel::GBRAPF32LE { 4 } else { 3 }) {
+                    self.composite_plane(frame, plane, &[component], Sample::F32, rgb);
+                }
+            }
+            _ => return false
+        }
+        true
+    }
+
+    /// `components` are the indices of the values returned by `value` for each component of a pixel in the plane
+    fn composite_plane(&self, frame: &mut Video, plane: usize, components: &[usize], sample: Sample, value: impl Fn(Rgba) -> [f64; 4]) {
+        let (plane_w, plane_h) = (frame.plane_width(plane) as usize, frame.plane_height(plane) as usize);
+        if plane_w == 0 || plane_h == 0 { return; }
+        let stride = frame.stride(plane);
+        let bytes = match sample { Sample::U8 => 1, Sample::U16Le(_) | Sample::U16Be(_) => 2, Sample::F32 => 4 };
+        let data = frame.data_mut(plane);
+
+        for y in 0..plane_h {
+            let src_row = (y * self.height / plane_h).min(self.height - 1) * self.width;
+            for x in 0..plane_w {
+                let p = self.pixels[src_row + (x * self.width / plane_w).min(self.width - 1)];
+                if p[3] == 0 { continue; }
+                let a = p[3] as f64 / 255.0;
+                let values = value(p);
+                for (i, c) in components.iter().enumerate() {
+                    let pos = y * stride + (x * components.len() + i) * bytes;
+                    let Some(dst) = data.get_mut(pos..pos + bytes) else { continue; };
+                    let v = values[*c];
+                    match sample {
+                        Sample::U8 => dst[0] = (dst[0] as f64 * (1.0 - a) + v * 255.0 * a).round() as u8,
+                        Sample::U16Le(max) => {
+                            let old = u16::from_le_bytes([dst[0], dst[1]]) as f64;
+                            dst.copy_from_slice(&((old * (1.0 - a) + v * max * a).round().min(max) as u16).to_le_bytes());
+                        }
+                        Sample::U16Be(max) => {
+                            let old = u16::from_be_bytes([dst[0], dst[1]]) as f64;
+                            dst.copy_from_slice(&((old * (1.0 - a) + v * max * a).round().min(max) as u16).to_be_bytes());
+                        }
+                        Sample::F32 => {
+                            let old = f32::from_le_bytes([dst[0], dst[1], dst[2], dst[3]]) as f64;
+                            dst.copy_from_slice(&((old * (1.0 - a) + v * a) as f32).to_le_bytes());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgba = [255, 0, 0, 255];
+
+    fn canvas(color: Rgba) -> DebugOverlay {
+        let mut overlay = DebugOverlay::new(DebugOverlayOptions { enabled: true, ..Default::default() });
+        (overlay.width, overlay.height) = (16, 16);
+        overlay.pixels = vec![color; 16 * 16];
+        overlay
+    }
+
+    fn test_frame(format: Pixel, range: Range) -> Video {
+        let mut frame = Video::new(format, 16, 16);
+        frame.set_color_range(range);
+        for plane in 0..frame.planes() {
+            frame.data_mut(plane).fill(0x40);
+        }
+        frame
+    }
+
+    fn planes(frame: &Video) -> Vec<Vec<u8>> {
+        (0..frame.planes()).map(|plane| frame.data(plane).to_vec()).collect()
+    }
+
+    // Every visible sample of the plane, `bytes` wide
+    fn samples(frame: &Video, plane: usize, per_pixel: usize, bytes: usize) -> Vec<u32> {
+        let data = frame.data(plane);
+        let stride = frame.stride(plane);
+        let mut out = Vec::new();
+        for y in 0..frame.plane_height(plane) as usize {
+            for x in 0..frame.plane_width(plane) as usize * per_pixel {
+                let pos = y * stride + x * bytes;
+                out.push(if bytes == 1 { data[pos] as u32 } else { u16::from_le_bytes([data[pos], data[pos + 1]]) as u32 });
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn transparent() {
+        for format in [Pixel::YUV420P, Pixel::P010LE, Pixel::RGBA] {
+            let mut frame = test_frame(format, Range::JPEG);
+            let before = planes(&frame);
+            assert!(canvas([255, 0, 0, 0]).composite(&mut frame));
+            assert_eq!(planes(&frame), before, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn opaque_yuv420p() {
+        let mut full = test_frame(Pixel::YUV420P, Range::JPEG);
+        assert!(canvas(RED).composite(&mut full));
+        for (plane, expected) in [54, 98, 255].into_iter().enumerate() {
+            assert!(samples(&full, plane, 1, 1).iter().all(|x| *x == expected), "plane {plane}");
+        }
+
+        let mut limited = test_frame(Pixel::YUV420P, Range::MPEG);
+        assert!(canvas(RED).composite(&mut limited));
+        for (plane, expected) in [63, 102, 240].into_iter().enumerate() {
+            assert!(samples(&limited, plane, 1, 1).iter().all(|x| *x == expected), "plane {plane}");
+        }
+    }
+
+    #[test]
+    fn opaque_p010() {
+        let mut frame = test_frame(Pixel::P010LE, Range::JPEG);
+        assert!(canvas(RED).composite(&mut frame));
+        assert!(samples(&frame, 0, 1, 2).iter().all(|x| *x == 13933));
+        // Interleaved U and V
+        let uv = samples(&frame, 1, 2, 2);
+        assert!(uv.chunks(2).all(|x| x == [25259, 65535]), "{uv:?}");
+    }
+
+    #[test]
+    fn opaque_rgba() {
+        let mut frame = test_frame(Pixel::RGBA, Range::JPEG);
+        assert!(canvas([20, 120, 220, 255]).composite(&mut frame));
+        let rgba = samples(&frame, 0, 4, 1);
+        assert!(rgba.chunks(4).all(|x| x == [20, 120, 220, 255]), "{rgba:?}");
+
+        // Half transparent is blended with the frame
+        let mut frame = test_frame(Pixel::RGBA, Range::JPEG);
+        assert!(canvas([255, 255, 255, 128]).composite(&mut frame));
+        let expected = (0x40 as f64 * (1.0 - 128.0 / 255.0) + 128.0).round() as u32;
+        assert!(samples(&frame, 0, 4, 1).iter().all(|x| *x == expected));
+    }
+
+    #[test]
+    fn unsupported_format() {
+        let mut frame = test_frame(Pixel::YUYV422, Range::JPEG);
+        let before = planes(&frame);
+        assert!(!canvas(RED).composite(&mut frame));
+        assert_eq!(planes(&frame), before);
+
+        // An empty canvas doesn't touch the frame with any format
+        assert!(DebugOverlay::new(DebugOverlayOptions::default()).composite(&mut frame));
+
+        // The overlay turns itself off after the first frame it can't draw into
+        let stab = StabilizationManager::default();
+        let mut overlay = DebugOverlay::new(DebugOverlayOptions { enabled: true, ..Default::default() });
+        overlay.apply(&stab, 0.0, &mut frame);
+        assert!(!overlay.options.enabled);
+        assert_eq!(planes(&frame), before);
+    }
+}
@@ -1,20 +1,149 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>, Maik <myco at gmx>
 
-use ffmpeg_next::{ ffi, codec, format, decoder, encoder, frame, Packet, Rescale, Rational, Error, format::context::Output, channel_layout::ChannelLayout};
+use ffmpeg_next::{ ffi, codec, format, decoder, encoder, filter, frame, Packet, Rescale, Rational, Error, format::context::Output, channel_layout::ChannelLayout};
 use super::audio_resampler::AudioResampler;
 use super::ffmpeg_processor::Status;
 use super::ffmpeg_processor::FrameTimestamps;
 
+// Integrated loudness below this is silence, there's nothing to normalize
+const SILENCE_LUFS: f64 = -70.0;
+
+/// Integrated loudness and true peak of the audio, EBU R128
+#[derive(Debug, Clone, Copy)]
+pub struct Loudness {
+    pub integrated_lufs: f64,
+    pub true_peak_db: f64, // dBTP
+}
+
+/// Constant gain to the target loudness, with the limiter when the peaks would go above the ceiling
+#[derive(Debug, Clone)]
+pub struct LoudnessNormalization {
+    pub target_lufs: f64,
+    pub true_peak_db: f64, // Ceiling, dBTP
+    pub limiter: bool,
+    pub measured: Loudness,
+}
+impl LoudnessNormalization {
+    pub fn gain_db(&self) -> f64 {
+        self.target_lufs - self.measured.integrated_lufs
+    }
+    fn filter_spec(&self) -> String {
+        let gain_db = self.gain_db();
+        let mut spec = format!("volume={gain_db:.2}dB");
+        if self.limiter && self.measured.true_peak_db + gain_db > self.true_peak_db {
+            // The limiter of ffmpeg can't go below -24 dB
+            let limit = 10f64.powf(self.true_peak_db / 20.0).clamp(0.0625, 1.0);
+            spec.push_str(&format!(",alimiter=limit={limit:.4}:attack=5:release=50:level=0:latency=1"));
+        } else if self.measured.true_peak_db + gain_db > 0.0 {
+            log::warn!("Audio peaks will be {:.1} dB above full scale after the normalization", self.measured.true_peak_db + gain_db);
+        }
+        // The resampler of the transcoder takes float planar samples from the filter
+        spec.push_str(",aformat=sample_fmts=fltp");
+        spec
+    }
+}
+
+/// Filter graph with the decoded audio as the input
+fn audio_filter_graph(decoder: &decoder::Audio, spec: &str) -> Result<filter::Graph, Error> {
+    let channels: i32 = decoder.channels().into();
+    let mut channel_layout = decoder.channel_layout();
+    if channel_layout.is_empty() {
+        channel_layout = ChannelLayout::default(channels);
+    }
+    let time_base = if decoder.time_base().numerator() > 0 && decoder.time_base().denominator() > 0 { decoder.time_base() } else { Rational(1, decoder.rate() as i32) };
+    let args = format!("time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}", time_base, decoder.rate(), decoder.format().name(), channel_layout.bits());
+
+    let mut graph = filter::Graph::new();
+    graph.add(&filter::find("abuffer").ok_or(Error::FilterNotFound)?, "in", &args)?;
+    graph.add(&filter::find("abuffersink").ok_or(Error::FilterNotFound)?, "out", "")?;
+    graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+    graph.validate()?;
+    Ok(graph)
+}
+
+fn filtered_frames(graph: &mut filter::Graph) -> Vec<frame::Audio> {
+    let mut frames = Vec::new();
+    let mut filtered = frame::Audio::empty();
+    while graph.get("out").unwrap().sink().frame(&mut filtered).is_ok() {
+        frames.push(std::mem::replace(&mut filtered, frame::Audio::empty()));
+    }
+    frames
+}
+
+/// Measures the loudness of the decoded audio with the ebur128 filter, which reports the values so far in the metadata of every frame
+pub struct LoudnessMeter {
+    pub decoder: decoder::Audio,
+    graph: filter::Graph,
+    integrated_lufs: Option<f64>,
+    true_peak: f64,
+}
+impl LoudnessMeter {
+    pub fn new(ist: &format::stream::Stream) -> Result<Self, Error> {
+        let ctx = codec::context::Context::from_parameters(ist.parameters())?;
+        let mut decoder = ctx.decoder().audio()?;
+        decoder.set_parameters(ist.parameters())?;
+        let graph = audio_filter_graph(&decoder, "ebur128=peak=true:metadata=1")?;
+        Ok(Self { decoder, graph, integrated_lufs: None, true_peak: 0.0 })
+    }
+
+    /// Measures the audio of the packet which is between `start_ms` and `end_ms`. Returns false when it's past the end
+    pub fn add_packet(&mut self, packet: &Packet, time_base: Rational, start_ms: Option<f64>, end_ms: Option<f64>) -> Result<bool, Error> {
+        let mut packet = packet.clone();
+        packet.rescale_ts(time_base, self.decoder.time_base());
+        self.decoder.send_packet(&packet)?;
+
+        let mut frame = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            if let Some(timestamp_ms) = frame.timestamp().map(|ts| ts.rescale(self.decoder.time_base(), (1, 1000)) as f64) {
+                if start_ms.is_some_and(|x| timestamp_ms < x) { continue; }
+                if end_ms.is_some_and(|x| timestamp_ms > x) { return Ok(false); }
+            }
+            self.graph.get("in").unwrap().source().add(&frame)?;
+            self.read_filtered();
+        }
+        Ok(true)
+    }
+
+    fn read_filtered(&mut self) {
+        for filtered in filtered_frames(&mut self.graph) {
+            for (k, v) in filtered.metadata().iter() {
+                let Ok(v) = v.parse::<f64>() else { continue; };
+                if k == "lavfi.r128.I" {
+                    self.integrated_lufs = Some(v);
+                } else if k.starts_with("lavfi.r128.true_peaks_ch") {
+                    self.true_peak = self.true_peak.max(v);
+                }
+            }
+        }
+    }
+
+    /// `None` when the audio is silent
+    pub fn finish(mut self) -> Result<Option<Loudness>, Error> {
+        self.graph.get("in").unwrap().source().flush()?;
+        self.read_filtered();
+        Ok(self.integrated_lufs.filter(|x| x.is_finite() && *x > SILENCE_LUFS).map(|integrated_lufs| Loudness {
+            integrated_lufs,
+            true_peak_db: 20.0 * self.true_peak.max(1e-10).log10()
+        }))
+    }
+}
+
 pub struct AudioTranscoder {
     pub ost_index: usize,
     pub decoder: decoder::Audio,
     pub encoder: encoder::Audio,
-    resampler: AudioResampler
+    filter: Option<filter::Graph>,
+    resampler: AudioResampler,
+    offset_us: i64,
+    end_ms: Option<f64>,
+    segment_start: Option<i64>,
+    last_end_us: i64,
 }
 
 impl AudioTranscoder {
-    pub fn new(codec_id: codec::Id, ist: &format::stream::Stream, octx: &mut Output, ost_index: usize) -> Result<Self, Error> {
+    /// `bitrate` in bits per second, `None` keeps the bitrate of the source up to 320 kbps
+    pub fn new(codec_id: codec::Id, ist: &format::stream::Stream, octx: &mut Output, ost_index: usize, bitrate: Option<usize>, normalization: Option<&LoudnessNormalization>) -> Result<Self, Error> {
         let ctx = codec::context::Context::from_parameters(ist.parameters())?;
         let mut decoder = ctx.decoder().audio()?;
         let codec = encoder::find(codec_id).expect("failed to find encoder").audio()?;
@@ -37,8 +166,8 @@ impl AudioTranscoder {
         encoder.set_channel_layout(channel_layout);
         // encoder.set_channels(channel_layout.channels());
         encoder.set_format(codec.formats().expect("unknown supported formats").next().unwrap());
-        encoder.set_bit_rate(decoder.bit_rate().min(320000));
-        encoder.set_max_bit_rate(decoder.max_bit_rate().min(320000));
+        encoder.set_bit_rate(bitrate.unwrap_or(decoder.bit_rate().min(320000)));
+        encoder.set_max_bit_rate(bitrate.unwrap_or(decoder.max_bit_rate().min(320000)));
 
         encoder.set_time_base((1, decoder.rate() as i32));
         output.set_time_base((1, decoder.rate() as i32));
@@ -50,8 +179,16 @@ impl AudioTranscoder {
         if in_channel_layout.is_empty() {
             in_channel_layout = ChannelLayout::default(channels);
         }
+        let filter = match normalization {
+            Some(normalization) => {
+                log::info!("Audio normalized from {:.1} LUFS to {:.1} LUFS ({:+.1} dB)", normalization.measured.integrated_lufs, normalization.target_lufs, normalization.gain_db());
+                Some(audio_filter_graph(&decoder, &normalization.filter_spec())?)
+            }
+            None => None
+        };
+        let in_format = if filter.is_some() { format::Sample::F32(format::sample::Type::Planar) } else { decoder.format() };
         let resampler = AudioResampler::new(
-            (decoder.format(), in_channel_layout, decoder.rate()),
+            (in_format, in_channel_layout, decoder.rate()),
             (encoder.format(), encoder.channel_layout(), encoder.rate()),
             1024
         )?;
@@ -60,14 +197,68 @@ impl AudioTranscoder {
             ost_index,
             decoder,
             encoder,
+            filter,
             resampler,
+            offset_us: 0,
+            end_ms: None,
+            segment_start: None,
+            last_end_us: 0,
         })
     }
 
+    /// Delays the audio by `offset_ms` (advances when negative). The samples are really inserted or removed at the start of every trim range,
+    /// because many players ignore the timestamps, and the audio is cut or padded at the end, so it's as long as the video.
+    /// `duration_ms` is the length of the input, used when the range goes to the end of the file
+    pub fn set_offset(&mut self, offset_ms: f64, duration_ms: f64) {
+        self.offset_us = (offset_ms * 1000.0).round() as i64;
+        self.end_ms = if duration_ms > 0.0 { Some(duration_ms) } else { None };
+    }
+
+    fn samples(&self, duration_us: i64) -> i64 {
+        (duration_us as f64 * self.encoder.rate() as f64 / 1_000_000.0).round() as i64
+    }
+
+    fn encode_resampled(&mut self, octx: &mut Output, ost_time_base: Rational) -> Result<(), Error> {
+        while let Some(out_frame) = self.resampler.run() {
+            self.encoder.send_frame(out_frame)?;
+            self.receive_and_process_encoded_packets(octx, ost_time_base)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the decoded frame, through the loudness filter if there's one.
+    /// `gap` is the number of samples to insert before the frame, or to drop when it's negative
+    fn filter_and_encode(&mut self, octx: &mut Output, ost_time_base: Rational, frame: &mut frame::Audio, mut gap: Option<i64>) -> Result<(), Error> {
+        let Some(graph) = self.filter.as_mut() else {
+            self.resampler.new_frame(frame)?;
+            self.apply_gap(gap);
+            return self.encode_resampled(octx, ost_time_base);
+        };
+        graph.get("in").unwrap().source().add(frame)?;
+        for mut filtered in filtered_frames(graph) {
+            self.resampler.new_frame(&mut filtered)?;
+            self.apply_gap(gap.take());
+            self.encode_resampled(octx, ost_time_base)?;
+        }
+        // The filter didn't output anything yet, the gap is before its next frame
+        self.apply_gap(gap);
+        Ok(())
+    }
+    fn apply_gap(&mut self, gap: Option<i64>) {
+        match gap {
+            Some(gap) if gap > 0 => self.resampler.pad(gap as usize),
+            Some(gap) => self.resampler.skip(-gap as usize),
+            None => { }
+        }
+    }
+
     pub fn receive_and_process_decoded_frames(&mut self, octx: &mut Output, ost_time_base: Rational, start_ms: Option<f64>, end_ms: Option<f64>, frame_ts: &mut FrameTimestamps) -> Result<Status, Error> {
         let mut status = Status::Continue;
         let mut frame = frame::Audio::empty();
 
+        // The source audio for the end of the video is `offset` earlier
+        let end_ms = if self.offset_us != 0 { end_ms.or(self.end_ms).map(|x| x - self.offset_us as f64 / 1000.0) } else { end_ms };
+
         while self.decoder.receive_frame(&mut frame).is_ok() {
 
             if let Some(ts) = frame.timestamp() {
@@ -80,17 +271,24 @@ impl AudioTranscoder {
                     }
                     let new_ts = timestamp_us - frame_ts.first.unwrap() + frame_ts.add_audio;
                     if new_ts >= 0 {
+                        let new_ts = new_ts + self.offset_us;
                         frame.set_pts(Some(new_ts.rescale((1, 1000000), self.decoder.time_base())));
 
-                        self.resampler.new_frame(&mut frame)?;
-                        while let Some(out_frame) = self.resampler.run() {
-                            self.encoder.send_frame(out_frame)?;
-                            self.receive_and_process_encoded_packets(octx, ost_time_base)?;
+                        let mut gap = None;
+                        if self.offset_us != 0 && self.segment_start != Some(frame_ts.add_audio) {
+                            // First frame of the range, fill or cut the audio up to the start of the video
+                            self.segment_start = Some(frame_ts.add_audio);
+                            gap = Some(self.samples(new_ts - frame_ts.add_audio));
                         }
+                        self.filter_and_encode(octx, ost_time_base, &mut frame, gap)?;
+
                         if let Some(last_ts) = frame_ts.last_audio {
                             frame_ts.last_duration_audio = new_ts - last_ts;
                         }
                         frame_ts.last_audio = Some(new_ts);
+                        if frame.rate() > 0 {
+                            self.last_end_us = new_ts + frame.samples() as i64 * 1_000_000 / frame.rate() as i64;
+                        }
                     }
                 }
                 if end_ms.is_some() && timestamp_ms > end_ms.unwrap() {
@@ -116,6 +314,25 @@ impl AudioTranscoder {
         self.decoder.send_eof()?;
         self.receive_and_process_decoded_frames(octx, ost_time_base, start_ms, end_ms, frame_ts)?;
 
+        if let Some(graph) = self.filter.as_mut() {
+            // Samples which are still in the limiter
+            graph.get("in").unwrap().source().flush()?;
+            for mut filtered in filtered_frames(graph) {
+                self.resampler.new_frame(&mut filtered)?;
+                self.encode_resampled(octx, ost_time_base)?;
+            }
+        }
+
+        if self.offset_us != 0 {
+            // Advanced audio ends before the video
+            let video_end_us = frame_ts.last_video.unwrap_or_default() + frame_ts.last_duration_video;
+            let missing = self.samples(video_end_us - self.last_end_us);
+            if missing > 0 {
+                self.resampler.pad(missing as usize);
+                self.encode_resampled(octx, ost_time_base)?;
+            }
+        }
+
         if let Some(out_frame) = self.resampler.flush() {
             self.encoder.send_frame(out_frame)?;
         }
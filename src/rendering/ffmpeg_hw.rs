@@ -112,39 +112,105 @@ pub unsafe fn pix_formats_to_vec(formats: *const ffi::AVPixelFormat) -> Vec<form
     ret
 }
 
+fn device_type_name(type_: DeviceType) -> String {
+    // returns a pointer to static string, shouldn't be freed
+    unsafe { CStr::from_ptr(ffi::av_hwdevice_get_type_name(type_)).to_string_lossy().into() }
+}
+
+/// Ordered list of the decoders to try, persisted in the settings. Empty means the order of ffmpeg with the software decoder last
+pub fn decoder_preference() -> Vec<String> {
+    gyroflow_core::settings::get_str("decoderPreference", "")
+        .split(',')
+        .map(|x| match x.trim().to_ascii_lowercase().as_str() {
+            "nvdec" => "cuda".to_owned(),
+            x => x.to_owned()
+        })
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+pub fn set_decoder_preference(preference: &[String]) {
+    gyroflow_core::settings::set("decoderPreference", preference.join(",").into());
+}
+
+unsafe fn hw_configs(codec: *const ffi::AVCodec) -> Vec<(DeviceType, ffi::AVPixelFormat)> {
+    let mut ret = Vec::new();
+    for i in 0..20 {
+        let config = ffi::avcodec_get_hw_config(codec, i);
+        if config.is_null() {
+            continue;
+        }
+        let type_ = (*config).device_type;
+        if type_ == ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE {
+            continue;
+        }
+        if cfg!(target_os = "windows") && type_ == ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI {
+            continue;
+        }
+        ret.push((type_, (*config).pix_fmt));
+    }
+    ret
+}
+
+/// Decoders which can be used for the codec (ffmpeg name, eg. "hevc"), the software decoder is always last.
+/// With an unknown codec, all hardware backends are listed
+pub fn available_decoders(codec_name: &str) -> Vec<String> {
+    let codec = CString::new(codec_name).ok().map_or(ptr::null(), |x| unsafe { ffi::avcodec_find_decoder_by_name(x.as_ptr()) });
+    let mut ret = if codec.is_null() {
+        supported_gpu_backends()
+    } else {
+        unsafe { hw_configs(codec) }.into_iter().map(|x| device_type_name(x.0)).collect()
+    };
+    ret.push("software".to_owned());
+    ret
+}
+
+/// Indexes in `available` (names of the hardware decoders) in the order of `preference`, `None` is the software decoder
+fn preference_order(available: &[String], preference: &[String]) -> Vec<Option<usize>> {
+    if preference.is_empty() {
+        return (0..available.len()).map(Some).collect();
+    }
+    preference.iter().filter_map(|name| {
+        if name == "software" {
+            Some(None)
+        } else {
+            available.iter().position(|x| x == name).map(Some)
+        }
+    }).collect()
+}
+
+/// Decoders to try in order, `None` is the software decoder
+fn ordered_decoders(codec: *const ffi::AVCodec) -> Vec<Option<(DeviceType, ffi::AVPixelFormat)>> {
+    let configs = unsafe { hw_configs(codec) };
+    let names = configs.iter().map(|x| device_type_name(x.0)).collect::<Vec<_>>();
+    preference_order(&names, &decoder_preference()).into_iter().map(|x| x.map(|i| configs[i])).collect()
+}
+
+/// Sets up the `index`-th decoder from the preference list, if it can't be created the following ones are tried.
+/// Returns the index of the used decoder, so the next one can be tried when this one fails
 pub fn init_device_for_decoding(index: usize, codec: *const ffi::AVCodec, decoder_ctx: &mut codec::context::Context, device: Option<&str>) -> Result<(usize, ffi::AVHWDeviceType, String, Option<ffi::AVPixelFormat>), super::FFmpegError> {
-    for i in index..20 {
-        unsafe {
-            let config = ffi::avcodec_get_hw_config(codec, i as i32);
-            if config.is_null() {
-                ::log::debug!("config null for {}", i);
-                continue;
-            }
-            let type_ = (*config).device_type;
-            if type_ == ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE {
-                continue;
-            }
-            if cfg!(target_os = "windows") && type_ == ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI {
-                continue;
-            }
-            ::log::debug!("[dec] codec type {:?} {}", type_, i);
-            let mut devices = DEVICES.lock();
-            let mut device_hash = 0;
-            if let Some(dev_name) = device {
-                let mut hasher = crc32fast::Hasher::new();
-                hasher.update(dev_name.as_bytes());
-                device_hash = hasher.finalize() as u64;
-            }
-            if let Entry::Vacant(e) = devices.entry(type_ as u64 + device_hash) {
-                if let Ok(dev) = HWDevice::from_type(type_, device) {
-                    e.insert(dev);
-                }
-            }
-            if let Some(dev) = devices.get(&(type_ as u64 + device_hash)) {
-                (*decoder_ctx.as_mut_ptr()).hw_device_ctx = dev.add_ref();
-                return Ok((i, type_, dev.name(), Some((*config).pix_fmt)));
+    let decoders = ordered_decoders(codec);
+    for (i, decoder) in decoders.iter().enumerate().skip(index) {
+        let Some((type_, pix_fmt)) = *decoder else {
+            break;
+        };
+        ::log::debug!("[dec] codec type {:?} {}", type_, i);
+        let mut devices = DEVICES.lock();
+        let mut device_hash = 0;
+        if let Some(dev_name) = device {
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(dev_name.as_bytes());
+            device_hash = hasher.finalize() as u64;
+        }
+        if let Entry::Vacant(e) = devices.entry(type_ as u64 + device_hash) {
+            if let Ok(dev) = HWDevice::from_type(type_, device) {
+                e.insert(dev);
             }
         }
+        if let Some(dev) = devices.get(&(type_ as u64 + device_hash)) {
+            unsafe { (*decoder_ctx.as_mut_ptr()).hw_device_ctx = dev.add_ref(); }
+            return Ok((i, type_, dev.name(), Some(pix_fmt)));
+        }
+        ::log::warn!("Hardware decoder {} is not available, trying the next one", device_type_name(type_));
     }
     Ok((0, ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE, String::new(), None))
 }
@@ -340,6 +406,19 @@ pub fn initialize_hwframes_context(encoder_ctx: *mut ffi::AVCodecContext, _frame
     Ok(())
 }
 
+fn pixel_format_depth(format: format::Pixel) -> i32 {
+    unsafe {
+        let desc = ffi::av_pix_fmt_desc_get(format.into());
+        if desc.is_null() { 0 } else { (*desc).comp[0].depth }
+    }
+}
+fn pixel_format_chroma(format: format::Pixel) -> (u8, u8) {
+    unsafe {
+        let desc = ffi::av_pix_fmt_desc_get(format.into());
+        if desc.is_null() { (0, 0) } else { ((*desc).log2_chroma_w, (*desc).log2_chroma_h) }
+    }
+}
+
 pub fn find_best_matching_codec(codec: format::Pixel, supported: &[format::Pixel]) -> Option<format::Pixel> {
     if supported.is_empty() { return None; }
 
@@ -361,6 +440,18 @@ pub fn find_best_matching_codec(codec: format::Pixel, supported: &[format::Pixel
     None
 }
 
+/// Software format with the bit depth of `codec`, or more if there's none. The same chroma subsampling is preferred.
+/// For HLG, which isn't HLG anymore when reduced to 8-bit
+pub fn find_same_depth_format(codec: format::Pixel, supported: &[format::Pixel]) -> Option<format::Pixel> {
+    let depth = pixel_format_depth(codec);
+    if depth <= 0 { return None; }
+    let chroma = pixel_format_chroma(codec);
+    supported.iter().copied().filter(|x| !is_hardware_format((*x).into()) && pixel_format_depth(*x) > 0).min_by_key(|x| {
+        let d = pixel_format_depth(*x);
+        (d < depth, (d - depth).abs(), pixel_format_chroma(*x) != chroma)
+    })
+}
+
 // pub fn get_supported_pixel_formats(name: &str) -> Vec<ffi::AVPixelFormat> {
 //     if let Some(mut codec) = encoder::find_by_name(name) {
 //         unsafe {
@@ -370,3 +461,33 @@ pub fn find_best_matching_codec(codec: format::Pixel, supported: &[format::Pixel
 //         Vec::new()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoder_order() {
+        let available = ["cuda", "d3d11va", "vulkan"].map(String::from);
+        let pref = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(preference_order(&available, &[]), vec![Some(0), Some(1), Some(2)]);
+        // The ones which aren't available for the codec are left out, the decoders after "software" are never tried
+        assert_eq!(preference_order(&available, &pref(&["vulkan", "videotoolbox", "cuda", "software", "d3d11va"])), vec![Some(2), Some(0), None, Some(1)]);
+        assert_eq!(preference_order(&available, &pref(&["software"])), vec![None]);
+    }
+
+    #[test]
+    fn hlg_format() {
+        use format::Pixel;
+        let supported = [Pixel::YUV420P, Pixel::YUV422P10LE, Pixel::YUV420P12LE];
+        // Not paired with any of them, the generic match would pick the 8-bit format
+        assert_eq!(find_best_matching_codec(Pixel::P010LE, &supported), None);
+        assert_eq!(find_same_depth_format(Pixel::P010LE, &supported), Some(Pixel::YUV422P10LE));
+        // More bits rather than fewer, and the same subsampling when the depth is the same
+        assert_eq!(find_same_depth_format(Pixel::P010LE, &[Pixel::YUV420P, Pixel::YUV420P12LE]), Some(Pixel::YUV420P12LE));
+        assert_eq!(find_same_depth_format(Pixel::P010LE, &[Pixel::YUV444P10LE, Pixel::YUV420P10LE]), Some(Pixel::YUV420P10LE));
+        assert_eq!(find_same_depth_format(Pixel::P010LE, &[Pixel::CUDA]), None);
+        // The pairs are still used first
+        assert_eq!(find_best_matching_codec(Pixel::P010LE, &[Pixel::YUV420P, Pixel::YUV420P10LE]), Some(Pixel::YUV420P10LE));
+    }
+}
@@ -32,6 +32,9 @@ pub struct FfmpegProcessor<'a> {
     pub video_codec: Option<String>,
 
     pub audio_codec: codec::Id,
+    pub audio_offset_ms: f64, // Positive delays the audio
+    pub audio_bitrate: Option<usize>, // Bits per second, `None` keeps the bitrate of the source
+    pub loudness_normalization: Option<LoudnessNormalization>,
 
     input_context: format::context::Input,
 
@@ -40,8 +43,13 @@ pub struct FfmpegProcessor<'a> {
     pub ranges_ms: Vec<(Option<f64>, Option<f64>)>,
 
     pub decoder_fps: f64,
+    pub output_frame_rate: Option<Rational>, // Constant frame rate of the output, `None` keeps the frame rate and the timestamps of the source
 
     pub preserve_other_tracks: bool,
+    pub copy_telemetry: bool, // Copy the camera metadata tracks, re-based to the trim ranges
+    pub null_output: bool,    // Encode without writing anything, for the first pass of the two-pass encoding
+
+    pub image_sequence_start: Option<u32>, // Number of the first written file of an image sequence
 
     #[cfg(target_os = "android")]
     pub android_handles: Option<AndroidHWHandles>,
@@ -74,13 +82,14 @@ pub enum FFmpegError {
     ToHWTransferError(i32),
     CannotCreateGPUDecoding,
     NoFramesContext,
-    GPUDecodingFailed,
+    GPUDecodingFailed(usize), // Index of the decoder in the decoder list, the next one can be tried
     ToHWBufferError(i32),
     PixelFormatNotSupported((format::Pixel, Vec<format::Pixel>, Option<format::Pixel>)),
     UnknownPixelFormat(format::Pixel),
     InternalError(ffmpeg_next::Error),
     CannotOpenInputFile((String, FilesystemError)),
     CannotOpenOutputFile((String, FilesystemError)),
+    EncoderOptions(String),
 }
 
 impl std::fmt::Display for FFmpegError {
@@ -98,7 +107,7 @@ impl std::fmt::Display for FFmpegError {
             FFmpegError::ToHWTransferError(i)   => write!(f, "Error transferring frame to the GPU: {:?}", ffmpeg_next::Error::Other { errno: *i }),
             FFmpegError::ToHWBufferError(i)     => write!(f, "Error getting HW transfer buffer to the GPU: {:?}", ffmpeg_next::Error::Other { errno: *i }),
             FFmpegError::NoFramesContext             => write!(f, "Empty hw frames context"),
-            FFmpegError::GPUDecodingFailed           => write!(f, "GPU decoding failed, please try again."),
+            FFmpegError::GPUDecodingFailed(_)        => write!(f, "GPU decoding failed, please try again."),
             FFmpegError::CannotCreateGPUDecoding     => write!(f, "Unable to create HW devices context"),
             FFmpegError::NoGPUDecodingDevice         => write!(f, "Unable to create any HW decoding context"),
             FFmpegError::UnknownPixelFormat(v) => write!(f, "Unknown pixel format: {:?}", v),
@@ -106,6 +115,7 @@ impl std::fmt::Display for FFmpegError {
             FFmpegError::InternalError(e)     => write!(f, "ffmpeg error: {:?}", e),
             FFmpegError::CannotOpenInputFile((url, e))   => write!(f, "Cannot open input file {url}: {e:?}"),
             FFmpegError::CannotOpenOutputFile((url, e))   => write!(f, "Cannot open output file {url}: {e:?}"),
+            FFmpegError::EncoderOptions(e)    => write!(f, "{e}"),
         }
     }
 }
@@ -130,7 +140,8 @@ pub struct VideoInfo {
     pub height: u32,
     pub bitrate: f64, // in Mbps
     pub rotation: i32,
-    pub created_at: Option<u64>
+    pub created_at: Option<u64>,
+    pub timecode: Option<String>, // Of the first frame
 }
 
 impl<'a> FfmpegProcessor<'a> {
@@ -185,24 +196,37 @@ impl<'a> FfmpegProcessor<'a> {
 
         let decoder_fps = stream.rate().into();
 
-        let mut decoder_ctx = unsafe { codec::context::Context::wrap(ffi::avcodec_alloc_context3(decoder), None) };
-        unsafe {
-            if ffi::avcodec_parameters_to_context(decoder_ctx.as_mut_ptr(), stream.parameters().as_ptr()) < 0 {
-                ::log::error!("avcodec_parameters_to_context failed");
-                return Err(FFmpegError::DecoderNotFound);
+        // When the hardware decoder can't be opened, the next one from the preference list is tried
+        let mut decoder_index = gpu_decoder_index;
+        let (video_decoder, hw_backend, used_decoder_index) = loop {
+            let mut decoder_ctx = unsafe { codec::context::Context::wrap(ffi::avcodec_alloc_context3(decoder), None) };
+            unsafe {
+                if ffi::avcodec_parameters_to_context(decoder_ctx.as_mut_ptr(), stream.parameters().as_ptr()) < 0 {
+                    ::log::error!("avcodec_parameters_to_context failed");
+                    return Err(FFmpegError::DecoderNotFound);
+                }
             }
-        }
-        decoder_ctx.set_threading(ffmpeg_next::threading::Config { kind: ffmpeg_next::threading::Type::Frame, count: 5 });
-
-        let codec = decoder_ctx.codec().ok_or(FFmpegError::DecoderNotFound)?;
-
-        let mut hw_backend = String::new();
-        if gpu_decoding {
-            let hw = ffmpeg_hw::init_device_for_decoding(gpu_decoder_index, unsafe { codec.as_ptr() }, &mut decoder_ctx, hwaccel_device.as_deref())?;
-            log::debug!("Selected HW backend {:?} ({}) with format {:?}", hw.1, hw.2, hw.3);
-            hw_backend = hw.2;
-        }
+            decoder_ctx.set_threading(ffmpeg_next::threading::Config { kind: ffmpeg_next::threading::Type::Frame, count: 5 });
+
+            let codec = decoder_ctx.codec().ok_or(FFmpegError::DecoderNotFound)?;
+
+            let mut hw_backend = String::new();
+            let mut used_decoder_index = 0;
+            if gpu_decoding {
+                let hw = ffmpeg_hw::init_device_for_decoding(decoder_index, unsafe { codec.as_ptr() }, &mut decoder_ctx, hwaccel_device.as_deref())?;
+                log::debug!("Selected HW backend {:?} ({}) with format {:?}", hw.1, hw.2, hw.3);
+                hw_backend = hw.2;
+                used_decoder_index = hw.0;
+                decoder_index = hw.0 + 1;
+            }
+            match decoder_ctx.decoder().open_as(codec).and_then(|x| x.video()) {
+                Ok(video_decoder) => break (video_decoder, hw_backend, used_decoder_index),
+                Err(e) if !hw_backend.is_empty() => log::warn!("Failed to open the {hw_backend} decoder: {e:?}, trying the next one"),
+                Err(e) => return Err(e.into())
+            }
+        };
         gpu_decoding = !hw_backend.is_empty();
+        ::log::info!("Decoding with {}", if gpu_decoding { &hw_backend } else { "software" });
 
         Ok(Self {
             _file: file,
@@ -211,6 +235,9 @@ impl<'a> FfmpegProcessor<'a> {
             video_codec: None,
 
             audio_codec: codec::Id::AAC,
+            audio_offset_ms: 0.0,
+            audio_bitrate: None,
+            loudness_normalization: None,
 
             ost_time_bases: Vec::new(),
 
@@ -219,6 +246,11 @@ impl<'a> FfmpegProcessor<'a> {
             ranges_ms: Vec::new(),
 
             preserve_other_tracks: false,
+            copy_telemetry: false,
+            null_output: false,
+
+            image_sequence_start: None,
+            output_frame_rate: None,
 
             decoder_fps,
 
@@ -228,12 +260,13 @@ impl<'a> FfmpegProcessor<'a> {
             video: VideoTranscoder {
                 gpu_encoding: true,
                 gpu_decoding,
+                gpu_decoder_index: used_decoder_index,
                 input_index: stream.index(),
                 encoder_params: EncoderParams {
                     options: Dictionary::new(),
                     ..EncoderParams::default()
                 },
-                decoder: Some(decoder_ctx.decoder().open_as(codec)?.video()?),
+                decoder: Some(video_decoder),
                 ..VideoTranscoder::default()
             },
 
@@ -241,9 +274,32 @@ impl<'a> FfmpegProcessor<'a> {
         })
     }
 
+    /// Loudness of the audio in `ranges_ms`, or of the whole file when they're empty. `None` when there's no audio or it's silent
+    pub fn measure_loudness(&mut self, ranges_ms: &[(Option<f64>, Option<f64>)], cancel_flag: &AtomicBool) -> Result<Option<Loudness>, FFmpegError> {
+        let (stream_index, mut meter) = match self.input_context.streams().best(media::Type::Audio) {
+            Some(stream) => (stream.index(), LoudnessMeter::new(&stream)?),
+            None => return Ok(None)
+        };
+        let ranges = if ranges_ms.is_empty() { vec![(None, None)] } else { ranges_ms.to_vec() };
+        for (start_ms, end_ms) in ranges {
+            let position = (start_ms.unwrap_or_default() as i64).rescale((1, 1000), rescale::TIME_BASE);
+            self.input_context.seek(position, ..position)?;
+            meter.decoder.flush();
+            for (stream, packet) in self.input_context.packets() {
+                if stream.index() != stream_index { continue; }
+                if !meter.add_packet(&packet, stream.time_base(), start_ms, end_ms)? { break; }
+                if cancel_flag.load(Relaxed) { return Ok(None); }
+            }
+        }
+        // Back to the start for the render
+        self.input_context.seek(0, ..0)?;
+        Ok(meter.finish()?)
+    }
+
     pub fn render(&mut self, base: &'a EngineBase, output_folder: &str, output_filename: &str, output_size: (u32, u32), bitrate: Option<f64>, cancel_flag: Arc<AtomicBool>, pause_flag: Arc<AtomicBool>) -> Result<(), FFmpegError> {
         let output_url = filesystem::get_file_url(output_folder, output_filename, true);
-        let mut file = FfmpegPathWrapper::new(base, &output_url, true).map_err(|e| FFmpegError::CannotOpenOutputFile((output_url.to_string(), e)))?;
+        let file = if self.null_output { None } else { Some(FfmpegPathWrapper::new(base, &output_url, true).map_err(|e| FFmpegError::CannotOpenOutputFile((output_url.to_string(), e)))?) };
+        let mut output_path = file.as_ref().map(|x| x.path.clone()).unwrap_or_default();
 
         let mut stream_mapping: Vec<isize> = vec![0; self.input_context.nb_streams() as _];
         let mut ist_time_bases = vec![Rational(0, 0); self.input_context.nb_streams() as _];
@@ -270,16 +326,25 @@ impl<'a> FfmpegProcessor<'a> {
 
         let mut output_options = Dictionary::new();
         let mut output_format = if let Some(pos) = output_filename.rfind('.') { &output_filename[pos+1..] } else { "mp4" }.to_ascii_lowercase();
-        if file.path.starts_with("fd:") {
-            output_options.set("fd", &file.path[3..]);
-            file.path = "fd:".into();
+        if output_path.starts_with("fd:") {
+            output_options.set("fd", &output_path[3..]);
+            output_path = "fd:".into();
         }
         if output_format == "mkv" { output_format = String::from("matroska"); }
+        if let Some(start) = self.image_sequence_start {
+            output_options.set("start_number", &start.to_string());
+        }
+        if self.copy_telemetry && (output_format == "mov" || output_format == "mp4") {
+            // Write also the metadata which doesn't have a standard atom, like the XMP
+            output_options.set("movflags", "use_metadata_tags");
+        }
 
-        let mut octx = if output_format == "exr" || output_format == "png" {
-            format::output_with(&file.path, output_options)
+        let mut octx = if self.null_output {
+            format::output_as_with("-", "null", output_options)
+        } else if output_format == "exr" || output_format == "png" || output_format == "tif" {
+            format::output_with(&output_path, output_options)
         } else {
-            format::output_as_with(&file.path, &output_format, output_options)
+            format::output_as_with(&output_path, &output_format, output_options)
         }?;
 
         // Copy metadata
@@ -297,7 +362,13 @@ impl<'a> FfmpegProcessor<'a> {
             }
 
             let medium = stream.parameters().medium();
-            if medium != media::Type::Audio && medium != media::Type::Video && (!self.preserve_other_tracks || medium != media::Type::Data) {
+            let copy_data = medium == media::Type::Data && (self.preserve_other_tracks || (self.copy_telemetry && is_telemetry_stream(&stream)));
+            if medium != media::Type::Audio && medium != media::Type::Video && !copy_data {
+                stream_mapping[i] = -1;
+                continue;
+            }
+            // Without an output audio stream it would be mapped to the index of the next output stream, and with the copied telemetry its packets would be written to that track
+            if medium == media::Type::Audio && self.audio_codec == codec::Id::None {
                 stream_mapping[i] = -1;
                 continue;
             }
@@ -323,16 +394,18 @@ impl<'a> FfmpegProcessor<'a> {
                 let mut out_stream = octx.add_stream(codec)?;
                 self.video.encoder_params.codec = Some(codec);
 
-                self.video.encoder_params.frame_rate = Some(stream.avg_frame_rate());
-                self.video.encoder_params.time_base = Some(stream.rate().invert());
+                let frame_rate = self.output_frame_rate.unwrap_or_else(|| stream.avg_frame_rate());
+                self.video.encoder_params.frame_rate = Some(frame_rate);
+                self.video.encoder_params.time_base = Some(self.output_frame_rate.unwrap_or_else(|| stream.rate()).invert());
 
-                out_stream.set_rate(stream.rate());
+                out_stream.set_rate(self.output_frame_rate.unwrap_or_else(|| stream.rate()));
                 out_stream.set_time_base(stream.time_base());
-                out_stream.set_avg_frame_rate(stream.avg_frame_rate());
+                out_stream.set_avg_frame_rate(frame_rate);
 
                 output_index += 1;
             } else if medium == media::Type::Audio && self.audio_codec != codec::Id::None {
-                if self.preserve_other_tracks/*stream.codec().id() == self.audio_codec*/ {
+                // The normalized audio can't be copied, and the offset needs the samples to be inserted or removed
+                if self.preserve_other_tracks && self.loudness_normalization.is_none() && self.audio_offset_ms == 0.0/*stream.codec().id() == self.audio_codec*/ {
                     // Direct stream copy
                     let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
                     ost.set_parameters(stream.parameters());
@@ -340,10 +413,14 @@ impl<'a> FfmpegProcessor<'a> {
                     unsafe { (*ost.parameters().as_mut_ptr()).codec_tag = 0; }
                 } else {
                     // Transcode audio
-                    atranscoders.insert(i, AudioTranscoder::new(self.audio_codec, &stream, &mut octx, output_index as _)?);
+                    let mut atranscoder = AudioTranscoder::new(self.audio_codec, &stream, &mut octx, output_index as _, self.audio_bitrate, self.loudness_normalization.as_ref())?;
+                    if self.audio_offset_ms != 0.0 {
+                        atranscoder.set_offset(self.audio_offset_ms, self.input_context.duration() as f64 / 1000.0);
+                    }
+                    atranscoders.insert(i, atranscoder);
                 }
                 output_index += 1;
-            } else if self.preserve_other_tracks && medium == media::Type::Data {
+            } else if copy_data {
                 // Direct stream copy
                 let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
                 ost.set_parameters(stream.parameters());
@@ -377,6 +454,8 @@ impl<'a> FfmpegProcessor<'a> {
         // let mut copied_stream_first_pts = None;
         // let mut copied_stream_first_dts = None;
 
+        let rebase_data = !self.preserve_other_tracks;
+
         let process_stream = |atranscoders: &mut HashMap<usize, AudioTranscoder>, octx: &mut format::context::Output, stream: Stream, mut packet: ffmpeg_next::Packet, start_ms: Option<f64>, end_ms: Option<f64>, ist_index: usize, ost_index: isize, ost_time_base: Rational, frame_ts: &mut FrameTimestamps| -> Result<bool, Error> {
             match atranscoders.get_mut(&ist_index) {
                 Some(atranscoder) => {
@@ -387,6 +466,23 @@ impl<'a> FfmpegProcessor<'a> {
                         return Ok(true);
                     }
                 }
+                None if rebase_data && stream.parameters().medium() == media::Type::Data => {
+                    // Telemetry of the trim range, on the same timeline as the video.
+                    // Packets starting before the range are dropped, because the samples inside have timing relative to the packet
+                    let Some(pts) = packet.pts() else { return Ok(false); };
+                    let timestamp_us = pts.rescale(ist_time_bases[ist_index], (1, 1000000));
+                    let timestamp_ms = timestamp_us as f64 / 1000.0;
+                    if start_ms.is_some_and(|x| timestamp_ms < x) || end_ms.is_some_and(|x| timestamp_ms > x) {
+                        return Ok(false);
+                    }
+                    let new_ts = (timestamp_us - frame_ts.first.unwrap_or(timestamp_us) + frame_ts.add_video).max(0).rescale((1, 1000000), ost_time_base);
+                    packet.set_pts(Some(new_ts));
+                    packet.set_dts(Some(new_ts));
+                    packet.set_duration(packet.duration().rescale(ist_time_bases[ist_index], ost_time_base));
+                    packet.set_position(-1);
+                    packet.set_stream(ost_index as _);
+                    packet.write_interleaved(octx)?;
+                }
                 None => {
                     // Direct stream copy
                     // TODO: Wrong pts, shifted by length of packet, would need to synchronize with first video frame pts
@@ -426,9 +522,16 @@ impl<'a> FfmpegProcessor<'a> {
                         {
                             let decoder = self.video.decoder.as_mut().ok_or(Error::DecoderNotFound)?;
                             packet.rescale_ts(stream.time_base(), (1, 1000000)); // rescale to microseconds
-                            if let Err(err) = decoder.send_packet(&packet) {
+                            let decode_start = std::time::Instant::now();
+                            let result = decoder.send_packet(&packet);
+                            {
+                                let mut counters = self.video.counters.borrow_mut();
+                                counters.decode_time += decode_start.elapsed();
+                                counters.packets_decoded += 1;
+                            }
+                            if let Err(err) = result {
                                 if self.gpu_decoding && FFMPEG_LOG.read().contains("failed to decode picture") {
-                                    return Err(FFmpegError::GPUDecodingFailed);
+                                    return Err(FFmpegError::GPUDecodingFailed(self.video.gpu_decoder_index));
                                 }
                                 if !any_encoded {
                                     return Err(err.into());
@@ -462,7 +565,7 @@ impl<'a> FfmpegProcessor<'a> {
                             }
                         }
                     }
-                } else if self.audio_codec != codec::Id::None || self.preserve_other_tracks {
+                } else if self.audio_codec != codec::Id::None || self.preserve_other_tracks || self.copy_telemetry {
                     if encoding_audio {
                         if !video_inited {
                             pending_packets.push((stream, packet, ist_index, ost_index));
@@ -559,7 +662,7 @@ impl<'a> FfmpegProcessor<'a> {
                     if let Err(err) = decoder.send_packet(&packet) {
                         ::log::error!("Decoder error {:?}", err);
                         if self.gpu_decoding && FFMPEG_LOG.read().contains("failed to decode picture") {
-                            return Err(FFmpegError::GPUDecodingFailed);
+                            return Err(FFmpegError::GPUDecodingFailed(self.video.gpu_decoder_index));
                         }
                         if !any_encoded {
                             return Err(err.into());
@@ -656,7 +759,8 @@ impl<'a> FfmpegProcessor<'a> {
                     height: video.height(),
                     bitrate: bitrate as f64 / 1024.0 / 1024.0,
                     rotation,
-                    created_at
+                    created_at,
+                    timecode: context.metadata().get("timecode").or(stream.metadata().get("timecode")).map(|x| x.to_owned()),
                 });
             }
         }
@@ -664,6 +768,75 @@ impl<'a> FfmpegProcessor<'a> {
     }
 }
 
+/// GoPro GPMF, Google CAMM and DJI metadata tracks
+fn is_telemetry_stream(stream: &Stream) -> bool {
+    let tag = unsafe { (*stream.parameters().as_ptr()).codec_tag };
+    [b"gpmd", b"camm", b"djmd"].iter().any(|x| u32::from_le_bytes(**x) == tag)
+}
+
+/// Joins two files with the same streams and encoding settings, like a paused render and its continuation.
+/// The second file starts where the video of the first one ends and the other streams are cut there
+pub fn concat_segments(base: &EngineBase, first_url: &str, second_url: &str, output_url: &str) -> Result<(), FFmpegError> {
+    let first = FfmpegPathWrapper::new(base, first_url, false).map_err(|e| FFmpegError::CannotOpenInputFile((first_url.to_string(), e)))?;
+    let second = FfmpegPathWrapper::new(base, second_url, false).map_err(|e| FFmpegError::CannotOpenInputFile((second_url.to_string(), e)))?;
+    let output = FfmpegPathWrapper::new(base, output_url, true).map_err(|e| FFmpegError::CannotOpenOutputFile((output_url.to_string(), e)))?;
+
+    let mut inputs = [format::input(&first.path)?, format::input(&second.path)?];
+
+    let output_filename = output_url.strip_suffix(".tmp").unwrap_or(output_url);
+    let mut output_format = if let Some(pos) = output_filename.rfind('.') { &output_filename[pos+1..] } else { "mp4" }.to_ascii_lowercase();
+    if output_format == "mkv" { output_format = String::from("matroska"); }
+    let mut octx = format::output_as(&output.path, &output_format)?;
+
+    octx.set_metadata(inputs[0].metadata().to_owned());
+    for stream in inputs[0].streams() {
+        let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+        ost.set_parameters(stream.parameters());
+        ost.set_metadata(stream.metadata().to_owned());
+        unsafe { (*ost.parameters().as_mut_ptr()).codec_tag = 0; }
+    }
+    octx.write_header()?;
+
+    let video_index = inputs[0].streams().best(media::Type::Video).map(|x| x.index()).ok_or(Error::StreamNotFound)?;
+    let first_end_us = {
+        let stream = inputs[0].stream(video_index).ok_or(Error::StreamNotFound)?;
+        if stream.duration() > 0 { stream.duration().rescale(stream.time_base(), rescale::TIME_BASE) } else { inputs[0].duration() }
+    };
+    let ost_time_bases = octx.streams().map(|x| x.time_base()).collect::<Vec<_>>();
+    let mut last_dts = vec![None; ost_time_bases.len()];
+    let mut offsets = vec![None; ost_time_bases.len()];
+
+    for (n, input) in inputs.iter_mut().enumerate() {
+        for (stream, mut packet) in input.packets() {
+            let i = stream.index();
+            if i >= ost_time_bases.len() { continue; }
+            let pts_us = packet.pts().map(|x| x.rescale(stream.time_base(), rescale::TIME_BASE));
+            if i != video_index && pts_us.is_some_and(|x| if n == 0 { x >= first_end_us } else { x < 0 }) {
+                continue;
+            }
+            packet.rescale_ts(stream.time_base(), ost_time_bases[i]);
+            if n > 0 {
+                // Right after the end of the first file, but never before its last packet
+                let offset = *offsets[i].get_or_insert_with(|| {
+                    let offset = first_end_us.rescale(rescale::TIME_BASE, ost_time_bases[i]);
+                    match (last_dts[i], packet.dts()) {
+                        (Some(last), Some(dts)) => offset.max(last + 1 - dts),
+                        _ => offset
+                    }
+                });
+                packet.set_pts(packet.pts().map(|x| x + offset));
+                packet.set_dts(packet.dts().map(|x| x + offset));
+            }
+            if packet.dts().is_some() { last_dts[i] = packet.dts(); }
+            packet.set_stream(i);
+            packet.set_position(-1);
+            packet.write_interleaved(&mut octx)?;
+        }
+    }
+    octx.write_trailer()?;
+    Ok(())
+}
+
 /* unsafe extern "C" fn get_hw_format(ctx: *mut ffi::AVCodecContext, pix_fmts: *const ffi::AVPixelFormat) -> ffi::AVPixelFormat {
     let mut i = 0;
     loop {
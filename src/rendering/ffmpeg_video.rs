@@ -8,6 +8,8 @@ use super::ffmpeg_processor::Status;
 use super::ffmpeg_processor::FFmpegError;
 use super::ffmpeg_processor::FrameTimestamps;
 use super::ffmpeg_video_converter::Converter;
+use super::render_stats::StageCounters;
+use std::{ cell::RefCell, rc::Rc, time::{ Duration, Instant } };
 use image::{ImageBuffer, Rgb};
 
 pub struct FrameBuffers {
@@ -35,6 +37,14 @@ pub enum ProcessingOrder {
     PostConversion
 }
 
+#[derive(Default, PartialEq, Debug, Clone, Copy)]
+pub enum RateControlMode {
+    #[default]
+    Constant,                 // The bitrate is the minimum and maximum
+    Quality,                  // No bitrate, the quality is set by the encoder options
+    Variable { max_mbps: Option<f64>, buffer_size_mbps: Option<f64> },
+}
+
 #[derive(Default)]
 pub struct EncoderParams<'a> {
     pub codec: Option<codec::codec::Codec>,
@@ -45,6 +55,7 @@ pub struct EncoderParams<'a> {
     pub frame_rate: Option<Rational>,
     pub time_base: Option<Rational>,
     pub keyframe_distance_s: f64,
+    pub rate_control: RateControlMode,
 }
 #[derive(Default)]
 pub struct VideoTranscoder<'a> {
@@ -64,6 +75,8 @@ pub struct VideoTranscoder<'a> {
     pub gpu_decoding: bool,
     pub gpu_encoding: bool,
     pub clone_frames: bool,
+    pub decoded_frames: usize,
+    pub gpu_decoder_index: usize, // In the decoder list, see `ffmpeg_hw::init_device_for_decoding`
 
     pub converter: Converter,
 
@@ -75,14 +88,17 @@ pub struct VideoTranscoder<'a> {
     pub processing_order: ProcessingOrder,
 
     pub ffmpeg_interpolation: i32,
+
+    pub counters: Rc<RefCell<StageCounters>>,
 }
 
 pub struct RateControl {
     pub out_timestamp_us: i64,
+    pub duration_us: i64, // Until the next frame of the source, 0 if unknown
     pub repeat_times: i64,
     pub repeat_interval: i64,
 }
-impl Default for RateControl { fn default() -> Self { Self { out_timestamp_us: 0, repeat_times: 1, repeat_interval: 0 } } }
+impl Default for RateControl { fn default() -> Self { Self { out_timestamp_us: 0, duration_us: 0, repeat_times: 1, repeat_interval: 0 } } }
 
 macro_rules! ffmpeg {
     ($func:stmt; $err:ident) => {
@@ -157,8 +173,31 @@ pub fn debug_save_frame(frame: &frame::Video, filename: &str) {
     log::debug!("Successfully saved frame to {}", filename);
 }
 
+// HDR metadata: mastering display color volume and content light level
+const HDR_SIDE_DATA: [(ffi::AVFrameSideDataType, ffi::AVPacketSideDataType); 2] = [
+    (ffi::AVFrameSideDataType::AV_FRAME_DATA_MASTERING_DISPLAY_METADATA, ffi::AVPacketSideDataType::AV_PKT_DATA_MASTERING_DISPLAY_METADATA),
+    (ffi::AVFrameSideDataType::AV_FRAME_DATA_CONTENT_LIGHT_LEVEL,        ffi::AVPacketSideDataType::AV_PKT_DATA_CONTENT_LIGHT_LEVEL),
+];
+
+/// HDR metadata of the decoded frame, or of the input stream when the frames don't have it. Indexes in `HDR_SIDE_DATA` with the raw structs
+unsafe fn hdr_metadata(frame: *const ffi::AVFrame, decoder: *const ffi::AVCodecContext) -> Vec<(usize, Vec<u8>)> {
+    let mut ret = Vec::new();
+    for (i, (frame_type, packet_type)) in HDR_SIDE_DATA.iter().enumerate() {
+        let sd = ffi::av_frame_get_side_data(frame, *frame_type);
+        if !sd.is_null() && !(*sd).data.is_null() {
+            ret.push((i, std::slice::from_raw_parts((*sd).data, (*sd).size).to_vec()));
+            continue;
+        }
+        let sd = ffi::av_packet_side_data_get((*decoder).coded_side_data, (*decoder).nb_coded_side_data, *packet_type);
+        if !sd.is_null() && !(*sd).data.is_null() {
+            ret.push((i, std::slice::from_raw_parts((*sd).data, (*sd).size).to_vec()));
+        }
+    }
+    ret
+}
+
 impl<'a> VideoTranscoder<'a> {
-    fn init_encoder(frame: &mut frame::Video, params: &EncoderParams, decoder: &mut decoder::Video, size: (u32, u32), bitrate_mbps: Option<f64>, octx: &mut format::context::Output, output_index: usize, hw_upload_format: &Option<format::Pixel>) -> Result<encoder::video::Video, FFmpegError> {
+    fn init_encoder(frame: &mut frame::Video, params: &EncoderParams, decoder: &mut decoder::Video, size: (u32, u32), bitrate_mbps: Option<f64>, octx: &mut format::context::Output, output_index: usize, hw_upload_format: &Option<format::Pixel>, hdr_side_data: &[(usize, Vec<u8>)]) -> Result<encoder::video::Video, FFmpegError> {
         let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
         let mut ost = octx.stream_mut(output_index).unwrap();
         let encoder_codec = params.codec.unwrap();
@@ -171,11 +210,18 @@ impl<'a> VideoTranscoder<'a> {
         let codec_name = encoder.codec().map(|x| x.name().to_string()).unwrap_or_default();
         let pixel_format = params.pixel_format.unwrap_or_else(|| frame.format());
         let mut color_range = frame.color_range();
+        if color_range == util::color::Range::Unspecified {
+            color_range = decoder.color_range();
+        }
 
         // Workaround for a bug in prores videotoolbox encoder
         if cfg!(any(target_os = "macos", target_os = "ios")) && pixel_format == format::Pixel::NV12 && (codec_name == "prores_videotoolbox" || codec_name == "dnxhd") {
             color_range = util::color::Range::MPEG;
         }
+        // DNxHR is always limited range
+        if codec_name == "dnxhd" {
+            color_range = util::color::Range::MPEG;
+        }
 
         log::debug!("Setting output pixel format: {:?}, color range: {:?}", pixel_format, color_range);
 
@@ -186,12 +232,27 @@ impl<'a> VideoTranscoder<'a> {
         encoder.set_frame_rate(params.frame_rate);
         encoder.set_time_base(params.time_base.unwrap());
         let bitrate = bitrate_mbps.map(|x| (x * 1024.0*1024.0) as usize).unwrap_or_else(|| decoder.bit_rate());
-        encoder.set_bit_rate(bitrate);
-        if !codec_name.contains("videotoolbox") {
-            encoder.set_max_bit_rate(bitrate);
-        }
-        unsafe {
-            (*encoder.as_mut_ptr()).rc_min_rate = bitrate as i64;
+        let to_bits = |mbps: f64| (mbps * 1024.0*1024.0) as usize;
+        match params.rate_control {
+            RateControlMode::Constant => {
+                encoder.set_bit_rate(bitrate);
+                if !codec_name.contains("videotoolbox") {
+                    encoder.set_max_bit_rate(bitrate);
+                }
+                unsafe {
+                    (*encoder.as_mut_ptr()).rc_min_rate = bitrate as i64;
+                }
+            }
+            RateControlMode::Quality => { }
+            RateControlMode::Variable { max_mbps, buffer_size_mbps } => {
+                encoder.set_bit_rate(bitrate);
+                if let Some(max) = max_mbps {
+                    encoder.set_max_bit_rate(to_bits(max));
+                }
+                if let Some(size) = buffer_size_mbps {
+                    unsafe { (*encoder.as_mut_ptr()).rc_buffer_size = to_bits(size) as i32; }
+                }
+            }
         }
         encoder.set_color_range(color_range);
         encoder.set_colorspace(frame.color_space());
@@ -203,8 +264,29 @@ impl<'a> VideoTranscoder<'a> {
                 (*encoder.as_mut_ptr()).color_trc = (*frame.as_ptr()).color_trc;
             }
             (*encoder.as_mut_ptr()).color_primaries = (*frame.as_ptr()).color_primaries;
+
+            // Frames downloaded from the GPU or converted may not have the color metadata, so it's carried from the source
+            let (enc, dec) = (encoder.as_mut_ptr(), decoder.as_ptr());
+            if (*enc).color_primaries == ffi::AVColorPrimaries::AVCOL_PRI_UNSPECIFIED { (*enc).color_primaries = (*dec).color_primaries; }
+            if (*enc).colorspace      == ffi::AVColorSpace::AVCOL_SPC_UNSPECIFIED     { (*enc).colorspace      = (*dec).colorspace; }
+            if (*enc).color_trc == ffi::AVColorTransferCharacteristic::AVCOL_TRC_UNSPECIFIED && !codec_name.contains("videotoolbox") {
+                (*enc).color_trc = (*dec).color_trc;
+            }
+            if (*enc).chroma_sample_location == ffi::AVChromaLocation::AVCHROMA_LOC_UNSPECIFIED { (*enc).chroma_sample_location = (*dec).chroma_sample_location; }
+
+            // The encoders which support it write the HDR metadata to the bitstream
+            for (i, data) in hdr_side_data {
+                let sd = ffi::av_frame_side_data_new(&mut (*enc).decoded_side_data, &mut (*enc).nb_decoded_side_data, HDR_SIDE_DATA[*i].0, data.len(), 0);
+                if !sd.is_null() {
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), (*sd).data, data.len());
+                }
+            }
         }
 
+        if codec_name == "dnxhd" || codec_name.starts_with("prores") {
+            // The output is always progressive, otherwise the field order is unknown and NLEs may treat it as interlaced
+            unsafe { (*encoder.as_mut_ptr()).field_order = ffi::AVFieldOrder::AV_FIELD_PROGRESSIVE; }
+        }
         if global_header {
             encoder.set_flags(codec::Flags::GLOBAL_HEADER);
         }
@@ -242,6 +324,20 @@ impl<'a> VideoTranscoder<'a> {
 
         let encoder = encoder.open_with(new_options)?;
         ost.set_parameters(&encoder);
+
+        // And the container, if the encoder didn't add it already
+        unsafe {
+            let par = ost.parameters().as_mut_ptr();
+            for (i, data) in hdr_side_data {
+                let typ = HDR_SIDE_DATA[*i].1;
+                if ffi::av_packet_side_data_get((*par).coded_side_data, (*par).nb_coded_side_data, typ).is_null() {
+                    let sd = ffi::av_packet_side_data_new(&mut (*par).coded_side_data, &mut (*par).nb_coded_side_data, typ, data.len(), 0);
+                    if !sd.is_null() {
+                        std::ptr::copy_nonoverlapping(data.as_ptr(), (*sd).data, data.len());
+                    }
+                }
+            }
+        }
         let context = unsafe { codec::context::Context::wrap(ctx_ptr, None) };
 
         if codec_name.contains("hevc") || codec_name.contains("x265") {
@@ -259,8 +355,20 @@ impl<'a> VideoTranscoder<'a> {
 
         let mut frame = frame::Video::empty();
         let mut sw_frame = &mut self.buffers.sw_frame;
+        let counters = self.counters.clone();
 
+        let mut decode_start = Instant::now();
         while decoder.receive_frame(&mut frame).is_ok() {
+            {
+                let mut counters = counters.borrow_mut();
+                counters.decode_time += decode_start.elapsed();
+                counters.frames_decoded += 1;
+            }
+            // Broken hardware decoders usually output garbage right away, so only the first frame is checked
+            if self.gpu_decoding && self.decoded_frames == 0 && unsafe { ((*frame.as_ptr()).flags & ffi::AV_FRAME_FLAG_CORRUPT as i32) != 0 || (*frame.as_ptr()).decode_error_flags != 0 } {
+                return Err(FFmpegError::GPUDecodingFailed(self.gpu_decoder_index));
+            }
+            self.decoded_frames += 1;
             let time_base = self.encoder_params.time_base.unwrap();
 
             if let Some(mut ts) = frame.timestamp() {
@@ -276,12 +384,14 @@ impl<'a> VideoTranscoder<'a> {
 
                     let mut rate_control = RateControl {
                         out_timestamp_us: ts,
+                        duration_us: unsafe { (*frame.as_ptr()).duration }, // The packets are in microseconds
                         ..Default::default()
                     };
 
                     // log::debug!("{:?}", sw_frame.format());
 
                     let mut hw_formats = None;
+                    let transfer_start = Instant::now();
                     let input_frame =
                         if unsafe { !(*frame.as_mut_ptr()).hw_frames_ctx.is_null() } {
                             hw_formats = Some(unsafe { super::ffmpeg_hw::get_transfer_formats_from_gpu(frame.as_mut_ptr()) });
@@ -293,9 +403,12 @@ impl<'a> VideoTranscoder<'a> {
                         } else {
                             &mut frame
                         };
+                    counters.borrow_mut().decode_time += transfer_start.elapsed();
                     
                     log::debug!("{:?}", input_frame.format());
 
+                    let hdr_side_data = if self.encoder.is_none() && !self.decode_only { unsafe { hdr_metadata(input_frame.as_ptr(), decoder.as_ptr()) } } else { Vec::new() };
+
                     if input_frame.format() == format::Pixel::YUVJ420P {
                         input_frame.set_format(format::Pixel::YUV420P);
                         input_frame.set_color_range(util::color::Range::JPEG);
@@ -337,7 +450,11 @@ impl<'a> VideoTranscoder<'a> {
                     // Process frame
                     if self.decode_only || self.processing_order == ProcessingOrder::PreConversion {
                         if let Some(ref mut cb) = self.on_frame_callback {
+                            let process_start = Instant::now();
                             cb(timestamp_us, input_frame, self.buffers.output_frame_pre.as_mut(), &mut self.converter, &mut rate_control)?;
+                            let mut counters = counters.borrow_mut();
+                            counters.process_time += process_start.elapsed();
+                            counters.frames_processed += 1;
                         }
                     }
 
@@ -350,6 +467,8 @@ impl<'a> VideoTranscoder<'a> {
 
                     // Encode output frame
                     if !self.decode_only {
+                        let encode_start = Instant::now();
+                        let mut post_process_time = Duration::ZERO;
                         let in_format = input_frame.format();
                         let mut final_frame = if self.processing_order == ProcessingOrder::PreConversion {
                             self.buffers.output_frame_pre.as_mut().unwrap()
@@ -362,7 +481,9 @@ impl<'a> VideoTranscoder<'a> {
                             if let Some(hw_formats) = &hw_formats {
                                 if !hw_formats.is_empty() {
                                     let dl_format = *hw_formats.first().ok_or(FFmpegError::NoHWTransferFormats)?;
+                                    let is_hlg = unsafe { (*decoder.as_ptr()).color_trc == ffi::AVColorTransferCharacteristic::AVCOL_TRC_ARIB_STD_B67 };
                                     let picked = super::ffmpeg_hw::find_best_matching_codec(dl_format, &self.codec_supported_formats)
+                                        .or_else(|| if is_hlg { super::ffmpeg_hw::find_same_depth_format(dl_format, &self.codec_supported_formats) } else { None })
                                         .unwrap_or_else(|| *self.codec_supported_formats.first().unwrap_or(&format::Pixel::None));
                                     if super::ffmpeg_hw::is_hardware_format(picked.into()) {
                                         hw_upload_format = Some(picked);
@@ -448,7 +569,12 @@ impl<'a> VideoTranscoder<'a> {
                                     self.buffers.output_frame_post = Some(out_frame);
                                 }
 
+                                let process_start = Instant::now();
                                 cb(timestamp_us, final_frame, self.buffers.output_frame_post.as_mut(), &mut self.converter, &mut rate_control)?;
+                                post_process_time = process_start.elapsed();
+                                let mut counters = counters.borrow_mut();
+                                counters.process_time += post_process_time;
+                                counters.frames_processed += 1;
 
                                 final_frame = self.buffers.output_frame_post.as_mut().unwrap();
 
@@ -467,7 +593,7 @@ impl<'a> VideoTranscoder<'a> {
 
                             // let mut stderr_buf  = gag::BufferRedirect::stderr().unwrap();
 
-                            let result = Self::init_encoder(final_frame, &self.encoder_params, decoder, size, bitrate, octx, self.output_index.unwrap_or_default(), &hw_upload_format);
+                            let result = Self::init_encoder(final_frame, &self.encoder_params, decoder, size, bitrate, octx, self.output_index.unwrap_or_default(), &hw_upload_format, &hdr_side_data);
 
                             // let mut output = String::new();
                             // std::io::Read::read_to_string(stderr_buf, &mut output).unwrap();
@@ -531,6 +657,7 @@ impl<'a> VideoTranscoder<'a> {
                             } else {
                                 encoder.send_frame(final_frame)?;
                             }
+                            counters.borrow_mut().frames_encoded += 1;
                             ts += rate_control.repeat_interval;
 
                             // Copy of receive_and_process_encoded_packets
@@ -539,6 +666,11 @@ impl<'a> VideoTranscoder<'a> {
                             let time_base = self.encoder_params.time_base.unwrap();//self.decoder.as_ref().ok_or(FFmpegError::DecoderNotFound)?.time_base();
                             let mut encoded = Packet::empty();
                             while encoder.receive_packet(&mut encoded).is_ok() {
+                                {
+                                    let mut counters = counters.borrow_mut();
+                                    counters.packets_encoded += 1;
+                                    counters.encoded_bytes += encoded.size();
+                                }
                                 encoded.set_stream(self.output_index.unwrap_or_default());
                                 encoded.rescale_ts(time_base, ost_time_base);
                                 if octx.format().name().contains("image") {
@@ -548,6 +680,7 @@ impl<'a> VideoTranscoder<'a> {
                                 }
                             }
                         }
+                        counters.borrow_mut().encode_time += encode_start.elapsed().saturating_sub(post_process_time);
                     }
                     if let Some(last_ts) = frame_ts.last_video {
                         frame_ts.last_duration_video = ts - last_ts;
@@ -559,6 +692,7 @@ impl<'a> VideoTranscoder<'a> {
                     }
                 }
             }
+            decode_start = Instant::now();
         }
 
         // if !self.decode_only && self.encoder.is_some() {
@@ -575,6 +709,11 @@ impl<'a> VideoTranscoder<'a> {
             let time_base = self.encoder_params.time_base.unwrap();//self.decoder.as_ref().ok_or(FFmpegError::DecoderNotFound)?.time_base();
             let mut encoded = Packet::empty();
             while self.encoder.as_mut().ok_or(FFmpegError::EncoderNotFound)?.receive_packet(&mut encoded).is_ok() {
+                {
+                    let mut counters = self.counters.borrow_mut();
+                    counters.packets_encoded += 1;
+                    counters.encoded_bytes += encoded.size();
+                }
                 encoded.set_stream(self.output_index.unwrap_or_default());
                 encoded.rescale_ts(time_base, ost_time_base);
                 if octx.format().name().contains("image") {
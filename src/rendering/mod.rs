@@ -8,18 +8,24 @@ mod audio_resampler;
 pub mod ffmpeg_processor;
 pub mod ffmpeg_hw;
 pub mod render_queue;
+pub mod debug_overlay;
+pub mod render_stats;
+pub mod cfr;
 pub mod mdk_processor;
 pub mod video_processor;
 pub mod zero_copy;
+pub mod thumbnails;
 use zero_copy::*;
 #[cfg(target_os = "android")]
 pub mod ffmpeg_android;
 
 use ffmpeg_video::debug_save_frame;
+use ffmpeg_video::RateControlMode;
 
 pub use self::video_processor::VideoProcessor;
 pub use self::ffmpeg_processor::{ FfmpegProcessor, FFmpegError };
 use render_queue::RenderOptions;
+use render_stats::{ RenderStats, StatsTracker };
 use crate::core::{ StabilizationManager, stabilization::* };
 use ffmpeg_next::{ format::Pixel, frame::Video, codec, Error, ffi };
 use std::cell::RefCell;
@@ -77,6 +83,7 @@ pub fn set_gpu_type_from_name(name: &str) {
 pub fn get_possible_encoders(codec: &str, use_gpu: bool) -> Vec<(&'static str, bool)> { // -> (name, is_gpu)
     if codec.contains("PNG") || codec.contains("png") { return vec![("png", false)]; }
     if codec.contains("EXR") || codec.contains("exr") { return vec![("exr", false)]; }
+    if codec.contains("TIFF") || codec.contains("tiff") { return vec![("tiff", false)]; }
 
     let mut encoders = if use_gpu {
         match codec {
@@ -168,23 +175,209 @@ pub fn get_possible_encoders(codec: &str, use_gpu: bool) -> Vec<(&'static str, b
     encoders
 }
 
+/// ProRes profile of the codec options and whether it's the variant with the alpha channel, eg. "4444 with alpha".
+/// The alpha variants are offered only with a transparent background
+pub fn prores_profile(codec_options: &str) -> (&str, bool) {
+    match codec_options.strip_suffix(" with alpha") {
+        Some(profile) => (profile, true),
+        None => (codec_options, false)
+    }
+}
+
+/// Rough estimate of the speed of the software ProRes encoder in frames per second, shown in the render queue because it's much slower than the hardware ones.
+/// `None` when a hardware encoder will be used
+pub fn prores_ks_fps_estimate(render_options: &RenderOptions) -> Option<f64> {
+    if render_options.codec != "ProRes" { return None; }
+    if render_options.use_gpu && cfg!(any(target_os = "macos", target_os = "ios")) { return None; }
+    // Megapixels per second per CPU core. These are not calibrated: ballpark values for a current desktop CPU, ordered by how much more
+    // work the encoder does for the higher profiles, and scaled only by the core count. The real speed is shown by the render progress
+    let speed = match prores_profile(&render_options.codec_options).0 {
+        "Proxy"  => 40.0,
+        "LT"     => 30.0,
+        "HQ"     => 20.0,
+        "4444"   => 14.0,
+        "4444XQ" => 11.0,
+        _        => 24.0
+    };
+    let megapixels = (render_options.output_width * render_options.output_height) as f64 / 1_000_000.0;
+    if megapixels <= 0.0 { return None; }
+    let cores = std::thread::available_parallelism().map(|x| x.get()).unwrap_or(4) as f64;
+    Some(speed * cores / megapixels)
+}
+
+/// Rate control of the encoder and its options for the `rate_control` of the render options.
+/// Fails when the encoder can't do the selected mode, so it can be checked before the render
+pub fn rate_control_options(encoder: &str, render_options: &RenderOptions, stats_file: &str) -> Result<(RateControlMode, Vec<(&'static str, String)>), String> {
+    let mbps = |x: f64| if x > 0.0 { Some(x) } else { None };
+    let variable = RateControlMode::Variable { max_mbps: mbps(render_options.max_bitrate), buffer_size_mbps: mbps(render_options.buffer_size) };
+    let q = format!("{}", render_options.quality);
+    match render_options.rate_control.as_ref() {
+        "CRF" => {
+            let options = match encoder {
+                "libx264" | "libx265" | "libsvtav1" | "libaom-av1" => vec![("crf", q)],
+                "librav1e" => vec![("qp", q)],
+                x if x.ends_with("_nvenc") => vec![("rc", "vbr".into()), ("cq", q)],
+                x if x.ends_with("_qsv") => vec![("global_quality", q)],
+                x if x.ends_with("_amf") => vec![("rc", "cqp".into()), ("qp_i", q.clone()), ("qp_p", q.clone()), ("qp_b", q)],
+                x if x.ends_with("_vaapi") => vec![("rc_mode", "CQP".into()), ("qp", q)],
+                x if x.ends_with("_videotoolbox") => vec![("qscale", q)],
+                _ => return Err(format!("Constant quality is not supported by the {encoder} encoder."))
+            };
+            Ok((RateControlMode::Quality, options))
+        }
+        "VBR" => {
+            let options = match encoder {
+                x if x.ends_with("_nvenc") => vec![("rc", "vbr".into())],
+                x if x.ends_with("_amf") => vec![("rc", "vbr_peak".into())],
+                x if x.ends_with("_vaapi") => vec![("rc_mode", "VBR".into())],
+                _ => vec![]
+            };
+            Ok((variable, options))
+        }
+        "2-pass" => {
+            if encoder != "libx264" && encoder != "libx265" {
+                return Err(format!("Two-pass encoding is not supported by the {encoder} encoder. Disable GPU encoding to use the software encoder."));
+            }
+            let pass = render_options.pass;
+            if pass != 1 && pass != 2 {
+                // Rendered outside of the render queue, only a single pass
+                return Ok((variable, vec![]));
+            }
+            let options = if encoder == "libx264" {
+                vec![("flags", format!("+pass{pass}")), ("stats", stats_file.to_owned())]
+            } else {
+                // Quoted, because the colon separates the parameters
+                vec![("x265-params", format!("pass={pass}:stats='{stats_file}'"))]
+            };
+            Ok((variable, options))
+        }
+        _ => Ok((RateControlMode::Constant, vec![]))
+    }
+}
+
+/// Fastest reasonable preset of the encoder, for the proxy render
+fn fast_encoder_preset(encoder: &str) -> Option<(&'static str, &'static str)> {
+    match encoder {
+        "libx264" | "libx265" => Some(("preset", "veryfast")),
+        x if x.ends_with("_nvenc") => Some(("preset", "p1")),
+        x if x.ends_with("_qsv") => Some(("preset", "veryfast")),
+        x if x.ends_with("_amf") => Some(("quality", "speed")),
+        _ => None
+    }
+}
+
+/// Checks the rate control with the encoder which will be used for the render
+pub fn validate_rate_control(render_options: &RenderOptions) -> Result<(), String> {
+    if render_options.rate_control.is_empty() || render_options.rate_control == "CBR" { return Ok(()); }
+    if !matches!(render_options.codec.as_ref(), "H.264/AVC" | "H.265/HEVC" | "AV1") { return Ok(()); }
+    let encoder = get_default_encoder(&render_options.codec, render_options.use_gpu);
+    rate_control_options(&encoder, render_options, "").map(|_| ())
+}
+
+/// Whether the output can have the alpha channel of a transparent background
+fn codec_supports_alpha(render_options: &RenderOptions) -> bool {
+    match render_options.codec.as_ref() {
+        "ProRes" => prores_profile(&render_options.codec_options).1,
+        x => get_possible_encoders(x, false).iter().any(|e| matches!(e.0, "png" | "exr" | "tiff"))
+    }
+}
+
+/// A transparent background needs a codec which keeps the alpha channel
+pub fn validate_transparent_background(render_options: &RenderOptions) -> Result<(), String> {
+    if !render_options.transparent_background || codec_supports_alpha(render_options) { return Ok(()); }
+    Err(format!("{} {} can't store the alpha channel of a transparent background. Use ProRes 4444 with alpha, PNG, EXR or TIFF.", render_options.codec, render_options.codec_options).replace("  ", " "))
+}
+
+/// Whether a paused render can continue from the frame where it stopped, otherwise it's rendered again from the start
+pub fn can_resume(stab: &StabilizationManager, render_options: &RenderOptions) -> bool {
+    let is_speed_changed = stab.params.read().video_speed != 1.0 || stab.keyframes.read().is_keyframed(&gyroflow_core::keyframes::KeyframeType::VideoSpeed);
+    cfg!(not(any(target_os = "android", target_os = "ios"))) && !is_speed_changed && render_options.rate_control != "2-pass" && !render_options.constant_frame_rate &&
+        !render_options.export_trims_separately && !render_options.pad_with_black && !render_options.preserve_other_tracks
+}
+
+/// Index of the trim range (0 - 1, empty is the whole clip) with the frame after the `written_frames` of the output, and the timestamp in ms to continue from.
+/// It's between the last written and the next frame, so it's not affected by the rounding of the timestamps. `None` if all the frames are written
+pub fn resume_point(trim_ranges: &[(f64, f64)], written_frames: usize, total_frame_count: usize, duration_ms: f64) -> Option<(usize, f64)> {
+    let whole = [(0.0, 1.0)];
+    let ranges = if trim_ranges.is_empty() { &whole[..] } else { trim_ranges };
+    let mut frame = written_frames;
+    for (i, range) in ranges.iter().enumerate() {
+        let range_frames = ((range.1 - range.0) * total_frame_count as f64).round() as usize;
+        if frame < range_frames {
+            return Some((i, range.0 * duration_ms + (frame as f64 - 0.5) * duration_ms / total_frame_count as f64));
+        }
+        frame -= range_frames;
+    }
+    None
+}
+
+/// The continuation of a paused render is written next to the output and joined with it at the end
+fn resume_part_filename(filename: &str) -> String {
+    match filename.rfind('.') {
+        Some(pos) => format!("{}.part{}", &filename[..pos], &filename[pos..]),
+        None => format!("{filename}.part")
+    }
+}
+
+/// Stats of the first pass, the job ids are only unique in one instance of the app
+pub fn two_pass_stats_file(job_id: u32) -> String {
+    std::env::temp_dir().join(format!("gyroflow_{}_{job_id}.2pass.log", std::process::id())).to_string_lossy().to_string()
+}
+
+/// Number of the first file of an image sequence starting at `trim_start` (0 - 1), counted from 1 like ffmpeg does
+pub fn image_sequence_start_number(trim_start: f64, frame_count: usize) -> u32 {
+    (trim_start * frame_count as f64).round() as u32 + 1
+}
+
+/// Output filename of the trim range when the trim ranges are exported separately
+pub fn trim_range_output_filename(filename: &str, trim_range_count: usize, trim_range_ind: Option<usize>) -> String {
+    let mut filename = filename.to_owned();
+    if trim_range_count > 1 {
+        if let Some(ind) = trim_range_ind {
+            if let Some(pos) = filename.rfind('.') {
+                filename.insert_str(pos, &format!("-{:0>3}", ind + 1));
+            }
+        }
+    }
+    filename
+}
+
+/// Component of the pixels of a plane which is the alpha. `yuvi` are the YUV(A) indices of the components as in `create_planes_proc`, empty for RGB(A)
+fn alpha_component(yuvi: &[usize], count: usize) -> Option<usize> {
+    if yuvi.is_empty() {
+        (count == 4).then_some(3)
+    } else {
+        yuvi.iter().position(|&x| x == 3)
+    }
+}
+
+/// Kernel of one plane of the rendered frames. The interpolation is the one of the render options, the preview's doesn't apply
+fn render_plane(stab: &StabilizationManager, render_options: &RenderOptions) -> Stabilization {
+    let mut plane = Stabilization::default();
+    plane.interpolation = render_options.interpolation.as_str().into();
+    plane.share_wgpu_instances = true;
+    plane.set_device(render_options.device.unwrap_or_else(|| stab.params.read().current_device) as isize);
+    plane
+}
+
 pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &gyroflow_core::InputFile, render_options: &RenderOptions, gpu_decoder_index: i32, trim_range_ind: Option<usize>, cancel_flag: Arc<AtomicBool>, pause_flag: Arc<AtomicBool>, encoder_initialized: F2) -> Result<(), FFmpegError>
-    where F: Fn((f64, usize, usize, bool, bool)) + Send + Sync + Clone,
+    where F: Fn(&RenderStats) + Send + Sync + Clone,
           F2: Fn(String) + Send + Sync + Clone
 {
     log::debug!("ffmpeg_hw::supported_gpu_backends: {:?}", ffmpeg_hw::supported_gpu_backends());
 
     let params = stab.params.read();
-    let org_trim_ranges = params.trim_ranges.clone();
-    let trim_ranges = trim_range_ind.map(|x| vec![params.trim_ranges[x]]).unwrap_or_else(|| params.trim_ranges.clone());
+    // The stabilization is computed for the trim ranges as they are, a proxy render only renders a part of them
+    let org_trim_ranges = render_options.render_trim_ranges(&params.trim_ranges);
+    let trim_ranges = trim_range_ind.map(|x| vec![org_trim_ranges[x]]).unwrap_or_else(|| org_trim_ranges.clone());
     let trim_ratio = if !render_options.pad_with_black && !render_options.preserve_other_tracks {
-        params.get_trim_ratio()
+        render_options.render_trim_ratio(&params.trim_ranges)
     } else {
         1.0
     };
     let total_frame_count = params.frame_count;
     let fps_scale = params.fps_scale;
-    let has_alpha = params.background[3] < 1.0;
+    let has_alpha = render_options.transparent_background || params.background[3] < 1.0 || stab.keyframes.read().is_keyframed(&gyroflow_core::keyframes::KeyframeType::BackgroundAlpha);
 
     let mut pixel_format = render_options.pixel_format.clone();
 
@@ -204,7 +397,7 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
     let render_frame_count = (total_frame_count as f64 * trim_ratio).round() as usize;
 
     // Only use post-conversion processing when background is not opaque
-    let order = if params.background[3] < 1.0 {
+    let order = if has_alpha {
         ffmpeg_video::ProcessingOrder::PostConversion
     } else {
         ffmpeg_video::ProcessingOrder::PreConversion
@@ -222,6 +415,11 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
     if input_file.image_sequence_start > 0 {
         decoder_options.set("start_number", &format!("{}", input_file.image_sequence_start));
     }
+    if render_options.copy_telemetry {
+        // Read also the user data atoms and the XMP, so they can be written to the output
+        decoder_options.set("export_all", "1");
+        decoder_options.set("export_xmp", "1");
+    }
     if cfg!(target_os = "android") {
         decoder_options.set("ndk_codec", "1");
     }
@@ -240,6 +438,12 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
         "PCM (s24be)" => proc.audio_codec = ffmpeg_next::codec::Id::PCM_S24BE,
         _ => { }
     }
+    // MXF can only have little-endian PCM audio
+    if render_options.container == "MXF" && !matches!(proc.audio_codec, ffmpeg_next::codec::Id::PCM_S16LE | ffmpeg_next::codec::Id::PCM_S24LE) {
+        proc.audio_codec = ffmpeg_next::codec::Id::PCM_S24LE;
+    }
+    proc.audio_offset_ms = render_options.audio_offset_ms;
+    proc.audio_bitrate = if render_options.audio_bitrate > 0.0 { Some((render_options.audio_bitrate * 1000.0).round() as usize) } else { None };
 
     let interpolation: Interpolation = render_options.interpolation.as_str().into();
     let ffmpeg_interpolation = match interpolation {
@@ -250,7 +454,9 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
 
     log::debug!("interpolation: {:?}", &interpolation);
     log::debug!("proc.gpu_device: {:?}", &proc.gpu_device);
-    let encoder = ffmpeg_hw::find_working_encoder(&get_possible_encoders(&render_options.codec, render_options.use_gpu), hwaccel_device);
+    // Only the software ProRes encoder writes the alpha channel
+    let use_gpu_encoder = render_options.use_gpu && !(render_options.transparent_background && render_options.codec == "ProRes");
+    let encoder = ffmpeg_hw::find_working_encoder(&get_possible_encoders(&render_options.codec, use_gpu_encoder), hwaccel_device);
     proc.video_codec = Some(encoder.0.to_owned());
     proc.video.gpu_encoding = encoder.1;
     proc.video.ffmpeg_interpolation = ffmpeg_interpolation.bits();
@@ -260,20 +466,30 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
     proc.video.processing_order = order;
     log::debug!("video_codec: {:?}, processing_order: {:?}", &proc.video_codec, proc.video.processing_order);
 
-    if !render_options.pad_with_black && !render_options.preserve_other_tracks && !trim_ranges.is_empty() {
-        proc.ranges_ms = trim_ranges.iter().map(|x| (if x.0 > 0.0 { Some(x.0 * duration_ms) } else { None }, if x.1 < 1.0 { Some(x.1 * duration_ms) } else { None })).collect();
-    }
+    let trim_ranges_ms = if !render_options.pad_with_black && !render_options.preserve_other_tracks {
+        trim_ranges.iter().map(|x| (if x.0 > 0.0 { Some(x.0 * duration_ms) } else { None }, if x.1 < 1.0 { Some(x.1 * duration_ms) } else { None })).collect()
+    } else {
+        Vec::new()
+    };
+    proc.ranges_ms = trim_ranges_ms.clone();
 
     match proc.video_codec.as_deref() {
         Some("prores_ks") | Some("prores_videotoolbox") => {
             let profiles = ["Proxy", "LT", "Standard", "HQ", "4444", "4444XQ"];
-            let pix_fmts = [Pixel::YUV422P10LE, Pixel::YUV422P10LE, Pixel::YUV422P10LE, Pixel::YUV422P10LE, Pixel::YUVA444P10LE, Pixel::YUVA444P10LE];
-            if let Some(profile) = profiles.iter().position(|&x| x == render_options.codec_options) {
+            let (profile_name, with_alpha) = prores_profile(&render_options.codec_options);
+            let pix_444 = if with_alpha && has_alpha { Pixel::YUVA444P10LE } else { Pixel::YUV444P10LE };
+            let pix_fmts = [Pixel::YUV422P10LE, Pixel::YUV422P10LE, Pixel::YUV422P10LE, Pixel::YUV422P10LE, pix_444, pix_444];
+            if let Some(profile) = profiles.iter().position(|&x| x == profile_name) {
                 proc.video.encoder_params.options.set("profile", &format!("{}", profile));
                 if proc.video_codec.as_deref() == Some("prores_ks") {
                     proc.video.encoder_params.pixel_format = Some(pix_fmts[profile]);
+                    proc.video.encoder_params.options.set("alpha_bits", if pix_fmts[profile] == Pixel::YUVA444P10LE { "16" } else { "0" });
                 }
             }
+            if proc.video_codec.as_deref() == Some("prores_ks") {
+                // Same vendor as Apple's encoder, some players and NLEs refuse the files otherwise
+                proc.video.encoder_params.options.set("vendor", "apl0");
+            }
             proc.video.clone_frames = proc.video_codec.as_deref() == Some("prores_ks");
         }
         Some("dnxhd") => {
@@ -301,6 +517,9 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
             proc.video.clone_frames = true;
             proc.video.encoder_params.options.set("compression", "1"); // RLE compression
             proc.video.encoder_params.options.set("gamma", "1.0");
+            if render_options.codec_options.contains("half") {
+                proc.video.encoder_params.options.set("format", "half");
+            }
             proc.video.encoder_params.pixel_format = Some(if has_alpha { Pixel::GBRAPF32LE } else { Pixel::GBRPF32LE });
             /*Decoder options:
                 -layer             <string>     .D.V....... Set the decoding layer (default "")
@@ -325,10 +544,40 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
                     smpte428_1      17           .D.V....... SMPTE ST 428-1
             */
         }
+        Some("tiff") => {
+            proc.video.encoder_params.pixel_format = Some(if has_alpha { Pixel::RGBA64LE } else { Pixel::RGB48LE });
+            proc.video.encoder_params.options.set("compression_algo", "deflate");
+            proc.video.clone_frames = true;
+        }
         _ => { }
     }
 
-    let is_sequence = matches!(proc.video_codec.as_deref(), Some("png") | Some("exr"));
+    let is_sequence = matches!(proc.video_codec.as_deref(), Some("png") | Some("exr") | Some("tiff"));
+
+    // Image sequences are numbered by the frame in the input video, so every trim range has its own numbers
+    let mut resumed_frames = 0;
+    if is_sequence {
+        let trim_start = if render_options.pad_with_black || render_options.preserve_other_tracks { 0.0 } else { trim_ranges.first().map(|x| x.0).unwrap_or_default() };
+        let first_frame = image_sequence_start_number(trim_start, total_frame_count);
+        if render_options.resume {
+            // Skip the frames which are already written
+            let sequence_filename = trim_range_output_filename(&render_options.output_filename, org_trim_ranges.len(), trim_range_ind);
+            while gyroflow_core::filesystem::exists_in_folder(&render_options.output_folder, &sequence_filename.replace("%05d", &format!("{:05}", first_frame as usize + resumed_frames))) {
+                resumed_frames += 1;
+            }
+            if resumed_frames > 0 {
+                let frame = (first_frame - 1) as usize + resumed_frames;
+                // Between the last written and the next frame, so it's not affected by the rounding of the timestamps
+                let start_ms = (frame as f64 - 0.5) * duration_ms / total_frame_count as f64;
+                log::info!("Resuming the image sequence from frame {}", frame + 1);
+                match proc.ranges_ms.first_mut() {
+                    Some(range) => range.0 = Some(start_ms),
+                    None => proc.ranges_ms.push((Some(start_ms), None))
+                }
+            }
+        }
+        proc.image_sequence_start = Some(first_frame + resumed_frames as u32);
+    }
 
     if cfg!(any(target_os = "macos", target_os = "ios")) {
         proc.video.encoder_params.options.set("allow_sw", "1");
@@ -365,7 +614,27 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
 
     proc.video.encoder_params.keyframe_distance_s = render_options.keyframe_distance.max(0.0001);
 
+    if matches!(render_options.codec.as_ref(), "H.264/AVC" | "H.265/HEVC" | "AV1") {
+        let (rate_control, options) = rate_control_options(encoder.0, render_options, &render_options.pass_log).map_err(FFmpegError::EncoderOptions)?;
+        proc.video.encoder_params.rate_control = rate_control;
+        for (key, value) in options {
+            proc.video.encoder_params.options.set(key, &value);
+        }
+    }
+    if render_options.pass == 1 {
+        // Only the video is analyzed in the first pass, and nothing is written
+        proc.audio_codec = codec::Id::None;
+        proc.null_output = true;
+    }
+
     proc.preserve_other_tracks = render_options.preserve_other_tracks && !crate::util::is_insta360(&input_file.url);
+    proc.copy_telemetry = render_options.copy_telemetry && !is_sequence;
+
+    if render_options.proxy {
+        if let Some((key, value)) = fast_encoder_preset(encoder.0) {
+            proc.video.encoder_params.options.set(key, value);
+        }
+    }
 
     for (key, value) in render_options_dict.iter() {
         log::info!("Setting encoder option {}: {}", key, value);
@@ -403,6 +672,12 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
     let is_prores_videotoolbox = proc.video_codec.as_deref() == Some("prores_videotoolbox");
 
     let progress2 = progress.clone();
+    let counters = proc.video.counters.clone();
+    let counters2 = counters.clone();
+    let stats_tracker = Rc::new(RefCell::new(StatsTracker::new(render_frame_count, fps)));
+    stats_tracker.borrow_mut().decoder = proc.gpu_device.clone().unwrap_or_else(|| "software".into());
+    let stats_tracker2 = stats_tracker.clone();
+    let cancel_flag2 = cancel_flag.clone();
     let mut process_frame = 0;
     if let Some(i) = trim_range_ind {
         for x in 0..i {
@@ -410,6 +685,24 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
             process_frame += ((x.1 - x.0) * total_frame_count as f64).round() as usize;
         }
     }
+    process_frame += resumed_frames;
+
+    // Continue the paused render after the frames which are already in the output file
+    let mut paused_frames = 0;
+    if render_options.paused_at_frame > 0 && !is_sequence && can_resume(&stab, render_options) &&
+       gyroflow_core::filesystem::exists_in_folder(&render_options.output_folder, &render_options.output_filename) {
+        if let Some((i, start_ms)) = resume_point(&trim_ranges, render_options.paused_at_frame, total_frame_count, duration_ms) {
+            if proc.ranges_ms.is_empty() {
+                proc.ranges_ms.push((Some(start_ms), None));
+            } else {
+                proc.ranges_ms.drain(..i);
+                proc.ranges_ms[0].0 = Some(start_ms);
+            }
+            paused_frames = render_options.paused_at_frame;
+            log::info!("Resuming the render from frame {}", paused_frames + 1);
+        }
+    }
+    process_frame += paused_frames;
 
     proc.on_encoder_initialized(|enc: &ffmpeg_next::encoder::video::Video| {
         encoder_initialized(enc.codec().map(|x| x.name().to_string()).unwrap_or_default());
@@ -425,7 +718,38 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
         proc.audio_codec = codec::Id::None; // Audio not supported when changing speed
     }
 
+    // The speed change already outputs a constant frame rate
+    let mut cfr_mapper = None;
+    if render_options.constant_frame_rate && !is_speed_changed && !is_sequence {
+        let target_fps = match render_options.constant_fps {
+            x if x > 0.0 => x,
+            _ if duration_ms > 0.0 && total_frame_count > 0 => total_frame_count as f64 * 1000.0 / duration_ms,
+            _ => fps
+        };
+        let rate = fps_to_rational(target_fps);
+        log::info!("Constant frame rate output at {rate}");
+        proc.output_frame_rate = Some(rate);
+        cfr_mapper = Some(cfr::CfrMapper::new(f64::from(rate)));
+    }
+
+    if render_options.audio_normalize && proc.audio_codec != codec::Id::None {
+        // Measured over all the rendered ranges, also when resuming, so the gain is the same in the whole file
+        match proc.measure_loudness(&trim_ranges_ms, &cancel_flag)? {
+            Some(measured) => {
+                log::info!("Measured loudness: {:.1} LUFS, true peak {:.1} dBTP", measured.integrated_lufs, measured.true_peak_db);
+                proc.loudness_normalization = Some(ffmpeg_audio::LoudnessNormalization {
+                    target_lufs: if render_options.audio_target_lufs < 0.0 { render_options.audio_target_lufs } else { -14.0 },
+                    true_peak_db: render_options.audio_true_peak.min(0.0),
+                    limiter: render_options.audio_limiter,
+                    measured
+                });
+            }
+            None => log::warn!("There's no audio to normalize")
+        }
+    }
+
     let render_globals = Rc::new(RefCell::new(zero_copy::RenderGlobals::default()));
+    let mut debug_overlay = render_options.debug_overlay.enabled.then(|| debug_overlay::DebugOverlay::new(render_options.debug_overlay.clone()));
 
     proc.on_frame(move |mut timestamp_us, input_frame, output_frame, converter, rate_control| {
         let fill_with_background = render_options.pad_with_black && !trim_ranges.is_empty() &&
@@ -443,6 +767,7 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
             prev_real_ts = rate_control.out_timestamp_us;
             if ramped_ts < (final_ts as f64 + interval as f64 / 2.0) { // interval/2 because we want frame in the middle of the range, not in the end
                 rate_control.repeat_times = 0; // skip this frame
+                stats_tracker2.borrow_mut().dropped_frames += 1;
                 process_frame += 1;
                 return Ok(());
             } else {
@@ -451,10 +776,23 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
                     // Need to duplicate the frames
                     rate_control.repeat_times = repeat_times.round() as i64;
                     rate_control.repeat_interval = interval;
+                    stats_tracker2.borrow_mut().duplicated_frames += rate_control.repeat_times as usize - 1;
                 }
             }
             rate_control.out_timestamp_us = final_ts;
             final_ts += interval * rate_control.repeat_times;
+        } else if let Some(cfr) = cfr_mapper.as_mut() {
+            // The output timestamp is changed, the stabilization still uses the timestamp of the source frame
+            let (out_timestamp_us, count) = cfr.map(rate_control.out_timestamp_us, rate_control.duration_us);
+            rate_control.repeat_times = count;
+            if count == 0 {
+                stats_tracker2.borrow_mut().dropped_frames += 1;
+                process_frame += 1;
+                return Ok(());
+            }
+            rate_control.out_timestamp_us = out_timestamp_us;
+            rate_control.repeat_interval = cfr.interval_us().round() as i64;
+            stats_tracker2.borrow_mut().duplicated_frames += count as usize - 1;
         }
 
         let output_frame = output_frame.unwrap();
@@ -473,10 +811,7 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
                         (params.size, params.output_size)
                     };
 
-                    let mut plane = Stabilization::default();
-                    plane.interpolation = interpolation;
-                    plane.share_wgpu_instances = true;
-                    plane.set_device(stab.params.read().current_device as isize);
+                    let mut plane = render_plane(&stab, render_options);
 
                     // Workaround for a bug in prores videotoolbox encoder
                     if $in_frame.format() == ffmpeg_next::format::Pixel::NV12 && is_prores_videotoolbox {
@@ -488,12 +823,16 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
 
                     let is_limited_range = $out_frame.color_range() == ffmpeg_next::util::color::Range::MPEG;
                     compute_params.background = <$t as PixelType>::from_rgb_color(compute_params.background, &$yuvi, is_limited_range);
+                    if render_options.transparent_background {
+                        plane.kernel_flags.set(KernelParamsFlags::TRANSPARENT_BACKGROUND, true);
+                        plane.alpha_channel = alpha_component(&$yuvi, <$t as PixelType>::COUNT);
+                    }
 
                     plane.init_size(org_sizes.0, org_sizes.1);
                     plane.set_compute_params(compute_params);
                     let render_globals = render_globals.clone();
                     $planes.push(Box::new(move |timestamp_us: i64, in_frame_data: &mut Video, out_frame_data: &mut Video, plane_index: usize, fill_with_background: bool| {
-                        log::debug!("Processing plane with pixel type: {:?}", stringify!($t));
+                        gyroflow_core::log_throttled!(5000, log::Level::Debug, "Processing plane pixel_type={} plane={plane_index}", stringify!($t));
                         let mut g = render_globals.borrow_mut();
                         let wgpu_format = $t::wgpu_format().map(|x| x.0);
 
@@ -535,7 +874,7 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
             }
 
             if let Some(underlying_format) = zero_copy::map_hardware_format(format, input_frame) {
-                log::debug!("HW frame ({:?}) underlying format: {:?}", format, underlying_format);
+                gyroflow_core::log_throttled!(5000, log::Level::Debug, "HW frame format={:?} underlying_format={:?}", format, underlying_format);
                 format = underlying_format;
             }
             match format {
@@ -639,7 +978,14 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
             for (i, cb) in planes.iter_mut().enumerate() {
                 (*cb)(timestamp_us, frame, out_frame, i, fill_with_background);
             }
-            progress2((process_frame as f64 / render_frame_count as f64, process_frame, render_frame_count, false, false));
+            if let Some(overlay) = debug_overlay.as_mut() {
+                overlay.apply(&stab, timestamp_us as f64 / 1000.0, out_frame);
+            }
+            // Always reported when stopping, the frame is where a paused render continues
+            let stopping = cancel_flag2.load(std::sync::atomic::Ordering::SeqCst);
+            if let Some(stats) = stats_tracker2.borrow_mut().update(process_frame, &counters2.borrow(), stopping) {
+                progress2(&stats);
+            }
         };
 
         match input_frame.format() {
@@ -674,7 +1020,7 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
         Ok(())
     });
 
-    let mut filename = render_options.output_filename.clone();
+    let filename = trim_range_output_filename(&render_options.output_filename, org_trim_ranges.len(), trim_range_ind);
     let folder = &render_options.output_folder;
     if cfg!(not(any(target_os = "android", target_os = "ios"))) && !gyroflow_core::filesystem::exists(folder) {
         let path = gyroflow_core::filesystem::url_to_path(folder);
@@ -682,23 +1028,31 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
             let _ = std::fs::create_dir_all(path);
         }
     }
-    if org_trim_ranges.len() > 1 {
-        if let Some(ind) = trim_range_ind {
-            if let Some(pos) = filename.rfind('.') {
-                filename.insert_str(pos, &format!("-{:0>3}", ind + 1));
-            }
-        }
-    }
 
-    let start_ms = proc.ranges_ms.first().and_then(|x| x.0);
+    let start_ms = if paused_frames > 0 { org_trim_ranges.first().map(|x| x.0 * duration_ms) } else { proc.ranges_ms.first().and_then(|x| x.0) };
     let mut render_filename = filename.clone();
     if cfg!(not(any(target_os = "android", target_os = "ios"))) && !is_sequence {
         render_filename = format!("{filename}.tmp");
     }
-    proc.render(&fs_base, folder, &render_filename, (output_width as u32, output_height as u32), if render_options.bitrate > 0.0 { Some(render_options.bitrate) } else { None }, cancel_flag, pause_flag)?;
+    let part_filename = format!("{}.tmp", resume_part_filename(&filename));
+    proc.render(&fs_base, folder, if paused_frames > 0 { &part_filename } else { &render_filename }, (output_width as u32, output_height as u32), if render_options.bitrate > 0.0 { Some(render_options.bitrate) } else { None }, cancel_flag, pause_flag)?;
 
     drop(proc);
 
+    if render_options.pass == 1 {
+        return Ok(());
+    }
+
+    if paused_frames > 0 {
+        let part_url = gyroflow_core::filesystem::get_file_url(folder, &part_filename, false);
+        ffmpeg_processor::concat_segments(&fs_base,
+            &gyroflow_core::filesystem::get_file_url(folder, &filename, false),
+            &part_url,
+            &gyroflow_core::filesystem::get_file_url(folder, &render_filename, true)
+        )?;
+        let _ = gyroflow_core::filesystem::remove_file(&part_url);
+    }
+
     let output_url = gyroflow_core::filesystem::get_file_url(folder, &filename, false);
 
     if render_filename != filename {
@@ -714,7 +1068,7 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
         let _ = gyroflow_core::filesystem::remove_file(&output_url);
     }
     if trim_range_ind.is_none() || trim_range_ind == Some(org_trim_ranges.len() - 1) {
-        progress((1.0, render_frame_count, render_frame_count, true, false));
+        progress(&stats_tracker.borrow_mut().finished(&counters.borrow()));
     }
 
     crate::util::update_file_times(&output_url, &input_file.url, start_ms);
@@ -725,6 +1079,13 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
         }
     }
 
+    if render_options.pass == 2 {
+        let stats_file = &render_options.pass_log;
+        for suffix in ["", ".mbtree", ".cutree", ".temp", ".mbtree.temp", ".cutree.temp"] {
+            let _ = std::fs::remove_file(format!("{stats_file}{suffix}"));
+        }
+    }
+
     crate::util::report_lens_profile_usage(lens_checksum);
 
     Ok(())
@@ -962,3 +1323,212 @@ pub fn test_decode() {
     let _ = proc.start_decoder_only(vec![(0.0, 1000.0)], Arc::new(AtomicBool::new(false)));
     ::log::debug!("Done in {:.3} ms", _time.elapsed().as_micros() as f64 / 1000.0);
 }*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_pass_options() {
+        let mut options = RenderOptions { rate_control: "2-pass".into(), bitrate: 20.0, ..Default::default() };
+        let stats_file = two_pass_stats_file(7);
+        assert_ne!(stats_file, two_pass_stats_file(8));
+        assert!(stats_file.contains(&std::process::id().to_string()));
+
+        // Outside of the render queue there's only a single pass
+        let (mode, opts) = rate_control_options("libx264", &options, &stats_file).unwrap();
+        assert_eq!(mode, RateControlMode::Variable { max_mbps: None, buffer_size_mbps: None });
+        assert!(opts.is_empty());
+
+        options.pass = 1;
+        let (_, opts) = rate_control_options("libx264", &options, &stats_file).unwrap();
+        assert_eq!(opts, vec![("flags", "+pass1".to_string()), ("stats", stats_file.clone())]);
+        options.pass = 2;
+        let (_, opts) = rate_control_options("libx265", &options, &stats_file).unwrap();
+        assert_eq!(opts, vec![("x265-params", format!("pass=2:stats='{stats_file}'"))]);
+
+        assert!(rate_control_options("h264_nvenc", &options, &stats_file).is_err());
+    }
+
+    #[test]
+    fn prores_alpha() {
+        assert_eq!(prores_profile("4444XQ with alpha"), ("4444XQ", true));
+        assert_eq!(prores_profile("4444"), ("4444", false));
+
+        let options = |codec_options: &str| RenderOptions { codec: "ProRes".into(), codec_options: codec_options.into(), transparent_background: true, ..Default::default() };
+        assert!(validate_transparent_background(&options("4444 with alpha")).is_ok());
+        assert!(validate_transparent_background(&options("4444")).is_err());
+        assert!(validate_transparent_background(&options("HQ")).is_err());
+        assert!(validate_transparent_background(&RenderOptions { transparent_background: false, ..options("HQ") }).is_ok());
+
+        // Same speed estimate with and without the alpha channel
+        let size = |x: RenderOptions| RenderOptions { output_width: 3840, output_height: 2160, use_gpu: false, ..x };
+        assert_eq!(prores_ks_fps_estimate(&size(options("4444 with alpha"))), prores_ks_fps_estimate(&size(options("4444"))));
+    }
+
+    // The frames as the render queue makes them, with the kernels of `render` from a copy of the project
+    #[test]
+    fn preview_quality_doesnt_affect_rendering() {
+        use gyroflow_core::gpu::{ BufferDescription, BufferSource };
+        use gyroflow_core::lens_profile::{ LensProfile, CameraParams, Dimensions };
+        use gyroflow_core::gyro_source::Quat64;
+        use gyroflow_core::preview_quality::PreviewQualityLevel;
+
+        let stab = StabilizationManager::default();
+        *stab.lens.write() = LensProfile {
+            calib_dimension: Dimensions { w: 1920, h: 1080 },
+            fisheye_params: CameraParams {
+                camera_matrix: vec![[1200.0, 0.0, 960.0], [0.0, 1200.0, 540.0], [0.0, 0.0, 1.0]],
+                distortion_coeffs: vec![0.02, -0.01, 0.0, 0.0],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        {
+            let mut params = stab.params.write();
+            params.fps = 30.0;
+            params.frame_count = 300;
+            params.duration_ms = 10000.0;
+        }
+        {
+            let mut gyro = stab.gyro.write();
+            gyro.duration_ms = 10000.0;
+            gyro.quaternions = (0..=2000).map(|i| (i * 5_000, Quat64::from_euler_angles(0.002 * i as f64, (i as f64 * 0.1).sin() * 0.05, 0.0))).collect();
+        }
+        stab.set_size(1920, 1080);
+        stab.set_output_size(1920, 1080);
+        stab.recompute_blocking();
+        stab.stabilization.write().interpolation = Interpolation::Lanczos4;
+
+        let options = RenderOptions { interpolation: "Bicubic".into(), device: Some(-1), ..Default::default() };
+        let render_frame = |timestamp_us: i64| {
+            let (width, height) = (320, 180);
+            let copy = stab.get_cloned();
+            copy.set_render_params((width, height), (width, height));
+
+            let mut plane = render_plane(&copy, &options);
+            assert!(matches!(plane.active_interpolation(), Interpolation::Bicubic));
+            plane.init_size((width, height), (width, height));
+            plane.set_compute_params(ComputeParams::from_manager(&copy));
+
+            let mut input = (0..width * height * 4).map(|i| (((i / 4) % width) ^ ((i / 4) / width)) as u8).collect::<Vec<u8>>();
+            let mut output = vec![0u8; width * height * 4];
+            let mut buffers = Buffers {
+                input:  BufferDescription { size: (width, height, width * 4), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+                output: BufferDescription { size: (width, height, width * 4), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+            };
+            plane.ensure_ready_for_processing::<RGBA8>(timestamp_us, None, &mut buffers);
+            plane.process_pixels::<RGBA8>(timestamp_us, None, &mut buffers, None).unwrap();
+            drop(buffers);
+            output
+        };
+        let reference = [1_000_000, 5_000_000].map(render_frame);
+
+        // Playback too slow for realtime
+        stab.set_preview_playing(true);
+        let levels = (0..100).filter_map(|_| stab.preview_frame_processed(500.0)).collect::<Vec<_>>();
+        assert_eq!(levels, [PreviewQualityLevel::Half, PreviewQualityLevel::Quarter]);
+        assert!(matches!(stab.stabilization.read().active_interpolation(), Interpolation::Bilinear));
+        assert_eq!([1_000_000, 5_000_000].map(render_frame), reference);
+    }
+
+    #[test]
+    fn resume_timestamps() {
+        // 300 frames at 30 fps
+        let frame_ms = 10000.0 / 300.0;
+        let (i, ts) = resume_point(&[], 90, 300, 10000.0).unwrap();
+        assert_eq!(i, 0);
+        assert!((ts - 89.5 * frame_ms).abs() < 1e-9);
+        assert_eq!(resume_point(&[], 300, 300, 10000.0), None);
+
+        // 60 frames in the first range, the next one starts at 6000 ms
+        let ranges = [(0.1, 0.3), (0.6, 0.9)];
+        let (i, ts) = resume_point(&ranges, 59, 300, 10000.0).unwrap();
+        assert_eq!(i, 0);
+        assert!((ts - (1000.0 + 58.5 * frame_ms)).abs() < 1e-9);
+        let (i, ts) = resume_point(&ranges, 70, 300, 10000.0).unwrap();
+        assert_eq!(i, 1);
+        assert!((ts - (6000.0 + 9.5 * frame_ms)).abs() < 1e-9);
+        assert_eq!(resume_point(&ranges, 150, 300, 10000.0), None);
+    }
+
+    // Two segments of a render generated with the ffmpeg cli, like the output of a paused render and its continuation
+    #[test]
+    fn concat_paused_segments() {
+        use ffmpeg_next::Rescale;
+        let dir = std::env::temp_dir().join(format!("gyroflow_concat_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let segment = |name: &str, duration: f64| {
+            let path = dir.join(name);
+            std::process::Command::new("ffmpeg")
+                .args(["-y", "-v", "error", "-f", "lavfi", "-i", &format!("testsrc2=size=320x240:rate=30:duration={duration}"),
+                       "-f", "lavfi", "-i", &format!("sine=duration={duration}"), "-c:v", "libx264", "-g", "15", "-c:a", "aac", "-shortest"])
+                .arg(&path)
+                .status().is_ok_and(|x| x.success()).then_some(path)
+        };
+        let (Some(first), Some(second)) = (segment("first.mp4", 2.0), segment("second.mp4", 1.0)) else {
+            eprintln!("ffmpeg not found, skipping");
+            return;
+        };
+        let output = dir.join("joined.mp4");
+        let url = |x: &std::path::Path| gyroflow_core::filesystem::path_to_url(&x.to_string_lossy());
+        ffmpeg_processor::concat_segments(&gyroflow_core::filesystem::get_engine_base(), &url(&first), &url(&second), &url(&output)).unwrap();
+
+        let mut input = ffmpeg_next::format::input(&output).unwrap();
+        let video_index = input.streams().best(ffmpeg_next::media::Type::Video).unwrap().index();
+        let time_base = input.stream(video_index).unwrap().time_base();
+        let mut last_dts = None;
+        let mut pts = Vec::new();
+        for (stream, packet) in input.packets() {
+            if stream.index() != video_index { continue; }
+            // Monotonic, the second segment is after the end of the first one
+            assert!(last_dts.is_none() || packet.dts() > last_dts, "{:?} after {:?}", packet.dts(), last_dts);
+            last_dts = packet.dts();
+            pts.push(packet.pts().unwrap().rescale(time_base, (1, 1000)));
+        }
+        assert_eq!(pts.len(), 90);
+        pts.sort();
+        for (i, ts) in pts.iter().enumerate() {
+            assert!((*ts as f64 - i as f64 * 1000.0 / 30.0).abs() <= 1.0, "Frame {i} at {ts} ms");
+        }
+        assert!((input.duration() as f64 / 1000.0 - 3000.0).abs() < 100.0, "{}", input.duration());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // The comparison clip through the whole autosync (decoding, optical flow, pose estimation and the offset search), twice
+    #[test]
+    fn deterministic_sync() {
+        let url = gyroflow_core::filesystem::path_to_url(&format!("{}/resources/comparison1.mp4", env!("CARGO_MANIFEST_DIR")));
+        let info = FfmpegProcessor::get_video_info(&url).unwrap();
+
+        // The clip has no motion data, a slow pan with a bit of shake is enough for the search to have a minimum
+        let gyro_path = std::env::temp_dir().join(format!("gyroflow_deterministic_{}.gcsv", std::process::id()));
+        let mut gcsv = String::from("GYROFLOW IMU LOG\nversion,1.3\nid,deterministic_test\norientation,XYZ\ntscale,0.001\ngscale,0.017453292519943295\nascale,1.0\nt,gx,gy,gz,ax,ay,az\n");
+        for i in 0..(info.duration_ms / 5.0) as usize {
+            let t = i as f64 / 200.0;
+            gcsv.push_str(&format!("{},{:.6},{:.6},{:.6},0,0,1\n", i * 5, (t * 11.0).sin() * 8.0, 5.0 + (t * 7.0).cos() * 3.0, (t * 13.0).sin() * 2.0));
+        }
+        std::fs::write(&gyro_path, gcsv).unwrap();
+        let gyro_url = gyroflow_core::filesystem::path_to_url(&gyro_path.to_string_lossy());
+
+        // Pose from the essential matrix (LMEDS, with the OpenCV RNG) and the parallel offset search of the essential matrix method
+        let run = || {
+            let stab = Arc::new(StabilizationManager::default());
+            stab.init_from_video_data(info.duration_ms, info.fps, info.frame_count, (info.width as usize, info.height as usize));
+            stab.input_file.write().url = url.clone();
+            stab.load_gyro_data(&gyro_url, false, &Default::default(), |_| (), Arc::new(AtomicBool::new(false))).unwrap();
+            stab.set_deterministic(true);
+            let sync_settings = serde_json::json!({
+                "max_sync_points": 2, "time_per_syncpoint": 1.0, "search_size": 1.0, "every_nth_frame": 1,
+                "of_method": 2, "offset_method": 0, "pose_method": 0, "auto_sync_points": false
+            });
+            render_queue::RenderQueue::autosync(stab.clone(), sync_settings, |_| (), |e: (String, String)| panic!("{e:?}"), 360);
+            let offsets = stab.gyro.read().get_offsets().iter().map(|(k, v)| (*k, v.to_bits())).collect::<Vec<_>>();
+            offsets
+        };
+        let first = run();
+        assert!(!first.is_empty());
+        assert_eq!(run(), first);
+        let _ = std::fs::remove_file(&gyro_path);
+    }
+}
@@ -31,6 +31,9 @@ pub struct RenderQueueItem {
     pub end_timestamp: u64,
     pub error_string: QString,
     pub processing_progress: f64,
+    pub paused: bool,
+    pub proxy: bool,
+    pub stats: QString, // Summary of `RenderStats` of the running render
 
     frame_times: std::collections::VecDeque<(u64, u64)>,
 
@@ -45,6 +48,7 @@ pub enum JobStatus {
     #[default]
     Queued,
     Rendering,
+    Paused,
     Finished,
     Error
 }
@@ -53,8 +57,18 @@ struct Job {
     render_options: RenderOptions,
     additional_data: String,
     cancel_flag: Arc<AtomicBool>,
+    pause_requested: Arc<AtomicBool>,
     project_data: Option<String>,
-    stab: Arc<StabilizationManager>
+    stab: Arc<StabilizationManager>,
+    state_checksum: Option<core::state_checksum::StateChecksum>, // Of the main project when the job was queued from it, the render is refused if the project changed since
+    last_stats: Option<rendering::render_stats::RenderStats>,
+}
+
+// Source clip of an imported timeline, which is replaced by the jobs of its events once it's loaded
+struct TimelineJob {
+    source: core::timeline_import::TimelineSource,
+    handles: u32,
+    presets: Vec<(String, String)>, // Camera name and the preset file or content
 }
 
 #[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -68,12 +82,21 @@ pub struct RenderMetadata {
 pub struct RenderOptions {
     pub codec: String,
     pub codec_options: String,
+    pub container: String, // "MXF" or "MOV" for DNxHR, empty for the default of the codec
     pub output_folder: String,
     pub output_filename: String,
     pub output_width: usize,
     pub output_height: usize,
     pub input_filename: String,
     pub bitrate: f64,
+    pub rate_control: String, // "CBR", "CRF", "VBR" or "2-pass"
+    pub quality: f64,         // CRF or CQ value of the encoder
+    pub max_bitrate: f64,     // VBR, Mbps
+    pub buffer_size: f64,     // VBR, Mbit
+    #[serde(skip)]
+    pub pass: u8,             // 1 and 2 when rendering the two passes, 0 otherwise
+    #[serde(skip)]
+    pub pass_log: String,     // Stats of the first pass, unique for the job
     pub use_gpu: bool,
     pub audio: bool,
     pub pixel_format: String,
@@ -83,21 +106,119 @@ pub struct RenderOptions {
     pub metadata: RenderMetadata,
     pub keyframe_distance: f64,
     pub preserve_other_tracks: bool,
+    pub copy_telemetry: bool,
     pub pad_with_black: bool,
     pub export_trims_separately: bool,
     pub audio_codec: String,
+    pub audio_offset_ms: f64,
+    pub audio_bitrate: f64,     // kbps, 0 keeps the bitrate of the source up to 320 kbps
+    pub audio_normalize: bool,  // EBU R128 loudness normalization, the audio is re-encoded
+    pub audio_target_lufs: f64,
+    pub audio_true_peak: f64,   // Ceiling of the limiter, dBTP
+    pub audio_limiter: bool,
     pub interpolation: String,
+    pub resume: bool, // Image sequences: keep the already written frames and continue after them
+    pub device: Option<i32>, // Processing device of this job, -1 is the CPU. `None` uses the device selected in the app
+    #[serde(skip)]
+    pub paused_at_frame: usize, // Frames which are already in the output of a paused job
+
+    // Proxy render, a quick preview with the same stabilization. See `with_proxy_overrides`
+    pub proxy: bool,
+    pub proxy_scale: f64,                // Output size relative to the full render, 0.5 when not set
+    pub proxy_range: Option<(f64, f64)>, // Part of the clip (0 - 1) to render, `None` renders the trim ranges
+
+    pub transparent_background: bool, // Alpha 0 outside of the source, needs a codec with the alpha channel
+    pub constant_frame_rate: bool, // Duplicate or drop the frames of a variable frame rate source, the audio is kept as is
+    pub constant_fps: f64,         // 0 is the average frame rate of the source
+
+    // Diagnostic data burned into the video, never saved in the presets
+    pub debug_overlay: super::debug_overlay::DebugOverlayOptions,
 }
 impl RenderOptions {
     pub fn settings_string(&self, fps: f64) -> String {
         let codec_info = match self.codec.as_ref() {
-            "H.264/AVC" | "H.265/HEVC" | "AV1" => format!("{} {:.0} Mbps", self.codec, self.bitrate),
+            "H.264/AVC" | "H.265/HEVC" | "AV1" => match self.rate_control.as_ref() {
+                "CRF"    => format!("{} CRF {}", self.codec, self.quality),
+                "VBR" if self.max_bitrate > 0.0 => format!("{} {:.0} Mbps VBR (max {:.0} Mbps)", self.codec, self.bitrate, self.max_bitrate),
+                "VBR"    => format!("{} {:.0} Mbps VBR", self.codec, self.bitrate),
+                "2-pass" => format!("{} {:.0} Mbps 2-pass", self.codec, self.bitrate),
+                _        => format!("{} {:.0} Mbps", self.codec, self.bitrate)
+            },
+            "DNxHD" if !self.container.is_empty() => format!("{} {}", self.codec_options, self.container),
             "DNxHD" => self.codec_options.clone(),
-            "ProRes" => format!("{} {}", self.codec, self.codec_options),
+            "ProRes" => match super::prores_ks_fps_estimate(self) {
+                Some(encoder_fps) => format!("{} {} (software encoder, rough estimate ~{:.0} fps)", self.codec, self.codec_options, encoder_fps),
+                None => format!("{} {}", self.codec, self.codec_options)
+            },
             _ => self.codec.clone()
         };
 
-        format!("{}x{} {:.3}fps | {}", self.output_width, self.output_height, fps, codec_info)
+        let proxy = if self.proxy { "Proxy | " } else { "" };
+        format!("{}{}x{} {:.3}fps | {}", proxy, self.output_width, self.output_height, fps, codec_info)
+    }
+
+    /// Options of the proxy render: scaled down output, fast constant quality H.264/H.265 and the "_proxy" suffix in the filename.
+    /// Only the resolution and the encoding change, the stabilization is the same as in the full render
+    pub fn with_proxy_overrides(&self) -> Self {
+        let mut ret = self.clone();
+        if !self.proxy { return ret; }
+
+        let scale = if self.proxy_scale > 0.0 { self.proxy_scale.min(1.0) } else { 0.5 };
+        let scaled = |x: usize| ((x as f64 * scale / 2.0).round() as usize * 2).max(2);
+        ret.output_width = scaled(self.output_width);
+        ret.output_height = scaled(self.output_height);
+
+        let keep_codec = matches!(self.codec.as_ref(), "H.264/AVC" | "H.265/HEVC");
+        if !keep_codec {
+            ret.codec = "H.264/AVC".into();
+            ret.codec_options = String::new();
+            ret.container = String::new();
+        }
+        ret.rate_control = "CRF".into();
+        ret.quality = 28.0;
+        if super::validate_rate_control(&ret).is_err() {
+            ret.rate_control = "CBR".into();
+            ret.bitrate = (self.bitrate * scale * scale).max(2.0);
+        }
+        ret.encoder_options = String::new();
+        ret.pixel_format = String::new();
+        ret.audio_codec = "AAC".into();
+        ret.pad_with_black = false;
+        ret.preserve_other_tracks = false;
+        ret.copy_telemetry = false;
+        ret.export_trims_separately = false;
+        ret.resume = false;
+        ret.transparent_background = false;
+
+        let (stem, ext) = match self.output_filename.rfind('.') {
+            Some(pos) => self.output_filename.split_at(pos),
+            None => (self.output_filename.as_str(), "")
+        };
+        let ext = if keep_codec && !ext.is_empty() { ext } else { ".mp4" };
+        ret.output_filename = format!("{}_proxy{}", stem.trim_end_matches("_%05d"), ext);
+        ret
+    }
+
+    /// Trim ranges which are rendered. A proxy render can be limited to a part of the clip,
+    /// and if none of the trim ranges are in that part, the whole part is rendered
+    pub fn render_trim_ranges(&self, trim_ranges: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        let Some((from, to)) = self.proxy_range.filter(|_| self.proxy) else { return trim_ranges.to_vec(); };
+        let (from, to) = (from.max(0.0), to.min(1.0));
+        let mut ret = trim_ranges.iter().map(|x| (x.0.max(from), x.1.min(to))).filter(|x| x.1 > x.0).collect::<Vec<_>>();
+        if ret.is_empty() && to > from {
+            ret.push((from, to));
+        }
+        ret
+    }
+    pub fn render_trim_ratio(&self, trim_ranges: &[(f64, f64)]) -> f64 {
+        let ranges = self.render_trim_ranges(trim_ranges);
+        if ranges.is_empty() { 1.0 } else { ranges.iter().map(|x| x.1 - x.0).sum() }
+    }
+
+    /// Output filename with the number of the first frame, for image sequences. The frames are numbered by the frame in the input video
+    pub fn first_output_filename(&self, trim_ranges: &[(f64, f64)], frame_count: usize) -> String {
+        let trim_start = if self.pad_with_black || self.preserve_other_tracks { 0.0 } else { trim_ranges.first().map(|x| x.0).unwrap_or_default() };
+        self.output_filename.replace("%05d", &format!("{:05}", super::image_sequence_start_number(trim_start, frame_count)))
     }
 
     pub fn get_encoder_options_dict(&self) -> ffmpeg_next::Dictionary {
@@ -124,9 +245,14 @@ impl RenderOptions {
         if let serde_json::Value::Object(obj) = obj {
             if let Some(v) = obj.get("codec")          .and_then(|x| x.as_str())  { self.codec = v.to_string(); }
             if let Some(v) = obj.get("codec_options")  .and_then(|x| x.as_str())  { self.codec_options = v.to_string(); }
+            if let Some(v) = obj.get("container")      .and_then(|x| x.as_str())  { self.container = v.to_string(); }
             if let Some(v) = obj.get("output_width")   .and_then(|x| x.as_u64())  { self.output_width = v as usize; }
             if let Some(v) = obj.get("output_height")  .and_then(|x| x.as_u64())  { self.output_height = v as usize; }
             if let Some(v) = obj.get("bitrate")        .and_then(|x| x.as_f64())  { self.bitrate = v; }
+            if let Some(v) = obj.get("rate_control")   .and_then(|x| x.as_str())  { self.rate_control = v.to_string(); }
+            if let Some(v) = obj.get("quality")        .and_then(|x| x.as_f64())  { self.quality = v; }
+            if let Some(v) = obj.get("max_bitrate")    .and_then(|x| x.as_f64())  { self.max_bitrate = v; }
+            if let Some(v) = obj.get("buffer_size")    .and_then(|x| x.as_f64())  { self.buffer_size = v; }
             if let Some(v) = obj.get("use_gpu")        .and_then(|x| x.as_bool()) { self.use_gpu = v; }
             if let Some(v) = obj.get("audio")          .and_then(|x| x.as_bool()) { self.audio = v; }
             if let Some(v) = obj.get("pixel_format")   .and_then(|x| x.as_str())  { self.pixel_format = v.to_string(); }
@@ -135,14 +261,37 @@ impl RenderOptions {
             if let Some(v) = obj.get("encoder_options")        .and_then(|x| x.as_str())  { self.encoder_options = v.to_string(); }
             if let Some(v) = obj.get("keyframe_distance")      .and_then(|x| x.as_f64())  { self.keyframe_distance = v; }
             if let Some(v) = obj.get("preserve_other_tracks")  .and_then(|x| x.as_bool()) { self.preserve_other_tracks = v; }
+            if let Some(v) = obj.get("copy_telemetry")         .and_then(|x| x.as_bool()) { self.copy_telemetry = v; }
             if let Some(v) = obj.get("pad_with_black")         .and_then(|x| x.as_bool()) { self.pad_with_black = v; }
             if let Some(v) = obj.get("export_trims_separately").and_then(|x| x.as_bool()) { self.export_trims_separately = v; }
             if let Some(v) = obj.get("audio_codec")            .and_then(|x| x.as_str())  { self.audio_codec = v.to_string(); }
+            if let Some(v) = obj.get("audio_offset_ms")        .and_then(|x| x.as_f64())  { self.audio_offset_ms = v; }
+            if let Some(v) = obj.get("audio_bitrate")          .and_then(|x| x.as_f64())  { self.audio_bitrate = v; }
+            if let Some(v) = obj.get("audio_normalize")        .and_then(|x| x.as_bool()) { self.audio_normalize = v; }
+            if let Some(v) = obj.get("audio_target_lufs")      .and_then(|x| x.as_f64())  { self.audio_target_lufs = v; }
+            if let Some(v) = obj.get("audio_true_peak")        .and_then(|x| x.as_f64())  { self.audio_true_peak = v; }
+            if let Some(v) = obj.get("audio_limiter")          .and_then(|x| x.as_bool()) { self.audio_limiter = v; }
             if let Some(v) = obj.get("interpolation")          .and_then(|x| x.as_str())  { self.interpolation = v.to_string(); }
+            if let Some(v) = obj.get("resume")                 .and_then(|x| x.as_bool()) { self.resume = v; }
+            if let Some(v) = obj.get("device")                 .and_then(|x| x.as_i64())  { self.device = Some(v as i32); }
+            if let Some(v) = obj.get("transparent_background") .and_then(|x| x.as_bool()) { self.transparent_background = v; }
+            if let Some(v) = obj.get("constant_frame_rate")    .and_then(|x| x.as_bool()) { self.constant_frame_rate = v; }
+            if let Some(v) = obj.get("constant_fps")           .and_then(|x| x.as_f64())  { self.constant_fps = v; }
+            if let Some(v) = obj.get("proxy")                  .and_then(|x| x.as_bool()) { self.proxy = v; }
+            if let Some(v) = obj.get("proxy_scale")            .and_then(|x| x.as_f64())  { self.proxy_scale = v; }
+            if let Some(v) = obj.get("proxy_range")            .and_then(|x| x.as_array()) {
+                self.proxy_range = match (v.first().and_then(|x| x.as_f64()), v.get(1).and_then(|x| x.as_f64())) {
+                    (Some(from), Some(to)) if to > from => Some((from, to)),
+                    _ => None
+                };
+            }
 
             if let Some(v) = obj.get("metadata").and_then(|x| x.as_object())  {
                 if let Some(s) = v.get("comment").and_then(|x| x.as_str()) { self.metadata.comment = s.to_string(); }
             }
+            if let Some(v) = obj.get("debug_overlay").and_then(|x| serde_json::from_value(x.clone()).ok()) {
+                self.debug_overlay = v;
+            }
 
             // Backwards compatibility
             if let Some(v) = obj.get("output_path").and_then(|x| x.as_str()) {
@@ -183,7 +332,12 @@ pub struct RenderQueue {
 
     render_job: qt_method!(fn(&mut self, job_id: u32)),
     cancel_job: qt_method!(fn(&self, job_id: u32)),
-    reset_job: qt_method!(fn(&self, job_id: u32)),
+    reset_job: qt_method!(fn(&mut self, job_id: u32)),
+    pause_job: qt_method!(fn(&mut self, job_id: u32)),
+    resume_job: qt_method!(fn(&mut self, job_id: u32)),
+    set_job_device: qt_method!(fn(&mut self, job_id: u32, device: i32)),
+    set_job_debug_overlay: qt_method!(fn(&mut self, job_id: u32, enabled: bool)),
+    get_job_debug_overlay: qt_method!(fn(&self, job_id: u32) -> bool),
     get_gyroflow_data: qt_method!(fn(&self, job_id: u32) -> QString),
 
     add_file: qt_method!(fn(&mut self, url: String, gyro_url: String, additional_data: String) -> u32),
@@ -216,6 +370,7 @@ pub struct RenderQueue {
     pub status_changed: qt_signal!(),
 
     pub render_progress: qt_signal!(job_id: u32, progress: f64, current_frame: usize, total_frames: usize, finished: bool, start_time: f64, is_conversion: bool),
+    pub render_stats: qt_signal!(job_id: u32, stats: QString), // `RenderStats` as JSON, a few times per second
     pub encoder_initialized: qt_signal!(job_id: u32, encoder_name: String),
 
     pub convert_format: qt_signal!(job_id: u32, format: QString, supported: QString, candidate: QString),
@@ -231,6 +386,8 @@ pub struct RenderQueue {
     get_active_render_count: qt_method!(fn(&self) -> usize),
 
     apply_to_all: qt_method!(fn(&mut self, data: String, additional_data: String, to_job_id: u32)),
+    import_timeline: qt_method!(fn(&mut self, url: String, options: String, additional_data: String) -> QString),
+    timeline_jobs: HashMap<u32, TimelineJob>,
 
     pause_flag: Arc<AtomicBool>,
 
@@ -253,6 +410,8 @@ pub struct RenderQueue {
     paused_timestamp: Option<u64>,
     start_frame: u64,
 
+    restored_paused_frames: HashMap<u32, usize>,
+
     stabilizer: Arc<StabilizationManager>,
 
     processing_resolution: i32,
@@ -376,32 +535,41 @@ impl RenderQueue {
             if let Some(out) = obj.get("output") {
                 if let Ok(mut render_options) = serde_json::from_value(out.clone()) as serde_json::Result<RenderOptions> {
                     render_options.update_from_json(out);
-                    let project_url = self.stabilizer.input_file.read().project_file_url.clone();
+                    let project_url = self.stabilizer.input_file.read().project_file_url.clone().filter(|_| !render_options.proxy);
                     if let Some(project_url) = project_url {
-                        // Save project file on disk
+                        // Save project file on disk. Not for the proxy render, the project keeps the settings of the full render
                         if let Err(e) = self.stabilizer.export_gyroflow_file(&project_url, core::GyroflowProjectType::WithGyroData, &additional_data) {
                             ::log::warn!("Failed to save project file: {}: {:?}", project_url, e);
                         }
                     }
+                    let state_checksum = self.stabilizer.get_state_checksum();
                     let stab = self.stabilizer.get_cloned();
 
                     // If it's added from main UI, never do the additional autosync
                     if let Some(ref mut obj) = stab.lens.write().sync_settings { obj.as_object_mut().and_then(|x| x.remove("do_autosync")); }
 
                     self.add_internal(job_id, Arc::new(stab), render_options, additional_data, thumbnail_url);
+                    if let Some(job) = self.jobs.get_mut(&job_id) {
+                        job.state_checksum = Some(state_checksum);
+                    }
                 }
             }
         }
         job_id
     }
 
-    pub fn add_internal(&mut self, job_id: u32, stab: Arc<StabilizationManager>, mut render_options: RenderOptions, additional_data: String, thumbnail_url: QString) {
+    pub fn add_internal(&mut self, job_id: u32, stab: Arc<StabilizationManager>, render_options: RenderOptions, additional_data: String, thumbnail_url: QString) {
+        // The queued project has the options as they were set, the proxy overrides are applied again when it's restored
+        let project_options = render_options.clone();
+        let mut render_options = render_options.with_proxy_overrides();
+
         let size = stab.params.read().size;
         stab.set_render_params(size, (render_options.output_width, render_options.output_height));
 
         let params = stab.params.read();
-        let trim_ratio = params.get_trim_ratio();
+        let trim_ratio = render_options.render_trim_ratio(&params.trim_ranges);
         let video_url = stab.input_file.read().url.clone();
+        let short_trim_range = params.trim_range_shorter_than_frame();
 
         let editing = self.jobs.contains_key(&job_id);
 
@@ -411,6 +579,7 @@ impl RenderQueue {
                 itm.output_filename = QString::from(render_options.output_filename.as_str());
                 itm.display_output_path = QString::from(filesystem::display_folder_filename(render_options.output_folder.as_str(), render_options.output_filename.as_str()));
                 itm.export_settings = QString::from(render_options.settings_string(params.fps));
+                itm.proxy = render_options.proxy;
                 itm.thumbnail_url = thumbnail_url;
                 itm.current_frame = 0;
                 itm.total_frames = (params.frame_count as f64 * trim_ratio).ceil() as u64;
@@ -421,6 +590,7 @@ impl RenderQueue {
                 itm.error_string = QString::default();
                 itm.status = JobStatus::Queued;
                 itm.frame_times.clear();
+                itm.stats = QString::default();
             });
         } else {
             let mut q = self.queue.borrow_mut();
@@ -442,27 +612,57 @@ impl RenderQueue {
                 processing_progress: 0.0,
                 error_string: QString::default(),
                 frame_times: Default::default(),
+                paused: false,
+                proxy: render_options.proxy,
+                stats: QString::default(),
                 status: JobStatus::Queued,
             });
         }
         drop(params);
 
-        let project_data = Self::get_gyroflow_data_internal(&stab, &additional_data, &render_options);
+        let project_data = Self::get_gyroflow_data_internal(&stab, &additional_data, &project_options);
 
         render_options.input_filename = filesystem::get_filename(&stab.input_file.read().url);
 
+        if let Some(frames) = self.restored_paused_frames.remove(&job_id) {
+            render_options.paused_at_frame = frames;
+        }
+        let paused_at_frame = render_options.paused_at_frame;
+
+        let validation_error = short_trim_range.map(|i| format!("Trim range {} is shorter than one frame.", i + 1))
+            .or_else(|| rendering::validate_rate_control(&render_options).err())
+            .or_else(|| rendering::validate_transparent_background(&render_options).err());
+
         self.jobs.insert(job_id, Job {
             queue_index: 0,
             render_options,
             additional_data,
             cancel_flag: Default::default(),
+            pause_requested: Default::default(),
             project_data,
-            stab: stab.clone()
+            state_checksum: None,
+            stab: stab.clone(),
+            last_stats: None,
         });
         self.update_queue_indices();
 
         self.queue_changed();
         self.added(job_id);
+
+        if paused_at_frame > 0 {
+            update_model!(self, job_id, itm {
+                itm.current_frame = paused_at_frame as u64;
+                itm.paused = true;
+                itm.status = JobStatus::Paused;
+            });
+        }
+        if let Some(e) = validation_error {
+            update_model!(self, job_id, itm {
+                itm.error_string = QString::from(e.as_str());
+                itm.status = JobStatus::Error;
+            });
+            self.error(job_id, QString::from("An error occured: %1"), QString::from(e), QString::default());
+        }
     }
 
     pub fn get_job_output_folder(&self, job_id: u32) -> QUrl {
@@ -483,6 +683,51 @@ impl RenderQueue {
         }
         QString::default()
     }
+    /// Processing report of the job, with its render settings and the statistics of the last render
+    pub fn get_job_report(&self, job_id: u32) -> Option<core::processing_report::ProcessingReport> {
+        use core::processing_report::{ EncodeReport, RenderReport };
+        let job = self.jobs.get(&job_id)?;
+        let options = &job.render_options;
+        let fps = job.stab.params.read().fps;
+        let mut report = job.stab.processing_report();
+        report.render = Some(RenderReport {
+            output_path: filesystem::display_folder_filename(&options.output_folder, &options.output_filename),
+            codec: options.codec.clone(),
+            width: options.output_width,
+            height: options.output_height,
+            settings: options.settings_string(fps),
+            encode: job.last_stats.as_ref().map(|s| EncodeReport {
+                frames: s.frame,
+                fps: s.fps,
+                decode_fps: s.decode_fps,
+                process_fps: s.process_fps,
+                encode_fps: s.encode_fps,
+                bitrate_kbps: s.bitrate_kbps,
+                dropped_frames: s.dropped_frames,
+                duplicated_frames: s.duplicated_frames,
+            }),
+        });
+        Some(report)
+    }
+    /// Writes the report next to the output file as `<output>.report.json`, and `<output>.report.html` with `html`. Returns the written files
+    pub fn write_job_report(&self, job_id: u32, html: bool) -> Result<Vec<String>, core::GyroflowCoreError> {
+        let (Some(job), Some(report)) = (self.jobs.get(&job_id), self.get_job_report(job_id)) else { return Ok(Vec::new()); };
+        let folder = &job.render_options.output_folder;
+        let filename = &job.render_options.output_filename;
+
+        let mut written = Vec::new();
+        let json_url = filesystem::get_file_url(folder, &filesystem::filename_with_extension(filename, "report.json"), true);
+        filesystem::write(&json_url, report.to_json().as_bytes())?;
+        written.push(json_url);
+        if html {
+            let duration_ms = job.stab.params.read().get_scaled_duration_ms();
+            let graph = job.stab.get_graph_data(0.0, duration_ms, 1000);
+            let html_url = filesystem::get_file_url(folder, &filesystem::filename_with_extension(filename, "report.html"), true);
+            filesystem::write(&html_url, report.to_html(&graph).as_bytes())?;
+            written.push(html_url);
+        }
+        Ok(written)
+    }
     pub fn remove(&mut self, job_id: u32) {
         if let Some(job) = self.jobs.get(&job_id) {
             job.cancel_flag.store(true, SeqCst);
@@ -660,16 +905,67 @@ impl RenderQueue {
             job.cancel_flag.store(true, SeqCst);
         }
     }
-    pub fn reset_job(&self, job_id: u32) {
-        if let Some(job) = self.jobs.get(&job_id) {
+    pub fn reset_job(&mut self, job_id: u32) {
+        if let Some(job) = self.jobs.get_mut(&job_id) {
             job.cancel_flag.store(true, SeqCst);
+            job.pause_requested.store(false, SeqCst);
+            job.render_options.paused_at_frame = 0;
         }
         update_model!(self, job_id, itm {
             itm.error_string = QString::default();
             itm.current_frame = 0;
+            itm.paused = false;
             itm.status = JobStatus::Queued;
         });
     }
+    /// Stops the job after the frames which are being encoded, the output is finalized and the render continues from there when resumed
+    pub fn pause_job(&mut self, job_id: u32) {
+        let mut is_rendering = false;
+        update_model!(self, job_id, itm {
+            is_rendering = itm.status == JobStatus::Rendering;
+            if itm.status == JobStatus::Queued {
+                itm.paused = true;
+                itm.status = JobStatus::Paused;
+            }
+        });
+        if let Some(job) = self.jobs.get(&job_id) {
+            if is_rendering {
+                job.pause_requested.store(true, SeqCst);
+                job.cancel_flag.store(true, SeqCst);
+            }
+        }
+        self.queue_changed();
+    }
+    pub fn resume_job(&mut self, job_id: u32) {
+        let mut was_paused = false;
+        update_model!(self, job_id, itm {
+            was_paused = itm.status == JobStatus::Paused;
+            if was_paused {
+                itm.paused = false;
+                itm.current_frame = 0;
+                itm.start_timestamp = 0;
+                itm.frame_times.clear();
+                itm.stats = QString::default();
+                itm.status = JobStatus::Queued;
+            }
+        });
+        if was_paused && self.status.to_string() == "active" {
+            self.start();
+        }
+    }
+    pub fn set_job_device(&mut self, job_id: u32, device: i32) {
+        if let Some(job) = self.jobs.get_mut(&job_id) {
+            job.render_options.device = Some(device);
+        }
+    }
+    pub fn set_job_debug_overlay(&mut self, job_id: u32, enabled: bool) {
+        if let Some(job) = self.jobs.get_mut(&job_id) {
+            job.render_options.debug_overlay.enabled = enabled;
+        }
+    }
+    pub fn get_job_debug_overlay(&self, job_id: u32) -> bool {
+        self.jobs.get(&job_id).is_some_and(|job| job.render_options.debug_overlay.enabled)
+    }
     pub fn update_status(&mut self) {
         for v in self.queue.borrow().iter() {
             if v.total_frames > 0 && v.status == JobStatus::Rendering {
@@ -687,7 +983,12 @@ impl RenderQueue {
         let mut all = Vec::new();
         for v in self.queue.borrow().iter() {
             if v.total_frames > 0 && v.status != JobStatus::Finished {
-                if let Ok(data) = serde_json::from_str(&self.get_gyroflow_data(v.job_id).to_string()) as serde_json::Result<serde_json::Value> {
+                if let Ok(mut data) = serde_json::from_str(&self.get_gyroflow_data(v.job_id).to_string()) as serde_json::Result<serde_json::Value> {
+                    if let (Some(obj), Some(job)) = (data.as_object_mut(), self.jobs.get(&v.job_id)) {
+                        if v.status == JobStatus::Paused && job.render_options.paused_at_frame > 0 {
+                            obj.insert("paused_at_frame".into(), job.render_options.paused_at_frame.into());
+                        }
+                    }
                     all.push(data);
                 }
             }
@@ -704,7 +1005,8 @@ impl RenderQueue {
         };
         if let Ok(val) = rqv {
             for x in &val {
-                if let Some(project) = x.get("project_file").and_then(|x| x.as_str()) {
+                let paused_at_frame = x.get("paused_at_frame").and_then(|x| x.as_u64()).unwrap_or_default() as usize;
+                let job_id = if let Some(project) = x.get("project_file").and_then(|x| x.as_str()) {
                     #[allow(unused_mut)]
                     let mut project = project.to_string();
                     #[cfg(any(target_os = "macos", target_os = "ios"))]
@@ -712,9 +1014,14 @@ impl RenderQueue {
                         let (resolved, _is_stale) = filesystem::apple::resolve_bookmark(bookmark, None);
                         if !resolved.is_empty() { project = resolved; }
                     }
-                    self.add_file(project, String::new(), additional_data.clone());
+                    self.add_file(project, String::new(), additional_data.clone())
                 } else if let Ok(data) = serde_json::to_string(&x) {
-                    self.add_file(data, String::new(), additional_data.clone());
+                    self.add_file(data, String::new(), additional_data.clone())
+                } else {
+                    continue;
+                };
+                if paused_at_frame > 0 {
+                    self.restored_paused_frames.insert(job_id, paused_at_frame);
                 }
             }
             return !val.is_empty();
@@ -723,7 +1030,7 @@ impl RenderQueue {
     }
 
     fn get_gyroflow_data_internal(stab: &StabilizationManager, additional_data: &str, render_options: &RenderOptions) -> Option<String> {
-        if let Some(url) = stab.input_file.read().project_file_url.as_ref() {
+        if let Some(url) = stab.input_file.read().project_file_url.as_ref().filter(|_| !render_options.proxy) {
             if filesystem::exists(url) {
                 #[cfg(any(target_os = "macos", target_os = "ios"))]
                 {
@@ -804,6 +1111,11 @@ impl RenderQueue {
                         itm.status = JobStatus::Finished;
                     }
                 });
+                if finished {
+                    if let Some(job) = this.jobs.get_mut(&job_id) {
+                        job.render_options.paused_at_frame = 0;
+                    }
+                }
 
                 this.end_timestamp = Self::current_timestamp();
                 this.render_progress(job_id, progress, current_frame, total_frames, finished, start_time as f64, is_conversion);
@@ -824,6 +1136,33 @@ impl RenderQueue {
                     }
                 }
             });
+            let render_stats = util::qt_queued_callback_mut(self, move |this, stats: rendering::render_stats::RenderStats| {
+                if let Some(job) = this.jobs.get_mut(&job_id) {
+                    job.last_stats = Some(stats.clone());
+                }
+                update_model!(this, job_id, itm {
+                    itm.stats = QString::from(stats.progress_line());
+                });
+                this.render_stats(job_id, QString::from(serde_json::to_string(&stats).unwrap_or_default()));
+            });
+            let paused = util::qt_queued_callback_mut(self, move |this, frames: usize| {
+                if let Some(job) = this.jobs.get_mut(&job_id) {
+                    job.render_options.paused_at_frame = frames;
+                }
+                update_model!(this, job_id, itm {
+                    itm.current_frame = frames as u64;
+                    itm.paused = true;
+                    itm.status = JobStatus::Paused;
+                });
+                this.queue_changed();
+
+                if this.get_pending_count() > 0 && this.status == "active".into() {
+                    // Start the next one
+                    this.start();
+                } else {
+                    this.update_status();
+                }
+            });
             let processing = util::qt_queued_callback_mut(self, move |this, progress: f64| {
                 update_model!(this, job_id, itm {
                     itm.processing_progress = progress;
@@ -887,20 +1226,23 @@ impl RenderQueue {
                 }
                 this.update_status();
             });
+            let render_options = job.render_options.clone();
             let params = stab.params.read();
-            let trim_ratio = params.get_trim_ratio();
+            let trim_ratio = render_options.render_trim_ratio(&params.trim_ranges);
             let total_frame_count = params.frame_count;
             drop(params);
             let mut input_file = stab.input_file.read().clone();
             let filename = filesystem::get_filename(&input_file.url);
-            let render_options = job.render_options.clone();
 
             progress((0.0, 0, (total_frame_count as f64 * trim_ratio).round() as usize, false, false));
 
             job.cancel_flag.store(false, SeqCst);
+            job.pause_requested.store(false, SeqCst);
             let cancel_flag = job.cancel_flag.clone();
+            let pause_requested = job.pause_requested.clone();
+            let resumable = rendering::can_resume(&stab, &render_options);
             let pause_flag = self.pause_flag.clone();
-            let export_project  = self.export_project;
+            let export_project  = if render_options.proxy { 0 } else { self.export_project };
             let export_metadata = self.export_metadata.clone();
             let export_stmap    = self.export_stmap.clone();
             let default_suffix = self.default_suffix.to_string();
@@ -913,6 +1255,17 @@ impl RenderQueue {
                 }
             }
 
+            // The job renders the project as it was queued. If the same video is still open and was changed since, the output wouldn't match what's in the main window
+            let changed = job.state_checksum
+                .filter(|_| self.stabilizer.input_file.read().url == input_file.url)
+                .map(|x| x.changed(&self.stabilizer.get_state_checksum()))
+                .unwrap_or_default();
+            if !changed.is_empty() {
+                ::log::warn!("Project of job {job_id} changed since it was queued: {changed:?}");
+                err(("An error occured: %1".to_string(), format!("The project changed since it was added to the queue ({}). Add it again to render the current state.", changed.join(", "))));
+                return;
+            }
+
             core::run_threaded(move || {
                 Self::do_autosync(stab.clone(), processing, err2, proc_height);
 
@@ -1038,38 +1391,90 @@ impl RenderQueue {
                     vec![None]
                 };
                 let original_gpu_decode = stab.gpu_decoding.load(SeqCst);
+                let written_frames = Arc::new(AtomicUsize::new(render_options.paused_at_frame));
                 'ranges: for range in ranges_to_render {
                     if cancel_flag.load(SeqCst) { break; }
-                    let mut i = 0;
-                    loop {  // 循环处理导出队列 
-                        let result = rendering::render(stab.clone(), progress.clone(), &input_file, &render_options, i, range, cancel_flag.clone(), pause_flag.clone(), encoder_initialized.clone());
-                        if let Err(e) = result {
-                            if let rendering::FFmpegError::PixelFormatNotSupported((fmt, supported, candidate)) = e {
-                                let candidate = if let Some(c) = candidate { format!("{c:?}").to_ascii_lowercase().to_string() } else { String::new() };
-                                convert_format((format!("{fmt:?}"), supported.into_iter().map(|v| format!("{:?}", v)).collect::<Vec<String>>().join(","), candidate));
-                                break 'ranges;
+                    // Two-pass encoding renders everything twice, the progress covers both passes
+                    let passes = if render_options.rate_control == "2-pass" { vec![1, 2] } else { vec![0] };
+                    for pass in passes {
+                        if cancel_flag.load(SeqCst) { break 'ranges; }
+                        let mut render_options = render_options.clone();
+                        render_options.pass = pass;
+                        render_options.pass_log = rendering::two_pass_stats_file(job_id);
+                        let progress = progress.clone();
+                        let pause_requested2 = pause_requested.clone();
+                        let written_frames2 = written_frames.clone();
+                        let pass_progress = move |(p, frame, total, finished, is_conversion): (f64, usize, usize, bool, bool)| {
+                            // When pausing, the render stops like it was finished, but the job isn't
+                            let finished = finished && !pause_requested2.load(SeqCst);
+                            if !finished { written_frames2.store(frame + 1, SeqCst); }
+                            if pass == 0 {
+                                progress((p, frame, total, finished, is_conversion));
+                            } else {
+                                let done = (pass - 1) as usize;
+                                progress(((p + done as f64) / 2.0, frame + done * total, total * 2, finished && pass == 2, is_conversion));
                             }
-                            if original_gpu_decode && stab.gpu_decoding.load(SeqCst) && matches!(e, rendering::FFmpegError::GPUDecodingFailed) {
-                                stab.gpu_decoding.store(false, SeqCst);
-                                continue;
+                        };
+                        let simple_progress = rendering::render_stats::simple_progress(pass_progress);
+                        let pause_requested2 = pause_requested.clone();
+                        let render_stats = render_stats.clone();
+                        let pass_progress = move |stats: &rendering::render_stats::RenderStats| {
+                            simple_progress(stats);
+                            let mut stats = stats.clone();
+                            if pass > 0 {
+                                let (total, done) = (stats.total_frames, (pass - 1) as usize);
+                                (stats.frame, stats.total_frames) = (stats.frame + done * total, total * 2);
+                                if pass == 1 && stats.fps > 0.0 {
+                                    // The second pass takes about as long
+                                    stats.eta_s = stats.eta_s.map(|x| x + total as f64 / stats.fps);
+                                }
                             }
-                            if rendered_frames.load(SeqCst) == 0 {
-                                if (0..4).contains(&i) {
-                                    // Try 4 times with different GPU decoders
-                                    i += 1;
-                                    continue;
+                            stats.finished = stats.finished && pass != 1 && !pause_requested2.load(SeqCst);
+                            render_stats(stats);
+                        };
+                        let mut i = 0;
+                        loop {  // 循环处理导出队列 
+                            let result = rendering::render(stab.clone(), pass_progress.clone(), &input_file, &render_options, i, range, cancel_flag.clone(), pause_flag.clone(), encoder_initialized.clone());
+                            if let Err(e) = result {
+                                if let rendering::FFmpegError::PixelFormatNotSupported((fmt, supported, candidate)) = e {
+                                    let candidate = if let Some(c) = candidate { format!("{c:?}").to_ascii_lowercase().to_string() } else { String::new() };
+                                    convert_format((format!("{fmt:?}"), supported.into_iter().map(|v| format!("{:?}", v)).collect::<Vec<String>>().join(","), candidate));
+                                    break 'ranges;
                                 }
-                                if (0..5).contains(&i) {
-                                    // Try without GPU decoder
-                                    i = -1;
-                                    continue;
+                                if let rendering::FFmpegError::GPUDecodingFailed(failed) = e {
+                                    if original_gpu_decode && stab.gpu_decoding.load(SeqCst) {
+                                        if (0..4).contains(&failed) && rendered_frames.load(SeqCst) == 0 {
+                                            // Try the decoder after the one which failed, the ones before it couldn't be opened
+                                            ::log::warn!("Decoder {failed} failed, trying the next one");
+                                            i = failed as i32 + 1;
+                                        } else {
+                                            stab.gpu_decoding.store(false, SeqCst);
+                                        }
+                                        continue;
+                                    }
                                 }
+                                if rendered_frames.load(SeqCst) == 0 {
+                                    if (0..4).contains(&i) {
+                                        // Try 4 times with different GPU decoders
+                                        i += 1;
+                                        continue;
+                                    }
+                                    if (0..5).contains(&i) {
+                                        // Try without GPU decoder
+                                        i = -1;
+                                        continue;
+                                    }
+                                }
+                                err(("An error occured: %1".to_string(), e.to_string()));
+                                break 'ranges;
+                            } else {
+                                // Render ok
+                                break;
                             }
-                            err(("An error occured: %1".to_string(), e.to_string()));
+                        }
+                        if pause_requested.load(SeqCst) {
+                            paused(if resumable && pass == 0 { written_frames.load(SeqCst) } else { 0 });
                             break 'ranges;
-                        } else {
-                            // Render ok
-                            break;
                         }
                     }
                 }
@@ -1089,13 +1494,15 @@ impl RenderQueue {
 
         let mut ext = override_ext.unwrap_or(match render_options.codec.as_ref() {
             "ProRes"        => ".mov",
+            "DNxHD" if render_options.container == "MXF" => ".mxf",
             "DNxHD"         => ".mov",
             "CineForm"      => ".mov",
             "EXR Sequence"  => "_%05d.exr",
             "PNG Sequence"  => "_%05d.png",
+            "TIFF Sequence" => "_%05d.tif",
             _ => ".mp4"
         });
-        if ext == ".mp4" && render_options.preserve_other_tracks {
+        if ext == ".mp4" && (render_options.preserve_other_tracks || render_options.copy_telemetry) {
             ext = ".mov";
         }
         if let Some(pos) = filename.rfind('.') {
@@ -1121,8 +1528,13 @@ impl RenderQueue {
         });
         let is_rendering = self.export_metadata.is_none() && self.export_stmap.is_none();
         let processing_done = util::qt_queued_callback_mut(self, move |this, _: ()| {
+            if let Some(timeline_job) = this.timeline_jobs.remove(&job_id) {
+                this.expand_timeline_job(job_id, timeline_job);
+                return;
+            }
             if let Some(job) = this.jobs.get(&job_id) {
-                if is_rendering && filesystem::exists_in_folder(&job.render_options.output_folder, &job.render_options.output_filename.replace("_%05d", "_00001")) {
+                let first_filename = { let params = job.stab.params.read(); job.render_options.first_output_filename(&params.trim_ranges, params.frame_count) };
+                if is_rendering && !job.render_options.resume && filesystem::exists_in_folder(&job.render_options.output_folder, &first_filename) {
                     let msg = QString::from(format!("file_exists:{}", serde_json::json!({ "filename": job.render_options.output_filename, "folder": job.render_options.output_folder })));
                     update_model!(this, job_id, itm {
                         itm.error_string = msg.clone();
@@ -1165,6 +1577,8 @@ impl RenderQueue {
                             fov:                    params.fov,
                             background:             params.background,
                             adaptive_zoom_window:   params.adaptive_zoom_window,
+                            adaptive_zoom_look_ahead: params.adaptive_zoom_look_ahead,
+                            static_zoom_percentile: params.static_zoom_percentile,
                             lens_correction_amount: params.lens_correction_amount,
                             light_refraction_coefficient: params.light_refraction_coefficient,
                             background_mode:           params.background_mode,
@@ -1179,9 +1593,14 @@ impl RenderQueue {
                             adaptive_zoom_method:      params.adaptive_zoom_method,
                             max_zoom:                  params.max_zoom,
                             max_zoom_iterations:       params.max_zoom_iterations,
+                            limit_to_zoom_budget:      params.limit_to_zoom_budget,
+                            deterministic:             params.deterministic,
+                            rolling_shutter_strength:  params.rolling_shutter_strength,
+                            horizon_compensation:      params.horizon_compensation,
+                            horizon_compensation_fov:  params.horizon_compensation_fov,
                             ..Default::default()
                         })),
-                        input_file: Arc::new(RwLock::new(gyroflow_core::InputFile { url: if is_gf_data { String::new() } else { url.clone() }, project_file_url: None, image_sequence_start: 0, image_sequence_fps: 0.0, preset_name: None, preset_output_size: None })),
+                        input_file: Arc::new(RwLock::new(gyroflow_core::InputFile { url: if is_gf_data { String::new() } else { url.clone() }, project_file_url: None, image_sequence_start: 0, image_sequence_fps: 0.0, preset_name: None, preset_output_size: None, preserved_project_data: None })),
                         lens_profile_db: stabilizer.lens_profile_db.clone(),
                         ..Default::default()
                     };
@@ -1224,13 +1643,26 @@ impl RenderQueue {
                         let fetch_thumb = |video_url: &str, ratio: f64| -> Result<(), rendering::FFmpegError> {
                             let mut fetched = false;
                             if !crate::cli::will_run_in_console() { // Don't fetch thumbs in the CLI
+                                use std::hash::{ Hash, Hasher };
+                                use gyroflow_core::frame_cache::{ self, CacheKind };
+                                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                                video_url.hash(&mut hasher);
+                                let key = hasher.finish();
+                                if let Some(thumb) = frame_cache::get::<String>(CacheKind::Thumbnail, key) {
+                                    thumb_fetched(QString::from(thumb.as_str()));
+                                    return Ok(());
+                                }
                                 let fs_base = filesystem::get_engine_base();
                                 let mut proc = rendering::VideoProcessor::from_file(&fs_base, video_url, false, 0, None)?;
                                 proc.on_frame(move |_timestamp_us, input_frame, _output_frame, converter, _rate_control| {
                                     let sf = converter.scale(input_frame, ffmpeg_next::format::Pixel::RGBA, (50.0 * ratio).round() as u32, 50)?;
 
                                     if !fetched {
-                                        thumb_fetched(util::image_data_to_base64(sf.plane_width(0), sf.plane_height(0), sf.stride(0) as u32, sf.data(0)));
+                                        let thumb = util::image_data_to_base64(sf.plane_width(0), sf.plane_height(0), sf.stride(0) as u32, sf.data(0));
+                                        let thumb_str = thumb.to_string();
+                                        let size = thumb_str.len();
+                                        frame_cache::insert(CacheKind::Thumbnail, key, thumb_str, size);
+                                        thumb_fetched(thumb);
                                         fetched = true;
                                     }
 
@@ -1402,145 +1834,251 @@ impl RenderQueue {
     }
 
     fn do_autosync<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((String, String)) + Send + Sync + Clone + 'static>(stab: Arc<StabilizationManager>, processing_cb: F, err: F2, proc_height: i32) {
-        let (url, duration_ms) = {
-            (stab.input_file.read().url.clone(), stab.params.read().duration_ms)
-        };
-
         let (has_sync_points, has_accurate_timestamps) = {
             let gyro = stab.gyro.read();
             let md = gyro.file_metadata.read();
             (!gyro.get_offsets().is_empty(), md.has_accurate_timestamps)
         };
-        let fps = stab.params.read().fps;
 
         let sync_settings = stab.lens.read().sync_settings.clone().unwrap_or_default();
         if !has_sync_points && !has_accurate_timestamps && sync_settings.get("do_autosync").and_then(|v| v.as_bool()).unwrap_or_default() {
-            // ----------------------------------------------------------------------------
-            // --------------------------------- Autosync ---------------------------------
-            processing_cb(0.01);
-            use gyroflow_core::synchronization::AutosyncProcess;
-            use gyroflow_core::synchronization;
-            use crate::rendering::VideoProcessor;
-            use itertools::Either;
-
-            if let Ok(mut sync_params) = serde_json::from_value(sync_settings) as serde_json::Result<synchronization::SyncParams> {
-                if sync_params.max_sync_points > 0 {
-                    let mut timestamps_fract = stab.get_optimal_sync_points(sync_params.max_sync_points);
-
-                    if timestamps_fract.is_empty() || !sync_params.auto_sync_points {
-                        let chunks = 1.0 / sync_params.max_sync_points as f64;
-                        let start = chunks / 2.0;
-                        timestamps_fract = (0..sync_params.max_sync_points).map(|i| start + (i as f64 * chunks)).collect();
-
-                        if !sync_params.custom_sync_pattern.is_null() {
-                            let v = Self::resolve_syncpoint_pattern(&sync_params.custom_sync_pattern, duration_ms, fps);
-                            timestamps_fract = v.into_iter().filter(|v| *v <= duration_ms).map(|v| v / duration_ms).collect();
-                        }
+            Self::autosync(stab, sync_settings, processing_cb, err, proc_height);
+        }
+    }
+
+    /// Synchronizes `stab` with the `SyncParams` in `sync_settings`, decoding the video at `proc_height`
+    pub fn autosync<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((String, String)) + Send + Sync + Clone + 'static>(stab: Arc<StabilizationManager>, sync_settings: serde_json::Value, processing_cb: F, err: F2, proc_height: i32) {
+        let (url, duration_ms) = {
+            (stab.input_file.read().url.clone(), stab.params.read().duration_ms)
+        };
+        let fps = stab.params.read().fps;
+
+        // ----------------------------------------------------------------------------
+        // --------------------------------- Autosync ---------------------------------
+        processing_cb(0.01);
+        use gyroflow_core::synchronization::AutosyncProcess;
+        use gyroflow_core::synchronization;
+        use crate::rendering::VideoProcessor;
+        use itertools::Either;
+
+        if let Ok(mut sync_params) = serde_json::from_value(sync_settings) as serde_json::Result<synchronization::SyncParams> {
+            if sync_params.max_sync_points > 0 {
+                let mut timestamps_fract = stab.get_optimal_sync_points(sync_params.max_sync_points);
+
+                if timestamps_fract.is_empty() || !sync_params.auto_sync_points {
+                    let chunks = 1.0 / sync_params.max_sync_points as f64;
+                    let start = chunks / 2.0;
+                    timestamps_fract = (0..sync_params.max_sync_points).map(|i| start + (i as f64 * chunks)).collect();
+
+                    if !sync_params.custom_sync_pattern.is_null() {
+                        let v = Self::resolve_syncpoint_pattern(&sync_params.custom_sync_pattern, duration_ms, fps);
+                        timestamps_fract = v.into_iter().filter(|v| *v <= duration_ms).map(|v| v / duration_ms).collect();
                     }
+                }
 
-                    #[cfg(not(any(target_os = "ios", target_os = "android")))]
-                    let _prevent_system_sleep = keep_awake::inhibit_system("Gyroflow", "Autosyncing");
-                    #[cfg(any(target_os = "ios", target_os = "android"))]
-                    let _prevent_system_sleep = keep_awake::inhibit_display("Gyroflow", "Autosyncing");
+                #[cfg(not(any(target_os = "ios", target_os = "android")))]
+                let _prevent_system_sleep = keep_awake::inhibit_system("Gyroflow", "Autosyncing");
+                #[cfg(any(target_os = "ios", target_os = "android"))]
+                let _prevent_system_sleep = keep_awake::inhibit_display("Gyroflow", "Autosyncing");
 
-                    let cancel_flag = Arc::new(AtomicBool::new(false));
-                    sync_params.initial_offset     *= 1000.0; // s to ms
-                    sync_params.time_per_syncpoint *= 1000.0; // s to ms
-                    sync_params.search_size        *= 1000.0; // s to ms
+                let cancel_flag = Arc::new(AtomicBool::new(false));
+                sync_params.initial_offset     *= 1000.0; // s to ms
+                sync_params.time_per_syncpoint *= 1000.0; // s to ms
+                sync_params.search_size        *= 1000.0; // s to ms
 
-                    let every_nth_frame = sync_params.every_nth_frame.max(1);
+                let every_nth_frame = sync_params.every_nth_frame.max(1);
 
-                    let size = stab.params.read().size;
+                let size = stab.params.read().size;
 
-                    if let Ok(mut sync) = AutosyncProcess::from_manager(&stab, &timestamps_fract, sync_params, "synchronize".into(), cancel_flag.clone()) {
-                        let processing_cb2 = processing_cb.clone();
-                        sync.on_progress(move |percent, _ready, _total| {
-                            processing_cb2(percent);
-                        });
-                        let stab2 = stab.clone();
-                        sync.on_finished(move |arg| {
-                            if let Either::Left(offsets) = arg {
-                                let mut gyro = stab2.gyro.write();
-                                gyro.prevent_recompute = true;
-                                for x in offsets {
-                                    ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4})", x.0, x.1, x.2);
-                                    let new_ts = ((x.0 - x.1) * 1000.0) as i64;
-                                    { // Check the offset
-                                        let sync_data = stab2.sync_data.read();
-                                        if !sync_data.rank.is_empty() {
-                                            let index = ((x.0 - x.1) as f64 / (sync_data.ratio * 1000.0)).round() as usize;
-                                            if index < sync_data.rank.len() && sync_data.rank[index] < 20.0 {
-                                                continue;
-                                            }
-                                        }
-                                    }
-                                    // Remove existing offsets within 100ms range
-                                    gyro.remove_offsets_near(new_ts, 100.0);
-                                    gyro.set_offset(new_ts, x.1);
-                                }
-                                gyro.prevent_recompute = false;
-                                gyro.adjust_offsets();
-                                stab2.keyframes.write().update_gyro(&gyro);
-                            }
-                        });
+                if let Ok(mut sync) = AutosyncProcess::from_manager(&stab, &timestamps_fract, sync_params, "synchronize".into(), cancel_flag.clone()) {
+                    let processing_cb2 = processing_cb.clone();
+                    sync.on_progress(move |percent, _ready, _total| {
+                        processing_cb2(percent);
+                    });
+                    let stab2 = stab.clone();
+                    sync.on_finished(move |arg| {
+                        let offsets = match arg {
+                            Either::Left(Ok(offsets)) => Some(offsets),
+                            Either::Left(Err(e)) => {
+                                ::log::warn!("Synchronization failed: {e}");
+                                Some(e.offsets)
+                            },
+                            _ => None
+                        };
+                        if let Some(offsets) = offsets {
+                            stab2.apply_sync_offsets(&offsets);
+                        }
+                    });
 
-                        let (sw, sh) = ((proc_height as f64 * (size.0 as f64 / size.1 as f64)).round() as u32, proc_height as u32);
+                    let (sw, sh) = ((proc_height as f64 * (size.0 as f64 / size.1 as f64)).round() as u32, proc_height as u32);
 
-                        let gpu_decoding = stab.gpu_decoding.load(SeqCst);
+                    let gpu_decoding = stab.gpu_decoding.load(SeqCst);
 
-                        let mut frame_no = 0;
-                        let mut abs_frame_no = 0;
-                        let sync = Arc::new(sync);
+                    let mut frame_no = 0;
+                    let mut abs_frame_no = 0;
+                    let sync = Arc::new(sync);
 
-                        let mut decoder_options = ffmpeg_next::Dictionary::new();
-                        if proc_height > 0 {
-                            decoder_options.set("scale", &format!("{}x{}", (proc_height * 16) / 9, proc_height));
-                        }
-                        ::log::debug!("Decoder options: {:?}", decoder_options);
-
-                        let fs_base = filesystem::get_engine_base();
-                        match VideoProcessor::from_file(&fs_base, &url, gpu_decoding, 0, Some(decoder_options)) {
-                            Ok(mut proc) => {
-                                let err2 = err.clone();
-                                let sync2 = sync.clone();
-                                proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
-                                    if abs_frame_no % every_nth_frame == 0 {
-                                        match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, sw, sh) {
-                                            Ok(small_frame) => {
-                                                let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data(0));
-
-                                                sync2.feed_frame(timestamp_us, frame_no, width, height, stride, pixels);
-                                            },
-                                            Err(e) => {
-                                                err2(("An error occured: %1".to_string(), e.to_string()))
-                                            }
+                    let mut decoder_options = ffmpeg_next::Dictionary::new();
+                    if proc_height > 0 {
+                        decoder_options.set("scale", &format!("{}x{}", (proc_height * 16) / 9, proc_height));
+                    }
+                    ::log::debug!("Decoder options: {:?}", decoder_options);
+
+                    let fs_base = filesystem::get_engine_base();
+                    match VideoProcessor::from_file(&fs_base, &url, gpu_decoding, 0, Some(decoder_options)) {
+                        Ok(mut proc) => {
+                            let err2 = err.clone();
+                            let sync2 = sync.clone();
+                            proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
+                                if abs_frame_no % every_nth_frame == 0 {
+                                    match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, sw, sh) {
+                                        Ok(small_frame) => {
+                                            let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data(0));
+
+                                            sync2.feed_frame(timestamp_us, frame_no, width, height, stride, pixels);
+                                        },
+                                        Err(e) => {
+                                            err2(("An error occured: %1".to_string(), e.to_string()))
                                         }
-                                        frame_no += 1;
                                     }
-                                    abs_frame_no += 1;
-                                    Ok(())
-                                });
-                                if let Err(e) = proc.start_decoder_only(sync.get_ranges(), cancel_flag) {
-                                    err(("An error occured: %1".to_string(), e.to_string()));
+                                    frame_no += 1;
                                 }
-
-                                sync.finished_feeding_frames();
-                            }
-                            Err(error) => {
-                                err(("An error occured: %1".to_string(), error.to_string()));
+                                abs_frame_no += 1;
+                                Ok(())
+                            });
+                            if let Err(e) = proc.start_decoder_only(sync.get_ranges(), cancel_flag) {
+                                err(("An error occured: %1".to_string(), e.to_string()));
                             }
-                        };
-                    } else {
-                        err(("An error occured: %1".to_string(), "Invalid parameters".to_string()));
-                    }
 
-                    stab.recompute_blocking();
+                            sync.finished_feeding_frames();
+                        }
+                        Err(error) => {
+                            err(("An error occured: %1".to_string(), error.to_string()));
+                        }
+                    };
+                } else {
+                    err(("An error occured: %1".to_string(), "Invalid parameters".to_string()));
                 }
+
+                stab.recompute_blocking();
+            }
+        }
+        processing_cb(1.0);
+        // --------------------------------- Autosync ---------------------------------
+        // ----------------------------------------------------------------------------
+    }
+
+    /// Adds a job for every video event of an EDL or OTIO timeline, rendering the used range of the source with handles.
+    /// `options` has the `search_dirs` for the media, the `handles` in frames and the `presets` by camera, eg. `{ "Sony": "sony.gyroflow" }`.
+    /// Returns a JSON report with the events whose media wasn't found
+    pub fn import_timeline(&mut self, url: String, options: String, additional_data: String) -> QString {
+        let options: serde_json::Value = serde_json::from_str(&options).unwrap_or_default();
+        let result = filesystem::read(&url).map_err(core::GyroflowCoreError::from).and_then(|data| {
+            let events = core::timeline_import::parse(&filesystem::get_filename(&url), &String::from_utf8_lossy(&data))?;
+            let timeline_dir = std::path::PathBuf::from(filesystem::url_to_path(&filesystem::get_folder(&url)));
+            let search_dirs = options.get("search_dirs").and_then(|x| x.as_array()).map(|x| {
+                x.iter().filter_map(|x| x.as_str()).map(|x| std::path::PathBuf::from(filesystem::url_to_path(&filesystem::path_to_url(x)))).collect::<Vec<_>>()
+            }).unwrap_or_default();
+            Ok(core::timeline_import::group_by_source(events, |x| core::timeline_import::resolve_media(x, Some(&timeline_dir), &search_dirs)))
+        });
+        let import = match result {
+            Ok(x) => x,
+            Err(e) => {
+                ::log::error!("Failed to import the timeline {url}: {e:?}");
+                return QString::from(serde_json::json!({ "error": format!("{e:?}") }).to_string());
             }
-            processing_cb(1.0);
-            // --------------------------------- Autosync ---------------------------------
-            // ----------------------------------------------------------------------------
+        };
+
+        let handles = options.get("handles").and_then(|x| x.as_u64()).unwrap_or_default() as u32;
+        let presets = options.get("presets").and_then(|x| x.as_object()).map(|x| {
+            x.iter().filter_map(|(k, v)| Some((k.to_lowercase(), v.as_str().map(|x| x.to_owned()).unwrap_or_else(|| v.to_string())))).collect::<Vec<_>>()
+        }).unwrap_or_default();
+        for source in &import.sources {
+            let job_id = self.add_file(filesystem::path_to_url(&source.path), String::new(), additional_data.clone());
+            self.timeline_jobs.insert(job_id, TimelineJob { source: source.clone(), handles, presets: presets.clone() });
+        }
+        for event in &import.unresolved {
+            ::log::warn!("Media of the event {} wasn't found. Reel: {}, clip: {}", event.name, event.reel, event.clip_name);
         }
+        QString::from(serde_json::json!({
+            "sources": import.sources.len(),
+            "events": import.sources.iter().map(|x| x.events.len()).sum::<usize>(),
+            "unresolved": import.unresolved,
+        }).to_string())
+    }
+
+    // The source of a timeline is loaded: applies the preset of its camera and synchronizes it once, then replaces it with a job for every event
+    fn expand_timeline_job(&mut self, job_id: u32, timeline_job: TimelineJob) {
+        let Some(job) = self.jobs.get(&job_id) else { return; };
+        let (stab, render_options, additional_data) = (job.stab.clone(), job.render_options.clone(), job.additional_data.clone());
+        let thumbnail_url = self.queue.borrow().iter().find(|x| x.job_id == job_id).map(|x| x.thumbnail_url.clone()).unwrap_or_default();
+
+        let camera = stab.camera_id.read().as_ref().map(|x| format!("{} {}", x.brand, x.model).to_lowercase()).unwrap_or_default();
+        if let Some((name, preset)) = timeline_job.presets.iter().find(|(name, _)| camera.contains(name.as_str())) {
+            ::log::info!("Applying the preset for {name} to {}", timeline_job.source.path);
+            let data = if preset.trim_start().starts_with('{') { Ok(preset.as_bytes().to_vec()) } else { filesystem::read(&filesystem::path_to_url(preset)) };
+            let mut is_preset = false;
+            if let Err(e) = data.map_err(core::GyroflowCoreError::from).and_then(|data| stab.import_gyroflow_data(&data, true, None, |_|(), Arc::new(AtomicBool::new(false)), &mut is_preset, false)) {
+                ::log::error!("Failed to apply the preset {preset}: {e:?}");
+            }
+        }
+        // Synchronized once for all the events
+        if stab.lens.read().sync_settings.as_ref().and_then(|x| x.get("do_autosync")).is_none() {
+            Self::update_sync_settings(&stab, &serde_json::json!({ "do_autosync": true }));
+        }
+
+        let processing = util::qt_queued_callback_mut(self, move |this, progress: f64| {
+            update_model!(this, job_id, itm { itm.processing_progress = progress; });
+            this.processing_progress(job_id, progress);
+        });
+        let err = util::qt_queued_callback_mut(self, move |this, (msg, arg): (String, String)| {
+            update_model!(this, job_id, itm {
+                itm.error_string = QString::from(arg.clone());
+                itm.status = JobStatus::Error;
+            });
+            this.error(job_id, QString::from(msg), QString::from(arg), QString::default());
+        });
+        let add_events = util::qt_queued_callback_mut(self, move |this, events: Vec<(Arc<StabilizationManager>, RenderOptions)>| {
+            this.remove(job_id);
+            this.jobs_added.remove(&job_id);
+            let events = events.into_iter().map(|x| (fastrand::u32(1..), x)).collect::<Vec<_>>();
+            // Added all at once, so the ones waiting for the whole queue to be processed see them as pending
+            this.jobs_added.extend(events.iter().map(|x| x.0));
+            for (new_job_id, (stab, render_options)) in events {
+                this.add_internal(new_job_id, stab, render_options, additional_data.clone(), thumbnail_url.clone());
+                this.processing_done(new_job_id, false);
+            }
+        });
+        let proc_height = self.processing_resolution;
+        core::run_threaded(move || {
+            Self::do_autosync(stab.clone(), processing, err, proc_height);
+
+            let url = stab.input_file.read().url.clone();
+            let (fps, duration_ms) = { let params = stab.params.read(); (params.fps, params.duration_ms) };
+            let media_start = rendering::VideoProcessor::get_video_info(&url).ok()
+                .and_then(|x| x.timecode)
+                .and_then(|x| core::timeline_import::SourceTime::parse_timecode(&x));
+            let filename = filesystem::get_filename(&url);
+            let stem = filename.rsplit_once('.').map(|x| x.0).unwrap_or(&filename);
+
+            let mut events = Vec::new();
+            for event in &timeline_job.source.events {
+                let (from, to) = event.source_range_ms(fps, media_start, timeline_job.handles, duration_ms);
+                if to <= from || duration_ms <= 0.0 {
+                    ::log::warn!("Event {} is outside of {}", event.name, timeline_job.source.path);
+                    continue;
+                }
+                let event_stab = stab.get_cloned();
+                if let Some(ref mut obj) = event_stab.lens.write().sync_settings { obj.as_object_mut().and_then(|x| x.remove("do_autosync")); }
+                event_stab.set_trim_ranges(vec![(from / duration_ms, to / duration_ms)]);
+                event_stab.recompute_blocking();
+
+                let mut render_options = render_options.clone();
+                render_options.output_filename = core::timeline_import::event_output_filename(&render_options.output_filename, stem, &event.name);
+                events.push((Arc::new(event_stab), render_options));
+            }
+            add_events(events);
+        });
     }
 
     pub fn apply_to_all(&mut self, data: String, additional_data: String, to_job_id: u32) {
@@ -1549,7 +2087,10 @@ impl RenderQueue {
         let mut new_output_options = None;
         if let Ok(obj) = &data_parsed {
             if let Some(output) = obj.get("output") {
-                new_output_options = Some(output.clone());
+                let mut output = output.clone();
+                // The debug overlay is only enabled per job, a preset never turns it on
+                if let Some(output) = output.as_object_mut() { output.remove("debug_overlay"); }
+                new_output_options = Some(output);
             }
         }
         let processing_done = util::qt_queued_callback_mut(self, |this, job_id: u32| {
@@ -1580,7 +2121,8 @@ impl RenderQueue {
                         }
                     }
                     let job_id = *job_id;
-                    if let Some(ref new_output_options) = new_output_options {
+                    // The proxy render keeps its own output settings
+                    if let Some(new_output_options) = new_output_options.as_ref().filter(|_| !job.render_options.proxy) {
                         let override_ext = new_output_options.get("output_extension").and_then(|x| x.as_str());
                         job.render_options.update_from_json(new_output_options);
                         job.render_options.output_folder = Self::get_output_folder(&itm.input_file.to_string(), &job.render_options.output_folder);
@@ -1590,7 +2132,8 @@ impl RenderQueue {
                         itm.output_folder   = QString::from(job.render_options.output_folder.as_str());
                         itm.display_output_path = QString::from(filesystem::display_folder_filename(job.render_options.output_folder.as_str(), job.render_options.output_filename.as_str()));
                         job.project_data = Self::get_gyroflow_data_internal(&job.stab, &job.additional_data, &job.render_options);
-                        if filesystem::exists_in_folder(&job.render_options.output_folder, &job.render_options.output_filename.replace("_%05d", "_00001")) {
+                        let first_filename = { let params = job.stab.params.read(); job.render_options.first_output_filename(&params.trim_ranges, params.frame_count) };
+                        if !job.render_options.resume && filesystem::exists_in_folder(&job.render_options.output_folder, &first_filename) {
                             let msg = QString::from(format!("file_exists:{}", serde_json::json!({ "filename": job.render_options.output_filename, "folder": job.render_options.output_folder })));
                             itm.error_string = msg.clone();
                             itm.status = JobStatus::Error;
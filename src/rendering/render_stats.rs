@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Statistics of a running render, reported with the progress a few times per second.
+// Decoding, processing and encoding run one after another on the render thread, so the fps of a stage is the frames
+// per second of the time spent in it, and the slowest stage is the bottleneck. The decoder and the encoder keep frames
+// on their own threads, which are the queue depths. The ETA is from an exponentially smoothed throughput.
+
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+const ROLLING_WINDOW: Duration = Duration::from_secs(5);
+const THROUGHPUT_SMOOTHING: f64 = 0.2; // Weight of the latest throughput in the ETA
+
+/// Running totals, updated by the pipeline as it goes
+#[derive(Default, Clone, Copy, Debug)]
+pub struct StageCounters {
+    pub decode_time: Duration,
+    pub process_time: Duration,
+    pub encode_time: Duration, // Including the pixel format conversion and the upload to the encoder's device
+    pub packets_decoded: usize, // Sent to the decoder
+    pub frames_decoded: usize,
+    pub frames_processed: usize,
+    pub frames_encoded: usize, // Sent to the encoder
+    pub packets_encoded: usize,
+    pub encoded_bytes: usize,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RenderStats {
+    pub frame: usize,
+    pub total_frames: usize,
+    pub decode_fps: f64,
+    pub process_fps: f64,
+    pub encode_fps: f64,
+    pub fps: f64,             // Smoothed throughput of the whole render
+    pub bitrate_kbps: f64,    // Encoded video over the last few seconds
+    pub decoder_queue: usize, // Packets in the decoder without a frame yet
+    pub encoder_queue: usize, // Frames in the encoder without a packet yet
+    pub dropped_frames: usize,    // Skipped by a speed change
+    pub duplicated_frames: usize, // Repeated by a speed change
+    pub eta_s: Option<f64>,
+    pub decoder: String, // Hardware decoder of this render, "software" when it's not accelerated
+    pub finished: bool,
+    pub is_conversion: bool,
+}
+
+impl RenderStats {
+    pub fn progress(&self) -> f64 {
+        if self.finished { return 1.0; }
+        if self.total_frames > 0 { self.frame as f64 / self.total_frames as f64 } else { 0.0 }
+    }
+
+    /// The slowest stage, `None` until all of them ran
+    pub fn bottleneck(&self) -> Option<&'static str> {
+        if self.decode_fps <= 0.0 || self.process_fps <= 0.0 || self.encode_fps <= 0.0 { return None; }
+        [("decode", self.decode_fps), ("process", self.process_fps), ("encode", self.encode_fps)]
+            .into_iter()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|x| x.0)
+    }
+
+    /// (progress, frame, total frames, finished, is conversion), as reported by the simple progress callback
+    pub fn as_simple(&self) -> (f64, usize, usize, bool, bool) {
+        (self.progress(), self.frame, self.total_frames, self.finished, self.is_conversion)
+    }
+
+    /// One line summary without the frame numbers, e.g. `42.1 fps (decode 180, process 95, encode 42.1) 48.3 Mbps, queues 4/9, ETA 01:23`
+    pub fn progress_line(&self) -> String {
+        let mut ret = format!("{:.1} fps", self.fps);
+        if self.decode_fps > 0.0 || self.process_fps > 0.0 || self.encode_fps > 0.0 {
+            let stage = |name: &str, fps: f64| if self.bottleneck() == Some(name) { format!("{name} {fps:.1}*") } else { format!("{name} {fps:.0}") };
+            ret.push_str(&format!(" ({}, {}, {})", stage("decode", self.decode_fps), stage("process", self.process_fps), stage("encode", self.encode_fps)));
+        }
+        if self.bitrate_kbps > 0.0 {
+            ret.push_str(&if self.bitrate_kbps >= 1000.0 { format!(" {:.1} Mbps", self.bitrate_kbps / 1000.0) } else { format!(" {:.0} kbps", self.bitrate_kbps) });
+        }
+        ret.push_str(&format!(", queues {}/{}", self.decoder_queue, self.encoder_queue));
+        if !self.decoder.is_empty() {
+            ret.push_str(&format!(", {} decoder", self.decoder));
+        }
+        if self.dropped_frames > 0 || self.duplicated_frames > 0 {
+            ret.push_str(&format!(", dropped {}, duplicated {}", self.dropped_frames, self.duplicated_frames));
+        }
+        if let Some(eta) = self.eta_s {
+            let eta = eta.round() as u64;
+            if eta >= 3600 {
+                ret.push_str(&format!(", ETA {}:{:02}:{:02}", eta / 3600, (eta / 60) % 60, eta % 60));
+            } else {
+                ret.push_str(&format!(", ETA {:02}:{:02}", eta / 60, eta % 60));
+            }
+        }
+        ret
+    }
+}
+
+/// Adapter for the callers which only need the progress fraction and the frame numbers
+pub fn simple_progress<F>(cb: F) -> impl Fn(&RenderStats) + Send + Sync + Clone where F: Fn((f64, usize, usize, bool, bool)) + Send + Sync + Clone {
+    move |stats: &RenderStats| cb(stats.as_simple())
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    time: Instant,
+    frame: usize,
+    counters: StageCounters,
+}
+
+pub struct StatsTracker {
+    total_frames: usize,
+    output_fps: f64,
+    last_report: Option<Instant>,
+    history: VecDeque<Sample>,
+    smoothed_fps: f64,
+    pub dropped_frames: usize,
+    pub duplicated_frames: usize,
+    pub decoder: String,
+}
+
+impl StatsTracker {
+    pub fn new(total_frames: usize, output_fps: f64) -> Self {
+        Self { total_frames, output_fps, last_report: None, history: VecDeque::new(), smoothed_fps: 0.0, dropped_frames: 0, duplicated_frames: 0, decoder: String::new() }
+    }
+
+    /// Stats at `frame`, or `None` if the previous ones were reported less than `REPORT_INTERVAL` ago, unless `force` is set
+    pub fn update(&mut self, frame: usize, counters: &StageCounters, force: bool) -> Option<RenderStats> {
+        let now = Instant::now();
+        if !force && self.last_report.is_some_and(|x| now - x < REPORT_INTERVAL) { return None; }
+        self.last_report = Some(now);
+
+        self.history.push_back(Sample { time: now, frame, counters: *counters });
+        // Keep one sample older than the window, so the window is always covered
+        while self.history.len() > 2 && now - self.history[1].time > ROLLING_WINDOW {
+            self.history.pop_front();
+        }
+        let first = self.history[0];
+        let c = counters;
+        let c0 = &first.counters;
+        let per_second = |count: usize, time: Duration| if time > Duration::ZERO { count as f64 / time.as_secs_f64() } else { 0.0 };
+
+        let elapsed = now - first.time;
+        if elapsed > Duration::ZERO {
+            let throughput = per_second(frame.saturating_sub(first.frame), elapsed);
+            self.smoothed_fps = if self.smoothed_fps > 0.0 { self.smoothed_fps + THROUGHPUT_SMOOTHING * (throughput - self.smoothed_fps) } else { throughput };
+        }
+
+        let packets = c.packets_encoded.saturating_sub(c0.packets_encoded);
+        let bitrate_kbps = if packets > 0 && self.output_fps > 0.0 {
+            c.encoded_bytes.saturating_sub(c0.encoded_bytes) as f64 * 8.0 / (packets as f64 / self.output_fps) / 1000.0
+        } else {
+            0.0
+        };
+
+        Some(RenderStats {
+            frame,
+            total_frames: self.total_frames,
+            decode_fps:  per_second(c.frames_decoded.saturating_sub(c0.frames_decoded),     c.decode_time.saturating_sub(c0.decode_time)),
+            process_fps: per_second(c.frames_processed.saturating_sub(c0.frames_processed), c.process_time.saturating_sub(c0.process_time)),
+            encode_fps:  per_second(c.frames_encoded.saturating_sub(c0.frames_encoded),     c.encode_time.saturating_sub(c0.encode_time)),
+            fps: self.smoothed_fps,
+            bitrate_kbps,
+            decoder_queue: c.packets_decoded.saturating_sub(c.frames_decoded),
+            encoder_queue: c.frames_encoded.saturating_sub(c.packets_encoded),
+            dropped_frames: self.dropped_frames,
+            duplicated_frames: self.duplicated_frames,
+            eta_s: (self.smoothed_fps > 0.0).then(|| self.total_frames.saturating_sub(frame) as f64 / self.smoothed_fps),
+            decoder: self.decoder.clone(),
+            finished: false,
+            is_conversion: false,
+        })
+    }
+
+    pub fn finished(&mut self, counters: &StageCounters) -> RenderStats {
+        let mut stats = self.update(self.total_frames, counters, true).unwrap_or_default();
+        stats.finished = true;
+        stats.eta_s = Some(0.0);
+        stats
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Clip thumbnails and timeline filmstrips, without the preview pipeline.
+// Every call opens its own decoder and works on a copy of the project, so it can run concurrently with a render.
+
+use super::{ FfmpegProcessor, FFmpegError, VideoProcessor };
+use gyroflow_core::{ StabilizationManager, filesystem, frame_cache::{ self, CacheKind }, state_checksum::StateChecksum };
+use gyroflow_core::gpu::{ Buffers, BufferDescription, BufferSource };
+use gyroflow_core::stabilization::RGBA8;
+use ffmpeg_next::format::Pixel;
+use image::RgbaImage;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::hash::{ Hash, Hasher };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering::SeqCst };
+
+/// Decodes the frames at `timestamps_us` and downsizes them to fit in `max_size` x `max_size`, in the order of `timestamps_us`.
+/// With `stabilized` set, the frames go through the stabilization kernel with the current state of that project
+/// and the kernel does the downsizing, on the GPU when available.
+/// Frames which couldn't be decoded (eg. past the end of the clip) are left out.
+pub fn generate_thumbnails(url: &str, timestamps_us: &[i64], max_size: u32, stabilized: Option<&StabilizationManager>) -> Result<Vec<RgbaImage>, FFmpegError> {
+    let info = FfmpegProcessor::get_video_info(url)?;
+    let frame_duration_us = (1_000_000.0 / info.fps.max(1.0)) as i64;
+    let last_frame_us = ((info.duration_ms * 1000.0) as i64 - frame_duration_us).max(0);
+    let max_size = max_size.max(2);
+
+    let base_key = cache_key(url, max_size, stabilized.map(|stab| stab.get_state_checksum()));
+    let key_at = |ts: i64| -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (base_key, ts).hash(&mut hasher);
+        hasher.finish()
+    };
+
+    let targets: Vec<i64> = timestamps_us.iter().map(|x| (*x).clamp(0, last_frame_us)).collect();
+    let mut result: BTreeMap<i64, Arc<RgbaImage>> = targets.iter().filter_map(|&ts| Some((ts, frame_cache::get::<RgbaImage>(CacheKind::Thumbnail, key_at(ts))?))).collect();
+    let mut pending: Vec<i64> = targets.iter().copied().filter(|ts| !result.contains_key(ts)).collect();
+    pending.sort();
+    pending.dedup();
+
+    if !pending.is_empty() {
+        let (in_size, out_size) = match stabilized {
+            Some(stab) => {
+                let params = stab.params.read();
+                (params.size, fit_size(params.output_size, max_size))
+            },
+            None => {
+                let size = (info.width as usize, info.height as usize);
+                (size, fit_size(size, max_size))
+            }
+        };
+        let stab = stabilized.map(|stab| {
+            let copy = stab.get_cloned();
+            copy.set_device(stab.params.read().current_device);
+            copy.set_render_params(in_size, out_size);
+            Arc::new(copy)
+        });
+
+        // One range per frame, merged when they overlap. Seeking lands on the previous keyframe and decodes up to the requested frame
+        let mut ranges: Vec<(f64, f64)> = Vec::new();
+        for &ts in &pending {
+            let range = (ts as f64 / 1000.0, (ts + frame_duration_us * 2) as f64 / 1000.0);
+            match ranges.last_mut() {
+                Some(last) if range.0 <= last.1 => last.1 = range.1,
+                _ => ranges.push(range)
+            }
+        }
+
+        let decoded = Arc::new(Mutex::new(BTreeMap::<i64, RgbaImage>::new()));
+        let done = Arc::new(AtomicBool::new(false));
+        let fs_base = filesystem::get_engine_base();
+        let mut proc = VideoProcessor::from_file(&fs_base, url, false, 0, None)?;
+        {
+            let (decoded, done, pending) = (decoded.clone(), done.clone(), pending.clone());
+            let mut next = 0;
+            proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
+                while next < pending.len() && timestamp_us + frame_duration_us / 2 >= pending[next] {
+                    let target = pending[next];
+                    next += 1;
+                    // Already past this one, the frame for it wasn't decoded
+                    if timestamp_us - frame_duration_us / 2 > target { continue; }
+
+                    let image = match &stab {
+                        Some(stab) => {
+                            let mut frame = converter.scale(input_frame, Pixel::RGBA, in_size.0 as u32, in_size.1 as u32)?;
+                            let stride = frame.stride(0);
+                            let mut output = vec![0u8; out_size.0 * out_size.1 * 4];
+                            let mut buffers = Buffers {
+                                input:  BufferDescription { size: (in_size.0, in_size.1, stride), data: BufferSource::Cpu { buffer: frame.data_mut(0) }, ..Default::default() },
+                                output: BufferDescription { size: (out_size.0, out_size.1, out_size.0 * 4), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+                            };
+                            if let Err(e) = stab.process_pixels::<RGBA8>(timestamp_us, None, &mut buffers) {
+                                ::log::warn!("Failed to stabilize the thumbnail timestamp_us={timestamp_us} error={e:?}");
+                                continue;
+                            }
+                            RgbaImage::from_raw(out_size.0 as u32, out_size.1 as u32, output)
+                        },
+                        None => {
+                            let frame = converter.scale(input_frame, Pixel::RGBA, out_size.0 as u32, out_size.1 as u32)?;
+                            let (width, height, stride) = (frame.plane_width(0) as usize, frame.plane_height(0) as usize, frame.stride(0));
+                            let data = frame.data(0);
+                            let pixels = (0..height).flat_map(|y| &data[y * stride..y * stride + width * 4]).copied().collect::<Vec<u8>>();
+                            RgbaImage::from_raw(width as u32, height as u32, pixels)
+                        }
+                    };
+                    if let Some(image) = image {
+                        decoded.lock().insert(target, image);
+                    }
+                }
+                if next >= pending.len() {
+                    done.store(true, SeqCst);
+                }
+                Ok(())
+            });
+        }
+        proc.start_decoder_only(ranges, done)?;
+
+        for (ts, image) in std::mem::take(&mut *decoded.lock()) {
+            let size = image.as_raw().len();
+            frame_cache::insert(CacheKind::Thumbnail, key_at(ts), image.clone(), size);
+            result.insert(ts, Arc::new(image));
+        }
+    }
+
+    Ok(targets.iter().filter_map(|ts| {
+        let image = result.get(ts).map(|x| x.as_ref().clone());
+        if image.is_none() { ::log::warn!("No frame for the thumbnail timestamp_us={ts}"); }
+        image
+    }).collect())
+}
+
+/// One thumbnail every `interval_s` seconds, stitched horizontally into a single image
+pub fn generate_filmstrip(url: &str, interval_s: f64, max_size: u32, stabilized: Option<&StabilizationManager>) -> Result<RgbaImage, FFmpegError> {
+    let info = FfmpegProcessor::get_video_info(url)?;
+    let interval_us = (interval_s.max(0.01) * 1_000_000.0) as i64;
+    let timestamps = (0..).map(|i| i * interval_us).take_while(|ts| (*ts as f64) < info.duration_ms * 1000.0).collect::<Vec<_>>();
+    let thumbs = generate_thumbnails(url, &timestamps, max_size, stabilized)?;
+
+    let width = thumbs.iter().map(|x| x.width()).sum::<u32>();
+    let height = thumbs.iter().map(|x| x.height()).max().unwrap_or_default();
+    let mut strip = RgbaImage::new(width.max(1), height.max(1));
+    let mut x = 0;
+    for thumb in &thumbs {
+        image::imageops::replace(&mut strip, thumb, x as i64, 0);
+        x += thumb.width();
+    }
+    Ok(strip)
+}
+
+// Of the thumbnails of `url`. The stabilized ones depend on everything that affects the output, see `StateChecksum`
+fn cache_key(url: &str, max_size: u32, state: Option<StateChecksum>) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (url, max_size).hash(&mut hasher);
+    if let Some(state) = state {
+        state.total().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Fits the size in `max_size` x `max_size` keeping the aspect ratio, with even dimensions
+fn fit_size(size: (usize, usize), max_size: u32) -> (usize, usize) {
+    let scale = (max_size as f64 / size.0.max(size.1).max(1) as f64).min(1.0);
+    let even = |x: f64| ((x / 2.0).round() as usize * 2).max(2);
+    (even(size.0 as f64 * scale), even(size.1 as f64 * scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnail_size() {
+        assert_eq!(fit_size((1920, 1080), 160), (160, 90));
+        assert_eq!(fit_size((1080, 1920), 160), (90, 160));
+        // Never upscaled, and always even
+        assert_eq!(fit_size((320, 240), 1000), (320, 240));
+        assert_eq!(fit_size((1920, 1080), 100), (100, 56));
+    }
+
+    #[test]
+    fn key_follows_the_state() {
+        let stab = StabilizationManager::default();
+        let key = || cache_key("file:///clip.mp4", 160, Some(stab.get_state_checksum()));
+        let initial = key();
+        assert_eq!(key(), initial);
+        assert_ne!(cache_key("file:///clip.mp4", 160, None), initial);
+        assert_ne!(cache_key("file:///clip.mp4", 320, Some(stab.get_state_checksum())), initial);
+        assert_ne!(cache_key("file:///other.mp4", 160, Some(stab.get_state_checksum())), initial);
+
+        // Any change of the output invalidates the stabilized thumbnails, also without a recompute
+        stab.set_fov(1.3);
+        let fov_changed = key();
+        assert_ne!(fov_changed, initial);
+        stab.set_background_color(nalgebra::Vector4::new(255.0, 0.0, 0.0, 255.0));
+        assert_ne!(key(), fov_changed);
+    }
+
+    #[test]
+    fn thumbnails() {
+        let url = filesystem::path_to_url(&format!("{}/resources/comparison1.mp4", env!("CARGO_MANIFEST_DIR")));
+        let info = FfmpegProcessor::get_video_info(&url).unwrap();
+        // Past the end is clamped to the last frame
+        let timestamps = [1_000_000, 0, 1_000_000, i64::MAX];
+        let thumbs = generate_thumbnails(&url, &timestamps, 120, None).unwrap();
+        assert_eq!(thumbs.len(), timestamps.len());
+        let size = fit_size((info.width as usize, info.height as usize), 120);
+        for thumb in &thumbs {
+            assert_eq!((thumb.width() as usize, thumb.height() as usize), size);
+        }
+        assert_eq!(thumbs[0], thumbs[2]);
+        assert_ne!(thumbs[0], thumbs[1]);
+
+        // From the cache the second time
+        assert_eq!(generate_thumbnails(&url, &timestamps, 120, None).unwrap(), thumbs);
+
+        let strip = generate_filmstrip(&url, 1.0, 120, None).unwrap();
+        let count = (info.duration_ms / 1000.0).ceil() as u32;
+        assert_eq!((strip.width(), strip.height()), (size.0 as u32 * count, size.1 as u32));
+    }
+}
@@ -245,6 +245,7 @@ Rectangle {
                         isDown: isMobileLayout;
                         property bool tempIsAddToQueue: false;
                         property bool isAddToQueue: false;
+                        property bool isProxy: false;
                         property bool allowFile: false;
                         property bool allowLens: false;
                         property bool allowSync: false;
@@ -268,6 +269,7 @@ Rectangle {
                         function updateModel(): void {
                             let m = [
                                 ["export",        isAddToQueue? QT_TRANSLATE_NOOP("Popup", "Export") : (render_queue.editing_job_id > 0? QT_TRANSLATE_NOOP("Popup", "Save") : QT_TRANSLATE_NOOP("Popup", "Add to render queue"))],
+                                ["proxy",         QT_TRANSLATE_NOOP("Popup", "Add proxy render to queue")],
                                 ["create_preset", QT_TRANSLATE_NOOP("Popup", "Create settings preset")],
                                 ["apply_all",     QT_TRANSLATE_NOOP("Popup", "Apply selected settings to all items in the render queue")],
                                 ["export_proj:WithGyroData", QT_TRANSLATE_NOOP("Popup", "Export project file (including gyro data)")],
@@ -314,8 +316,9 @@ Rectangle {
                                 ]);
                                 return;
                             }
-                            const exists = filesystem.exists_in_folder(outputFile.folderUrl, outputFile.filename.replace("_%05d", "_00001"));
-                            if ((exists || render_queue.file_exists_in_folder(outputFile.folderUrl, outputFile.filename)) && !allowFile) {
+                            // The proxy render goes to its own file and it's overwritten every time
+                            const exists = !isProxy && filesystem.exists_in_folder(outputFile.folderUrl, outputFile.filename.replace("_%05d", "_00001"));
+                            if (!isProxy && (exists || render_queue.file_exists_in_folder(outputFile.folderUrl, outputFile.filename)) && !allowFile) {
                                 function overwrite() {
                                     allowFile = true;
                                     renderBtn.render();
@@ -376,8 +379,10 @@ Rectangle {
                                     ], undefined, Text.AutoText, "keep-in-foreground");
                                 }
 
-                                const job_id = render_queue.add(window.getAdditionalProjectDataJson(), controller.image_to_b64(result.image));
-                                if (renderBtn.isAddToQueue || renderBtn.tempIsAddToQueue || render_queue.get_active_render_count() >= render_queue.parallel_renders) {
+                                let data = window.getAdditionalProjectData();
+                                if (renderBtn.isProxy) data.output = exportSettings.item.getProxyExportOptions();
+                                const job_id = render_queue.add(JSON.stringify(data), controller.image_to_b64(result.image));
+                                if (renderBtn.isAddToQueue || renderBtn.tempIsAddToQueue || renderBtn.isProxy || render_queue.get_active_render_count() >= render_queue.parallel_renders) {
                                     // Add to queue
                                     renderBtn.addQueueDelayed = true;
                                     renderBtn.btn.enabled = false;
@@ -395,9 +400,11 @@ Rectangle {
                                     render_queue.render_job(job_id);
                                 }
                                 renderBtn.tempIsAddToQueue = false;
+                                renderBtn.isProxy = false;
                             }, Qt.size(50 * dpiScale * videoArea.vid.parent.ratio, 50 * dpiScale));
                         }
                         btn.onClicked: {
+                            isProxy = false;
                             allowFile = false;
                             allowLens = false;
                             allowSync = false;
@@ -412,9 +419,18 @@ Rectangle {
                                     popup.close();
                                     renderBtn.btn.clicked();
                                 break;
+                                case "proxy": // Quick preview render, added to the queue next to the full render
+                                    popup.close();
+                                    renderBtn.allowFile = false;
+                                    renderBtn.allowLens = false;
+                                    renderBtn.allowSync = false;
+                                    renderBtn.isProxy = true;
+                                    window.videoArea.vid.pause();
+                                    renderBtn.render();
+                                break;
                                 case "create_preset": // Create preset
                                 case "apply_all": // Apply settings to render queue
-                                    const el = Qt.createComponent("SettingsSelector.qml").createObject(window, { type: index == 1? "preset" : "apply" });
+                                    const el = Qt.createComponent("SettingsSelector.qml").createObject(window, { type: action == "create_preset"? "preset" : "apply" });
                                     el.opened = true;
                                     el.onApply.connect((obj) => {
                                         const allData = JSON.parse(controller.export_gyroflow_data("Simple", window.getAdditionalProjectData()));
@@ -422,6 +438,7 @@ Rectangle {
 
                                         if (finalData.hasOwnProperty("output")) {
                                             finalData.output.output_filename = ""; // Don't modify filenames, only target folder
+                                            delete finalData.output.debug_overlay; // Only for a single render
                                         }
                                         if (obj.synchronization && obj.synchronization.do_autosync) {
                                             finalData.synchronization.do_autosync = true;
@@ -625,6 +642,17 @@ Rectangle {
             const el = messageBox(Modal.Info, heading + changelog, [ { text: qsTr("Download"),accent: true, clicked: () => openUpdatePage() },{ text: qsTr("Close") }], undefined, Text.MarkdownText);
             el.t.horizontalAlignment = Text.AlignLeft;
         }
+        function onEmbedded_data_corrupted(project_url: url, details: string, source_url: string): void {
+            const text = qsTr("Embedded gyro data in the project file is corrupted (%1).").arg(details);
+            if (source_url) {
+                messageBox(Modal.Warning, text + "\n" + qsTr("Do you want to read the motion data again from %1?").arg("<b>" + filesystem.display_url(source_url) + "</b>"), [
+                    { text: qsTr("Yes"), accent: true, clicked: () => controller.import_gyroflow_file_reloading_telemetry(project_url) },
+                    { text: qsTr("No") },
+                ]);
+            } else {
+                messageBox(Modal.Error, text + "\n" + qsTr("The source file of the motion data was not found, load it manually."), [ { text: qsTr("Ok") } ]);
+            }
+        }
         function onRequest_location(url: string, type: string): void {
             gfFileDialog.projectType = type;
             gfFileDialog.currentFolder = filesystem.get_folder(url);
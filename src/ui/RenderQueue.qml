@@ -294,7 +294,7 @@ Item {
             property bool isError: error_string.length > 0 && !isQuestion && !isInfo;
             property bool isInfo: error_string == "uses_cpu";
             property bool isQuestion: error_string.startsWith("convert_format:") || error_string.startsWith("file_exists:");
-            property bool isInProgress: (!isFinished && !isError && !isQuestion && total_frames > 0) && (current_frame > 0 || isProcessing);
+            property bool isInProgress: (!isFinished && !isError && !isQuestion && !paused && total_frames > 0) && (current_frame > 0 || isProcessing);
             property bool isProcessing: processing_progress > 0.0 && processing_progress < 1.0;
             property string errorString: error_string;
             property real basicTextSize: (window.isMobileLayout? 10 : 12) * dpiScale;
@@ -377,6 +377,31 @@ Item {
                     enabled: !isFinished && !isInProgress;
                     onTriggered: render_queue.render_job(job_id);
                 }
+                Action {
+                    iconName: paused? "play" : "pause";
+                    text: paused? qsTr("Resume") : qsTr("Pause");
+                    enabled: paused || (!isFinished && !isError && !isQuestion);
+                    onTriggered: paused? render_queue.resume_job(job_id) : render_queue.pause_job(job_id);
+                }
+                Menu {
+                    Component.onCompleted: this.setIcon("settings");
+                    title: qsTr("Processing device");
+                    enabled: !isInProgress && window.advanced.processingDevice.model.length > 0;
+                    ComboBox {
+                        width: 250 * dpiScale;
+                        model: window.advanced.processingDevice.model;
+                        font.pixelSize: 12 * dpiScale;
+                        currentIndex: -1;
+                        onActivated: render_queue.set_job_device(job_id, currentIndex == model.length - 1? -1 : currentIndex);
+                    }
+                }
+                Action {
+                    text: qsTr("Burn in debug overlay");
+                    checkable: true;
+                    checked: render_queue.get_job_debug_overlay(job_id);
+                    enabled: !isInProgress;
+                    onTriggered: render_queue.set_job_debug_overlay(job_id, checked);
+                }
                 Action {
                     iconName: "pencil";
                     text: qsTr("Edit");
@@ -584,6 +609,7 @@ Item {
                     }
                     BasicText { text: qsTr("Save to: %1").arg("<b>" + display_output_path + "</b>"); font.pixelSize: basicTextSize; width: parent.width; wrapMode: Text.WordWrap; }
                     BasicText { text: qsTr("Export settings: %1").arg("<b>" + export_settings + "</b>"); font.pixelSize: basicTextSize; width: parent.width; wrapMode: Text.WordWrap; }
+                    BasicText { visible: isInProgress && !isProcessing && stats.length > 0; text: qsTr("Render speed: %1").arg(stats); font.pixelSize: basicTextSize; width: parent.width; wrapMode: Text.WordWrap; }
                 }
 
                 Column {
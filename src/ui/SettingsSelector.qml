@@ -23,17 +23,18 @@ Modal {
             "Rotation":   ["rotation"],
             "Frame rate": ["fps_scale", "vfr_fps"],
         },
-        "Lens profile": ["calibration_data", "light_refraction_coefficient"],
+        "Lens profile": ["calibration_data", "light_refraction_coefficient", "lens_flip"],
         "Motion data|gyro_source": {
-            "Low pass filter":    ["lpf"],
+            "Low pass filter":    ["lpf", "acc_lpf"],
             "Median filter":      ["mf"],
             "Rotation":           ["rotation", "acc_rotation"],
-            "Gyro bias":          ["gyro_bias"],
+            "Gyro bias":          ["gyro_bias", "detected_bias"],
             "IMU orientation":    ["imu_orientation"],
-            "Integration method": ["integration_method"],
+            "Integration method": ["integration_method", "integration_params"],
+            "Magnetometer":       ["magnetometer"],
         },
         "Trim range": ["trim_ranges_ms"],
-        "Offsets":    ["offsets"],
+        "Offsets":    ["offsets", "offset_interpolation", "offset_drift"],
         "Keyframes":  ["keyframes"]
     },
     { // Right column
@@ -47,9 +48,10 @@ Modal {
         "Stabilization|stabilization": {
             "FOV":                        ["fov"],
             "Smoothing params":           ["method", "smoothing_params"],
-            "Horizon lock":               ["horizon_lock_amount", "horizon_lock_roll", "use_gravity_vectors"],
+            "Horizon lock":               ["horizon_lock_amount", "horizon_lock_roll", "use_gravity_vectors", "axis_lock"],
             "Rolling shutter correction": ["frame_readout_time", "frame_readout_direction"],
-            "Zooming":                    ["adaptive_zoom_window", "adaptive_zoom_center_offset", "adaptive_zoom_method", "additional_rotation", "additional_translation", "max_zoom", "max_zoom_iterations"],
+            "Residual wobble removal":    ["residual_correction_strength", "residual_corrections"],
+            "Zooming":                    ["adaptive_zoom_window", "adaptive_zoom_look_ahead", "static_zoom_percentile", "adaptive_zoom_center_offset", "adaptive_zoom_method", "additional_rotation", "additional_translation", "horizon_compensation", "horizon_compensation_fov", "max_zoom", "max_zoom_iterations", "limit_to_zoom_budget"],
             "Lens correction strength":   ["lens_correction_amount"],
             "Video speed":                ["video_speed", "video_speed_affects_smoothing", "video_speed_affects_zooming", "video_speed_affects_zooming_limit"],
         },
@@ -67,7 +69,7 @@ Modal {
         }
     }];
 
-    property var defaultOff: ["trim_ranges_ms", "offsets", "video_infofps_scale", "video_inforotation", "synchronizationdo_autosync"];
+    property var defaultOff: ["trim_ranges_ms", "offsets", "stabilizationresidual_correction_strength", "video_infofps_scale", "video_inforotation", "synchronizationdo_autosync"];
 
     text: type == "preset"? qsTr("Select settings you want to include in the preset")
         : type == "apply"? qsTr("Select settings you want to apply to all items in the render queue")
@@ -314,7 +316,7 @@ Modal {
         }
     }
     function getFilteredObject(source: var, desc: var): var {
-        let finalData = { version: 2 };
+        let finalData = { version: 3 };
         copyObj(source, desc, finalData);
         // Cleanup empty objects
         for (const key in finalData) {
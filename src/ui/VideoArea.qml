@@ -106,6 +106,12 @@ Item {
         target: controller;
         function onGyroflow_file_loaded(obj: var): void {
             if (obj && +obj.version > 0) {
+                const migration = obj.project_migration || { };
+                if (migration.read_only) {
+                    messageBox(Modal.Warning, qsTr("This project was saved by a newer version of Gyroflow (file format version %1). Some settings may not be loaded. The project is read-only, but it can be saved as a new file, which keeps them.").arg(migration.file_version), [ { text: qsTr("Ok") } ]);
+                } else if (migration.actions && migration.actions.length > 0) {
+                    console.log("Project upgraded from version " + migration.file_version + ":\n" + migration.actions.map(x => x.description).join("\n"));
+                }
                 let duration_ms = videoArea.vid.duration;
                 const info = obj.video_info || { };
                 if (info && Object.keys(info).length > 0) {
@@ -123,9 +129,6 @@ Item {
                 for (const ts in obj.offsets) {
                     controller.set_offset(ts, obj.offsets[ts]);
                 }
-                if (obj.hasOwnProperty("trim_start")) {
-                    timeline.setTrimRanges([[obj.trim_start, obj.trim_end]]);
-                }
                 if (obj.hasOwnProperty("trim_ranges_ms")) {
                     timeline.setTrimRanges(obj.trim_ranges_ms.map(x => [x[0] / duration_ms, x[1] / duration_ms]));
                 } else if (obj.hasOwnProperty("trim_ranges")) {
@@ -665,6 +668,7 @@ Item {
                         }
                     }
 
+                    onPlayingChanged: controller.set_preview_playing(playing, vid);
                     onCurrentFrameChanged: {
                         fovChanged();
                         controller.update_keyframe_values(timestamp);
@@ -13,6 +13,8 @@ Item {
     property var prevTrimRanges: [];
     property bool trimActive: trimRanges.length > 0;
     property bool restrictTrim: true;
+    property bool snapKeyframes: false;
+    onSnapKeyframesChanged: controller.set_keyframe_quantization(snapKeyframes);
 
     property real durationMs: 0;
     property real orgDurationMs: 0;
@@ -68,6 +70,11 @@ Item {
             if (start >= end) {
                 trimRanges.splice(i, 1);
                 i--;
+            } else if (i > 0 && start <= trimRanges[i - 1][1]) {
+                // Touching or overlapping, merge with the previous one
+                trimRanges[i - 1][1] = Math.max(trimRanges[i - 1][1], end);
+                trimRanges.splice(i, 1);
+                i--;
             }
         }
         root.trimRangesChanged();
@@ -241,6 +248,7 @@ Item {
         id: sett;
         property alias timelineChart: chart.viewMode;
         property alias restrictTrimRange: root.restrictTrim;
+        property alias snapKeyframes: root.snapKeyframes;
         Component.onCompleted: settings.init(sett);
         function propChanged() { settings.propChanged(sett); }
     }
@@ -408,12 +416,24 @@ Item {
                                 checkable: true;
                                 onTriggered: keyframeContextMenu.updateEasing();
                             }
+                            Action {
+                                id: holdValue;
+                                text: qsTr("Hold value");
+                                checkable: true;
+                                onTriggered: {
+                                    easeIn.checked = false;
+                                    easeOut.checked = false;
+                                    controller.set_keyframe_easing(keyframeContextMenu.pressedKeyframe, keyframeContextMenu.pressedKeyframeTs, checked? "Hold" : "NoEasing");
+                                }
+                            }
                             function updateEasingMenu(): void {
                                 let e = controller.keyframe_easing(pressedKeyframe, pressedKeyframeTs);
                                 easeIn.checked  = e == "EaseIn"  || e == "EaseInOut";
                                 easeOut.checked = e == "EaseOut" || e == "EaseInOut";
+                                holdValue.checked = e == "Hold";
                             }
                             function updateEasing(): void {
+                                holdValue.checked = false;
                                 let e = "NoEasing";
                                 if (easeIn.checked) e = "EaseIn";
                                 if (easeOut.checked) e = "EaseOut";
@@ -436,6 +456,10 @@ Item {
                             QT_TR_NOOP("Additional 3D translation Z");
                             QT_TR_NOOP("Background margin");
                             QT_TR_NOOP("Background feather");
+                            QT_TR_NOOP("Background red");
+                            QT_TR_NOOP("Background green");
+                            QT_TR_NOOP("Background blue");
+                            QT_TR_NOOP("Background opacity");
                             QT_TR_NOOP("Horizon lock amount");
                             QT_TR_NOOP("Horizon lock roll correction");
                             QT_TR_NOOP("Lens correction strength");
@@ -755,6 +779,20 @@ Item {
                         onTriggered: root.restrictTrim = !root.restrictTrim;
                     }
                 }
+                Menu {
+                    font.pixelSize: 11.5 * dpiScale;
+                    title: qsTr("Keyframes");
+                    Action {
+                        checkable: true;
+                        checked: root.snapKeyframes;
+                        text: qsTr("Snap to frames");
+                        onTriggered: root.snapKeyframes = checked;
+                    }
+                    Action {
+                        text: qsTr("Move all keyframes to the nearest frame");
+                        onTriggered: controller.quantize_all_keyframes();
+                    }
+                }
                 QQC.MenuSeparator { verticalPadding: 5 * dpiScale; }
                 Menu {
                     font.pixelSize: 11.5 * dpiScale;
@@ -926,6 +964,7 @@ Item {
                 position: timestamp_us / (root.durationMs * 1000.0); // TODO: Math.round?
                 value: sharpness;
                 unit: qsTr("px");
+                tooltip: score_info;
                 isCalibPoint: true;
                 onEdit: (ts_us, val) => {
                     vid.setTimestamp(ts_us / 1000);
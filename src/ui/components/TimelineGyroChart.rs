@@ -340,7 +340,7 @@ impl TimelineGyroChart {
                         if let Some(g) = x.gyro.as_ref() {
                             if self.gyro.is_empty() { self.gyro.reserve(imu_len); }
                             self.gyro.push(ChartData {
-                                timestamp_us: ((x.timestamp_ms + gyro.offset_at_gyro_timestamp(x.timestamp_ms)) * 1000.0) as i64,
+                                timestamp_us: (gyro.gyro_to_video_timestamp(x.timestamp_ms) * 1000.0) as i64,
                                 values: [g[0], g[1], g[2]]
                             });
                         }
@@ -349,7 +349,7 @@ impl TimelineGyroChart {
                         if let Some(a) = x.accl.as_ref() {
                             if self.accl.is_empty() { self.accl.reserve(imu_len); }
                             self.accl.push(ChartData {
-                                timestamp_us: ((x.timestamp_ms + gyro.offset_at_gyro_timestamp(x.timestamp_ms)) * 1000.0) as i64,
+                                timestamp_us: (gyro.gyro_to_video_timestamp(x.timestamp_ms) * 1000.0) as i64,
                                 values: [a[0], a[1], a[2]]
                             });
                         }
@@ -358,7 +358,7 @@ impl TimelineGyroChart {
                         if let Some(m) = x.magn.as_ref() {
                             if self.magn.is_empty() { self.magn.reserve(imu_len); }
                             self.magn.push(ChartData {
-                                timestamp_us: ((x.timestamp_ms + gyro.offset_at_gyro_timestamp(x.timestamp_ms)) * 1000.0) as i64,
+                                timestamp_us: (gyro.gyro_to_video_timestamp(x.timestamp_ms) * 1000.0) as i64,
                                 values: [m[0], m[1], m[2]]
                             });
                         }
@@ -371,8 +371,7 @@ impl TimelineGyroChart {
                 self.smoothed_quats = Vec::with_capacity(gyro.smoothed_quaternions.len());
                 let add_quats = |quats: &TimeQuat, out_quats: &mut Vec<ChartData<4>>| {
                     for x in quats {
-                        let mut ts = *x.0 as f64 / 1000.0;
-                        ts += gyro.offset_at_gyro_timestamp(ts);
+                        let ts = gyro.gyro_to_video_timestamp(*x.0 as f64 / 1000.0);
                         let q = x.1.as_vector();
                         out_quats.push(ChartData {
                             timestamp_us: (ts * 1000.0) as i64,
@@ -9,6 +9,7 @@ Rectangle {
     property real position: 0;
     property real value: 0;
     property string unit: "";
+    property string tooltip: "";
 
     property bool isCalibPoint: false;
 
@@ -106,6 +107,7 @@ Rectangle {
                 }
             }
         }
+        ToolTip { visible: !isMobile && root.tooltip.length > 0 && ma.containsMouse; text: root.tooltip; }
         ContextMenuLoader {
             id: menuLoader;
             sourceComponent: menu
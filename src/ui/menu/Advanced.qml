@@ -18,11 +18,15 @@ MenuItem {
         property alias uiScaling: uiScaling.currentIndex;
         property alias safeAreaGuide: safeAreaGuide.checked;
         property alias gpudecode: gpudecode.checked;
+        property alias frameCacheBudgetMB: frameCacheBudget.value;
         property alias backgroundMode: backgroundMode.currentIndex;
         property alias marginPixels: marginPixels.value;
         property alias featherPixels: featherPixels.value;
         property alias defaultSuffix: defaultSuffix.text;
         property alias playSounds: playSounds.checked;
+        property alias logLevels: logLevels.text;
+        property alias logJson: logJson.checked;
+        property alias deterministic: deterministic.checked;
         property alias r3dConvertFormat: r3dConvertFormat.currentIndex;
         property alias r3dColorMode: r3dColorMode.currentIndex;
         property alias r3dGammaCurve: r3dGammaCurve.currentIndex;
@@ -41,8 +45,10 @@ MenuItem {
     }
     property alias defaultSuffix: defaultSuffix;
     property alias previewResolution: previewResolution.currentIndex;
+    property alias previewQuality: previewQuality.currentIndex;
     property alias r3dConvertFormat: r3dConvertFormat;
     property alias gpudecode: gpudecode;
+    property alias processingDevice: processingDevice;
 
     function loadGyroflow(obj: var): void {
         if (obj.hasOwnProperty("background_mode")) backgroundMode.currentIndex = +obj.background_mode;
@@ -79,6 +85,33 @@ MenuItem {
             }
         }
     }
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Preview quality");
+
+        ComboBox {
+            id: previewQuality;
+            model: [QT_TRANSLATE_NOOP("Popup", "Auto"), QT_TRANSLATE_NOOP("Popup", "Full"), QT_TRANSLATE_NOOP("Popup", "Half"), QT_TRANSLATE_NOOP("Popup", "Quarter")];
+            font.pixelSize: 12 * dpiScale;
+            width: parent.width;
+            currentIndex: 0;
+            Component.onCompleted: {
+                if (settings.value("previewQuality", -1) != -1)
+                    currentIndex = +settings.value("previewQuality", -1);
+            }
+            onCurrentIndexChanged: {
+                controller.set_preview_quality(currentIndex, window.videoArea.vid);
+                settings.setValue("previewQuality", currentIndex);
+            }
+        }
+    }
+    BasicText {
+        visible: window.videoArea.vid.playing && controller.preview_quality_level != "Full";
+        text: qsTr("Preview rendered at reduced quality: %1").arg(qsTranslate("Popup", controller.preview_quality_level));
+        width: parent.width;
+        wrapMode: Text.WordWrap;
+        font.pixelSize: 11 * dpiScale;
+    }
 
     Label {
         position: Label.LeftPosition;
@@ -140,6 +173,29 @@ MenuItem {
             onTextChanged: controller.set_background_color(text, window.videoArea.vid);
         }
     }
+    Label {
+        position: Label.LeftPosition;
+        visible: backgroundMode.currentIndex == 0;
+        text: qsTr("Background opacity");
+
+        SliderWithField {
+            id: backgroundAlpha;
+            from: 0;
+            to: 100;
+            value: 1.0;
+            defaultValue: 100;
+            unit: "%";
+            precision: 0;
+            width: parent.width;
+            keyframe: "BackgroundAlpha";
+            scaler: 100.0;
+            onValueChanged: {
+                const c = Qt.darker(renderBackground.text, 1.0);
+                const color = Qt.rgba(c.r, c.g, c.b, value).toString();
+                if (color != renderBackground.text) renderBackground.text = color;
+            }
+        }
+    }
     Label {
         position: Label.LeftPosition;
         text: qsTr("Theme");
@@ -238,6 +294,40 @@ MenuItem {
         checked: true;
         onCheckedChanged: controller.set_gpu_decoding(checked);
     }
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Decoder order");
+        visible: gpudecode.checked;
+        TextField {
+            id: decoderPreference;
+            width: parent.width;
+            placeholderText: qsTr("Automatic");
+            text: settings.value("decoderPreference", "");
+            onEditingFinished: controller.set_decoder_preference(text);
+            tooltip: hovered? qsTr("Comma separated list of the decoders to try, in order. If one fails, the next one is used.") + "\n" +
+                              qsTr("Available: %1").arg(controller.available_decoders()) + "\n" +
+                              qsTr("The decoder used by each render is shown in the render queue.") : "";
+        }
+    }
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Frame cache size");
+        NumberField {
+            id: frameCacheBudget;
+            width: parent.width;
+            value: 4096;
+            defaultValue: 4096;
+            precision: 0;
+            from: 256;
+            unit: "MB";
+            onValueChanged: controller.set_frame_cache_budget(value);
+            function usageText(): string {
+                const usage = controller.get_frame_cache_usage();
+                return qsTr("Currently used: %1 MB, pinned: %2 MB").arg((usage.used / 1024 / 1024).toFixed(0)).arg((usage.pinned / 1024 / 1024).toFixed(0));
+            }
+            tooltip: hovered? qsTr("Memory limit for the cached frames and GPU buffers") + "\n" + usageText() : "";
+        }
+    }
     Label {
         id: r3dConvertFormatLabel;
         position: Label.LeftPosition;
@@ -398,7 +488,42 @@ MenuItem {
         text: qsTr("Notification sounds");
         checked: true;
     }
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Log levels");
+
+        TextField {
+            id: logLevels;
+            text: "";
+            placeholderText: "debug";
+            width: parent.width;
+            tooltip: qsTr("Log level per module, eg. \"info,gyroflow_core::synchronization=trace\"");
+            onEditingFinished: {
+                const error = controller.set_log_levels(text);
+                if (error) messageBox(Modal.Error, error, [ { text: qsTr("Ok") } ]);
+            }
+        }
+    }
+    CheckBox {
+        id: logJson;
+        text: qsTr("Write JSON log");
+        checked: false;
+        tooltip: qsTr("Also write the log as JSON lines to gyroflow.log.jsonl in the data folder");
+        onCheckedChanged: controller.set_json_log(checked);
+    }
+    CheckBox {
+        id: deterministic;
+        text: qsTr("Deterministic processing");
+        checked: false;
+        tooltip: qsTr("Synchronization and zooming give exactly the same results every time, for comparing settings. Zooming is slower when editing keyframes");
+        onCheckedChanged: controller.set_deterministic(checked);
+    }
     Item { width: 1; height: 10 * dpiScale; }
+    LinkButton {
+        text: qsTr("Copy recent log to clipboard");
+        anchors.horizontalCenter: parent.horizontalCenter;
+        onClicked: controller.copy_to_clipboard(controller.get_recent_logs(0));
+    }
     LinkButton {
         text: qsTr("Reset all settings to default");
         textColor: "#f67575"
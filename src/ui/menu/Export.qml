@@ -28,8 +28,9 @@ MenuItem {
             { "name": "ProRes",        "max_size": [16384, 16384], "extension": ".mov",      "gpu": isOsx, "audio": true,  "variants": ["Proxy", "LT", "Standard", "HQ", "4444", "4444XQ"] },
             { "name": "DNxHD",         "max_size": [8192, 8192],   "extension": ".mov",      "gpu": false, "audio": true,  "variants": [/*"DNxHD", */"DNxHR LB", "DNxHR SQ", "DNxHR HQ", "DNxHR HQX", "DNxHR 444"] },
             { "name": "CineForm",      "max_size": [16384, 16384], "extension": ".mov",      "gpu": false, "audio": true,  "variants": [] },
-            { "name": "EXR Sequence",  "max_size": false,          "extension": "_%05d.exr", "gpu": false, "audio": false, "variants": [] },
+            { "name": "EXR Sequence",  "max_size": false,          "extension": "_%05d.exr", "gpu": false, "audio": false, "variants": ["32-bit float", "16-bit half"] },
             { "name": "PNG Sequence",  "max_size": false,          "extension": "_%05d.png", "gpu": false, "audio": false, "variants": ["8-bit", "16-bit"] },
+            { "name": "TIFF Sequence", "max_size": false,          "extension": "_%05d.tif", "gpu": false, "audio": false, "variants": [] },
         ];
         if (Qt.platform.os == "android") { // We can't render sequences on Android because of file system restrictions
             list = list.filter(x => !x.name.includes("Sequence"));
@@ -83,12 +84,23 @@ MenuItem {
         property alias keyframeDistance: keyframeDistance.value;
         property alias preserveOtherTracks: preserveOtherTracks.checked;
         property alias padWithBlack: padWithBlack.checked;
+        property alias transparentBackground: transparentBackground.checked;
+        property alias constantFrameRate: constantFrameRate.checked;
+        property alias constantFps: constantFps.value;
+        property alias copyTelemetry: copyTelemetry.checked;
         property alias exportTrimsSeparately: exportTrimsSeparately.checked;
         property alias metadataComment: metadataComment.text;
         property alias audioCodec: audioCodec.currentIndex;
+        property alias audioBitrate: audioBitrate.value;
+        property alias audioNormalize: audioNormalize.checked;
+        property alias audioTargetLufs: audioTargetLufs.value;
+        property alias audioTruePeak: audioTruePeak.value;
+        property alias audioLimiter: audioLimiter.checked;
         property alias interpolationMethod: interpolationMethod.currentIndex;
         property alias preserveOutputSettings: preserveOutputSettings.checked;
         property alias preserveOutputPath: preserveOutputPath.checked;
+        property alias proxyScale: proxyScale.currentIndex;
+        property alias proxyVisibleRange: proxyVisibleRange.checked;
 
         Component.onCompleted: settings.init(sett);
         function propChanged() { settings.propChanged(sett); }
@@ -120,11 +132,16 @@ MenuItem {
         return {
             codec:          root.outCodec,
             codec_options:  root.outCodecOptions,
+            container:      outCodec === "DNxHD"? container.currentText : "",
             output_folder:    window.outputFile.folderUrl.toString(),
             output_filename:  window.outputFile.filename,
             output_width:   root.outWidth,
             output_height:  root.outHeight,
             bitrate:        root.outBitrate,
+            rate_control:   root.outRateControl,
+            quality:        quality.value,
+            max_bitrate:    maxBitrate.value,
+            buffer_size:    bufferSize.value,
             use_gpu:        root.outGpu,
             audio:          root.outAudio,
             pixel_format:   "",
@@ -134,12 +151,41 @@ MenuItem {
             metadata:              { comment: metadataComment.text },
             keyframe_distance:     keyframeDistance.value,
             preserve_other_tracks: preserveOtherTracks.checked,
+            copy_telemetry:        copyTelemetry.checked,
             pad_with_black:        padWithBlack.checked,
+            transparent_background: transparentBackground.checked,
+            constant_frame_rate:   constantFrameRate.checked,
+            constant_fps:          constantFps.value,
             export_trims_separately: exportTrimsSeparately.checked,
             audio_codec:           audioCodec.currentText,
-            interpolation:         interpolationMethod.currentText
+            audio_offset_ms:       audioOffset.value,
+            audio_bitrate:         audioBitrate.value,
+            audio_normalize:       audioNormalize.checked,
+            audio_target_lufs:     audioTargetLufs.value,
+            audio_true_peak:       audioTruePeak.value,
+            audio_limiter:         audioLimiter.checked,
+            interpolation:         interpolationMethod.currentText,
+            resume:                resumeSequence.visible && resumeSequence.checked,
+            debug_overlay: {
+                enabled:     debugOverlay.checked,
+                gyro:        debugOverlayGyro.checked,
+                horizon:     debugOverlayHorizon.checked,
+                correction:  debugOverlayCorrection.checked,
+                sync_points: debugOverlaySyncPoints.checked
+            }
         };
     }
+    // Same settings with the proxy overrides, the size and range of the proxy are only kept in the app settings
+    function getProxyExportOptions(): var {
+        let options = getExportOptions();
+        options.proxy = true;
+        options.proxy_scale = [1/2, 1/3, 1/4][proxyScale.currentIndex];
+        if (proxyVisibleRange.checked) {
+            const timeline = window.videoArea.timeline;
+            options.proxy_range = [timeline.visibleAreaLeft, timeline.visibleAreaRight];
+        }
+        return options;
+    }
 
     property bool disableUpdate: false;
     function notifySizeChanged(): void {
@@ -218,12 +264,17 @@ MenuItem {
 
             if (output.codec)         Util.setComboValue(codec,        output.codec);
             if (output.codec_options) Util.setComboValue(codecOptions, output.codec_options);
+            if (output.container)     Util.setComboValue(container,    output.container);
 
             if (output.output_width && output.output_height) {
                 setDefaultSize(output.output_width, output.output_height);
                 Qt.callLater(notifySizeChanged);
             }
             if (output.bitrate) root.outBitrate = output.bitrate;
+            if (output.rate_control) rateControl.currentIndex = Math.max(0, rateControlModes.indexOf(output.rate_control));
+            if (output.hasOwnProperty("quality"))     quality.value    = +output.quality;
+            if (output.hasOwnProperty("max_bitrate")) maxBitrate.value = +output.max_bitrate;
+            if (output.hasOwnProperty("buffer_size")) bufferSize.value = +output.buffer_size;
             if (output.hasOwnProperty("use_gpu")) root.outGpu   = output.use_gpu;
             if (output.hasOwnProperty("audio"))   root.outAudio = output.audio;
 
@@ -231,10 +282,21 @@ MenuItem {
             if (output.hasOwnProperty("encoder_options"))       encoderOptions.text         = output.encoder_options;
             if (output.hasOwnProperty("keyframe_distance"))     keyframeDistance.value      = +output.keyframe_distance;
             if (output.hasOwnProperty("preserve_other_tracks")) preserveOtherTracks.checked = output.preserve_other_tracks;
+            if (output.hasOwnProperty("copy_telemetry"))        copyTelemetry.checked       = output.copy_telemetry;
             if (output.hasOwnProperty("pad_with_black"))        padWithBlack.checked        = output.pad_with_black;
+            if (output.hasOwnProperty("transparent_background")) transparentBackground.checked = !!output.transparent_background;
+            if (output.hasOwnProperty("constant_frame_rate"))   constantFrameRate.checked   = !!output.constant_frame_rate;
+            if (output.hasOwnProperty("constant_fps"))          constantFps.value           = +output.constant_fps;
             if (output.hasOwnProperty("export_trims_separately")) exportTrimsSeparately.checked = output.export_trims_separately;
             if (output.hasOwnProperty("audio_codec"))           Util.setComboValue(audioCodec, output.audio_codec);
+            if (output.hasOwnProperty("audio_offset_ms"))       audioOffset.value           = +output.audio_offset_ms;
+            if (output.hasOwnProperty("audio_bitrate"))         audioBitrate.value          = +output.audio_bitrate;
+            if (output.hasOwnProperty("audio_normalize"))       audioNormalize.checked      = !!output.audio_normalize;
+            if (output.hasOwnProperty("audio_target_lufs") && +output.audio_target_lufs < 0) audioTargetLufs.value = +output.audio_target_lufs;
+            if (output.hasOwnProperty("audio_true_peak"))       audioTruePeak.value         = +output.audio_true_peak;
+            if (output.hasOwnProperty("audio_limiter"))         audioLimiter.checked        = !!output.audio_limiter;
             if (output.hasOwnProperty("interpolation"))         Util.setComboValue(interpolationMethod, output.interpolation);
+            if (output.hasOwnProperty("resume"))                resumeSequence.checked      = output.resume;
             if (output.hasOwnProperty("metadata")) {
                 metadataComment.text = output.metadata.comment || "";
             }
@@ -274,22 +336,42 @@ MenuItem {
             if (!audio.enabled2) audio.checked = false;
 
             updateGpuStatus();
-            updateExtension(format.extension);
+            updateExtension(format.name == "DNxHD" && container.currentText == "MXF"? ".mxf" : format.extension);
         }
     }
     ComboBox {
         id: codecOptions;
-        model: exportFormats[codec.currentIndex].variants;
+        // ProRes 4444 keeps the alpha channel only in the variants which are offered with a transparent background
+        model: exportFormats[codec.currentIndex].variants.concat(exportFormats[codec.currentIndex].name == "ProRes" && transparentBackground.checked? ["4444 with alpha", "4444XQ with alpha"] : []);
         width: parent.width;
         visible: model.length > 0;
         onVisibleChanged: if (!visible) { root.outCodecOptions = ""; } else { root.outCodecOptions = currentText; }
         onCurrentTextChanged: root.outCodecOptions = currentText;
         onModelChanged: {
             const format = exportFormats[codec.currentIndex];
+            // Keep the selected ProRes profile when only the alpha variants were added or removed
+            const previous = format.name == "ProRes"? root.outCodecOptions.replace(" with alpha", "") : "";
+            if (previous && model.indexOf(previous) > -1) {
+                currentIndex = model.indexOf(root.outCodecOptions) > -1? model.indexOf(root.outCodecOptions) : model.indexOf(previous);
+                return;
+            }
             if (format.name == "ProRes") currentIndex = 3; // ProRes HQ by default
             if (format.name == "DNxHD") currentIndex = 2; // DNxHR HQ by default
         }
     }
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Container");
+        visible: outCodec === "DNxHD";
+        ComboBox {
+            id: container;
+            model: ["MOV", "MXF"];
+            font.pixelSize: 12 * dpiScale;
+            width: parent.width;
+            currentIndex: 0;
+            onCurrentTextChanged: if (parent.visible) codec.updateExtension(currentText == "MXF"? ".mxf" : ".mov");
+        }
+    }
     Label {
         position: Label.LeftPosition;
         text: qsTr("Output size");
@@ -449,10 +531,43 @@ MenuItem {
         text: qsTr("Resolution must be divisible by 2.");
     }
 
+    property bool hasRateControl: outCodec === "H.264/AVC" || outCodec === "H.265/HEVC" || outCodec === "AV1";
+    readonly property var rateControlModes: ["CBR", "CRF", "VBR", "2-pass"];
+    property string outRateControl: hasRateControl? rateControlModes[rateControl.currentIndex] : "CBR";
+
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Rate control");
+        visible: hasRateControl;
+        ComboBox {
+            id: rateControl;
+            model: [qsTr("Constant bitrate"), qsTr("Constant quality"), qsTr("Variable bitrate"), qsTr("Two-pass")];
+            font.pixelSize: 12 * dpiScale;
+            width: parent.width;
+            currentIndex: 0;
+            tooltip: currentIndex == 3? qsTr("Two-pass encoding is available with the software encoders only and takes about twice as long.") : "";
+        }
+    }
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Quality");
+        visible: hasRateControl && outRateControl === "CRF";
+
+        NumberField {
+            id: quality;
+            value: 23;
+            defaultValue: 23;
+            precision: 0;
+            from: 0;
+            to: 63;
+            width: parent.width;
+            tooltip: qsTr("Lower value means higher quality and bigger file.");
+        }
+    }
     Label {
         position: Label.LeftPosition;
         text: qsTr("Bitrate");
-        visible: outCodec === "H.264/AVC" || outCodec === "H.265/HEVC" || outCodec === "AV1";
+        visible: hasRateControl && outRateControl !== "CRF";
 
         NumberField {
             id: bitrate;
@@ -468,6 +583,35 @@ MenuItem {
         }
     }
 
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Maximum bitrate");
+        visible: hasRateControl && outRateControl === "VBR";
+
+        NumberField {
+            id: maxBitrate;
+            value: 0;
+            defaultValue: 0;
+            unit: qsTr("Mbps");
+            width: parent.width;
+            tooltip: qsTr("0 means no limit.");
+        }
+    }
+    Label {
+        position: Label.LeftPosition;
+        text: qsTr("Buffer size");
+        visible: hasRateControl && outRateControl === "VBR";
+
+        NumberField {
+            id: bufferSize;
+            value: 0;
+            defaultValue: 0;
+            unit: qsTr("Mbit");
+            width: parent.width;
+            tooltip: qsTr("0 means the encoder's default.");
+        }
+    }
+
     CheckBox {
         id: gpu;
         text: qsTr("Use GPU encoding");
@@ -558,6 +702,14 @@ MenuItem {
             tooltip: qsTr("This disables trim range and you need to use the .mov output file extension");
             onCheckedChanged: if (checked) codec.updateExtension(".mov");
         }
+        CheckBox {
+            id: copyTelemetry;
+            text: qsTr("Copy camera telemetry");
+            checked: false;
+            visible: !outCodec.includes("Sequence");
+            tooltip: qsTr("Keeps the GPS and other camera metadata tracks, aligned to the trim range. This needs the .mov output file extension");
+            onCheckedChanged: if (checked) codec.updateExtension(".mov");
+        }
         CheckBox {
             id: padWithBlack;
             text: qsTr("Use black frames outside trim range and keep original file duration");
@@ -565,6 +717,39 @@ MenuItem {
             width: parent.width;
             Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
         }
+        CheckBox {
+            id: transparentBackground;
+            text: qsTr("Transparent background");
+            tooltip: qsTr("Areas outside of the source frame are exported with alpha 0. Requires ProRes 4444 with alpha, PNG, EXR or TIFF");
+            checked: false;
+            width: parent.width;
+            Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
+        }
+        CheckBox {
+            id: constantFrameRate;
+            text: qsTr("Constant frame rate");
+            tooltip: qsTr("Converts a variable frame rate video by duplicating or dropping frames, the audio stays in sync");
+            checked: false;
+            visible: !outCodec.includes("Sequence");
+            width: parent.width;
+            Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
+        }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Frame rate");
+            visible: constantFrameRate.visible && constantFrameRate.checked;
+
+            NumberField {
+                id: constantFps;
+                width: parent.width;
+                height: 25 * dpiScale;
+                value: 0;
+                from: 0;
+                precision: 3;
+                unit: qsTr("fps");
+                tooltip: qsTr("0 uses the average frame rate of the source");
+            }
+        }
         CheckBox {
             id: exportTrimsSeparately;
             text: qsTr("Export trim ranges as separate videos");
@@ -572,6 +757,15 @@ MenuItem {
             width: parent.width;
             Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
         }
+        CheckBox {
+            id: resumeSequence;
+            text: qsTr("Continue after the already rendered frames");
+            tooltip: qsTr("Keeps the existing files of the image sequence and renders only the missing frames after them");
+            visible: outCodec.includes("Sequence");
+            checked: false;
+            width: parent.width;
+            Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
+        }
         Label {
             position: Label.LeftPosition;
             text: qsTr("Audio codec");
@@ -584,6 +778,105 @@ MenuItem {
                 currentIndex: 0;
             }
         }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Audio offset");
+            enabled: audio.checked;
+            Item {
+                width: parent.width;
+                height: audioOffset.height;
+                NumberField {
+                    id: audioOffset;
+                    anchors.verticalCenter: parent.verticalCenter;
+                    anchors.left: parent.left;
+                    anchors.right: useSyncOffset.left;
+                    anchors.rightMargin: 5 * dpiScale;
+                    height: 25 * dpiScale;
+                    value: 0;
+                    defaultValue: 0;
+                    precision: 1;
+                    unit: qsTr("ms");
+                    tooltip: qsTr("Positive value delays the audio");
+                }
+                LinkButton {
+                    id: useSyncOffset;
+                    height: parent.height;
+                    anchors.verticalCenter: parent.verticalCenter;
+                    anchors.right: parent.right;
+                    text: qsTr("Use sync offset");
+                    tooltip: qsTr("Shift the audio by the gyro sync offset at the start of the trim range");
+                    onClicked: {
+                        const timeline = window.videoArea.timeline;
+                        const startUs = timeline.getTrimRanges()[0][0] * timeline.durationMs * 1000;
+                        audioOffset.value = +controller.offset_at_video_timestamp(Math.round(startUs)).toFixed(1);
+                    }
+                }
+            }
+        }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Audio bitrate");
+            enabled: audio.checked;
+            NumberField {
+                id: audioBitrate;
+                width: parent.width;
+                height: 25 * dpiScale;
+                value: 0;
+                defaultValue: 0;
+                from: 0;
+                to: 1024;
+                unit: qsTr("kbps");
+                tooltip: qsTr("0 keeps the bitrate of the source");
+            }
+        }
+        CheckBox {
+            id: audioNormalize;
+            text: qsTr("Normalize audio loudness");
+            enabled: audio.checked;
+            checked: false;
+            tooltip: qsTr("Measures the loudness of the audio in the trim range and adjusts the volume to the target (EBU R128). The audio is always re-encoded");
+        }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Target loudness");
+            visible: audioNormalize.checked;
+            enabled: audio.checked;
+            NumberField {
+                id: audioTargetLufs;
+                width: parent.width;
+                height: 25 * dpiScale;
+                value: -14;
+                defaultValue: -14;
+                from: -40;
+                to: -5;
+                precision: 1;
+                unit: qsTr("LUFS");
+            }
+        }
+        CheckBox {
+            id: audioLimiter;
+            text: qsTr("Limit true peak");
+            visible: audioNormalize.checked;
+            enabled: audio.checked;
+            checked: true;
+        }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("True peak ceiling");
+            visible: audioNormalize.checked && audioLimiter.checked;
+            enabled: audio.checked;
+            NumberField {
+                id: audioTruePeak;
+                width: parent.width;
+                height: 25 * dpiScale;
+                value: -1;
+                defaultValue: -1;
+                from: -9;
+                to: 0;
+                precision: 1;
+                unit: qsTr("dBTP");
+            }
+        }
         Label {
             position: Label.LeftPosition;
             text: qsTr("Interpolation method");
@@ -673,5 +966,39 @@ MenuItem {
                 }
             }
         }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Proxy render size");
+            ComboBox {
+                id: proxyScale;
+                model: ["1/2", "1/3", "1/4"];
+                font.pixelSize: 12 * dpiScale;
+                width: parent.width;
+                currentIndex: 0;
+                tooltip: qsTr("Output size of the proxy render, relative to the export size");
+            }
+        }
+        CheckBox {
+            id: proxyVisibleRange;
+            text: qsTr("Limit proxy render to the visible part of the timeline");
+            checked: false;
+            width: parent.width;
+            Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
+        }
+        // Not saved in the settings, so it's never turned on by default
+        CheckBox {
+            id: debugOverlay;
+            text: qsTr("Burn in debug overlay");
+            tooltip: qsTr("Draws the gyro data, the correction, the horizon and the sync points into the rendered video");
+            checked: false;
+        }
+        Column {
+            visible: debugOverlay.checked;
+            x: 15 * dpiScale;
+            CheckBox { id: debugOverlayGyro;       text: qsTr("Gyro data");    checked: true; }
+            CheckBox { id: debugOverlayCorrection; text: qsTr("Correction");   checked: true; }
+            CheckBox { id: debugOverlayHorizon;    text: qsTr("Horizon line"); checked: true; }
+            CheckBox { id: debugOverlaySyncPoints; text: qsTr("Sync points");  checked: true; }
+        }
     }
 }
@@ -598,6 +598,13 @@ MenuItem {
             show: processingResolution.currentIndex > 1;
             text: qsTr("Lens calibration should be processed at full resolution or at least at 4k. Change this setting only if you know what you're doing.");
         }
+        CheckBox {
+            text: qsTr("Pick the calibration frames automatically");
+            tooltip: qsTr("Choose the sharpest frames which cover the most of the image area and board angles, instead of the best of random sets.\nDeleted calibration points are excluded and added points are always used.");
+            checked: false;
+            width: parent.width;
+            onCheckedChanged: controller.set_calibration_auto_select(checked);
+        }
         CheckBox {
             text: qsTr("Lens is asymmetrical");
             checked: false;
@@ -611,12 +618,77 @@ MenuItem {
             width: parent.width;
             onCheckedChanged: calib.calibrationInfo.global_shutter = checked;
         }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Calibration target");
+            ComboBox {
+                id: boardType;
+                model: [QT_TRANSLATE_NOOP("Popup", "Chessboard"), "ChArUco"];
+                font.pixelSize: 12 * dpiScale;
+                width: parent.width;
+                currentIndex: 0;
+                onCurrentIndexChanged: charuco.update();
+            }
+        }
         CheckBox {
             id: noMarker;
+            visible: boardType.currentIndex == 0;
             text: qsTr("Plain chessboard pattern (previous version without dots in the middle)");
             checked: false;
             width: parent.width;
             Component.onCompleted: contentItem.wrapMode = Text.WordWrap;
         }
+        Column {
+            id: charuco;
+            visible: boardType.currentIndex == 1;
+            width: parent.width;
+            spacing: parent.spacing;
+            function update(): void {
+                if (boardType.currentIndex == 0) {
+                    controller.set_calibration_board(JSON.stringify({ "type": "chessboard" }));
+                } else {
+                    controller.set_calibration_board(JSON.stringify({
+                        "type": "charuco",
+                        "squares_x": squaresX.value,
+                        "squares_y": squaresY.value,
+                        "square_length": squareLength.value,
+                        "marker_length": markerLength.value,
+                        "dictionary": dictionary.currentText
+                    }));
+                }
+            }
+            Label {
+                position: Label.LeftPosition;
+                text: qsTr("Squares");
+                Row {
+                    width: parent.width;
+                    spacing: 5 * dpiScale;
+                    NumberField { id: squaresX; width: (parent.width - parent.spacing) / 2; value: 12; from: 3; precision: 0; onValueChanged: charuco.update(); }
+                    NumberField { id: squaresY; width: (parent.width - parent.spacing) / 2; value: 9;  from: 3; precision: 0; onValueChanged: charuco.update(); }
+                }
+            }
+            Label {
+                position: Label.LeftPosition;
+                text: qsTr("Square size");
+                NumberField { id: squareLength; width: parent.width; value: 30; from: 1; precision: 1; unit: qsTr("mm"); onValueChanged: charuco.update(); }
+            }
+            Label {
+                position: Label.LeftPosition;
+                text: qsTr("Marker size");
+                NumberField { id: markerLength; width: parent.width; value: 22; from: 1; precision: 1; unit: qsTr("mm"); onValueChanged: charuco.update(); }
+            }
+            Label {
+                position: Label.LeftPosition;
+                text: qsTr("Marker dictionary");
+                ComboBox {
+                    id: dictionary;
+                    model: ["DICT_4X4_50", "DICT_4X4_100", "DICT_4X4_250", "DICT_5X5_50", "DICT_5X5_100", "DICT_5X5_250", "DICT_6X6_50", "DICT_6X6_100", "DICT_6X6_250", "DICT_7X7_50", "DICT_7X7_100", "DICT_7X7_250"];
+                    font.pixelSize: 12 * dpiScale;
+                    width: parent.width;
+                    currentIndex: 4;
+                    onCurrentIndexChanged: charuco.update();
+                }
+            }
+        }
     }
 }
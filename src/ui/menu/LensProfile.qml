@@ -34,10 +34,10 @@ MenuItem {
 
     FileDialog {
         id: fileDialog;
-        property var extensions: ["json"];
+        property var extensions: ["json", "yml", "yaml"];
 
         title: qsTr("Choose a lens profile")
-        nameFilters: [qsTr("Lens profiles") + " (*.json" + (Qt.platform.os == "ios"? " *.txt" : "") + ")"];
+        nameFilters: [qsTr("Lens profiles") + " (*.json" + (Qt.platform.os == "ios"? " *.txt" : "") + ")", qsTr("OpenCV calibration") + " (*.yml *.yaml *.json)"];
         type: "lens";
         onAccepted: loadFile(fileDialog.selectedFile);
     }
@@ -50,6 +50,10 @@ MenuItem {
         if (typeof obj.light_refraction_coefficient !== "undefined") {
             isUnderwater.checked = Math.round(+obj.light_refraction_coefficient * 1000) == 1330;
         }
+        if (obj.lens_flip && obj.lens_flip.length == 2) {
+            flipHorizontal.checked = !!obj.lens_flip[0];
+            flipVertical.checked   = !!obj.lens_flip[1];
+        }
     }
 
     Component.onCompleted: {
@@ -177,6 +181,31 @@ MenuItem {
                 Qt.callLater(controller.recompute_threaded);
             }
         }
+        function onLens_validation_finished(report: string): void {
+            if (!report) {
+                messageBox(Modal.Warning, qsTr("Not enough motion or features were found to validate the lens profile."), [ { text: qsTr("Ok") } ]);
+                return;
+            }
+            const r = JSON.parse(report);
+            const fit = { "Good": qsTr("Good"), "Acceptable": qsTr("Acceptable"), "Poor": qsTr("Poor") }[r.fit];
+            const line = (name, x) => name + ": <b>" + x.rms_px.toFixed(2) + " px</b> (" + x.points + ")";
+            messageBox(r.fit == "Poor"? Modal.Warning : Modal.Info, qsTr("Lens profile fit: <b>%1</b>").arg(fit) + "<br><br>" + [
+                line(qsTr("Overall"), r.all),
+                line(qsTr("Center"),  r.center),
+                line(qsTr("Edges"),   r.edges),
+                line(qsTr("Corners"), r.corners)
+            ].join("<br>") + "<br><br>" + qsTr("Good below %1 px, acceptable below %2 px.").arg(r.good_threshold_px.toFixed(2)).arg(r.acceptable_threshold_px.toFixed(2)), [ { text: qsTr("Ok") } ]);
+        }
+        function onLens_profile_candidates(candidates: string): void {
+            const list = JSON.parse(candidates);
+            if (!list || !list.length) return;
+            let buttons = list.map(x => ({
+                text: x.name + " (" + Math.round(x.score * 100) + "%)",
+                clicked: () => { root.selected_manually = true; controller.load_lens_profile(x.id); }
+            }));
+            buttons.push({ text: qsTr("Cancel") });
+            messageBox(Modal.Question, qsTr("No lens profile matches this video exactly. Do you want to use one of these?"), buttons);
+        }
     }
 
     property int currentVideoAspectRatio: Math.round((root.videoWidth / Math.max(1, root.videoHeight)) * 1000);
@@ -346,6 +375,27 @@ MenuItem {
                 sourceComponent: isUnderwaterMenu
             }
         }
+        CheckBox {
+            id: flipHorizontal;
+            text: qsTr("Video is mirrored horizontally");
+            checked: false;
+            tooltip: qsTr("Enable for footage from front-facing cameras or mirrored by an app, when the profile was calibrated on unmirrored video.");
+            onCheckedChanged: controller.set_lens_flip(flipHorizontal.checked, flipVertical.checked);
+        }
+        CheckBox {
+            id: flipVertical;
+            text: qsTr("Video is mirrored vertically");
+            checked: false;
+            tooltip: qsTr("Enable for footage flipped by the camera, eg. when mounted upside down with image flip enabled.");
+            onCheckedChanged: controller.set_lens_flip(flipHorizontal.checked, flipVertical.checked);
+        }
+        LinkButton {
+            anchors.horizontalCenter: parent.horizontalCenter;
+            text: qsTr("Validate on this video");
+            enabled: window.videoArea.vid.loaded && !controller.sync_in_progress;
+            tooltip: qsTr("Measures how well the lens profile fits this video, using optical flow at a few points of the clip.");
+            onClicked: controller.start_autosync("0.1;0.3;0.5;0.7;0.9", window.sync.getSettingsJson(), "validate_lens");
+        }
 
         component SmallNumberField: NumberField {
             property bool preventChange2: true;
@@ -15,6 +15,7 @@ MenuItem {
 
     property alias hasQuaternions: integrator.hasQuaternions;
     property bool hasAccurateTimestamps: false;
+    property bool hasMagnetometer: false;
     property alias hasRawGyro: integrator.hasRawGyro;
     property alias integrationMethod: integrator.currentIndex;
     property alias orientationIndicator: orientationIndicator;
@@ -22,6 +23,7 @@ MenuItem {
     property string filename: "";
     property string detectedFormat: "";
     property url lastSelectedFile: "";
+    property var detectedBias: null;
 
     FileDialog {
         id: fileDialog;
@@ -66,6 +68,14 @@ MenuItem {
                 lpf.value = +gyro.lpf;
                 lpfcb.checked = lpf.value > 0;
             }
+            if (gyro.magnetometer) {
+                magnetometerTc.value = +gyro.magnetometer.time_constant_s || 20;
+                magnetometerCb.checked = !!gyro.magnetometer.enabled;
+            }
+            if (+gyro.acc_lpf > 0) {
+                accLpf.value = +gyro.acc_lpf;
+                accLpfcb.checked = accLpf.value > 0;
+            }
             if (typeof gyro.sample_index === "number") {
                 currentLog.currentIndex = gyro.sample_index + 1;
             }
@@ -97,6 +107,8 @@ MenuItem {
             integrator.hasQuaternions = !additional_data.contains_quats;
             integrator.hasQuaternions = additional_data.contains_quats;
             root.hasAccurateTimestamps = additional_data.has_accurate_timestamps || false;
+            root.hasMagnetometer = additional_data.contains_magnetometer || false;
+            root.detectedBias = additional_data.detected_bias || null;
             if (additional_data.contains_quats && !is_main_video) {
                 if (integrator.hasRawGyro) {
                     integrator.currentIndex = 2;
@@ -114,6 +126,7 @@ MenuItem {
             }
 
             controller.set_imu_lpf(lpfcb.checked? lpf.value : 0);
+            controller.set_imu_acc_lpf(accLpfcb.checked? accLpf.value : 0);
             controller.set_imu_median_filter(mfcb.checked? mf.value : 0);
             controller.set_imu_rotation(rot.checked? p.value : 0, rot.checked? r.value : 0, rot.checked? y.value : 0);
             controller.set_acc_rotation(arot.checked? ap.value : 0, arot.checked? ar.value : 0, arot.checked? ay.value : 0);
@@ -221,7 +234,6 @@ MenuItem {
         text: qsTr("Low pass filter");
         onCheckedChanged: {
             controller.set_imu_lpf(checked? lpf.value : 0);
-            Qt.callLater(controller.recompute_gyro);
         }
 
         NumberField {
@@ -234,7 +246,26 @@ MenuItem {
             tooltip: qsTr("Lower cutoff frequency means more filtering");
             onValueChanged: {
                 controller.set_imu_lpf(lpfcb.checked? value : 0);
-                Qt.callLater(controller.recompute_gyro);
+            }
+        }
+    }
+    CheckBoxWithContent {
+        id: accLpfcb;
+        text: qsTr("Accelerometer low pass filter");
+        onCheckedChanged: {
+            controller.set_imu_acc_lpf(checked? accLpf.value : 0);
+        }
+
+        NumberField {
+            id: accLpf;
+            unit: qsTr("Hz");
+            precision: 2;
+            value: 10;
+            from: 0;
+            width: parent.width;
+            tooltip: qsTr("Filters the accelerometer separately from the gyroscope, it only affects the horizon lock and the integration");
+            onValueChanged: {
+                controller.set_imu_acc_lpf(accLpfcb.checked? value : 0);
             }
         }
     }
@@ -393,6 +424,16 @@ MenuItem {
             }
         }
     }
+    InfoMessageSmall {
+        show: !!root.detectedBias && !gyrobias.checked;
+        type: InfoMessage.Info;
+        text: root.detectedBias? qsTr("Detected %1 static segments in the motion data. Click here to use the estimated bias (%2, %3, %4 °/s).").arg(root.detectedBias.segments.length).arg(root.detectedBias.bias[0].toFixed(2)).arg(root.detectedBias.bias[1].toFixed(2)).arg(root.detectedBias.bias[2].toFixed(2)) : "";
+        MouseArea {
+            anchors.fill: parent;
+            cursorShape: Qt.PointingHandCursor;
+            onClicked: controller.accept_detected_bias();
+        }
+    }
     Label {
         position: Label.LeftPosition;
         text: qsTr("IMU orientation");
@@ -430,6 +471,31 @@ MenuItem {
             }
         }
     }
+    CheckBoxWithContent {
+        id: magnetometerCb;
+        visible: root.hasMagnetometer;
+        text: qsTr("Magnetometer heading correction");
+        cb.tooltip: qsTr("Corrects the yaw drift using the magnetometer. Only used by the integration methods which use the accelerometer");
+        function update(): void {
+            controller.set_magnetometer_correction(checked, magnetometerTc.value);
+        }
+        onCheckedChanged: update();
+
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Time constant");
+            NumberField {
+                id: magnetometerTc;
+                unit: qsTr("s");
+                precision: 1;
+                value: 20;
+                from: 0.1;
+                width: parent.width;
+                tooltip: qsTr("Lower value follows the magnetic heading faster, higher value is less affected by magnetic interference");
+                onValueChanged: magnetometerCb.update();
+            }
+        }
+    }
 
     CheckBoxWithContent {
         id: orientationCheckbox;
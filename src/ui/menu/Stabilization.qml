@@ -35,6 +35,7 @@ MenuItem {
         property alias zoomingMethod: zoomingMethod.currentIndex;
         property alias maxZoom: maxZoomSlider.value;
         property alias maxZoomIterations: maxZoomIterations.value;
+        property alias limitToZoomBudget: limitToZoomBudget.checked;
 
         Component.onCompleted: settings.init(sett);
         function propChanged() { settings.propChanged(sett); }
@@ -47,8 +48,11 @@ MenuItem {
             const methodIndex = smoothingAlgorithms.indexOf(stab.method);
             if (methodIndex > -1) {
                 smoothingMethod.currentIndex = methodIndex;
+            } else if (stab.method) {
+                console.warn("Smoothing algorithm", stab.method, "is not available, using the default one");
+                smoothingMethod.currentIndex = 1;
             }
-            if (stab.smoothing_params) {
+            if (stab.smoothing_params && (methodIndex > -1 || !stab.method)) {
                 Qt.callLater(function() {
                     for (const x of stab.smoothing_params) {
                         const el = root.getParamElement(x.name);
@@ -64,6 +68,24 @@ MenuItem {
                 setFrameReadoutTime(+stab.frame_readout_time, stab.frame_readout_direction);
             }
 
+            if (typeof stab.rolling_shutter_strength === 'number') {
+                rollingShutterStrength.value = +stab.rolling_shutter_strength;
+            }
+            if (typeof stab.residual_correction_strength === 'number') {
+                const hasCorrections = !!stab.residual_corrections && Object.keys(stab.residual_corrections).length > 0;
+                if (+stab.residual_correction_strength > 0) residualStrength.value = +stab.residual_correction_strength;
+                residualWobbleCb.status = "";
+                residualWobbleCb.checked = hasCorrections && +stab.residual_correction_strength > 0;
+            }
+            if (stab.hasOwnProperty("frame_readout_time_override")) {
+                const hasOverride = typeof stab.frame_readout_time_override === 'number';
+                if (hasOverride) {
+                    readoutOverride.value = +stab.frame_readout_time_override;
+                    readoutOverrideDirection.set(stab.frame_readout_direction_override || 0);
+                }
+                readoutOverrideCb.checked = hasOverride;
+            }
+
             if (typeof stab.lens_correction_amount !== "undefined") {
                 correctionAmount.value = +stab.lens_correction_amount;
             }
@@ -77,6 +99,12 @@ MenuItem {
             } else {
                 croppingMode.currentIndex = 0; // No cropping
             }
+            if (typeof stab.static_zoom_percentile !== "undefined") {
+                staticZoomPercentile.value = +stab.static_zoom_percentile;
+            }
+            if (typeof stab.adaptive_zoom_look_ahead !== "undefined") {
+                adaptiveZoomLookAhead.value = +stab.adaptive_zoom_look_ahead;
+            }
             if (stab.hasOwnProperty("adaptive_zoom_center_offset")) {
                 zoomingCenterX.value = stab.adaptive_zoom_center_offset[0];
                 zoomingCenterY.value = stab.adaptive_zoom_center_offset[1];
@@ -86,6 +114,11 @@ MenuItem {
                 additionalRotationY.value = stab.additional_rotation[1];
                 additionalRotationZ.value = stab.additional_rotation[2];
             }
+            if (stab.hasOwnProperty("horizon_compensation")) {
+                horizonCompensationPitch.value = stab.horizon_compensation[0];
+                horizonCompensationRoll.value = stab.horizon_compensation[1];
+            }
+            if (stab.hasOwnProperty("horizon_compensation_fov")) horizonCompensationFov.value = +stab.horizon_compensation_fov;
             if (stab.hasOwnProperty("additional_translation")) {
                 additionalTranslationX.value = stab.additional_translation[0];
                 additionalTranslationY.value = stab.additional_translation[1];
@@ -104,12 +137,26 @@ MenuItem {
             horizonCb.checked = (+stab.horizon_lock_amount || 0) > 0;
             horizonSlider.value = horizonCb.checked? +stab.horizon_lock_amount : 100;
             horizonRollSlider.value = horizonCb.checked? +stab.horizon_lock_roll : 0;
+            if (stab.hasOwnProperty("horizon_lock_gravity_reference")) gravityReferenceSlider.value = +stab.horizon_lock_gravity_reference * 100;
             Qt.callLater(updateHorizonLock);
 
+            if (stab.hasOwnProperty("axis_lock")) {
+                const al = stab.axis_lock;
+                axisLockPitch.checked = !!al.pitch;
+                axisLockYaw.checked = !!al.yaw;
+                axisLockRoll.checked = !!al.roll;
+                axisLockHorizon.checked = !!al.horizon_reference;
+                axisLockPitchTarget.value = +al.target[0];
+                axisLockYawTarget.value = +al.target[1];
+                axisLockRollTarget.value = +al.target[2];
+                axisLockCb.checked = axisLockPitch.checked || axisLockYaw.checked || axisLockRoll.checked;
+            }
+
             if (stab.hasOwnProperty("video_speed")) videoSpeed.value = +stab.video_speed;
             if (stab.hasOwnProperty("video_speed_affects_smoothing"))     videoSpeedAffectsSmoothing.checked    = !!stab.video_speed_affects_smoothing;
             if (stab.hasOwnProperty("video_speed_affects_zooming"))       videoSpeedAffectsZooming.checked      = !!stab.video_speed_affects_zooming;
             if (stab.hasOwnProperty("video_speed_affects_zooming_limit")) videoSpeedAffectsZoomingLimit.checked = !!stab.video_speed_affects_zooming_limit;
+            if (stab.hasOwnProperty("limit_to_zoom_budget")) limitToZoomBudget.checked = !!stab.limit_to_zoom_budget;
         }
     }
 
@@ -143,10 +190,16 @@ MenuItem {
         return traverseChildren(smoothingOptions, "param-" + name);
     }
 
+    function updateAxisLock(): void {
+        const on = axisLockCb.checked;
+        controller.set_axis_lock(on && axisLockPitch.checked, on && axisLockYaw.checked, on && axisLockRoll.checked, axisLockHorizon.checked);
+        controller.set_axis_lock_target(axisLockPitchTarget.value, axisLockYawTarget.value, axisLockRollTarget.value);
+    }
     function updateHorizonLock(): void {
         const lockAmount = horizonCb.checked? horizonSlider.value : 0.0;
         const roll = horizonCb.checked? horizonRollSlider.value : 0.0;
         controller.set_horizon_lock(lockAmount, roll);
+        controller.set_horizon_gravity_reference(horizonCb.checked? gravityReferenceSlider.value / 100.0 : 0.0);
         controller.set_use_gravity_vectors(useGravityVectors.checked);
         controller.set_horizon_lock_integration_method(integrationMethod.currentIndex);
     }
@@ -161,6 +214,7 @@ MenuItem {
                 maxValues.maxYaw   = max_angles[1];
                 maxValues.maxRoll  = max_angles[2];
                 maxValues.maxZoom  = min_fov > 0.0001? (100 / min_fov) : min_fov;
+                staticZoomExceeding.frames = controller.get_static_zoom_exceeding_frames();
                 const status = controller.get_smoothing_status();
                 // Clear current params
                 for (let i = smoothingStatus.children.length; i > 0; --i) {
@@ -208,6 +262,16 @@ MenuItem {
         function onRolling_shutter_estimated(rolling_shutter: real): void {
             root.setFrameReadoutTime(rolling_shutter, 0);
         }
+        function onResidual_wobble_estimated(report: string): void {
+            const r = JSON.parse(report);
+            if (!r.frames) {
+                residualWobbleCb.status = "";
+                messageBox(Modal.Warning, qsTr("Not enough features were found to estimate the residual wobble."), [ { text: qsTr("Ok") } ]);
+                return;
+            }
+            residualWobbleCb.status = qsTr("%1 frames corrected, average %2°, max %3°").arg(r.frames).arg(r.rms_deg.toFixed(3)).arg(r.max_deg.toFixed(3));
+            residualWobbleCb.checked = true;
+        }
     }
 
     Component.onCompleted: {
@@ -215,6 +279,9 @@ MenuItem {
         QT_TRANSLATE_NOOP("Popup", "Default"),
         QT_TRANSLATE_NOOP("Popup", "Plain 3D");
         QT_TRANSLATE_NOOP("Popup", "Fixed camera");
+        QT_TRANSLATE_NOOP("Popup", "Per axis with horizon lock");
+        QT_TRANSLATE_NOOP("Popup", "Velocity adaptive");
+        QT_TRANSLATE_NOOP("Popup", "Tripod");
 
         QT_TRANSLATE_NOOP("Stabilization", "Pitch smoothness");
         QT_TRANSLATE_NOOP("Stabilization", "Yaw smoothness");
@@ -231,6 +298,13 @@ MenuItem {
         QT_TRANSLATE_NOOP("Stabilization", "Yaw angle");
         QT_TRANSLATE_NOOP("Stabilization", "Pitch angle");
         QT_TRANSLATE_NOOP("Stabilization", "Roll angle");
+        QT_TRANSLATE_NOOP("Stabilization", "Lock roll to the horizon");
+        QT_TRANSLATE_NOOP("Stabilization", "Use the accelerometer for the horizon");
+        QT_TRANSLATE_NOOP("Stabilization", "Responsiveness threshold");
+        QT_TRANSLATE_NOOP("Stabilization", "Adaptation speed");
+        QT_TRANSLATE_NOOP("Stabilization", "Re-centering time");
+        QT_TRANSLATE_NOOP("Stabilization", "Lock start");
+        QT_TRANSLATE_NOOP("Stabilization", "Re-center only between keyframes");
     }
 
     ComboBox {
@@ -375,6 +449,23 @@ MenuItem {
                 onValueChanged: Qt.callLater(updateHorizonLock);
             }
         }
+        Label {
+            width: parent.width;
+            spacing: 2 * dpiScale;
+            text: qsTr("Accelerometer drift correction");
+            visible: !useGravityVectors.checked;
+            SliderWithField {
+                id: gravityReferenceSlider;
+                width: parent.width;
+                from: 0;
+                to: 100;
+                value: 0;
+                defaultValue: 0;
+                unit: qsTr("%");
+                precision: 0;
+                onValueChanged: Qt.callLater(updateHorizonLock);
+            }
+        }
         CheckBox {
             id: useGravityVectors;
             text: qsTr("Use gravity vectors");
@@ -408,6 +499,49 @@ MenuItem {
         }
     }
 
+    CheckBoxWithContent {
+        id: axisLockCb;
+        text: qsTr("Lock axes");
+        cb.tooltip: qsTr("Replaces the chosen axes of the smoothed orientation, with any smoothing algorithm");
+
+        cb.onCheckedChanged: Qt.callLater(updateAxisLock);
+
+        Label {
+            text: qsTr("Pitch"); position: Label.LeftPosition;
+            Row {
+                width: parent.width;
+                CheckBox { id: axisLockPitch; text: ""; onCheckedChanged: Qt.callLater(updateAxisLock); }
+                SliderWithField { id: axisLockPitchTarget; enabled: axisLockPitch.checked; precision: 1; value: 0; defaultValue: 0; from: -90; to: 90; unit: "°"; width: parent.width - axisLockPitch.width; keyframe: "AxisLockPitch";
+                                  onValueChanged: Qt.callLater(updateAxisLock); }
+            }
+        }
+        Label {
+            text: qsTr("Yaw"); position: Label.LeftPosition;
+            Row {
+                width: parent.width;
+                CheckBox { id: axisLockYaw; text: ""; onCheckedChanged: Qt.callLater(updateAxisLock); }
+                SliderWithField { id: axisLockYawTarget; enabled: axisLockYaw.checked; precision: 1; value: 0; defaultValue: 0; from: -180; to: 180; unit: "°"; width: parent.width - axisLockYaw.width; keyframe: "AxisLockYaw";
+                                  onValueChanged: Qt.callLater(updateAxisLock); }
+            }
+        }
+        Label {
+            text: qsTr("Roll"); position: Label.LeftPosition;
+            Row {
+                width: parent.width;
+                CheckBox { id: axisLockRoll; text: ""; checked: true; onCheckedChanged: Qt.callLater(updateAxisLock); }
+                SliderWithField { id: axisLockRollTarget; enabled: axisLockRoll.checked; precision: 1; value: 0; defaultValue: 0; from: -180; to: 180; unit: "°"; width: parent.width - axisLockRoll.width; keyframe: "AxisLockRoll";
+                                  onValueChanged: Qt.callLater(updateAxisLock); }
+            }
+        }
+        CheckBox {
+            id: axisLockHorizon;
+            text: qsTr("Angles relative to the horizon");
+            tooltip: qsTr("Otherwise the angles are relative to the camera orientation at the start");
+            checked: true;
+            onCheckedChanged: Qt.callLater(updateAxisLock);
+        }
+    }
+
     InfoMessageSmall {
         id: maxValues;
         property real maxPitch: 0;
@@ -452,6 +586,37 @@ MenuItem {
             }
         }
     }
+    Label {
+        text: qsTr("Covered frames");
+        visible: croppingMode.currentIndex == 2;
+        position: Label.LeftPosition;
+        tooltip: qsTr("The static zoom covers this percentage of frames. The remaining frames with the most motion will show black corners, but the zoom will be smaller.");
+        SliderWithField {
+            id: staticZoomPercentile;
+            value: 100;
+            defaultValue: 100;
+            from: 90;
+            to: 100;
+            unit: "%";
+            precision: 1;
+            width: parent.width;
+            onValueChanged: controller.static_zoom_percentile = value;
+        }
+    }
+    InfoMessageSmall {
+        id: staticZoomExceeding;
+        property var frames: [];
+        show: croppingMode.currentIndex == 2 && frames.length > 0;
+        text: qsTr("%1 frames need more zoom, by up to %2%").arg(frames.length).arg(((frames.reduce((a, x) => Math.max(a, x.excess), 1.0) - 1.0) * 100).toFixed(1));
+    }
+    CheckBox {
+        id: limitToZoomBudget;
+        text: qsTr("Limit correction to the frame");
+        tooltip: qsTr("Reduce the stabilization where it would show the black corners at the current FOV.");
+        checked: true;
+        visible: croppingMode.currentIndex == 0;
+        onCheckedChanged: controller.set_limit_to_zoom_budget(checked);
+    }
     Label {
         text: qsTr("Zoom limit");
         visible: smoothingMethod.currentIndex == 1 || smoothingMethod.currentIndex == 2;
@@ -488,6 +653,24 @@ MenuItem {
             onKeyframesEnabledChanged: Qt.callLater(zoomingMethod.adjustMethod);
         }
     }
+    Label {
+        text: qsTr("Zoom look-ahead");
+        visible: croppingMode.currentIndex == 1;
+        position: Label.LeftPosition;
+        tooltip: qsTr("How long before a fast motion the zoom can start.\n0 is automatic.");
+        SliderWithField {
+            id: adaptiveZoomLookAhead;
+            value: 0;
+            defaultValue: 0;
+            from: 0;
+            to: 5;
+            unit: qsTr("s");
+            precision: 2;
+            width: parent.width;
+            keyframe: "ZoomingLookAhead";
+            onValueChanged: controller.adaptive_zoom_look_ahead = value;
+        }
+    }
 
     Label {
         text: qsTr("Lens correction");
@@ -558,6 +741,102 @@ MenuItem {
                     onDirectionChanged: controller.frame_readout_direction = readoutDirection.getInt();
                 }
             }
+            Label {
+                text: qsTr("Correction strength");
+                SliderWithField {
+                    id: rollingShutterStrength;
+                    from: 0.0;
+                    to: 200.0;
+                    value: 1.0;
+                    unit: "%";
+                    defaultValue: 100.0;
+                    precision: 0;
+                    slider.stepSize: 1;
+                    width: parent.width;
+                    keyframe: "RollingShutterStrength";
+                    scaler: 100.0;
+                    onValueChanged: controller.rolling_shutter_strength = value;
+                }
+            }
+            CheckBoxWithContent {
+                id: readoutOverrideCb;
+                text: qsTr("Override the readout time");
+                cb.tooltip: qsTr("Use this readout time and direction instead of the ones from the lens profile or the camera metadata");
+                function update(): void {
+                    if (cb.checked) controller.set_frame_readout_override(readoutOverride.value, readoutOverrideDirection.getInt());
+                    else            controller.set_frame_readout_override(-1, -1);
+                }
+                cb.onCheckedChanged: update();
+
+                Label {
+                    text: qsTr("Frame readout time");
+                    SliderWithField {
+                        id: readoutOverride;
+                        defaultValue: 0;
+                        from: 0.0;
+                        to: 1000 / Math.max(1, window.videoArea.timeline.scaledFps);
+                        width: parent.width;
+                        unit: qsTr("ms");
+                        precision: 2;
+                        onValueChanged: readoutOverrideCb.update();
+                    }
+                    ReadoutDirection {
+                        id: readoutOverrideDirection;
+                        onDirectionChanged: readoutOverrideCb.update();
+                    }
+                }
+            }
+        }
+
+        CheckBoxWithContent {
+            id: residualWobbleCb;
+            text: qsTr("Residual wobble removal");
+            cb.tooltip: qsTr("Removes the wobble which remains after the stabilization, using optical flow of the stabilized video. Analyze the video again after changing the stabilization settings.");
+            property string status: "";
+            cb.onCheckedChanged: controller.residual_correction_strength = cb.checked? residualStrength.value : 0.0;
+
+            LinkButton {
+                anchors.horizontalCenter: parent.horizontalCenter;
+                text: qsTr("Analyze the video");
+                enabled: window.videoArea.vid.loaded && controller.gyro_loaded && !controller.sync_in_progress;
+                tooltip: qsTr("Tracks the whole video, or the trimmed ranges. This can take a while.");
+                onClicked: {
+                    residualWobbleCb.status = "";
+                    controller.start_autosync(window.videoArea.timeline.getTrimRanges().flat().join(";"), window.sync.getSettingsJson(), "residual_wobble");
+                }
+            }
+            Label {
+                text: qsTr("Correction strength");
+                SliderWithField {
+                    id: residualStrength;
+                    from: 0.0;
+                    to: 200.0;
+                    value: 1.0;
+                    unit: "%";
+                    defaultValue: 100.0;
+                    precision: 0;
+                    slider.stepSize: 1;
+                    width: parent.width;
+                    keyframe: "ResidualCorrectionStrength";
+                    scaler: 100.0;
+                    onValueChanged: if (residualWobbleCb.checked) controller.residual_correction_strength = value;
+                }
+            }
+            BasicText {
+                width: parent.width;
+                wrapMode: Text.WordWrap;
+                visible: text.length > 0;
+                text: residualWobbleCb.status;
+            }
+            LinkButton {
+                anchors.horizontalCenter: parent.horizontalCenter;
+                text: qsTr("Clear the corrections");
+                onClicked: {
+                    controller.clear_residual_corrections();
+                    residualWobbleCb.status = "";
+                    residualWobbleCb.checked = false;
+                }
+            }
         }
 
         Label {
@@ -726,6 +1005,28 @@ MenuItem {
                 }
             }
         }
+        Label {
+            text: qsTr("Horizon compensation");
+            Column {
+                width: parent.width;
+                Label {
+                    text: qsTr("Pitch"); position: Label.LeftPosition;
+                    SliderWithField { id: horizonCompensationPitch; precision: 2; value: 0; defaultValue: 0; from: -30; to: 30; unit: "°"; width: parent.width; keyframe: "HorizonCompensationPitch";
+                                      onValueChanged: controller.horizon_compensation_pitch = value; }
+                }
+                Label {
+                    text: qsTr("Roll"); position: Label.LeftPosition;
+                    SliderWithField { id: horizonCompensationRoll; precision: 2; value: 0; defaultValue: 0; from: -30; to: 30; unit: "°"; width: parent.width; keyframe: "HorizonCompensationRoll";
+                                      onValueChanged: controller.horizon_compensation_roll = value; }
+                }
+                Label {
+                    text: qsTr("At FOV"); position: Label.LeftPosition;
+                    tooltip: qsTr("The pitch is set at this FOV and adjusted with the zoom, so the horizon stays in place");
+                    SliderWithField { id: horizonCompensationFov; precision: 2; value: 1; defaultValue: 1; from: 0.1; to: 3; width: parent.width;
+                                      onValueChanged: controller.horizon_compensation_fov = value; }
+                }
+            }
+        }
         Label {
             text: qsTr("Additional 3D translation");
             visible: false;
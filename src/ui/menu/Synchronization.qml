@@ -40,6 +40,7 @@ MenuItem {
     property alias poseMethod: poseMethod;
     property var customSyncTimestamps: [];
     property var additionalSyncTimestamps: [];
+    property bool motionDataChecked: false;
 
     function loadGyroflow(obj: var): void {
         const o = obj.synchronization || { };
@@ -58,6 +59,13 @@ MenuItem {
             if (o.hasOwnProperty("auto_sync_points")) experimentalAutoSyncPoints.checked    = !!o.auto_sync_points;
             if (o.hasOwnProperty("do_autosync") && o.do_autosync) autosyncTimer.doRun = true;
         }
+        if (obj.hasOwnProperty("offset_interpolation")) {
+            const idx = offsetInterpolation.modes.indexOf(obj.offset_interpolation);
+            if (idx > -1) offsetInterpolation.currentIndex = idx;
+        } else if (obj.hasOwnProperty("videofile")) {
+            // Projects saved before it was added are linear
+            offsetInterpolation.currentIndex = 1;
+        }
     }
     Timer {
         id: autosyncTimer;
@@ -67,7 +75,7 @@ MenuItem {
         onTriggered: {
             doRun = false;
             if (controller.offsets_model.rowCount() == 0 && !window.motionData.hasAccurateTimestamps)
-                autosync.doSync();
+                autosync.checkAndSync();
         }
     }
     function getSettings(): var {
@@ -131,7 +139,12 @@ MenuItem {
     }
     Connections {
         target: controller;
+        function onOffsets_updated(): void {
+            const drift = controller.get_offset_drift();
+            driftInfo.fit = drift? JSON.parse(drift) : null;
+        }
         function onTelemetry_loaded(is_main_video: bool, filename: string, camera: string, additional_data: var): void {
+            sync.motionDataChecked = false;
             sync.additionalSyncTimestamps = [];
             if (additional_data.additional_sync_points) {
                 for (const x of additional_data.additional_sync_points.split(";")) {
@@ -139,6 +152,25 @@ MenuItem {
                 }
             }
         }
+        function onMotion_data_consistency_checked(report: string): void {
+            sync.motionDataChecked = true;
+            const r = report? JSON.parse(report) : null;
+            if (!r || !r.mismatch) {
+                autosync.doSync();
+                return;
+            }
+            let buttons = [];
+            if (r.suggested_offset_ms !== null && r.suggested_offset_ms !== undefined) {
+                const offset = r.suggested_offset_ms / 1000.0;
+                buttons.push({ text: qsTr("Sync with offset %1 s").arg(offset.toFixed(1)), accent: true, clicked: function() {
+                    initialOffset.value = offset;
+                    autosync.doSync();
+                }});
+            }
+            buttons.push({ text: qsTr("Sync anyway"), clicked: function() { autosync.doSync(); } });
+            buttons.push({ text: qsTr("Cancel") });
+            messageBox(Modal.Warning, qsTr("The motion data may not belong to this video, the synchronization will most likely fail or give wrong results.") + "<br><br>" + r.reasons.join("<br>"), buttons);
+        }
     }
 
     Button {
@@ -183,16 +215,26 @@ MenuItem {
             }
             controller.start_autosync(sync_points, sync.getSettingsJson(), "synchronize");
         }
+        // Checks once per loaded motion data if it matches the video, the sync starts when it's done
+        function checkAndSync(): void {
+            if (sync.motionDataChecked || !controller.gyro_loaded) {
+                doSync();
+                return;
+            }
+            let points = [];
+            for (let i = 0; i < 30; ++i) points.push((i + 0.5) / 30);
+            controller.start_autosync(points.join(";"), sync.getSettingsJson(), "check_consistency");
+        }
         onClicked: {
             if (!controller.lens_loaded) {
                 messageBox(Modal.Warning, qsTr("Lens profile is not loaded, synchronization will most likely give wrong results. Are you sure you want to continue?"), [
                     { text: qsTr("Yes"), clicked: function() {
-                        doSync();
+                        checkAndSync();
                     }},
                     { text: qsTr("No"), accent: true },
                 ]);
             } else {
-                doSync();
+                checkAndSync();
             }
         }
 
@@ -378,6 +420,35 @@ MenuItem {
                 tooltip: tooltips[currentIndex];
             }
         }
+        Label {
+            text: qsTr("Offset interpolation");
+            position: Label.LeftPosition;
+
+            ComboBox {
+                id: offsetInterpolation;
+                model: [QT_TRANSLATE_NOOP("Popup", "Step"), QT_TRANSLATE_NOOP("Popup", "Linear"), QT_TRANSLATE_NOOP("Popup", "Spline"), QT_TRANSLATE_NOOP("Popup", "Linear drift fit")];
+                property var modes: ["Step", "Linear", "Spline", "DriftFit"];
+                font.pixelSize: 12 * dpiScale;
+                width: parent.width;
+                currentIndex: 1;
+                property var tooltips: ([
+                    qsTr("Keep the offset of the previous sync point until the next one."),
+                    qsTr("Interpolate linearly between the sync points."),
+                    qsTr("Smooth curve through the sync points, without overshooting between them."),
+                    qsTr("Fit a single line through all sync points.\nBest for cameras where the gyro clock drifts at a constant rate, the noise of the individual sync points is averaged out.")
+                ]);
+                tooltip: tooltips[currentIndex];
+                onCurrentIndexChanged: controller.set_offset_interpolation(modes[currentIndex]);
+            }
+        }
+        BasicText {
+            id: driftInfo;
+            property var fit: null;
+            width: parent.width;
+            wrapMode: Text.WordWrap;
+            visible: !!fit;
+            text: fit? qsTr("Clock drift: %1 ppm, residuals: %2 ms RMS, %3 ms max").arg(fit.drift_ppm.toFixed(1)).arg(fit.rms_ms.toFixed(2)).arg(fit.max_ms.toFixed(2)) : "";
+        }
         CheckBoxWithContent {
             id: lpfcb;
             text: qsTr("Low pass filter");
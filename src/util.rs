@@ -248,22 +248,27 @@ pub fn init_logging() {
         .fold(ConfigBuilder::new(), |mut cfg, x| { cfg.add_filter_ignore_str(x); cfg })
         .build();
 
+    // Levels are filtered by gyroflow_core::logging, so the sinks accept everything
+    let mut sinks: Vec<Box<dyn ::log::Log>> = Vec::new();
+
     #[cfg(target_os = "android")]
-    WriteLogger::init(LevelFilter::Debug, log_config, crate::util::AndroidLog::default()).unwrap();
+    sinks.push(WriteLogger::new(LevelFilter::Trace, log_config, crate::util::AndroidLog::default()));
 
     #[cfg(not(target_os = "android"))]
     {
+        sinks.push(TermLogger::new(LevelFilter::Trace, log_config, TerminalMode::Mixed, ColorChoice::Auto));
         let exe_loc = gyroflow_core::settings::data_dir().join("gyroflow.log");
         if let Ok(file_log) = std::fs::File::create(exe_loc) {
-            let _ = CombinedLogger::init(vec![
-                TermLogger::new(LevelFilter::Debug, log_config, TerminalMode::Mixed, ColorChoice::Auto),
-                WriteLogger::new(LevelFilter::Debug, file_log_config, file_log)
-            ]);
-        } else {
-            let _ = TermLogger::init(LevelFilter::Debug, log_config, TerminalMode::Mixed, ColorChoice::Auto);
+            sinks.push(WriteLogger::new(LevelFilter::Trace, file_log_config, file_log));
         }
     }
 
+    let levels = gyroflow_core::settings::get_str("logLevels", "");
+    let _ = gyroflow_core::logging::init(sinks, if levels.trim().is_empty() { gyroflow_core::logging::DEFAULT_LEVELS } else { &levels });
+    if gyroflow_core::settings::get_bool("logJson", false) {
+        let _ = gyroflow_core::logging::set_json_file(Some(&gyroflow_core::settings::data_dir().join("gyroflow.log.jsonl")));
+    }
+
     qmetaobject::log::init_qt_to_rust();
 
     qml_video_rs::video_item::MDKVideoItem::setLogHandler(|level: i32, text: &str| {
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Shared by the tests which drive the app over the control server socket
+#![allow(dead_code)]
+
+use serde_json::{ json, Value };
+use std::io::{ BufRead, BufReader, Write };
+use std::net::TcpStream;
+use std::path::{ Path, PathBuf };
+use std::process::{ Child, Command, Stdio };
+
+pub struct Client {
+    pub reader: BufReader<TcpStream>,
+    pub writer: TcpStream,
+    pub next_id: u64,
+    pub notifications: Vec<Value>,
+}
+impl Client {
+    pub fn request(&mut self, method: &str, params: Value) -> Result<Value, Value> {
+        self.next_id += 1;
+        writeln!(self.writer, "{}", json!({ "jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params })).unwrap();
+        loop {
+            let msg = self.read();
+            if msg.get("id") == Some(&json!(self.next_id)) {
+                return match msg.get("error") { Some(e) => Err(e.clone()), None => Ok(msg["result"].clone()) };
+            }
+            self.notifications.push(msg);
+        }
+    }
+    pub fn read(&mut self) -> Value {
+        let mut line = String::new();
+        assert!(self.reader.read_line(&mut line).unwrap() > 0, "Connection closed");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// Renders the queue and waits until `job_id` is finished
+    pub fn render(&mut self, job_id: &Value) {
+        self.request("render.start", json!({})).unwrap();
+        loop {
+            let msg = self.read();
+            assert_ne!(msg["method"], "render.error", "{msg}");
+            if msg["method"] == "render.progress" && msg["params"]["job_id"] == *job_id && msg["params"]["finished"] == true {
+                break;
+            }
+        }
+        let status = self.request("render.status", json!({})).unwrap();
+        assert_eq!(status["jobs"][0]["error"], "");
+    }
+}
+
+/// Starts the app with the control server on a free port, returns the process and a client connected to it
+pub fn start_server(token: &str) -> (Child, Client) {
+    start_server_with_temp_dir(token, &std::env::temp_dir())
+}
+/// Same as `start_server`, with `temp` as the temporary directory of the app
+pub fn start_server_with_temp_dir(token: &str, temp: &Path) -> (Child, Client) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_gyroflow"))
+        .args(["--server", "0", "--server-token", token])
+        .envs(["TMPDIR", "TMP", "TEMP"].map(|x| (x, temp)))
+        .stdout(Stdio::piped())
+        .spawn().unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let addr = loop {
+        let mut line = String::new();
+        assert!(stdout.read_line(&mut line).unwrap() > 0, "Server exited");
+        if let Some(x) = line.strip_prefix("Control server listening on ") {
+            break x.split(',').next().unwrap().trim().to_string();
+        }
+    };
+    std::thread::spawn(move || { for _ in stdout.lines() { } });
+
+    let stream = TcpStream::connect(&addr).unwrap();
+    (server, Client { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream, next_id: 0, notifications: Vec::new() })
+}
+
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gyroflow_{name}_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn resource(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("resources").join(name)
+}
+
+// Slow pan with a bit of shake, at 200 Hz
+pub fn write_gyro_log(path: &Path, duration_s: f64) {
+    let mut gcsv = String::from("GYROFLOW IMU LOG\nversion,1.3\nid,control_server_test\norientation,XYZ\ntscale,0.001\ngscale,0.017453292519943295\nascale,1.0\nt,gx,gy,gz,ax,ay,az\n");
+    for i in 0..(duration_s * 200.0) as usize {
+        let t = i as f64 / 200.0;
+        gcsv.push_str(&format!("{},{:.6},{:.6},{:.6},0,0,1\n", i * 5, (t * 11.0).sin() * 8.0, 5.0 + (t * 7.0).cos() * 3.0, (t * 13.0).sin() * 2.0));
+    }
+    std::fs::write(path, gcsv).unwrap();
+}
+
+pub fn has_ffprobe() -> bool {
+    Command::new("ffprobe").arg("-version").output().is_ok_and(|x| x.status.success())
+}
+/// Streams and format of the file from `ffprobe`, see `has_ffprobe`
+pub fn ffprobe(path: &Path) -> Value {
+    let out = Command::new("ffprobe")
+        .args(["-v", "error", "-print_format", "json", "-show_format", "-show_streams"])
+        .arg(path)
+        .output().unwrap();
+    assert!(out.status.success(), "ffprobe failed: {}", String::from_utf8_lossy(&out.stderr));
+    serde_json::from_slice(&out.stdout).unwrap()
+}
+pub fn stream<'a>(probe: &'a Value, codec_type: &str) -> &'a Value {
+    probe["streams"].as_array().unwrap().iter().find(|x| x["codec_type"] == codec_type).unwrap_or_else(|| panic!("No {codec_type} stream in {probe}"))
+}
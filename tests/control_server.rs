@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Scripts a full open -> sync -> render of a short clip over the control server socket
+#![cfg(feature = "control-server")]
+
+mod common;
+
+use serde_json::json;
+use gyroflow_core::filesystem::path_to_url;
+use common::*;
+
+#[test]
+fn open_sync_render() {
+    let dir = temp_dir("control_server");
+    let gyro_path = dir.join("clip.gcsv");
+    write_gyro_log(&gyro_path, 4.5);
+    let clip = resource("comparison1.mp4");
+
+    let (mut server, mut client) = start_server("test-token");
+
+    assert_eq!(client.request("render.status", json!({})).unwrap_err()["code"], -32001);
+    assert!(client.request("auth", json!({ "token": "wrong" })).is_err());
+    assert_eq!(client.request("auth", json!({ "token": "test-token" })).unwrap(), true);
+
+    let job_id = client.request("project.load", json!({
+        "url": path_to_url(&clip.to_string_lossy()),
+        "gyro_url": path_to_url(&gyro_path.to_string_lossy()),
+        "output": { "codec": "H.264/AVC", "use_gpu": false, "audio": false, "output_folder": path_to_url(&format!("{}/", dir.to_string_lossy())), "output_filename": "clip_stabilized.mp4" },
+    })).unwrap()["job_id"].clone();
+
+    client.request("params.set", json!({ "job_id": job_id, "data": { "stabilization": { "fov": 1.1 } } })).unwrap();
+    assert!((client.request("params.get", json!({ "job_id": job_id, "path": "/stabilization/fov" })).unwrap().as_f64().unwrap() - 1.1).abs() < 1e-6);
+    client.request("keyframes.set", json!({ "job_id": job_id, "type": "Fov", "timestamp_us": 1_000_000, "value": 1.2, "easing": "EaseIn" })).unwrap();
+    assert_eq!(client.request("keyframes.get", json!({ "job_id": job_id, "type": "Fov" })).unwrap()["1000000"]["value"], 1.2);
+    client.request("keyframes.remove", json!({ "job_id": job_id, "type": "Fov", "timestamp_us": 1_000_000 })).unwrap();
+    assert_eq!(client.request("unknown.method", json!({})).unwrap_err()["code"], -32601);
+
+    let invalid = client.request("sync.start", json!({ "job_id": job_id, "params": { "max_sync_points": "two" } })).unwrap_err();
+    assert_eq!(invalid["code"], -32602, "{invalid}");
+
+    let sync = client.request("sync.start", json!({ "job_id": job_id, "params": { "max_sync_points": 2, "time_per_syncpoint": 0.5, "search_size": 1.0, "processing_resolution": 240 } })).unwrap();
+    let offsets = sync["offsets"].as_array().unwrap();
+    assert!(!offsets.is_empty() && offsets.len() <= 2, "{sync}");
+    for x in offsets {
+        // [timestamp in us, offset in ms], within the clip and the search range around the initial offset of 0
+        let (ts, offset) = (x[0].as_i64().unwrap(), x[1].as_f64().unwrap());
+        assert!((0..4_500_000).contains(&ts), "{sync}");
+        assert!(offset.abs() <= 1000.0, "{sync}");
+    }
+    // The same as in the project
+    let project_offsets = client.request("params.get", json!({ "job_id": job_id, "path": "/offsets" })).unwrap();
+    assert_eq!(project_offsets.as_object().unwrap().len(), offsets.len());
+    for x in offsets {
+        let saved = project_offsets[x[0].as_i64().unwrap().to_string()].as_f64().unwrap();
+        assert!((saved - x[1].as_f64().unwrap()).abs() < 1e-9, "{project_offsets} vs {sync}");
+    }
+    assert!(client.notifications.iter().any(|x| x["method"] == "sync.progress" && x["params"]["job_id"] == job_id));
+
+    client.render(&job_id);
+    assert!(std::fs::metadata(dir.join("clip_stabilized.mp4")).unwrap().len() > 0);
+
+    client.request("shutdown", json!({})).unwrap();
+    assert!(server.wait().unwrap().success());
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2024 Adrian <adrian.eddy at gmail>
+
+// Renders a short clip with the export settings which need something from the muxer or the encoder, and checks the output with ffprobe.
+// Skipped when ffprobe is not installed
+#![cfg(feature = "control-server")]
+
+mod common;
+
+use serde_json::{ json, Value };
+use std::path::{ Path, PathBuf };
+use std::process::Command;
+use std::sync::{ Arc, atomic::AtomicBool };
+use gyroflow_core::filesystem::path_to_url;
+use gyroflow_core::gyro_source::{ GyroSource, FileLoadOptions, TimeIMU };
+use common::*;
+
+// `synthetic_gyro` loads a generated gyro log instead of the telemetry of the clip. `params` is set on the project before rendering
+fn render_clip(name: &str, clip: &Path, synthetic_gyro: bool, params: Value, output: Value) -> Option<(PathBuf, Value)> {
+    if !has_ffprobe() {
+        eprintln!("ffprobe not found, skipping");
+        return None;
+    }
+    let dir = temp_dir(name);
+    let gyro_path = dir.join("clip.gcsv");
+    let gyro_url = if synthetic_gyro {
+        write_gyro_log(&gyro_path, 4.5);
+        path_to_url(&gyro_path.to_string_lossy())
+    } else {
+        String::new()
+    };
+
+    // Own temporary directory, so the files of the render can be checked
+    let tmp = dir.join("tmp");
+    std::fs::create_dir_all(&tmp).unwrap();
+    let (mut server, mut client) = start_server_with_temp_dir("test-token", &tmp);
+    client.request("auth", json!({ "token": "test-token" })).unwrap();
+
+    let mut output = output;
+    output["output_folder"] = json!(path_to_url(&format!("{}/", dir.to_string_lossy())));
+    let filename = output["output_filename"].as_str().unwrap().to_string();
+    let job_id = client.request("project.load", json!({
+        "url": path_to_url(&clip.to_string_lossy()),
+        "gyro_url": gyro_url,
+        "output": output,
+    })).unwrap()["job_id"].clone();
+    if params.is_object() {
+        client.request("params.set", json!({ "job_id": job_id, "data": params })).unwrap();
+    }
+    client.render(&job_id);
+
+    let path = dir.join(&filename);
+    let probe = ffprobe(&path);
+
+    client.request("shutdown", json!({})).unwrap();
+    assert!(server.wait().unwrap().success());
+    Some((path, probe))
+}
+
+fn imu_samples(path: &Path) -> Vec<TimeIMU> {
+    let md = GyroSource::parse_telemetry_file(&path_to_url(&path.to_string_lossy()), &FileLoadOptions::default(), (1920, 1080), 30.0, |_| (), Arc::new(AtomicBool::new(false))).unwrap();
+    md.raw_imu
+}
+
+#[test]
+fn dnxhr_mxf() {
+    let Some((path, probe)) = render_clip("dnxhr_mxf", &resource("comparison1.mp4"), true, Value::Null, json!({
+        "codec": "DNxHD", "codec_options": "DNxHR LB", "container": "MXF", "use_gpu": false, "audio": true,
+        "output_width": 1280, "output_height": 720, "output_filename": "clip_stabilized.mxf"
+    })) else { return; };
+
+    assert!(probe["format"]["format_name"].as_str().unwrap().contains("mxf"), "{probe}");
+    let video = stream(&probe, "video");
+    assert_eq!(video["codec_name"], "dnxhd");
+    assert_eq!(video["color_range"], "tv");
+    assert_eq!(video["field_order"], "progressive");
+    // The AAC of the source can't be muxed into MXF
+    assert_eq!(stream(&probe, "audio")["codec_name"], "pcm_s24le");
+    let _ = std::fs::remove_dir_all(path.parent().unwrap());
+}
+
+// tests/data/gpmf.mov is a 4.48 s GPMF track of a HERO8 (200 Hz gyro and accelerometer, one payload per second) without video,
+// it's muxed with the video of comparison1.mp4 with the ffmpeg cli. Set GYROFLOW_TEST_GOPRO_CLIP to test a real clip instead
+#[test]
+fn gpmf_passthrough() {
+    let clip = match std::env::var("GYROFLOW_TEST_GOPRO_CLIP") {
+        Ok(clip) => PathBuf::from(clip),
+        Err(_) => {
+            let clip = temp_dir("gpmf_source").join("gopro.mov");
+            let muxed = Command::new("ffmpeg")
+                .args(["-y", "-v", "error", "-copy_unknown", "-i"]).arg(resource("comparison1.mp4"))
+                .arg("-i").arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/gpmf.mov"))
+                .args(["-map", "0:v", "-map", "1:d", "-c", "copy", "-tag:d", "gpmd", "-movflags", "+faststart"])
+                .arg(&clip)
+                .status().is_ok_and(|x| x.success());
+            if !muxed {
+                eprintln!("ffmpeg not found, skipping");
+                return;
+            }
+            clip
+        }
+    };
+    let (trim_start, trim_end) = (1000.0, 3000.0);
+    let Some((path, probe)) = render_clip("gpmf_passthrough", &clip, false, json!({ "trim_ranges_ms": [[trim_start, trim_end]] }), json!({
+        "codec": "H.264/AVC", "use_gpu": false, "audio": false, "copy_telemetry": true,
+        "output_width": 1280, "output_height": 720, "output_filename": "clip_stabilized.mov"
+    })) else { return; };
+
+    assert!(probe["streams"].as_array().unwrap().iter().any(|x| x["codec_type"] == "data" && x["codec_tag_string"] == "gpmd"), "{probe}");
+    // Without audio the audio track of the source must not end up in the telemetry track
+    assert!(!probe["streams"].as_array().unwrap().iter().any(|x| x["codec_type"] == "audio"), "{probe}");
+
+    // The copied samples are the ones of the trim range, on the timeline of the output.
+    // The packets starting before the range are dropped, so the output can start up to one packet (~1 s) later
+    let source = imu_samples(&clip);
+    let output = imu_samples(&path);
+    assert!(!output.is_empty());
+    assert!(output[0].timestamp_ms >= -1.0 && output[0].timestamp_ms < 1100.0, "{}", output[0].timestamp_ms);
+    assert!(output.last().unwrap().timestamp_ms < trim_end - trim_start + 1100.0);
+    for sample in output.iter().step_by(50) {
+        let ts = sample.timestamp_ms + trim_start;
+        let nearest = source.iter().min_by(|a, b| (a.timestamp_ms - ts).abs().total_cmp(&(b.timestamp_ms - ts).abs())).unwrap();
+        // Within a video frame, the first video frame of the range isn't exactly at the trim start
+        assert!((nearest.timestamp_ms - ts).abs() < 40.0, "{} vs {}", nearest.timestamp_ms, ts);
+        let matching = source.iter().filter(|x| (x.timestamp_ms - ts).abs() < 40.0).any(|x| x.gyro == sample.gyro && x.accl == sample.accl);
+        assert!(matching, "No source sample matches the output sample at {} ms", sample.timestamp_ms);
+    }
+    let _ = std::fs::remove_dir_all(path.parent().unwrap());
+}
+
+// 10-bit HLG source, generated with the ffmpeg cli
+#[test]
+fn hlg_metadata() {
+    let dir = temp_dir("hlg_source");
+    let clip = dir.join("hlg.mp4");
+    let generated = Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-f", "lavfi", "-i", "testsrc2=size=1280x720:rate=30:duration=4.5",
+               "-pix_fmt", "yuv420p10le", "-c:v", "libx265", "-x265-params", "log-level=error",
+               "-color_primaries", "bt2020", "-color_trc", "arib-std-b67", "-colorspace", "bt2020nc", "-color_range", "tv"])
+        .arg(&clip)
+        .status().is_ok_and(|x| x.success());
+    if !generated {
+        eprintln!("ffmpeg with libx265 not found, skipping");
+        return;
+    }
+
+    let Some((path, probe)) = render_clip("hlg_metadata", &clip, true, Value::Null, json!({
+        "codec": "H.265/HEVC", "use_gpu": false, "audio": false, "output_filename": "clip_stabilized.mp4"
+    })) else { return; };
+
+    let video = stream(&probe, "video");
+    assert_eq!(video["pix_fmt"], "yuv420p10le", "{probe}");
+    assert_eq!(video["color_transfer"], "arib-std-b67");
+    assert_eq!(video["color_primaries"], "bt2020");
+    assert_eq!(video["color_space"], "bt2020nc");
+    assert_eq!(video["color_range"], "tv");
+    let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn two_pass() {
+    let Some((path, probe)) = render_clip("two_pass", &resource("comparison1.mp4"), true, Value::Null, json!({
+        "codec": "H.264/AVC", "use_gpu": false, "audio": false, "rate_control": "2-pass", "bitrate": 4,
+        "output_width": 1280, "output_height": 720, "output_filename": "clip_stabilized.mp4"
+    })) else { return; };
+
+    // The first pass doesn't write anything next to the output
+    let files = std::fs::read_dir(path.parent().unwrap()).unwrap().map(|x| x.unwrap().file_name().to_string_lossy().to_string()).collect::<Vec<_>>();
+    assert!(files.iter().all(|x| x == "clip_stabilized.mp4" || x.ends_with(".gcsv") || x == "tmp"), "{files:?}");
+
+    let bit_rate = probe["format"]["bit_rate"].as_str().unwrap().parse::<f64>().unwrap() / 1024.0 / 1024.0;
+    assert!(bit_rate > 2.0 && bit_rate < 6.0, "{bit_rate} Mbps");
+
+    // The stats of the first pass are removed
+    let leftover = std::fs::read_dir(path.parent().unwrap().join("tmp")).unwrap().filter_map(|x| x.ok())
+        .map(|x| x.file_name().to_string_lossy().to_string())
+        .filter(|x| x.contains(".2pass.log"))
+        .collect::<Vec<_>>();
+    assert!(leftover.is_empty(), "{leftover:?}");
+    let _ = std::fs::remove_dir_all(path.parent().unwrap());
+}